@@ -368,6 +368,18 @@ async fn main() {
                         ctx.lock().await.del_node(node);
                     }
                 },
+                SdnExtOut::ServiceUnavailable(service, ()) => {
+                    log::warn!("Service {service} is unavailable");
+                }
+                SdnExtOut::RemoteServiceUnavailable(node, service) => {
+                    log::warn!("Service {service} is unavailable in node {node}");
+                }
+                SdnExtOut::Topology((), topology) => {
+                    log::info!("Topology neighbours {:?}", topology.neighbours);
+                }
+                SdnExtOut::RouteExplanation((), dest, explanation) => {
+                    log::info!("Route to {dest}: {:?}", explanation);
+                }
                 SdnExtOut::FeaturesEvent(_, event) => {
                     if let FeaturesEvent::RouterSync(event) = event {
                         match event {
@@ -377,6 +389,15 @@ async fn main() {
                                     let _ = v.send(json.clone());
                                 }
                             }
+                            router_sync::Event::SyncInterval(interval_ms) => {
+                                log::info!("Router sync interval {interval_ms} ms");
+                            }
+                            router_sync::Event::ServiceNodes(service, nodes) => {
+                                log::info!("Service {service} nodes {:?}", nodes);
+                            }
+                            router_sync::Event::Convergence(convergence) => {
+                                log::info!("Router convergence {:?}", convergence);
+                            }
                         }
                     }
                 }
@@ -5,5 +5,10 @@ use libfuzzer_sys::fuzz_target;
 use atm0s_sdn_network::base::NeighboursControl;
 
 fuzz_target!(|data: &[u8]| {
-    let _ = NeighboursControl::try_from(data);
+    if let Ok(control) = NeighboursControl::try_from(data) {
+        let buf: Vec<u8> = (&control).try_into().expect("Parsed control should serialize");
+        let parsed = NeighboursControl::try_from(buf.as_slice()).expect("Serialized control should parse");
+        let buf2: Vec<u8> = (&parsed).try_into().expect("Parsed control should serialize");
+        assert_eq!(buf, buf2);
+    }
 });
@@ -0,0 +1,28 @@
+#![no_main]
+
+use atm0s_sdn_network::base::TransportMsgHeader;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    //relays rewrite the ttl of packets before parsing them
+    let mut raw = data.to_vec();
+    let decreased = TransportMsgHeader::decrease_ttl(&mut raw);
+
+    let header = match TransportMsgHeader::try_from(data) {
+        Ok(header) => header,
+        Err(_) => return,
+    };
+    assert!(header.serialize_size() <= data.len());
+    assert_eq!(TransportMsgHeader::is_secure(data[0]), header.encrypt);
+    assert_eq!(decreased, header.ttl > 0);
+    if decreased {
+        assert_eq!(TransportMsgHeader::try_from(raw.as_slice()).map(|h| h.ttl), Ok(header.ttl - 1));
+    }
+    if header.hops.is_some() {
+        assert!(header.rewrite_hops(&mut raw).is_some());
+    }
+
+    let mut buf = vec![0; header.serialize_size()];
+    assert_eq!(header.to_bytes(&mut buf), Some(buf.len()));
+    assert_eq!(TransportMsgHeader::try_from(buf.as_slice()), Ok(header));
+});
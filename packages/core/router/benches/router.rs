@@ -1,12 +1,36 @@
-use std::vec;
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+    vec,
+};
 
 use atm0s_sdn_identity::ConnId;
-use atm0s_sdn_router::core::{Metric, RegistrySync, Router, RouterSync};
+use atm0s_sdn_router::core::{Metric, RegistrySync, Router, RouterSync, TableSync};
 use criterion::{criterion_group, criterion_main, Criterion};
 
-criterion_group!(benches, benchmark_empty, benchmark_single, benchmark_full);
+criterion_group!(benches, benchmark_empty, benchmark_single, benchmark_full, benchmark_sync, benchmark_memory);
 criterion_main!(benches);
 
+/// Counts live heap bytes, for the memory of routers
+struct CountingAlloc;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
 fn benchmark_empty(c: &mut Criterion) {
     let mut group = c.benchmark_group("empty");
     group.throughput(criterion::Throughput::Elements(1));
@@ -71,8 +95,81 @@ fn benchmark_full(c: &mut Criterion) {
         services.push((s, Metric::new(1, vec![1], 100000)));
     }
     router.set_direct(ConnId::from_in(0, 0), Metric::new(1, vec![1], 100000));
-    router.apply_sync(ConnId::from_in(0, 0), Metric::new(1, vec![], 100000), RouterSync(RegistrySync(services), [None, None, None, None]));
+    router.apply_sync(ConnId::from_in(0, 0), Metric::new(1, vec![1], 100000), RouterSync(RegistrySync(services), [None, None, None, None]));
     group.bench_function("next_service", |b| {
         b.iter(|| router.service_next(1, &[]));
     });
 }
+
+/// Router with 200 destinations learned from a single neighbour, the latency of `changed` destination is `latency`
+fn table_sync(changed: u32, latency: u16) -> RouterSync {
+    let dests = (2..=200)
+        .map(|n| {
+            (
+                n as u8,
+                Metric::new(
+                    if n == changed {
+                        latency
+                    } else {
+                        1
+                    },
+                    vec![n],
+                    100000,
+                ),
+            )
+        })
+        .collect();
+    RouterSync(RegistrySync(vec![]), [Some(TableSync(dests)), None, None, None])
+}
+
+fn benchmark_sync(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sync");
+    group.throughput(criterion::Throughput::Elements(1));
+    let conn = ConnId::from_in(0, 0);
+    let mut router = Router::new(0);
+    router.set_direct(conn, Metric::new(1, vec![1], 100000));
+    router.apply_sync(conn, Metric::new(1, vec![1], 100000), table_sync(0, 1));
+    let gens = router.generations();
+    router.apply_sync(conn, Metric::new(1, vec![1], 100000), table_sync(100, 10));
+
+    let full = bincode::serialize(&router.create_sync(250)).expect("").len();
+    let delta = bincode::serialize(&router.create_sync_delta(250, Some(gens))).expect("").len();
+    println!("sync of 200 destinations after 1 change: full {full} bytes, delta {delta} bytes");
+
+    group.bench_function("create_sync", |b| {
+        b.iter(|| router.create_sync(250));
+    });
+
+    group.bench_function("create_sync_delta", |b| {
+        b.iter(|| router.create_sync_delta(250, Some(gens)));
+    });
+}
+
+/// Router with 5 direct routes in each of the 4 layers, like an edge node
+fn sparse_router() -> Box<Router> {
+    let mut router = Box::new(Router::new(0));
+    for layer in 0..4 {
+        for n in 1..=5_u32 {
+            let node = n << (8 * layer);
+            router.set_direct(ConnId::from_in(0, node as u64), Metric::new(1, vec![node], 100000));
+        }
+    }
+    router
+}
+
+fn benchmark_memory(c: &mut Criterion) {
+    let mut group = c.benchmark_group("memory");
+    group.throughput(criterion::Throughput::Elements(1));
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let router = sparse_router();
+    let bytes = ALLOCATED.load(Ordering::Relaxed) - before;
+    println!("router with 4 layers of 5 routes: {bytes} heap bytes, {} routes", router.size());
+
+    group.bench_function("build_sparse", |b| {
+        b.iter(sparse_router);
+    });
+
+    group.bench_function("next_node_sparse", |b| {
+        b.iter(|| router.next(0x0300, &[]));
+    });
+}
@@ -5,8 +5,11 @@ mod router;
 mod table;
 
 pub use self::registry::{RegisterDestDump, RegisterDump, Registry, RegistryDelta, RegistryDestDelta, RegistrySync};
-pub use self::router::{Router, RouterDelta, RouterDump, RouterSync};
-pub use self::table::{DestDelta, DestDump, Metric, Path, TableDelta, TableDump, TableSync, BANDWIDTH_LIMIT};
+pub use self::router::{Router, RouterDelta, RouterDump, RouterSync, RouterSyncDelta};
+pub use self::table::{
+    DestDelta, DestDump, FlapDampingCfg, Metric, MetricCompareMode, Path, RouteCandidate, RouteExplanation, RouteReason, TableDelta, TableDiffEntry, TableDump, TableSnapshot, TableSync,
+    TableSyncDelta, BANDWIDTH_LIMIT, MAX_LATENCY_MS,
+};
 
 #[derive(PartialEq, Debug)]
 pub enum ServiceDestination {
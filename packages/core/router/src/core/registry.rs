@@ -32,6 +32,7 @@ pub struct Registry {
     local_destinations: [bool; 256],
     remote_destinations: [RegistryDest; 256],
     deltas: VecDeque<RegistryDelta>,
+    max_hops: Option<usize>,
 }
 
 impl Registry {
@@ -41,9 +42,15 @@ impl Registry {
             local_destinations: [false; 256],
             remote_destinations: std::array::from_fn(|_| RegistryDest::default()),
             deltas: VecDeque::new(),
+            max_hops: None,
         }
     }
 
+    /// Reject service paths from syncs which are longer than `max_hops`
+    pub fn set_max_hops(&mut self, max_hops: Option<usize>) {
+        self.max_hops = max_hops;
+    }
+
     pub fn dump(&self) -> RegisterDump {
         let mut local = Vec::new();
         let mut remotes = HashMap::new();
@@ -67,7 +74,6 @@ impl Registry {
         self.deltas.push_back(RegistryDelta::SetServiceLocal(service_id));
     }
 
-    #[allow(unused)]
     pub fn remove_service(&mut self, service_id: u8) {
         self.local_destinations[service_id as usize] = false;
         self.deltas.push_back(RegistryDelta::DelServiceLocal(service_id));
@@ -99,7 +105,12 @@ impl Registry {
         log::debug!("apply sync from {} -> {}, sync {:?}", src, self.node_id, sync.0);
         let mut cached: HashMap<u8, Metric> = HashMap::new();
         for (index, s_metric) in sync.0 {
-            cached.insert(index, s_metric.add(&metric));
+            let s_metric = s_metric.add(&metric);
+            if !s_metric.within_hops(self.max_hops) {
+                log::debug!("[Registry] reject service {} from {} with {} hops", index, src, s_metric.hops.len());
+                continue;
+            }
+            cached.insert(index, s_metric);
         }
 
         for i in 0..=255_u8 {
@@ -1,11 +1,11 @@
 use atm0s_sdn_identity::{ConnId, NodeId, NodeIdType};
 use serde::{Deserialize, Serialize};
 
-use crate::core::{Metric, Path};
+use crate::core::{Metric, MetricCompareMode, Path};
 use crate::core::{Registry, RegistrySync};
 
 use super::registry::{RegisterDump, RegistryDelta};
-use super::table::{NodeIndex, Table, TableDelta, TableDump, TableSync};
+use super::table::{FlapDampingCfg, NodeIndex, RouteExplanation, Table, TableDelta, TableDump, TableSnapshot, TableSync, TableSyncDelta};
 use super::ServiceDestination;
 
 #[derive(Debug, PartialEq, Clone)]
@@ -20,6 +20,10 @@ pub type Layer = u8;
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct RouterSync(pub RegistrySync, pub [Option<TableSync>; 4]);
 
+/// Sync with only changed table slots, the service registry is small so it is always full
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct RouterSyncDelta(pub RegistrySync, pub [Option<TableSyncDelta>; 4]);
+
 #[derive(Debug, Serialize, Clone, PartialEq, Eq)]
 pub struct RouterDump {
     node_id: NodeId,
@@ -27,10 +31,18 @@ pub struct RouterDump {
     layers: [TableDump; 4],
 }
 
+impl RouterDump {
+    pub fn layer(&self, layer: Layer) -> &TableDump {
+        &self.layers[layer as usize]
+    }
+}
+
 pub struct Router {
     node_id: NodeId,
     tables: [Table; 4],
     service_registry: Registry,
+    compare_mode: MetricCompareMode,
+    ecmp_tolerance: Option<u32>,
 }
 
 impl Router {
@@ -41,6 +53,8 @@ impl Router {
             node_id: local_node_id,
             tables,
             service_registry: Registry::new(local_node_id),
+            compare_mode: MetricCompareMode::default(),
+            ecmp_tolerance: None,
         }
     }
 
@@ -56,6 +70,11 @@ impl Router {
         self.node_id
     }
 
+    /// Snapshot best paths of a layer, which can be diffed with other node snapshot
+    pub fn table_snapshot(&self, layer: Layer) -> TableSnapshot {
+        self.tables[layer as usize].snapshot()
+    }
+
     pub fn size(&self) -> usize {
         let mut size = 0;
         for i in 0..4 {
@@ -68,6 +87,10 @@ impl Router {
         self.service_registry.add_service(service_id);
     }
 
+    pub fn unregister_service(&mut self, service_id: u8) {
+        self.service_registry.remove_service(service_id);
+    }
+
     pub fn service_next(&self, service_id: u8, excepts: &[NodeId]) -> Option<ServiceDestination> {
         self.service_registry.next(service_id, excepts)
     }
@@ -101,6 +124,82 @@ impl Router {
         }
     }
 
+    /// Next hop to dest which path has bottleneck bandwidth at least `min_bw`, the last value is true if no path meets it
+    /// and the widest one is returned instead
+    pub fn next_with_min_bw(&self, dest: NodeId, min_bw: u32, excepts: &[NodeId]) -> Option<(ConnId, NodeId, bool)> {
+        let eq_util_layer = self.node_id.eq_util_layer(&dest) as usize;
+        if eq_util_layer == 0 {
+            None
+        } else {
+            self.tables.get(eq_util_layer - 1)?.next_with_min_bw(dest, min_bw, excepts)
+        }
+    }
+
+    /// Enable damping of flapping destinations in all tables
+    pub fn set_flap_damping(&mut self, cfg: FlapDampingCfg) {
+        for table in self.tables.iter_mut() {
+            table.set_flap_damping(cfg);
+        }
+    }
+
+    /// Evict paths learned from syncs which are not refreshed within `timeout_ms`
+    pub fn set_route_timeout(&mut self, timeout_ms: u64) {
+        for table in self.tables.iter_mut() {
+            table.set_route_timeout(timeout_ms);
+        }
+    }
+
+    /// Reject paths from syncs which are longer than `max_hops` in all tables and the service registry
+    pub fn set_max_hops(&mut self, max_hops: Option<usize>) {
+        self.service_registry.set_max_hops(max_hops);
+        for table in self.tables.iter_mut() {
+            table.set_max_hops(max_hops);
+        }
+    }
+
+    pub fn on_tick(&mut self, now_ms: u64) {
+        for table in self.tables.iter_mut() {
+            table.on_tick(now_ms);
+        }
+    }
+
+    /// Change how paths are ordered in all tables, all nodes in a network should use the same mode
+    pub fn set_compare_mode(&mut self, mode: MetricCompareMode) {
+        self.compare_mode = mode;
+        for table in self.tables.iter_mut() {
+            table.set_compare_mode(mode);
+        }
+    }
+
+    pub fn compare_mode(&self) -> MetricCompareMode {
+        self.compare_mode
+    }
+
+    /// Enable or disable equal-cost multi-path deltas, a path is equal-cost if its score is not greater than best score + tolerance
+    pub fn set_ecmp_tolerance(&mut self, tolerance: Option<u32>) {
+        self.ecmp_tolerance = tolerance;
+        for table in self.tables.iter_mut() {
+            table.set_ecmp_tolerance(tolerance);
+        }
+    }
+
+    /// Emit up to `max` next best paths of each destination as deltas, 0 disables them
+    pub fn set_max_alternates(&mut self, max: usize) {
+        for table in self.tables.iter_mut() {
+            table.set_max_alternates(max);
+        }
+    }
+
+    /// All equal-cost next hops to dest, best first. Without ecmp enabled, ties must have exactly the same score.
+    pub fn next_ecmp(&self, dest: NodeId, excepts: &[NodeId]) -> Vec<(ConnId, NodeId)> {
+        let eq_util_layer = self.node_id.eq_util_layer(&dest) as usize;
+        debug_assert!(eq_util_layer <= 4);
+        match self.tables.get(eq_util_layer.wrapping_sub(1)) {
+            Some(table) => table.next_ecmp(dest, excepts, self.ecmp_tolerance.unwrap_or(0)),
+            None => vec![],
+        }
+    }
+
     pub fn next_path(&self, dest: NodeId, excepts: &[NodeId]) -> Option<Path> {
         let eq_util_layer = self.node_id.eq_util_layer(&dest) as usize;
         debug_assert!(eq_util_layer <= 4);
@@ -111,6 +210,17 @@ impl Router {
         }
     }
 
+    /// Paths considered by `next` in the table of dest, None for the local node
+    pub fn explain(&self, dest: NodeId, excepts: &[NodeId]) -> Option<RouteExplanation> {
+        let eq_util_layer = self.node_id.eq_util_layer(&dest) as usize;
+        debug_assert!(eq_util_layer <= 4);
+        if eq_util_layer == 0 {
+            None
+        } else {
+            Some(self.tables.get(eq_util_layer - 1)?.explain(dest, excepts))
+        }
+    }
+
     pub fn closest_node(&self, key: NodeId, excepts: &[NodeId]) -> Option<(ConnId, NodeId, Layer, NodeIndex)> {
         for i in [3, 2, 1, 0] {
             let index = key.layer(i);
@@ -130,6 +240,25 @@ impl Router {
         None
     }
 
+    /// Up to `n` nodes closer to `key` than the local node, in the same order `closest_node` would prefer them.
+    /// The first entry is always the result of `closest_node` without excepts.
+    pub fn closest_nodes(&self, key: NodeId, n: usize) -> Vec<(ConnId, NodeId, Layer, NodeIndex)> {
+        let mut res = vec![];
+        for i in [3, 2, 1, 0] {
+            let index = key.layer(i);
+            let current_distance = index ^ self.node_id.layer(i);
+            for (next_index, next_conn, next_node) in self.tables[i as usize].closest_n(index, n - res.len()) {
+                if current_distance > next_index ^ index {
+                    res.push((next_conn, next_node, i, next_index));
+                }
+            }
+            if res.len() >= n {
+                break;
+            }
+        }
+        res
+    }
+
     pub fn create_sync(&self, for_node: NodeId) -> RouterSync {
         RouterSync(
             self.service_registry.sync_for(for_node),
@@ -142,6 +271,31 @@ impl Router {
         )
     }
 
+    /// Current generation of each table, a later `create_sync_delta` based on them only carries newer changes
+    pub fn generations(&self) -> [u64; 4] {
+        std::array::from_fn(|layer| self.tables[layer].generation())
+    }
+
+    /// Sync with table changes after `since` generations, or full tables if None
+    pub fn create_sync_delta(&self, for_node: NodeId, since: Option<[u64; 4]>) -> RouterSyncDelta {
+        RouterSyncDelta(
+            self.service_registry.sync_for(for_node),
+            std::array::from_fn(|layer| self.tables[layer].sync_delta_for(for_node, since.map(|since| since[layer]))),
+        )
+    }
+
+    /// Return false if some tables detected a gap from the previous delta, then a full sync should be requested
+    pub fn apply_sync_delta(&mut self, conn: ConnId, metric: Metric, sync: RouterSyncDelta) -> bool {
+        self.service_registry.apply_sync(conn, metric.clone(), sync.0);
+        let mut applied = true;
+        for (index, table_sync) in sync.1.into_iter().enumerate() {
+            if let Some(table_sync) = table_sync {
+                applied &= self.tables[index].apply_sync_delta(conn, metric.clone(), table_sync);
+            }
+        }
+        applied
+    }
+
     pub fn apply_sync(&mut self, conn: ConnId, metric: Metric, sync: RouterSync) {
         self.service_registry.apply_sync(conn, metric.clone(), sync.0);
         for (index, table_sync) in sync.1.into_iter().enumerate() {
@@ -223,6 +377,31 @@ mod tests {
         assert_eq!(router.next(z_node2, &[]), Some((z_node1_conn, z_node1)));
     }
 
+    #[test]
+    fn route_across_all_layers() {
+        let mut router = Router::new(0x0);
+        let nodes: [NodeId; 4] = [0x00000001, 0x00000100, 0x00010000, 0x01000000];
+        for node in nodes {
+            router.set_direct(ConnId::from_out(0, node as u64), Metric::new(1, vec![node], 1));
+        }
+        for (layer, node) in nodes.iter().enumerate() {
+            assert_eq!(router.tables[layer].slots(), vec![node.layer(layer as u8)]);
+        }
+
+        //the highest different layer is used, lower layers are only for the last hops inside the zone
+        let conn = |node: NodeId| Some((ConnId::from_out(0, node as u64), node));
+        assert_eq!(router.next(0x01abcdef, &[]), conn(0x01000000));
+        assert_eq!(router.next(0x00010203, &[]), conn(0x00010000));
+        assert_eq!(router.next(0x00000102, &[]), conn(0x00000100));
+        assert_eq!(router.next(0x00000001, &[]), conn(0x00000001));
+        assert_eq!(router.next_path(0x01abcdef, &[]).map(|p| p.0), conn(0x01000000).map(|c| c.0));
+        assert_eq!(router.next_ecmp(0x00010203, &[]), vec![(ConnId::from_out(0, 0x00010000), 0x00010000)]);
+
+        //unknown zone at a higher layer doesn't fall back to lower layers
+        assert_eq!(router.next(0x00020000, &[]), None);
+        assert_eq!(router.next(0x00000000, &[]), None);
+    }
+
     fn create_router(node_id: NodeId) -> (NodeId, ConnId, Router) {
         (node_id, ConnId::from_out(0, node_id as u64), Router::new(node_id))
     }
@@ -242,6 +421,50 @@ mod tests {
         assert_eq!(router2.tables[0].slots(), vec![1, 3]);
     }
 
+    #[test]
+    fn sync_delta_smaller_than_full() {
+        let conn: ConnId = ConnId::from_out(0, 0x1);
+        let table_sync = |changed: u32| {
+            let dests = (2..=200)
+                .map(|n| {
+                    (
+                        n as u8,
+                        Metric::new(
+                            if n == changed {
+                                10
+                            } else {
+                                1
+                            },
+                            vec![n],
+                            100000,
+                        ),
+                    )
+                })
+                .collect();
+            RouterSync(RegistrySync(vec![]), [Some(TableSync(dests)), None, None, None])
+        };
+
+        let mut router = Router::new(0x0);
+        router.set_direct(conn, Metric::new(1, vec![0x1], 100000));
+        router.apply_sync(conn, Metric::new(1, vec![0x1], 100000), table_sync(0));
+        let gens = router.generations();
+        let mut receiver = Router::new(0xfa);
+        receiver.set_direct(conn, Metric::new(1, vec![0x0], 100000));
+        assert!(receiver.apply_sync_delta(conn, Metric::new(1, vec![0x0], 100000), router.create_sync_delta(0xfa, None)));
+        router.apply_sync(conn, Metric::new(1, vec![0x1], 100000), table_sync(100));
+
+        let delta = router.create_sync_delta(0xfa, Some(gens));
+        assert_eq!(delta.1[0].as_ref().map(|d| d.changes.len()), Some(1));
+        let full_len = bincode::serialize(&router.create_sync(0xfa)).expect("").len();
+        let delta_len = bincode::serialize(&delta).expect("").len();
+        assert!(delta_len * 10 < full_len, "delta {delta_len} vs full {full_len}");
+
+        assert!(receiver.apply_sync_delta(conn, Metric::new(1, vec![0x0], 100000), delta.clone()));
+        //same delta again is a gap
+        assert!(!receiver.apply_sync_delta(conn, Metric::new(1, vec![0x0], 100000), delta));
+        assert_eq!(receiver.dump().layer(0).dest_indexes().len(), 201);
+    }
+
     #[test]
     fn complex_sync_same_zone() {
         // A -1- B -1- C -1- F
@@ -454,6 +677,35 @@ mod tests {
         assert_eq!(router_a.closest_node(NodeId::build(2, 6, 0, 4), &[]), None);
     }
 
+    #[test]
+    fn closest_nodes() {
+        let (_node_a, _conn_a, mut router_a) = create_router(NodeId::build(1, 0, 0, 1));
+        assert_eq!(router_a.closest_nodes(0x01, 3), vec![]);
+
+        let node_0002 = NodeId::build(1, 0, 0, 2);
+        let node_0003 = NodeId::build(1, 0, 0, 3);
+        let node_5000 = NodeId::build(5, 0, 0, 0);
+
+        let conn_0002 = ConnId::from_out(0, 2);
+        let conn_0003 = ConnId::from_out(0, 3);
+        let conn_5000 = ConnId::from_out(0, 5000);
+
+        router_a.set_direct(conn_0002, Metric::new(1, vec![node_0002], 1));
+        router_a.set_direct(conn_0003, Metric::new(1, vec![node_0003], 1));
+        router_a.set_direct(conn_5000, Metric::new(1, vec![node_5000], 1));
+
+        let key = NodeId::build(4, 0, 0, 2);
+        let closest = router_a.closest_nodes(key, 3);
+        assert_eq!(closest.first().copied(), router_a.closest_node(key, &[]));
+        // nodes in the same upper layers are still closer than local node by lower layer
+        assert_eq!(closest, vec![(conn_5000, node_5000, 3, 5), (conn_0002, node_0002, 0, 2), (conn_0003, node_0003, 0, 3)]);
+        assert_eq!(router_a.closest_nodes(key, 2), vec![(conn_5000, node_5000, 3, 5), (conn_0002, node_0002, 0, 2)]);
+
+        let key = NodeId::build(1, 0, 0, 2);
+        assert_eq!(router_a.closest_nodes(key, 3), vec![(conn_0002, node_0002, 0, 2), (conn_0003, node_0003, 0, 3)]);
+        assert_eq!(router_a.closest_nodes(key, 1), vec![(conn_0002, node_0002, 0, 2)]);
+    }
+
     #[test]
     fn random_test_closest() {
         //TODO
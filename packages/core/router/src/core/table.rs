@@ -1,13 +1,19 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 
 use atm0s_sdn_identity::{ConnId, NodeId, NodeIdType};
+use atm0s_sdn_utils::metrics;
 use serde::{Deserialize, Serialize};
 
+pub use damping::FlapDampingCfg;
+use damping::FlapState;
 pub use dest::{Dest, DestDelta, DestDump};
-pub use metric::{Metric, BANDWIDTH_LIMIT};
+pub use explain::{RouteCandidate, RouteExplanation, RouteReason};
+pub use metric::{Metric, MetricCompareMode, BANDWIDTH_LIMIT, MAX_LATENCY_MS};
 pub use path::Path;
 
+mod damping;
 mod dest;
+mod explain;
 mod metric;
 mod path;
 
@@ -20,18 +26,108 @@ pub struct TableDelta(pub u8, pub DestDelta);
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct TableSync(pub Vec<(u8, Metric)>);
 
+/// Changed slots of a table between two generations, None metric means the slot is removed.
+/// `since` is None for a full sync, then slots which are not listed are removed
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct TableSyncDelta {
+    pub since: Option<u64>,
+    pub gen: u64,
+    pub changes: Vec<(u8, Option<Metric>)>,
+}
+
 #[derive(Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct TableDump {
     layer: u8,
     dests: HashMap<u8, DestDump>,
 }
 
+/// Best next hop and metric of each reachable destination in a table
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TableSnapshot {
+    layer: u8,
+    dests: BTreeMap<NodeIndex, (NodeId, Metric)>,
+}
+
+/// One destination where two snapshots disagree, `a` is the snapshot `diff` called on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TableDiffEntry {
+    OnlyInA(NodeIndex, NodeId, Metric),
+    OnlyInB(NodeIndex, NodeId, Metric),
+    /// Both have the destination but with different next hop or metric: (index, a, b)
+    Differs(NodeIndex, (NodeId, Metric), (NodeId, Metric)),
+}
+
+impl TableDump {
+    /// Indexes of destinations which have at least one path, sorted
+    pub fn dest_indexes(&self) -> Vec<NodeIndex> {
+        let mut indexes: Vec<_> = self.dests.keys().copied().collect();
+        indexes.sort();
+        indexes
+    }
+}
+
+impl TableSnapshot {
+    pub fn layer(&self) -> u8 {
+        self.layer
+    }
+
+    pub fn get(&self, index: NodeIndex) -> Option<&(NodeId, Metric)> {
+        self.dests.get(&index)
+    }
+
+    /// Compare with other snapshot, entries are sorted by destination index
+    pub fn diff(&self, other: &TableSnapshot) -> Vec<TableDiffEntry> {
+        let mut res = vec![];
+        for (index, (next, metric)) in self.dests.iter() {
+            match other.dests.get(index) {
+                None => res.push(TableDiffEntry::OnlyInA(*index, *next, metric.clone())),
+                Some((o_next, o_metric)) => {
+                    if next != o_next || metric != o_metric {
+                        res.push(TableDiffEntry::Differs(*index, (*next, metric.clone()), (*o_next, o_metric.clone())));
+                    }
+                }
+            }
+        }
+        for (index, (next, metric)) in other.dests.iter() {
+            if !self.dests.contains_key(index) {
+                res.push(TableDiffEntry::OnlyInB(*index, *next, metric.clone()));
+            }
+        }
+        res.sort_by_key(|e| match e {
+            TableDiffEntry::OnlyInA(index, ..) | TableDiffEntry::OnlyInB(index, ..) | TableDiffEntry::Differs(index, ..) => *index,
+        });
+        res
+    }
+}
+
 pub struct Table {
     node_id: NodeId,
     layer: u8,
-    dests: [Dest; 256],
+    /// Only indexes which have paths, most tables are sparse so they are not allocated for all 256 indexes
+    dests: HashMap<u8, Dest>,
+    /// Ordered indexes of `dests`
     slots: Vec<u8>,
     deltas: VecDeque<TableDelta>,
+    mode: MetricCompareMode,
+    ecmp_tolerance: Option<u32>,
+    /// Current equal-cost paths of indexes which have more than one
+    ecmp: HashMap<u8, Vec<(ConnId, u32)>>,
+    /// Max alternate paths of each index, 0 disables them
+    max_alternates: usize,
+    /// Current alternate paths of indexes which have any
+    alternates: HashMap<u8, Vec<ConnId>>,
+    flap_damping: Option<FlapDampingCfg>,
+    flaps: HashMap<u8, FlapState>,
+    route_timeout_ms: Option<u64>,
+    max_hops: Option<usize>,
+    now_ms: u64,
+    /// Increased on each slot change, `slot_gens` keeps the generation of the last change of each slot which ever changed
+    gen: u64,
+    slot_gens: HashMap<u8, u64>,
+    /// Generation of the last delta sync applied from each neighbour
+    applied_gens: HashMap<ConnId, u64>,
+    /// Slot count which is added to the routes metric of the layer
+    reported_routes: usize,
 }
 
 impl Table {
@@ -39,16 +135,41 @@ impl Table {
         Table {
             node_id,
             layer,
-            dests: std::array::from_fn(|_| Dest::default()),
+            dests: HashMap::new(),
             slots: vec![],
             deltas: VecDeque::new(),
+            mode: MetricCompareMode::default(),
+            ecmp_tolerance: None,
+            ecmp: HashMap::new(),
+            max_alternates: 0,
+            alternates: HashMap::new(),
+            flap_damping: None,
+            flaps: HashMap::new(),
+            route_timeout_ms: None,
+            max_hops: None,
+            now_ms: 0,
+            gen: 0,
+            slot_gens: HashMap::new(),
+            applied_gens: HashMap::new(),
+            reported_routes: 0,
         }
     }
 
     pub fn dump(&self) -> TableDump {
         TableDump {
             layer: self.layer,
-            dests: HashMap::from_iter(self.dests.iter().enumerate().filter(|d| !d.1.is_empty()).map(|d| (d.0 as u8, d.1.dump()))),
+            dests: HashMap::from_iter(self.dests.iter().map(|(index, dest)| (*index, dest.dump()))),
+        }
+    }
+
+    pub fn snapshot(&self) -> TableSnapshot {
+        TableSnapshot {
+            layer: self.layer,
+            dests: self
+                .dests
+                .iter()
+                .filter_map(|(index, dest)| dest.next_path(&[]).map(|path| (*index, (path.1.over_node(), path.1))))
+                .collect(),
         }
     }
 
@@ -58,36 +179,51 @@ impl Table {
     }
 
     pub fn size(&self) -> usize {
-        let mut size = 0;
-        for i in 0..256 {
-            if !self.dests[i].is_empty() {
-                size += 1;
-            }
-        }
-        size
+        self.slots.len()
     }
 
     pub fn add_direct(&mut self, conn: ConnId, metric: Metric) {
         let index = metric.over_node().layer(self.layer);
-        if self.dests[index as usize].is_empty() {
-            log::info!("[Table {}/{}] added index {} from conn {} metric: {:?}", self.node_id, self.layer, index, conn, metric);
+        if !self.dests.contains_key(&index) {
+            log::log!(
+                self.slot_log_level(index),
+                "[Table {}/{}] added index {} from conn {} metric: {:?}",
+                self.node_id,
+                self.layer,
+                index,
+                conn,
+                metric
+            );
             self.slots.push(index);
             self.slots.sort();
+            self.on_slot_toggle(index);
         }
-        self.dests[index as usize].set_path(conn, metric);
+        self.dests.entry(index).or_default().set_path(conn, metric, self.mode, self.now_ms);
         self.poll_delta_index(index);
     }
 
     pub fn del_direct(&mut self, conn: ConnId) {
-        for i in 0..=255 {
-            let pre_empty = self.dests[i as usize].is_empty();
-            if let Some(path) = self.dests[i as usize].del_path(conn) {
-                if !pre_empty && self.dests[i as usize].is_empty() {
-                    log::info!("[Table {}/{}] removed index {} from conn: {}, metric: {:?}", self.node_id, self.layer, i, conn, path.1);
+        self.applied_gens.remove(&conn);
+        for i in self.slots.clone() {
+            let Some(dest) = self.dests.get_mut(&i) else {
+                continue;
+            };
+            if let Some(path) = dest.del_path(conn) {
+                if dest.is_empty() {
+                    log::log!(
+                        self.slot_log_level(i),
+                        "[Table {}/{}] removed index {} from conn: {}, metric: {:?}",
+                        self.node_id,
+                        self.layer,
+                        i,
+                        conn,
+                        path.1
+                    );
 
                     if let Ok(index) = self.slots.binary_search(&i) {
                         self.slots.remove(index);
                     }
+                    self.on_slot_toggle(i);
                 }
                 self.poll_delta_index(i);
             }
@@ -96,12 +232,105 @@ impl Table {
 
     pub fn next(&self, dest: NodeId, excepts: &[NodeId]) -> Option<(ConnId, NodeId)> {
         let index = dest.layer(self.layer);
-        self.dests[index as usize].next(excepts)
+        self.dests.get(&index)?.next(excepts)
+    }
+
+    /// Next hop which path has bottleneck bandwidth at least `min_bw`, see [`Dest::next_with_min_bw`] for the degraded fallback
+    pub fn next_with_min_bw(&self, dest: NodeId, min_bw: u32, excepts: &[NodeId]) -> Option<(ConnId, NodeId, bool)> {
+        let index = dest.layer(self.layer);
+        self.dests.get(&index)?.next_with_min_bw(min_bw, excepts)
     }
 
     pub fn next_path(&self, dest: NodeId, excepts: &[NodeId]) -> Option<Path> {
         let index = dest.layer(self.layer);
-        self.dests[index as usize].next_path(excepts)
+        self.dests.get(&index)?.next_path(excepts)
+    }
+
+    /// All paths to dest and why `next` picks its path, for debugging routes. It does not change the selection
+    pub fn explain(&self, dest: NodeId, excepts: &[NodeId]) -> RouteExplanation {
+        let index = dest.layer(self.layer);
+        let paths = self.dests.get(&index).into_iter().flat_map(|dest| dest.paths());
+        RouteExplanation::new(dest, self.layer, self.mode, paths, excepts)
+    }
+
+    pub fn next_ecmp(&self, dest: NodeId, excepts: &[NodeId], tolerance: u32) -> Vec<(ConnId, NodeId)> {
+        let index = dest.layer(self.layer);
+        self.dests.get(&index).map(|dest| dest.next_ecmp(excepts, tolerance)).unwrap_or_default()
+    }
+
+    /// Enable damping of flapping slots. Suppressed slots still forward packets but are not advertised in syncs
+    pub fn set_flap_damping(&mut self, cfg: FlapDampingCfg) {
+        self.flap_damping = Some(cfg);
+    }
+
+    /// Evict paths learned from syncs which are not refreshed within `timeout_ms`.
+    /// Direct paths are kept since they are removed by disconnect events
+    pub fn set_route_timeout(&mut self, timeout_ms: u64) {
+        self.route_timeout_ms = Some(timeout_ms);
+    }
+
+    /// Reject paths from syncs which are longer than `max_hops`, as if the neighbour did not advertise them
+    pub fn set_max_hops(&mut self, max_hops: Option<usize>) {
+        self.max_hops = max_hops;
+    }
+
+    /// Update time for route timeout and flap damping
+    pub fn on_tick(&mut self, now_ms: u64) {
+        self.now_ms = now_ms;
+        self.evict_expired(now_ms);
+        let cfg = match &self.flap_damping {
+            Some(cfg) => *cfg,
+            None => return,
+        };
+        let (node_id, layer) = (self.node_id, self.layer);
+        let mut stable = vec![];
+        self.flaps.retain(|index, state| {
+            if state.on_tick(now_ms, &cfg) {
+                log::info!("[Table {}/{}] index {} is stable again => advertise in syncs", node_id, layer, index);
+                stable.push(*index);
+            }
+            !state.is_idle()
+        });
+        for index in stable {
+            self.bump_gen(index);
+        }
+    }
+
+    pub fn is_suppressed(&self, index: NodeIndex) -> bool {
+        self.flaps.get(&index).is_some_and(|state| state.suppressed())
+    }
+
+    /// Change how paths are ordered, best paths are re-selected with the new mode
+    pub fn set_compare_mode(&mut self, mode: MetricCompareMode) {
+        self.mode = mode;
+        for i in self.slots.clone() {
+            if let Some(dest) = self.dests.get_mut(&i) {
+                dest.resort(mode);
+            }
+            self.poll_delta_index(i);
+        }
+    }
+
+    /// Enable tracking of equal-cost paths, a path is equal-cost if its score is not greater than best score + tolerance.
+    /// Each change is emitted as DestDelta::SetEcmpPaths
+    pub fn set_ecmp_tolerance(&mut self, tolerance: Option<u32>) {
+        self.ecmp_tolerance = tolerance;
+        for i in self.slots.clone() {
+            self.check_ecmp(i);
+        }
+    }
+
+    /// Enable tracking of up to `max` next best paths of each index, 0 disables it.
+    /// Each change is emitted as DestDelta::SetAlternatePaths
+    pub fn set_max_alternates(&mut self, max: usize) {
+        self.max_alternates = max;
+        for i in self.slots.clone() {
+            self.check_alternates(i);
+        }
+        let stale: Vec<u8> = self.alternates.keys().filter(|i| !self.slots.contains(i)).copied().collect();
+        for i in stale {
+            self.check_alternates(i);
+        }
     }
 
     pub fn closest_for(&self, key: u8, excepts: &[NodeId]) -> Option<(NodeIndex, ConnId, NodeId)> {
@@ -109,7 +338,7 @@ impl Table {
         for slot in &self.slots {
             let distance = *slot ^ key;
             if closest_distance.is_none() || distance < closest_distance.expect("").3 {
-                if let Some((conn, node)) = self.dests[*slot as usize].next(excepts) {
+                if let Some((conn, node)) = self.dests.get(slot).and_then(|dest| dest.next(excepts)) {
                     closest_distance = Some((*slot, conn, node, distance));
                 }
             }
@@ -118,12 +347,26 @@ impl Table {
         closest_distance.map(|(index, conn, node, _)| (index, conn, node))
     }
 
+    /// Up to `n` routable destinations nearest to `key`, sorted by the same xor distance as `closest_for`
+    pub fn closest_n(&self, key: u8, n: usize) -> Vec<(NodeIndex, ConnId, NodeId)> {
+        let mut candidates = self
+            .slots
+            .iter()
+            .filter_map(|slot| self.dests.get(slot)?.next(&[]).map(|(conn, node)| (*slot, conn, node)))
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|(slot, _, _)| *slot ^ key);
+        candidates.truncate(n);
+        candidates
+    }
+
     pub fn apply_sync(&mut self, conn: ConnId, metric: Metric, sync: TableSync) {
         let src = metric.over_node();
         log::debug!("[Table {}/{}] apply sync from conn: {} sync {:?}", self.node_id, self.layer, conn, sync.0);
         let mut cached: HashMap<u8, Metric> = HashMap::new();
         for (index, s_metric) in sync.0 {
-            cached.insert(index, s_metric.add(&metric));
+            if let Some(s_metric) = self.synced_metric(conn, index, s_metric, &metric) {
+                cached.insert(index, s_metric);
+            }
         }
 
         for i in 0..=255_u8 {
@@ -131,29 +374,104 @@ impl Table {
                 continue;
             }
 
-            let dest = &mut self.dests[i as usize];
             if let Some(metric) = cached.remove(&i) {
-                if dest.is_empty() {
-                    log::info!("[Table {}/{}] sync => added index {} from conn: {} metric: {:?}", self.node_id, self.layer, i, conn, metric);
-                    self.slots.push(i);
-                    self.slots.sort();
-                }
-                dest.set_path(conn, metric);
-            } else if !dest.is_empty() && metric.over_node().layer(self.layer) != i {
-                // log::debug!("remove {} over {}", i, src);
-                let pre_empty = dest.is_empty();
-                dest.del_path(conn);
-                if !pre_empty && dest.is_empty() {
-                    log::info!("[Table {}/{}] sync => removed index {} from conn: {} over node: {}", self.node_id, self.layer, i, conn, src);
-                    if let Ok(index) = self.slots.binary_search(&i) {
-                        self.slots.remove(index);
-                    }
-                }
+                self.set_synced_path(i, conn, metric);
+            } else if self.dests.contains_key(&i) && src.layer(self.layer) != i {
+                self.del_synced_path(i, conn, src);
             }
-            self.poll_delta_index(i);
         }
     }
 
+    /// Apply changes from a neighbour, return false without applying if the delta is not based on the last applied generation.
+    /// A full sync is always applied, after a false the neighbour should be asked for one.
+    ///
+    /// Slots which are not changed keep the metric of the connection at the time they were synced, until the next full sync
+    pub fn apply_sync_delta(&mut self, conn: ConnId, metric: Metric, delta: TableSyncDelta) -> bool {
+        let since = match delta.since {
+            Some(since) => since,
+            None => {
+                let full = delta.changes.into_iter().filter_map(|(index, m)| m.map(|m| (index, m))).collect();
+                self.apply_sync(conn, metric, TableSync(full));
+                self.applied_gens.insert(conn, delta.gen);
+                return true;
+            }
+        };
+        if self.applied_gens.get(&conn) != Some(&since) {
+            log::debug!(
+                "[Table {}/{}] delta sync from conn: {} since {} has gap vs applied {:?}",
+                self.node_id,
+                self.layer,
+                conn,
+                since,
+                self.applied_gens.get(&conn)
+            );
+            return false;
+        }
+        let src = metric.over_node();
+        for dest in self.dests.values_mut() {
+            dest.touch(conn, self.now_ms);
+        }
+        for (i, s_metric) in delta.changes {
+            if i == self.node_id.layer(self.layer) {
+                continue;
+            }
+            match s_metric.and_then(|s_metric| self.synced_metric(conn, i, s_metric, &metric)) {
+                Some(metric) => self.set_synced_path(i, conn, metric),
+                None if src.layer(self.layer) != i => self.del_synced_path(i, conn, src),
+                None => {}
+            }
+        }
+        self.applied_gens.insert(conn, delta.gen);
+        true
+    }
+
+    /// Metric over this node of a path advertised by a neighbour, None if it is over the hop limit
+    fn synced_metric(&self, conn: ConnId, index: u8, s_metric: Metric, metric: &Metric) -> Option<Metric> {
+        let s_metric = s_metric.add(metric);
+        if !s_metric.within_hops(self.max_hops) {
+            log::debug!("[Table {}/{}] reject index {} from conn: {} with {} hops", self.node_id, self.layer, index, conn, s_metric.hops.len());
+            return None;
+        }
+        Some(s_metric)
+    }
+
+    fn set_synced_path(&mut self, i: u8, conn: ConnId, metric: Metric) {
+        let was_empty = !self.dests.contains_key(&i);
+        if was_empty {
+            log::log!(
+                self.slot_log_level(i),
+                "[Table {}/{}] sync => added index {} from conn: {} metric: {:?}",
+                self.node_id,
+                self.layer,
+                i,
+                conn,
+                metric
+            );
+            self.slots.push(i);
+            self.slots.sort();
+        }
+        self.dests.entry(i).or_default().set_path(conn, metric, self.mode, self.now_ms);
+        if was_empty {
+            self.on_slot_toggle(i);
+        }
+        self.poll_delta_index(i);
+    }
+
+    fn del_synced_path(&mut self, i: u8, conn: ConnId, src: NodeId) {
+        let log_level = self.slot_log_level(i);
+        let Some(dest) = self.dests.get_mut(&i) else {
+            return;
+        };
+        if dest.del_path(conn).is_some() && dest.is_empty() {
+            log::log!(log_level, "[Table {}/{}] sync => removed index {} from conn: {} over node: {}", self.node_id, self.layer, i, conn, src);
+            if let Ok(index) = self.slots.binary_search(&i) {
+                self.slots.remove(index);
+            }
+            self.on_slot_toggle(i);
+        }
+        self.poll_delta_index(i);
+    }
+
     pub fn pop_delta(&mut self) -> Option<TableDelta> {
         self.deltas.pop_front()
     }
@@ -165,10 +483,9 @@ impl Table {
         }
 
         let mut res = vec![];
-        for i in 0..=255 {
-            let dest = &self.dests[i as usize];
-            if !dest.is_empty() && i != self.node_id.layer(self.layer) {
-                if let Some(Path(_over, metric)) = dest.best_for(node) {
+        for i in self.slots.iter().copied() {
+            if i != self.node_id.layer(self.layer) && !self.is_suppressed(i) {
+                if let Some(Path(_over, metric)) = self.dests.get(&i).and_then(|dest| dest.best_for(node)) {
                     res.push((i, metric));
                 }
             }
@@ -176,42 +493,545 @@ impl Table {
         Some(TableSync(res))
     }
 
-    pub fn log_dump(&self) {
-        let mut slots = vec![];
-        for (index, dest) in self.dests.iter().enumerate() {
-            if !dest.is_empty() {
-                slots.push(index);
+    /// Current generation, a delta sync based on it only carries later changes
+    pub fn generation(&self) -> u64 {
+        self.gen
+    }
+
+    /// Like `sync_for` but only with slots changed after `since_gen`, or all slots if None.
+    /// Slots which are removed or not advertised anymore to this node are listed with None metric
+    pub fn sync_delta_for(&self, node: NodeId, since_gen: Option<u64>) -> Option<TableSyncDelta> {
+        let since = match since_gen {
+            Some(since) => since,
+            None => {
+                let full = self.sync_for(node)?;
+                return Some(TableSyncDelta {
+                    since: None,
+                    gen: self.gen,
+                    changes: full.0.into_iter().map(|(index, metric)| (index, Some(metric))).collect(),
+                });
             }
+        };
+        let eq_util_layer = self.node_id.eq_util_layer(&node) as usize;
+        if eq_util_layer > self.layer as usize + 1 {
+            return None;
+        }
+        let mut indexes: Vec<u8> = self
+            .slot_gens
+            .iter()
+            .filter(|(i, gen)| **gen > since && **i != self.node_id.layer(self.layer))
+            .map(|(i, _)| *i)
+            .collect();
+        indexes.sort();
+        let mut changes = vec![];
+        for i in indexes {
+            let metric = if self.is_suppressed(i) {
+                None
+            } else {
+                self.dests.get(&i).and_then(|dest| dest.best_for(node)).map(|Path(_over, metric)| metric)
+            };
+            changes.push((i, metric));
         }
-        log::debug!("[Table {}/{}/{}] slots: {:?}", self.node_id, self.layer, self.node_id.layer(self.layer), slots);
+        Some(TableSyncDelta {
+            since: Some(since),
+            gen: self.gen,
+            changes,
+        })
+    }
+
+    pub fn log_dump(&self) {
+        log::debug!("[Table {}/{}/{}] slots: {:?}", self.node_id, self.layer, self.node_id.layer(self.layer), self.slots);
     }
 
     pub fn print_dump(&self) {
-        let mut slots = vec![];
-        for (index, dest) in self.dests.iter().enumerate() {
-            if !dest.is_empty() {
-                slots.push(index);
+        println!("[Table {}/{}/{}] slots: {:?}", self.node_id, self.layer, self.node_id.layer(self.layer), self.slots);
+    }
+
+    fn evict_expired(&mut self, now_ms: u64) {
+        let timeout_ms = match self.route_timeout_ms {
+            Some(timeout_ms) => timeout_ms,
+            None => return,
+        };
+        let before_ms = match now_ms.checked_sub(timeout_ms) {
+            Some(before_ms) => before_ms,
+            None => return,
+        };
+        for i in self.slots.clone() {
+            let Some(dest) = self.dests.get_mut(&i) else {
+                continue;
+            };
+            let expired = dest.del_expired(before_ms);
+            if expired.is_empty() {
+                continue;
             }
+            if dest.is_empty() {
+                log::warn!("[Table {}/{}] evicted index {} after {} ms without refresh", self.node_id, self.layer, i, timeout_ms);
+                if let Ok(index) = self.slots.binary_search(&i) {
+                    self.slots.remove(index);
+                }
+                self.on_slot_toggle(i);
+            }
+            self.poll_delta_index(i);
+        }
+    }
+
+    /// Suppressed slots keep toggling, so they are logged in debug level only
+    fn slot_log_level(&self, index: NodeIndex) -> log::Level {
+        if self.is_suppressed(index) {
+            log::Level::Debug
+        } else {
+            log::Level::Info
         }
-        println!("[Table {}/{}/{}] slots: {:?}", self.node_id, self.layer, self.node_id.layer(self.layer), slots);
     }
 
+    fn bump_gen(&mut self, index: NodeIndex) {
+        self.gen += 1;
+        self.slot_gens.insert(index, self.gen);
+    }
+
+    fn on_slot_toggle(&mut self, index: NodeIndex) {
+        if let Some(metric) = metrics::ROUTER_ROUTES.get(self.layer as usize) {
+            metric.track(&mut self.reported_routes, self.slots.len());
+        }
+        //suppression may change with the toggle
+        self.bump_gen(index);
+        let cfg = match &self.flap_damping {
+            Some(cfg) => *cfg,
+            None => return,
+        };
+        let now_ms = self.now_ms;
+        let state = self.flaps.entry(index).or_insert_with(|| FlapState::new(now_ms));
+        if state.on_toggle(now_ms, &cfg) {
+            log::warn!("[Table {}/{}] index {} is flapping => suppress from syncs", self.node_id, self.layer, index);
+        }
+    }
+
+    /// Empty dests are released here, after their last deltas are taken
     fn poll_delta_index(&mut self, index: u8) {
-        while let Some(delta) = self.dests[index as usize].pop_delta() {
+        let Some(dest) = self.dests.get_mut(&index) else {
+            self.check_ecmp(index);
+            self.check_alternates(index);
+            return;
+        };
+        let changed = dest.take_changed();
+        while let Some(delta) = dest.pop_delta() {
             self.deltas.push_back(TableDelta(index, delta));
         }
+        if changed {
+            self.bump_gen(index);
+        }
+        self.check_ecmp(index);
+        self.check_alternates(index);
+        if self.dests.get(&index).is_some_and(|dest| dest.is_empty()) {
+            self.dests.remove(&index);
+        }
+    }
+
+    fn next_ecmp_at(&self, index: u8, tolerance: u32) -> Vec<(ConnId, u32)> {
+        self.dests
+            .get(&index)
+            .map(|dest| dest.ecmp_paths(&[], tolerance).into_iter().map(|path| (path.0, path.1.bandwidth)).collect())
+            .unwrap_or_default()
+    }
+
+    fn check_ecmp(&mut self, index: u8) {
+        let paths = match self.ecmp_tolerance {
+            Some(tolerance) => self.next_ecmp_at(index, tolerance),
+            None => vec![],
+        };
+        //single path is handled by SetBestPath/DelBestPath
+        if paths.len() > 1 {
+            if self.ecmp.get(&index) != Some(&paths) {
+                self.ecmp.insert(index, paths.clone());
+                self.deltas.push_back(TableDelta(index, DestDelta::SetEcmpPaths(paths)));
+            }
+        } else if self.ecmp.remove(&index).is_some() {
+            self.deltas.push_back(TableDelta(index, DestDelta::SetEcmpPaths(vec![])));
+        }
+    }
+
+    fn check_alternates(&mut self, index: u8) {
+        let paths: Vec<ConnId> = match self.max_alternates {
+            0 => vec![],
+            max => self.dests.get(&index).map(|dest| dest.paths().skip(1).take(max).map(|path| path.0).collect()).unwrap_or_default(),
+        };
+        if !paths.is_empty() {
+            if self.alternates.get(&index) != Some(&paths) {
+                self.alternates.insert(index, paths.clone());
+                self.deltas.push_back(TableDelta(index, DestDelta::SetAlternatePaths(paths)));
+            }
+        } else if self.alternates.remove(&index).is_some() {
+            self.deltas.push_back(TableDelta(index, DestDelta::SetAlternatePaths(vec![])));
+        }
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        if let Some(metric) = metrics::ROUTER_ROUTES.get(self.layer as usize) {
+            metric.track(&mut self.reported_routes, 0);
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use atm0s_sdn_identity::{ConnId, NodeId, NodeIdType};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
 
     use crate::core::{
-        table::{Table, TableSync},
-        DestDelta, Metric, Path, TableDelta,
+        table::{Dest, FlapDampingCfg, RouteReason, Table, TableDiffEntry, TableSync, TableSyncDelta},
+        DestDelta, Metric, MetricCompareMode, Path, TableDelta, MAX_LATENCY_MS,
     };
 
+    #[test]
+    fn route_timeout_evict() {
+        let node0: NodeId = 0x0;
+        let node1: NodeId = 0x1;
+        let node5: NodeId = 0x5;
+        let node6: NodeId = 0x6;
+        let conn1: ConnId = ConnId::from_out(0, 0x1);
+
+        let mut table = Table::new(node0, 0);
+        table.set_route_timeout(1000);
+        table.add_direct(conn1, Metric::new(1, vec![1], 1));
+        table.apply_sync(conn1, Metric::new(1, vec![1], 1), TableSync(vec![(5, Metric::new(1, vec![5], 1)), (6, Metric::new(1, vec![6], 1))]));
+        assert_eq!(table.slots(), vec![1, 5, 6]);
+        while table.pop_delta().is_some() {}
+
+        //only slot 6 is refreshed
+        table.on_tick(600);
+        table.apply_sync(conn1, Metric::new(1, vec![1], 1), TableSync(vec![(6, Metric::new(1, vec![6], 1))]));
+        assert_eq!(table.slots(), vec![1, 6]);
+        assert_eq!(table.pop_delta(), Some(TableDelta(5, DestDelta::DelBestPath)));
+
+        //neighbour goes silent, learned routes are evicted but direct one is kept
+        table.on_tick(1500);
+        assert_eq!(table.slots(), vec![1, 6]);
+        assert_eq!(table.pop_delta(), None);
+        table.on_tick(1700);
+        assert_eq!(table.slots(), vec![1]);
+        assert_eq!(table.pop_delta(), Some(TableDelta(6, DestDelta::DelBestPath)));
+        assert_eq!(table.next(node5, &[]), None);
+        assert_eq!(table.next(node6, &[]), None);
+        assert_eq!(table.next(node1, &[]), Some((conn1, node1)));
+    }
+
+    #[test]
+    fn delta_sync_only_changed() {
+        let node_a: NodeId = 0x0;
+        let node_b: NodeId = 0xa;
+        let conn_a: ConnId = ConnId::from_out(0, 0xa0);
+        let link = Metric::new(1, vec![node_a], 1);
+
+        let mut table_a = Table::new(node_a, 0);
+        let mut table_b = Table::new(node_b, 0);
+        for n in 1..=5 {
+            table_a.add_direct(ConnId::from_out(0, n), Metric::new(1, vec![n as NodeId], 1));
+        }
+        table_b.add_direct(conn_a, link.clone());
+
+        let full = table_a.sync_delta_for(node_b, None).expect("Should have sync");
+        assert_eq!(full.since, None);
+        assert_eq!(full.changes.len(), 5);
+        assert!(table_b.apply_sync_delta(conn_a, link.clone(), full));
+        assert_eq!(table_b.slots(), vec![0, 1, 2, 3, 4, 5]);
+        let gen = table_a.generation();
+
+        //same paths are synced again without change
+        table_a.add_direct(ConnId::from_out(0, 1), Metric::new(1, vec![1], 1));
+        assert_eq!(table_a.sync_delta_for(node_b, Some(gen)).map(|d| d.changes), Some(vec![]));
+
+        table_a.del_direct(ConnId::from_out(0, 2));
+        table_a.add_direct(ConnId::from_out(0, 6), Metric::new(1, vec![6], 1));
+        let delta = table_a.sync_delta_for(node_b, Some(gen)).expect("Should have sync");
+        assert_eq!(delta.changes, vec![(2, None), (6, Some(Metric::new(1, vec![6], 1)))]);
+        assert!(table_b.apply_sync_delta(conn_a, link.clone(), delta.clone()));
+        assert_eq!(table_b.slots(), vec![0, 1, 3, 4, 5, 6]);
+
+        //result is the same as a full sync
+        let mut table_c = Table::new(node_b, 0);
+        table_c.add_direct(conn_a, link.clone());
+        table_c.apply_sync(conn_a, link.clone(), table_a.sync_for(node_b).expect("Should have sync"));
+        assert_eq!(table_b.snapshot().diff(&table_c.snapshot()), vec![]);
+
+        //replayed or missing delta is a gap
+        assert!(!table_b.apply_sync_delta(conn_a, link.clone(), delta));
+        let missing = TableSyncDelta {
+            since: Some(gen + 100),
+            gen: gen + 101,
+            changes: vec![],
+        };
+        assert!(!table_b.apply_sync_delta(conn_a, link, missing));
+        assert_eq!(table_b.slots(), vec![0, 1, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn max_hops_reject_over_limit() {
+        let node0: NodeId = 0x0;
+        let node5: NodeId = 0x5;
+        let node6: NodeId = 0x6;
+        let conn1: ConnId = ConnId::from_out(0, 0x1);
+
+        let mut table = Table::new(node0, 0);
+        table.set_max_hops(Some(3));
+        table.add_direct(conn1, Metric::new(1, vec![1], 1));
+        //slot 5 is exactly at limit with saturated latency, slot 6 is one hop over
+        table.apply_sync(
+            conn1,
+            Metric::new(1, vec![1], 1),
+            TableSync(vec![(5, Metric::new(u16::MAX, vec![5, 4], 1)), (6, Metric::new(1, vec![6, 3, 2], 1))]),
+        );
+        assert_eq!(table.slots(), vec![1, 5]);
+        assert_eq!(table.next(node5, &[]), Some((conn1, 1)));
+        assert_eq!(table.next(node6, &[]), None);
+        assert_eq!(table.dests[&5].best_for(node6).map(|p| (p.1.latency, p.1.hops)), Some((MAX_LATENCY_MS, vec![5, 4, 1])));
+
+        //path which grows over the limit is removed
+        table.apply_sync(conn1, Metric::new(1, vec![1], 1), TableSync(vec![(5, Metric::new(1, vec![5, 4, 3], 1))]));
+        assert_eq!(table.slots(), vec![1]);
+        assert_eq!(table.next(node5, &[]), None);
+    }
+
+    #[test]
+    fn flap_damping_suppress_sync() {
+        let node0: NodeId = 0x0;
+        let node1: NodeId = 0x1;
+        let node2: NodeId = 0x2;
+        let node5: NodeId = 0x5;
+        let conn1: ConnId = ConnId::from_out(0, 0x1);
+        let conn2: ConnId = ConnId::from_out(0, 0x2);
+
+        let mut table = Table::new(node0, 0);
+        table.set_flap_damping(FlapDampingCfg {
+            penalty: 1000,
+            suppress_threshold: 2500,
+            half_life_ms: 1000,
+        });
+        table.add_direct(conn1, Metric::new(1, vec![1], 1));
+        table.add_direct(conn2, Metric::new(1, vec![2], 1));
+
+        let with_5 = TableSync(vec![(5, Metric::new(1, vec![5], 1))]);
+        let without_5 = TableSync(vec![]);
+        table.apply_sync(conn1, Metric::new(1, vec![1], 1), with_5.clone());
+        table.apply_sync(conn1, Metric::new(1, vec![1], 1), without_5.clone());
+
+        //penalty 2000 decays to 1000 after one half-life
+        table.on_tick(1000);
+        table.apply_sync(conn1, Metric::new(1, vec![1], 1), with_5.clone());
+        assert!(!table.is_suppressed(5));
+        assert!(table.sync_for(node2).expect("Should have sync").0.iter().any(|(i, _)| *i == 5));
+
+        table.apply_sync(conn1, Metric::new(1, vec![1], 1), without_5);
+        table.apply_sync(conn1, Metric::new(1, vec![1], 1), with_5);
+        assert!(table.is_suppressed(5));
+        //still forwarding but not advertising
+        assert_eq!(table.next(node5, &[]), Some((conn1, node1)));
+        assert!(!table.sync_for(node2).expect("Should have sync").0.iter().any(|(i, _)| *i == 5));
+
+        //penalty 4000 must decay below half of threshold
+        table.on_tick(2000);
+        assert!(table.is_suppressed(5));
+        table.on_tick(3000);
+        assert!(!table.is_suppressed(5));
+        assert!(table.sync_for(node2).expect("Should have sync").0.iter().any(|(i, _)| *i == 5));
+    }
+
+    #[test]
+    fn compare_mode_latency_first() {
+        let node0: NodeId = 0x0;
+        let node1: NodeId = 0x1;
+        let node2: NodeId = 0x2;
+        let node9: NodeId = 0x9;
+        let conn1: ConnId = ConnId::from_out(0, 0x1);
+        let conn2: ConnId = ConnId::from_out(0, 0x2);
+
+        let mut table = Table::new(node0, 0);
+        table.add_direct(conn1, Metric::new(10, vec![1], 10000));
+        table.add_direct(conn2, Metric::new(10, vec![2], 10000));
+        //2 hops with high latency over node1, 3 hops with lower latency over node2 and node3
+        table.apply_sync(conn1, Metric::new(10, vec![1], 10000), TableSync(vec![(9, Metric::new(50, vec![9], 10000))]));
+        table.apply_sync(conn2, Metric::new(10, vec![2], 10000), TableSync(vec![(9, Metric::new(45, vec![9, 3], 10000))]));
+        assert_eq!(table.next(node9, &[]), Some((conn1, node1)));
+        while table.pop_delta().is_some() {}
+
+        table.set_compare_mode(MetricCompareMode::LatencyFirst);
+        assert_eq!(table.next(node9, &[]), Some((conn2, node2)));
+        assert_eq!(table.pop_delta(), Some(TableDelta(9, DestDelta::SetBestPath(conn2))));
+        assert_eq!(table.pop_delta(), None);
+
+        //new syncs are ordered with the current mode
+        table.apply_sync(conn1, Metric::new(10, vec![1], 10000), TableSync(vec![(9, Metric::new(44, vec![9], 10000))]));
+        assert_eq!(table.next(node9, &[]), Some((conn1, node1)));
+
+        table.set_compare_mode(MetricCompareMode::HopsFirst);
+        table.apply_sync(conn1, Metric::new(10, vec![1], 10000), TableSync(vec![(9, Metric::new(80, vec![9], 10000))]));
+        assert_eq!(table.next(node9, &[]), Some((conn1, node1)));
+    }
+
+    #[test]
+    fn explain_matches_next() {
+        let node0: NodeId = 0x0;
+        let node1: NodeId = 0x1;
+        let node2: NodeId = 0x2;
+        let node9: NodeId = 0x9;
+        let conn1: ConnId = ConnId::from_out(0, 0x1);
+        let conn2: ConnId = ConnId::from_out(0, 0x2);
+
+        let mut table = Table::new(node0, 0);
+        table.add_direct(conn1, Metric::new(10, vec![1], 10000));
+        table.add_direct(conn2, Metric::new(10, vec![2], 10000));
+        table.apply_sync(conn1, Metric::new(10, vec![1], 10000), TableSync(vec![(9, Metric::new(50, vec![9], 10000))]));
+        table.apply_sync(conn2, Metric::new(10, vec![2], 10000), TableSync(vec![(9, Metric::new(45, vec![9, 3], 10000))]));
+
+        let check = |table: &Table, excepts: &[NodeId]| {
+            let explanation = table.explain(node9, excepts);
+            assert_eq!(explanation.candidates.len(), 2);
+            assert_eq!(explanation.chosen().map(|c| (c.conn, c.over)), table.next(node9, excepts));
+            explanation.reason
+        };
+
+        //one hop less is worth more than 5ms of latency in the score
+        assert_eq!(check(&table, &[]), Some(RouteReason::FewerHops));
+        assert_eq!(check(&table, &[node1]), Some(RouteReason::OnlyPath));
+        assert_eq!(check(&table, &[node1, node2]), None);
+        assert!(table.explain(node9, &[node1]).candidates[0].excepted);
+
+        table.set_compare_mode(MetricCompareMode::LatencyFirst);
+        assert_eq!(check(&table, &[]), Some(RouteReason::LowerLatency));
+
+        //a path under the bandwidth limit gets the score penalty
+        table.set_compare_mode(MetricCompareMode::Score);
+        table.apply_sync(conn1, Metric::new(10, vec![1], 10000), TableSync(vec![(9, Metric::new(50, vec![9], 100))]));
+        assert_eq!(check(&table, &[]), Some(RouteReason::HigherBandwidth));
+        assert_eq!(table.explain(node2, &[]).reason, Some(RouteReason::OnlyPath));
+        assert_eq!(table.explain(0x8, &[]).chosen(), None);
+    }
+
+    #[test]
+    fn ecmp_deltas() {
+        let node0: NodeId = 0x0;
+        let node1: NodeId = 0x1;
+        let node2: NodeId = 0x2;
+        let node5: NodeId = 0x5;
+        let conn1: ConnId = ConnId::from_out(0, 0x1);
+        let conn2: ConnId = ConnId::from_out(0, 0x2);
+
+        let mut table = Table::new(node0, 0);
+        table.add_direct(conn1, Metric::new(1, vec![1], 1));
+        table.add_direct(conn2, Metric::new(1, vec![2], 1));
+        table.apply_sync(conn1, Metric::new(1, vec![1], 1), TableSync(vec![(5, Metric::new(1, vec![5], 1))]));
+        table.apply_sync(conn2, Metric::new(1, vec![2], 1), TableSync(vec![(5, Metric::new(1, vec![5], 1))]));
+        while table.pop_delta().is_some() {}
+
+        //ecmp disabled by default
+        assert_eq!(table.next_ecmp(node5, &[], 0), vec![(conn1, node1), (conn2, node2)]);
+
+        table.set_ecmp_tolerance(Some(0));
+        assert_eq!(table.pop_delta(), Some(TableDelta(5, DestDelta::SetEcmpPaths(vec![(conn1, 1), (conn2, 1)]))));
+        assert_eq!(table.pop_delta(), None);
+
+        table.apply_sync(conn2, Metric::new(1, vec![2], 1), TableSync(vec![(5, Metric::new(1, vec![5], 1))]));
+        assert_eq!(table.pop_delta(), None);
+
+        //a bandwidth change re-weights the paths
+        table.apply_sync(conn2, Metric::new(1, vec![2], 3), TableSync(vec![(5, Metric::new(1, vec![5], 3))]));
+        assert_eq!(table.pop_delta(), Some(TableDelta(5, DestDelta::SetEcmpPaths(vec![(conn1, 1), (conn2, 3)]))));
+        assert_eq!(table.pop_delta(), None);
+
+        table.del_direct(conn2);
+        assert_eq!(table.pop_delta(), Some(TableDelta(2, DestDelta::DelBestPath)));
+        assert_eq!(table.pop_delta(), Some(TableDelta(5, DestDelta::SetEcmpPaths(vec![]))));
+        assert_eq!(table.pop_delta(), None);
+    }
+
+    #[test]
+    fn alternate_deltas() {
+        let node0: NodeId = 0x0;
+        let conn1: ConnId = ConnId::from_out(0, 0x1);
+        let conn2: ConnId = ConnId::from_out(0, 0x2);
+
+        let mut table = Table::new(node0, 0);
+        table.add_direct(conn1, Metric::new(1, vec![1], 1));
+        table.add_direct(conn2, Metric::new(1, vec![2], 1));
+        table.apply_sync(conn1, Metric::new(1, vec![1], 1), TableSync(vec![(5, Metric::new(1, vec![5], 1))]));
+        table.apply_sync(conn2, Metric::new(3, vec![2], 1), TableSync(vec![(5, Metric::new(1, vec![5], 1))]));
+        while table.pop_delta().is_some() {}
+
+        table.set_max_alternates(2);
+        assert_eq!(table.pop_delta(), Some(TableDelta(5, DestDelta::SetAlternatePaths(vec![conn2]))));
+        assert_eq!(table.pop_delta(), None);
+
+        //the alternate becomes the best path
+        table.del_direct(conn1);
+        assert_eq!(table.pop_delta(), Some(TableDelta(1, DestDelta::DelBestPath)));
+        assert_eq!(table.pop_delta(), Some(TableDelta(5, DestDelta::SetBestPath(conn2))));
+        assert_eq!(table.pop_delta(), Some(TableDelta(5, DestDelta::SetAlternatePaths(vec![]))));
+        assert_eq!(table.pop_delta(), None);
+    }
+
+    #[test]
+    fn closest_key_n() {
+        let node0: NodeId = 0x0;
+        let mut table = Table::new(node0, 0);
+        assert_eq!(table.closest_n(5, 2), vec![]);
+
+        let conn1: ConnId = ConnId::from_out(0, 0x1);
+        let conn4: ConnId = ConnId::from_out(0, 0x4);
+        let conn200: ConnId = ConnId::from_out(0, 200);
+
+        table.add_direct(conn1, Metric::new(1, vec![1], 1));
+        table.add_direct(conn4, Metric::new(1, vec![4], 1));
+        table.add_direct(conn200, Metric::new(1, vec![200], 1));
+
+        assert_eq!(table.closest_n(5, 2), vec![(4, conn4, 4), (1, conn1, 1)]);
+        assert_eq!(table.closest_n(201, 10), vec![(200, conn200, 200), (1, conn1, 1), (4, conn4, 4)]);
+        assert_eq!(table.closest_n(5, 0), vec![]);
+
+        // removed dest is skipped
+        table.del_direct(conn4);
+        assert_eq!(table.closest_n(5, 2), vec![(1, conn1, 1), (200, conn200, 200)]);
+    }
+
+    #[test]
+    fn snapshot_diff() {
+        let node0: NodeId = 0x0;
+        let mut table_a = Table::new(node0, 0);
+        let mut table_b = Table::new(node0, 0);
+
+        let conn1: ConnId = ConnId::from_out(0, 0x1);
+        let conn2: ConnId = ConnId::from_out(0, 0x2);
+        let conn3: ConnId = ConnId::from_out(0, 0x3);
+        let conn4: ConnId = ConnId::from_out(0, 0x4);
+
+        // same in both
+        table_a.add_direct(conn1, Metric::new(1, vec![1], 1));
+        table_b.add_direct(conn1, Metric::new(1, vec![1], 1));
+        // same next hop, different metric
+        table_a.add_direct(conn2, Metric::new(1, vec![2], 1));
+        table_b.add_direct(conn2, Metric::new(5, vec![2], 1));
+        // index 3 over different next hop
+        table_a.apply_sync(conn1, Metric::new(1, vec![1], 1), TableSync(vec![(3, Metric::new(1, vec![3], 1))]));
+        table_b.apply_sync(conn2, Metric::new(1, vec![2], 1), TableSync(vec![(3, Metric::new(1, vec![3], 1))]));
+        // only in one side
+        table_a.add_direct(conn4, Metric::new(1, vec![4], 1));
+        table_b.add_direct(conn3, Metric::new(1, vec![5], 1));
+
+        let snap_a = table_a.snapshot();
+        let snap_b = table_b.snapshot();
+        assert_eq!(snap_a.diff(&snap_a), vec![]);
+        assert_eq!(
+            snap_a.diff(&snap_b),
+            vec![
+                TableDiffEntry::Differs(2, (2, Metric::new(1, vec![2], 1)), (2, Metric::new(5, vec![2], 1))),
+                TableDiffEntry::Differs(3, (1, Metric::new(2, vec![3, 1], 1)), (2, Metric::new(2, vec![3, 2], 1))),
+                TableDiffEntry::OnlyInA(4, 4, Metric::new(1, vec![4], 1)),
+                TableDiffEntry::OnlyInB(5, 5, Metric::new(1, vec![5], 1)),
+            ]
+        );
+        assert_eq!(snap_b.diff(&snap_a).len(), 4);
+    }
+
     #[test]
     fn create_manual() {
         let node0: NodeId = 0x0;
@@ -383,4 +1203,111 @@ mod tests {
 
         assert_eq!(table.closest_for(254, &[]), Some((40, conn40, 40)));
     }
+
+    /// Table with a dest for each of the 256 indexes, like the table before dests became sparse
+    struct ArrayTable {
+        node_id: NodeId,
+        dests: Vec<Dest>,
+    }
+
+    impl ArrayTable {
+        fn new(node_id: NodeId) -> Self {
+            Self {
+                node_id,
+                dests: (0..256).map(|_| Dest::default()).collect(),
+            }
+        }
+
+        fn add_direct(&mut self, conn: ConnId, metric: Metric) {
+            let index = metric.over_node().layer(0) as usize;
+            self.dests[index].set_path(conn, metric, MetricCompareMode::default(), 0);
+        }
+
+        fn del_direct(&mut self, conn: ConnId) {
+            for dest in self.dests.iter_mut() {
+                dest.del_path(conn);
+            }
+        }
+
+        fn apply_sync(&mut self, conn: ConnId, metric: Metric, sync: TableSync) {
+            let src = metric.over_node().layer(0);
+            for i in 0..=255_u8 {
+                if i == self.node_id.layer(0) {
+                    continue;
+                }
+                match sync.0.iter().find(|(index, _)| *index == i) {
+                    Some((_, s_metric)) => self.dests[i as usize].set_path(conn, s_metric.add(&metric), MetricCompareMode::default(), 0),
+                    None if !self.dests[i as usize].is_empty() && src != i => {
+                        self.dests[i as usize].del_path(conn);
+                    }
+                    None => {}
+                }
+            }
+        }
+
+        fn sync_for(&self, node: NodeId) -> Vec<(u8, Metric)> {
+            (0..=255_u8)
+                .filter(|i| *i != self.node_id.layer(0))
+                .filter_map(|i| self.dests[i as usize].best_for(node).map(|Path(_, metric)| (i, metric)))
+                .collect()
+        }
+    }
+
+    fn assert_same_metrics(a: &[(u8, Metric)], b: &[(u8, Metric)]) {
+        assert_eq!(a.len(), b.len());
+        for ((index_a, metric_a), (index_b, metric_b)) in a.iter().zip(b.iter()) {
+            assert_eq!(index_a, index_b);
+            assert!(metric_a.same_as(metric_b), "{:?} vs {:?}", metric_a, metric_b);
+        }
+    }
+
+    #[test]
+    fn sparse_dests_match_array_table() {
+        let node0: NodeId = 0;
+        let mut table = Table::new(node0, 0);
+        let mut reference = ArrayTable::new(node0);
+        let mut rng = StdRng::seed_from_u64(1341);
+
+        for _ in 0..2000 {
+            let neighbour: NodeId = rng.gen_range(1..8);
+            let conn = ConnId::from_out(0, neighbour as u64);
+            let link = Metric::new(rng.gen_range(1..20), vec![neighbour], 1000);
+            match rng.gen_range(0..10) {
+                0..=2 => {
+                    table.add_direct(conn, link.clone());
+                    reference.add_direct(conn, link);
+                }
+                3 => {
+                    table.del_direct(conn);
+                    reference.del_direct(conn);
+                }
+                _ => {
+                    let sync = TableSync(
+                        (0..rng.gen_range(0..6))
+                            .map(|_| rng.gen_range(1..40_u8))
+                            .collect::<std::collections::BTreeSet<_>>()
+                            .into_iter()
+                            .map(|index| (index, Metric::new(rng.gen_range(1..50), vec![index as NodeId], 1000)))
+                            .collect(),
+                    );
+                    table.apply_sync(conn, link.clone(), sync.clone());
+                    reference.apply_sync(conn, link, sync);
+                }
+            }
+            while table.pop_delta().is_some() {}
+
+            let used: Vec<u8> = (0..=255_u8).filter(|i| !reference.dests[*i as usize].is_empty()).collect();
+            assert_eq!(table.slots(), used);
+            assert_eq!(table.size(), used.len());
+            //empty dests are released
+            assert_eq!(table.dests.len(), used.len());
+            for i in 0..=255_u8 {
+                assert_eq!(table.next(i as NodeId, &[]), reference.dests[i as usize].next(&[]));
+            }
+            for node in 1..8 {
+                let sync = table.sync_for(node).expect("Should have sync");
+                assert_same_metrics(&sync.0, &reference.sync_for(node));
+            }
+        }
+    }
 }
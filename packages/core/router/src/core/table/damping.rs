@@ -0,0 +1,129 @@
+/// Damping of slots which toggle between reachable and unreachable too often.
+///
+/// Each toggle adds `penalty`, which then decays exponentially with `half_life_ms`. A slot is
+/// suppressed once its penalty exceeds `suppress_threshold`, and is released when the penalty
+/// decays below half of the threshold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlapDampingCfg {
+    pub penalty: u32,
+    pub suppress_threshold: u32,
+    pub half_life_ms: u64,
+}
+
+impl Default for FlapDampingCfg {
+    fn default() -> Self {
+        Self {
+            penalty: 1000,
+            suppress_threshold: 3000,
+            half_life_ms: 15_000,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FlapState {
+    penalty: f64,
+    updated_ms: u64,
+    suppressed: bool,
+}
+
+impl FlapState {
+    pub fn new(now_ms: u64) -> Self {
+        Self {
+            penalty: 0.0,
+            updated_ms: now_ms,
+            suppressed: false,
+        }
+    }
+
+    pub fn suppressed(&self) -> bool {
+        self.suppressed
+    }
+
+    /// Add penalty of a toggle, return true if the slot has just become suppressed
+    pub fn on_toggle(&mut self, now_ms: u64, cfg: &FlapDampingCfg) -> bool {
+        self.decay(now_ms, cfg);
+        self.penalty += cfg.penalty as f64;
+        if !self.suppressed && self.penalty > cfg.suppress_threshold as f64 {
+            self.suppressed = true;
+            return true;
+        }
+        false
+    }
+
+    /// Decay penalty, return true if the slot has just been released
+    pub fn on_tick(&mut self, now_ms: u64, cfg: &FlapDampingCfg) -> bool {
+        self.decay(now_ms, cfg);
+        if self.suppressed && self.penalty < cfg.suppress_threshold as f64 / 2.0 {
+            self.suppressed = false;
+            return true;
+        }
+        false
+    }
+
+    /// Nothing left to remember, the state can be dropped
+    pub fn is_idle(&self) -> bool {
+        !self.suppressed && self.penalty < 1.0
+    }
+
+    fn decay(&mut self, now_ms: u64, cfg: &FlapDampingCfg) {
+        let elapsed = now_ms.saturating_sub(self.updated_ms);
+        if cfg.half_life_ms == 0 {
+            self.penalty = 0.0;
+        } else {
+            self.penalty *= 0.5_f64.powf(elapsed as f64 / cfg.half_life_ms as f64);
+        }
+        self.updated_ms = now_ms;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FlapDampingCfg, FlapState};
+
+    const CFG: FlapDampingCfg = FlapDampingCfg {
+        penalty: 1000,
+        suppress_threshold: 2500,
+        half_life_ms: 1000,
+    };
+
+    #[test]
+    fn suppress_after_threshold() {
+        let mut state = FlapState::new(0);
+        assert!(!state.on_toggle(0, &CFG));
+        assert!(!state.on_toggle(0, &CFG));
+        assert!(!state.suppressed());
+        assert!(state.on_toggle(0, &CFG));
+        assert!(state.suppressed());
+        //already suppressed
+        assert!(!state.on_toggle(0, &CFG));
+    }
+
+    #[test]
+    fn decay_by_half_life() {
+        let mut state = FlapState::new(0);
+        state.on_toggle(0, &CFG);
+        state.on_toggle(0, &CFG);
+        //penalty 2000 => 1000 after one half-life, so the next toggle doesn't reach threshold
+        assert!(!state.on_toggle(1000, &CFG));
+        assert!(!state.suppressed());
+    }
+
+    #[test]
+    fn release_below_half_threshold() {
+        let mut state = FlapState::new(0);
+        for _ in 0..3 {
+            state.on_toggle(0, &CFG);
+        }
+        assert!(state.suppressed());
+        //3000 => 1500 is still above 1250
+        assert!(!state.on_tick(1000, &CFG));
+        assert!(state.suppressed());
+        //1500 => 750
+        assert!(state.on_tick(2000, &CFG));
+        assert!(!state.suppressed());
+        assert!(!state.is_idle());
+        state.on_tick(20000, &CFG);
+        assert!(state.is_idle());
+    }
+}
@@ -3,12 +3,18 @@ use std::collections::{HashMap, VecDeque};
 use atm0s_sdn_identity::{ConnId, NodeId};
 use serde::Serialize;
 
-use super::{Metric, Path};
+use super::{Metric, MetricCompareMode, Path};
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum DestDelta {
     SetBestPath(ConnId),
     DelBestPath,
+    /// Equal-cost paths or their bandwidth changed, only emitted by Table when ecmp is enabled. Empty means back to single best path.
+    /// Each path comes with its bandwidth in kbps, which weights the share of flows it gets
+    SetEcmpPaths(Vec<(ConnId, u32)>),
+    /// Next best paths after the best one, best first, only emitted by Table when alternates are enabled. Empty means no alternate.
+    /// They are used when the connections of the best or equal-cost paths are gone before the table is updated
+    SetAlternatePaths(Vec<ConnId>),
 }
 
 #[derive(Debug, Serialize, Clone, PartialEq, Eq)]
@@ -16,28 +22,66 @@ pub struct DestDump(HashMap<NodeId, Metric>);
 
 #[derive(Debug, Default)]
 pub struct Dest {
-    paths: Vec<Path>,
+    /// Paths sorted from best to worst, each with the time it was last set
+    paths: Vec<(Path, u64)>,
     deltas: VecDeque<DestDelta>,
+    /// Paths or their order changed since the last `take_changed`
+    changed: bool,
 }
 
 impl Dest {
     pub fn dump(&self) -> DestDump {
-        DestDump(self.paths.iter().map(|p| (p.1.over_node(), p.1.clone())).collect())
+        DestDump(self.paths.iter().map(|(p, _)| (p.1.over_node(), p.1.clone())).collect())
     }
 
-    pub fn set_path(&mut self, over: ConnId, metric: Metric) {
-        let pre_best_conn = self.paths.first().map(|p| p.0);
+    pub fn set_path(&mut self, over: ConnId, metric: Metric, mode: MetricCompareMode, now_ms: u64) {
+        let pre_best_conn = self.best_conn();
         match self.index_of(over) {
             Some(index) => {
                 let slot = &mut self.paths[index];
-                slot.1 = metric;
+                if !slot.0 .1.same_as(&metric) {
+                    slot.0 .1 = metric;
+                    self.changed = true;
+                }
+                slot.1 = now_ms;
             }
             None => {
-                self.paths.push(Path(over, metric));
+                self.paths.push((Path(over, metric), now_ms));
+                self.changed = true;
             }
         }
-        self.paths.sort();
-        let after_best_conn = self.paths.first().map(|p| p.0);
+        self.sort_paths(pre_best_conn, mode);
+    }
+
+    /// Refresh the time of the path over `over` without changing it, for syncs which only carry changes
+    pub fn touch(&mut self, over: ConnId, now_ms: u64) {
+        if let Some(index) = self.index_of(over) {
+            self.paths[index].1 = now_ms;
+        }
+    }
+
+    /// Reorder paths after compare mode changed
+    pub fn resort(&mut self, mode: MetricCompareMode) {
+        let pre_best_conn = self.best_conn();
+        let pre_order: Vec<ConnId> = self.paths.iter().map(|(p, _)| p.0).collect();
+        self.sort_paths(pre_best_conn, mode);
+        if !self.paths.iter().map(|(p, _)| p.0).eq(pre_order) {
+            self.changed = true;
+        }
+    }
+
+    /// Return true if paths changed since last call
+    pub fn take_changed(&mut self) -> bool {
+        std::mem::replace(&mut self.changed, false)
+    }
+
+    fn sort_paths(&mut self, pre_best_conn: Option<ConnId>, mode: MetricCompareMode) {
+        self.paths.sort_by(|a, b| mode.cmp(&a.0 .1, &b.0 .1));
+        self.check_best_changed(pre_best_conn);
+    }
+
+    fn check_best_changed(&mut self, pre_best_conn: Option<ConnId>) {
+        let after_best_conn = self.best_conn();
         if pre_best_conn != after_best_conn {
             if let Some(conn) = after_best_conn {
                 self.deltas.push_back(DestDelta::SetBestPath(conn));
@@ -48,21 +92,34 @@ impl Dest {
     }
 
     pub fn del_path(&mut self, over: ConnId) -> Option<Path> {
-        match self.index_of(over) {
-            Some(index) => {
-                if index == 0 {
-                    //if remove first => changed best
-                    let after_best_conn = self.paths.get(1).map(|p| p.0);
-                    if let Some(conn) = after_best_conn {
-                        self.deltas.push_back(DestDelta::SetBestPath(conn));
-                    } else {
-                        self.deltas.push_back(DestDelta::DelBestPath);
-                    }
-                }
-                Some(self.paths.remove(index))
-            }
-            None => None,
+        let index = self.index_of(over)?;
+        let pre_best_conn = self.best_conn();
+        let (path, _) = self.paths.remove(index);
+        self.changed = true;
+        self.check_best_changed(pre_best_conn);
+        Some(path)
+    }
+
+    /// Remove indirect paths which are not set since `before_ms`, return removed paths.
+    /// Direct paths are kept, they are removed when the connection is closed
+    pub fn del_expired(&mut self, before_ms: u64) -> Vec<Path> {
+        let is_expired = |path: &Path, updated_ms: u64| updated_ms < before_ms && path.1.hops.len() > 1;
+        if !self.paths.iter().any(|(path, updated_ms)| is_expired(path, *updated_ms)) {
+            return vec![];
         }
+        let pre_best_conn = self.best_conn();
+        let mut expired = vec![];
+        self.paths.retain(|(path, updated_ms)| {
+            if is_expired(path, *updated_ms) {
+                expired.push(path.clone());
+                false
+            } else {
+                true
+            }
+        });
+        self.changed = true;
+        self.check_best_changed(pre_best_conn);
+        expired
     }
 
     pub fn pop_delta(&mut self) -> Option<DestDelta> {
@@ -73,9 +130,14 @@ impl Dest {
         self.paths.is_empty()
     }
 
+    /// Paths from best to worst
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.paths.iter().map(|(path, _)| path)
+    }
+
     /// get next node to dest but not in excepts
     pub fn next(&self, excepts: &[NodeId]) -> Option<(ConnId, NodeId)> {
-        for path in self.paths.iter() {
+        for (path, _) in self.paths.iter() {
             if !excepts.contains(&path.1.over_node()) {
                 return Some((path.0, path.1.over_node()));
             }
@@ -83,8 +145,23 @@ impl Dest {
         None
     }
 
+    /// Get the best path not in excepts which bottleneck bandwidth is at least `min_bw`.
+    /// If there is none, fall back to the path with highest bandwidth, the last value is true for this degraded case
+    pub fn next_with_min_bw(&self, min_bw: u32, excepts: &[NodeId]) -> Option<(ConnId, NodeId, bool)> {
+        let mut widest: Option<&Path> = None;
+        for path in self.paths.iter().map(|(p, _)| p).filter(|p| !excepts.contains(&p.1.over_node())) {
+            if path.1.bandwidth >= min_bw {
+                return Some((path.0, path.1.over_node(), false));
+            }
+            if widest.map_or(true, |w| path.1.bandwidth > w.1.bandwidth) {
+                widest = Some(path);
+            }
+        }
+        widest.map(|p| (p.0, p.1.over_node(), true))
+    }
+
     pub fn best_for(&self, neighbour_id: NodeId) -> Option<Path> {
-        for path in self.paths.iter() {
+        for (path, _) in self.paths.iter() {
             if !path.1.contain_in_hops(neighbour_id) {
                 return Some(path.clone());
             }
@@ -93,7 +170,7 @@ impl Dest {
     }
 
     pub fn next_path(&self, excepts: &[NodeId]) -> Option<Path> {
-        for path in self.paths.iter() {
+        for (path, _) in self.paths.iter() {
             if !excepts.contains(&path.1.over_node()) {
                 return Some(path.clone());
             }
@@ -101,17 +178,31 @@ impl Dest {
         None
     }
 
-    fn index_of(&self, goal: ConnId) -> Option<usize> {
-        if self.paths.is_empty() {
-            return None;
-        }
-        for (index, path) in self.paths.iter().enumerate() {
-            if path.0 == goal {
-                return Some(index);
-            }
-        }
+    /// Get all paths which are not in excepts and have score not greater than best score + tolerance.
+    /// The best path is always the first one.
+    pub fn next_ecmp(&self, excepts: &[NodeId], tolerance: u32) -> Vec<(ConnId, NodeId)> {
+        self.ecmp_paths(excepts, tolerance).into_iter().map(|p| (p.0, p.1.over_node())).collect()
+    }
 
-        None
+    /// Same paths as `next_ecmp`, with their metrics
+    pub fn ecmp_paths(&self, excepts: &[NodeId], tolerance: u32) -> Vec<&Path> {
+        let mut paths = self.paths.iter().map(|(p, _)| p).filter(|p| !excepts.contains(&p.1.over_node()));
+        let best = match paths.next() {
+            Some(best) => best,
+            None => return vec![],
+        };
+        let max_score = best.1.score().saturating_add(tolerance);
+        let mut res = vec![best];
+        res.extend(paths.take_while(|p| p.1.score() <= max_score));
+        res
+    }
+
+    fn best_conn(&self) -> Option<ConnId> {
+        self.paths.first().map(|(p, _)| p.0)
+    }
+
+    fn index_of(&self, goal: ConnId) -> Option<usize> {
+        self.paths.iter().position(|(path, _)| path.0 == goal)
     }
 }
 
@@ -119,7 +210,7 @@ impl Dest {
 mod tests {
     use atm0s_sdn_identity::{ConnId, NodeId};
 
-    use crate::core::{table::Dest, DestDelta, Metric, Path};
+    use crate::core::{table::Dest, DestDelta, Metric, MetricCompareMode, Path};
 
     #[test]
     fn push_sort() {
@@ -133,9 +224,9 @@ mod tests {
         let node3: NodeId = 0x3;
 
         let mut dest = Dest::default();
-        dest.set_path(conn1, Metric::new(1, vec![4, 1], 1)); //directed connection
+        dest.set_path(conn1, Metric::new(1, vec![4, 1], 1), MetricCompareMode::Score, 0); //directed connection
         assert_eq!(dest.pop_delta(), Some(DestDelta::SetBestPath(conn1)));
-        dest.set_path(conn2, Metric::new(2, vec![4, 2], 1));
+        dest.set_path(conn2, Metric::new(2, vec![4, 2], 1), MetricCompareMode::Score, 0);
         assert_eq!(dest.pop_delta(), None);
 
         assert_eq!(dest.next(&[]), Some((conn1, node1)));
@@ -146,6 +237,29 @@ mod tests {
         assert_eq!(dest.next_path(&[node1, node2]), None);
     }
 
+    #[test]
+    fn next_with_min_bw() {
+        let conn1: ConnId = ConnId::from_out(0, 0x1);
+        let node1: NodeId = 0x1;
+        let conn2: ConnId = ConnId::from_out(0, 0x2);
+        let node2: NodeId = 0x2;
+        let conn3: ConnId = ConnId::from_out(0, 0x3);
+        let node3: NodeId = 0x3;
+
+        let mut dest = Dest::default();
+        dest.set_path(conn1, Metric::new(1, vec![4, 1], 20000), MetricCompareMode::Score, 0);
+        dest.set_path(conn2, Metric::new(2, vec![4, 2], 50000), MetricCompareMode::Score, 0);
+        dest.set_path(conn3, Metric::new(3, vec![4, 3], 100000), MetricCompareMode::Score, 0);
+
+        assert_eq!(dest.next_with_min_bw(0, &[]), Some((conn1, node1, false)));
+        assert_eq!(dest.next_with_min_bw(30000, &[]), Some((conn2, node2, false)));
+        assert_eq!(dest.next_with_min_bw(100000, &[]), Some((conn3, node3, false)));
+        //nothing is wide enough, use the widest one which is not excepted
+        assert_eq!(dest.next_with_min_bw(200000, &[]), Some((conn3, node3, true)));
+        assert_eq!(dest.next_with_min_bw(200000, &[node3]), Some((conn2, node2, true)));
+        assert_eq!(dest.next_with_min_bw(200000, &[node1, node2, node3]), None);
+    }
+
     #[test]
     fn delete_sort() {
         let conn1: ConnId = ConnId::from_out(0, 0x1);
@@ -158,10 +272,10 @@ mod tests {
         let node3: NodeId = 0x3;
 
         let mut dest = Dest::default();
-        dest.set_path(conn1, Metric::new(1, vec![4, 1], 1));
+        dest.set_path(conn1, Metric::new(1, vec![4, 1], 1), MetricCompareMode::Score, 0);
         assert_eq!(dest.pop_delta(), Some(DestDelta::SetBestPath(conn1)));
-        dest.set_path(conn2, Metric::new(2, vec![4, 6, 2], 1));
-        dest.set_path(conn3, Metric::new(3, vec![4, 6, 2, 3], 1));
+        dest.set_path(conn2, Metric::new(2, vec![4, 6, 2], 1), MetricCompareMode::Score, 0);
+        dest.set_path(conn3, Metric::new(3, vec![4, 6, 2, 3], 1), MetricCompareMode::Score, 0);
         assert_eq!(dest.pop_delta(), None);
 
         dest.del_path(conn1);
@@ -173,6 +287,50 @@ mod tests {
         assert_eq!(dest.next_path(&[node3]), Some(Path(conn2, Metric::new(2, vec![4, 6, 2], 1))));
     }
 
+    #[test]
+    fn next_ecmp() {
+        let conn1: ConnId = ConnId::from_out(0, 0x1);
+        let conn2: ConnId = ConnId::from_out(0, 0x2);
+        let conn3: ConnId = ConnId::from_out(0, 0x3);
+
+        let mut dest = Dest::default();
+        assert_eq!(dest.next_ecmp(&[], 0), vec![]);
+        dest.set_path(conn1, Metric::new(10, vec![4, 1], 1), MetricCompareMode::Score, 0);
+        assert_eq!(dest.next_ecmp(&[], 0), vec![(conn1, 1)]);
+        dest.set_path(conn2, Metric::new(10, vec![4, 2], 1), MetricCompareMode::Score, 0);
+        dest.set_path(conn3, Metric::new(13, vec![4, 3], 1), MetricCompareMode::Score, 0);
+
+        assert_eq!(dest.next_ecmp(&[], 0), vec![(conn1, 1), (conn2, 2)]);
+        assert_eq!(dest.next_ecmp(&[], 3), vec![(conn1, 1), (conn2, 2), (conn3, 3)]);
+        assert_eq!(dest.next_ecmp(&[1], 0), vec![(conn2, 2)]);
+        assert_eq!(dest.next_ecmp(&[1, 2], 0), vec![(conn3, 3)]);
+    }
+
+    #[test]
+    fn del_expired() {
+        let conn1: ConnId = ConnId::from_out(0, 0x1);
+        let conn2: ConnId = ConnId::from_out(0, 0x2);
+        let conn3: ConnId = ConnId::from_out(0, 0x3);
+
+        let mut dest = Dest::default();
+        dest.set_path(conn1, Metric::new(1, vec![4, 1], 1), MetricCompareMode::Score, 0);
+        dest.set_path(conn2, Metric::new(2, vec![4, 2], 1), MetricCompareMode::Score, 100);
+        dest.set_path(conn3, Metric::new(30, vec![4], 1), MetricCompareMode::Score, 0);
+        assert_eq!(dest.pop_delta(), Some(DestDelta::SetBestPath(conn1)));
+        assert_eq!(dest.pop_delta(), None);
+
+        assert_eq!(dest.del_expired(0), vec![]);
+        assert_eq!(dest.del_expired(50), vec![Path(conn1, Metric::new(1, vec![4, 1], 1))]);
+        assert_eq!(dest.pop_delta(), Some(DestDelta::SetBestPath(conn2)));
+
+        //refreshed path is kept, direct path is never expired
+        dest.set_path(conn2, Metric::new(2, vec![4, 2], 1), MetricCompareMode::Score, 200);
+        assert_eq!(dest.del_expired(150), vec![]);
+        assert_eq!(dest.del_expired(1000), vec![Path(conn2, Metric::new(2, vec![4, 2], 1))]);
+        assert_eq!(dest.pop_delta(), Some(DestDelta::SetBestPath(conn3)));
+        assert_eq!(dest.next(&[]), Some((conn3, 4)));
+    }
+
     #[test]
     fn with_hops() {
         let conn1: ConnId = ConnId::from_out(0, 0x1);
@@ -189,7 +347,7 @@ mod tests {
 
         let mut dest = Dest::default();
         //this path from 3 => 2 => 1
-        dest.set_path(conn1, Metric::new(1, vec![3, 2, 1], 1));
+        dest.set_path(conn1, Metric::new(1, vec![3, 2, 1], 1), MetricCompareMode::Score, 0);
 
         assert_eq!(dest.best_for(node4), Some(Path(conn1, Metric::new(1, vec![3, 2, 1], 1))));
         assert_eq!(dest.best_for(node1), None);
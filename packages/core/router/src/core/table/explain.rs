@@ -0,0 +1,100 @@
+use atm0s_sdn_identity::{ConnId, NodeId};
+use serde::Serialize;
+
+use super::{metric::HOP_PLUS_RTT, Metric, MetricCompareMode, Path, BANDWIDTH_LIMIT};
+
+/// Why the chosen path is ordered before the next usable one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum RouteReason {
+    /// No other path is usable
+    OnlyPath,
+    FewerHops,
+    LowerLatency,
+    HigherBandwidth,
+    /// Same order, the path which was ordered first is kept
+    Tie,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RouteCandidate {
+    pub conn: ConnId,
+    pub over: NodeId,
+    pub metric: Metric,
+    pub score: u32,
+    /// The path goes over a node of `excepts`, so it is skipped
+    pub excepted: bool,
+}
+
+/// Paths which the table considered for a destination, see [`super::Table::explain`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RouteExplanation {
+    pub dest: NodeId,
+    pub layer: u8,
+    pub mode: MetricCompareMode,
+    /// All paths of the destination, in the order they are selected
+    pub candidates: Vec<RouteCandidate>,
+    /// Index in `candidates` of the path which `next` returns
+    pub chosen: Option<usize>,
+    /// None if there is no usable path
+    pub reason: Option<RouteReason>,
+}
+
+impl RouteExplanation {
+    pub(crate) fn new<'a>(dest: NodeId, layer: u8, mode: MetricCompareMode, paths: impl Iterator<Item = &'a Path>, excepts: &[NodeId]) -> Self {
+        let candidates: Vec<RouteCandidate> = paths
+            .map(|path| RouteCandidate {
+                conn: path.0,
+                over: path.1.over_node(),
+                metric: path.1.clone(),
+                score: path.1.score(),
+                excepted: excepts.contains(&path.1.over_node()),
+            })
+            .collect();
+        let mut usable = candidates.iter().enumerate().filter(|(_, c)| !c.excepted);
+        let (chosen, reason) = match usable.next() {
+            Some((index, winner)) => {
+                let reason = match usable.next() {
+                    Some((_, other)) => reason_of(mode, &winner.metric, &other.metric),
+                    None => RouteReason::OnlyPath,
+                };
+                (Some(index), Some(reason))
+            }
+            None => (None, None),
+        };
+        Self {
+            dest,
+            layer,
+            mode,
+            candidates,
+            chosen,
+            reason,
+        }
+    }
+
+    pub fn chosen(&self) -> Option<&RouteCandidate> {
+        self.candidates.get(self.chosen?)
+    }
+}
+
+/// The first key of the compare mode which differs, then the part of the score which makes most of the difference
+fn reason_of(mode: MetricCompareMode, winner: &Metric, other: &Metric) -> RouteReason {
+    match mode {
+        MetricCompareMode::HopsFirst if winner.hops.len() != other.hops.len() => return RouteReason::FewerHops,
+        MetricCompareMode::LatencyFirst if winner.latency != other.latency => return RouteReason::LowerLatency,
+        MetricCompareMode::BandwidthFirst if winner.bandwidth != other.bandwidth => return RouteReason::HigherBandwidth,
+        _ => {}
+    }
+    if winner.score() == other.score() {
+        return RouteReason::Tie;
+    }
+    if winner.bandwidth >= BANDWIDTH_LIMIT && other.bandwidth < BANDWIDTH_LIMIT {
+        return RouteReason::HigherBandwidth;
+    }
+    let hops_gain = (other.hops.len() as i64 - winner.hops.len() as i64) * HOP_PLUS_RTT as i64;
+    let latency_gain = other.latency as i64 - winner.latency as i64;
+    if hops_gain >= latency_gain {
+        RouteReason::FewerHops
+    } else {
+        RouteReason::LowerLatency
+    }
+}
@@ -5,7 +5,9 @@ use serde::{Deserialize, Serialize};
 
 pub const BANDWIDTH_LIMIT: u32 = 10000; //10Mbps
 const BANDWIDTH_SCORE_PENALTY: u32 = 1000; //1s
-const HOP_PLUS_RTT: u16 = 10; //10ms each hops
+pub(super) const HOP_PLUS_RTT: u16 = 10; //10ms each hops
+/// Latency of a path saturates at this value instead of overflowing
+pub const MAX_LATENCY_MS: u16 = 60000;
 
 /// Concatenate two hops array, with condition that the last hop of `a` is the first hop of `b`, if not return None
 pub fn concat_hops(a: &[NodeId], b: &[NodeId]) -> Vec<NodeId> {
@@ -36,14 +38,25 @@ impl Metric {
         self.hops.contains(&node_id)
     }
 
+    /// Latency saturates at [`MAX_LATENCY_MS`] so deep or slow paths are kept as the worst choice instead of overflowing.
+    /// Hops are never truncated since they are used for loop detection, paths over a hop limit are rejected with [`Metric::within_hops`]
     pub fn add(&self, other: &Self) -> Self {
         Metric {
-            latency: self.latency + other.latency,
+            latency: self.latency.saturating_add(other.latency).min(MAX_LATENCY_MS),
             hops: concat_hops(&self.hops, &other.hops),
             bandwidth: std::cmp::min(self.bandwidth, other.bandwidth),
         }
     }
 
+    /// Exact compare, unlike `==` which only compares by score
+    pub fn same_as(&self, other: &Self) -> bool {
+        self.latency == other.latency && self.hops == other.hops && self.bandwidth == other.bandwidth
+    }
+
+    pub fn within_hops(&self, max_hops: Option<usize>) -> bool {
+        max_hops.map_or(true, |max_hops| self.hops.len() <= max_hops)
+    }
+
     pub fn score(&self) -> u32 {
         let based_score = self.latency as u32 + (self.hops.len() as u32 * HOP_PLUS_RTT as u32);
         if self.bandwidth >= BANDWIDTH_LIMIT {
@@ -62,6 +75,32 @@ impl Metric {
     }
 }
 
+/// How paths to the same destination are ordered, every node in a network should use the same mode
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MetricCompareMode {
+    /// Combined score of latency, hops and bandwidth penalty, see [`Metric::score`]
+    #[default]
+    Score,
+    /// Fewer hops first, then score
+    HopsFirst,
+    /// Lower accumulated latency first even with more hops, then score
+    LatencyFirst,
+    /// Higher bottleneck bandwidth first, then score
+    BandwidthFirst,
+}
+
+impl MetricCompareMode {
+    pub fn cmp(&self, a: &Metric, b: &Metric) -> Ordering {
+        let first = match self {
+            MetricCompareMode::Score => Ordering::Equal,
+            MetricCompareMode::HopsFirst => a.hops.len().cmp(&b.hops.len()),
+            MetricCompareMode::LatencyFirst => a.latency.cmp(&b.latency),
+            MetricCompareMode::BandwidthFirst => b.bandwidth.cmp(&a.bandwidth),
+        };
+        first.then_with(|| a.score().cmp(&b.score()))
+    }
+}
+
 impl Ord for Metric {
     fn cmp(&self, other: &Self) -> Ordering {
         self.score().cmp(&other.score())
@@ -84,7 +123,9 @@ impl PartialEq<Self> for Metric {
 
 #[cfg(test)]
 mod tests {
-    use super::Metric;
+    use std::cmp::Ordering;
+
+    use super::{Metric, MetricCompareMode, MAX_LATENCY_MS};
 
     #[test]
     fn eq() {
@@ -123,6 +164,20 @@ mod tests {
         assert!(m2 == m4);
     }
 
+    #[test]
+    fn compare_mode() {
+        let two_hops_slow = Metric::new(60, vec![9, 1], 10000);
+        let three_hops_fast = Metric::new(55, vec![9, 3, 2], 10000);
+        let three_hops_wide = Metric::new(55, vec![9, 3, 2], 20000);
+
+        assert_eq!(MetricCompareMode::Score.cmp(&two_hops_slow, &three_hops_fast), Ordering::Less);
+        assert_eq!(MetricCompareMode::HopsFirst.cmp(&two_hops_slow, &three_hops_fast), Ordering::Less);
+        assert_eq!(MetricCompareMode::LatencyFirst.cmp(&two_hops_slow, &three_hops_fast), Ordering::Greater);
+        assert_eq!(MetricCompareMode::BandwidthFirst.cmp(&two_hops_slow, &three_hops_fast), Ordering::Less);
+        assert_eq!(MetricCompareMode::BandwidthFirst.cmp(&two_hops_slow, &three_hops_wide), Ordering::Greater);
+        assert_eq!(MetricCompareMode::LatencyFirst.cmp(&three_hops_fast, &three_hops_wide), Ordering::Equal);
+    }
+
     #[test]
     fn add() {
         let m1 = Metric::new(1, vec![1, 2], 10000);
@@ -130,6 +185,16 @@ mod tests {
         assert_eq!(m1.add(&m2), Metric::new(3, vec![1, 2, 3], 10000));
     }
 
+    #[test]
+    fn add_saturate_latency() {
+        let m1 = Metric::new(MAX_LATENCY_MS - 1, vec![1, 2], 10000);
+        let m2 = Metric::new(u16::MAX, vec![3], 20000);
+        assert_eq!(m1.add(&m2), Metric::new(MAX_LATENCY_MS, vec![1, 2, 3], 10000));
+        assert!(m1.add(&m2).within_hops(Some(3)));
+        assert!(!m1.add(&m2).within_hops(Some(2)));
+        assert!(m1.add(&m2).within_hops(None));
+    }
+
     #[test]
     fn hops_has_affect_latancy() {
         let m1 = Metric::new(1, vec![1, 2], 10000);
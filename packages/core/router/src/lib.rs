@@ -1,10 +1,15 @@
 #![allow(clippy::bool_assert_comparison)]
 
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
 use atm0s_sdn_identity::{NodeId, NodeIdType};
 pub mod core;
 pub mod shadow;
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub enum ServiceBroadcastLevel {
     Global,
     Geo1,
@@ -45,7 +50,7 @@ impl From<u8> for ServiceBroadcastLevel {
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub enum RouteRule {
     Direct,
     ToNode(NodeId),
@@ -53,6 +58,12 @@ pub enum RouteRule {
     /// First is service id, second is the level, and third is seq of message
     ToServices(u8, ServiceBroadcastLevel, u16),
     ToKey(NodeId),
+    /// Key and replica index, 0 is the closest node to key same as `ToKey`, others are the closest node to [`replica_key`].
+    /// Each hop ranks by the same derived key, so all sources reach the same replica. In small networks replicas can be the same node
+    ToKeyReplica(NodeId, u8),
+    /// Like global `ToServices` but limited to nodes within a number of hops: service id, seq of message, then radius.
+    /// Radius 0 is local only, 1 reaches the neighbours, the limit is applied with the ttl by the data plane
+    BroadcastScoped(u8, u16, u8),
 }
 
 /// Determine the destination of an action/message
@@ -64,6 +75,8 @@ pub enum RouteAction<Remote> {
     Local,
     /// Will be forward to the given connection
     Next(Remote),
+    /// Will be forward to one of equal-cost connections, each with its weight, see [`RouteAction::pick_flow`]
+    NextMulti(Vec<(Remote, u32)>),
     /// Will be forward to the given connection, first is local or not, next is the list of remote dests
     Broadcast(bool, Vec<Remote>),
 }
@@ -78,10 +91,42 @@ impl<Remote> RouteAction<Remote> {
     }
 
     pub fn is_remote(&self) -> bool {
-        matches!(self, RouteAction::Next(_))
+        matches!(self, RouteAction::Next(_) | RouteAction::NextMulti(_))
+    }
+}
+
+impl<Remote: Copy + Hash> RouteAction<Remote> {
+    /// Resolve `NextMulti` to a single `Next` by flow hash, so packets of the same flow always take the same path.
+    ///
+    /// This is weighted rendezvous hashing: each remote gets a share of flows in proportion to its weight, and when a remote
+    /// is added or removed only the flows which move to or from it change path. A weight of 0 counts as 1
+    pub fn pick_flow(self, flow: u64) -> Self {
+        match self {
+            RouteAction::NextMulti(remotes) => {
+                let picked = remotes.iter().map(|(remote, weight)| (*remote, flow_rank(flow, remote, *weight))).min_by(|a, b| a.1.total_cmp(&b.1));
+                picked.map(|(remote, _)| RouteAction::Next(remote)).unwrap_or(RouteAction::Reject)
+            }
+            _ => self,
+        }
     }
 }
 
+/// Key which the `replica`-th replica of `key` is stored at, 0 is the key itself.
+/// The index is spread over all layers, so replicas land in other zones when the network has them
+pub fn replica_key(key: NodeId, replica: u8) -> NodeId {
+    key ^ (replica as u32).wrapping_mul(0x9E37_79B9)
+}
+
+/// Rank of a remote for a flow, lowest wins. `-ln(u) / weight` with u uniform in (0, 1] is exponentially distributed,
+/// so the chance of a remote to have the lowest rank is its weight over the total weight
+fn flow_rank<Remote: Hash>(flow: u64, remote: &Remote, weight: u32) -> f64 {
+    let mut hasher = DefaultHasher::new();
+    flow.hash(&mut hasher);
+    remote.hash(&mut hasher);
+    let unit = ((hasher.finish() >> 11) + 1) as f64 / (1u64 << 53) as f64;
+    -unit.ln() / weight.max(1) as f64
+}
+
 pub trait RouterTable<Remote> {
     /// Find the closest node for the given key
     fn closest_for(&self, key: NodeId) -> Option<Remote>;
@@ -91,6 +136,10 @@ pub trait RouterTable<Remote> {
     fn path_to_node(&self, dest: NodeId) -> RouteAction<Remote>;
     /// Determine the next action for the given key
     fn path_to_key(&self, key: NodeId) -> RouteAction<Remote>;
+    /// Determine the next action for the `replica`-th replica of the given key, see [`replica_key`]
+    fn path_to_key_replica(&self, key: NodeId, replica: u8) -> RouteAction<Remote> {
+        self.path_to_key(replica_key(key, replica))
+    }
     /// Determine the next action for the given service
     fn path_to_service(&self, service_id: u8) -> RouteAction<Remote>;
     /// Determine the next action if we need broadcast to all node running a service.
@@ -103,8 +152,10 @@ pub trait RouterTable<Remote> {
             RouteRule::Direct => RouteAction::Local,
             RouteRule::ToNode(dest) => self.path_to_node(*dest),
             RouteRule::ToKey(key) => self.path_to_key(*key),
+            RouteRule::ToKeyReplica(key, replica) => self.path_to_key_replica(*key, *replica),
             RouteRule::ToService(service) => self.path_to_service(*service),
             RouteRule::ToServices(service, level, seq) => self.path_to_services(*service, *seq, *level, source, relay_from),
+            RouteRule::BroadcastScoped(service, seq, _) => self.path_to_services(*service, *seq, ServiceBroadcastLevel::Global, source, relay_from),
         }
     }
 }
@@ -114,6 +165,56 @@ mod tests {
     use atm0s_sdn_identity::ConnId;
     type RouteAction = super::RouteAction<ConnId>;
 
+    fn picked(action: &RouteAction, flow: u64) -> ConnId {
+        match action.clone().pick_flow(flow) {
+            RouteAction::Next(conn) => conn,
+            other => panic!("expected a picked path, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_pick_flow() {
+        let conn1 = ConnId::from_out(1, 1);
+        let conn2 = ConnId::from_out(1, 2);
+        let conn3 = ConnId::from_out(1, 3);
+        let multi = RouteAction::NextMulti(vec![(conn1, 1), (conn2, 1)]);
+
+        //a flow always maps to the same path
+        let paths: Vec<_> = (0..1000).map(|flow| picked(&multi, flow)).collect();
+        assert!((0..1000).all(|flow| picked(&multi, flow) == paths[flow as usize]));
+        //the order of candidates does not matter
+        let reversed = RouteAction::NextMulti(vec![(conn2, 1), (conn1, 1)]);
+        assert!((0..1000).all(|flow| picked(&reversed, flow) == paths[flow as usize]));
+
+        assert_eq!(RouteAction::NextMulti(vec![]).pick_flow(1), RouteAction::Reject);
+        assert_eq!(RouteAction::Next(conn1).pick_flow(1), RouteAction::Next(conn1));
+        assert!(multi.is_remote());
+
+        //adding a path only moves flows to the new path
+        let added = RouteAction::NextMulti(vec![(conn1, 1), (conn2, 1), (conn3, 1)]);
+        let moved = (0..1000).filter(|flow| picked(&added, *flow) != paths[*flow as usize]).collect::<Vec<_>>();
+        assert!(moved.iter().all(|flow| picked(&added, *flow) == conn3));
+        assert!(moved.len() > 200 && moved.len() < 450, "moved {}", moved.len());
+
+        //removing a path only moves its own flows
+        let removed = RouteAction::NextMulti(vec![(conn1, 1)]);
+        assert!((0..1000).all(|flow| picked(&removed, flow) == conn1));
+    }
+
+    #[test]
+    fn test_pick_flow_weighted() {
+        let conn1 = ConnId::from_out(1, 1);
+        let conn2 = ConnId::from_out(1, 2);
+        let multi = RouteAction::NextMulti(vec![(conn1, 3000), (conn2, 1000)]);
+
+        let to_conn1 = (0..4000).filter(|flow| picked(&multi, *flow) == conn1).count();
+        assert!(to_conn1 > 2800 && to_conn1 < 3200, "conn1 got {to_conn1} flows");
+
+        //zero bandwidth still gets a share
+        let zero = RouteAction::NextMulti(vec![(conn1, 0), (conn2, 0)]);
+        assert!((0..100).any(|flow| picked(&zero, flow) == conn2));
+    }
+
     #[test]
     fn test_is_local() {
         let local = RouteAction::Local;
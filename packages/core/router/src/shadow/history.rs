@@ -0,0 +1,188 @@
+//! Bounded [`ShadowRouterHistory`] which is shared by all data planes of a node.
+//!
+//! Broadcasts are identified by (source, service, seq), and seq is only 16 bits, so the history trades memory for
+//! correctness in both directions: if it is too small or the ttl too short, a broadcast which is still looping in the
+//! network is forgotten and forwarded again as a duplicate; if it is too large, memory is wasted and a seq which wraps
+//! around within the ttl is wrongly suppressed. Entries are evicted oldest first, a hit doesn't refresh them.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::Mutex,
+};
+
+use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_utils::metrics;
+
+use super::ShadowRouterHistory;
+
+/// Max received broadcasts which are remembered by default
+pub const HISTORY_CAPACITY: usize = 10000;
+/// How long a received broadcast is remembered by default
+pub const HISTORY_TTL_MS: u64 = 2000;
+
+type Key = (Option<NodeId>, u8, u16);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShadowHistoryStats {
+    /// Broadcasts which were already received, so they are suppressed
+    pub hits: u64,
+    /// Broadcasts which are received for the first time
+    pub misses: u64,
+    /// Entries dropped before their ttl because the history was full
+    pub evictions: u64,
+    /// Entries dropped after their ttl
+    pub expired: u64,
+}
+
+impl ShadowHistoryStats {
+    /// Share of received broadcasts which were duplicates
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            total => self.hits as f64 / total as f64,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    now_ms: u64,
+    queue: VecDeque<(u64, Key)>,
+    keys: HashSet<Key>,
+    stats: ShadowHistoryStats,
+}
+
+#[derive(Debug)]
+pub struct LruShadowHistory {
+    capacity: usize,
+    ttl_ms: u64,
+    inner: Mutex<Inner>,
+}
+
+impl LruShadowHistory {
+    pub fn new(capacity: usize, ttl_ms: u64) -> Self {
+        Self {
+            capacity,
+            ttl_ms,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    pub fn stats(&self) -> ShadowHistoryStats {
+        self.lock().stats
+    }
+
+    /// Number of remembered broadcasts
+    pub fn len(&self) -> usize {
+        self.lock().keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Default for LruShadowHistory {
+    fn default() -> Self {
+        Self::new(HISTORY_CAPACITY, HISTORY_TTL_MS)
+    }
+}
+
+impl ShadowRouterHistory for LruShadowHistory {
+    fn already_received_broadcast(&self, from: Option<NodeId>, service: u8, seq: u16) -> bool {
+        let mut inner = self.lock();
+        let key = (from, service, seq);
+        if inner.keys.contains(&key) {
+            inner.stats.hits += 1;
+            metrics::BROADCAST_HISTORY_HITS.inc();
+            return true;
+        }
+        inner.stats.misses += 1;
+        if self.capacity == 0 {
+            return false;
+        }
+        while inner.queue.len() >= self.capacity {
+            let (_, old) = inner.queue.pop_front().expect("queue should not empty");
+            inner.keys.remove(&old);
+            inner.stats.evictions += 1;
+            metrics::BROADCAST_HISTORY_EVICTIONS.inc();
+        }
+        let now_ms = inner.now_ms;
+        inner.queue.push_back((now_ms, key));
+        inner.keys.insert(key);
+        false
+    }
+
+    fn set_ts(&self, now_ms: u64) {
+        let mut inner = self.lock();
+        inner.now_ms = now_ms;
+        while let Some((time, key)) = inner.queue.front().copied() {
+            if now_ms < time + self.ttl_ms {
+                break;
+            }
+            inner.queue.pop_front();
+            inner.keys.remove(&key);
+            inner.stats.expired += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        shadow::{ShadowRouter, ShadowRouterDelta, ShadowRouterHistory},
+        RouteAction, RouterTable, ServiceBroadcastLevel,
+    };
+
+    use super::{LruShadowHistory, ShadowHistoryStats};
+
+    #[test]
+    fn broadcast_seen_twice_should_be_suppressed() {
+        let history = Arc::new(LruShadowHistory::new(16, 1000));
+        let mut router = ShadowRouter::<u64>::new(1, history.clone());
+        router.apply_delta(ShadowRouterDelta::SetServiceLocal { service: 100 });
+
+        assert_eq!(router.path_to_services(100, 1, ServiceBroadcastLevel::Global, Some(2), None), RouteAction::Local);
+        assert_eq!(router.path_to_services(100, 1, ServiceBroadcastLevel::Global, Some(2), None), RouteAction::Reject);
+        //other source or seq is a new broadcast
+        assert_eq!(router.path_to_services(100, 1, ServiceBroadcastLevel::Global, Some(3), None), RouteAction::Local);
+        assert_eq!(router.path_to_services(100, 2, ServiceBroadcastLevel::Global, Some(2), None), RouteAction::Local);
+        assert_eq!(
+            history.stats(),
+            ShadowHistoryStats {
+                hits: 1,
+                misses: 3,
+                evictions: 0,
+                expired: 0
+            }
+        );
+        assert_eq!(history.stats().hit_rate(), 0.25);
+
+        //after ttl the same seq is accepted again
+        history.set_ts(1000);
+        assert!(history.is_empty());
+        assert_eq!(router.path_to_services(100, 1, ServiceBroadcastLevel::Global, Some(2), None), RouteAction::Local);
+        assert_eq!(history.stats().expired, 3);
+    }
+
+    #[test]
+    fn full_history_should_evict_oldest() {
+        let history = LruShadowHistory::new(2, 1000);
+        assert_eq!(history.already_received_broadcast(Some(1), 1, 1), false);
+        assert_eq!(history.already_received_broadcast(Some(1), 1, 2), false);
+        assert_eq!(history.already_received_broadcast(Some(1), 1, 1), true);
+        assert_eq!(history.already_received_broadcast(Some(1), 1, 3), false);
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.stats().evictions, 1);
+
+        //the first one is forgotten even though it was hit, so it is forwarded again
+        assert_eq!(history.already_received_broadcast(Some(1), 1, 1), false);
+        assert_eq!(history.already_received_broadcast(Some(1), 1, 3), true);
+    }
+}
@@ -4,8 +4,10 @@ use atm0s_sdn_identity::{NodeId, NodeIdType};
 
 use crate::{RouteAction, RouterTable, ServiceBroadcastLevel};
 
+pub use self::history::{LruShadowHistory, ShadowHistoryStats, HISTORY_CAPACITY, HISTORY_TTL_MS};
 use self::{service::Service, table::ShadowTable};
 
+mod history;
 mod service;
 mod table;
 
@@ -21,12 +23,49 @@ pub trait ShadowRouterHistory: Send + Sync {
 
 #[derive(Debug, Clone)]
 pub enum ShadowRouterDelta<Remote> {
-    SetTable { layer: u8, index: u8, next: Remote },
-    DelTable { layer: u8, index: u8 },
-    SetServiceRemote { service: u8, conn: Remote, next: NodeId, dest: NodeId, score: u32 },
-    DelServiceRemote { service: u8, conn: Remote },
-    SetServiceLocal { service: u8 },
-    DelServiceLocal { service: u8 },
+    SetTable {
+        layer: u8,
+        index: u8,
+        next: Remote,
+    },
+    DelTable {
+        layer: u8,
+        index: u8,
+    },
+    /// Equal-cost remotes of a table index with their weights, empty for single best path
+    SetTableMulti {
+        layer: u8,
+        index: u8,
+        nexts: Vec<(Remote, u32)>,
+    },
+    /// Next best remotes of a table index, best first, empty for none
+    SetTableAlternates {
+        layer: u8,
+        index: u8,
+        nexts: Vec<Remote>,
+    },
+    SetServiceRemote {
+        service: u8,
+        conn: Remote,
+        next: NodeId,
+        dest: NodeId,
+        score: u32,
+    },
+    DelServiceRemote {
+        service: u8,
+        conn: Remote,
+    },
+    SetServiceLocal {
+        service: u8,
+    },
+    DelServiceLocal {
+        service: u8,
+    },
+    /// The remote moved to another address, all table and service paths through it are kept
+    ReplaceRemote {
+        old: Remote,
+        new: Remote,
+    },
 }
 
 pub struct ShadowRouter<Remote: Debug + Hash + Eq + Clone + Copy> {
@@ -56,6 +95,12 @@ impl<Remote: Debug + Hash + Eq + Clone + Copy> ShadowRouter<Remote> {
             ShadowRouterDelta::DelTable { layer, index } => {
                 self.tables[layer as usize].del(index);
             }
+            ShadowRouterDelta::SetTableMulti { layer, index, nexts } => {
+                self.tables[layer as usize].set_multi(index, nexts);
+            }
+            ShadowRouterDelta::SetTableAlternates { layer, index, nexts } => {
+                self.tables[layer as usize].set_alternates(index, nexts);
+            }
             ShadowRouterDelta::SetServiceRemote { service, conn, next, dest, score } => {
                 self.remote_registry[service as usize].set_conn(conn, next, dest, score);
             }
@@ -68,7 +113,26 @@ impl<Remote: Debug + Hash + Eq + Clone + Copy> ShadowRouter<Remote> {
             ShadowRouterDelta::DelServiceLocal { service } => {
                 self.local_registries[service as usize] = false;
             }
+            ShadowRouterDelta::ReplaceRemote { old, new } => {
+                for table in self.tables.iter_mut() {
+                    table.replace(old, new);
+                }
+                for service in self.remote_registry.iter_mut() {
+                    service.replace_conn(old, new);
+                }
+            }
+        }
+    }
+}
+
+impl<Remote: Debug + Hash + Eq + Clone + Copy> ShadowRouter<Remote> {
+    /// Next best remotes to dest after the best or equal-cost ones, best first
+    pub fn alternates_to_node(&self, dest: NodeId) -> &[Remote] {
+        let eq_util_layer = self.node_id.eq_util_layer(&dest) as usize;
+        if dest == self.node_id || eq_util_layer == 0 {
+            return &[];
         }
+        self.tables[eq_util_layer - 1].alternates(dest)
     }
 }
 
@@ -111,6 +175,12 @@ impl<Remote: Debug + Hash + Eq + Clone + Copy> RouterTable<Remote> for ShadowRou
         if dest == self.node_id {
             return RouteAction::Local;
         }
+        let eq_util_layer = self.node_id.eq_util_layer(&dest) as usize;
+        if eq_util_layer > 0 {
+            if let Some(remotes) = self.tables[eq_util_layer - 1].next_multi(dest) {
+                return RouteAction::NextMulti(remotes.to_vec());
+            }
+        }
         match self.next(dest) {
             Some(remote) => RouteAction::Next(remote),
             None => RouteAction::Reject,
@@ -144,10 +214,102 @@ impl<Remote: Debug + Hash + Eq + Clone + Copy> RouterTable<Remote> for ShadowRou
 mod tests {
     use std::sync::Arc;
 
-    use crate::{shadow::MockShadowRouterHistory, RouteAction, RouterTable, ServiceBroadcastLevel};
+    use crate::{replica_key, shadow::MockShadowRouterHistory, RouteAction, RouterTable, ServiceBroadcastLevel};
 
     use super::{ShadowRouter, ShadowRouterDelta};
 
+    #[test]
+    fn should_route_to_node_multi_paths() {
+        let history = MockShadowRouterHistory::new();
+        let mut router = ShadowRouter::<u64>::new(1, Arc::new(history));
+        router.apply_delta(ShadowRouterDelta::SetTable { layer: 0, index: 2, next: 10 });
+        assert_eq!(router.path_to_node(2), RouteAction::Next(10));
+
+        router.apply_delta(ShadowRouterDelta::SetTableMulti {
+            layer: 0,
+            index: 2,
+            nexts: vec![(10, 1), (11, 2)],
+        });
+        assert_eq!(router.path_to_node(2), RouteAction::NextMulti(vec![(10, 1), (11, 2)]));
+        assert_eq!(router.next(2), Some(10));
+
+        router.apply_delta(ShadowRouterDelta::SetTableMulti { layer: 0, index: 2, nexts: vec![] });
+        assert_eq!(router.path_to_node(2), RouteAction::Next(10));
+
+        router.apply_delta(ShadowRouterDelta::SetTableMulti {
+            layer: 0,
+            index: 2,
+            nexts: vec![(10, 1), (11, 1)],
+        });
+        router.apply_delta(ShadowRouterDelta::DelTable { layer: 0, index: 2 });
+        assert_eq!(router.path_to_node(2), RouteAction::Reject);
+    }
+
+    #[test]
+    fn should_route_to_key_replica_same_from_all_nodes() {
+        // full mesh of nodes in layer 0, remote is the next node id
+        let nodes: Vec<u32> = vec![1, 5, 7, 0x20, 0x81, 0xc3];
+        let routers: Vec<ShadowRouter<u32>> = nodes
+            .iter()
+            .map(|node| {
+                let mut router = ShadowRouter::<u32>::new(*node, Arc::new(MockShadowRouterHistory::new()));
+                for other in nodes.iter().filter(|other| *other != node) {
+                    router.apply_delta(ShadowRouterDelta::SetTable {
+                        layer: 0,
+                        index: *other as u8,
+                        next: *other,
+                    });
+                }
+                router
+            })
+            .collect();
+        let dest = |start: usize, key: u32, replica: u8| {
+            let mut current = start;
+            loop {
+                match routers[current].path_to_key_replica(key, replica) {
+                    RouteAction::Local => return nodes[current],
+                    RouteAction::Next(next) => current = nodes.iter().position(|n| *n == next).expect("Should be known node"),
+                    action => panic!("unexpected action {action:?}"),
+                }
+            }
+        };
+
+        for key in [0, 4, 0x80, 0x0102_03ff] {
+            assert_eq!(routers[0].path_to_key_replica(key, 0), routers[0].path_to_key(key));
+            for replica in 0..3 {
+                let first = dest(0, key, replica);
+                assert!((1..nodes.len()).all(|start| dest(start, key, replica) == first), "key {key} replica {replica}");
+            }
+        }
+        assert_eq!(replica_key(4, 0), 4);
+        assert_ne!(replica_key(4, 1), replica_key(4, 2));
+    }
+
+    #[test]
+    fn should_replace_remote_in_place() {
+        let history = MockShadowRouterHistory::new();
+        let mut router = ShadowRouter::<u64>::new(1, Arc::new(history));
+        router.apply_delta(ShadowRouterDelta::SetTable { layer: 0, index: 2, next: 10 });
+        router.apply_delta(ShadowRouterDelta::SetTable { layer: 0, index: 3, next: 11 });
+        router.apply_delta(ShadowRouterDelta::SetTableMulti {
+            layer: 0,
+            index: 3,
+            nexts: vec![(11, 1), (10, 2)],
+        });
+        router.apply_delta(ShadowRouterDelta::SetServiceRemote {
+            service: 1,
+            conn: 10,
+            next: 2,
+            dest: 2,
+            score: 4,
+        });
+
+        router.apply_delta(ShadowRouterDelta::ReplaceRemote { old: 10, new: 20 });
+        assert_eq!(router.path_to_node(2), RouteAction::Next(20));
+        assert_eq!(router.path_to_node(3), RouteAction::NextMulti(vec![(11, 1), (20, 2)]));
+        assert_eq!(router.path_to_service(1), RouteAction::Next(20));
+    }
+
     #[test]
     fn should_route_to_next_service_local() {
         let history = MockShadowRouterHistory::new();
@@ -49,6 +49,13 @@ impl<Remote: Debug + Hash + Copy + Eq + PartialEq> Service<Remote> {
         self.dests.retain(|x| x.conn != conn);
     }
 
+    /// Move destinations of `old` to `new`, without changing their scores
+    pub fn replace_conn(&mut self, old: Remote, new: Remote) {
+        for dest in self.dests.iter_mut().filter(|x| x.conn == old) {
+            dest.conn = new;
+        }
+    }
+
     pub fn best_conn(&self) -> Option<Remote> {
         self.dests.first().map(|x| x.conn)
     }
@@ -1,14 +1,57 @@
+use std::collections::HashMap;
+
 use atm0s_sdn_identity::{NodeId, NodeIdType};
 
 #[derive(Debug)]
 pub struct ShadowTable<Remote> {
     layer: u8,
     dests: [Option<Remote>; 256],
+    /// Equal-cost remotes with their weights
+    multi: HashMap<u8, Vec<(Remote, u32)>>,
+    /// Next best remotes after the best one, best first
+    alternates: HashMap<u8, Vec<Remote>>,
 }
 
-impl<Remote: Copy> ShadowTable<Remote> {
+impl<Remote: Copy + PartialEq> ShadowTable<Remote> {
     pub fn new(layer: u8) -> Self {
-        Self { layer, dests: [None; 256] }
+        Self {
+            layer,
+            dests: [None; 256],
+            multi: HashMap::new(),
+            alternates: HashMap::new(),
+        }
+    }
+
+    pub fn set_multi(&mut self, index: u8, remotes: Vec<(Remote, u32)>) {
+        if remotes.len() > 1 {
+            self.multi.insert(index, remotes);
+        } else {
+            self.multi.remove(&index);
+        }
+    }
+
+    /// Equal-cost remotes for dest, only when there are more than one
+    pub fn next_multi(&self, dest: NodeId) -> Option<&[(Remote, u32)]> {
+        let index = dest.layer(self.layer);
+        if self.dests[index as usize].is_some() {
+            self.multi.get(&index).map(|remotes| remotes.as_slice())
+        } else {
+            None
+        }
+    }
+
+    pub fn set_alternates(&mut self, index: u8, remotes: Vec<Remote>) {
+        if remotes.is_empty() {
+            self.alternates.remove(&index);
+        } else {
+            self.alternates.insert(index, remotes);
+        }
+    }
+
+    /// Next best remotes for dest, best first
+    pub fn alternates(&self, dest: NodeId) -> &[Remote] {
+        let index = dest.layer(self.layer);
+        self.alternates.get(&index).map(|remotes| remotes.as_slice()).unwrap_or_default()
     }
 
     pub fn set(&mut self, index: u8, remote: Remote) {
@@ -17,6 +60,23 @@ impl<Remote: Copy> ShadowTable<Remote> {
 
     pub fn del(&mut self, index: u8) {
         self.dests[index as usize] = None;
+        self.multi.remove(&index);
+        self.alternates.remove(&index);
+    }
+
+    /// Point all paths which use `old` to `new`, for a connection which moved to another address
+    pub fn replace(&mut self, old: Remote, new: Remote) {
+        for remote in self
+            .dests
+            .iter_mut()
+            .flatten()
+            .chain(self.multi.values_mut().flatten().map(|(remote, _)| remote))
+            .chain(self.alternates.values_mut().flatten())
+        {
+            if *remote == old {
+                *remote = new;
+            }
+        }
     }
 
     pub fn next(&self, dest: NodeId) -> Option<Remote> {
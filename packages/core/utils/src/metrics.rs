@@ -0,0 +1,167 @@
+//! Process wide counters and gauges of atm0s-sdn, exported in the Prometheus text format by [`gather`].
+//!
+//! Metrics are plain atomics which are updated where the events already happen, so the core crates don't need a
+//! metrics library or an HTTP server, the application serves `gather()` output on its own scrape endpoint.
+//! All nodes running inside the same process add up into the same values.
+
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicI64, Ordering},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Counter,
+    Gauge,
+}
+
+pub struct Metric {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static str,
+    kind: Kind,
+    value: AtomicI64,
+}
+
+impl Metric {
+    const fn new(kind: Kind, name: &'static str, help: &'static str, labels: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            kind,
+            value: AtomicI64::new(0),
+        }
+    }
+
+    pub const fn counter(name: &'static str, help: &'static str) -> Self {
+        Self::new(Kind::Counter, name, help, "")
+    }
+
+    pub const fn gauge(name: &'static str, help: &'static str) -> Self {
+        Self::new(Kind::Gauge, name, help, "")
+    }
+
+    /// Gauge with a label set like `layer="0"`, gauges with the same name are exported as one metric family
+    pub const fn labeled_gauge(name: &'static str, help: &'static str, labels: &'static str) -> Self {
+        Self::new(Kind::Gauge, name, help, labels)
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, value: u64) {
+        self.value.fetch_add(value as i64, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        debug_assert_eq!(self.kind, Kind::Gauge, "counter {} can't decrease", self.name);
+        self.value.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Move a gauge by the change of `value` since the `reported` one, so each instance only adds its own part
+    pub fn track(&self, reported: &mut usize, value: usize) {
+        debug_assert_eq!(self.kind, Kind::Gauge, "counter {} can't be tracked", self.name);
+        self.value.fetch_add(value as i64 - *reported as i64, Ordering::Relaxed);
+        *reported = value;
+    }
+
+    pub fn get(&self) -> i64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+pub static CONNECTIONS: Metric = Metric::gauge("atm0s_sdn_connections", "Connected neighbours");
+pub static ROUTER_ROUTES: [Metric; 4] = [
+    Metric::labeled_gauge("atm0s_sdn_router_routes", "Reachable destinations in the router table of each layer", "layer=\"0\""),
+    Metric::labeled_gauge("atm0s_sdn_router_routes", "Reachable destinations in the router table of each layer", "layer=\"1\""),
+    Metric::labeled_gauge("atm0s_sdn_router_routes", "Reachable destinations in the router table of each layer", "layer=\"2\""),
+    Metric::labeled_gauge("atm0s_sdn_router_routes", "Reachable destinations in the router table of each layer", "layer=\"3\""),
+];
+pub static BYTES_IN: Metric = Metric::counter("atm0s_sdn_bytes_in_total", "Bytes received on connections, before decryption");
+pub static BYTES_OUT: Metric = Metric::counter("atm0s_sdn_bytes_out_total", "Bytes sent on connections, after encryption");
+pub static DECRYPT_FAILURES: Metric = Metric::counter("atm0s_sdn_decrypt_failures_total", "Incoming packets which can't be decrypted");
+pub static DROPPED_OUTPUTS: Metric = Metric::counter("atm0s_sdn_dropped_outputs_total", "Bulk outputs dropped by full data plane queues");
+pub static SHAPED_BYTES: Metric = Metric::counter("atm0s_sdn_shaped_bytes_total", "Bulk bytes deferred by data plane shapers");
+pub static OVERSIZED_PAYLOADS: Metric = Metric::counter("atm0s_sdn_oversized_payloads_total", "Incoming packets dropped because the payload is above the feature limit");
+pub static DHT_KV_LOCAL_MAPS: Metric = Metric::labeled_gauge("atm0s_sdn_dht_kv_maps", "dht_kv maps used by local actors or stored for remote nodes", "side=\"local\"");
+pub static DHT_KV_REMOTE_MAPS: Metric = Metric::labeled_gauge("atm0s_sdn_dht_kv_maps", "dht_kv maps used by local actors or stored for remote nodes", "side=\"remote\"");
+pub static PUBSUB_CHANNELS: Metric = Metric::gauge("atm0s_sdn_pubsub_channels", "Pubsub channels with a relay on this node");
+pub static PUBSUB_CONSUMERS: Metric = Metric::gauge("atm0s_sdn_pubsub_consumers", "Local subscribers of pubsub channels");
+pub static BROADCAST_HISTORY_HITS: Metric = Metric::counter("atm0s_sdn_broadcast_history_hits_total", "Duplicated broadcasts suppressed by the history");
+pub static BROADCAST_HISTORY_EVICTIONS: Metric = Metric::counter("atm0s_sdn_broadcast_history_evictions_total", "Broadcasts forgotten before their ttl because the history was full");
+
+/// Metrics of the same name must be next to each other
+static ALL: [&Metric; 17] = [
+    &CONNECTIONS,
+    &ROUTER_ROUTES[0],
+    &ROUTER_ROUTES[1],
+    &ROUTER_ROUTES[2],
+    &ROUTER_ROUTES[3],
+    &BYTES_IN,
+    &BYTES_OUT,
+    &DECRYPT_FAILURES,
+    &DROPPED_OUTPUTS,
+    &SHAPED_BYTES,
+    &OVERSIZED_PAYLOADS,
+    &DHT_KV_LOCAL_MAPS,
+    &DHT_KV_REMOTE_MAPS,
+    &PUBSUB_CHANNELS,
+    &PUBSUB_CONSUMERS,
+    &BROADCAST_HISTORY_HITS,
+    &BROADCAST_HISTORY_EVICTIONS,
+];
+
+/// All metrics in the Prometheus text exposition format
+pub fn gather() -> String {
+    let mut out = String::new();
+    let mut last_name = "";
+    for metric in ALL {
+        if metric.name != last_name {
+            let kind = match metric.kind {
+                Kind::Counter => "counter",
+                Kind::Gauge => "gauge",
+            };
+            let _ = writeln!(out, "# HELP {} {}", metric.name, metric.help);
+            let _ = writeln!(out, "# TYPE {} {}", metric.name, kind);
+            last_name = metric.name;
+        }
+        if metric.labels.is_empty() {
+            let _ = writeln!(out, "{} {}", metric.name, metric.get());
+        } else {
+            let _ = writeln!(out, "{}{{{}}} {}", metric.name, metric.labels, metric.get());
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{gather, Metric, CONNECTIONS, ROUTER_ROUTES};
+
+    #[test]
+    fn exposition_format() {
+        CONNECTIONS.inc();
+        CONNECTIONS.inc();
+        CONNECTIONS.dec();
+        ROUTER_ROUTES[2].add(5);
+        let text = gather();
+        assert!(text.contains("# HELP atm0s_sdn_connections Connected neighbours\n# TYPE atm0s_sdn_connections gauge\natm0s_sdn_connections 1\n"));
+        assert!(text.contains("atm0s_sdn_router_routes{layer=\"2\"} 5\n"));
+        assert_eq!(text.matches("# TYPE atm0s_sdn_router_routes gauge").count(), 1);
+        assert!(text.contains("# TYPE atm0s_sdn_bytes_in_total counter\natm0s_sdn_bytes_in_total 0\n"));
+    }
+
+    #[test]
+    fn track_instances_add_up() {
+        let gauge = Metric::gauge("test", "test");
+        let (mut reported1, mut reported2) = (0, 0);
+        gauge.track(&mut reported1, 3);
+        gauge.track(&mut reported2, 4);
+        assert_eq!(gauge.get(), 7);
+        gauge.track(&mut reported1, 1);
+        assert_eq!(gauge.get(), 5);
+        assert_eq!((reported1, reported2), (1, 4));
+    }
+}
@@ -0,0 +1,81 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use atm0s_sdn_network::{
+    base::Buffer,
+    data_plane::{BufferPool, MAX_SECURE_OVERHEAD},
+};
+use criterion::{criterion_group, criterion_main, Criterion};
+
+criterion_group!(benches, benchmark_fan_out);
+criterion_main!(benches);
+
+const PAYLOAD_SIZE: usize = 1200;
+const REMOTES: usize = 50;
+
+/// Counts allocations, for printing how many each fan-out needs
+struct CountingAlloc;
+
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// Copies for each remote of a secure broadcast, as the encryption of each connection needs its own buffer
+fn fan_out_alloc(payload: &[u8], out: &mut Vec<Buffer>) {
+    for _ in 0..REMOTES {
+        out.push(Buffer::build(payload, 0, MAX_SECURE_OVERHEAD));
+    }
+    out.clear();
+}
+
+/// Same copies from the pool, the sent buffers are returned after each round
+fn fan_out_pool(pool: &mut BufferPool, payload: &[u8], out: &mut Vec<Buffer>) {
+    for _ in 0..REMOTES {
+        out.push(pool.build(payload, MAX_SECURE_OVERHEAD));
+    }
+    for buf in out.drain(..) {
+        pool.recycle(buf);
+    }
+}
+
+fn count_allocs(mut f: impl FnMut()) -> usize {
+    let before = ALLOCS.load(Ordering::Relaxed);
+    f();
+    ALLOCS.load(Ordering::Relaxed) - before
+}
+
+fn benchmark_fan_out(c: &mut Criterion) {
+    let payload = vec![1u8; PAYLOAD_SIZE];
+    let mut out = Vec::with_capacity(REMOTES);
+    let mut pool = BufferPool::default();
+    //warm up the pool, next rounds only reuse
+    fan_out_pool(&mut pool, &payload, &mut out);
+
+    println!("allocations per fan-out to {REMOTES} remotes: alloc {}", count_allocs(|| fan_out_alloc(&payload, &mut out)));
+    println!("allocations per fan-out to {REMOTES} remotes: pool {}", count_allocs(|| fan_out_pool(&mut pool, &payload, &mut out)));
+
+    let mut group = c.benchmark_group("secure_fan_out");
+    group.throughput(criterion::Throughput::Bytes((PAYLOAD_SIZE * REMOTES) as u64));
+    group.bench_function("alloc", |b| {
+        b.iter(|| fan_out_alloc(&payload, &mut out));
+    });
+    group.bench_function("pool", |b| {
+        b.iter(|| fan_out_pool(&mut pool, &payload, &mut out));
+    });
+    group.finish();
+    println!("pool stats {:?}", pool.stats());
+}
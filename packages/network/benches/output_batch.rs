@@ -0,0 +1,80 @@
+use std::sync::Arc;
+
+use atm0s_sdn_network::{
+    base::{ServiceId, DEFAULT_MSG_TTL},
+    data_plane::{CrossWorker, DataPlane, DataPlaneCfg, Input},
+};
+use atm0s_sdn_router::shadow::LruShadowHistory;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use rand::rngs::mock::StepRng;
+use sans_io_runtime::TaskSwitcherChild;
+
+criterion_group!(benches, benchmark_pop);
+criterion_main!(benches);
+
+const OUTPUTS: usize = 1024;
+const BATCH: usize = 64;
+
+type BenchDataPlane = DataPlane<(), (), u32, (), ()>;
+
+/// Data plane with OUTPUTS queued service events
+fn queued_plane() -> BenchDataPlane {
+    let mut plane = DataPlane::new(
+        1,
+        DataPlaneCfg {
+            worker_id: 0,
+            services: vec![],
+            history: Arc::new(LruShadowHistory::default()),
+            unknown_service: Default::default(),
+            random: Box::new(StepRng::new(0, 1)),
+            rekey: Default::default(),
+            max_ttl: DEFAULT_MSG_TTL,
+            output_queue: Default::default(),
+            feature_weights: Default::default(),
+            dscp: Default::default(),
+            shapers: Default::default(),
+            features: Default::default(),
+            max_payloads: Default::default(),
+        },
+    );
+    for i in 0..OUTPUTS {
+        plane.on_event(0, Input::Worker(CrossWorker::Service(ServiceId::from(1), (), i as u32)));
+    }
+    plane
+}
+
+fn benchmark_pop(c: &mut Criterion) {
+    let mut group = c.benchmark_group("data_plane_pop");
+    group.throughput(criterion::Throughput::Elements(OUTPUTS as u64));
+
+    group.bench_function("single", |b| {
+        b.iter_batched(
+            queued_plane,
+            |mut plane| {
+                let mut count = 0;
+                while plane.pop_output(0).is_some() {
+                    count += 1;
+                }
+                count
+            },
+            BatchSize::SmallInput,
+        );
+    });
+
+    group.bench_function("batch", |b| {
+        b.iter_batched(
+            queued_plane,
+            |mut plane| {
+                let mut count = 0;
+                loop {
+                    let batch = plane.pop_batch(0, BATCH);
+                    if batch.is_empty() {
+                        break count;
+                    }
+                    count += batch.len();
+                }
+            },
+            BatchSize::SmallInput,
+        );
+    });
+}
@@ -0,0 +1,43 @@
+use atm0s_sdn_network::base::{Buffer, TransportMsg, TransportMsgHeader};
+use atm0s_sdn_router::RouteRule;
+use criterion::{criterion_group, criterion_main, Criterion};
+
+criterion_group!(benches, benchmark_relay);
+criterion_main!(benches);
+
+const PAYLOAD_SIZE: usize = 1024;
+const LOCALS: usize = 50;
+
+fn benchmark_relay(c: &mut Criterion) {
+    let mut group = c.benchmark_group("relay");
+    group.throughput(criterion::Throughput::Bytes(PAYLOAD_SIZE as u64));
+    let header = TransportMsgHeader::build(5, 0, RouteRule::Direct);
+    let received = TransportMsg::from_payload_bincode(header.clone(), &(1u64, vec![1u8; PAYLOAD_SIZE])).take();
+
+    //parse from a slice, copy for each local and encode again for forwarding
+    group.bench_function("copy", |b| {
+        b.iter(|| {
+            let msg = TransportMsg::try_from(&received as &[u8]).expect("Should parse");
+            let (id, data): (u64, Vec<u8>) = msg.get_payload_bincode().expect("Should decode");
+            let locals: Vec<Vec<u8>> = (0..LOCALS).map(|_| data.to_vec()).collect();
+            let forward = TransportMsg::from_payload_bincode(header.clone(), &(id, data)).take();
+            (locals, forward)
+        });
+    });
+
+    //parse the owned buffer, move data to the last local and reuse the payload for forwarding
+    group.bench_function("zero_copy", |b| {
+        b.iter_batched(
+            || Buffer::from(received.to_vec()),
+            |buf| {
+                let msg = TransportMsg::try_from(buf).expect("Should parse");
+                let (_id, data): (u64, Vec<u8>) = msg.get_payload_bincode().expect("Should decode");
+                let mut locals: Vec<Vec<u8>> = (1..LOCALS).map(|_| data.clone()).collect();
+                locals.push(data);
+                let forward = TransportMsg::build_raw(header.clone(), msg.take_payload()).take();
+                (locals, forward)
+            },
+            criterion::BatchSize::SmallInput,
+        );
+    });
+}
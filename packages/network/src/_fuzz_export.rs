@@ -1,2 +1,3 @@
 pub use crate::base::NeighboursControl;
 pub use crate::base::TransportMsg;
+pub use crate::base::TransportMsgHeader;
@@ -0,0 +1,36 @@
+use std::{collections::HashSet, net::SocketAddr};
+
+use atm0s_sdn_identity::{ConnId, NodeId};
+
+/// Decide which connections the neighbours manager accepts, so geo, ASN or reputation rules can be plugged in.
+/// It is checked for each incoming connect request before the blacklist, allowlist and limits, and for each address
+/// of an outgoing connect. `conn` is the id which the connection would have. Refused connections fail with
+/// `NeighboursConnectError::Blocked`
+pub trait AcceptPolicy: Send + Sync {
+    fn accept(&self, node: NodeId, conn: ConnId, remote: SocketAddr) -> bool;
+}
+
+pub struct AcceptAll;
+
+impl AcceptPolicy for AcceptAll {
+    fn accept(&self, _node: NodeId, _conn: ConnId, _remote: SocketAddr) -> bool {
+        true
+    }
+}
+
+/// Accepts only the listed nodes, from any address
+pub struct AllowlistPolicy {
+    nodes: HashSet<NodeId>,
+}
+
+impl AllowlistPolicy {
+    pub fn new(nodes: impl IntoIterator<Item = NodeId>) -> Self {
+        Self { nodes: nodes.into_iter().collect() }
+    }
+}
+
+impl AcceptPolicy for AllowlistPolicy {
+    fn accept(&self, node: NodeId, _conn: ConnId, _remote: SocketAddr) -> bool {
+        self.nodes.contains(&node)
+    }
+}
@@ -0,0 +1,88 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Millisecond time source of a node. The planes never read it themselves, they get the time as `now_ms`
+/// from the loop which drives them, and that loop reads its clock.
+///
+/// Timestamps are signed into neighbour control messages and checked by the remote, so clocks of
+/// connected nodes must agree within the message timeout
+pub trait Clock: Send + Sync {
+    fn now_ms(&self) -> u64;
+}
+
+/// Unix time in milliseconds, read once at creation and then advanced by a monotonic clock,
+/// so wall-clock adjustments don't make it jump
+pub struct SystemClock {
+    instant: Instant,
+    started_ms: u64,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        let since_the_epoch = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards");
+        Self {
+            instant: Instant::now(),
+            started_ms: since_the_epoch.as_millis() as u64,
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u64 {
+        self.started_ms + self.instant.elapsed().as_millis() as u64
+    }
+}
+
+/// Clock which only moves when it is set, for tests and for following an external simulation time
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    now_ms: AtomicU64,
+}
+
+impl ManualClock {
+    pub fn new(now_ms: u64) -> Self {
+        Self { now_ms: AtomicU64::new(now_ms) }
+    }
+
+    pub fn set_ms(&self, now_ms: u64) {
+        self.now_ms.store(now_ms, Ordering::Relaxed);
+    }
+
+    pub fn advance_ms(&self, delta_ms: u64) {
+        self.now_ms.fetch_add(delta_ms, Ordering::Relaxed);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_ms(&self) -> u64 {
+        self.now_ms.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use super::{Clock, ManualClock, SystemClock};
+
+    #[test]
+    fn system_clock_should_follow_unix_time() {
+        let clock = SystemClock::default();
+        let unix_ms = SystemTime::now().duration_since(UNIX_EPOCH).expect("Should after epoch").as_millis() as u64;
+        let now_ms = clock.now_ms();
+        assert!(now_ms.abs_diff(unix_ms) < 1000, "{now_ms} vs {unix_ms}");
+        assert!(clock.now_ms() >= now_ms);
+    }
+
+    #[test]
+    fn manual_clock_should_only_move_when_set() {
+        let clock = ManualClock::new(100);
+        assert_eq!(clock.now_ms(), 100);
+        clock.advance_ms(50);
+        assert_eq!(clock.now_ms(), 150);
+        clock.set_ms(1000);
+        assert_eq!(clock.now_ms(), 1000);
+    }
+}
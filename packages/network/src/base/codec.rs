@@ -0,0 +1,113 @@
+//! Serialization of control and feature messages.
+//!
+//! Bincode is the format of all nodes and the fallback of the negotiation. Postcard is a compact format for thin links
+//! and CBOR is for nodes which are not written in Rust. Neighbour controls tag their codec in the framing, so each
+//! side of a connection decodes them without knowing the negotiated codec. Payloads of features are not tagged, a
+//! feature which sends with the codec of `ConnectionCtx` must only do it to that neighbour.
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodecError {
+    Encode,
+    Decode,
+}
+
+pub trait Codec {
+    fn encode<M: Serialize>(msg: &M) -> Result<Vec<u8>, CodecError>;
+    fn decode<M: DeserializeOwned>(buf: &[u8]) -> Result<M, CodecError>;
+}
+
+/// Same encoding as the `bincode` helpers of `TransportMsg`
+pub struct BincodeCodec;
+
+impl Codec for BincodeCodec {
+    fn encode<M: Serialize>(msg: &M) -> Result<Vec<u8>, CodecError> {
+        bincode::serialize(msg).map_err(|_| CodecError::Encode)
+    }
+
+    fn decode<M: DeserializeOwned>(buf: &[u8]) -> Result<M, CodecError> {
+        bincode::deserialize(buf).map_err(|_| CodecError::Decode)
+    }
+}
+
+pub struct PostcardCodec;
+
+impl Codec for PostcardCodec {
+    fn encode<M: Serialize>(msg: &M) -> Result<Vec<u8>, CodecError> {
+        postcard::to_allocvec(msg).map_err(|_| CodecError::Encode)
+    }
+
+    fn decode<M: DeserializeOwned>(buf: &[u8]) -> Result<M, CodecError> {
+        postcard::from_bytes(buf).map_err(|_| CodecError::Decode)
+    }
+}
+
+pub struct CborCodec;
+
+impl Codec for CborCodec {
+    fn encode<M: Serialize>(msg: &M) -> Result<Vec<u8>, CodecError> {
+        let mut buf = Vec::new();
+        ciborium::into_writer(msg, &mut buf).map_err(|_| CodecError::Encode)?;
+        Ok(buf)
+    }
+
+    fn decode<M: DeserializeOwned>(buf: &[u8]) -> Result<M, CodecError> {
+        ciborium::from_reader(buf).map_err(|_| CodecError::Decode)
+    }
+}
+
+/// Codec which is selected in the connect handshake, like `CipherSuite`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum WireCodec {
+    #[default]
+    Bincode,
+    Postcard,
+    Cbor,
+}
+
+impl WireCodec {
+    /// Preference of a node which is not configured
+    pub const DEFAULT_PREFERENCE: [WireCodec; 1] = [WireCodec::Bincode];
+
+    pub fn encode<M: Serialize>(&self, msg: &M) -> Result<Vec<u8>, CodecError> {
+        match self {
+            WireCodec::Bincode => BincodeCodec::encode(msg),
+            WireCodec::Postcard => PostcardCodec::encode(msg),
+            WireCodec::Cbor => CborCodec::encode(msg),
+        }
+    }
+
+    pub fn decode<M: DeserializeOwned>(&self, buf: &[u8]) -> Result<M, CodecError> {
+        match self {
+            WireCodec::Bincode => BincodeCodec::decode(buf),
+            WireCodec::Postcard => PostcardCodec::decode(buf),
+            WireCodec::Cbor => CborCodec::decode(buf),
+        }
+    }
+
+    /// Select the first codec of local preference which is offered by the remote, fallback to bincode
+    pub fn negotiate(local: &[WireCodec], offered: &[WireCodec]) -> WireCodec {
+        local.iter().find(|c| offered.contains(c)).copied().unwrap_or_default()
+    }
+
+    /// Check if a codec selected by the remote is acceptable with the local preference
+    pub fn is_acceptable(local: &[WireCodec], selected: WireCodec) -> bool {
+        selected == WireCodec::Bincode || local.contains(&selected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WireCodec;
+
+    #[test]
+    fn negotiate_fallback_bincode() {
+        let local = [WireCodec::Cbor, WireCodec::Postcard];
+        assert_eq!(WireCodec::negotiate(&local, &[WireCodec::Postcard, WireCodec::Cbor]), WireCodec::Cbor);
+        assert_eq!(WireCodec::negotiate(&local, &[WireCodec::Postcard]), WireCodec::Postcard);
+        assert_eq!(WireCodec::negotiate(&local, &WireCodec::DEFAULT_PREFERENCE), WireCodec::Bincode);
+        assert!(WireCodec::is_acceptable(&local, WireCodec::Bincode));
+        assert!(!WireCodec::is_acceptable(&WireCodec::DEFAULT_PREFERENCE, WireCodec::Cbor));
+    }
+}
@@ -2,16 +2,99 @@ use atm0s_sdn_identity::NodeId;
 use bincode::Options;
 use serde::{Deserialize, Serialize};
 
-use super::Authorization;
+use super::{Authorization, CipherSuite, WireCodec};
 
 const MSG_TIMEOUT_MS: u64 = 10000;
 
+/// First byte of a control packet, it has version bits 3 so it never parses as a TransportMsgHeader
+const CONTROL_MARK: u8 = 255;
+/// Second byte of versioned framing. Unversioned packets start the bincode `from` varint there, which is never 254 for an u32
+const CONTROL_MAGIC: u8 = 254;
+/// Version of the control framing and commands, packets of other versions are rejected instead of decoded.
+/// Version 1 is the unversioned framing `[255, bincode]` before cipher negotiation, version 2 is before identity proofs in the connect handshake,
+/// version 3 is before feature versions in the connect handshake, version 4 is before the codec of the commands
+pub const NEIGHBOURS_CONTROL_VERSION: u8 = 5;
+const HEADER_SIZE: usize = 3;
+/// Shortest packet starting with the control mark, in any framing version
+pub const NEIGHBOURS_CONTROL_MIN_LEN: usize = HEADER_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NeighboursControlError {
+    /// Not a control packet, it should be handled as a TransportMsg
+    NotControl,
+    UnsupportedVersion(u8),
+    InvalidData,
+}
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 pub enum NeighboursConnectError {
     AlreadyConnected,
     InvalidSignature,
     InvalidData,
     InvalidState,
+    /// Responder is at its `max_connections` limit
+    ConnectionLimit,
+    /// Responder is at its `max_connections_per_ip` limit for the requester ip
+    IpConnectionLimit,
+    /// Peer is blacklisted, not in the allowlist or refused by the `AcceptPolicy`
+    Blocked,
+    /// Hostname of the address is not found, or none of its resolved addresses could be connected
+    DestinationNotFound,
+    /// Requester signature of the node id challenge is not valid
+    InvalidProof,
+    /// Identity proof of the peer is not signed by a key which its node id is derived from
+    InvalidIdentity,
+    /// Peer has no identity but `NeighboursCfg::require_identity` is set
+    IdentityRequired,
+}
+
+impl NeighboursConnectError {
+    /// Rejections which won't change by retrying the same request soon
+    pub fn is_rejected(&self) -> bool {
+        matches!(
+            self,
+            Self::ConnectionLimit | Self::IpConnectionLimit | Self::Blocked | Self::InvalidProof | Self::InvalidIdentity | Self::IdentityRequired
+        )
+    }
+}
+
+/// Ed25519 public key of the sender and its signature of the connect handshake, see `NodeIdentity`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct IdentityProof {
+    pub public_key: [u8; 32],
+    pub signature: Vec<u8>,
+}
+
+/// Wire versions of the features of a node, by feature id. Each side of a connection advertises its versions in the
+/// connect handshake, so a feature can keep sending the old format to a peer which is not upgraded yet.
+/// A feature which is not listed runs version 1
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FeatureVersions(Vec<(u8, u8)>);
+
+impl FeatureVersions {
+    pub fn new(versions: impl IntoIterator<Item = (u8, u8)>) -> Self {
+        let mut list = Self::default();
+        for (feature, version) in versions {
+            list.set(feature, version);
+        }
+        list
+    }
+
+    pub fn get(&self, feature: u8) -> u8 {
+        self.0.iter().find(|(id, _)| *id == feature).map_or(1, |(_, version)| *version)
+    }
+
+    /// Lower version of each feature, a feature which one side does not list stays at version 1
+    pub fn negotiate(local: &Self, remote: &Self) -> Self {
+        Self::new(local.0.iter().map(|(feature, version)| (*feature, (*version).min(remote.get(*feature)))))
+    }
+
+    pub fn set(&mut self, feature: u8, version: u8) {
+        match self.0.iter_mut().find(|(id, _)| *id == feature) {
+            Some(slot) => slot.1 = version,
+            None => self.0.push((feature, version)),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
@@ -22,26 +105,91 @@ pub enum NeighboursDisconnectReason {
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum NeighboursControlCmds {
-    ConnectRequest { to: NodeId, session: u64, handshake: Vec<u8> },
-    ConnectResponse { session: u64, result: Result<Vec<u8>, NeighboursConnectError> },
-    Ping { session: u64, seq: u64, sent_ms: u64 },
-    Pong { session: u64, seq: u64, sent_ms: u64 },
-    DisconnectRequest { session: u64, reason: NeighboursDisconnectReason },
-    DisconnectResponse { session: u64 },
+    /// `ciphers` and `codecs` are the requester preferences, `identity` is set if the requester has a `NodeIdentity`
+    ConnectRequest {
+        to: NodeId,
+        session: u64,
+        handshake: Vec<u8>,
+        ciphers: Vec<CipherSuite>,
+        identity: Option<IdentityProof>,
+        features: FeatureVersions,
+        codecs: Vec<WireCodec>,
+    },
+    /// Responder which requires a node id proof answers a connect request with a nonce
+    ConnectChallenge {
+        session: u64,
+        nonce: u64,
+    },
+    /// `proof` is the requester signature of the challenge, it is answered with a `ConnectResponse`
+    ConnectProof {
+        session: u64,
+        proof: Vec<u8>,
+    },
+    /// Accepted response carries the cipher and the codec selected by the responder, and its identity proof if it has one
+    ConnectResponse {
+        session: u64,
+        result: Result<(CipherSuite, Vec<u8>), NeighboursConnectError>,
+        identity: Option<IdentityProof>,
+        features: FeatureVersions,
+        codec: WireCodec,
+    },
+    Ping {
+        session: u64,
+        seq: u64,
+        sent_ms: u64,
+    },
+    Pong {
+        session: u64,
+        seq: u64,
+        sent_ms: u64,
+    },
+    DisconnectRequest {
+        session: u64,
+        reason: NeighboursDisconnectReason,
+    },
+    DisconnectResponse {
+        session: u64,
+    },
+    /// Rotate the session key of an established connection, see `NeighbourConnection` for the exchange.
+    /// `epoch` is the key epoch byte which will tag packets encrypted with the new key
+    RekeyRequest {
+        session: u64,
+        epoch: u8,
+        handshake: Vec<u8>,
+    },
+    RekeyResponse {
+        session: u64,
+        epoch: u8,
+        handshake: Vec<u8>,
+    },
+    /// Requester already encrypts with the new key, so the responder can switch too
+    RekeyConfirm {
+        session: u64,
+        epoch: u8,
+    },
 }
 
+/// The framing is always bincode, `cmd` is encoded with `codec` so the receiver decodes it before it knows the connection
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NeighboursControl {
     pub from: NodeId,
+    pub codec: WireCodec,
     pub cmd: Vec<u8>,
     pub signature: Vec<u8>,
 }
 
 impl NeighboursControl {
+    pub fn is_control(first_byte: u8) -> bool {
+        first_byte == CONTROL_MARK
+    }
+
     #[allow(clippy::result_unit_err)]
     pub fn validate(&self, now: u64, auth: &dyn Authorization) -> Result<NeighboursControlCmds, ()> {
         auth.validate(self.from, &self.cmd, &self.signature).ok_or(())?;
-        let (ts, cmd) = bincode::DefaultOptions::new().with_limit(1499).deserialize::<(u64, NeighboursControlCmds)>(&self.cmd).map_err(|_| ())?;
+        let (ts, cmd) = match self.codec {
+            WireCodec::Bincode => bincode::DefaultOptions::new().with_limit(1499).deserialize::<(u64, NeighboursControlCmds)>(&self.cmd).map_err(|_| ())?,
+            codec => codec.decode::<(u64, NeighboursControlCmds)>(&self.cmd).map_err(|_| ())?,
+        };
         if ts + MSG_TIMEOUT_MS < now {
             return Err(());
         }
@@ -49,20 +197,35 @@ impl NeighboursControl {
     }
 
     pub fn build(now: u64, from: NodeId, cmd: NeighboursControlCmds, auth: &dyn Authorization) -> Self {
-        let cmd = bincode::DefaultOptions::new().with_limit(1499).serialize(&(now, cmd)).unwrap();
+        Self::build_with_codec(now, from, cmd, auth, WireCodec::Bincode)
+    }
+
+    pub fn build_with_codec(now: u64, from: NodeId, cmd: NeighboursControlCmds, auth: &dyn Authorization, codec: WireCodec) -> Self {
+        let cmd = match codec {
+            WireCodec::Bincode => bincode::DefaultOptions::new().with_limit(1499).serialize(&(now, cmd)).unwrap(),
+            codec => codec.encode(&(now, cmd)).unwrap(),
+        };
         let signature = auth.sign(&cmd);
-        Self { from, cmd, signature }
+        Self { from, codec, cmd, signature }
     }
 }
 
 impl TryFrom<&[u8]> for NeighboursControl {
-    type Error = ();
+    type Error = NeighboursControlError;
 
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        if value.first() == Some(&255) {
-            bincode::DefaultOptions::new().with_limit(1499).deserialize(&value[1..]).map_err(|_| ())
-        } else {
-            Err(())
+        match value {
+            [CONTROL_MARK, CONTROL_MAGIC, version, payload @ ..] => {
+                if *version != NEIGHBOURS_CONTROL_VERSION {
+                    return Err(NeighboursControlError::UnsupportedVersion(*version));
+                }
+                bincode::DefaultOptions::new()
+                    .with_limit((1500 - HEADER_SIZE) as u64)
+                    .deserialize(payload)
+                    .map_err(|_| NeighboursControlError::InvalidData)
+            }
+            [CONTROL_MARK, ..] => Err(NeighboursControlError::UnsupportedVersion(1)),
+            _ => Err(NeighboursControlError::NotControl),
         }
     }
 }
@@ -72,8 +235,8 @@ impl TryInto<Vec<u8>> for &NeighboursControl {
 
     fn try_into(self) -> Result<Vec<u8>, Self::Error> {
         let mut buf = Vec::with_capacity(1500);
-        buf.push(255);
-        bincode::DefaultOptions::new().with_limit(1499).serialize_into(&mut buf, &self).map_err(|_| ())?;
+        buf.extend_from_slice(&[CONTROL_MARK, CONTROL_MAGIC, NEIGHBOURS_CONTROL_VERSION]);
+        bincode::DefaultOptions::new().with_limit((1500 - HEADER_SIZE) as u64).serialize_into(&mut buf, &self).map_err(|_| ())?;
         Ok(buf)
     }
 }
@@ -96,4 +259,60 @@ mod tests {
         assert_eq!(control.validate(0, &auth), Ok(cmd));
         assert_eq!(control.validate(MSG_TIMEOUT_MS + 1, &auth), Err(()));
     }
+
+    #[test]
+    fn versioned_framing_roundtrip() {
+        let auth = StaticKeyAuthorization::new("demo_key");
+        let control = NeighboursControl::build(0, 1, NeighboursControlCmds::DisconnectResponse { session: 1000 }, &auth);
+        let buf: Vec<u8> = (&control).try_into().expect("Should serialize");
+        assert_eq!(buf[..HEADER_SIZE], [CONTROL_MARK, CONTROL_MAGIC, NEIGHBOURS_CONTROL_VERSION]);
+        let decoded = NeighboursControl::try_from(buf.as_slice()).expect("Should parse");
+        assert_eq!(decoded.validate(0, &auth), Ok(NeighboursControlCmds::DisconnectResponse { session: 1000 }));
+    }
+
+    #[test]
+    fn commands_roundtrip_all_codecs() {
+        let auth = StaticKeyAuthorization::new("demo_key");
+        let cmd = NeighboursControlCmds::ConnectResponse {
+            session: 1000,
+            result: Ok((CipherSuite::Aes256Gcm, vec![1, 2, 3])),
+            identity: None,
+            features: FeatureVersions::new([(3, 2)]),
+            codec: WireCodec::Cbor,
+        };
+        for codec in [WireCodec::Bincode, WireCodec::Postcard, WireCodec::Cbor] {
+            let control = NeighboursControl::build_with_codec(0, 1, cmd.clone(), &auth, codec);
+            let buf: Vec<u8> = (&control).try_into().expect("Should serialize");
+            let decoded = NeighboursControl::try_from(buf.as_slice()).expect("Should parse");
+            assert_eq!(decoded.codec, codec);
+            assert_eq!(decoded.validate(0, &auth), Ok(cmd.clone()));
+        }
+    }
+
+    #[test]
+    fn reject_other_framing_versions() {
+        let auth = StaticKeyAuthorization::new("demo_key");
+        let control = NeighboursControl::build(0, 1, NeighboursControlCmds::DisconnectResponse { session: 1000 }, &auth);
+
+        //v1 framing of older nodes, without magic and version
+        for from in [1, 250, 251, 1000, u32::MAX] {
+            let control = NeighboursControl { from, ..control.clone() };
+            let mut v1 = vec![CONTROL_MARK];
+            bincode::DefaultOptions::new().serialize_into(&mut v1, &control).expect("Should serialize");
+            assert_eq!(NeighboursControl::try_from(v1.as_slice()).unwrap_err(), NeighboursControlError::UnsupportedVersion(1));
+        }
+
+        let mut other: Vec<u8> = (&control).try_into().expect("Should serialize");
+        for version in [2, 3, 4, NEIGHBOURS_CONTROL_VERSION + 1] {
+            other[2] = version;
+            assert_eq!(NeighboursControl::try_from(other.as_slice()).unwrap_err(), NeighboursControlError::UnsupportedVersion(version));
+        }
+
+        assert_eq!(NeighboursControl::try_from([CONTROL_MARK].as_slice()).unwrap_err(), NeighboursControlError::UnsupportedVersion(1));
+        assert_eq!(
+            NeighboursControl::try_from([CONTROL_MARK, CONTROL_MAGIC, NEIGHBOURS_CONTROL_VERSION, 1].as_slice()).unwrap_err(),
+            NeighboursControlError::InvalidData
+        );
+        assert_eq!(NeighboursControl::try_from([0, 1, 2].as_slice()).unwrap_err(), NeighboursControlError::NotControl);
+    }
 }
@@ -1,10 +1,11 @@
 use atm0s_sdn_identity::{ConnId, NodeAddr, NodeId};
 use atm0s_sdn_router::{shadow::ShadowRouter, RouteRule};
+use rand::RngCore;
 use sans_io_runtime::TaskSwitcherChild;
 
 use crate::data_plane::NetPair;
 
-use super::{Buffer, ConnectionCtx, ConnectionEvent, ServiceId, TransportMsgHeader, Ttl};
+use super::{Buffer, ConnectionCtx, ConnectionEvent, HopList, ServiceId, TransportMsgHeader, Ttl};
 
 #[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct NetIncomingMeta {
@@ -31,17 +32,57 @@ impl From<&TransportMsgHeader> for NetIncomingMeta {
     }
 }
 
+/// Traffic class of outgoing packets, the data plane maps it to a DSCP value with `DscpMap`
+#[derive(Debug, Default, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum TrafficClass {
+    #[default]
+    BestEffort,
+    /// Routing and connection keeping traffic, like neighbours and router_sync
+    NetworkControl,
+}
+
 #[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct NetOutgoingMeta {
     pub source: bool,
     pub ttl: Ttl,
     pub meta: u8,
     pub secure: bool,
+    /// Carry visited nodes in the header, so relays drop the message when it loops back. Only used with `RouteRule::ToServices`
+    pub hops: bool,
+    pub class: TrafficClass,
+    /// Key of the flow for picking one of equal-cost paths, packets with the same key stay on the same path.
+    /// None hashes the header fields instead, see [`NetOutgoingMeta::with_flow`]
+    pub flow: Option<u64>,
 }
 
 impl NetOutgoingMeta {
     pub fn new(source: bool, ttl: Ttl, meta: u8, secure: bool) -> Self {
-        Self { source, ttl, meta, secure }
+        Self {
+            source,
+            ttl,
+            meta,
+            secure,
+            hops: false,
+            class: TrafficClass::BestEffort,
+            flow: None,
+        }
+    }
+
+    pub fn with_hops(mut self) -> Self {
+        self.hops = true;
+        self
+    }
+
+    pub fn with_class(mut self, class: TrafficClass) -> Self {
+        self.class = class;
+        self
+    }
+
+    /// Pin the packet to the path of a feature flow, like a stream id. The key is not carried in the header,
+    /// so relays still pick their own next hop by the header fields
+    pub fn with_flow(mut self, flow: u64) -> Self {
+        self.flow = Some(flow);
+        self
     }
 
     pub fn secure() -> Self {
@@ -50,6 +91,9 @@ impl NetOutgoingMeta {
             ttl: Ttl::default(),
             meta: 0,
             secure: true,
+            hops: false,
+            class: TrafficClass::BestEffort,
+            flow: None,
         }
     }
 
@@ -62,6 +106,7 @@ impl NetOutgoingMeta {
                 None
             })
             .set_encrypt(self.secure)
+            .set_hops(self.hops.then(|| HopList::new(node_id)))
     }
 
     pub fn to_incoming(&self, node_id: NodeId) -> NetIncomingMeta {
@@ -98,18 +143,41 @@ impl<UserData> FeatureControlActor<UserData> {
     }
 }
 
+/// Default delivery target of a feature events when the control is sent over the controller ext api.
+/// Explicit worker controls (ExtWorker) are not affected and always deliver to the worker.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FeatureEventTarget {
+    #[default]
+    Controller,
+    /// Handle the control inside the data plane of the same worker, so events don't go over the controller ext output
+    Worker,
+}
+
 #[derive(Debug, Clone)]
 pub enum FeatureSharedInput {
     Tick(u64),
     Connection(ConnectionEvent),
 }
 
+/// Why a direct send of a feature was dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DirectSendError {
+    /// The controller has no connection with this id, it is closed or was never opened
+    ConnClosed,
+    /// The worker has no pinned connection with this id, it is just closed or not pinned yet
+    NotPinned,
+}
+
 #[derive(Debug, Clone)]
 pub enum FeatureInput<'a, UserData, Control, ToController> {
     FromWorker(ToController),
     Control(FeatureControlActor<UserData>, Control),
     Net(&'a ConnectionCtx, NetIncomingMeta, Buffer),
     Local(NetIncomingMeta, Buffer),
+    /// Payload of a `SendRoute` from this feature which is dropped because there is no usable next hop
+    Undeliverable(RouteRule, Buffer),
+    /// A `SendDirect` or `RawDirect` from this feature, in the controller or a worker, which is dropped because the connection is gone
+    DirectUndeliverable(ConnId, DirectSendError),
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -121,6 +189,8 @@ pub enum FeatureOutput<UserData, Event, ToWorker> {
     SendRoute(RouteRule, NetOutgoingMeta, Buffer),
     NeighboursConnectTo(NodeAddr),
     NeighboursDisconnectFrom(NodeId),
+    NeighboursBlacklist(NodeId),
+    NeighboursAllowlistOnly(NodeId),
     OnResourceEmpty,
 }
 
@@ -138,6 +208,8 @@ impl<UserData, Event, ToWorker> FeatureOutput<UserData, Event, ToWorker> {
             FeatureOutput::SendRoute(rule, ttl, buf) => FeatureOutput::SendRoute(rule, ttl, buf),
             FeatureOutput::NeighboursConnectTo(addr) => FeatureOutput::NeighboursConnectTo(addr),
             FeatureOutput::NeighboursDisconnectFrom(id) => FeatureOutput::NeighboursDisconnectFrom(id),
+            FeatureOutput::NeighboursBlacklist(id) => FeatureOutput::NeighboursBlacklist(id),
+            FeatureOutput::NeighboursAllowlistOnly(id) => FeatureOutput::NeighboursAllowlistOnly(id),
             FeatureOutput::OnResourceEmpty => FeatureOutput::OnResourceEmpty,
         }
     }
@@ -212,6 +284,7 @@ impl<UserData, Control, Event, ToController> FeatureWorkerOutput<UserData, Contr
 pub struct FeatureWorkerContext {
     pub node_id: NodeId,
     pub router: ShadowRouter<NetPair>,
+    pub random: Box<dyn RngCore + Send + Sync>,
 }
 
 pub trait FeatureWorker<UserData, SdkControl, SdkEvent, ToController, ToWorker>: TaskSwitcherChild<FeatureWorkerOutput<UserData, SdkControl, SdkEvent, ToController>> {
@@ -1,13 +1,25 @@
+mod accept;
+mod clock;
+mod codec;
 mod control;
 mod feature;
 mod msg;
+mod request;
+mod resolver;
 mod secure;
 mod service;
 
+use std::sync::Arc;
+
+pub use accept::*;
 use atm0s_sdn_identity::{ConnId, NodeId};
+pub use clock::*;
+pub use codec::*;
 pub use control::*;
 pub use feature::*;
 pub use msg::*;
+pub use request::*;
+pub use resolver::*;
 pub use sans_io_runtime::Buffer;
 pub use secure::*;
 pub use service::*;
@@ -19,6 +31,20 @@ pub struct ConnectionCtx {
     pub conn: ConnId,
     pub node: NodeId,
     pub pair: NetPair,
+    /// Feature versions which both sides advertised in the handshake
+    pub features: FeatureVersions,
+    /// Codec which is selected in the handshake, only for messages which are sent directly to this neighbour
+    pub codec: WireCodec,
+    /// Ed25519 public key which the neighbour proved in the handshake, see [`SecureContext::peer_identity`].
+    /// It is shared to keep connection events small
+    pub peer_identity: Option<Arc<[u8; 32]>>,
+}
+
+impl ConnectionCtx {
+    /// Wire version of the feature which both sides speak, messages of newer versions must not be sent
+    pub fn feature_version(&self, feature: u8) -> u8 {
+        self.features.get(feature)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -26,9 +52,62 @@ pub struct ConnectionStats {
     pub rtt_ms: u32,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RekeyReason {
+    /// The encryptor switched to the key of a new epoch after the rekey interval elapsed.
+    /// Epoch keys are derived from the same handshake shared key, this is not a new key exchange
+    Interval,
+    /// A new key was installed by a key exchange with the remote, `epoch` of the stats is the key epoch byte
+    Exchange,
+}
+
+/// When a connection should start a new key exchange, for forward secrecy of long-lived connections.
+/// Both thresholds are disabled by default
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RekeyPolicy {
+    /// Encrypted bytes sent with the current key
+    pub after_bytes: Option<u64>,
+    /// Age of the current key
+    pub after_ms: Option<u64>,
+}
+
+/// Audit metadata for a single rekey, it never contains any key material.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RekeyStats {
+    pub at_ms: u64,
+    pub epoch: u64,
+    /// Total rekeys of this connection, including this one
+    pub rekey_count: u64,
+    /// Encrypted bytes which was sent with the previous key
+    pub bytes_since_last: u64,
+    pub reason: RekeyReason,
+}
+
+/// Why a connection is closed, so a feature can decide between retrying and cleaning up permanently
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// A disconnect request of either side is answered, for example on shutdown or `DisconnectFrom`
+    Graceful,
+    /// The remote stopped answering pings or the disconnect request
+    Timeout,
+    /// The transport reported the link as broken, the built-in udp transport never does
+    TransportError,
+    /// Closed locally because the node is not allowed anymore by the ACL
+    Rejected,
+    /// The remote restarted with a new session, which gets its own connection
+    Replaced,
+}
+
 #[derive(Debug, Clone)]
 pub enum ConnectionEvent {
     Connected(ConnectionCtx, SecureContext),
     Stats(ConnectionCtx, ConnectionStats),
-    Disconnected(ConnectionCtx),
+    Rekey(ConnectionCtx, RekeyStats),
+    /// Path MTU of the connection, the largest UDP payload which passes it
+    Mtu(ConnectionCtx, usize),
+    /// Remote addr of the connection changed, the ctx has the new pair and the second one is the old pair
+    Migrated(ConnectionCtx, NetPair),
+    Disconnected(ConnectionCtx, DisconnectReason),
+    /// Outgoing connection to the node is refused, by our ACL or by the remote, or no address of it is reachable. It won't be retried
+    ConnectRejected(NodeId, NeighboursConnectError),
 }
@@ -6,6 +6,8 @@ use sans_io_runtime::Buffer;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
+use super::{CodecError, WireCodec};
+
 pub const DEFAULT_MSG_TTL: u8 = 64;
 
 const ROUTE_RULE_DIRECT: u8 = 0;
@@ -13,6 +15,14 @@ const ROUTE_RULE_TO_NODE: u8 = 1;
 const ROUTE_RULE_TO_SERVICE: u8 = 2;
 const ROUTE_RULE_TO_SERVICES: u8 = 3;
 const ROUTE_RULE_TO_KEY: u8 = 4;
+const ROUTE_RULE_TO_KEY_REPLICA: u8 = 5;
+const ROUTE_RULE_BROADCAST_SCOPED: u8 = 6;
+/// Bit in the level byte of ToServices, or the radius byte of BroadcastScoped, which is set if the header carries a hop list
+const HOPS_BIT: u8 = 1 << 7;
+
+/// Number of last visited nodes kept in a hop list
+pub const MAX_HOPS: usize = 8;
+const HOP_LIST_SIZE: usize = 4 + 4 * MAX_HOPS;
 
 simple_pub_type!(Ttl, u8);
 
@@ -26,6 +36,7 @@ impl Default for Ttl {
 pub enum TransportMsgHeaderError {
     InvalidVersion,
     InvalidRoute,
+    InvalidHops,
     TooSmall,
 }
 
@@ -54,20 +65,71 @@ pub enum TransportMsgHeaderError {
 ///     - 1: ToNode : which node received this msg will route it to node_id
 ///     - 2: ToService : which node received this msg will route it to service meta
 ///     - 3: ToKey : which node received this msg will route it to key
+///     - 5: ToKeyReplica : which node received this msg will route it to the closest node of the derived replica key
+///     - 6: BroadcastScoped : same as global ToServices, but limited to a radius in hops
 ///     - .. Not used
 ///
 /// - Ttl (TTL): 8 bits
 /// - Feature Id: 8 bits
 ///
-/// - Route destination (Route Destination): 32 bits (if R is not Direct)
+/// - Route destination (Route Destination): 32 bits (if R is not Direct), 64 bits for ToKeyReplica
 ///
 ///     - If route type is ToNode, this field is 32bit node_id
 ///     - If route type is ToService, this field is 32bit service meta
 ///     - If route type is ToKey, this field is 32bit key
+///     - If route type is ToKeyReplica, this field is 32bit key then 8bit replica index and 24bit reserved
+///     - If route type is ToServices, the highest bit of level byte is set if the header has a hop list
+///     - If route type is BroadcastScoped, this field is 8bit service, 7bit radius with the hop list bit, then 16bit seq
 ///
 /// - From Node Id: 32 bits (optional if N bit is set)
+/// - Hop list: 8bit next slot, 8bit filled slots, 16bit reserved, then MAX_HOPS x 32bit node_id (optional for ToServices and BroadcastScoped)
 ///
 
+/// Last visited nodes of a broadcast message, in fixed slots so relays can append in place
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct HopList {
+    next: u8,
+    len: u8,
+    slots: [NodeId; MAX_HOPS],
+}
+
+impl HopList {
+    pub fn new(node: NodeId) -> Self {
+        let mut hops = Self::default();
+        hops.push(node);
+        hops
+    }
+
+    /// Append a node, the oldest one is overwritten when all slots are filled
+    pub fn push(&mut self, node: NodeId) {
+        self.slots[self.next as usize] = node;
+        self.next = (self.next + 1) % MAX_HOPS as u8;
+        self.len = (self.len + 1).min(MAX_HOPS as u8);
+    }
+
+    pub fn contains(&self, node: NodeId) -> bool {
+        self.slots[..self.len as usize].contains(&node)
+    }
+
+    fn write(&self, output: &mut [u8]) {
+        output[0] = self.next;
+        output[1] = self.len;
+        output[2..4].fill(0);
+        for (i, node) in self.slots.iter().enumerate() {
+            output[4 + i * 4..8 + i * 4].copy_from_slice(&node.to_be_bytes());
+        }
+    }
+
+    fn read(bytes: &[u8]) -> Option<Self> {
+        let (next, len) = (bytes[0], bytes[1]);
+        if next as usize >= MAX_HOPS || len as usize > MAX_HOPS {
+            return None;
+        }
+        let slots = std::array::from_fn(|i| NodeId::from_be_bytes([bytes[4 + i * 4], bytes[5 + i * 4], bytes[6 + i * 4], bytes[7 + i * 4]]));
+        Some(Self { next, len, slots })
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct TransportMsgHeader {
     pub version: u8,
@@ -78,6 +140,8 @@ pub struct TransportMsgHeader {
     pub meta: u8,
     /// Which can be anonymous or specific node
     pub from_node: Option<NodeId>,
+    /// Visited nodes for loop detection, only serialized with `RouteRule::ToServices` and `RouteRule::BroadcastScoped`
+    pub hops: Option<HopList>,
 }
 
 impl Default for TransportMsgHeader {
@@ -87,6 +151,9 @@ impl Default for TransportMsgHeader {
 }
 
 impl TransportMsgHeader {
+    /// Size of a Direct header without source, shorter packets can't be parsed
+    pub const MIN_SIZE: usize = 4;
+
     pub fn is_secure(first_byte: u8) -> bool {
         first_byte & 0b0010_0000 != 0
     }
@@ -101,6 +168,7 @@ impl TransportMsgHeader {
             feature: 0,
             meta: 0,
             from_node: None,
+            hops: None,
         }
     }
 
@@ -113,6 +181,7 @@ impl TransportMsgHeader {
             feature,
             meta,
             from_node: None,
+            hops: None,
         }
     }
 
@@ -146,6 +215,19 @@ impl TransportMsgHeader {
         self
     }
 
+    /// Set hop list, only used with `RouteRule::ToServices` and `RouteRule::BroadcastScoped`
+    pub fn set_hops(mut self, hops: Option<HopList>) -> Self {
+        self.hops = hops;
+        self
+    }
+
+    fn serialized_hops(&self) -> Option<&HopList> {
+        match self.route {
+            RouteRule::ToServices(..) | RouteRule::BroadcastScoped(..) => self.hops.as_ref(),
+            _ => None,
+        }
+    }
+
     /// Set rule
     pub fn set_route(mut self, route: RouteRule) -> Self {
         self.route = route;
@@ -184,6 +266,8 @@ impl TransportMsgHeader {
             RouteRule::ToService(_) => ROUTE_RULE_TO_SERVICE,
             RouteRule::ToServices(_, _, _) => ROUTE_RULE_TO_SERVICES,
             RouteRule::ToKey(_) => ROUTE_RULE_TO_KEY,
+            RouteRule::ToKeyReplica(_, _) => ROUTE_RULE_TO_KEY_REPLICA,
+            RouteRule::BroadcastScoped(_, _, _) => ROUTE_RULE_BROADCAST_SCOPED,
         };
 
         output[0] = (self.version << 6) | e_bit | n_bit | (route_type & 15);
@@ -205,7 +289,12 @@ impl TransportMsgHeader {
             }
             RouteRule::ToServices(service, level, seq) => {
                 output[ptr] = service;
-                output[ptr + 1] = level.into();
+                output[ptr + 1] = u8::from(level)
+                    | if self.serialized_hops().is_some() {
+                        HOPS_BIT
+                    } else {
+                        0
+                    };
                 output[ptr + 2..ptr + 4].copy_from_slice(&seq.to_be_bytes());
                 ptr += 4;
             }
@@ -213,23 +302,34 @@ impl TransportMsgHeader {
                 output[ptr..ptr + 4].copy_from_slice(&key.to_be_bytes());
                 ptr += 4;
             }
+            RouteRule::ToKeyReplica(key, replica) => {
+                output[ptr..ptr + 4].copy_from_slice(&key.to_be_bytes());
+                output[ptr + 4] = replica;
+                output[ptr + 5..ptr + 8].fill(0);
+                ptr += 8;
+            }
+            RouteRule::BroadcastScoped(service, seq, radius) => {
+                output[ptr] = service;
+                output[ptr + 1] = radius.min(!HOPS_BIT)
+                    | if self.serialized_hops().is_some() {
+                        HOPS_BIT
+                    } else {
+                        0
+                    };
+                output[ptr + 2..ptr + 4].copy_from_slice(&seq.to_be_bytes());
+                ptr += 4;
+            }
         }
         if let Some(from_node) = self.from_node {
             output[ptr..ptr + 4].copy_from_slice(&from_node.to_be_bytes());
             ptr += 4;
         }
+        if let Some(hops) = self.serialized_hops() {
+            hops.write(&mut output[ptr..ptr + HOP_LIST_SIZE]);
+            ptr += HOP_LIST_SIZE;
+        }
 
-        Some(
-            4 + if self.from_node.is_some() {
-                4
-            } else {
-                0
-            } + if self.route == RouteRule::Direct {
-                0
-            } else {
-                4
-            },
-        )
+        Some(self.serialize_size())
     }
 
     /// Rewrite the ttl in the given buffer with the new ttl.
@@ -251,6 +351,17 @@ impl TransportMsgHeader {
         Some(())
     }
 
+    /// Rewrite the hop list in the given buffer with the current hop list of this header, which must be parsed from the same buffer.
+    pub fn rewrite_hops(&self, buf: &mut [u8]) -> Option<()> {
+        let hops = self.serialized_hops()?;
+        let end = self.serialize_size();
+        if buf.len() < end {
+            return None;
+        }
+        hops.write(&mut buf[end - HOP_LIST_SIZE..end]);
+        Some(())
+    }
+
     /// Decrease the ttl in the given buffer.
     ///
     /// # Arguments
@@ -278,10 +389,14 @@ impl TransportMsgHeader {
             4
         } else {
             0
-        } + if self.route == RouteRule::Direct {
-            0
+        } + match self.route {
+            RouteRule::Direct => 0,
+            RouteRule::ToKeyReplica(_, _) => 8,
+            _ => 4,
+        } + if self.serialized_hops().is_some() {
+            HOP_LIST_SIZE
         } else {
-            4
+            0
         }
     }
 }
@@ -355,6 +470,14 @@ impl TransportMsg {
         self.buffer
     }
 
+    /// Takes ownership of the message and returns its payload, the header bytes are only skipped, not copied.
+    /// The returned buffer keeps the header space in front, so it can be wrapped again with `build_raw` without reallocating.
+    pub fn take_payload(self) -> Buffer {
+        let mut buffer = self.buffer;
+        buffer.move_front_right(self.payload_start).expect("Buffer should bigger or equal header");
+        buffer
+    }
+
     /// Returns a reference to the message buffer.
     pub fn get_buf(&self) -> &[u8] {
         &self.buffer
@@ -408,6 +531,30 @@ impl TransportMsg {
             payload_start: header_size,
         }
     }
+
+    /// Deserializes the message payload with the given codec, bincode is the same as `get_payload_bincode`.
+    pub fn get_payload<M: DeserializeOwned>(&self, codec: WireCodec) -> Result<M, CodecError> {
+        codec.decode(self.payload())
+    }
+
+    /// Constructs a TransportMsg from a message header and payload with the given codec, bincode is the same as `from_payload_bincode`.
+    pub fn from_payload<M: Serialize>(header: TransportMsgHeader, codec: WireCodec, msg: &M) -> Self {
+        if codec == WireCodec::Bincode {
+            return Self::from_payload_bincode(header, msg);
+        }
+        let payload = codec.encode(msg).expect("Should serialize payload");
+        let header_size = header.serialize_size();
+        let mut buffer = Buffer::new(0, header_size + payload.len());
+        let _ = header.to_bytes(buffer.back_mut(header_size)).expect("Should serialize header");
+        buffer.move_back_right(header_size);
+        buffer.push_back(&payload);
+
+        Self {
+            buffer,
+            header,
+            payload_start: header_size,
+        }
+    }
 }
 
 impl TryFrom<Vec<u8>> for TransportMsg {
@@ -422,6 +569,18 @@ impl TryFrom<Vec<u8>> for TransportMsg {
     }
 }
 
+impl TryFrom<Buffer> for TransportMsg {
+    type Error = TransportMsgHeaderError;
+    fn try_from(buffer: Buffer) -> Result<Self, Self::Error> {
+        let header = TransportMsgHeader::try_from(&buffer as &[u8])?;
+        Ok(Self {
+            payload_start: header.serialize_size(),
+            buffer,
+            header,
+        })
+    }
+}
+
 impl TryFrom<&[u8]> for TransportMsg {
     type Error = TransportMsgHeaderError;
     fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
@@ -438,7 +597,7 @@ impl TryFrom<&[u8]> for TransportMsg {
 impl TryFrom<&[u8]> for TransportMsgHeader {
     type Error = TransportMsgHeaderError;
     fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
-        if bytes.len() < 4 {
+        if bytes.len() < Self::MIN_SIZE {
             return Err(TransportMsgHeaderError::TooSmall);
         }
         let version = bytes[0] >> 6; //2 bits
@@ -455,6 +614,7 @@ impl TryFrom<&[u8]> for TransportMsgHeader {
         let meta = bytes[3];
 
         let mut ptr = 4;
+        let mut has_hops = false;
 
         let route = match route_type {
             ROUTE_RULE_DIRECT => RouteRule::Direct,
@@ -478,7 +638,12 @@ impl TryFrom<&[u8]> for TransportMsgHeader {
                 if bytes.len() < ptr + 4 {
                     return Err(TransportMsgHeaderError::TooSmall);
                 }
-                let rr = RouteRule::ToServices(bytes[ptr], ServiceBroadcastLevel::from(bytes[ptr + 1]), u16::from_be_bytes([bytes[ptr + 2], bytes[ptr + 3]]));
+                has_hops = bytes[ptr + 1] & HOPS_BIT != 0;
+                let rr = RouteRule::ToServices(
+                    bytes[ptr],
+                    ServiceBroadcastLevel::from(bytes[ptr + 1] & !HOPS_BIT),
+                    u16::from_be_bytes([bytes[ptr + 2], bytes[ptr + 3]]),
+                );
                 ptr += 4;
                 rr
             }
@@ -490,6 +655,23 @@ impl TryFrom<&[u8]> for TransportMsgHeader {
                 ptr += 4;
                 rr
             }
+            ROUTE_RULE_TO_KEY_REPLICA => {
+                if bytes.len() < ptr + 8 {
+                    return Err(TransportMsgHeaderError::TooSmall);
+                }
+                let rr = RouteRule::ToKeyReplica(NodeId::from_be_bytes([bytes[ptr], bytes[ptr + 1], bytes[ptr + 2], bytes[ptr + 3]]), bytes[ptr + 4]);
+                ptr += 8;
+                rr
+            }
+            ROUTE_RULE_BROADCAST_SCOPED => {
+                if bytes.len() < ptr + 4 {
+                    return Err(TransportMsgHeaderError::TooSmall);
+                }
+                has_hops = bytes[ptr + 1] & HOPS_BIT != 0;
+                let rr = RouteRule::BroadcastScoped(bytes[ptr], u16::from_be_bytes([bytes[ptr + 2], bytes[ptr + 3]]), bytes[ptr + 1] & !HOPS_BIT);
+                ptr += 4;
+                rr
+            }
             _ => return Err(TransportMsgHeaderError::InvalidRoute),
         };
 
@@ -504,6 +686,17 @@ impl TryFrom<&[u8]> for TransportMsgHeader {
             None
         };
 
+        let hops = if has_hops {
+            if bytes.len() < ptr + HOP_LIST_SIZE {
+                return Err(TransportMsgHeaderError::TooSmall);
+            }
+            let hops = HopList::read(&bytes[ptr..ptr + HOP_LIST_SIZE]).ok_or(TransportMsgHeaderError::InvalidHops)?;
+            ptr += HOP_LIST_SIZE;
+            Some(hops)
+        } else {
+            None
+        };
+
         Ok(Self {
             version,
             encrypt: e_bit,
@@ -512,6 +705,7 @@ impl TryFrom<&[u8]> for TransportMsgHeader {
             feature,
             meta,
             from_node,
+            hops,
         })
     }
 }
@@ -532,6 +726,7 @@ mod tests {
             route: RouteRule::Direct,
             encrypt: true,
             from_node: None,
+            hops: None,
         };
         let size = header.to_bytes(&mut buf).expect("should serialize");
         assert_eq!(header.serialize_size(), 4);
@@ -558,6 +753,7 @@ mod tests {
             route: RouteRule::ToNode(4),
             encrypt: true,
             from_node: None,
+            hops: None,
         };
         let size = header.to_bytes(&mut buf).expect("should serialize");
         assert_eq!(header.serialize_size(), 8);
@@ -582,6 +778,7 @@ mod tests {
             route: RouteRule::ToServices(4, ServiceBroadcastLevel::Geo2, 1000),
             encrypt: true,
             from_node: None,
+            hops: None,
         };
         let size = header.to_bytes(&mut buf).expect("should serialize");
         assert_eq!(header.serialize_size(), 8);
@@ -606,6 +803,7 @@ mod tests {
             route: RouteRule::ToService(4),
             encrypt: true,
             from_node: Some(5),
+            hops: None,
         };
         let size = header.to_bytes(&mut buf).expect("should serialize");
         assert_eq!(header.serialize_size(), 12);
@@ -618,6 +816,82 @@ mod tests {
         assert_eq!(header.from_node, Some(5));
     }
 
+    #[test]
+    fn test_header_with_key_replica_dest() {
+        let mut buf = [0; 16];
+        let header = TransportMsgHeader {
+            version: 0,
+            ttl: 1,
+            feature: 2,
+            meta: 3,
+            route: RouteRule::ToKeyReplica(0x01020304, 2),
+            encrypt: false,
+            from_node: Some(5),
+            hops: None,
+        };
+        let size = header.to_bytes(&mut buf).expect("should serialize");
+        assert_eq!(size, 16);
+        assert_eq!(header.serialize_size(), 16);
+        let header2 = TransportMsgHeader::try_from(&buf[0..size]).expect("");
+        assert_eq!(header2, header);
+        assert_eq!(TransportMsgHeader::try_from(&buf[0..8]), Err(TransportMsgHeaderError::TooSmall));
+    }
+
+    #[test]
+    fn test_header_with_hops() {
+        let mut buf = [0; 64];
+        let mut hops = HopList::new(1);
+        hops.push(2);
+        let header = TransportMsgHeader::build(2, 3, RouteRule::ToServices(4, ServiceBroadcastLevel::Geo2, 1000))
+            .set_from_node(Some(5))
+            .set_hops(Some(hops));
+        let size = header.to_bytes(&mut buf).expect("should serialize");
+        assert_eq!(size, 12 + HOP_LIST_SIZE);
+        let mut header2 = TransportMsgHeader::try_from(&buf[0..size]).expect("");
+        assert_eq!(header2, header);
+        assert_eq!(header2.route, RouteRule::ToServices(4, ServiceBroadcastLevel::Geo2, 1000));
+
+        //relay append itself in place
+        header2.hops.as_mut().expect("Should have hops").push(3);
+        header2.rewrite_hops(&mut buf[0..size]).expect("Should rewrite");
+        let header3 = TransportMsgHeader::try_from(&buf[0..size]).expect("");
+        assert!(header3.hops.as_ref().is_some_and(|h| h.contains(1) && h.contains(3) && !h.contains(4)));
+
+        //hops are not serialized for other rules
+        let header = TransportMsgHeader::build(2, 3, RouteRule::ToNode(4)).set_hops(Some(HopList::new(1)));
+        assert_eq!(header.serialize_size(), 8);
+        let size = header.to_bytes(&mut buf).expect("should serialize");
+        assert_eq!(TransportMsgHeader::try_from(&buf[0..size]).expect("").hops, None);
+    }
+
+    #[test]
+    fn test_header_with_scoped_broadcast() {
+        let mut buf = [0; 64];
+        let header = TransportMsgHeader::build(2, 3, RouteRule::BroadcastScoped(4, 1000, 2)).set_from_node(Some(5));
+        let size = header.to_bytes(&mut buf).expect("should serialize");
+        assert_eq!(size, 12);
+        assert_eq!(TransportMsgHeader::try_from(&buf[0..size]).expect(""), header);
+
+        //radius shares its byte with the hop list bit
+        let header = header.set_hops(Some(HopList::new(5)));
+        let size = header.to_bytes(&mut buf).expect("should serialize");
+        assert_eq!(size, 12 + HOP_LIST_SIZE);
+        assert_eq!(TransportMsgHeader::try_from(&buf[0..size]).expect(""), header);
+    }
+
+    #[test]
+    fn hop_list_keep_last_hops() {
+        let mut hops = HopList::new(0);
+        for node in 1..(MAX_HOPS as u32 + 2) {
+            hops.push(node);
+        }
+        assert!(!hops.contains(0));
+        assert!(!hops.contains(1));
+        for node in 2..(MAX_HOPS as u32 + 2) {
+            assert!(hops.contains(node));
+        }
+    }
+
     /// test with invalid version
     #[test]
     fn test_with_invalid_version() {
@@ -630,6 +904,7 @@ mod tests {
             route: RouteRule::ToNode(4),
             encrypt: true,
             from_node: Some(5),
+            hops: None,
         };
         let size = header.to_bytes(&mut buf).expect("should serialize");
         let err = TransportMsgHeader::try_from(&buf[0..size]).unwrap_err();
@@ -653,4 +928,19 @@ mod tests {
         assert_eq!(msg, msg2);
         assert_eq!(msg.payload(), &[1, 2, 3, 4]);
     }
+
+    #[test]
+    fn msg_from_buffer_take_payload() {
+        let msg = TransportMsg::build(1, 2, RouteRule::ToNode(3), &[1, 2, 3, 4]);
+        let msg2 = TransportMsg::try_from(msg.clone().take()).expect("");
+        assert_eq!(msg, msg2);
+
+        let payload = msg2.take_payload();
+        assert_eq!(&payload as &[u8], &[1, 2, 3, 4]);
+
+        //wrapping again with other header reuses the same payload
+        let msg3 = TransportMsg::build_raw(TransportMsgHeader::build(1, 2, RouteRule::Direct), payload);
+        assert_eq!(msg3.header, TransportMsgHeader::build(1, 2, RouteRule::Direct));
+        assert_eq!(msg3.payload(), &[1, 2, 3, 4]);
+    }
 }
@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+
+use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_router::RouteRule;
+use serde::{Deserialize, Serialize};
+
+use super::FeatureControlActor;
+
+/// Request to a remote node's feature, the reply is routed back to `from` with the same `id`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteRequest<T> {
+    pub id: u64,
+    pub from: NodeId,
+    pub body: T,
+}
+
+impl<T> RemoteRequest<T> {
+    /// Rule for sending the reply back to the requester
+    pub fn reply<R>(&self, body: R) -> (RouteRule, RemoteReply<R>) {
+        (RouteRule::ToNode(self.from), RemoteReply { id: self.id, body })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteReply<T> {
+    pub id: u64,
+    pub body: T,
+}
+
+struct Pending<UserData, T> {
+    sent_ms: u64,
+    timeout_ms: u64,
+    actor: FeatureControlActor<UserData>,
+    ctx: T,
+}
+
+/// Requests which are waiting for a remote reply, each one keeps the actor which issued the control and some context `T`.
+///
+/// Requests without a reply within `timeout_ms` are returned by `pop_timeouts`, so the feature can answer the actor with an error.
+pub struct PendingRequests<UserData, T> {
+    seq: u64,
+    timeout_ms: u64,
+    waits: HashMap<u64, Pending<UserData, T>>,
+}
+
+impl<UserData, T> PendingRequests<UserData, T> {
+    pub fn new(timeout_ms: u64) -> Self {
+        Self {
+            seq: 0,
+            timeout_ms,
+            waits: HashMap::new(),
+        }
+    }
+
+    /// Register a request from `actor`, the returned id must be sent inside the request
+    pub fn add(&mut self, now_ms: u64, actor: FeatureControlActor<UserData>, ctx: T) -> u64 {
+        self.add_with_timeout(now_ms, self.timeout_ms, actor, ctx)
+    }
+
+    /// Like `add` but with its own timeout instead of the default one
+    pub fn add_with_timeout(&mut self, now_ms: u64, timeout_ms: u64, actor: FeatureControlActor<UserData>, ctx: T) -> u64 {
+        let id = self.seq;
+        self.seq += 1;
+        self.waits.insert(
+            id,
+            Pending {
+                sent_ms: now_ms,
+                timeout_ms,
+                actor,
+                ctx,
+            },
+        );
+        id
+    }
+
+    /// Id which the next `add` will return
+    pub fn next_id(&self) -> u64 {
+        self.seq
+    }
+
+    /// Build a request with a new id, `from` is the local node
+    pub fn request<B>(&mut self, now_ms: u64, from: NodeId, actor: FeatureControlActor<UserData>, ctx: T, body: B) -> RemoteRequest<B> {
+        RemoteRequest {
+            id: self.add(now_ms, actor, ctx),
+            from,
+            body,
+        }
+    }
+
+    /// Match a reply id, None if it is unknown or already timed out. Returns the actor, context and the time the request was sent
+    pub fn take(&mut self, id: u64) -> Option<(FeatureControlActor<UserData>, T, u64)> {
+        let pending = self.waits.remove(&id)?;
+        Some((pending.actor, pending.ctx, pending.sent_ms))
+    }
+
+    pub fn pop_timeouts(&mut self, now_ms: u64) -> Vec<(FeatureControlActor<UserData>, T)> {
+        let timeouts: Vec<u64> = self.waits.iter().filter(|(_, p)| now_ms >= p.sent_ms + p.timeout_ms).map(|(id, _)| *id).collect();
+        timeouts.into_iter().filter_map(|id| self.waits.remove(&id)).map(|pending| (pending.actor, pending.ctx)).collect()
+    }
+
+    /// Contexts of all waiting requests with their id, for resending them
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (u64, &mut T)> {
+        self.waits.iter_mut().map(|(id, pending)| (*id, &mut pending.ctx))
+    }
+
+    pub fn len(&self) -> usize {
+        self.waits.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waits.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use atm0s_sdn_identity::NodeId;
+    use atm0s_sdn_router::RouteRule;
+
+    use crate::base::FeatureControlActor;
+
+    use super::{PendingRequests, RemoteReply, RemoteRequest};
+
+    /// Echo feature which answers each request with the same body
+    struct Echo {
+        node: NodeId,
+        pending: PendingRequests<u8, NodeId>,
+    }
+
+    impl Echo {
+        fn send(&mut self, now_ms: u64, actor: FeatureControlActor<u8>, dest: NodeId, body: &str) -> (RouteRule, Vec<u8>) {
+            let req = self.pending.request(now_ms, self.node, actor, dest, body.to_string());
+            (RouteRule::ToNode(dest), bincode::serialize(&req).expect("Should serialize"))
+        }
+
+        fn on_request(&self, buf: &[u8]) -> (RouteRule, Vec<u8>) {
+            let req: RemoteRequest<String> = bincode::deserialize(buf).expect("Should deserialize");
+            let (rule, reply) = req.reply(req.body.clone());
+            (rule, bincode::serialize(&reply).expect("Should serialize"))
+        }
+
+        fn on_reply(&mut self, buf: &[u8]) -> Option<(FeatureControlActor<u8>, NodeId, String)> {
+            let reply: RemoteReply<String> = bincode::deserialize(buf).expect("Should deserialize");
+            let (actor, dest, _) = self.pending.take(reply.id)?;
+            Some((actor, dest, reply.body))
+        }
+    }
+
+    #[test]
+    fn echo_reply_to_caller() {
+        let mut node1 = Echo {
+            node: 1,
+            pending: PendingRequests::new(1000),
+        };
+        let node2 = Echo {
+            node: 2,
+            pending: PendingRequests::new(1000),
+        };
+
+        let (rule1, req1) = node1.send(0, FeatureControlActor::Controller(10), 2, "hello");
+        let (_, req2) = node1.send(0, FeatureControlActor::Worker(1, 20), 2, "world");
+        assert_eq!(rule1, RouteRule::ToNode(2));
+        assert_eq!(node1.pending.len(), 2);
+
+        //replies come back out of order, each one still reaches its caller
+        let (rule2, reply2) = node2.on_request(&req2);
+        assert_eq!(rule2, RouteRule::ToNode(1));
+        assert_eq!(node1.on_reply(&reply2), Some((FeatureControlActor::Worker(1, 20), 2, "world".to_string())));
+        let (_, reply1) = node2.on_request(&req1);
+        assert_eq!(node1.on_reply(&reply1), Some((FeatureControlActor::Controller(10), 2, "hello".to_string())));
+
+        //duplicated reply is ignored
+        assert_eq!(node1.on_reply(&reply1), None);
+        assert!(node1.pending.is_empty());
+    }
+
+    #[test]
+    fn timeout_without_reply() {
+        let mut pending = PendingRequests::<u8, ()>::new(1000);
+        let id1 = pending.add(0, FeatureControlActor::Controller(1), ());
+        pending.add(500, FeatureControlActor::Controller(2), ());
+        pending.add_with_timeout(0, 100, FeatureControlActor::Controller(3), ());
+        assert_eq!(pending.pop_timeouts(100), vec![(FeatureControlActor::Controller(3), ())]);
+        assert_eq!(pending.pop_timeouts(999), vec![]);
+        assert_eq!(pending.pop_timeouts(1000), vec![(FeatureControlActor::Controller(1), ())]);
+        //late reply after timeout is unknown
+        assert!(pending.take(id1).is_none());
+        assert_eq!(pending.len(), 1);
+    }
+}
@@ -0,0 +1,17 @@
+use std::net::IpAddr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameResolved {
+    pub host: String,
+    /// Empty if the host is not found
+    pub addrs: Vec<IpAddr>,
+    /// How long the addresses are cached before the next connect resolves the host again
+    pub ttl_ms: u64,
+}
+
+/// Resolves hostnames of `/dns`, `/dns4` and `/dns6` protocols in a NodeAddr.
+/// Lookups must not block: `resolve` starts one and the neighbours manager takes its result with `pop_resolved` on a later tick
+pub trait NameResolver: Send + Sync {
+    fn resolve(&self, host: &str);
+    fn pop_resolved(&self) -> Option<NameResolved>;
+}
@@ -1,13 +1,76 @@
 use std::fmt::Debug;
 
 use atm0s_sdn_identity::NodeId;
+use serde::{Deserialize, Serialize};
 
 use super::Buffer;
 
+/// AEAD cipher of a secure connection, negotiated in the neighbour handshake.
+/// ChaCha20-Poly1305 must be supported by every node, it is used when peers don't share any other suite
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum CipherSuite {
+    ChaCha20Poly1305,
+    Aes256Gcm,
+}
+
+impl CipherSuite {
+    /// Largest overhead of all suites, used when a buffer is prepared before the connection is known
+    pub const MAX_OVERHEAD: usize = 12 + 16;
+    /// Preference of a node which is not configured, AES-256-GCM first because it is faster with hardware AES
+    pub const DEFAULT_PREFERENCE: [CipherSuite; 2] = [CipherSuite::Aes256Gcm, CipherSuite::ChaCha20Poly1305];
+
+    pub fn nonce_len(&self) -> usize {
+        match self {
+            CipherSuite::ChaCha20Poly1305 | CipherSuite::Aes256Gcm => 12,
+        }
+    }
+
+    pub fn tag_len(&self) -> usize {
+        match self {
+            CipherSuite::ChaCha20Poly1305 | CipherSuite::Aes256Gcm => 16,
+        }
+    }
+
+    /// Bytes appended to each encrypted packet
+    pub fn overhead(&self) -> usize {
+        self.nonce_len() + self.tag_len()
+    }
+
+    /// Select the first suite of local preference which is offered by the remote, fallback to ChaCha20-Poly1305
+    pub fn negotiate(local: &[CipherSuite], offered: &[CipherSuite]) -> CipherSuite {
+        local.iter().find(|s| offered.contains(s)).copied().unwrap_or(CipherSuite::ChaCha20Poly1305)
+    }
+
+    /// Check if a suite selected by the remote is acceptable with the local preference
+    pub fn is_acceptable(local: &[CipherSuite], selected: CipherSuite) -> bool {
+        selected == CipherSuite::ChaCha20Poly1305 || local.contains(&selected)
+    }
+
+    /// Sequence of an encrypted packet, which is the last 6 bytes of its trailing nonce
+    pub fn packet_seq(&self, packet: &[u8]) -> Option<u64> {
+        if packet.len() < self.overhead() {
+            return None;
+        }
+        let mut bytes = [0; 8];
+        bytes[2..].copy_from_slice(&packet[packet.len() - 6..]);
+        Some(u64::from_be_bytes(bytes))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct SecureContext {
+    pub(crate) cipher: CipherSuite,
     pub(crate) encryptor: Box<dyn Encryptor>,
     pub(crate) decryptor: Box<dyn Decryptor>,
+    pub(crate) peer_identity: Option<[u8; 32]>,
+}
+
+impl SecureContext {
+    /// Ed25519 public key which the peer proved in the handshake, its node id is derived from it.
+    /// None if the peer has no identity, which is only accepted without `NeighboursCfg::require_identity`
+    pub fn peer_identity(&self) -> Option<&[u8; 32]> {
+        self.peer_identity.as_ref()
+    }
 }
 
 #[mockall::automock]
@@ -31,14 +94,17 @@ pub trait HandshakeBuilder: Send + Sync {
 #[mockall::automock]
 pub trait HandshakeRequester {
     fn create_public_request(&self) -> Result<Vec<u8>, HandshakeError>;
+    /// `offered` is the cipher list sent in the request, it must be bound into the keys with `cipher`
+    /// so a negotiation modified on the way gives both sides different keys
     #[allow(clippy::type_complexity)]
-    fn process_public_response(&mut self, response: &[u8]) -> Result<(Box<dyn Encryptor>, Box<dyn Decryptor>), HandshakeError>;
+    fn process_public_response(&mut self, response: &[u8], offered: &[CipherSuite], cipher: CipherSuite) -> Result<(Box<dyn Encryptor>, Box<dyn Decryptor>), HandshakeError>;
 }
 
 #[mockall::automock]
 pub trait HandshakeResponder {
+    /// `offered` is the cipher list received with the request, see [`HandshakeRequester::process_public_response`]
     #[allow(clippy::type_complexity)]
-    fn process_public_request(&mut self, request: &[u8]) -> Result<(Box<dyn Encryptor>, Box<dyn Decryptor>, Vec<u8>), HandshakeError>;
+    fn process_public_request(&mut self, request: &[u8], offered: &[CipherSuite], cipher: CipherSuite) -> Result<(Box<dyn Encryptor>, Box<dyn Decryptor>, Vec<u8>), HandshakeError>;
 }
 
 #[derive(Debug)]
@@ -46,9 +112,15 @@ pub enum EncryptionError {
     EncryptFailed,
 }
 
+/// Encrypted packets must end with the nonce, and the last 6 bytes of the nonce is a big endian packet sequence
+/// which increases for each packet of the connection, it is checked by the receiver for replay protection.
 #[mockall::automock]
 pub trait Encryptor: Debug + Send + Sync {
     fn encrypt(&mut self, now_ms: u64, data: &mut Buffer) -> Result<(), EncryptionError>;
+    /// Epoch of the key which was used in the latest encrypt, a change of this value is reported as a rekey
+    fn key_epoch(&self) -> u64 {
+        0
+    }
     fn clone_box(&self) -> Box<dyn Encryptor>;
 }
 
@@ -34,6 +34,20 @@ pub enum ServiceOutput<UserData, FeaturesControl, ServiceEvent, ToWorker> {
     OnResourceEmpty,
 }
 
+/// How a plane reacts to traffic targeting a ServiceId which is not registered, for example a disabled service or version skew between nodes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnknownServicePolicy {
+    /// Silently drop the message, only the unknown service counter is increased.
+    #[default]
+    Drop,
+    /// Drop the message and reply `ExtOut::ServiceUnavailable` to the sender if it is an external actor.
+    /// Messages from remote nodes are replied with a transport message, which the sender node reports as `ExtOut::RemoteServiceUnavailable`.
+    Reply,
+}
+
+/// Output budget of services which don't override `ServiceBuilder::output_budget`
+pub const DEFAULT_SERVICE_OUTPUT_BUDGET: u8 = 8;
+
 pub struct ServiceCtx {
     pub node_id: NodeId,
     pub session: u64,
@@ -123,6 +137,10 @@ pub trait ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, ServiceContro
     fn discoverable(&self) -> bool {
         true
     }
+    /// Max outputs popped from the service in one turn before other services with pending outputs are served
+    fn output_budget(&self) -> u8 {
+        DEFAULT_SERVICE_OUTPUT_BUDGET
+    }
     fn create(&self) -> Box<dyn Service<UserData, FeaturesControl, FeaturesEvent, ServiceControl, ServiceEvent, ToController, ToWorker>>;
     fn create_worker(&self) -> Box<dyn ServiceWorker<UserData, FeaturesControl, FeaturesEvent, ServiceControl, ServiceEvent, ToController, ToWorker>>;
 }
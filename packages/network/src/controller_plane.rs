@@ -1,17 +1,35 @@
-use std::{collections::VecDeque, fmt::Debug, hash::Hash, net::SocketAddr, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    hash::Hash,
+    net::SocketAddr,
+    sync::Arc,
+};
 
-use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_identity::{ConnId, NodeId};
 use atm0s_sdn_router::shadow::ShadowRouterHistory;
+use atm0s_sdn_utils::metrics;
 use rand::RngCore;
 use sans_io_runtime::{return_if_none, return_if_some, TaskSwitcher, TaskSwitcherBranch, TaskSwitcherChild};
 
 use crate::{
     base::{
-        Authorization, ConnectionEvent, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput, HandshakeBuilder, ServiceBuilder, ServiceControlActor, ServiceCtx,
-        ServiceInput, ServiceOutput, ServiceSharedInput,
+        AcceptPolicy, Authorization, CipherSuite, ConnectionEvent, DirectSendError, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput, HandshakeBuilder,
+        NameResolver, ServiceBuilder, ServiceControlActor, ServiceCtx, ServiceId, ServiceInput, ServiceOutput, ServiceSharedInput, UnknownServicePolicy, WireCodec,
+    },
+    data_plane::ConnStats,
+    features::{
+        data::DataCfg,
+        dht_kv::DhtKvCfg,
+        local_feature_versions,
+        neighbours::{ConnectionCounts, NeighboursCfg},
+        pubsub::PubSubCfg,
+        router_sync::{Convergence, RouterSyncCfg},
+        vpn::VpnCfg,
+        Features, FeaturesConfig, FeaturesControl, FeaturesEvent,
     },
-    features::{FeaturesControl, FeaturesEvent},
-    ExtIn, ExtOut, LogicControl, LogicEvent,
+    secure::NodeIdentity,
+    ExtIn, ExtOut, LogicControl, LogicEvent, Topology,
 };
 
 use self::{features::FeatureManager, neighbours::NeighboursManager, services::ServiceManager};
@@ -20,6 +38,11 @@ mod features;
 mod neighbours;
 mod services;
 
+/// Features and services get this long for sending their teardown messages, before the connections are closed
+const SHUTDOWN_FLUSH_MS: u64 = 2000;
+/// Connections which are not closed gracefully in this time are dropped
+const SHUTDOWN_DISCONNECT_MS: u64 = 3000;
+
 #[derive(Debug, Clone, convert_enum::From)]
 pub enum Input<UserData, SC, SE, TC> {
     Ext(ExtIn<UserData, SC>),
@@ -42,6 +65,17 @@ enum TaskType {
     Service = 2,
 }
 
+/// Stages of a shutdown, each one ends when its work is done or at its deadline.
+/// New ext inputs are rejected from the first stage on
+enum ShutdownStage {
+    /// Features and services release their resources, e.g. pubsub unsubscribes and dht_kv waits for pending acks
+    Flushing { deadline_ms: u64 },
+    /// Neighbour connections are closed, after the teardown messages are sent over them
+    Disconnecting { deadline_ms: u64 },
+    /// The plane is empty when the last outputs are popped
+    Closed,
+}
+
 pub struct ControllerPlaneCfg<UserData, SC, SE, TC, TW> {
     pub session: u64,
     pub bind_addrs: Vec<SocketAddr>,
@@ -49,8 +83,34 @@ pub struct ControllerPlaneCfg<UserData, SC, SE, TC, TW> {
     pub services: Vec<Arc<dyn ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>,
     pub authorization: Arc<dyn Authorization>,
     pub handshake_builder: Arc<dyn HandshakeBuilder>,
+    /// Signs the neighbour handshakes, its node id must be the node id of this node
+    pub identity: Option<Arc<NodeIdentity>>,
+    /// Checked before the other rules for each new connection
+    pub accept_policy: Arc<dyn AcceptPolicy>,
     pub random: Box<dyn RngCore + Send + Sync>,
     pub history: Arc<dyn ShadowRouterHistory>,
+    pub unknown_service: UnknownServicePolicy,
+    pub router_sync: RouterSyncCfg,
+    pub dht_kv: DhtKvCfg,
+    pub data: DataCfg,
+    pub neighbours: NeighboursCfg,
+    /// Allowed destinations of tunneled packets
+    pub vpn: VpnCfg,
+    /// Access control of pubsub channels
+    pub pubsub: PubSubCfg,
+    /// Cipher preference for new connections, ChaCha20-Poly1305 is always accepted as fallback
+    pub cipher_suites: Vec<CipherSuite>,
+    /// Codec preference of neighbour controls, bincode is always accepted as fallback
+    pub codecs: Vec<WireCodec>,
+    /// Same as DataPlaneCfg::feature_weights, for the features of the controller
+    pub feature_weights: HashMap<Features, u8>,
+    /// Wire versions advertised to neighbours instead of the ones of this build, for pinning a feature to its
+    /// older version until all nodes are upgraded
+    pub feature_versions: HashMap<Features, u8>,
+    /// Features which are constructed, disabled ones drop their packets and controls
+    pub features: FeaturesConfig,
+    /// Resolver for hostnames in NodeAddr, hostnames are skipped without it
+    pub resolver: Option<Arc<dyn NameResolver>>,
 }
 
 pub struct ControllerPlane<UserData, SC, SE, TC, TW> {
@@ -63,8 +123,12 @@ pub struct ControllerPlane<UserData, SC, SE, TC, TW> {
     services: TaskSwitcherBranch<ServiceManager<UserData, SC, SE, TC, TW>, services::Output<UserData, SE, TW>>,
     switcher: TaskSwitcher,
     queue: VecDeque<Output<UserData, SE, TW>>,
-    shutdown: bool,
+    shutdown: Option<ShutdownStage>,
     history: Arc<dyn ShadowRouterHistory>,
+    unknown_service: UnknownServicePolicy,
+    unknown_service_count: u64,
+    /// Latest stats reported by each worker for each connection
+    conn_stats: HashMap<ConnId, HashMap<u16, ConnStats>>,
 }
 
 impl<UserData, SC, SE, TC, TW> ControllerPlane<UserData, SC, SE, TC, TW>
@@ -84,24 +148,110 @@ where
     pub fn new(node_id: NodeId, cfg: ControllerPlaneCfg<UserData, SC, SE, TC, TW>) -> Self {
         log::info!("Create ControllerPlane for node: {}, running session {}", node_id, cfg.session);
         let service_ids = cfg.services.iter().filter(|s| s.discoverable()).map(|s| s.service_id()).collect();
+        let mut random = cfg.random;
+        //features take their seeds first, then the rest of random source belongs to neighbours
+        let features = FeatureManager::new(
+            node_id, cfg.session, service_ids, cfg.router_sync, cfg.dht_kv, cfg.data, cfg.vpn, cfg.pubsub, &cfg.feature_weights, cfg.features, &mut *random,
+        );
 
         Self {
             tick_count: 0,
             feature_ctx: FeatureContext { node_id, session: cfg.session },
             service_ctx: ServiceCtx { node_id, session: cfg.session },
             neighbours: TaskSwitcherBranch::new(
-                NeighboursManager::new(node_id, cfg.bind_addrs, cfg.authorization, cfg.handshake_builder, cfg.random),
+                NeighboursManager::new(
+                    node_id,
+                    cfg.bind_addrs,
+                    cfg.authorization,
+                    cfg.handshake_builder,
+                    cfg.identity,
+                    cfg.accept_policy,
+                    cfg.cipher_suites,
+                    local_feature_versions(&cfg.feature_versions),
+                    cfg.codecs,
+                    random,
+                    cfg.neighbours,
+                    cfg.resolver,
+                ),
                 TaskType::Neighbours,
             ),
-            features: TaskSwitcherBranch::new(FeatureManager::new(node_id, cfg.session, service_ids), TaskType::Feature),
+            features: TaskSwitcherBranch::new(features, TaskType::Feature),
             services: TaskSwitcherBranch::new(ServiceManager::new(cfg.services), TaskType::Service),
             switcher: TaskSwitcher::new(3), //3 types: Neighbours, Feature, Service
             queue: VecDeque::new(),
-            shutdown: false,
+            shutdown: None,
             history: cfg.history,
+            unknown_service: cfg.unknown_service,
+            unknown_service_count: 0,
+            conn_stats: HashMap::new(),
         }
     }
 
+    /// Number of messages dropped because they target an unregistered service
+    pub fn unknown_service_count(&self) -> u64 {
+        self.unknown_service_count
+    }
+
+    /// Install a service after startup, a discoverable one is advertised with the next sync.
+    /// Data planes of the node need the same service with `DataPlane::register_service`
+    pub fn register_service(&mut self, builder: Arc<dyn ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>) {
+        let service = builder.service_id();
+        if builder.discoverable() {
+            self.features.input(&mut self.switcher).register_local_service(service);
+        } else {
+            self.features.input(&mut self.switcher).unregister_local_service(service);
+        }
+        self.services.input(&mut self.switcher).register(builder);
+    }
+
+    /// Remove a service at runtime, later messages to it are handled by the UnknownServicePolicy.
+    /// Returns false if it is not registered
+    pub fn unregister_service(&mut self, service: ServiceId) -> bool {
+        self.features.input(&mut self.switcher).unregister_local_service(*service);
+        self.services.input(&mut self.switcher).unregister(service)
+    }
+
+    /// Number of packets and controls dropped because they target a disabled feature
+    pub fn feature_unavailable_count(&self) -> u64 {
+        self.features.unavailable_count()
+    }
+
+    /// Nodes which run the service with their load weight, needs `RouterSyncCfg::service_load_interval_ms`.
+    /// Use `router_sync::pick_by_inverse_load` to choose one of them for `RouteRule::ToNode`
+    pub fn find_service(&self, service_id: u8) -> Vec<(NodeId, u32)> {
+        self.features.find_service(service_id)
+    }
+
+    /// Generation of the routing table and whether it is stable, see `router_sync::Convergence`
+    pub fn router_convergence(&self, now_ms: u64) -> Convergence {
+        self.features.router_convergence(now_ms)
+    }
+
+    /// Routing table did not change for the quiescence period, for tests which wait on topology changes
+    pub fn is_converged(&self, now_ms: u64) -> bool {
+        self.router_convergence(now_ms).converged
+    }
+
+    /// Number of neighbour connections, which are checked against the connection limits
+    pub fn connection_counts(&self) -> ConnectionCounts {
+        self.neighbours.connection_counts()
+    }
+
+    /// Round trip time of a neighbour connection, measured by keepalive pings
+    pub fn connection_rtt_ms(&self, conn: ConnId) -> Option<u32> {
+        self.neighbours.rtt_ms(conn)
+    }
+
+    /// Traffic counters of a connection summed over all workers, as of their latest report
+    pub fn connection_stats(&self, conn: ConnId) -> Option<ConnStats> {
+        let workers = self.conn_stats.get(&conn)?;
+        let mut total = ConnStats::default();
+        for stats in workers.values() {
+            total.merge(stats);
+        }
+        Some(total)
+    }
+
     pub fn on_tick(&mut self, now_ms: u64) {
         log::trace!("[ControllerPlane] on_tick: {}", now_ms);
         self.neighbours.input(&mut self.switcher).on_tick(now_ms, self.tick_count);
@@ -116,6 +266,10 @@ where
     }
 
     pub fn on_event(&mut self, now_ms: u64, event: Input<UserData, SC, SE, TC>) {
+        if self.shutdown.is_some() && matches!(event, Input::Ext(_)) {
+            log::warn!("[ControllerPlane] Reject ext input after shutdown");
+            return;
+        }
         match event {
             Input::Ext(ExtIn::ConnectTo(addr)) => {
                 self.neighbours.input(&mut self.switcher).on_input(now_ms, neighbours::Input::ConnectTo(addr));
@@ -132,19 +286,66 @@ where
                 );
             }
             Input::Ext(ExtIn::ServicesControl(service, userdata, control)) => {
+                return_if_none!(self.check_service(service, Some(userdata)));
                 self.services
                     .input(&mut self.switcher)
                     .on_input(&self.service_ctx, now_ms, service, ServiceInput::Control(ServiceControlActor::Controller(userdata), control));
             }
+            Input::Ext(ExtIn::QueryTopology(userdata)) => {
+                let topology = Topology {
+                    neighbours: self.neighbours.neighbours(now_ms),
+                    routes: self.features.router_dump(),
+                };
+                self.queue.push_back(Output::Ext(ExtOut::Topology(userdata, Box::new(topology))));
+            }
+            Input::Ext(ExtIn::ExplainRoute(userdata, dest)) => {
+                let explanation = self.features.explain_route(dest).map(Box::new);
+                self.queue.push_back(Output::Ext(ExtOut::RouteExplanation(userdata, dest, explanation)));
+            }
             Input::Control(LogicControl::NetNeighbour(pair, control)) => {
                 self.neighbours.input(&mut self.switcher).on_input(now_ms, neighbours::Input::Control(pair, control));
             }
+            Input::Control(LogicControl::ConnectionRekey(conn, stats)) => {
+                let ctx = return_if_none!(self.neighbours.conn(conn));
+                let event = ConnectionEvent::Rekey(ctx.clone(), stats);
+                self.features
+                    .input(&mut self.switcher)
+                    .on_shared_input(&self.feature_ctx, now_ms, FeatureSharedInput::Connection(event.clone()));
+                self.services
+                    .input(&mut self.switcher)
+                    .on_shared_input(&self.service_ctx, now_ms, ServiceSharedInput::Connection(event));
+            }
+            Input::Control(LogicControl::ConnectionMtu(conn, mtu)) => {
+                let ctx = return_if_none!(self.neighbours.conn(conn));
+                let event = ConnectionEvent::Mtu(ctx.clone(), mtu);
+                self.features
+                    .input(&mut self.switcher)
+                    .on_shared_input(&self.feature_ctx, now_ms, FeatureSharedInput::Connection(event.clone()));
+                self.services
+                    .input(&mut self.switcher)
+                    .on_shared_input(&self.service_ctx, now_ms, ServiceSharedInput::Connection(event));
+            }
+            Input::Control(LogicControl::ConnectionMigrated(conn, old_pair, new_pair)) => {
+                self.neighbours.input(&mut self.switcher).on_input(now_ms, neighbours::Input::Migrate(conn, old_pair, new_pair));
+            }
+            Input::Control(LogicControl::ConnectionStats(worker, stats)) => {
+                for (conn, stats) in stats {
+                    //reports can arrive after the connection is closed
+                    if self.neighbours.conn(conn).is_some() {
+                        self.conn_stats.entry(conn).or_default().insert(worker, stats);
+                    }
+                }
+            }
+            Input::Control(LogicControl::ConnectionRekeyRequest(conn)) => {
+                self.neighbours.input(&mut self.switcher).on_input(now_ms, neighbours::Input::Rekey(conn));
+            }
             Input::Control(LogicControl::Feature(to)) => {
                 self.features
                     .input(&mut self.switcher)
                     .on_input(&self.feature_ctx, now_ms, to.to_feature(), FeatureInput::FromWorker(to));
             }
             Input::Control(LogicControl::Service(service, to)) => {
+                return_if_none!(self.check_service(service, None));
                 self.services.input(&mut self.switcher).on_input(&self.service_ctx, now_ms, service, ServiceInput::FromWorker(to));
             }
             Input::Control(LogicControl::NetRemote(feature, conn, meta, msg)) => {
@@ -155,10 +356,26 @@ where
             Input::Control(LogicControl::NetLocal(feature, meta, msg)) => {
                 self.features.input(&mut self.switcher).on_input(&self.feature_ctx, now_ms, feature, FeatureInput::Local(meta, msg));
             }
+            Input::Control(LogicControl::NetUndeliverable(feature, rule, msg)) => {
+                self.features
+                    .input(&mut self.switcher)
+                    .on_input(&self.feature_ctx, now_ms, feature, FeatureInput::Undeliverable(rule, msg));
+            }
+            Input::Control(LogicControl::NetDirectUndeliverable(feature, conn, reason)) => {
+                self.features
+                    .input(&mut self.switcher)
+                    .on_input(&self.feature_ctx, now_ms, feature, FeatureInput::DirectUndeliverable(conn, reason));
+            }
             Input::Control(LogicControl::ServiceEvent(service, event)) => {
+                return_if_none!(self.check_service(service, None));
                 self.services.input(&mut self.switcher).on_input(&self.service_ctx, now_ms, service, ServiceInput::FeatureEvent(event));
             }
             Input::Control(LogicControl::ServicesControl(actor, service, control)) => {
+                let sender = match actor {
+                    ServiceControlActor::Controller(userdata) => Some(userdata),
+                    ServiceControlActor::Worker(..) => None,
+                };
+                return_if_none!(self.check_service(service, sender));
                 self.services
                     .input(&mut self.switcher)
                     .on_input(&self.service_ctx, now_ms, service, ServiceInput::Control(actor, control));
@@ -177,15 +394,63 @@ where
         }
     }
 
+    /// Start the shutdown stages, connections are closed only after features and services flushed their teardown
     pub fn on_shutdown(&mut self, now_ms: u64) {
-        if self.shutdown {
+        if self.shutdown.is_some() {
             return;
         }
-        log::info!("[ControllerPlane] Shutdown");
+        log::info!("[ControllerPlane] Shutdown, flushing features and services");
         self.features.input(&mut self.switcher).on_shutdown(&self.feature_ctx, now_ms);
         self.services.input(&mut self.switcher).on_shutdown(&self.service_ctx, now_ms);
-        self.neighbours.input(&mut self.switcher).on_shutdown(now_ms);
-        self.shutdown = true;
+        self.shutdown = Some(ShutdownStage::Flushing {
+            deadline_ms: now_ms + SHUTDOWN_FLUSH_MS,
+        });
+    }
+
+    /// Called when all tasks are idle, returns true if the next stage was started and may have outputs
+    fn update_shutdown(&mut self, now_ms: u64) -> bool {
+        match self.shutdown {
+            Some(ShutdownStage::Flushing { deadline_ms }) => {
+                let flushed = self.features.is_empty() && self.services.is_empty();
+                if !flushed && now_ms < deadline_ms {
+                    return false;
+                }
+                if !flushed {
+                    log::warn!("[ControllerPlane] Shutdown flush timeout after {SHUTDOWN_FLUSH_MS} ms, closing connections anyway");
+                }
+                log::info!("[ControllerPlane] Shutdown, closing connections");
+                self.neighbours.input(&mut self.switcher).on_shutdown(now_ms);
+                self.shutdown = Some(ShutdownStage::Disconnecting {
+                    deadline_ms: now_ms + SHUTDOWN_DISCONNECT_MS,
+                });
+                true
+            }
+            Some(ShutdownStage::Disconnecting { .. }) if self.neighbours.is_empty() => {
+                log::info!("[ControllerPlane] Shutdown, all connections closed");
+                self.shutdown = Some(ShutdownStage::Closed);
+                false
+            }
+            Some(ShutdownStage::Disconnecting { deadline_ms }) if now_ms >= deadline_ms => {
+                log::warn!("[ControllerPlane] Shutdown disconnect timeout after {SHUTDOWN_DISCONNECT_MS} ms, dropping remaining connections");
+                self.neighbours.input(&mut self.switcher).drop_connections();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Return None if the service is not registered, after applying the configured UnknownServicePolicy.
+    /// The sender is only set when the message comes from an external actor on this controller.
+    fn check_service(&mut self, service: ServiceId, sender: Option<UserData>) -> Option<()> {
+        if self.services.has_service(service) {
+            return Some(());
+        }
+        self.unknown_service_count += 1;
+        log::warn!("[ControllerPlane] message for unknown service {service} => apply policy {:?}", self.unknown_service);
+        if let (UnknownServicePolicy::Reply, Some(userdata)) = (self.unknown_service, sender) {
+            self.queue.push_back(Output::Ext(ExtOut::ServiceUnavailable(service, userdata)));
+        }
+        None
     }
 
     fn pop_neighbours(&mut self, now_ms: u64) {
@@ -200,11 +465,24 @@ where
                     .input(&mut self.switcher)
                     .on_shared_input(&self.service_ctx, now_ms, ServiceSharedInput::Connection(event.clone()));
                 match event {
-                    ConnectionEvent::Connected(ctx, secure) => self.queue.push_back(Output::Event(LogicEvent::Pin(ctx.conn, ctx.node, ctx.pair, secure))),
+                    ConnectionEvent::Connected(ctx, secure) => {
+                        metrics::CONNECTIONS.inc();
+                        self.queue.push_back(Output::Event(LogicEvent::Pin(ctx.conn, ctx.node, ctx.pair, secure)));
+                    }
                     ConnectionEvent::Stats(_ctx, _stats) => {}
-                    ConnectionEvent::Disconnected(ctx) => self.queue.push_back(Output::Event(LogicEvent::UnPin(ctx.conn))),
+                    ConnectionEvent::Rekey(_ctx, _stats) => {}
+                    ConnectionEvent::Mtu(_ctx, _mtu) => {}
+                    ConnectionEvent::Migrated(ctx, old_pair) => self.queue.push_back(Output::Event(LogicEvent::Migrate(ctx.conn, old_pair, ctx.pair))),
+                    ConnectionEvent::ConnectRejected(..) => {}
+                    ConnectionEvent::Disconnected(ctx, reason) => {
+                        metrics::CONNECTIONS.dec();
+                        self.conn_stats.remove(&ctx.conn);
+                        self.queue.push_back(Output::Event(LogicEvent::UnPin(ctx.conn, reason)));
+                    }
                 }
             }
+            neighbours::Output::Rekey(conn, epoch, secure, activate) => self.queue.push_back(Output::Event(LogicEvent::Rekey(conn, epoch, secure, activate))),
+            neighbours::Output::RekeyActivate(conn, epoch) => self.queue.push_back(Output::Event(LogicEvent::RekeyActivate(conn, epoch))),
             neighbours::Output::OnResourceEmpty => {
                 log::info!("[ControllerPlane] Neighbours OnResourceEmpty");
             }
@@ -230,14 +508,22 @@ where
                     FeatureControlActor::Controller(userdata) => self.queue.push_back(Output::Ext(ExtOut::FeaturesEvent(userdata, event))),
                     FeatureControlActor::Worker(worker, userdata) => self.queue.push_back(Output::Event(LogicEvent::ExtFeaturesEvent(worker, userdata, event))),
                     FeatureControlActor::Service(service) => {
+                        return_if_none!(self.check_service(service, None));
                         self.services.input(&mut self.switcher).on_input(&self.service_ctx, now_ms, service, ServiceInput::FeatureEvent(event));
                     }
                 }
             }
             FeatureOutput::SendDirect(conn, meta, buf) => {
                 log::debug!("[ControllerPlane] SendDirect to conn: {:?}, len: {}", conn, buf.len());
-                let conn_ctx = return_if_none!(self.neighbours.conn(conn));
-                self.queue.push_back(Output::Event(LogicEvent::NetDirect(feature, conn_ctx.pair, conn, meta, buf)))
+                match self.neighbours.conn(conn) {
+                    Some(conn_ctx) => self.queue.push_back(Output::Event(LogicEvent::NetDirect(feature, conn_ctx.pair, conn, meta, buf))),
+                    None => {
+                        log::debug!("[ControllerPlane] SendDirect of {feature:?} to closed conn {conn}");
+                        self.features
+                            .input(&mut self.switcher)
+                            .on_input(&self.feature_ctx, now_ms, feature, FeatureInput::DirectUndeliverable(conn, DirectSendError::ConnClosed));
+                    }
+                }
             }
             FeatureOutput::SendRoute(rule, ttl, buf) => {
                 log::debug!("[ControllerPlane] SendRoute to rule: {:?}, len: {}", rule, buf.len());
@@ -249,6 +535,12 @@ where
             FeatureOutput::NeighboursDisconnectFrom(node) => {
                 self.neighbours.input(&mut self.switcher).on_input(now_ms, neighbours::Input::DisconnectFrom(node));
             }
+            FeatureOutput::NeighboursBlacklist(node) => {
+                self.neighbours.input(&mut self.switcher).add_blacklist(now_ms, node);
+            }
+            FeatureOutput::NeighboursAllowlistOnly(node) => {
+                self.neighbours.input(&mut self.switcher).add_allowlist_only(now_ms, node);
+            }
             FeatureOutput::OnResourceEmpty => {
                 log::info!("[ControllerPlane] Feature {feature:?} OnResourceEmpty");
             }
@@ -295,22 +587,198 @@ where
     }
 
     fn is_empty(&self) -> bool {
-        self.shutdown && self.queue.is_empty() && self.neighbours.is_empty() && self.features.is_empty() && self.services.is_empty()
+        matches!(self.shutdown, Some(ShutdownStage::Closed)) && self.queue.is_empty()
     }
 
     fn pop_output(&mut self, now_ms: u64) -> Option<Output<UserData, SE, TW>> {
         return_if_some!(self.queue.pop_front());
 
-        while let Some(current) = self.switcher.current() {
-            match current.try_into().expect("Should convert to TaskType") {
-                TaskType::Neighbours => self.pop_neighbours(now_ms),
-                TaskType::Feature => self.pop_features(now_ms),
-                TaskType::Service => self.pop_services(now_ms),
+        loop {
+            while let Some(current) = self.switcher.current() {
+                match current.try_into().expect("Should convert to TaskType") {
+                    TaskType::Neighbours => self.pop_neighbours(now_ms),
+                    TaskType::Feature => self.pop_features(now_ms),
+                    TaskType::Service => self.pop_services(now_ms),
+                }
+
+                return_if_some!(self.queue.pop_front());
             }
 
-            return_if_some!(self.queue.pop_front());
+            if !self.update_shutdown(now_ms) {
+                return None;
+            }
         }
+    }
+}
 
-        None
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+        sync::Arc,
+    };
+
+    use atm0s_sdn_identity::{NodeAddrBuilder, NodeId, Protocol};
+    use atm0s_sdn_router::shadow::MockShadowRouterHistory;
+    use rand::rngs::mock::StepRng;
+    use sans_io_runtime::TaskSwitcherChild;
+
+    use crate::{
+        base::{AcceptAll, CipherSuite, NeighboursControlCmds, WireCodec},
+        data_plane::NetPair,
+        features::{
+            pubsub::{self, ChannelControl, ChannelId},
+            FeaturesControl, FeaturesToWorker,
+        },
+        secure::{HandshakeBuilderXDA, StaticKeyAuthorization},
+        ExtIn, ExtOut, LogicControl, LogicEvent,
+    };
+
+    use super::{ControllerPlane, ControllerPlaneCfg, Input, Output};
+
+    type TestPlane = ControllerPlane<(), (), (), (), ()>;
+    type TestOutput = Output<(), (), ()>;
+
+    fn addr(node: NodeId) -> SocketAddr {
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, node as u16))
+    }
+
+    fn create_plane(node_id: NodeId) -> TestPlane {
+        let mut history = MockShadowRouterHistory::new();
+        history.expect_already_received_broadcast().return_const(false);
+        history.expect_set_ts().return_const(());
+        ControllerPlane::new(
+            node_id,
+            ControllerPlaneCfg {
+                session: node_id as u64,
+                bind_addrs: vec![addr(node_id)],
+                services: vec![],
+                authorization: Arc::new(StaticKeyAuthorization::new("demo-key")),
+                handshake_builder: Arc::new(HandshakeBuilderXDA),
+                identity: None,
+                accept_policy: Arc::new(AcceptAll),
+                random: Box::new(StepRng::new(node_id as u64 * 1000, 1)),
+                history: Arc::new(history),
+                unknown_service: Default::default(),
+                router_sync: Default::default(),
+                dht_kv: Default::default(),
+                data: Default::default(),
+                neighbours: Default::default(),
+                vpn: Default::default(),
+                pubsub: Default::default(),
+                cipher_suites: CipherSuite::DEFAULT_PREFERENCE.to_vec(),
+                codecs: WireCodec::DEFAULT_PREFERENCE.to_vec(),
+                feature_weights: Default::default(),
+                feature_versions: Default::default(),
+                features: Default::default(),
+                resolver: None,
+            },
+        )
+    }
+
+    fn pop_all(now: u64, plane: &mut TestPlane) -> Vec<TestOutput> {
+        let mut outputs = vec![];
+        while let Some(out) = plane.pop_output(now) {
+            outputs.push(out);
+        }
+        outputs
+    }
+
+    /// Deliver neighbour controls between the planes until there is none, other outputs are dropped
+    fn exchange(now: u64, planes: &mut [TestPlane; 2]) {
+        loop {
+            let mut controls = vec![];
+            for (index, plane) in planes.iter_mut().enumerate() {
+                for out in pop_all(now, plane) {
+                    if let Output::Event(LogicEvent::NetNeighbour(pair, control)) = out {
+                        controls.push((1 - index, NetPair::new(pair.remote, pair.local), control));
+                    }
+                }
+            }
+            if controls.is_empty() {
+                return;
+            }
+            for (index, pair, control) in controls {
+                planes[index].on_event(now, Input::Control(LogicControl::NetNeighbour(pair, control)));
+            }
+        }
+    }
+
+    #[test]
+    fn shutdown_should_flush_features_before_disconnect() {
+        let mut planes = [create_plane(1), create_plane(2)];
+        let mut builder = NodeAddrBuilder::new(2);
+        builder.add_protocol(Protocol::Ip4(Ipv4Addr::LOCALHOST));
+        builder.add_protocol(Protocol::Udp(2));
+        planes[0].on_event(0, Input::Ext(ExtIn::ConnectTo(builder.addr())));
+        for step in 0..10 {
+            for plane in planes.iter_mut() {
+                plane.on_tick(step * 100);
+            }
+            exchange(step * 100, &mut planes);
+        }
+        assert_eq!(planes[0].connection_counts().established, 1);
+
+        let channel = ChannelId(1);
+        let control = FeaturesControl::PubSub(pubsub::Control(channel, ChannelControl::PubStart));
+        planes[0].on_event(1000, Input::Ext(ExtIn::FeaturesControl((), control)));
+        exchange(1000, &mut planes);
+
+        planes[0].on_shutdown(1000);
+        //new ext inputs are rejected after shutdown started
+        planes[0].on_event(1000, Input::Ext(ExtIn::QueryTopology(())));
+        let outputs = pop_all(1000, &mut planes[0]);
+        assert!(!outputs.iter().any(|out| matches!(out, Output::Ext(ExtOut::Topology(..)))));
+
+        let auth = StaticKeyAuthorization::new("demo-key");
+        let unregister = outputs
+            .iter()
+            .position(|out| matches!(out, Output::Event(LogicEvent::Feature(true, FeaturesToWorker::PubSub(pubsub::ToWorker::SourceHint(c, None, _)))) if *c == channel))
+            .expect("Should unregister the local source");
+        let disconnect = outputs
+            .iter()
+            .position(|out| match out {
+                Output::Event(LogicEvent::NetNeighbour(_, control)) => {
+                    matches!(control.validate(1000, &auth), Ok(NeighboursControlCmds::DisconnectRequest { .. }))
+                }
+                _ => false,
+            })
+            .expect("Should request disconnect");
+        assert!(unregister < disconnect);
+        assert!(!planes[0].is_empty());
+
+        //the plane is empty after the neighbour answered the disconnect
+        for out in outputs {
+            if let Output::Event(LogicEvent::NetNeighbour(pair, control)) = out {
+                planes[1].on_event(1000, Input::Control(LogicControl::NetNeighbour(NetPair::new(pair.remote, pair.local), control)));
+            }
+        }
+        exchange(1000, &mut planes);
+        assert!(planes[0].is_empty());
+    }
+
+    #[test]
+    fn shutdown_should_drop_connections_after_deadline() {
+        let mut planes = [create_plane(1), create_plane(2)];
+        let mut builder = NodeAddrBuilder::new(2);
+        builder.add_protocol(Protocol::Ip4(Ipv4Addr::LOCALHOST));
+        builder.add_protocol(Protocol::Udp(2));
+        planes[0].on_event(0, Input::Ext(ExtIn::ConnectTo(builder.addr())));
+        for step in 0..10 {
+            for plane in planes.iter_mut() {
+                plane.on_tick(step * 100);
+            }
+            exchange(step * 100, &mut planes);
+        }
+
+        //the remote never answers the disconnect request
+        planes[0].on_shutdown(1000);
+        pop_all(1000, &mut planes[0]);
+        assert!(!planes[0].is_empty());
+
+        let now = 1000 + super::SHUTDOWN_DISCONNECT_MS;
+        planes[0].on_tick(now);
+        pop_all(now, &mut planes[0]);
+        assert!(planes[0].is_empty());
     }
 }
@@ -1,7 +1,10 @@
+use std::collections::HashMap;
 use std::fmt::Debug;
 use std::hash::Hash;
 
 use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_router::core::{RouteExplanation, RouterDump};
+use rand::RngCore;
 use sans_io_runtime::{TaskSwitcher, TaskSwitcherBranch, TaskSwitcherChild};
 
 use crate::base::{Feature, FeatureContext, FeatureInput, FeatureOutput, FeatureSharedInput};
@@ -25,83 +28,375 @@ pub struct FeatureManager<UserData> {
     neighbours: TaskSwitcherBranch<neighbours::NeighboursFeature<UserData>, neighbours::Output<UserData>>,
     data: TaskSwitcherBranch<data::DataFeature<UserData>, data::Output<UserData>>,
     router_sync: TaskSwitcherBranch<router_sync::RouterSyncFeature<UserData>, router_sync::Output<UserData>>,
-    vpn: TaskSwitcherBranch<vpn::VpnFeature<UserData>, vpn::Output<UserData>>,
-    dht_kv: TaskSwitcherBranch<dht_kv::DhtKvFeature<UserData>, dht_kv::Output<UserData>>,
-    pubsub: TaskSwitcherBranch<pubsub::PubSubFeature<UserData>, pubsub::Output<UserData>>,
-    alias: TaskSwitcherBranch<alias::AliasFeature<UserData>, alias::Output<UserData>>,
-    socket: TaskSwitcherBranch<socket::SocketFeature<UserData>, socket::Output<UserData>>,
+    vpn: Option<TaskSwitcherBranch<vpn::VpnFeature<UserData>, vpn::Output<UserData>>>,
+    dht_kv: Option<TaskSwitcherBranch<dht_kv::DhtKvFeature<UserData>, dht_kv::Output<UserData>>>,
+    pubsub: Option<TaskSwitcherBranch<pubsub::PubSubFeature<UserData>, pubsub::Output<UserData>>>,
+    alias: Option<TaskSwitcherBranch<alias::AliasFeature<UserData>, alias::Output<UserData>>>,
+    socket: Option<TaskSwitcherBranch<socket::SocketFeature<UserData>, socket::Output<UserData>>>,
+    rpc: Option<TaskSwitcherBranch<rpc::RpcFeature<UserData>, rpc::Output<UserData>>>,
+    hole_punch: Option<TaskSwitcherBranch<hole_punch::HolePunchFeature<UserData>, hole_punch::Output<UserData>>>,
     switcher: TaskSwitcher,
+    scheduler: FeatureScheduler,
+    features: FeaturesConfig,
+    unavailable_count: u64,
     shutdown: bool,
 }
 
 impl<UserData: 'static + Hash + Eq + Copy + Debug> FeatureManager<UserData> {
-    pub fn new(node: NodeId, session: u64, services: Vec<u8>) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        node: NodeId,
+        session: u64,
+        services: Vec<u8>,
+        router_sync: router_sync::RouterSyncCfg,
+        dht_kv: dht_kv::DhtKvCfg,
+        data: data::DataCfg,
+        vpn: vpn::VpnCfg,
+        pubsub: pubsub::PubSubCfg,
+        weights: &HashMap<Features, u8>,
+        features: FeaturesConfig,
+        random: &mut dyn RngCore,
+    ) -> Self {
+        let scheduler = FeatureScheduler::new(weights);
         Self {
-            neighbours: TaskSwitcherBranch::default(Features::Neighbours as usize),
-            data: TaskSwitcherBranch::default(Features::Data as usize),
-            router_sync: TaskSwitcherBranch::new(router_sync::RouterSyncFeature::new(node, services), Features::RouterSync as usize),
-            vpn: TaskSwitcherBranch::default(Features::Vpn as usize),
-            dht_kv: TaskSwitcherBranch::new(dht_kv::DhtKvFeature::new(node, session), Features::DhtKv as usize),
-            pubsub: TaskSwitcherBranch::new(pubsub::PubSubFeature::new(), Features::PubSub as usize),
-            alias: TaskSwitcherBranch::default(Features::Alias as usize),
-            socket: TaskSwitcherBranch::default(Features::Socket as usize),
-            switcher: TaskSwitcher::new(8),
+            neighbours: TaskSwitcherBranch::default(scheduler.slot(Features::Neighbours)),
+            data: TaskSwitcherBranch::new(data::DataFeature::new(data), scheduler.slot(Features::Data)),
+            router_sync: TaskSwitcherBranch::new(router_sync::RouterSyncFeature::new(node, services, router_sync), scheduler.slot(Features::RouterSync)),
+            vpn: features
+                .is_enabled(Features::Vpn)
+                .then(|| TaskSwitcherBranch::new(vpn::VpnFeature::new(vpn), scheduler.slot(Features::Vpn))),
+            dht_kv: features
+                .is_enabled(Features::DhtKv)
+                .then(|| TaskSwitcherBranch::new(dht_kv::DhtKvFeature::new(node, session, dht_kv, random.next_u64()), scheduler.slot(Features::DhtKv))),
+            pubsub: features
+                .is_enabled(Features::PubSub)
+                .then(|| TaskSwitcherBranch::new(pubsub::PubSubFeature::new(pubsub), scheduler.slot(Features::PubSub))),
+            alias: features.is_enabled(Features::Alias).then(|| TaskSwitcherBranch::default(scheduler.slot(Features::Alias))),
+            socket: features.is_enabled(Features::Socket).then(|| TaskSwitcherBranch::default(scheduler.slot(Features::Socket))),
+            rpc: features.is_enabled(Features::Rpc).then(|| TaskSwitcherBranch::default(scheduler.slot(Features::Rpc))),
+            hole_punch: features.is_enabled(Features::HolePunch).then(|| TaskSwitcherBranch::default(scheduler.slot(Features::HolePunch))),
+            switcher: TaskSwitcher::new(FEATURES_COUNT),
+            scheduler,
+            features,
+            unavailable_count: 0,
             shutdown: false,
         }
     }
 
+    /// Number of packets and controls dropped because they target a disabled feature
+    pub fn unavailable_count(&self) -> u64 {
+        self.unavailable_count
+    }
+
+    fn is_available(&mut self, feature: Features) -> bool {
+        if self.features.is_enabled(feature) {
+            return true;
+        }
+        log::debug!("[FeatureManager] drop input for disabled feature {:?}", feature);
+        self.unavailable_count += 1;
+        false
+    }
+
+    pub fn find_service(&self, service: u8) -> Vec<(NodeId, u32)> {
+        self.router_sync.find_service(service)
+    }
+
+    pub fn router_dump(&self) -> RouterDump {
+        self.router_sync.router_dump()
+    }
+
+    pub fn explain_route(&self, dest: NodeId) -> Option<RouteExplanation> {
+        self.router_sync.explain_route(dest)
+    }
+
+    pub fn register_local_service(&mut self, service: u8) {
+        self.router_sync.input(&mut self.switcher).register_local_service(service);
+    }
+
+    pub fn unregister_local_service(&mut self, service: u8) {
+        self.router_sync.input(&mut self.switcher).unregister_local_service(service);
+    }
+
+    pub fn router_convergence(&self, now_ms: u64) -> router_sync::Convergence {
+        self.router_sync.convergence(now_ms)
+    }
+
     pub fn on_shared_input(&mut self, ctx: &FeatureContext, now_ms: u64, input: FeatureSharedInput) {
         self.data.input(&mut self.switcher).on_shared_input(ctx, now_ms, input.clone());
         self.neighbours.input(&mut self.switcher).on_shared_input(ctx, now_ms, input.clone());
         self.router_sync.input(&mut self.switcher).on_shared_input(ctx, now_ms, input.clone());
-        self.dht_kv.input(&mut self.switcher).on_shared_input(ctx, now_ms, input.clone());
-        self.vpn.input(&mut self.switcher).on_shared_input(ctx, now_ms, input.clone());
-        self.pubsub.input(&mut self.switcher).on_shared_input(ctx, now_ms, input.clone());
-        self.alias.input(&mut self.switcher).on_shared_input(ctx, now_ms, input.clone());
-        self.socket.input(&mut self.switcher).on_shared_input(ctx, now_ms, input);
+        if let Some(dht_kv) = &mut self.dht_kv {
+            dht_kv.input(&mut self.switcher).on_shared_input(ctx, now_ms, input.clone());
+        }
+        if let Some(vpn) = &mut self.vpn {
+            vpn.input(&mut self.switcher).on_shared_input(ctx, now_ms, input.clone());
+        }
+        if let Some(pubsub) = &mut self.pubsub {
+            pubsub.input(&mut self.switcher).on_shared_input(ctx, now_ms, input.clone());
+        }
+        if let Some(alias) = &mut self.alias {
+            alias.input(&mut self.switcher).on_shared_input(ctx, now_ms, input.clone());
+        }
+        if let Some(socket) = &mut self.socket {
+            socket.input(&mut self.switcher).on_shared_input(ctx, now_ms, input.clone());
+        }
+        if let Some(rpc) = &mut self.rpc {
+            rpc.input(&mut self.switcher).on_shared_input(ctx, now_ms, input.clone());
+        }
+        if let Some(hole_punch) = &mut self.hole_punch {
+            hole_punch.input(&mut self.switcher).on_shared_input(ctx, now_ms, input);
+        }
     }
 
     pub fn on_input(&mut self, ctx: &FeatureContext, now_ms: u64, feature: Features, input: FeaturesInput<'_, UserData>) {
+        let target = match &input {
+            FeatureInput::FromWorker(to) => to.to_feature(),
+            FeatureInput::Control(_, control) => control.to_feature(),
+            _ => feature,
+        };
+        if !self.is_available(target) {
+            return;
+        }
         match input {
             FeatureInput::FromWorker(to) => match to {
                 FeaturesToController::Data(to) => self.data.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::FromWorker(to)),
                 FeaturesToController::Neighbours(to) => self.neighbours.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::FromWorker(to)),
                 FeaturesToController::RouterSync(to) => self.router_sync.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::FromWorker(to)),
-                FeaturesToController::Vpn(to) => self.vpn.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::FromWorker(to)),
-                FeaturesToController::DhtKv(to) => self.dht_kv.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::FromWorker(to)),
-                FeaturesToController::PubSub(to) => self.pubsub.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::FromWorker(to)),
-                FeaturesToController::Alias(to) => self.alias.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::FromWorker(to)),
-                FeaturesToController::Socket(to) => self.socket.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::FromWorker(to)),
+                FeaturesToController::Vpn(to) => {
+                    if let Some(vpn) = &mut self.vpn {
+                        vpn.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::FromWorker(to));
+                    }
+                }
+                FeaturesToController::DhtKv(to) => {
+                    if let Some(dht_kv) = &mut self.dht_kv {
+                        dht_kv.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::FromWorker(to));
+                    }
+                }
+                FeaturesToController::PubSub(to) => {
+                    if let Some(pubsub) = &mut self.pubsub {
+                        pubsub.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::FromWorker(to));
+                    }
+                }
+                FeaturesToController::Alias(to) => {
+                    if let Some(alias) = &mut self.alias {
+                        alias.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::FromWorker(to));
+                    }
+                }
+                FeaturesToController::Socket(to) => {
+                    if let Some(socket) = &mut self.socket {
+                        socket.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::FromWorker(to));
+                    }
+                }
+                FeaturesToController::Rpc(to) => {
+                    if let Some(rpc) = &mut self.rpc {
+                        rpc.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::FromWorker(to));
+                    }
+                }
+                FeaturesToController::HolePunch(to) => {
+                    if let Some(hole_punch) = &mut self.hole_punch {
+                        hole_punch.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::FromWorker(to));
+                    }
+                }
             },
             FeatureInput::Control(service, control) => match control {
                 FeaturesControl::Data(control) => self.data.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Control(service, control)),
                 FeaturesControl::Neighbours(control) => self.neighbours.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Control(service, control)),
                 FeaturesControl::RouterSync(control) => self.router_sync.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Control(service, control)),
-                FeaturesControl::Vpn(control) => self.vpn.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Control(service, control)),
-                FeaturesControl::DhtKv(control) => self.dht_kv.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Control(service, control)),
-                FeaturesControl::PubSub(control) => self.pubsub.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Control(service, control)),
-                FeaturesControl::Alias(control) => self.alias.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Control(service, control)),
-                FeaturesControl::Socket(control) => self.socket.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Control(service, control)),
+                FeaturesControl::Vpn(control) => {
+                    if let Some(vpn) = &mut self.vpn {
+                        vpn.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Control(service, control));
+                    }
+                }
+                FeaturesControl::DhtKv(control) => {
+                    if let Some(dht_kv) = &mut self.dht_kv {
+                        dht_kv.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Control(service, control));
+                    }
+                }
+                FeaturesControl::PubSub(control) => {
+                    if let Some(pubsub) = &mut self.pubsub {
+                        pubsub.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Control(service, control));
+                    }
+                }
+                FeaturesControl::Alias(control) => {
+                    if let Some(alias) = &mut self.alias {
+                        alias.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Control(service, control));
+                    }
+                }
+                FeaturesControl::Socket(control) => {
+                    if let Some(socket) = &mut self.socket {
+                        socket.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Control(service, control));
+                    }
+                }
+                FeaturesControl::Rpc(control) => {
+                    if let Some(rpc) = &mut self.rpc {
+                        rpc.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Control(service, control));
+                    }
+                }
+                FeaturesControl::HolePunch(control) => {
+                    if let Some(hole_punch) = &mut self.hole_punch {
+                        hole_punch.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Control(service, control));
+                    }
+                }
             },
             FeatureInput::Net(con_ctx, header, buf) => match feature {
                 Features::Data => self.data.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Net(con_ctx, header, buf)),
                 Features::Neighbours => self.neighbours.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Net(con_ctx, header, buf)),
                 Features::RouterSync => self.router_sync.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Net(con_ctx, header, buf)),
-                Features::Vpn => self.vpn.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Net(con_ctx, header, buf)),
-                Features::DhtKv => self.dht_kv.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Net(con_ctx, header, buf)),
-                Features::PubSub => self.pubsub.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Net(con_ctx, header, buf)),
-                Features::Alias => self.alias.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Net(con_ctx, header, buf)),
-                Features::Socket => self.socket.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Net(con_ctx, header, buf)),
+                Features::Vpn => {
+                    if let Some(vpn) = &mut self.vpn {
+                        vpn.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Net(con_ctx, header, buf));
+                    }
+                }
+                Features::DhtKv => {
+                    if let Some(dht_kv) = &mut self.dht_kv {
+                        dht_kv.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Net(con_ctx, header, buf));
+                    }
+                }
+                Features::PubSub => {
+                    if let Some(pubsub) = &mut self.pubsub {
+                        pubsub.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Net(con_ctx, header, buf));
+                    }
+                }
+                Features::Alias => {
+                    if let Some(alias) = &mut self.alias {
+                        alias.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Net(con_ctx, header, buf));
+                    }
+                }
+                Features::Socket => {
+                    if let Some(socket) = &mut self.socket {
+                        socket.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Net(con_ctx, header, buf));
+                    }
+                }
+                Features::Rpc => {
+                    if let Some(rpc) = &mut self.rpc {
+                        rpc.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Net(con_ctx, header, buf));
+                    }
+                }
+                Features::HolePunch => {
+                    if let Some(hole_punch) = &mut self.hole_punch {
+                        hole_punch.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Net(con_ctx, header, buf));
+                    }
+                }
             },
             FeatureInput::Local(header, buf) => match feature {
                 Features::Data => self.data.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Local(header, buf)),
                 Features::Neighbours => self.neighbours.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Local(header, buf)),
                 Features::RouterSync => self.router_sync.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Local(header, buf)),
-                Features::Vpn => self.vpn.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Local(header, buf)),
-                Features::DhtKv => self.dht_kv.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Local(header, buf)),
-                Features::PubSub => self.pubsub.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Local(header, buf)),
-                Features::Alias => self.alias.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Local(header, buf)),
-                Features::Socket => self.socket.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Local(header, buf)),
+                Features::Vpn => {
+                    if let Some(vpn) = &mut self.vpn {
+                        vpn.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Local(header, buf));
+                    }
+                }
+                Features::DhtKv => {
+                    if let Some(dht_kv) = &mut self.dht_kv {
+                        dht_kv.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Local(header, buf));
+                    }
+                }
+                Features::PubSub => {
+                    if let Some(pubsub) = &mut self.pubsub {
+                        pubsub.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Local(header, buf));
+                    }
+                }
+                Features::Alias => {
+                    if let Some(alias) = &mut self.alias {
+                        alias.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Local(header, buf));
+                    }
+                }
+                Features::Socket => {
+                    if let Some(socket) = &mut self.socket {
+                        socket.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Local(header, buf));
+                    }
+                }
+                Features::Rpc => {
+                    if let Some(rpc) = &mut self.rpc {
+                        rpc.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Local(header, buf));
+                    }
+                }
+                Features::HolePunch => {
+                    if let Some(hole_punch) = &mut self.hole_punch {
+                        hole_punch.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Local(header, buf));
+                    }
+                }
+            },
+            FeatureInput::Undeliverable(rule, buf) => match feature {
+                Features::Data => self.data.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Undeliverable(rule, buf)),
+                Features::Neighbours => self.neighbours.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Undeliverable(rule, buf)),
+                Features::RouterSync => self.router_sync.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Undeliverable(rule, buf)),
+                Features::Vpn => {
+                    if let Some(vpn) = &mut self.vpn {
+                        vpn.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Undeliverable(rule, buf));
+                    }
+                }
+                Features::DhtKv => {
+                    if let Some(dht_kv) = &mut self.dht_kv {
+                        dht_kv.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Undeliverable(rule, buf));
+                    }
+                }
+                Features::PubSub => {
+                    if let Some(pubsub) = &mut self.pubsub {
+                        pubsub.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Undeliverable(rule, buf));
+                    }
+                }
+                Features::Alias => {
+                    if let Some(alias) = &mut self.alias {
+                        alias.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Undeliverable(rule, buf));
+                    }
+                }
+                Features::Socket => {
+                    if let Some(socket) = &mut self.socket {
+                        socket.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Undeliverable(rule, buf));
+                    }
+                }
+                Features::Rpc => {
+                    if let Some(rpc) = &mut self.rpc {
+                        rpc.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Undeliverable(rule, buf));
+                    }
+                }
+                Features::HolePunch => {
+                    if let Some(hole_punch) = &mut self.hole_punch {
+                        hole_punch.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Undeliverable(rule, buf));
+                    }
+                }
+            },
+            FeatureInput::DirectUndeliverable(conn, reason) => match feature {
+                Features::Data => self.data.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::DirectUndeliverable(conn, reason)),
+                Features::Neighbours => self.neighbours.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::DirectUndeliverable(conn, reason)),
+                Features::RouterSync => self.router_sync.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::DirectUndeliverable(conn, reason)),
+                Features::Vpn => {
+                    if let Some(vpn) = &mut self.vpn {
+                        vpn.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::DirectUndeliverable(conn, reason));
+                    }
+                }
+                Features::DhtKv => {
+                    if let Some(dht_kv) = &mut self.dht_kv {
+                        dht_kv.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::DirectUndeliverable(conn, reason));
+                    }
+                }
+                Features::PubSub => {
+                    if let Some(pubsub) = &mut self.pubsub {
+                        pubsub.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::DirectUndeliverable(conn, reason));
+                    }
+                }
+                Features::Alias => {
+                    if let Some(alias) = &mut self.alias {
+                        alias.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::DirectUndeliverable(conn, reason));
+                    }
+                }
+                Features::Socket => {
+                    if let Some(socket) = &mut self.socket {
+                        socket.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::DirectUndeliverable(conn, reason));
+                    }
+                }
+                Features::Rpc => {
+                    if let Some(rpc) = &mut self.rpc {
+                        rpc.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::DirectUndeliverable(conn, reason));
+                    }
+                }
+                Features::HolePunch => {
+                    if let Some(hole_punch) = &mut self.hole_punch {
+                        hole_punch.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::DirectUndeliverable(conn, reason));
+                    }
+                }
             },
         }
     }
@@ -114,11 +409,27 @@ impl<UserData: 'static + Hash + Eq + Copy + Debug> FeatureManager<UserData> {
         self.neighbours.input(&mut self.switcher).on_shutdown(ctx, now_ms);
         self.data.input(&mut self.switcher).on_shutdown(ctx, now_ms);
         self.router_sync.input(&mut self.switcher).on_shutdown(ctx, now_ms);
-        self.vpn.input(&mut self.switcher).on_shutdown(ctx, now_ms);
-        self.dht_kv.input(&mut self.switcher).on_shutdown(ctx, now_ms);
-        self.pubsub.input(&mut self.switcher).on_shutdown(ctx, now_ms);
-        self.alias.input(&mut self.switcher).on_shutdown(ctx, now_ms);
-        self.socket.input(&mut self.switcher).on_shutdown(ctx, now_ms);
+        if let Some(vpn) = &mut self.vpn {
+            vpn.input(&mut self.switcher).on_shutdown(ctx, now_ms);
+        }
+        if let Some(dht_kv) = &mut self.dht_kv {
+            dht_kv.input(&mut self.switcher).on_shutdown(ctx, now_ms);
+        }
+        if let Some(pubsub) = &mut self.pubsub {
+            pubsub.input(&mut self.switcher).on_shutdown(ctx, now_ms);
+        }
+        if let Some(alias) = &mut self.alias {
+            alias.input(&mut self.switcher).on_shutdown(ctx, now_ms);
+        }
+        if let Some(socket) = &mut self.socket {
+            socket.input(&mut self.switcher).on_shutdown(ctx, now_ms);
+        }
+        if let Some(rpc) = &mut self.rpc {
+            rpc.input(&mut self.switcher).on_shutdown(ctx, now_ms);
+        }
+        if let Some(hole_punch) = &mut self.hole_punch {
+            hole_punch.input(&mut self.switcher).on_shutdown(ctx, now_ms);
+        }
         self.shutdown = true;
     }
 }
@@ -135,56 +446,78 @@ impl<UserData: Hash + Eq + Copy + Debug> TaskSwitcherChild<Output<UserData>> for
             && self.neighbours.is_empty()
             && self.data.is_empty()
             && self.router_sync.is_empty()
-            && self.vpn.is_empty()
-            && self.dht_kv.is_empty()
-            && self.pubsub.is_empty()
-            && self.alias.is_empty()
-            && self.socket.is_empty()
+            && self.vpn.as_ref().map_or(true, |vpn| vpn.is_empty())
+            && self.dht_kv.as_ref().map_or(true, |dht_kv| dht_kv.is_empty())
+            && self.pubsub.as_ref().map_or(true, |pubsub| pubsub.is_empty())
+            && self.alias.as_ref().map_or(true, |alias| alias.is_empty())
+            && self.socket.as_ref().map_or(true, |socket| socket.is_empty())
+            && self.rpc.as_ref().map_or(true, |rpc| rpc.is_empty())
+            && self.hole_punch.as_ref().map_or(true, |hole_punch| hole_punch.is_empty())
     }
 
     fn pop_output<'a>(&mut self, now: u64) -> Option<Output<UserData>> {
         loop {
-            match (self.switcher.current()? as u8).try_into().ok()? {
+            match self.scheduler.current(&mut self.switcher)? {
                 Features::Neighbours => {
                     if let Some(out) = self.neighbours.pop_output(now, &mut self.switcher) {
+                        self.scheduler.on_output();
                         return Some(Output::Output(Features::Neighbours, out.into2()));
                     }
                 }
                 Features::Data => {
                     if let Some(out) = self.data.pop_output(now, &mut self.switcher) {
+                        self.scheduler.on_output();
                         return Some(Output::Output(Features::Data, out.into2()));
                     }
                 }
                 Features::RouterSync => {
                     if let Some(out) = self.router_sync.pop_output(now, &mut self.switcher) {
+                        self.scheduler.on_output();
                         return Some(Output::Output(Features::RouterSync, out.into2()));
                     }
                 }
                 Features::Vpn => {
-                    if let Some(out) = self.vpn.pop_output(now, &mut self.switcher) {
+                    if let Some(out) = self.vpn.as_mut().and_then(|vpn| vpn.pop_output(now, &mut self.switcher)) {
+                        self.scheduler.on_output();
                         return Some(Output::Output(Features::Vpn, out.into2()));
                     }
                 }
                 Features::DhtKv => {
-                    if let Some(out) = self.dht_kv.pop_output(now, &mut self.switcher) {
+                    if let Some(out) = self.dht_kv.as_mut().and_then(|dht_kv| dht_kv.pop_output(now, &mut self.switcher)) {
+                        self.scheduler.on_output();
                         return Some(Output::Output(Features::DhtKv, out.into2()));
                     }
                 }
                 Features::PubSub => {
-                    if let Some(out) = self.pubsub.pop_output(now, &mut self.switcher) {
+                    if let Some(out) = self.pubsub.as_mut().and_then(|pubsub| pubsub.pop_output(now, &mut self.switcher)) {
+                        self.scheduler.on_output();
                         return Some(Output::Output(Features::PubSub, out.into2()));
                     }
                 }
                 Features::Alias => {
-                    if let Some(out) = self.alias.pop_output(now, &mut self.switcher) {
+                    if let Some(out) = self.alias.as_mut().and_then(|alias| alias.pop_output(now, &mut self.switcher)) {
+                        self.scheduler.on_output();
                         return Some(Output::Output(Features::Alias, out.into2()));
                     }
                 }
                 Features::Socket => {
-                    if let Some(out) = self.socket.pop_output(now, &mut self.switcher) {
+                    if let Some(out) = self.socket.as_mut().and_then(|socket| socket.pop_output(now, &mut self.switcher)) {
+                        self.scheduler.on_output();
                         return Some(Output::Output(Features::Socket, out.into2()));
                     }
                 }
+                Features::Rpc => {
+                    if let Some(out) = self.rpc.as_mut().and_then(|rpc| rpc.pop_output(now, &mut self.switcher)) {
+                        self.scheduler.on_output();
+                        return Some(Output::Output(Features::Rpc, out.into2()));
+                    }
+                }
+                Features::HolePunch => {
+                    if let Some(out) = self.hole_punch.as_mut().and_then(|hole_punch| hole_punch.pop_output(now, &mut self.switcher)) {
+                        self.scheduler.on_output();
+                        return Some(Output::Output(Features::HolePunch, out.into2()));
+                    }
+                }
             }
         }
     }
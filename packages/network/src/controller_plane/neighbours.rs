@@ -1,30 +1,48 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     net::{IpAddr, SocketAddr},
     sync::Arc,
 };
 
 use atm0s_sdn_identity::{ConnId, NodeAddr, NodeId, Protocol};
-use sans_io_runtime::TaskSwitcherChild;
+use sans_io_runtime::{return_if_none, TaskSwitcherChild};
 
 use crate::{
-    base::{self, Authorization, ConnectionCtx, HandshakeBuilder, NeighboursControl, NeighboursControlCmds, SecureContext},
+    base::{
+        self, AcceptPolicy, Authorization, CipherSuite, ConnectionCtx, DisconnectReason, FeatureVersions, HandshakeBuilder, NameResolved, NameResolver, NeighboursConnectError, NeighboursControl,
+        NeighboursControlCmds, SecureContext, WireCodec,
+    },
     data_plane::NetPair,
+    features::neighbours::{ConnectionCounts, IpPreference, NeighboursCfg},
+    memory::memory_socket_addr,
+    secure::NodeIdentity,
+    NeighbourInfo,
 };
 
-use self::connection::{ConnectionEvent, NeighbourConnection};
+use self::{
+    connection::{ConnectionEvent, NeighbourConnection},
+    rate_limit::HandshakeLimiter,
+};
 
 mod connection;
+mod rate_limit;
 
 pub enum Input {
     ConnectTo(NodeAddr),
     DisconnectFrom(NodeId),
     Control(NetPair, NeighboursControl),
+    /// Start a key exchange on an established connection
+    Rekey(ConnId),
+    /// Data plane authenticated a packet of the connection from a new pair
+    Migrate(ConnId, NetPair, NetPair),
 }
 
 pub enum Output {
     Control(NetPair, NeighboursControl),
     Event(base::ConnectionEvent),
+    /// New key material is only for the data planes, so it is not part of [`base::ConnectionEvent`]
+    Rekey(ConnId, u8, SecureContext, bool),
+    RekeyActivate(ConnId, u8),
     OnResourceEmpty,
 }
 
@@ -37,11 +55,51 @@ pub struct NeighboursManager {
     shutdown: bool,
     authorization: Arc<dyn Authorization>,
     handshake_builder: Arc<dyn HandshakeBuilder>,
+    identity: Option<Arc<NodeIdentity>>,
+    accept_policy: Arc<dyn AcceptPolicy>,
+    ciphers: Vec<CipherSuite>,
+    /// Local feature versions, advertised in the handshake of each connection
+    features: FeatureVersions,
+    /// Local codec preference, for the controls of each connection
+    codecs: Vec<WireCodec>,
     random: Box<dyn rand::RngCore>,
+    cfg: NeighboursCfg,
+    handshake_limiter: Option<HandshakeLimiter>,
+    blacklist: HashSet<NodeId>,
+    /// When set, only these nodes are allowed
+    allowlist: Option<HashSet<NodeId>>,
+    resolver: Option<Arc<dyn NameResolver>>,
+    /// Resolved addresses of hostnames and when they expire
+    dns_cache: HashMap<String, (Vec<IpAddr>, u64)>,
+    /// Connect requests which wait for a hostname lookup
+    dns_waiting: HashMap<String, Vec<NodeAddr>>,
+    /// Outgoing connections to resolved addresses, until one to the node is connected or all of them fail
+    dns_pairs: HashMap<NetPair, (NodeId, String)>,
+}
+
+/// Hostname part of a NodeAddr, `ipv4` is None if both address families are accepted
+struct DnsDest {
+    host: String,
+    port: u16,
+    ipv4: Option<bool>,
 }
 
 impl NeighboursManager {
-    pub fn new(node_id: NodeId, bind_addrs: Vec<SocketAddr>, authorization: Arc<dyn Authorization>, handshake_builder: Arc<dyn HandshakeBuilder>, random: Box<dyn rand::RngCore>) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        node_id: NodeId,
+        bind_addrs: Vec<SocketAddr>,
+        authorization: Arc<dyn Authorization>,
+        handshake_builder: Arc<dyn HandshakeBuilder>,
+        identity: Option<Arc<NodeIdentity>>,
+        accept_policy: Arc<dyn AcceptPolicy>,
+        ciphers: Vec<CipherSuite>,
+        features: FeatureVersions,
+        codecs: Vec<WireCodec>,
+        random: Box<dyn rand::RngCore>,
+        cfg: NeighboursCfg,
+        resolver: Option<Arc<dyn NameResolver>>,
+    ) -> Self {
         Self {
             node_id,
             bind_addrs,
@@ -51,50 +109,303 @@ impl NeighboursManager {
             shutdown: false,
             authorization,
             handshake_builder,
+            identity,
+            accept_policy,
+            ciphers,
+            features,
+            codecs,
             random,
+            handshake_limiter: cfg.handshake_rate.map(HandshakeLimiter::new),
+            cfg,
+            blacklist: HashSet::new(),
+            allowlist: None,
+            resolver,
+            dns_cache: HashMap::new(),
+            dns_waiting: HashMap::new(),
+            dns_pairs: HashMap::new(),
+        }
+    }
+
+    fn is_allowed(&self, node: NodeId) -> bool {
+        !self.blacklist.contains(&node) && self.allowlist.as_ref().map_or(true, |list| list.contains(&node))
+    }
+
+    /// Close connections to nodes which are not allowed anymore
+    fn disconnect_not_allowed(&mut self, now_ms: u64) {
+        let blacklist = &self.blacklist;
+        let allowlist = &self.allowlist;
+        for conn in self.connections.values_mut() {
+            let node = conn.dest_node();
+            if blacklist.contains(&node) || allowlist.as_ref().is_some_and(|list| !list.contains(&node)) {
+                log::info!("[Neighbours] Disconnect from {node} because it is not allowed anymore");
+                conn.disconnect(now_ms, DisconnectReason::Rejected);
+            }
         }
     }
 
+    pub fn add_blacklist(&mut self, now_ms: u64, node: NodeId) {
+        log::info!("[Neighbours] Add {node} to blacklist");
+        self.blacklist.insert(node);
+        self.disconnect_not_allowed(now_ms);
+    }
+
+    /// First call switches to allowlist mode, so all nodes which are not added are refused
+    pub fn add_allowlist_only(&mut self, now_ms: u64, node: NodeId) {
+        log::info!("[Neighbours] Add {node} to allowlist");
+        self.allowlist.get_or_insert_with(HashSet::new).insert(node);
+        self.disconnect_not_allowed(now_ms);
+    }
+
     pub fn conn(&self, conn: ConnId) -> Option<&ConnectionCtx> {
         self.neighbours.get(&conn)
     }
 
+    /// Keepalive round trip time of an established connection
+    pub fn rtt_ms(&self, conn: ConnId) -> Option<u32> {
+        let pair = self.neighbours.get(&conn)?.pair;
+        self.connections.get(&pair)?.rtt_ms()
+    }
+
+    /// Established connections sorted by node then addr, the uptime is counted until `now_ms`
+    pub fn neighbours(&self, now_ms: u64) -> Vec<NeighbourInfo> {
+        let mut neighbours: Vec<_> = self
+            .neighbours
+            .values()
+            .filter_map(|ctx| {
+                let conn = self.connections.get(&ctx.pair)?;
+                let established_ms = conn.connected_ms()?;
+                Some(NeighbourInfo {
+                    node: ctx.node,
+                    conn: ctx.conn,
+                    remote: ctx.pair.remote,
+                    direction: ctx.conn.direction(),
+                    cipher: conn.cipher(),
+                    rtt_ms: conn.rtt_ms(),
+                    established_ms,
+                    uptime_ms: now_ms.saturating_sub(established_ms),
+                })
+            })
+            .collect();
+        neighbours.sort_by_key(|info| (info.node, info.remote));
+        neighbours
+    }
+
+    pub fn connection_counts(&self) -> ConnectionCounts {
+        ConnectionCounts {
+            total: self.connections.len(),
+            established: self.neighbours.len(),
+        }
+    }
+
+    /// Check limits for a new incoming connection, existing connections are never closed for it
+    fn check_limits(&self, pair: &NetPair) -> Result<(), NeighboursConnectError> {
+        if let Some(max) = self.cfg.max_connections {
+            if self.connections.len() >= max {
+                return Err(NeighboursConnectError::ConnectionLimit);
+            }
+        }
+        if let Some(max) = self.cfg.max_connections_per_ip {
+            let ip = pair.remote.ip();
+            if self.connections.keys().filter(|p| p.remote.ip() == ip).count() >= max {
+                return Err(NeighboursConnectError::IpConnectionLimit);
+            }
+        }
+        Ok(())
+    }
+
     pub fn on_tick(&mut self, now_ms: u64, _tick_count: u64) {
         for conn in self.connections.values_mut() {
-            conn.on_tick(now_ms);
+            conn.on_tick(now_ms, &self.cfg);
+        }
+        self.dns_cache.retain(|_, (_, expire_ms)| *expire_ms >= now_ms);
+        if let Some(limiter) = &mut self.handshake_limiter {
+            limiter.on_tick(now_ms);
+        }
+        if let Some(resolver) = self.resolver.clone() {
+            while let Some(resolved) = resolver.pop_resolved() {
+                self.on_resolved(now_ms, resolved);
+            }
+        }
+    }
+
+    fn on_resolved(&mut self, now_ms: u64, resolved: NameResolved) {
+        log::info!("[Neighbours] Resolved {} to {:?}", resolved.host, resolved.addrs);
+        let waiting = self.dns_waiting.remove(&resolved.host).unwrap_or_default();
+        self.dns_cache.insert(resolved.host, (resolved.addrs, now_ms + resolved.ttl_ms));
+        for addr in waiting {
+            if self.is_allowed(addr.node_id()) {
+                self.connect_to(now_ms, addr);
+            }
         }
     }
 
+    /// Start a lookup for the connect request, returns false if there is no resolver
+    fn resolve(&mut self, host: &str, addr: &NodeAddr) -> bool {
+        let resolver = match &self.resolver {
+            Some(resolver) => resolver,
+            None => {
+                log::warn!("[Neighbours] No resolver for hostname {host} of {addr}");
+                return false;
+            }
+        };
+        let waiting = self.dns_waiting.entry(host.to_string()).or_default();
+        if waiting.is_empty() {
+            log::info!("[Neighbours] Resolving hostname {host}");
+            resolver.resolve(host);
+        }
+        waiting.push(addr.clone());
+        true
+    }
+
+    /// Connect from all bind addrs to all addresses of the node. Ip addresses go out directly,
+    /// hostnames use cached addresses or wait for a lookup
+    /// Family which must be dialed, None for both. The preferred family is only used if it has a local bind and a dest
+    fn dial_family(&self, dests: &[(SocketAddr, Option<&str>)]) -> Option<bool> {
+        let ipv4 = match self.cfg.ip_preference {
+            IpPreference::Any => return None,
+            IpPreference::PreferIpv4 => true,
+            IpPreference::PreferIpv6 => false,
+        };
+        let reachable = self.bind_addrs.iter().any(|local| local.is_ipv4() == ipv4) && dests.iter().any(|(remote, _)| remote.is_ipv4() == ipv4);
+        reachable.then_some(ipv4)
+    }
+
+    fn connect_to(&mut self, now_ms: u64, addr: NodeAddr) {
+        let dest_node = addr.node_id();
+        let (ip_dests, dns_dests) = get_node_addr_dests(&addr);
+        let mut dests: Vec<(SocketAddr, Option<&str>)> = ip_dests.into_iter().map(|remote| (remote, None)).collect();
+        let mut waiting = false;
+        for dns in &dns_dests {
+            match self.dns_cache.get(&dns.host) {
+                Some((ips, expire_ms)) if *expire_ms >= now_ms => {
+                    let ips = ips.iter().filter(|ip| dns.ipv4.map_or(true, |ipv4| ip.is_ipv4() == ipv4));
+                    dests.extend(ips.map(|ip| (SocketAddr::new(*ip, dns.port), Some(dns.host.as_str()))));
+                }
+                _ => waiting |= self.resolve(&dns.host, &addr),
+            }
+        }
+
+        let family = self.dial_family(&dests);
+        let mut started = false;
+        let mut refused = false;
+        let mut accepted = false;
+        for local in &self.bind_addrs {
+            for (remote, host) in &dests {
+                if local.is_ipv4() != remote.is_ipv4() || family.map_or(false, |ipv4| local.is_ipv4() != ipv4) {
+                    continue;
+                }
+
+                let pair = NetPair::new(*local, *remote);
+                started = true;
+                if self.connections.contains_key(&pair) {
+                    accepted = true;
+                    continue;
+                }
+                let session_id = self.random.next_u64();
+                if !self.accept_policy.accept(dest_node, ConnId::from_out(0, session_id), *remote) {
+                    log::warn!("[Neighbours] Accept policy refused connect from {local} to {remote}, dest_node {dest_node}");
+                    refused = true;
+                    continue;
+                }
+                accepted = true;
+                log::info!("[Neighbours] Sending connect request from {local} to {remote}, dest_node {dest_node}");
+                let mut conn = NeighbourConnection::new_outgoing(
+                    self.handshake_builder.clone(),
+                    self.authorization.clone(),
+                    self.identity.clone(),
+                    self.ciphers.clone(),
+                    self.features.clone(),
+                    self.codecs.clone(),
+                    self.node_id,
+                    dest_node,
+                    session_id,
+                    pair,
+                    now_ms,
+                );
+                if self.cfg.require_node_id_proof {
+                    conn.require_node_id_proof(self.random.next_u64());
+                }
+                if self.cfg.require_identity {
+                    conn.require_identity();
+                }
+                self.connections.insert(pair, conn);
+                if let Some(host) = host {
+                    self.dns_pairs.insert(pair, (dest_node, host.to_string()));
+                }
+            }
+        }
+
+        if refused && !accepted && !waiting {
+            self.queue.push_back(Output::Event(base::ConnectionEvent::ConnectRejected(dest_node, NeighboursConnectError::Blocked)));
+        }
+        if !dns_dests.is_empty() && !waiting && !started {
+            log::warn!("[Neighbours] No reachable address for {addr}");
+            self.queue
+                .push_back(Output::Event(base::ConnectionEvent::ConnectRejected(dest_node, NeighboursConnectError::DestinationNotFound)));
+        }
+    }
+
+    /// All connections of the node to resolved addresses failed, the hostname is resolved again on the next connect
+    fn on_dns_pair_failed(&mut self, pair: NetPair) {
+        let (node, host) = return_if_none!(self.dns_pairs.remove(&pair));
+        if self.dns_pairs.values().any(|(other, _)| *other == node) {
+            return;
+        }
+        log::warn!("[Neighbours] All resolved addresses of {host} failed for node {node}");
+        self.dns_cache.remove(&host);
+        self.queue
+            .push_back(Output::Event(base::ConnectionEvent::ConnectRejected(node, NeighboursConnectError::DestinationNotFound)));
+    }
+
     pub fn on_input(&mut self, now_ms: u64, input: Input) {
         match input {
             Input::ConnectTo(addr) => {
                 let dest_node = addr.node_id();
-                let dests = get_node_addr_dests(addr);
-                for local in &self.bind_addrs {
-                    for remote in &dests {
-                        if local.is_ipv4() != remote.is_ipv4() {
-                            continue;
-                        }
-
-                        let pair = NetPair::new(*local, *remote);
-                        if self.connections.contains_key(&pair) {
-                            continue;
-                        }
-                        log::info!("[Neighbours] Sending connect request from {local} to {remote}, dest_node {dest_node}");
-                        let session_id = self.random.next_u64();
-                        let conn = NeighbourConnection::new_outgoing(self.handshake_builder.clone(), self.node_id, dest_node, session_id, pair, now_ms);
-                        self.connections.insert(pair, conn);
-                    }
+                if !self.is_allowed(dest_node) {
+                    log::warn!("[Neighbours] Refuse to connect to blocked node {dest_node}");
+                    self.queue.push_back(Output::Event(base::ConnectionEvent::ConnectRejected(dest_node, NeighboursConnectError::Blocked)));
+                    return;
                 }
+                self.connect_to(now_ms, addr);
             }
             Input::DisconnectFrom(node) => {
                 for conn in self.connections.values_mut() {
                     if conn.dest_node() == node {
-                        conn.disconnect(now_ms);
+                        conn.disconnect(now_ms, DisconnectReason::Graceful);
                     }
                 }
             }
+            Input::Rekey(conn) => {
+                let pair = return_if_none!(self.neighbours.get(&conn)).pair;
+                let conn = return_if_none!(self.connections.get_mut(&pair));
+                conn.start_rekey(now_ms);
+            }
+            Input::Migrate(conn, old_pair, new_pair) => {
+                if self.neighbours.get(&conn).map(|ctx| ctx.pair) != Some(old_pair) {
+                    log::warn!("[Neighbours] Migrate unknown conn {conn} from {old_pair} => ignore");
+                    return;
+                }
+                if self.connections.contains_key(&new_pair) {
+                    log::warn!("[Neighbours] Migrate conn {conn} to {new_pair} which is used by other connection => ignore");
+                    return;
+                }
+                let mut connection = return_if_none!(self.connections.remove(&old_pair));
+                log::info!("[Neighbours] Conn {conn} migrated from {old_pair} to {new_pair}");
+                connection.set_pair(new_pair);
+                let ctx = connection.ctx();
+                self.connections.insert(new_pair, connection);
+                self.neighbours.insert(conn, ctx.clone());
+                self.queue.push_back(Output::Event(base::ConnectionEvent::Migrated(ctx, old_pair)));
+            }
             Input::Control(addr, control) => {
+                //checked before the signature, so a flood of new connections is dropped without any crypto work
+                if !self.connections.contains_key(&addr) {
+                    if let Some(limiter) = &mut self.handshake_limiter {
+                        if !limiter.try_acquire(now_ms, addr.remote.ip()) {
+                            return;
+                        }
+                    }
+                }
                 let cmd: NeighboursControlCmds = match control.validate(now_ms, &*self.authorization) {
                     Ok(cmd) => cmd,
                     Err(_) => {
@@ -109,7 +420,44 @@ impl NeighboursManager {
                 } else {
                     match cmd {
                         NeighboursControlCmds::ConnectRequest { session, .. } => {
-                            let mut conn = NeighbourConnection::new_incoming(self.handshake_builder.clone(), self.node_id, control.from, session, addr, now_ms);
+                            //the policy goes first, so a refused peer never reaches the other rules
+                            let accepted = self.accept_policy.accept(control.from, ConnId::from_in(0, session), addr.remote);
+                            let allowed = if accepted && self.is_allowed(control.from) {
+                                Ok(())
+                            } else {
+                                Err(NeighboursConnectError::Blocked)
+                            };
+                            if let Err(err) = allowed.and_then(|_| self.check_limits(&addr)) {
+                                log::warn!("[Neighbours] Reject connect request from {} node {}: {:?}", addr, control.from, err);
+                                let cmd = NeighboursControlCmds::ConnectResponse {
+                                    session,
+                                    result: Err(err),
+                                    identity: None,
+                                    features: self.features.clone(),
+                                    codec: WireCodec::Bincode,
+                                };
+                                self.queue.push_back(Output::Control(addr, NeighboursControl::build(now_ms, self.node_id, cmd, &*self.authorization)));
+                                return;
+                            }
+                            let mut conn = NeighbourConnection::new_incoming(
+                                self.handshake_builder.clone(),
+                                self.authorization.clone(),
+                                self.identity.clone(),
+                                self.ciphers.clone(),
+                                self.features.clone(),
+                                self.codecs.clone(),
+                                self.node_id,
+                                control.from,
+                                session,
+                                addr,
+                                now_ms,
+                            );
+                            if self.cfg.require_node_id_proof {
+                                conn.require_node_id_proof(self.random.next_u64());
+                            }
+                            if self.cfg.require_identity {
+                                conn.require_identity();
+                            }
                             conn.on_input(now_ms, control.from, cmd);
                             self.connections.insert(addr, conn);
                         }
@@ -128,7 +476,17 @@ impl NeighboursManager {
         }
         self.shutdown = true;
         for conn in self.connections.values_mut() {
-            conn.disconnect(now_ms);
+            conn.disconnect(now_ms, DisconnectReason::Graceful);
+        }
+    }
+
+    /// Connections which didn't close after `on_shutdown` are dropped, established ones are reported as disconnected by timeout
+    pub fn drop_connections(&mut self) {
+        for (_, conn) in self.connections.drain() {
+            let ctx = conn.ctx();
+            if self.neighbours.remove(&ctx.conn).is_some() {
+                self.queue.push_back(Output::Event(base::ConnectionEvent::Disconnected(ctx, DisconnectReason::Timeout)));
+            }
         }
     }
 }
@@ -150,33 +508,62 @@ impl TaskSwitcherChild<Output> for NeighboursManager {
         }
 
         let mut to_remove = Vec::new();
+        let mut dns_failed = Vec::new();
         for (remote, conn) in self.connections.iter_mut() {
             while let Some(output) = conn.pop_output() {
                 match output {
                     connection::Output::Event(event) => {
                         let event = match event {
-                            ConnectionEvent::Connected(encryptor, decryptor) => {
+                            ConnectionEvent::Connected(cipher, encryptor, decryptor) => {
                                 let ctx = conn.ctx();
                                 self.neighbours.insert(ctx.conn, ctx.clone());
-                                Some(base::ConnectionEvent::Connected(ctx, SecureContext { encryptor, decryptor }))
+                                self.dns_pairs.retain(|_, (node, _)| *node != ctx.node);
+                                let secure = SecureContext {
+                                    cipher,
+                                    encryptor,
+                                    decryptor,
+                                    peer_identity: conn.peer_identity(),
+                                };
+                                Some(base::ConnectionEvent::Connected(ctx, secure))
                             }
-                            ConnectionEvent::ConnectError(_) => {
+                            ConnectionEvent::ConnectError(err) => {
                                 to_remove.push(*remote);
-                                None
+                                if err.is_rejected() {
+                                    self.dns_pairs.remove(remote);
+                                    Some(base::ConnectionEvent::ConnectRejected(conn.dest_node(), err))
+                                } else {
+                                    dns_failed.push(*remote);
+                                    None
+                                }
                             }
                             ConnectionEvent::ConnectTimeout => {
                                 to_remove.push(*remote);
+                                dns_failed.push(*remote);
                                 None
                             }
                             ConnectionEvent::Stats(stats) => {
                                 let ctx = conn.ctx();
                                 Some(base::ConnectionEvent::Stats(ctx, stats))
                             }
-                            ConnectionEvent::Disconnected => {
+                            ConnectionEvent::Rekeyed(epoch, encryptor, decryptor, activate) => {
+                                let secure = SecureContext {
+                                    cipher: conn.cipher(),
+                                    encryptor,
+                                    decryptor,
+                                    peer_identity: conn.peer_identity(),
+                                };
+                                self.queue.push_back(Output::Rekey(conn.ctx().conn, epoch, secure, activate));
+                                None
+                            }
+                            ConnectionEvent::RekeyActivated(epoch) => {
+                                self.queue.push_back(Output::RekeyActivate(conn.ctx().conn, epoch));
+                                None
+                            }
+                            ConnectionEvent::Disconnected(reason) => {
                                 let ctx = conn.ctx();
                                 self.neighbours.remove(&ctx.conn);
                                 to_remove.push(*remote);
-                                Some(base::ConnectionEvent::Disconnected(ctx))
+                                Some(base::ConnectionEvent::Disconnected(ctx, reason))
                             }
                         };
                         if let Some(event) = event {
@@ -185,7 +572,8 @@ impl TaskSwitcherChild<Output> for NeighboursManager {
                     }
                     connection::Output::Net(now_ms, remote, cmd) => {
                         log::debug!("[NeighboursManager] pop_output Net(remote: {:?}, cmd: {:?})", remote, cmd);
-                        self.queue.push_back(Output::Control(remote, NeighboursControl::build(now_ms, self.node_id, cmd, &*self.authorization)));
+                        let control = NeighboursControl::build_with_codec(now_ms, self.node_id, cmd, &*self.authorization, conn.codec());
+                        self.queue.push_back(Output::Control(remote, control));
                     }
                 }
             }
@@ -194,30 +582,62 @@ impl TaskSwitcherChild<Output> for NeighboursManager {
         for remote in to_remove {
             self.connections.remove(&remote);
         }
+        for pair in dns_failed {
+            self.on_dns_pair_failed(pair);
+        }
 
         self.queue.pop_front()
     }
 }
 
-fn get_node_addr_dests(addr: NodeAddr) -> Vec<SocketAddr> {
+/// Ip addresses and hostnames of a NodeAddr, each of them is followed by an udp port
+fn get_node_addr_dests(addr: &NodeAddr) -> (Vec<SocketAddr>, Vec<DnsDest>) {
     let mut dests = Vec::new();
+    let mut dns_dests = Vec::new();
     log::info!("Connect to: addr {}", addr);
     let mut dest_ip = None;
+    let mut dest_host = None;
     for part in addr.multiaddr().iter() {
         match part {
             Protocol::Ip4(i) => {
                 dest_ip = Some(IpAddr::V4(i));
+                dest_host = None;
             }
             Protocol::Ip6(i) => {
                 dest_ip = Some(IpAddr::V6(i));
+                dest_host = None;
+            }
+            Protocol::Dns(host) => {
+                dest_host = Some((host.to_string(), None));
+                dest_ip = None;
+            }
+            Protocol::Dns4(host) => {
+                dest_host = Some((host.to_string(), Some(true)));
+                dest_ip = None;
+            }
+            Protocol::Dns6(host) => {
+                dest_host = Some((host.to_string(), Some(false)));
+                dest_ip = None;
             }
             Protocol::Udp(port) => {
                 if let Some(ip) = dest_ip {
                     dests.push(SocketAddr::new(ip, port));
                 }
+                if let Some((host, ipv4)) = &dest_host {
+                    dns_dests.push(DnsDest {
+                        host: host.clone(),
+                        port,
+                        ipv4: *ipv4,
+                    });
+                }
+            }
+            Protocol::Memory(port) => {
+                dests.push(memory_socket_addr(port));
+                dest_ip = None;
+                dest_host = None;
             }
             _ => {}
         }
     }
-    dests
+    (dests, dns_dests)
 }
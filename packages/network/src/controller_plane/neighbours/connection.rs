@@ -3,56 +3,130 @@ use std::{collections::VecDeque, fmt::Debug, sync::Arc};
 use atm0s_sdn_identity::{ConnId, NodeId};
 
 use crate::{
-    base::{ConnectionCtx, ConnectionStats, Decryptor, Encryptor, HandshakeBuilder, HandshakeRequester, NeighboursConnectError, NeighboursControlCmds, NeighboursDisconnectReason},
+    base::{
+        Authorization, CipherSuite, ConnectionCtx, ConnectionStats, Decryptor, DisconnectReason, Encryptor, FeatureVersions, HandshakeBuilder, HandshakeRequester, IdentityProof,
+        NeighboursConnectError, NeighboursControlCmds, NeighboursDisconnectReason, WireCodec,
+    },
     data_plane::NetPair,
+    features::neighbours::NeighboursCfg,
+    secure::NodeIdentity,
 };
 
 const INIT_RTT_MS: u32 = 1000;
 const RETRY_CMD_MS: u64 = 1000;
-const CONNECT_TIMEOUT_MS: u64 = 30000; //we need connect more time
 const CONNECTION_TIMEOUT_MS: u64 = 10000;
+/// A connect request with a new session replaces a connection only after this, same as the control message timeout,
+/// so a delayed request of the handshake which created the connection is not taken as a restart of the remote
+const REPLACE_AFTER_MS: u64 = 10000;
+
+/// Node id proof, enabled by `NeighboursCfg::require_node_id_proof` on the responder.
+///
+/// 1. The responder answers a `ConnectRequest` with `ConnectChallenge` carrying a random nonce, instead of accepting it.
+/// 2. The requester signs the nonce together with both node ids and the session by its `Authorization`, and sends it
+///    back in `ConnectProof`.
+/// 3. The responder validates the proof for the requester node id and answers the kept request with `ConnectResponse`,
+///    or rejects it with `NeighboursConnectError::InvalidProof`.
+///
+/// Unlike the signature of each control, which can be replayed until the message expires, the proof is only valid for
+/// one nonce. Retransmitted requests are answered with the same challenge.
+///
+/// The proof is only as strong as the `Authorization` backend. With a shared key like `StaticKeyAuthorization`, every
+/// member of the network can sign for any node id, so the proof only shows the requester has the key. Binding the
+/// node id to a key of its own is done by the Ed25519 identity, see `NeighboursCfg::require_identity`.
+///
+/// Rotation of the session key of a connected neighbour, started when the data plane hits its rekey policy.
+///
+/// 1. The requester sends `RekeyRequest` with the next key epoch and a fresh handshake.
+/// 2. The responder installs the new key for decrypting only, keeps encrypting with the old one and
+///    answers with `RekeyResponse`.
+/// 3. The requester installs the new key for both directions and sends `RekeyConfirm`, after which
+///    the responder encrypts with the new key too. A packet received with the new key has the same effect,
+///    in case the confirm is lost.
+///
+/// Until the old key is retired, data planes decrypt packets of both keys, picked by the epoch byte.
+/// If both sides start at the same time, the request of the outgoing side wins.
+enum RekeyState {
+    Requesting {
+        epoch: u8,
+        started_ms: u64,
+        at_ms: u64,
+        requester: Box<dyn HandshakeRequester>,
+        handshake: Vec<u8>,
+    },
+    /// request and response are cached for answering a retransmitted request
+    Responded {
+        epoch: u8,
+        started_ms: u64,
+        at_ms: u64,
+        request: Vec<u8>,
+        response: Vec<u8>,
+    },
+}
 
 enum State {
     OutgoingWait {
         at_ms: u64,
         requester: Box<dyn HandshakeRequester>,
+        /// Latest sent request, the identity proof of the response is checked against it
+        handshake: Vec<u8>,
     },
     IncomingWait {
         at_ms: u64,
     },
+    /// Waiting for the node id proof of the requester, its connect request is kept until the proof is valid
+    IncomingChallenged {
+        at_ms: u64,
+        nonce: u64,
+        handshake: Vec<u8>,
+        ciphers: Vec<CipherSuite>,
+        cipher: CipherSuite,
+    },
     // TODO: Use thiserror and warn on dead_code
     #[allow(dead_code)]
     ConnectError(NeighboursConnectError),
     ConnectTimeout,
     Connected {
-        last_pong_ms: u64,
+        connected_ms: u64,
+        last_ping_ms: u64,
         ping_seq: u64,
+        /// Pings sent since the last received pong
+        missed_pongs: u32,
         stats: ConnectionStats,
-        /// handshake_req, handshake_res, remote_session
-        handshake: Option<(Vec<u8>, Vec<u8>, u64)>,
+        /// handshake_req, handshake_res, remote_session, selected cipher
+        handshake: Option<(Vec<u8>, Vec<u8>, u64, CipherSuite)>,
+        cipher: CipherSuite,
+        key_epoch: u8,
+        rekey: Option<RekeyState>,
     },
     Disconnecting {
         at_ms: u64,
+        reason: DisconnectReason,
     },
     Disconnected,
 }
 
 pub enum ConnectionEvent {
-    Connected(Box<dyn Encryptor>, Box<dyn Decryptor>),
+    Connected(CipherSuite, Box<dyn Encryptor>, Box<dyn Decryptor>),
     ConnectError(NeighboursConnectError),
     ConnectTimeout,
     Stats(ConnectionStats),
-    Disconnected,
+    /// A key exchange produced key `epoch`, the flag is set if the encryptor should switch to it now
+    Rekeyed(u8, Box<dyn Encryptor>, Box<dyn Decryptor>, bool),
+    /// The remote confirmed key `epoch`, the encryptor can switch to it
+    RekeyActivated(u8),
+    Disconnected(DisconnectReason),
 }
 
 impl Debug for ConnectionEvent {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            ConnectionEvent::Connected(_, _) => write!(f, "Connected"),
+            ConnectionEvent::Connected(cipher, _, _) => write!(f, "Connected({:?})", cipher),
             ConnectionEvent::ConnectError(err) => write!(f, "ConnectError({:?})", err),
             ConnectionEvent::ConnectTimeout => write!(f, "ConnectTimeout"),
             ConnectionEvent::Stats(_) => write!(f, "Stats"),
-            ConnectionEvent::Disconnected => write!(f, "Disconnected"),
+            ConnectionEvent::Rekeyed(epoch, _, _, activate) => write!(f, "Rekeyed({}, {})", epoch, activate),
+            ConnectionEvent::RekeyActivated(epoch) => write!(f, "RekeyActivated({})", epoch),
+            ConnectionEvent::Disconnected(reason) => write!(f, "Disconnected({:?})", reason),
         }
     }
 }
@@ -60,11 +134,13 @@ impl Debug for ConnectionEvent {
 impl PartialEq for ConnectionEvent {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
-            (ConnectionEvent::Connected(_, _), ConnectionEvent::Connected(_, _)) => true,
+            (ConnectionEvent::Connected(cipher1, _, _), ConnectionEvent::Connected(cipher2, _, _)) => cipher1 == cipher2,
             (ConnectionEvent::ConnectError(err1), ConnectionEvent::ConnectError(err2)) => err1 == err2,
             (ConnectionEvent::ConnectTimeout, ConnectionEvent::ConnectTimeout) => true,
             (ConnectionEvent::Stats(_), ConnectionEvent::Stats(_)) => true,
-            (ConnectionEvent::Disconnected, ConnectionEvent::Disconnected) => true,
+            (ConnectionEvent::Rekeyed(epoch1, _, _, activate1), ConnectionEvent::Rekeyed(epoch2, _, _, activate2)) => epoch1 == epoch2 && activate1 == activate2,
+            (ConnectionEvent::RekeyActivated(epoch1), ConnectionEvent::RekeyActivated(epoch2)) => epoch1 == epoch2,
+            (ConnectionEvent::Disconnected(reason1), ConnectionEvent::Disconnected(reason2)) => reason1 == reason2,
             _ => false,
         }
     }
@@ -84,25 +160,88 @@ pub struct NeighbourConnection {
     state: State,
     output: VecDeque<Output>,
     handshake_builder: Arc<dyn HandshakeBuilder>,
+    /// Signs node id proofs as requester and validates them as responder
+    authorization: Arc<dyn Authorization>,
+    /// Nonce for challenging the requester if a node id proof is required
+    challenge: Option<u64>,
+    /// Signs the local side of the handshake
+    identity: Option<Arc<NodeIdentity>>,
+    /// Peers without an identity proof are rejected
+    identity_required: bool,
+    /// Public key which the peer proved
+    peer_identity: Option<[u8; 32]>,
+    /// Local cipher preference, offered in outgoing requests and used for selecting in incoming requests
+    ciphers: Vec<CipherSuite>,
+    /// Local feature versions, sent in the request or the response
+    features: FeatureVersions,
+    /// Feature versions which both sides speak, version 1 for all until the handshake is done
+    shared_features: FeatureVersions,
+    /// Local codec preference, same as the ciphers
+    codecs: Vec<WireCodec>,
+    /// Codec of the controls after the handshake, bincode until then
+    codec: WireCodec,
 }
 
 impl NeighbourConnection {
-    pub fn new_outgoing(handshake_builder: Arc<dyn HandshakeBuilder>, local: NodeId, node: NodeId, session: u64, pair: NetPair, now_ms: u64) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_outgoing(
+        handshake_builder: Arc<dyn HandshakeBuilder>,
+        authorization: Arc<dyn Authorization>,
+        identity: Option<Arc<NodeIdentity>>,
+        ciphers: Vec<CipherSuite>,
+        features: FeatureVersions,
+        codecs: Vec<WireCodec>,
+        local: NodeId,
+        node: NodeId,
+        session: u64,
+        pair: NetPair,
+        now_ms: u64,
+    ) -> Self {
         let requester = handshake_builder.requester();
         let handshake = requester.create_public_request().expect("Should have handshake");
-        let state = State::OutgoingWait { at_ms: now_ms, requester };
-        Self {
+        let state = State::OutgoingWait {
+            at_ms: now_ms,
+            requester,
+            handshake: handshake.clone(),
+        };
+        let mut conn = Self {
             conn: ConnId::from_out(0, session),
             local,
             node,
             pair,
             state,
-            output: VecDeque::from([Output::Net(now_ms, pair, NeighboursControlCmds::ConnectRequest { to: node, session, handshake })]),
+            output: VecDeque::new(),
             handshake_builder,
-        }
+            authorization,
+            challenge: None,
+            identity,
+            identity_required: false,
+            peer_identity: None,
+            ciphers,
+            features,
+            shared_features: FeatureVersions::default(),
+            codecs,
+            codec: WireCodec::Bincode,
+        };
+        let request = conn.connect_request(handshake);
+        conn.output.push_back(conn.generate_control(now_ms, request));
+        conn
     }
 
-    pub fn new_incoming(handshake_builder: Arc<dyn HandshakeBuilder>, local: NodeId, node: NodeId, session: u64, pair: NetPair, now_ms: u64) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_incoming(
+        handshake_builder: Arc<dyn HandshakeBuilder>,
+        authorization: Arc<dyn Authorization>,
+        identity: Option<Arc<NodeIdentity>>,
+        ciphers: Vec<CipherSuite>,
+        features: FeatureVersions,
+        codecs: Vec<WireCodec>,
+        local: NodeId,
+        node: NodeId,
+        session: u64,
+        pair: NetPair,
+        now_ms: u64,
+    ) -> Self {
         let state: State = State::IncomingWait { at_ms: now_ms };
         Self {
             conn: ConnId::from_in(0, session),
@@ -112,26 +251,90 @@ impl NeighbourConnection {
             state,
             output: VecDeque::new(),
             handshake_builder,
+            authorization,
+            challenge: None,
+            identity,
+            identity_required: false,
+            peer_identity: None,
+            ciphers,
+            features,
+            shared_features: FeatureVersions::default(),
+            codecs,
+            codec: WireCodec::Bincode,
         }
     }
 
+    /// Connect requests which are accepted by this connection must be proven with the nonce, see the node id proof above.
+    /// This also applies to an outgoing connection which switches to incoming
+    pub fn require_node_id_proof(&mut self, nonce: u64) {
+        self.challenge = Some(nonce);
+    }
+
+    /// Reject the peer if it doesn't prove an identity, a proof which is sent is always verified
+    pub fn require_identity(&mut self) {
+        self.identity_required = true;
+    }
+
+    /// Ed25519 public key which the peer proved in the handshake
+    pub fn peer_identity(&self) -> Option<[u8; 32]> {
+        self.peer_identity
+    }
+
+    /// Controls are sent to the new remote addr after the connection migrated
+    pub fn set_pair(&mut self, pair: NetPair) {
+        self.pair = pair;
+    }
+
     pub fn dest_node(&self) -> NodeId {
         self.node
     }
 
+    /// Cipher of the established connection, the default suite if not connected yet
+    pub fn cipher(&self) -> CipherSuite {
+        match &self.state {
+            State::Connected { cipher, .. } => *cipher,
+            _ => CipherSuite::ChaCha20Poly1305,
+        }
+    }
+
+    /// Codec which is selected in the handshake, bincode if not connected yet
+    pub fn codec(&self) -> WireCodec {
+        self.codec
+    }
+
     pub fn ctx(&self) -> ConnectionCtx {
         ConnectionCtx {
             conn: self.conn,
             node: self.node,
             pair: self.pair,
+            features: self.shared_features.clone(),
+            codec: self.codec,
+            peer_identity: self.peer_identity.map(Arc::new),
+        }
+    }
+
+    /// Time when the handshake completed, None if not connected
+    pub fn connected_ms(&self) -> Option<u64> {
+        match &self.state {
+            State::Connected { connected_ms, .. } => Some(*connected_ms),
+            _ => None,
+        }
+    }
+
+    /// Round trip time measured by the latest keepalive pong, None if not connected
+    pub fn rtt_ms(&self) -> Option<u32> {
+        match &self.state {
+            State::Connected { stats, .. } => Some(stats.rtt_ms),
+            _ => None,
         }
     }
 
-    pub fn disconnect(&mut self, now_ms: u64) {
+    /// Close with a disconnect request, `reason` is reported locally when the connection is closed
+    pub fn disconnect(&mut self, now_ms: u64, reason: DisconnectReason) {
         match &mut self.state {
             State::OutgoingWait { .. } | State::Connected { .. } => {
-                log::info!("[NeighbourConnection] Sending disconnect request with remote {}", self.pair);
-                self.state = State::Disconnecting { at_ms: now_ms };
+                log::info!("[NeighbourConnection] Sending disconnect request with remote {} because {:?}", self.pair, reason);
+                self.state = State::Disconnecting { at_ms: now_ms, reason };
                 self.output.push_back(self.generate_control(
                     now_ms,
                     NeighboursControlCmds::DisconnectRequest {
@@ -146,43 +349,76 @@ impl NeighbourConnection {
         }
     }
 
-    pub fn on_tick(&mut self, now_ms: u64) {
+    /// Start a key exchange, this is ignored if the connection is not established or already rekeying
+    pub fn start_rekey(&mut self, now_ms: u64) {
+        let session = self.conn.session();
+        let (key_epoch, rekey) = match &mut self.state {
+            State::Connected { key_epoch, rekey: rekey @ None, .. } => (*key_epoch, rekey),
+            _ => return,
+        };
+        let requester = self.handshake_builder.requester();
+        let handshake = match requester.create_public_request() {
+            Ok(handshake) => handshake,
+            Err(e) => {
+                log::warn!("[NeighbourConnection] Cannot create handshake for rekey with {}: {:?}", self.pair, e);
+                return;
+            }
+        };
+        let epoch = key_epoch.wrapping_add(1);
+        log::info!("[NeighbourConnection] Start rekey with {} for key epoch {epoch}", self.pair);
+        *rekey = Some(RekeyState::Requesting {
+            epoch,
+            started_ms: now_ms,
+            at_ms: now_ms,
+            requester,
+            handshake: handshake.clone(),
+        });
+        self.output.push_back(self.generate_control(now_ms, NeighboursControlCmds::RekeyRequest { session, epoch, handshake }));
+    }
+
+    /// Keepalive pings are sent every `keepalive_interval_ms`, the connection is dead when
+    /// `keepalive_miss_limit` pings in a row are not answered within an interval
+    pub fn on_tick(&mut self, now_ms: u64, cfg: &NeighboursCfg) {
+        self.tick_rekey(now_ms);
         match &mut self.state {
-            State::OutgoingWait { at_ms, requester } => {
-                if now_ms - *at_ms >= CONNECT_TIMEOUT_MS {
+            State::OutgoingWait { at_ms, requester, handshake } => {
+                if now_ms - *at_ms >= cfg.handshake_timeout_ms {
                     self.state = State::ConnectTimeout;
                     self.output.push_back(Output::Event(ConnectionEvent::ConnectTimeout));
-                    log::warn!("[NeighbourConnection] Connection timeout to {} after {} ms", self.pair, CONNECT_TIMEOUT_MS);
+                    log::warn!("[NeighbourConnection] Connection timeout to {} after {} ms", self.pair, cfg.handshake_timeout_ms);
                 } else if now_ms - *at_ms >= RETRY_CMD_MS {
                     if let Ok(request_buf) = requester.create_public_request() {
-                        self.output.push_back(self.generate_control(
-                            now_ms,
-                            NeighboursControlCmds::ConnectRequest {
-                                to: self.node,
-                                session: self.conn.session(),
-                                handshake: request_buf,
-                            },
-                        ));
+                        handshake.clone_from(&request_buf);
+                        let request = self.connect_request(request_buf);
+                        self.output.push_back(self.generate_control(now_ms, request));
                         log::debug!("[NeighbourConnection] Resend connect request to {}, dest_node {}", self.pair, self.node);
                     } else {
                         log::warn!("[NeighbourConnection] Cannot create handshake for resending connect request to {}, dest_node {}", self.pair, self.node);
                     }
                 }
             }
-            State::IncomingWait { at_ms } => {
-                if now_ms - *at_ms >= CONNECT_TIMEOUT_MS {
+            State::IncomingWait { at_ms } | State::IncomingChallenged { at_ms, .. } => {
+                if now_ms - *at_ms >= cfg.handshake_timeout_ms {
                     self.state = State::ConnectTimeout;
                     self.output.push_back(Output::Event(ConnectionEvent::ConnectTimeout));
-                    log::warn!("[NeighbourConnection] Connection timeout from {} after {} ms", self.pair, CONNECT_TIMEOUT_MS);
+                    log::warn!("[NeighbourConnection] Connection timeout from {} after {} ms", self.pair, cfg.handshake_timeout_ms);
                 }
             }
-            State::Connected { ping_seq, last_pong_ms, .. } => {
-                if now_ms - *last_pong_ms >= CONNECTION_TIMEOUT_MS {
-                    log::warn!("[NeighbourConnection] Connection timeout {} after a while not received pong, last {last_pong_ms}", self.pair);
-                    self.output.push_back(Output::Event(ConnectionEvent::Disconnected));
+            State::Connected {
+                ping_seq, last_ping_ms, missed_pongs, ..
+            } => {
+                if now_ms - *last_ping_ms < cfg.keepalive_interval_ms {
+                    return;
+                }
+                if *missed_pongs >= cfg.keepalive_miss_limit {
+                    log::warn!("[NeighbourConnection] Connection timeout {} after {missed_pongs} missed pongs", self.pair);
+                    self.state = State::Disconnected;
+                    self.output.push_back(Output::Event(ConnectionEvent::Disconnected(DisconnectReason::Timeout)));
                 } else {
                     log::debug!("[NeighbourConnection] Send ping {}", self.pair);
                     *ping_seq += 1;
+                    *missed_pongs += 1;
+                    *last_ping_ms = now_ms;
                     let cmd = NeighboursControlCmds::Ping {
                         session: self.conn.session(),
                         seq: *ping_seq,
@@ -191,10 +427,10 @@ impl NeighbourConnection {
                     self.output.push_back(self.generate_control(now_ms, cmd));
                 }
             }
-            State::Disconnecting { at_ms } => {
+            State::Disconnecting { at_ms, .. } => {
                 if now_ms - *at_ms >= CONNECTION_TIMEOUT_MS {
                     self.state = State::Disconnected;
-                    self.output.push_back(Output::Event(ConnectionEvent::Disconnected));
+                    self.output.push_back(Output::Event(ConnectionEvent::Disconnected(DisconnectReason::Timeout)));
                     log::warn!("[NeighbourConnection] Disconnect request timeout {} after {} ms", self.pair, CONNECTION_TIMEOUT_MS);
                 } else {
                     *at_ms = now_ms;
@@ -214,92 +450,111 @@ impl NeighbourConnection {
 
     pub fn on_input(&mut self, now_ms: u64, from: NodeId, cmd: NeighboursControlCmds) {
         match cmd {
-            NeighboursControlCmds::ConnectRequest { to, session, handshake } => {
+            NeighboursControlCmds::ConnectRequest {
+                to,
+                session,
+                handshake,
+                ciphers,
+                identity,
+                features,
+                codecs,
+            } => {
+                let cipher = CipherSuite::negotiate(&self.ciphers, &ciphers);
+                let request = handshake.clone();
+                let signed = identity_msg(b"request", session, from, to, &[&handshake]);
                 let result = if self.local == to && self.node == from {
-                    match &mut self.state {
-                        State::IncomingWait { .. } => {
-                            let mut responder = self.handshake_builder.responder();
-                            match responder.process_public_request(&handshake) {
-                                Ok((encryptor, decryptor, response)) => {
-                                    self.output.push_back(Output::Event(ConnectionEvent::Connected(encryptor, decryptor)));
-                                    self.state = State::Connected {
-                                        last_pong_ms: now_ms,
-                                        ping_seq: 0,
-                                        stats: ConnectionStats { rtt_ms: INIT_RTT_MS },
-                                        handshake: Some((handshake, response.clone(), session)),
-                                    };
-                                    log::info!("[NeighbourConnection] Connected {} as incoming conn", self.pair);
-                                    Ok(response)
-                                }
-                                Err(_) => {
-                                    log::error!("[NeighbourConnection] Invalid connect request from {}", self.pair);
-                                    Err(NeighboursConnectError::InvalidData)
-                                }
+                    self.shared_features = FeatureVersions::negotiate(&self.features, &features);
+                    self.codec = WireCodec::negotiate(&self.codecs, &codecs);
+                    match verify_identity(from, self.identity_required, &signed, identity.as_ref()) {
+                        Err(err) => {
+                            log::warn!("[NeighbourConnection] Invalid identity in connect request from {}: {:?}", self.pair, err);
+                            if matches!(self.state, State::IncomingWait { .. }) {
+                                self.state = State::ConnectError(err);
+                                self.output.push_back(Output::Event(ConnectionEvent::ConnectError(err)));
                             }
+                            Err(err)
                         }
-                        State::OutgoingWait { .. } => {
-                            if self.conn.session() >= session {
-                                //check if we can replace the existing connection to accept the new one
-                                log::warn!(
-                                    "[NeighbourConnection] Conflic state from {}, local session {}, remote session {} => switch to incoming",
-                                    self.pair,
-                                    self.conn.session(),
-                                    session
-                                );
-                                self.switch_to_incoming(session);
-
-                                let mut responder = self.handshake_builder.responder();
-                                match responder.process_public_request(&handshake) {
-                                    Ok((encryptor, decryptor, response)) => {
-                                        self.output.push_back(Output::Event(ConnectionEvent::Connected(encryptor, decryptor)));
-                                        self.state = State::Connected {
-                                            last_pong_ms: now_ms,
-                                            ping_seq: 0,
-                                            stats: ConnectionStats { rtt_ms: INIT_RTT_MS },
-                                            handshake: Some((handshake, response.clone(), session)),
-                                        };
-                                        log::info!("[NeighbourConnection] Connected {} as incoming conn", self.pair);
-                                        Ok(response)
-                                    }
-                                    Err(_) => {
-                                        log::error!("[NeighbourConnection] Invalid connect request from {}", self.pair);
-                                        Err(NeighboursConnectError::InvalidData)
+                        Ok(peer_identity) => match &mut self.state {
+                            State::IncomingWait { at_ms } if self.challenge.is_some() => {
+                                let at_ms = *at_ms;
+                                self.peer_identity = peer_identity;
+                                self.challenge_request(now_ms, at_ms, handshake, ciphers, cipher);
+                                return;
+                            }
+                            State::IncomingWait { .. } => {
+                                self.peer_identity = peer_identity;
+                                self.accept_request(now_ms, session, handshake, &ciphers, cipher)
+                            }
+                            State::IncomingChallenged { at_ms, .. } if session == self.conn.session() => {
+                                //retransmitted request, the requester may not got the challenge
+                                let at_ms = *at_ms;
+                                self.peer_identity = peer_identity;
+                                self.challenge_request(now_ms, at_ms, handshake, ciphers, cipher);
+                                return;
+                            }
+                            State::OutgoingWait { .. } => {
+                                if self.conn.session() >= session {
+                                    //check if we can replace the existing connection to accept the new one
+                                    log::warn!(
+                                        "[NeighbourConnection] Conflic state from {}, local session {}, remote session {} => switch to incoming",
+                                        self.pair,
+                                        self.conn.session(),
+                                        session
+                                    );
+                                    self.switch_to_incoming(session);
+                                    self.peer_identity = peer_identity;
+                                    if self.challenge.is_some() {
+                                        self.challenge_request(now_ms, now_ms, handshake, ciphers, cipher);
+                                        return;
                                     }
+                                    self.accept_request(now_ms, session, handshake, &ciphers, cipher)
+                                } else {
+                                    log::warn!(
+                                        "[NeighbourConnection] Conflic state from {}, local session {}, remote session {} => don't switch to incoming",
+                                        self.pair,
+                                        self.conn.session(),
+                                        session
+                                    );
+                                    return;
                                 }
-                            } else {
+                            }
+                            State::Connected { connected_ms, .. } if session != self.conn.session() && now_ms >= *connected_ms + REPLACE_AFTER_MS => {
+                                //the remote restarted with a new session, it retries the request and gets a new connection
                                 log::warn!(
-                                    "[NeighbourConnection] Conflic state from {}, local session {}, remote session {} => don't switch to incoming",
+                                    "[NeighbourConnection] Connect request from {} with new session {} => close old session {}",
                                     self.pair,
-                                    self.conn.session(),
-                                    session
+                                    session,
+                                    self.conn.session()
                                 );
+                                self.state = State::Disconnected;
+                                self.output.push_back(Output::Event(ConnectionEvent::Disconnected(DisconnectReason::Replaced)));
                                 return;
                             }
-                        }
-                        State::Connected { handshake: pre_hand, .. } => {
-                            if let Some(pre_hand) = pre_hand {
-                                if handshake.eq(&pre_hand.0) && pre_hand.2 == session {
-                                    Ok(pre_hand.1.clone())
+                            State::Connected { handshake: pre_hand, .. } => {
+                                if let Some(pre_hand) = pre_hand {
+                                    if handshake.eq(&pre_hand.0) && pre_hand.2 == session {
+                                        Ok((pre_hand.3, pre_hand.1.clone()))
+                                    } else {
+                                        log::warn!(
+                                            "[NeighbourConnection] Invalid handshake from {}, expected {} {:?}, got {} {:?}",
+                                            self.pair,
+                                            session,
+                                            handshake,
+                                            pre_hand.2,
+                                            pre_hand.0,
+                                        );
+                                        Err(NeighboursConnectError::InvalidData)
+                                    }
                                 } else {
-                                    log::warn!(
-                                        "[NeighbourConnection] Invalid handshake from {}, expected {} {:?}, got {} {:?}",
-                                        self.pair,
-                                        session,
-                                        handshake,
-                                        pre_hand.2,
-                                        pre_hand.0,
-                                    );
+                                    log::warn!("[NeighbourConnection] Invalid handshake from {}, expected {:?}, got None", self.pair, handshake);
                                     Err(NeighboursConnectError::InvalidData)
                                 }
-                            } else {
-                                log::warn!("[NeighbourConnection] Invalid handshake from {}, expected {:?}, got None", self.pair, handshake);
-                                Err(NeighboursConnectError::InvalidData)
                             }
-                        }
-                        _ => {
-                            log::warn!("[NeighbourConnection] Invalid state, should be Connecting for connect request from {}", self.pair);
-                            Err(NeighboursConnectError::InvalidState)
-                        }
+                            _ => {
+                                log::warn!("[NeighbourConnection] Invalid state, should be Connecting for connect request from {}", self.pair);
+                                Err(NeighboursConnectError::InvalidState)
+                            }
+                        },
                     }
                 } else {
                     log::warn!(
@@ -312,29 +567,140 @@ impl NeighbourConnection {
                     );
                     Err(NeighboursConnectError::InvalidData)
                 };
-                self.output.push_back(self.generate_control(now_ms, NeighboursControlCmds::ConnectResponse { session, result }));
+                let identity = match &result {
+                    Ok((_, response)) => self.response_identity(session, &request, response),
+                    Err(_) => None,
+                };
+                self.output.push_back(self.generate_control(
+                    now_ms,
+                    NeighboursControlCmds::ConnectResponse {
+                        session,
+                        result,
+                        identity,
+                        features: self.features.clone(),
+                        codec: self.codec,
+                    },
+                ));
             }
-            NeighboursControlCmds::ConnectResponse { session, result } => {
+            NeighboursControlCmds::ConnectChallenge { session, nonce } => {
+                if session == self.conn.session() && self.node == from && matches!(self.state, State::OutgoingWait { .. }) {
+                    log::info!("[NeighbourConnection] Connect challenge from {} => send node id proof", self.pair);
+                    let proof = self.authorization.sign(&node_id_proof_msg(nonce, self.local, self.node, session));
+                    self.output.push_back(self.generate_control(now_ms, NeighboursControlCmds::ConnectProof { session, proof }));
+                } else {
+                    log::warn!("[NeighbourConnection] Invalid state or session for connect challenge from {}", self.pair);
+                }
+            }
+            NeighboursControlCmds::ConnectProof { session, proof } => {
+                let (nonce, handshake, ciphers, cipher) = match &mut self.state {
+                    State::IncomingChallenged {
+                        nonce, handshake, ciphers, cipher, ..
+                    } if session == self.conn.session() => (*nonce, std::mem::take(handshake), std::mem::take(ciphers), *cipher),
+                    State::Connected { .. } => {
+                        log::debug!("[NeighbourConnection] Ignore connect proof from {} after connected", self.pair);
+                        return;
+                    }
+                    _ => {
+                        log::warn!("[NeighbourConnection] Invalid state, should be Challenged for connect proof from {}", self.pair);
+                        let result = Err(NeighboursConnectError::InvalidState);
+                        self.output.push_back(self.generate_control(
+                            now_ms,
+                            NeighboursControlCmds::ConnectResponse {
+                                session,
+                                result,
+                                identity: None,
+                                features: self.features.clone(),
+                                codec: self.codec,
+                            },
+                        ));
+                        return;
+                    }
+                };
+                let msg = node_id_proof_msg(nonce, self.node, self.local, session);
+                let request = handshake.clone();
+                let result = if self.node == from && self.authorization.validate(from, &msg, &proof).is_some() {
+                    self.accept_request(now_ms, session, handshake, &ciphers, cipher)
+                } else {
+                    log::warn!("[NeighbourConnection] Invalid node id proof from {} node {}", self.pair, from);
+                    self.state = State::ConnectError(NeighboursConnectError::InvalidProof);
+                    self.output.push_back(Output::Event(ConnectionEvent::ConnectError(NeighboursConnectError::InvalidProof)));
+                    Err(NeighboursConnectError::InvalidProof)
+                };
+                let identity = match &result {
+                    Ok((_, response)) => self.response_identity(session, &request, response),
+                    Err(_) => None,
+                };
+                self.output.push_back(self.generate_control(
+                    now_ms,
+                    NeighboursControlCmds::ConnectResponse {
+                        session,
+                        result,
+                        identity,
+                        features: self.features.clone(),
+                        codec: self.codec,
+                    },
+                ));
+            }
+            NeighboursControlCmds::ConnectResponse {
+                session,
+                result,
+                identity,
+                features,
+                codec,
+            } => {
                 if session == self.conn.session() {
-                    if let State::OutgoingWait { requester, .. } = &mut self.state {
+                    if let State::OutgoingWait { requester, handshake: request, .. } = &mut self.state {
                         match (requester, result) {
-                            (requester, Ok(handshake_res)) => match requester.process_public_response(&handshake_res) {
-                                Ok((encryptor, decryptor)) => {
-                                    self.output.push_back(Output::Event(ConnectionEvent::Connected(encryptor, decryptor)));
-                                    self.state = State::Connected {
-                                        last_pong_ms: now_ms,
-                                        ping_seq: 0,
-                                        stats: ConnectionStats { rtt_ms: INIT_RTT_MS },
-                                        handshake: None,
-                                    };
-                                    log::info!("Connected to {} as outgoing conn", self.pair);
-                                }
-                                Err(e) => {
-                                    log::warn!("Connect response from  {} but handshake error {:?}", self.pair, e);
-                                    self.state = State::ConnectError(NeighboursConnectError::InvalidData);
-                                    self.output.push_back(Output::Event(ConnectionEvent::ConnectError(NeighboursConnectError::InvalidData)));
+                            (_, Ok((cipher, _))) if !CipherSuite::is_acceptable(&self.ciphers, cipher) => {
+                                log::warn!("Connect response from {} with not offered cipher {:?}", self.pair, cipher);
+                                self.state = State::ConnectError(NeighboursConnectError::InvalidData);
+                                self.output.push_back(Output::Event(ConnectionEvent::ConnectError(NeighboursConnectError::InvalidData)));
+                            }
+                            (_, Ok(_)) if !WireCodec::is_acceptable(&self.codecs, codec) => {
+                                log::warn!("Connect response from {} with not offered codec {:?}", self.pair, codec);
+                                self.state = State::ConnectError(NeighboursConnectError::InvalidData);
+                                self.output.push_back(Output::Event(ConnectionEvent::ConnectError(NeighboursConnectError::InvalidData)));
+                            }
+                            (requester, Ok((cipher, handshake_res))) => {
+                                let signed = identity_msg(b"response", session, self.node, self.local, &[request.as_slice(), handshake_res.as_slice()]);
+                                match verify_identity(self.node, self.identity_required, &signed, identity.as_ref()) {
+                                    Err(err) => {
+                                        log::warn!("[NeighbourConnection] Invalid identity in connect response from {}: {:?}", self.pair, err);
+                                        self.state = State::ConnectError(err);
+                                        self.output.push_back(Output::Event(ConnectionEvent::ConnectError(err)));
+                                    }
+                                    Ok(peer_identity) => match requester.process_public_response(&handshake_res, &self.ciphers, cipher) {
+                                        Ok((encryptor, decryptor)) => {
+                                            self.peer_identity = peer_identity;
+                                            self.shared_features = FeatureVersions::negotiate(&self.features, &features);
+                                            self.codec = codec;
+                                            self.output.push_back(Output::Event(ConnectionEvent::Connected(cipher, encryptor, decryptor)));
+                                            self.state = State::Connected {
+                                                connected_ms: now_ms,
+                                                last_ping_ms: now_ms,
+                                                ping_seq: 0,
+                                                missed_pongs: 0,
+                                                stats: ConnectionStats { rtt_ms: INIT_RTT_MS },
+                                                handshake: None,
+                                                cipher,
+                                                key_epoch: 0,
+                                                rekey: None,
+                                            };
+                                            log::info!("Connected to {} as outgoing conn with {:?}", self.pair, cipher);
+                                        }
+                                        Err(e) => {
+                                            log::warn!("Connect response from  {} but handshake error {:?}", self.pair, e);
+                                            self.state = State::ConnectError(NeighboursConnectError::InvalidData);
+                                            self.output.push_back(Output::Event(ConnectionEvent::ConnectError(NeighboursConnectError::InvalidData)));
+                                        }
+                                    },
                                 }
-                            },
+                            }
+                            (_, Err(err)) if err.is_rejected() => {
+                                log::warn!("Connect to {} rejected: {:?}", self.pair, err);
+                                self.state = State::ConnectError(err);
+                                self.output.push_back(Output::Event(ConnectionEvent::ConnectError(err)));
+                            }
                             (_, Err(err)) => {
                                 // We don't need to fire error here, we will reconnect utils timeout
                                 log::warn!("Connect response error from {}: {:?}", self.pair, err);
@@ -360,8 +726,8 @@ impl NeighbourConnection {
             }
             NeighboursControlCmds::Pong { session, sent_ms, .. } => {
                 if session == self.conn.session() {
-                    if let State::Connected { last_pong_ms, stats, .. } = &mut self.state {
-                        *last_pong_ms = now_ms;
+                    if let State::Connected { missed_pongs, stats, .. } = &mut self.state {
+                        *missed_pongs = 0;
                         if sent_ms <= now_ms {
                             stats.rtt_ms = (now_ms - sent_ms) as u32;
                             self.output.push_back(Output::Event(ConnectionEvent::Stats(stats.clone())));
@@ -380,17 +746,38 @@ impl NeighbourConnection {
                 if session == self.conn.session() {
                     self.state = State::Disconnected;
                     self.output.push_back(self.generate_control(now_ms, NeighboursControlCmds::DisconnectResponse { session }));
-                    self.output.push_back(Output::Event(ConnectionEvent::Disconnected));
+                    self.output.push_back(Output::Event(ConnectionEvent::Disconnected(DisconnectReason::Graceful)));
                     log::info!("[NeighbourConnection] Disconnect request from {}", self.pair);
                 } else {
                     log::warn!("[NeighbourConnection] Invalid session in disconnect request from {}", self.pair);
                 }
             }
+            NeighboursControlCmds::RekeyRequest { session, epoch, handshake } => {
+                if session == self.conn.session() {
+                    self.on_rekey_request(now_ms, epoch, handshake);
+                } else {
+                    log::warn!("[NeighbourConnection] Invalid session in rekey request from {}", self.pair);
+                }
+            }
+            NeighboursControlCmds::RekeyResponse { session, epoch, handshake } => {
+                if session == self.conn.session() {
+                    self.on_rekey_response(now_ms, epoch, handshake);
+                } else {
+                    log::warn!("[NeighbourConnection] Invalid session in rekey response from {}", self.pair);
+                }
+            }
+            NeighboursControlCmds::RekeyConfirm { session, epoch } => {
+                if session == self.conn.session() {
+                    self.on_rekey_confirm(epoch);
+                } else {
+                    log::warn!("[NeighbourConnection] Invalid session in rekey confirm from {}", self.pair);
+                }
+            }
             NeighboursControlCmds::DisconnectResponse { session } => {
                 if session == self.conn.session() {
-                    if let State::Disconnecting { .. } = self.state {
+                    if let State::Disconnecting { reason, .. } = self.state {
                         self.state = State::Disconnected;
-                        self.output.push_back(Output::Event(ConnectionEvent::Disconnected));
+                        self.output.push_back(Output::Event(ConnectionEvent::Disconnected(reason)));
                         log::info!("[NeighbourConnection] Disconnected response from {}", self.pair);
                     } else {
                         log::warn!("[NeighbourConnection] Invalid state, should be Disconnecting for disconnect response from {}", self.pair);
@@ -406,6 +793,204 @@ impl NeighbourConnection {
         self.output.pop_front()
     }
 
+    /// Resend the pending rekey message, or give up after the connection timeout
+    fn tick_rekey(&mut self, now_ms: u64) {
+        let session = self.conn.session();
+        let rekey = match &mut self.state {
+            State::Connected { rekey, .. } => rekey,
+            _ => return,
+        };
+        let cmd = match rekey {
+            Some(RekeyState::Requesting { started_ms, .. }) | Some(RekeyState::Responded { started_ms, .. }) if now_ms - *started_ms >= CONNECTION_TIMEOUT_MS => {
+                log::warn!("[NeighbourConnection] Rekey with {} timeout after {} ms", self.pair, CONNECTION_TIMEOUT_MS);
+                *rekey = None;
+                return;
+            }
+            Some(RekeyState::Requesting { epoch, at_ms, handshake, .. }) if now_ms - *at_ms >= RETRY_CMD_MS => {
+                *at_ms = now_ms;
+                NeighboursControlCmds::RekeyRequest {
+                    session,
+                    epoch: *epoch,
+                    handshake: handshake.clone(),
+                }
+            }
+            Some(RekeyState::Responded { epoch, at_ms, response, .. }) if now_ms - *at_ms >= RETRY_CMD_MS => {
+                *at_ms = now_ms;
+                NeighboursControlCmds::RekeyResponse {
+                    session,
+                    epoch: *epoch,
+                    handshake: response.clone(),
+                }
+            }
+            _ => return,
+        };
+        log::debug!("[NeighbourConnection] Resend rekey cmd to {}", self.pair);
+        self.output.push_back(self.generate_control(now_ms, cmd));
+    }
+
+    fn on_rekey_request(&mut self, now_ms: u64, epoch: u8, request: Vec<u8>) {
+        let session = self.conn.session();
+        let is_outgoing = self.conn.is_outgoing();
+        let (cipher, key_epoch, rekey) = match &mut self.state {
+            State::Connected { cipher, key_epoch, rekey, .. } => (*cipher, *key_epoch, rekey),
+            _ => {
+                log::warn!("[NeighbourConnection] Invalid state, should be Connected for rekey request from {}", self.pair);
+                return;
+            }
+        };
+        match rekey {
+            Some(RekeyState::Responded {
+                epoch: cached,
+                request: cached_req,
+                response,
+                ..
+            }) if *cached == epoch && *cached_req == request => {
+                let handshake = response.clone();
+                self.output.push_back(self.generate_control(now_ms, NeighboursControlCmds::RekeyResponse { session, epoch, handshake }));
+                return;
+            }
+            Some(RekeyState::Requesting { .. }) if is_outgoing => {
+                log::info!("[NeighbourConnection] Concurrent rekey with {} => keep local request", self.pair);
+                return;
+            }
+            _ => {}
+        }
+        if epoch == key_epoch {
+            log::debug!("[NeighbourConnection] Ignore rekey request from {} for current key epoch {epoch}", self.pair);
+            return;
+        }
+        let mut responder = self.handshake_builder.responder();
+        match responder.process_public_request(&request, &[cipher], cipher) {
+            Ok((encryptor, decryptor, response)) => {
+                log::info!("[NeighbourConnection] Rekey request from {} for key epoch {epoch} => install", self.pair);
+                *rekey = Some(RekeyState::Responded {
+                    epoch,
+                    started_ms: now_ms,
+                    at_ms: now_ms,
+                    request,
+                    response: response.clone(),
+                });
+                self.output.push_back(Output::Event(ConnectionEvent::Rekeyed(epoch, encryptor, decryptor, false)));
+                self.output
+                    .push_back(self.generate_control(now_ms, NeighboursControlCmds::RekeyResponse { session, epoch, handshake: response }));
+            }
+            Err(e) => {
+                log::warn!("[NeighbourConnection] Invalid rekey request from {}: {:?}", self.pair, e);
+            }
+        }
+    }
+
+    fn on_rekey_response(&mut self, now_ms: u64, epoch: u8, response: Vec<u8>) {
+        let session = self.conn.session();
+        let (cipher, key_epoch, rekey) = match &mut self.state {
+            State::Connected { cipher, key_epoch, rekey, .. } => (*cipher, key_epoch, rekey),
+            _ => {
+                log::warn!("[NeighbourConnection] Invalid state, should be Connected for rekey response from {}", self.pair);
+                return;
+            }
+        };
+        match rekey {
+            Some(RekeyState::Requesting { epoch: pending, requester, .. }) if *pending == epoch => match requester.process_public_response(&response, &[cipher], cipher) {
+                Ok((encryptor, decryptor)) => {
+                    log::info!("[NeighbourConnection] Rekey response from {} => switch to key epoch {epoch}", self.pair);
+                    *key_epoch = epoch;
+                    *rekey = None;
+                    self.output.push_back(Output::Event(ConnectionEvent::Rekeyed(epoch, encryptor, decryptor, true)));
+                    self.output.push_back(self.generate_control(now_ms, NeighboursControlCmds::RekeyConfirm { session, epoch }));
+                }
+                Err(e) => {
+                    log::warn!("[NeighbourConnection] Rekey response from {} but handshake error {:?}", self.pair, e);
+                    *rekey = None;
+                }
+            },
+            None if *key_epoch == epoch => {
+                //our confirm was lost
+                self.output.push_back(self.generate_control(now_ms, NeighboursControlCmds::RekeyConfirm { session, epoch }));
+            }
+            _ => {
+                log::warn!("[NeighbourConnection] Unexpected rekey response from {} for key epoch {epoch}", self.pair);
+            }
+        }
+    }
+
+    fn on_rekey_confirm(&mut self, epoch: u8) {
+        if let State::Connected { key_epoch, rekey, .. } = &mut self.state {
+            if matches!(rekey, Some(RekeyState::Responded { epoch: pending, .. }) if *pending == epoch) {
+                log::info!("[NeighbourConnection] Rekey confirmed by {} => switch to key epoch {epoch}", self.pair);
+                *key_epoch = epoch;
+                *rekey = None;
+                self.output.push_back(Output::Event(ConnectionEvent::RekeyActivated(epoch)));
+            }
+        }
+    }
+
+    /// Answer a connect request as responder, the connection is established if the handshake is valid.
+    /// `offered` is the cipher preference of the requester
+    fn accept_request(&mut self, now_ms: u64, session: u64, handshake: Vec<u8>, offered: &[CipherSuite], cipher: CipherSuite) -> Result<(CipherSuite, Vec<u8>), NeighboursConnectError> {
+        let mut responder = self.handshake_builder.responder();
+        match responder.process_public_request(&handshake, offered, cipher) {
+            Ok((encryptor, decryptor, response)) => {
+                self.output.push_back(Output::Event(ConnectionEvent::Connected(cipher, encryptor, decryptor)));
+                self.state = State::Connected {
+                    connected_ms: now_ms,
+                    last_ping_ms: now_ms,
+                    ping_seq: 0,
+                    missed_pongs: 0,
+                    stats: ConnectionStats { rtt_ms: INIT_RTT_MS },
+                    handshake: Some((handshake, response.clone(), session, cipher)),
+                    cipher,
+                    key_epoch: 0,
+                    rekey: None,
+                };
+                log::info!("[NeighbourConnection] Connected {} as incoming conn with {:?}", self.pair, cipher);
+                Ok((cipher, response))
+            }
+            Err(_) => {
+                log::error!("[NeighbourConnection] Invalid connect request from {}", self.pair);
+                Err(NeighboursConnectError::InvalidData)
+            }
+        }
+    }
+
+    /// Request of this connection with the identity proof if there is a local identity
+    fn connect_request(&self, handshake: Vec<u8>) -> NeighboursControlCmds {
+        let session = self.conn.session();
+        let identity = self
+            .identity
+            .as_ref()
+            .map(|identity| identity.prove(&identity_msg(b"request", session, self.local, self.node, &[&handshake])));
+        NeighboursControlCmds::ConnectRequest {
+            to: self.node,
+            session,
+            handshake,
+            ciphers: self.ciphers.clone(),
+            identity,
+            features: self.features.clone(),
+            codecs: self.codecs.clone(),
+        }
+    }
+
+    /// Identity proof of an accepted response, it also signs the request because that has the fresh handshake of the requester
+    fn response_identity(&self, session: u64, request: &[u8], response: &[u8]) -> Option<IdentityProof> {
+        let identity = self.identity.as_ref()?;
+        Some(identity.prove(&identity_msg(b"response", session, self.local, self.node, &[request, response])))
+    }
+
+    /// Keep the connect request and send the challenge, `at_ms` is when the handshake started
+    fn challenge_request(&mut self, now_ms: u64, at_ms: u64, handshake: Vec<u8>, ciphers: Vec<CipherSuite>, cipher: CipherSuite) {
+        let nonce = self.challenge.expect("Should have challenge nonce");
+        log::info!("[NeighbourConnection] Connect request from {} => challenge for node id proof", self.pair);
+        self.state = State::IncomingChallenged {
+            at_ms,
+            nonce,
+            handshake,
+            ciphers,
+            cipher,
+        };
+        let session = self.conn.session();
+        self.output.push_back(self.generate_control(now_ms, NeighboursControlCmds::ConnectChallenge { session, nonce }));
+    }
+
     fn generate_control(&self, now_ms: u64, control: NeighboursControlCmds) -> Output {
         Output::Net(now_ms, self.pair, control)
     }
@@ -417,12 +1002,248 @@ impl NeighbourConnection {
     }
 }
 
+/// Message which is signed for a node id challenge, it binds the nonce to both nodes and the session
+fn node_id_proof_msg(nonce: u64, requester: NodeId, responder: NodeId, session: u64) -> Vec<u8> {
+    let mut msg = b"node-id-proof".to_vec();
+    msg.extend_from_slice(&nonce.to_be_bytes());
+    msg.extend_from_slice(&requester.to_be_bytes());
+    msg.extend_from_slice(&responder.to_be_bytes());
+    msg.extend_from_slice(&session.to_be_bytes());
+    msg
+}
+
+/// Message which is signed for an identity proof by `signer`, `kind` tells the request and response proofs apart
+fn identity_msg(kind: &[u8], session: u64, signer: NodeId, peer: NodeId, handshakes: &[&[u8]]) -> Vec<u8> {
+    let mut msg = b"identity-".to_vec();
+    msg.extend_from_slice(kind);
+    msg.extend_from_slice(&session.to_be_bytes());
+    msg.extend_from_slice(&signer.to_be_bytes());
+    msg.extend_from_slice(&peer.to_be_bytes());
+    for handshake in handshakes {
+        msg.extend_from_slice(&(handshake.len() as u32).to_be_bytes());
+        msg.extend_from_slice(handshake);
+    }
+    msg
+}
+
+/// Verified public key of the peer, None if it has no identity and one is not required
+fn verify_identity(node: NodeId, required: bool, msg: &[u8], proof: Option<&IdentityProof>) -> Result<Option<[u8; 32]>, NeighboursConnectError> {
+    match proof {
+        Some(proof) if NodeIdentity::verify(node, msg, proof) => Ok(Some(proof.public_key)),
+        Some(_) => Err(NeighboursConnectError::InvalidIdentity),
+        None if required => Err(NeighboursConnectError::IdentityRequired),
+        None => Ok(None),
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::base::{MockDecryptor, MockEncryptor, MockHandshakeBuilder, MockHandshakeRequester, MockHandshakeResponder};
+    use crate::{
+        base::{MockAuthorization, MockDecryptor, MockEncryptor, MockHandshakeBuilder, MockHandshakeRequester, MockHandshakeResponder},
+        secure::{HandshakeBuilderXDA, StaticKeyAuthorization},
+    };
 
     use super::*;
 
+    fn auth() -> Arc<dyn Authorization> {
+        Arc::new(StaticKeyAuthorization::new("demo-key"))
+    }
+
+    /// Connected client and server with real handshakes, all outputs are already consumed
+    fn connected_pair() -> (NeighbourConnection, NeighbourConnection) {
+        let pair = NetPair::new_str("1.1.1.1:1000", "1.2.3.4:1000").expect("Should parse");
+        let ciphers = CipherSuite::DEFAULT_PREFERENCE.to_vec();
+        let mut client = NeighbourConnection::new_outgoing(
+            Arc::new(HandshakeBuilderXDA),
+            auth(),
+            None,
+            ciphers.clone(),
+            FeatureVersions::default(),
+            WireCodec::DEFAULT_PREFERENCE.to_vec(),
+            1,
+            2,
+            1000,
+            pair,
+            100,
+        );
+        let mut server = NeighbourConnection::new_incoming(
+            Arc::new(HandshakeBuilderXDA),
+            auth(),
+            None,
+            ciphers,
+            FeatureVersions::default(),
+            WireCodec::DEFAULT_PREFERENCE.to_vec(),
+            2,
+            1,
+            1000,
+            pair,
+            100,
+        );
+        let request = pop_cmd(&mut client).expect("Should have request");
+        server.on_input(100, 1, request);
+        assert!(matches!(server.pop_output(), Some(Output::Event(ConnectionEvent::Connected(..)))));
+        let response = pop_cmd(&mut server).expect("Should have response");
+        client.on_input(100, 2, response);
+        assert!(matches!(client.pop_output(), Some(Output::Event(ConnectionEvent::Connected(..)))));
+        assert_eq!(client.pop_output(), None);
+        (client, server)
+    }
+
+    fn pop_cmd(conn: &mut NeighbourConnection) -> Option<NeighboursControlCmds> {
+        match conn.pop_output()? {
+            Output::Net(_, _, cmd) => Some(cmd),
+            out => panic!("unexpected output {out:?}"),
+        }
+    }
+
+    fn pop_event(conn: &mut NeighbourConnection) -> Option<ConnectionEvent> {
+        match conn.pop_output()? {
+            Output::Event(event) => Some(event),
+            out => panic!("unexpected output {out:?}"),
+        }
+    }
+
+    #[test]
+    fn should_rotate_key_by_rekey_exchange() {
+        let (mut client, mut server) = connected_pair();
+
+        client.start_rekey(1000);
+        let request = pop_cmd(&mut client).expect("Should have rekey request");
+        assert!(matches!(request, NeighboursControlCmds::RekeyRequest { session: 1000, epoch: 1, .. }));
+        //already rekeying
+        client.start_rekey(1000);
+        assert_eq!(client.pop_output(), None);
+
+        //server decrypts with the new key but keeps encrypting with the old one
+        server.on_input(1000, 1, request.clone());
+        assert!(matches!(pop_event(&mut server), Some(ConnectionEvent::Rekeyed(1, _, _, false))));
+        let response = pop_cmd(&mut server).expect("Should have rekey response");
+        assert!(matches!(response, NeighboursControlCmds::RekeyResponse { session: 1000, epoch: 1, .. }));
+
+        //retransmitted request is answered from cache without installing again
+        server.on_input(1000, 1, request);
+        assert_eq!(pop_cmd(&mut server), Some(response.clone()));
+        assert_eq!(server.pop_output(), None);
+
+        client.on_input(1000, 2, response.clone());
+        assert!(matches!(pop_event(&mut client), Some(ConnectionEvent::Rekeyed(1, _, _, true))));
+        let confirm = pop_cmd(&mut client).expect("Should have rekey confirm");
+        assert_eq!(confirm, NeighboursControlCmds::RekeyConfirm { session: 1000, epoch: 1 });
+
+        //confirm lost, so the server resends its response
+        server.on_tick(2000, &NeighboursCfg::default());
+        assert_eq!(pop_cmd(&mut server), Some(response.clone()));
+        assert!(matches!(pop_cmd(&mut server), Some(NeighboursControlCmds::Ping { .. })));
+        client.on_input(2000, 2, response);
+        assert_eq!(pop_cmd(&mut client), Some(confirm.clone()));
+
+        server.on_input(2000, 1, confirm);
+        assert_eq!(pop_event(&mut server), Some(ConnectionEvent::RekeyActivated(1)));
+        assert_eq!(server.pop_output(), None);
+
+        //next exchange can be started by either side
+        server.start_rekey(3000);
+        assert!(matches!(pop_cmd(&mut server), Some(NeighboursControlCmds::RekeyRequest { epoch: 2, .. })));
+    }
+
+    #[test]
+    fn should_disconnect_after_missed_pongs() {
+        let (mut client, mut server) = connected_pair();
+        let cfg = NeighboursCfg {
+            keepalive_interval_ms: 500,
+            keepalive_miss_limit: 2,
+            ..Default::default()
+        };
+
+        //no ping before the interval
+        client.on_tick(300, &cfg);
+        assert_eq!(client.pop_output(), None);
+
+        client.on_tick(600, &cfg);
+        let ping = pop_cmd(&mut client).expect("Should have ping");
+        assert!(matches!(ping, NeighboursControlCmds::Ping { session: 1000, seq: 1, sent_ms: 600 }));
+        server.on_input(600, 1, ping);
+        let pong = pop_cmd(&mut server).expect("Should have pong");
+        client.on_input(650, 2, pong);
+        assert_eq!(pop_event(&mut client), Some(ConnectionEvent::Stats(ConnectionStats { rtt_ms: 50 })));
+        assert_eq!(client.rtt_ms(), Some(50));
+
+        //two pings are lost
+        client.on_tick(1100, &cfg);
+        assert!(matches!(pop_cmd(&mut client), Some(NeighboursControlCmds::Ping { seq: 2, .. })));
+        client.on_tick(1600, &cfg);
+        assert!(matches!(pop_cmd(&mut client), Some(NeighboursControlCmds::Ping { seq: 3, .. })));
+        client.on_tick(2000, &cfg);
+        assert_eq!(client.pop_output(), None);
+        client.on_tick(2100, &cfg);
+        assert_eq!(pop_event(&mut client), Some(ConnectionEvent::Disconnected(DisconnectReason::Timeout)));
+        assert_eq!(client.rtt_ms(), None);
+
+        //dead connection is only reported once
+        client.on_tick(2600, &cfg);
+        assert_eq!(client.pop_output(), None);
+    }
+
+    #[test]
+    fn should_report_disconnect_reason() {
+        let (mut client, mut server) = connected_pair();
+        client.disconnect(200, DisconnectReason::Rejected);
+        let request = pop_cmd(&mut client).expect("Should have disconnect request");
+        server.on_input(200, 1, request);
+        let response = pop_cmd(&mut server).expect("Should have disconnect response");
+        //the remote only knows that the close is requested
+        assert_eq!(pop_event(&mut server), Some(ConnectionEvent::Disconnected(DisconnectReason::Graceful)));
+        client.on_input(200, 2, response);
+        assert_eq!(pop_event(&mut client), Some(ConnectionEvent::Disconnected(DisconnectReason::Rejected)));
+    }
+
+    #[test]
+    fn should_replace_connection_by_new_session() {
+        let (_client, mut server) = connected_pair();
+        let pair = NetPair::new_str("1.1.1.1:1000", "1.2.3.4:1000").expect("Should parse");
+        let mut restarted = NeighbourConnection::new_outgoing(
+            Arc::new(HandshakeBuilderXDA),
+            auth(),
+            None,
+            CipherSuite::DEFAULT_PREFERENCE.to_vec(),
+            FeatureVersions::default(),
+            WireCodec::DEFAULT_PREFERENCE.to_vec(),
+            1,
+            2,
+            2000,
+            pair,
+            200,
+        );
+        let request = pop_cmd(&mut restarted).expect("Should have request");
+
+        //shortly after the handshake it may be a delayed request, so it does not close the connection
+        server.on_input(200, 1, request.clone());
+        assert!(matches!(pop_cmd(&mut server), Some(NeighboursControlCmds::ConnectResponse { result: Err(_), .. })));
+
+        server.on_input(100 + REPLACE_AFTER_MS, 1, request);
+        assert_eq!(pop_event(&mut server), Some(ConnectionEvent::Disconnected(DisconnectReason::Replaced)));
+        assert_eq!(server.pop_output(), None);
+    }
+
+    #[test]
+    fn concurrent_rekey_should_keep_outgoing_request() {
+        let (mut client, mut server) = connected_pair();
+
+        client.start_rekey(1000);
+        server.start_rekey(1000);
+        let client_req = pop_cmd(&mut client).expect("Should have rekey request");
+        let server_req = pop_cmd(&mut server).expect("Should have rekey request");
+
+        client.on_input(1000, 2, server_req);
+        assert_eq!(client.pop_output(), None);
+
+        server.on_input(1000, 1, client_req);
+        assert!(matches!(pop_event(&mut server), Some(ConnectionEvent::Rekeyed(1, _, _, false))));
+        let response = pop_cmd(&mut server).expect("Should have rekey response");
+        client.on_input(1000, 2, response);
+        assert!(matches!(pop_event(&mut client), Some(ConnectionEvent::Rekeyed(1, _, _, true))));
+    }
+
     #[test]
     fn should_handle_outgoing_connect_correct() {
         let mut client_handshake = MockHandshakeBuilder::default();
@@ -431,11 +1252,23 @@ mod tests {
             requester.expect_create_public_request().return_once(|| Ok(vec![1, 2, 3]));
             requester
                 .expect_process_public_response()
-                .return_once(move |_| Ok((Box::new(MockEncryptor::default()), Box::new(MockDecryptor::default()))));
+                .return_once(move |_, _, _| Ok((Box::new(MockEncryptor::default()), Box::new(MockDecryptor::default()))));
             Box::new(requester)
         });
         let pair = NetPair::new_str("1.1.1.1:1000", "1.2.3.4:1000").expect("Should parse");
-        let mut client = NeighbourConnection::new_outgoing(Arc::new(client_handshake), 1, 2, 1000, pair, 100);
+        let mut client = NeighbourConnection::new_outgoing(
+            Arc::new(client_handshake),
+            auth(),
+            None,
+            CipherSuite::DEFAULT_PREFERENCE.to_vec(),
+            FeatureVersions::default(),
+            WireCodec::DEFAULT_PREFERENCE.to_vec(),
+            1,
+            2,
+            1000,
+            pair,
+            100,
+        );
         assert_eq!(
             client.pop_output(),
             Some(Output::Net(
@@ -444,7 +1277,11 @@ mod tests {
                 NeighboursControlCmds::ConnectRequest {
                     to: 2,
                     session: 1000,
-                    handshake: vec![1, 2, 3]
+                    handshake: vec![1, 2, 3],
+                    ciphers: CipherSuite::DEFAULT_PREFERENCE.to_vec(),
+                    identity: None,
+                    features: FeatureVersions::default(),
+                    codecs: WireCodec::DEFAULT_PREFERENCE.to_vec(),
                 }
             ))
         );
@@ -455,12 +1292,19 @@ mod tests {
             2,
             NeighboursControlCmds::ConnectResponse {
                 session: 1000,
-                result: Ok(vec![2, 3, 4]),
+                result: Ok((CipherSuite::Aes256Gcm, vec![2, 3, 4])),
+                identity: None,
+                features: FeatureVersions::default(),
+                codec: WireCodec::Bincode,
             },
         );
         assert_eq!(
             client.pop_output(),
-            Some(Output::Event(ConnectionEvent::Connected(Box::new(MockEncryptor::default()), Box::new(MockDecryptor::default()))))
+            Some(Output::Event(ConnectionEvent::Connected(
+                CipherSuite::Aes256Gcm,
+                Box::new(MockEncryptor::default()),
+                Box::new(MockDecryptor::default())
+            )))
         );
     }
 
@@ -471,11 +1315,23 @@ mod tests {
             let mut responder = MockHandshakeResponder::default();
             responder
                 .expect_process_public_request()
-                .return_once(|req| Ok((Box::new(MockEncryptor::default()), Box::new(MockDecryptor::default()), req.to_vec())));
+                .return_once(|req, _, _| Ok((Box::new(MockEncryptor::default()), Box::new(MockDecryptor::default()), req.to_vec())));
             Box::new(responder)
         });
         let pair = NetPair::new_str("1.1.1.1:1000", "1.2.3.4:1000").expect("Should parse");
-        let mut server = NeighbourConnection::new_incoming(Arc::new(server_handshake), 1, 2, 1000, pair, 100);
+        let mut server = NeighbourConnection::new_incoming(
+            Arc::new(server_handshake),
+            auth(),
+            None,
+            CipherSuite::DEFAULT_PREFERENCE.to_vec(),
+            FeatureVersions::default(),
+            WireCodec::DEFAULT_PREFERENCE.to_vec(),
+            1,
+            2,
+            1000,
+            pair,
+            100,
+        );
         server.on_input(
             1100,
             2,
@@ -483,12 +1339,20 @@ mod tests {
                 to: 1,
                 session: 1000,
                 handshake: vec![1, 2, 3],
+                ciphers: vec![CipherSuite::ChaCha20Poly1305],
+                identity: None,
+                features: FeatureVersions::default(),
+                codecs: WireCodec::DEFAULT_PREFERENCE.to_vec(),
             },
         );
 
         assert_eq!(
             server.pop_output(),
-            Some(Output::Event(ConnectionEvent::Connected(Box::new(MockEncryptor::default()), Box::new(MockDecryptor::default()))))
+            Some(Output::Event(ConnectionEvent::Connected(
+                CipherSuite::ChaCha20Poly1305,
+                Box::new(MockEncryptor::default()),
+                Box::new(MockDecryptor::default())
+            )))
         );
         assert_eq!(
             server.pop_output(),
@@ -497,7 +1361,10 @@ mod tests {
                 pair,
                 NeighboursControlCmds::ConnectResponse {
                     session: 1000,
-                    result: Ok(vec![1, 2, 3])
+                    result: Ok((CipherSuite::ChaCha20Poly1305, vec![1, 2, 3])),
+                    identity: None,
+                    features: FeatureVersions::default(),
+                    codec: WireCodec::Bincode,
                 }
             ))
         );
@@ -511,6 +1378,10 @@ mod tests {
                 to: 1,
                 session: 1000,
                 handshake: vec![1, 2, 3, 4],
+                ciphers: vec![CipherSuite::ChaCha20Poly1305],
+                identity: None,
+                features: FeatureVersions::default(),
+                codecs: WireCodec::DEFAULT_PREFERENCE.to_vec(),
             },
         );
         assert_eq!(
@@ -520,7 +1391,10 @@ mod tests {
                 pair,
                 NeighboursControlCmds::ConnectResponse {
                     session: 1000,
-                    result: Err(NeighboursConnectError::InvalidData)
+                    result: Err(NeighboursConnectError::InvalidData),
+                    identity: None,
+                    features: FeatureVersions::default(),
+                    codec: WireCodec::Bincode,
                 }
             ))
         );
@@ -534,6 +1408,10 @@ mod tests {
                 to: 1,
                 session: 1000,
                 handshake: vec![1, 2, 3],
+                ciphers: vec![CipherSuite::ChaCha20Poly1305],
+                identity: None,
+                features: FeatureVersions::default(),
+                codecs: WireCodec::DEFAULT_PREFERENCE.to_vec(),
             },
         );
         assert_eq!(
@@ -543,10 +1421,497 @@ mod tests {
                 pair,
                 NeighboursControlCmds::ConnectResponse {
                     session: 1000,
-                    result: Ok(vec![1, 2, 3])
+                    result: Ok((CipherSuite::ChaCha20Poly1305, vec![1, 2, 3])),
+                    identity: None,
+                    features: FeatureVersions::default(),
+                    codec: WireCodec::Bincode,
                 }
             ))
         );
         assert_eq!(server.pop_output(), None);
     }
+
+    #[test]
+    fn should_fallback_chacha20_when_no_shared_cipher() {
+        let mut server_handshake = MockHandshakeBuilder::default();
+        server_handshake.expect_responder().returning(move || {
+            let mut responder = MockHandshakeResponder::default();
+            responder
+                .expect_process_public_request()
+                .withf(|_, offered, cipher| offered == [CipherSuite::Aes256Gcm] && *cipher == CipherSuite::ChaCha20Poly1305)
+                .return_once(|req, _, _| Ok((Box::new(MockEncryptor::default()), Box::new(MockDecryptor::default()), req.to_vec())));
+            Box::new(responder)
+        });
+        let pair = NetPair::new_str("1.1.1.1:1000", "1.2.3.4:1000").expect("Should parse");
+        let mut server = NeighbourConnection::new_incoming(
+            Arc::new(server_handshake),
+            auth(),
+            None,
+            vec![CipherSuite::ChaCha20Poly1305],
+            FeatureVersions::default(),
+            WireCodec::DEFAULT_PREFERENCE.to_vec(),
+            1,
+            2,
+            1000,
+            pair,
+            100,
+        );
+        server.on_input(
+            1100,
+            2,
+            NeighboursControlCmds::ConnectRequest {
+                to: 1,
+                session: 1000,
+                handshake: vec![1, 2, 3],
+                ciphers: vec![CipherSuite::Aes256Gcm],
+                identity: None,
+                features: FeatureVersions::default(),
+                codecs: WireCodec::DEFAULT_PREFERENCE.to_vec(),
+            },
+        );
+
+        assert_eq!(
+            server.pop_output(),
+            Some(Output::Event(ConnectionEvent::Connected(
+                CipherSuite::ChaCha20Poly1305,
+                Box::new(MockEncryptor::default()),
+                Box::new(MockDecryptor::default())
+            )))
+        );
+        assert_eq!(
+            server.pop_output(),
+            Some(Output::Net(
+                1100,
+                pair,
+                NeighboursControlCmds::ConnectResponse {
+                    session: 1000,
+                    result: Ok((CipherSuite::ChaCha20Poly1305, vec![1, 2, 3])),
+                    identity: None,
+                    features: FeatureVersions::default(),
+                    codec: WireCodec::Bincode,
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn should_reject_not_offered_cipher() {
+        let mut client_handshake = MockHandshakeBuilder::default();
+        client_handshake.expect_requester().returning(move || {
+            let mut requester = MockHandshakeRequester::default();
+            requester.expect_create_public_request().return_once(|| Ok(vec![1, 2, 3]));
+            Box::new(requester)
+        });
+        let pair = NetPair::new_str("1.1.1.1:1000", "1.2.3.4:1000").expect("Should parse");
+        let mut client = NeighbourConnection::new_outgoing(
+            Arc::new(client_handshake),
+            auth(),
+            None,
+            vec![CipherSuite::ChaCha20Poly1305],
+            FeatureVersions::default(),
+            WireCodec::DEFAULT_PREFERENCE.to_vec(),
+            1,
+            2,
+            1000,
+            pair,
+            100,
+        );
+        assert!(matches!(client.pop_output(), Some(Output::Net(..))));
+
+        client.on_input(
+            1100,
+            2,
+            NeighboursControlCmds::ConnectResponse {
+                session: 1000,
+                result: Ok((CipherSuite::Aes256Gcm, vec![2, 3, 4])),
+                identity: None,
+                features: FeatureVersions::default(),
+                codec: WireCodec::Bincode,
+            },
+        );
+        assert_eq!(client.pop_output(), Some(Output::Event(ConnectionEvent::ConnectError(NeighboursConnectError::InvalidData))));
+    }
+
+    #[test]
+    fn should_exchange_feature_versions() {
+        let pair = NetPair::new_str("1.1.1.1:1000", "1.2.3.4:1000").expect("Should parse");
+        let ciphers = CipherSuite::DEFAULT_PREFERENCE.to_vec();
+        let mut client = NeighbourConnection::new_outgoing(
+            Arc::new(HandshakeBuilderXDA),
+            auth(),
+            None,
+            ciphers.clone(),
+            FeatureVersions::new([(3, 2)]),
+            WireCodec::DEFAULT_PREFERENCE.to_vec(),
+            1,
+            2,
+            1000,
+            pair,
+            100,
+        );
+        let mut server = NeighbourConnection::new_incoming(
+            Arc::new(HandshakeBuilderXDA),
+            auth(),
+            None,
+            ciphers,
+            FeatureVersions::new([(3, 3), (4, 2)]),
+            WireCodec::DEFAULT_PREFERENCE.to_vec(),
+            2,
+            1,
+            1000,
+            pair,
+            100,
+        );
+        //unknown until the handshake is done
+        assert_eq!(client.ctx().feature_version(3), 1);
+
+        let request = pop_cmd(&mut client).expect("Should have request");
+        server.on_input(100, 1, request);
+        assert!(matches!(pop_event(&mut server), Some(ConnectionEvent::Connected(..))));
+        let response = pop_cmd(&mut server).expect("Should have response");
+        client.on_input(100, 2, response);
+        assert!(matches!(pop_event(&mut client), Some(ConnectionEvent::Connected(..))));
+
+        //the lower version of both sides, a feature which one side does not list stays at version 1
+        for conn in [&client, &server] {
+            assert_eq!(conn.ctx().feature_version(3), 2);
+            assert_eq!(conn.ctx().feature_version(4), 1);
+        }
+    }
+
+    #[test]
+    fn should_negotiate_codec() {
+        let pair = NetPair::new_str("1.1.1.1:1000", "1.2.3.4:1000").expect("Should parse");
+        let ciphers = CipherSuite::DEFAULT_PREFERENCE.to_vec();
+        let codecs = vec![WireCodec::Postcard, WireCodec::Cbor];
+        let mut client = NeighbourConnection::new_outgoing(Arc::new(HandshakeBuilderXDA), auth(), None, ciphers.clone(), FeatureVersions::default(), codecs, 1, 2, 1000, pair, 100);
+        let mut server = NeighbourConnection::new_incoming(
+            Arc::new(HandshakeBuilderXDA),
+            auth(),
+            None,
+            ciphers,
+            FeatureVersions::default(),
+            vec![WireCodec::Cbor],
+            2,
+            1,
+            1000,
+            pair,
+            100,
+        );
+        assert_eq!(client.codec(), WireCodec::Bincode);
+
+        let request = pop_cmd(&mut client).expect("Should have request");
+        server.on_input(100, 1, request);
+        assert!(matches!(pop_event(&mut server), Some(ConnectionEvent::Connected(..))));
+        let response = pop_cmd(&mut server).expect("Should have response");
+        client.on_input(100, 2, response);
+        assert!(matches!(pop_event(&mut client), Some(ConnectionEvent::Connected(..))));
+
+        for conn in [&client, &server] {
+            assert_eq!(conn.codec(), WireCodec::Cbor);
+            assert_eq!(conn.ctx().codec, WireCodec::Cbor);
+        }
+    }
+
+    #[test]
+    fn should_reject_not_offered_codec() {
+        let pair = NetPair::new_str("1.1.1.1:1000", "1.2.3.4:1000").expect("Should parse");
+        let ciphers = CipherSuite::DEFAULT_PREFERENCE.to_vec();
+        let mut client = NeighbourConnection::new_outgoing(
+            Arc::new(HandshakeBuilderXDA),
+            auth(),
+            None,
+            ciphers,
+            FeatureVersions::default(),
+            WireCodec::DEFAULT_PREFERENCE.to_vec(),
+            1,
+            2,
+            1000,
+            pair,
+            100,
+        );
+        assert!(matches!(client.pop_output(), Some(Output::Net(..))));
+
+        client.on_input(
+            1100,
+            2,
+            NeighboursControlCmds::ConnectResponse {
+                session: 1000,
+                result: Ok((CipherSuite::ChaCha20Poly1305, vec![2, 3, 4])),
+                identity: None,
+                features: FeatureVersions::default(),
+                codec: WireCodec::Postcard,
+            },
+        );
+        assert_eq!(client.pop_output(), Some(Output::Event(ConnectionEvent::ConnectError(NeighboursConnectError::InvalidData))));
+    }
+
+    fn proof_cfg() -> NeighboursCfg {
+        NeighboursCfg {
+            handshake_timeout_ms: 5000,
+            require_node_id_proof: true,
+            ..Default::default()
+        }
+    }
+
+    /// Client and server with real handshakes, the server requires a node id proof with nonce 1234
+    fn challenged_pair(server_auth: Arc<dyn Authorization>) -> (NeighbourConnection, NeighbourConnection) {
+        let pair = NetPair::new_str("1.1.1.1:1000", "1.2.3.4:1000").expect("Should parse");
+        let ciphers = CipherSuite::DEFAULT_PREFERENCE.to_vec();
+        let client = NeighbourConnection::new_outgoing(
+            Arc::new(HandshakeBuilderXDA),
+            auth(),
+            None,
+            ciphers.clone(),
+            FeatureVersions::default(),
+            WireCodec::DEFAULT_PREFERENCE.to_vec(),
+            1,
+            2,
+            1000,
+            pair,
+            100,
+        );
+        let mut server = NeighbourConnection::new_incoming(
+            Arc::new(HandshakeBuilderXDA),
+            server_auth,
+            None,
+            ciphers,
+            FeatureVersions::default(),
+            WireCodec::DEFAULT_PREFERENCE.to_vec(),
+            2,
+            1,
+            1000,
+            pair,
+            100,
+        );
+        server.require_node_id_proof(1234);
+        (client, server)
+    }
+
+    #[test]
+    fn should_connect_with_node_id_proof() {
+        let (mut client, mut server) = challenged_pair(auth());
+        let request = pop_cmd(&mut client).expect("Should have request");
+        server.on_input(100, 1, request.clone());
+        let challenge = pop_cmd(&mut server).expect("Should have challenge");
+        assert_eq!(challenge, NeighboursControlCmds::ConnectChallenge { session: 1000, nonce: 1234 });
+        assert_eq!(server.pop_output(), None);
+
+        //retransmitted request gets the same challenge
+        server.on_input(200, 1, request);
+        assert_eq!(pop_cmd(&mut server), Some(challenge.clone()));
+
+        client.on_input(200, 2, challenge);
+        let proof = pop_cmd(&mut client).expect("Should have proof");
+        assert!(matches!(proof, NeighboursControlCmds::ConnectProof { session: 1000, .. }));
+
+        server.on_input(300, 1, proof);
+        assert!(matches!(pop_event(&mut server), Some(ConnectionEvent::Connected(..))));
+        let response = pop_cmd(&mut server).expect("Should have response");
+        client.on_input(300, 2, response);
+        assert!(matches!(pop_event(&mut client), Some(ConnectionEvent::Connected(..))));
+    }
+
+    #[test]
+    fn should_timeout_handshake_by_cfg() {
+        let cfg = proof_cfg();
+        let (mut client, mut server) = challenged_pair(auth());
+        let request = pop_cmd(&mut client).expect("Should have request");
+        server.on_input(100, 1, request);
+        assert!(matches!(pop_cmd(&mut server), Some(NeighboursControlCmds::ConnectChallenge { .. })));
+
+        //the challenge is lost
+        server.on_tick(5000, &cfg);
+        assert_eq!(server.pop_output(), None);
+        server.on_tick(5100, &cfg);
+        assert_eq!(pop_event(&mut server), Some(ConnectionEvent::ConnectTimeout));
+
+        client.on_tick(5100, &cfg);
+        assert_eq!(pop_event(&mut client), Some(ConnectionEvent::ConnectTimeout));
+    }
+
+    #[test]
+    fn should_reject_unexpected_handshake_message() {
+        let (mut client, mut server) = challenged_pair(auth());
+        let request = pop_cmd(&mut client).expect("Should have request");
+
+        //proof before any challenge
+        server.on_input(100, 1, NeighboursControlCmds::ConnectProof { session: 1000, proof: vec![1, 2, 3] });
+        assert_eq!(
+            pop_cmd(&mut server),
+            Some(NeighboursControlCmds::ConnectResponse {
+                session: 1000,
+                result: Err(NeighboursConnectError::InvalidState),
+                identity: None,
+                features: FeatureVersions::default(),
+                codec: WireCodec::Bincode,
+            })
+        );
+
+        //proof of other session
+        server.on_input(100, 1, request);
+        assert!(matches!(pop_cmd(&mut server), Some(NeighboursControlCmds::ConnectChallenge { .. })));
+        server.on_input(200, 1, NeighboursControlCmds::ConnectProof { session: 1001, proof: vec![1, 2, 3] });
+        assert_eq!(
+            pop_cmd(&mut server),
+            Some(NeighboursControlCmds::ConnectResponse {
+                session: 1001,
+                result: Err(NeighboursConnectError::InvalidState),
+                identity: None,
+                features: FeatureVersions::default(),
+                codec: WireCodec::Bincode,
+            })
+        );
+
+        //challenge of other session
+        client.on_input(200, 2, NeighboursControlCmds::ConnectChallenge { session: 1001, nonce: 1234 });
+        assert_eq!(client.pop_output(), None);
+
+        //none of them is an error which stops the handshake, the server still waits for the proof
+        client.on_input(300, 2, NeighboursControlCmds::ConnectChallenge { session: 1000, nonce: 1234 });
+        let proof = pop_cmd(&mut client).expect("Should have proof");
+        server.on_input(300, 1, proof);
+        assert!(matches!(pop_event(&mut server), Some(ConnectionEvent::Connected(..))));
+    }
+
+    #[test]
+    fn should_reject_failed_node_id_proof() {
+        let mut server_auth = MockAuthorization::default();
+        server_auth.expect_validate().returning(|_, _, _| None);
+        let (mut client, mut server) = challenged_pair(Arc::new(server_auth));
+        let request = pop_cmd(&mut client).expect("Should have request");
+        server.on_input(100, 1, request);
+        let challenge = pop_cmd(&mut server).expect("Should have challenge");
+        client.on_input(100, 2, challenge);
+        let proof = pop_cmd(&mut client).expect("Should have proof");
+
+        server.on_input(200, 1, proof);
+        assert_eq!(pop_event(&mut server), Some(ConnectionEvent::ConnectError(NeighboursConnectError::InvalidProof)));
+        let response = pop_cmd(&mut server).expect("Should have response");
+        assert_eq!(
+            response,
+            NeighboursControlCmds::ConnectResponse {
+                session: 1000,
+                result: Err(NeighboursConnectError::InvalidProof),
+                identity: None,
+                features: FeatureVersions::default(),
+                codec: WireCodec::Bincode,
+            }
+        );
+
+        //requester stops instead of retrying until timeout
+        client.on_input(200, 2, response);
+        assert_eq!(pop_event(&mut client), Some(ConnectionEvent::ConnectError(NeighboursConnectError::InvalidProof)));
+    }
+
+    /// Client and server with real handshakes, all outputs are still queued
+    fn identity_pair(client: Option<NodeIdentity>, client_id: NodeId, server: Option<NodeIdentity>, server_id: NodeId) -> (NeighbourConnection, NeighbourConnection) {
+        let pair = NetPair::new_str("1.1.1.1:1000", "1.2.3.4:1000").expect("Should parse");
+        let ciphers = CipherSuite::DEFAULT_PREFERENCE.to_vec();
+        let client = NeighbourConnection::new_outgoing(
+            Arc::new(HandshakeBuilderXDA),
+            auth(),
+            client.map(Arc::new),
+            ciphers.clone(),
+            FeatureVersions::default(),
+            WireCodec::DEFAULT_PREFERENCE.to_vec(),
+            client_id,
+            server_id,
+            1000,
+            pair,
+            100,
+        );
+        let server = NeighbourConnection::new_incoming(
+            Arc::new(HandshakeBuilderXDA),
+            auth(),
+            server.map(Arc::new),
+            ciphers,
+            FeatureVersions::default(),
+            WireCodec::DEFAULT_PREFERENCE.to_vec(),
+            server_id,
+            client_id,
+            1000,
+            pair,
+            100,
+        );
+        (client, server)
+    }
+
+    #[test]
+    fn should_connect_with_identities() {
+        let (client_identity, server_identity) = (NodeIdentity::from_secret([1; 32]), NodeIdentity::from_secret([2; 32]));
+        let (client_id, server_id) = (client_identity.node_id(), server_identity.node_id());
+        let (client_key, server_key) = (client_identity.public_key(), server_identity.public_key());
+        let (mut client, mut server) = identity_pair(Some(client_identity), client_id, Some(server_identity), server_id);
+        server.require_identity();
+        client.require_identity();
+
+        let request = pop_cmd(&mut client).expect("Should have request");
+        server.on_input(100, client_id, request);
+        assert!(matches!(pop_event(&mut server), Some(ConnectionEvent::Connected(..))));
+        let response = pop_cmd(&mut server).expect("Should have response");
+        client.on_input(100, server_id, response);
+        assert!(matches!(pop_event(&mut client), Some(ConnectionEvent::Connected(..))));
+
+        assert_eq!(server.peer_identity(), Some(client_key));
+        assert_eq!(client.peer_identity(), Some(server_key));
+        assert_eq!(server.ctx().peer_identity.as_deref(), Some(&client_key));
+        assert_eq!(client.ctx().peer_identity.as_deref(), Some(&server_key));
+    }
+
+    #[test]
+    fn should_reject_spoofed_identity() {
+        let server_identity = NodeIdentity::from_secret([2; 32]);
+        let server_id = server_identity.node_id();
+        //node 1 is not derived from this key
+        let (mut client, mut server) = identity_pair(Some(NodeIdentity::from_secret([1; 32])), 1, Some(server_identity), server_id);
+
+        let request = pop_cmd(&mut client).expect("Should have request");
+        server.on_input(100, 1, request);
+        assert_eq!(pop_event(&mut server), Some(ConnectionEvent::ConnectError(NeighboursConnectError::InvalidIdentity)));
+        let response = pop_cmd(&mut server).expect("Should have response");
+        assert_eq!(
+            response,
+            NeighboursControlCmds::ConnectResponse {
+                session: 1000,
+                result: Err(NeighboursConnectError::InvalidIdentity),
+                identity: None,
+                features: FeatureVersions::default(),
+                codec: WireCodec::Bincode,
+            }
+        );
+        client.on_input(100, server_id, response);
+        assert_eq!(pop_event(&mut client), Some(ConnectionEvent::ConnectError(NeighboursConnectError::InvalidIdentity)));
+    }
+
+    #[test]
+    fn should_reject_identity_with_bad_signature() {
+        let client_identity = NodeIdentity::from_secret([1; 32]);
+        let client_id = client_identity.node_id();
+        let (mut client, mut server) = identity_pair(Some(client_identity), client_id, None, 2);
+
+        let mut request = pop_cmd(&mut client).expect("Should have request");
+        match &mut request {
+            NeighboursControlCmds::ConnectRequest { identity: Some(identity), .. } => identity.signature[0] ^= 1,
+            cmd => panic!("unexpected cmd {cmd:?}"),
+        }
+        server.on_input(100, client_id, request);
+        assert_eq!(pop_event(&mut server), Some(ConnectionEvent::ConnectError(NeighboursConnectError::InvalidIdentity)));
+        assert_eq!(server.peer_identity(), None);
+    }
+
+    #[test]
+    fn should_require_identity_only_when_configured() {
+        //bare numeric ids are still accepted by default
+        let (mut client, mut server) = identity_pair(None, 1, Some(NodeIdentity::from_secret([2; 32])), 2);
+        let request = pop_cmd(&mut client).expect("Should have request");
+        server.on_input(100, 1, request.clone());
+        assert!(matches!(pop_event(&mut server), Some(ConnectionEvent::Connected(..))));
+        assert_eq!(server.peer_identity(), None);
+
+        let (_, mut server) = identity_pair(None, 1, Some(NodeIdentity::from_secret([2; 32])), 2);
+        server.require_identity();
+        server.on_input(100, 1, request);
+        assert_eq!(pop_event(&mut server), Some(ConnectionEvent::ConnectError(NeighboursConnectError::IdentityRequired)));
+    }
 }
@@ -0,0 +1,132 @@
+//! Token buckets of inbound handshakes, keyed by the source ip.
+//!
+//! Tokens are counted in thousandths, so a bucket with `per_sec` rate gains `per_sec` thousandths each ms and no
+//! float is needed. A new source starts with a full bucket of `burst` tokens.
+
+use std::{collections::HashMap, net::IpAddr};
+
+use crate::features::neighbours::HandshakeRateCfg;
+
+/// Throttled handshakes are logged at most once in this interval
+const LOG_INTERVAL_MS: u64 = 5000;
+const TOKEN: u64 = 1000;
+
+struct Bucket {
+    tokens: u64,
+    updated_ms: u64,
+}
+
+pub struct HandshakeLimiter {
+    cfg: HandshakeRateCfg,
+    buckets: HashMap<IpAddr, Bucket>,
+    /// Throttled since the last log
+    unlogged: u64,
+    last_log_ms: Option<u64>,
+}
+
+impl HandshakeLimiter {
+    pub fn new(cfg: HandshakeRateCfg) -> Self {
+        Self {
+            cfg,
+            buckets: HashMap::new(),
+            unlogged: 0,
+            last_log_ms: None,
+        }
+    }
+
+    fn capacity(&self) -> u64 {
+        self.cfg.burst.max(1) as u64 * TOKEN
+    }
+
+    fn refilled(&self, bucket: &Bucket, now_ms: u64) -> u64 {
+        let gained = now_ms.saturating_sub(bucket.updated_ms).saturating_mul(self.cfg.per_sec as u64);
+        bucket.tokens.saturating_add(gained).min(self.capacity())
+    }
+
+    /// Take a token of the source, false if the handshake must be dropped
+    pub fn try_acquire(&mut self, now_ms: u64, ip: IpAddr) -> bool {
+        let capacity = self.capacity();
+        let tokens = match self.buckets.get(&ip) {
+            Some(bucket) => self.refilled(bucket, now_ms),
+            None => capacity,
+        };
+        let allowed = tokens >= TOKEN;
+        let tokens = if allowed {
+            tokens - TOKEN
+        } else {
+            tokens
+        };
+        self.buckets.insert(ip, Bucket { tokens, updated_ms: now_ms });
+        if !allowed {
+            self.unlogged += 1;
+            if self.last_log_ms.map_or(true, |last| now_ms >= last + LOG_INTERVAL_MS) {
+                log::warn!("[Neighbours] Throttled {} inbound handshakes, latest from {ip}", self.unlogged);
+                self.unlogged = 0;
+                self.last_log_ms = Some(now_ms);
+            }
+        }
+        allowed
+    }
+
+    /// Forget sources whose bucket is full again, they would start with a full bucket anyway
+    pub fn on_tick(&mut self, now_ms: u64) {
+        let capacity = self.capacity();
+        let full: Vec<IpAddr> = self.buckets.iter().filter(|(_, bucket)| self.refilled(bucket, now_ms) >= capacity).map(|(ip, _)| *ip).collect();
+        for ip in full {
+            self.buckets.remove(&ip);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use crate::features::neighbours::HandshakeRateCfg;
+
+    use super::HandshakeLimiter;
+
+    const IP1: IpAddr = IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1));
+    const IP2: IpAddr = IpAddr::V4(Ipv4Addr::new(2, 2, 2, 2));
+
+    #[test]
+    fn should_allow_burst_then_refill() {
+        let mut limiter = HandshakeLimiter::new(HandshakeRateCfg { per_sec: 2, burst: 3 });
+        for _ in 0..3 {
+            assert!(limiter.try_acquire(0, IP1));
+        }
+        assert!(!limiter.try_acquire(0, IP1));
+        //other sources have their own bucket
+        assert!(limiter.try_acquire(0, IP2));
+
+        //2 tokens per second, so one token after 500ms
+        assert!(!limiter.try_acquire(499, IP1));
+        assert!(limiter.try_acquire(500, IP1));
+        assert!(!limiter.try_acquire(500, IP1));
+
+        //refill is capped by the burst
+        for _ in 0..3 {
+            assert!(limiter.try_acquire(100_000, IP1));
+        }
+        assert!(!limiter.try_acquire(100_000, IP1));
+    }
+
+    #[test]
+    fn should_drop_flood_with_bounded_state() {
+        let mut limiter = HandshakeLimiter::new(HandshakeRateCfg { per_sec: 10, burst: 5 });
+        let mut allowed = 0;
+        //10000 attempts in one second
+        for i in 0..10_000 {
+            if limiter.try_acquire(i / 10, IP1) {
+                allowed += 1;
+            }
+        }
+        assert_eq!(allowed, 5 + 9);
+        assert_eq!(limiter.buckets.len(), 1);
+
+        limiter.on_tick(1000);
+        assert_eq!(limiter.buckets.len(), 1);
+        limiter.on_tick(1500);
+        assert_eq!(limiter.buckets.len(), 0);
+    }
+}
@@ -6,6 +6,7 @@ use sans_io_runtime::{TaskSwitcher, TaskSwitcherBranch, TaskSwitcherChild};
 use crate::base::Service;
 use crate::base::{ServiceBuilder, ServiceCtx, ServiceId, ServiceInput, ServiceOutput, ServiceSharedInput};
 use crate::features::{FeaturesControl, FeaturesEvent};
+use crate::services::ServiceScheduler;
 
 pub enum Output<UserData, ServiceEvent, ToWorker> {
     Output(ServiceId, ServiceOutput<UserData, FeaturesControl, ServiceEvent, ToWorker>),
@@ -20,6 +21,7 @@ type ServiceSwitcher<UserData, ServiceControl, ServiceEvent, ToController, ToWor
 struct ServiceSlot<UserData, ServiceControl, ServiceEvent, ToController, ToWorker> {
     service: ServiceSwitcher<UserData, ServiceControl, ServiceEvent, ToController, ToWorker>,
     is_empty: bool,
+    budget: u8,
 }
 
 /// To manage the services we need to create an object that will hold the services
@@ -29,6 +31,7 @@ pub struct ServiceManager<UserData, ServiceControl, ServiceEvent, ToController,
     services_count: usize,
     empty_services: HashSet<ServiceId>,
     switcher: TaskSwitcher,
+    scheduler: ServiceScheduler,
     shutdown: bool,
 }
 
@@ -42,10 +45,12 @@ impl<UserData, ServiceControl, ServiceEvent, ToController, ToWorker> ServiceMana
                 services.iter().find(|s| s.service_id() == index as u8).map(|s| ServiceSlot {
                     service: TaskSwitcherBranch::new(s.create(), index),
                     is_empty: false,
+                    budget: s.output_budget(),
                 })
             }),
             empty_services: HashSet::default(),
             switcher: TaskSwitcher::new(max_service_id as usize + 1),
+            scheduler: ServiceScheduler::new((0..=max_service_id).map(|id| services.iter().find(|s| s.service_id() == id).map_or(1, |s| s.output_budget())).collect()),
             shutdown: false,
         }
     }
@@ -56,6 +61,50 @@ impl<UserData, ServiceControl, ServiceEvent, ToController, ToWorker> ServiceMana
         }
     }
 
+    pub fn has_service(&self, id: ServiceId) -> bool {
+        self.services[*id as usize].is_some()
+    }
+
+    /// Install a service at runtime, a running service with the same id is replaced
+    pub fn register(&mut self, builder: Arc<dyn ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, ServiceControl, ServiceEvent, ToController, ToWorker>>) {
+        let id = builder.service_id();
+        log::info!("[ControllerPlane] register service {} {}", id, builder.service_name());
+        if self.services[id as usize].is_none() {
+            self.services_count += 1;
+        }
+        self.empty_services.remove(&id.into());
+        self.services[id as usize] = Some(ServiceSlot {
+            service: TaskSwitcherBranch::new(builder.create(), id as usize),
+            is_empty: false,
+            budget: builder.output_budget(),
+        });
+        self.reset_switcher();
+    }
+
+    /// Remove a service at runtime, its queued outputs are dropped. Returns false if it is not registered
+    pub fn unregister(&mut self, id: ServiceId) -> bool {
+        if self.services[*id as usize].take().is_none() {
+            return false;
+        }
+        log::info!("[ControllerPlane] unregister service {id}");
+        self.services_count -= 1;
+        self.empty_services.remove(&id);
+        true
+    }
+
+    /// The switcher and scheduler are sized by the largest service id, so they are rebuilt when a service is added.
+    /// All services are flagged again, none of their pending outputs is lost
+    fn reset_switcher(&mut self) {
+        let max_service_id = self.services.iter().rposition(Option::is_some).unwrap_or(0);
+        self.switcher = TaskSwitcher::new(max_service_id + 1);
+        self.scheduler = ServiceScheduler::new((0..=max_service_id).map(|id| self.services[id].as_ref().map_or(1, |s| s.budget)).collect());
+        for id in 0..=max_service_id {
+            if self.services[id].is_some() {
+                self.switcher.flag_task(id);
+            }
+        }
+    }
+
     pub fn on_input(&mut self, ctx: &ServiceCtx, now: u64, id: ServiceId, input: ServiceInput<UserData, FeaturesEvent, ServiceControl, ToController>) {
         if let Some(Some(service)) = self.services.get_mut(*id as usize) {
             self.switcher.flag_task(*id as usize);
@@ -90,9 +139,10 @@ impl<UserData, ServiceControl, ServiceEvent, ToController, ToWorker> TaskSwitche
 
     fn pop_output(&mut self, now: u64) -> Option<Output<UserData, ServiceEvent, ToWorker>> {
         loop {
-            let index = self.switcher.current()?;
+            let index = self.scheduler.current(&mut self.switcher)?;
             if let Some(Some(slot)) = self.services.get_mut(index) {
                 if let Some(output) = slot.service.pop_output(now, &mut self.switcher) {
+                    self.scheduler.on_output();
                     return Some(Output::Output((index as u8).into(), output));
                 } else {
                     if !slot.is_empty {
@@ -116,3 +166,102 @@ impl<UserData, ServiceControl, ServiceEvent, ToController, ToWorker> TaskSwitche
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, sync::Arc};
+
+    use sans_io_runtime::TaskSwitcherChild;
+
+    use crate::{
+        base::{Service, ServiceBuilder, ServiceControlActor, ServiceCtx, ServiceId, ServiceInput, ServiceOutput, ServiceSharedInput, ServiceWorker, DEFAULT_SERVICE_OUTPUT_BUDGET},
+        features::{FeaturesControl, FeaturesEvent},
+    };
+
+    use super::{Output, ServiceManager};
+
+    /// Each control makes the service emit `outputs` events
+    struct MockService {
+        id: u8,
+        outputs: usize,
+        queue: VecDeque<ServiceOutput<(), FeaturesControl, (), ()>>,
+    }
+
+    impl Service<(), FeaturesControl, FeaturesEvent, (), (), (), ()> for MockService {
+        fn is_service_empty(&self) -> bool {
+            false
+        }
+
+        fn service_id(&self) -> u8 {
+            self.id
+        }
+
+        fn service_name(&self) -> &str {
+            "mock"
+        }
+
+        fn on_shared_input(&mut self, _ctx: &ServiceCtx, _now: u64, _input: ServiceSharedInput) {}
+
+        fn on_input(&mut self, _ctx: &ServiceCtx, _now: u64, _input: ServiceInput<(), FeaturesEvent, (), ()>) {
+            for _ in 0..self.outputs {
+                self.queue.push_back(ServiceOutput::Event(ServiceControlActor::Controller(()), ()));
+            }
+        }
+
+        fn on_shutdown(&mut self, _ctx: &ServiceCtx, _now: u64) {}
+
+        fn pop_output2(&mut self, _now: u64) -> Option<ServiceOutput<(), FeaturesControl, (), ()>> {
+            self.queue.pop_front()
+        }
+    }
+
+    struct MockServiceBuilder {
+        id: u8,
+        outputs: usize,
+    }
+
+    impl ServiceBuilder<(), FeaturesControl, FeaturesEvent, (), (), (), ()> for MockServiceBuilder {
+        fn service_id(&self) -> u8 {
+            self.id
+        }
+
+        fn service_name(&self) -> &str {
+            "mock"
+        }
+
+        fn create(&self) -> Box<dyn Service<(), FeaturesControl, FeaturesEvent, (), (), (), ()>> {
+            Box::new(MockService {
+                id: self.id,
+                outputs: self.outputs,
+                queue: VecDeque::new(),
+            })
+        }
+
+        fn create_worker(&self) -> Box<dyn ServiceWorker<(), FeaturesControl, FeaturesEvent, (), (), (), ()>> {
+            unimplemented!("controller only")
+        }
+    }
+
+    #[test]
+    fn greedy_service_should_not_starve_others() {
+        let greedy = Arc::new(MockServiceBuilder { id: 0, outputs: 100 });
+        let polite = Arc::new(MockServiceBuilder { id: 1, outputs: 1 });
+        let mut manager = ServiceManager::<(), (), (), (), ()>::new(vec![greedy, polite]);
+        let ctx = ServiceCtx { node_id: 1, session: 0 };
+        manager.on_input(&ctx, 0, ServiceId(0), ServiceInput::Control(ServiceControlActor::Controller(()), ()));
+        manager.on_input(&ctx, 0, ServiceId(1), ServiceInput::Control(ServiceControlActor::Controller(()), ()));
+
+        let mut popped = vec![];
+        while let Some(out) = manager.pop_output(0) {
+            match out {
+                Output::Output(id, ServiceOutput::Event(..)) => popped.push(*id),
+                _ => panic!("unexpected output"),
+            }
+        }
+        assert_eq!(popped.len(), 101);
+        //the polite service is served right after the greedy one used its budget
+        let mut expected = vec![0; DEFAULT_SERVICE_OUTPUT_BUDGET as usize];
+        expected.push(1);
+        assert_eq!(popped[..expected.len()], expected);
+    }
+}
@@ -8,25 +8,57 @@ use std::{
 
 use atm0s_sdn_identity::{ConnId, NodeId};
 use atm0s_sdn_router::{
-    shadow::{ShadowRouter, ShadowRouterHistory},
+    shadow::{ShadowRouter, ShadowRouterDelta, ShadowRouterHistory},
     RouteAction, RouteRule, RouterTable,
 };
-use sans_io_runtime::{collections::DynamicDeque, return_if_err, return_if_none, return_if_some, TaskSwitcher, TaskSwitcherBranch, TaskSwitcherChild};
+use atm0s_sdn_utils::metrics;
+use rand::RngCore;
+use sans_io_runtime::{collections::DynamicDeque, return_if_none, return_if_some, TaskSwitcher, TaskSwitcherBranch, TaskSwitcherChild};
 
 use crate::{
     base::{
-        Buffer, FeatureControlActor, FeatureWorkerContext, FeatureWorkerInput, FeatureWorkerOutput, NeighboursControl, NetOutgoingMeta, ServiceBuilder, ServiceControlActor, ServiceId,
-        ServiceWorkerCtx, ServiceWorkerInput, ServiceWorkerOutput, TransportMsg, TransportMsgHeader,
+        Buffer, DirectSendError, DisconnectReason, FeatureControlActor, FeatureWorkerContext, FeatureWorkerInput, FeatureWorkerOutput, NeighboursControl, NeighboursControlError, NetOutgoingMeta,
+        RekeyPolicy, SecureContext, ServiceBuilder, ServiceControlActor, ServiceId, ServiceWorkerCtx, ServiceWorkerInput, ServiceWorkerOutput, TrafficClass, TransportMsg, TransportMsgHeader,
+        TransportMsgHeaderError, Ttl, UnknownServicePolicy, NEIGHBOURS_CONTROL_MIN_LEN, NEIGHBOURS_CONTROL_VERSION,
     },
-    features::{Features, FeaturesControl, FeaturesEvent},
+    features::{FeaturePriority, Features, FeaturesConfig, FeaturesControl, FeaturesEvent},
     ExtIn, ExtOut, LogicControl, LogicEvent,
 };
 
-use self::{connection::DataPlaneConnection, features::FeatureWorkerManager, services::ServiceWorkerManager};
+pub use self::connection::{ConnDropStats, ConnStats, DropReason, MAX_SECURE_OVERHEAD};
+pub use self::dscp::{DscpMap, DSCP_CS6, DSCP_DEFAULT, DSCP_MAX};
+pub use self::features::DEFAULT_MAX_PAYLOAD;
+pub use self::pmtu::{PMTU_DEFAULT, PMTU_MAX};
+pub use self::pool::{BufferPool, BufferPoolStats, BUFFER_POOL_CAPACITY};
+pub use self::queue::{OutputQueueCfg, OverflowPolicy};
+pub use self::shaper::ShaperCfg;
+use self::{connection::DataPlaneConnection, features::FeatureWorkerManager, pmtu::PMTU_FEATURE_ID, queue::BulkQueue, services::ServiceWorkerManager, shaper::Shaper};
 
 mod connection;
+mod dscp;
 mod features;
+mod pmtu;
+mod pool;
+mod queue;
+mod replay_window;
 mod services;
+mod shaper;
+
+/// Feature id of the reply which is sent back to the source of a message routed to an unknown service, with UnknownServicePolicy::Reply.
+/// The payload is the service id. It is handled by the data plane itself, and it is never a `Features` value
+const SERVICE_UNAVAILABLE_FEATURE_ID: u8 = 254;
+
+/// Number of ticks between reports of connection stats to the controller
+const CONN_STATS_TICKS: u64 = 10;
+/// Max migration trials per remote addr in one tick, each trial decrypts the packet with every conn on the local addr
+const MIGRATE_TRIALS_PER_TICK: u32 = 4;
+/// Max remote addrs which are tracked for migration trials in one tick, so spoofed source addrs can't grow the cost
+const MIGRATE_SOURCES_PER_TICK: usize = 64;
+
+/// 64-bit FNV-1a hash, which is the same in every build unlike the std hasher
+fn fnv1a_64(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| (hash ^ *b as u64).wrapping_mul(0x0000_0100_0000_01b3))
+}
 
 /// NetPair is a pair between remote addr and local addr.
 /// This is for solving problems with multi-ip-addresses system.
@@ -80,6 +112,10 @@ pub enum Input<UserData, SC, SE, TW> {
 pub enum NetOutput {
     UdpPacket(NetPair, Buffer),
     UdpPackets(Vec<NetPair>, Buffer),
+    /// Different packets for each pair which are produced together, transports can send them in one vectored call
+    UdpBatch(Vec<(NetPair, Buffer)>),
+    /// Packet of a traffic class with a DSCP value, transports set it on the socket for this send or skip it if not supported
+    UdpMarked(NetPair, u8, Buffer),
     #[cfg(feature = "vpn")]
     TunPacket(Buffer),
 }
@@ -91,6 +127,10 @@ pub enum Output<UserData, SC, SE, TC> {
     Control(LogicControl<UserData, SC, SE, TC>),
     #[convert_enum(optout)]
     Worker(u16, CrossWorker<UserData, SE>),
+    /// Queued outputs went over `OutputQueueCfg::high_watermark` (true) or drained to half of it (false),
+    /// drivers can hold back new inputs until the backlog clears
+    #[convert_enum(optout)]
+    Backlog(bool),
     #[convert_enum(optout)]
     OnResourceEmpty,
     #[convert_enum(optout)]
@@ -109,6 +149,29 @@ pub struct DataPlaneCfg<UserData, SC, SE, TC, TW> {
     #[allow(clippy::type_complexity)]
     pub services: Vec<Arc<dyn ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>,
     pub history: Arc<dyn ShadowRouterHistory>,
+    pub unknown_service: UnknownServicePolicy,
+    /// Source of random choices in worker features, seeded in tests for being reproducible
+    pub random: Box<dyn RngCore + Send + Sync>,
+    /// When connections ask the controller for a new key exchange
+    pub rekey: RekeyPolicy,
+    /// Cap of the TTL in outgoing packets, incoming packets above it are dropped.
+    /// `DEFAULT_MSG_TTL` keeps the default TTL of features working
+    pub max_ttl: u8,
+    /// Bound of queued packets from bulk features
+    pub output_queue: OutputQueueCfg,
+    /// How many outputs a feature pops per turn, higher weights also go first.
+    /// Features which are missing here use DEFAULT_CONTROL_WEIGHT for control priority and 1 for bulk
+    pub feature_weights: HashMap<Features, u8>,
+    /// DSCP values of outgoing traffic classes. Marks are only applied by transports which set socket options,
+    /// nodes of the runner `SdnBuilder` always use `DscpMap::disabled()`
+    pub dscp: DscpMap,
+    /// Pacing of bulk features, features which are missing here are sent without shaping
+    pub shapers: HashMap<Features, ShaperCfg>,
+    /// Features which are constructed, must be the same as ControllerPlaneCfg::features
+    pub features: FeaturesConfig,
+    /// Largest payload of incoming packets for each feature, bigger ones are dropped before decoding.
+    /// Features which are missing here use DEFAULT_MAX_PAYLOAD
+    pub max_payloads: HashMap<Features, usize>,
 }
 
 pub struct DataPlane<UserData, SC, SE, TC, TW> {
@@ -121,7 +184,22 @@ pub struct DataPlane<UserData, SC, SE, TC, TW> {
     services: TaskSwitcherBranch<ServiceWorkerManager<UserData, SC, SE, TC, TW>, services::Output<UserData, SC, SE, TC>>,
     conns: HashMap<NetPair, DataPlaneConnection>,
     conns_reverse: HashMap<ConnId, NetPair>,
+    conns_inconsistency: u64,
+    rekey_policy: RekeyPolicy,
+    max_ttl: u8,
+    unknown_service: UnknownServicePolicy,
+    unknown_service_count: u64,
+    undeliverable_count: u64,
+    malformed_count: u64,
+    migrated_count: u64,
+    migrate_trials: HashMap<SocketAddr, u32>,
     queue: DynamicDeque<Output<UserData, SC, SE, TC>, 16>,
+    bulk_queue: BulkQueue<NetOutput>,
+    high_watermark: Option<usize>,
+    backlogged: bool,
+    shapers: HashMap<Features, Shaper>,
+    pool: BufferPool,
+    dscp: DscpMap,
     shutdown: bool,
     switcher: TaskSwitcher,
 }
@@ -139,13 +217,29 @@ where
             feature_ctx: FeatureWorkerContext {
                 node_id,
                 router: ShadowRouter::new(node_id, cfg.history),
+                random: cfg.random,
             },
             service_ctx: ServiceWorkerCtx { node_id },
-            features: TaskSwitcherBranch::new(FeatureWorkerManager::new(), TaskType::Feature),
+            features: TaskSwitcherBranch::new(FeatureWorkerManager::new(&cfg.feature_weights, cfg.features, cfg.max_payloads), TaskType::Feature),
             services: TaskSwitcherBranch::new(ServiceWorkerManager::new(cfg.services), TaskType::Service),
             conns: HashMap::new(),
             conns_reverse: HashMap::new(),
+            conns_inconsistency: 0,
+            rekey_policy: cfg.rekey,
+            max_ttl: cfg.max_ttl,
+            unknown_service: cfg.unknown_service,
+            unknown_service_count: 0,
+            undeliverable_count: 0,
+            malformed_count: 0,
+            migrated_count: 0,
+            migrate_trials: HashMap::new(),
             queue: DynamicDeque::default(),
+            bulk_queue: BulkQueue::new(cfg.output_queue),
+            high_watermark: cfg.output_queue.high_watermark,
+            backlogged: false,
+            shapers: cfg.shapers.into_iter().map(|(feature, shaper)| (feature, Shaper::new(shaper, cfg.output_queue))).collect(),
+            pool: BufferPool::default(),
+            dscp: cfg.dscp,
             shutdown: false,
             switcher: TaskSwitcher::new(2),
         }
@@ -155,14 +249,174 @@ where
         self.feature_ctx.router.derive_action(&rule, source, relay_from)
     }
 
+    /// Number of times `conns` and `conns_reverse` were found out of sync and had to be repaired.
+    pub fn conns_inconsistency(&self) -> u64 {
+        self.conns_inconsistency
+    }
+
+    /// Number of messages dropped because they target an unregistered service
+    pub fn unknown_service_count(&self) -> u64 {
+        self.unknown_service_count
+    }
+
+    /// Number of packets and controls dropped because they target a disabled feature
+    pub fn feature_unavailable_count(&self) -> u64 {
+        self.features.unavailable_count()
+    }
+
+    /// Number of incoming packets dropped because the payload is above the limit of the feature
+    pub fn feature_oversized_count(&self) -> u64 {
+        self.features.oversized_count()
+    }
+
+    /// Number of local messages which had no usable next hop, each of them is reported to the sending feature
+    pub fn undeliverable_count(&self) -> u64 {
+        self.undeliverable_count
+    }
+
+    /// Number of incoming udp packets dropped because they are too short for their kind or have an invalid control payload
+    pub fn malformed_count(&self) -> u64 {
+        self.malformed_count
+    }
+
+    /// Number of connections which moved to a new remote addr after it was authenticated by their session key
+    pub fn migrated_count(&self) -> u64 {
+        self.migrated_count
+    }
+
+    /// Hit and miss counters of the buffers used by secure broadcasts
+    pub fn buffer_pool_stats(&self) -> BufferPoolStats {
+        self.pool.stats()
+    }
+
+    /// Give back a buffer of a sent packet, so the next secure broadcast can reuse it instead of allocating
+    pub fn recycle_buffer(&mut self, buf: Buffer) {
+        self.pool.recycle(buf);
+    }
+
+    /// Install a service after startup, see `ControllerPlane::register_service`
+    pub fn register_service(&mut self, builder: Arc<dyn ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>) {
+        self.services.input(&mut self.switcher).register(builder);
+    }
+
+    /// Remove a service at runtime, later messages to it are handled by the UnknownServicePolicy
+    pub fn unregister_service(&mut self, service: ServiceId) -> bool {
+        self.services.input(&mut self.switcher).unregister(service)
+    }
+
+    /// Number of bulk outputs dropped because the output queue or a shaper queue was full
+    pub fn dropped_outputs(&self) -> u64 {
+        self.bulk_queue.dropped() + self.shapers.values().map(|s| s.dropped()).sum::<u64>()
+    }
+
+    /// Outputs which are ready to pop, shaped outputs which wait for tokens are not counted
+    pub fn queue_len(&self) -> usize {
+        self.queue.len() + self.bulk_queue.len()
+    }
+
+    /// Pops up to `max` outputs at once, for drivers which hand them to the transport together
+    pub fn pop_batch(&mut self, now: u64, max: usize) -> Vec<Output<UserData, SC, SE, TC>> {
+        let mut batch = Vec::with_capacity(max.min(self.queue_len().max(1)));
+        while batch.len() < max {
+            match self.pop_output(now) {
+                Some(out) => batch.push(out),
+                None => break,
+            }
+        }
+        batch
+    }
+
+    /// Backlog event if the queue crossed the high watermark since the last check. It is cleared at half of the
+    /// watermark, so a queue around the watermark does not flip on each output
+    fn backlog_changed(&mut self) -> Option<Output<UserData, SC, SE, TC>> {
+        let watermark = self.high_watermark?;
+        let len = self.queue_len();
+        let backlogged = if self.backlogged {
+            len > watermark / 2
+        } else {
+            len > watermark
+        };
+        if backlogged == self.backlogged {
+            return None;
+        }
+        log::debug!("[DataPlane] worker {} backlog {backlogged} with {len} queued outputs", self.worker_id);
+        self.backlogged = backlogged;
+        Some(Output::Backlog(backlogged))
+    }
+
+    /// True if the output queue or a shaper queue is full with OverflowPolicy::Block, new sends should wait until it drains
+    pub fn is_blocked(&self) -> bool {
+        self.bulk_queue.is_blocked() || self.shapers.values().any(|s| s.is_blocked())
+    }
+
+    /// Bytes of bulk features which waited in a shaper because they were over the configured rate
+    pub fn shaped_bytes(&self) -> u64 {
+        self.shapers.values().map(|s| s.deferred_bytes()).sum()
+    }
+
+    /// Dropped packet counters of a pinned connection, None if the connection is not pinned.
+    pub fn conn_drop_stats(&self, conn: ConnId) -> Option<ConnDropStats> {
+        let pair = self.conns_reverse.get(&conn)?;
+        self.conns.get(pair).filter(|c| c.conn() == conn).map(|c| *c.drop_stats())
+    }
+
+    /// Traffic counters of a pinned connection in this worker, None if the connection is not pinned.
+    pub fn connection_stats(&self, conn: ConnId) -> Option<ConnStats> {
+        let pair = self.conns_reverse.get(&conn)?;
+        self.conns.get(pair).filter(|c| c.conn() == conn).map(|c| *c.stats())
+    }
+
+    /// Path MTU of a pinned connection in this worker, None if the connection is not pinned.
+    pub fn connection_mtu(&self, conn: ConnId) -> Option<usize> {
+        let pair = self.conns_reverse.get(&conn)?;
+        self.conns.get(pair).filter(|c| c.conn() == conn).map(|c| c.mtu())
+    }
+
+    /// Ed25519 public key which the peer of a pinned connection proved, None if it has no identity or is not pinned.
+    pub fn connection_peer_identity(&self, conn: ConnId) -> Option<[u8; 32]> {
+        let pair = self.conns_reverse.get(&conn)?;
+        self.conns.get(pair).filter(|c| c.conn() == conn).and_then(|c| c.peer_identity().copied())
+    }
+
     pub fn on_tick(&mut self, now_ms: u64) {
         log::trace!("[DataPlane] on_tick: {}", now_ms);
         self.features.input(&mut self.switcher).on_tick(&mut self.feature_ctx, now_ms, self.tick_count);
         self.services.input(&mut self.switcher).on_tick(&self.service_ctx, now_ms, self.tick_count);
+        for (pair, conn) in self.conns.iter_mut() {
+            if conn.on_tick(now_ms) {
+                self.queue.push_back(LogicControl::ConnectionRekeyRequest(conn.conn()).into());
+            }
+            if let Some(probe) = conn.pmtu_probe(now_ms, self.feature_ctx.random.next_u32()) {
+                if let Some(out) = Self::build_send_to(now_ms, conn, *pair, probe) {
+                    self.queue.push_back(out.into());
+                }
+            }
+            if let Some(stats) = conn.pop_rekey() {
+                self.queue.push_back(LogicControl::ConnectionRekey(conn.conn(), stats).into());
+            }
+        }
+        for (feature, shaper) in self.shapers.iter_mut() {
+            shaper.refill(now_ms);
+            while let Some(out) = shaper.pop_ready() {
+                if !self.bulk_queue.push_back(out) {
+                    log::debug!("[DataPlane] output queue of bulk feature {feature:?} is full, drop shaped output");
+                    metrics::DROPPED_OUTPUTS.inc();
+                }
+            }
+        }
+        if self.tick_count % CONN_STATS_TICKS == 0 && !self.conns.is_empty() {
+            let stats = self.conns.values().map(|c| (c.conn(), *c.stats())).collect();
+            self.queue.push_back(LogicControl::ConnectionStats(self.worker_id, stats).into());
+        }
+        self.migrate_trials.clear();
         self.tick_count += 1;
     }
 
     pub fn on_event(&mut self, now_ms: u64, event: Input<UserData, SC, SE, TW>) {
+        if self.shutdown && matches!(event, Input::Ext(_)) {
+            log::warn!("[DataPlane] Reject ext input after shutdown");
+            return;
+        }
         match event {
             Input::Ext(ext) => match ext {
                 ExtIn::ConnectTo(_remote) => {
@@ -171,6 +425,12 @@ where
                 ExtIn::DisconnectFrom(_node) => {
                     panic!("DisconnectFrom is not supported")
                 }
+                ExtIn::QueryTopology(_userdata) => {
+                    panic!("QueryTopology is not supported")
+                }
+                ExtIn::ExplainRoute(_userdata, _dest) => {
+                    panic!("ExplainRoute is not supported")
+                }
                 ExtIn::FeaturesControl(userdata, control) => {
                     let feature: Features = control.to_feature();
                     let actor = FeatureControlActor::Worker(self.worker_id, userdata);
@@ -179,6 +439,7 @@ where
                         .on_input(&mut self.feature_ctx, feature, now_ms, FeatureWorkerInput::Control(actor, control));
                 }
                 ExtIn::ServicesControl(service, userdata, control) => {
+                    return_if_none!(self.check_service(service, Some(userdata)));
                     let actor = ServiceControlActor::Worker(self.worker_id, userdata);
                     self.services
                         .input(&mut self.switcher)
@@ -188,13 +449,21 @@ where
             Input::Worker(CrossWorker::Feature(userdata, event)) => self.queue.push_back(Output::Ext(ExtOut::FeaturesEvent(userdata, event))),
             Input::Worker(CrossWorker::Service(service, userdata, event)) => self.queue.push_back(Output::Ext(ExtOut::ServicesEvent(service, userdata, event))),
             Input::Net(NetInput::UdpPacket(pair, buf)) => {
-                if buf.is_empty() {
+                if Self::is_truncated(&buf) {
+                    log::debug!("[DataPlane] Drop truncated packet from {pair} with {} bytes", buf.len());
+                    self.malformed_count += 1;
                     return;
                 }
-                if let Ok(control) = NeighboursControl::try_from(&*buf) {
-                    self.queue.push_back(LogicControl::NetNeighbour(pair, control).into());
-                } else {
-                    self.incoming_route(now_ms, pair, buf);
+                match NeighboursControl::try_from(&*buf) {
+                    Ok(control) => self.queue.push_back(LogicControl::NetNeighbour(pair, control).into()),
+                    Err(NeighboursControlError::NotControl) => self.incoming_route(now_ms, pair, buf),
+                    Err(NeighboursControlError::UnsupportedVersion(version)) => {
+                        log::error!("[DataPlane] Drop neighbours control from {pair} with unsupported version {version}, we are {NEIGHBOURS_CONTROL_VERSION}");
+                    }
+                    Err(NeighboursControlError::InvalidData) => {
+                        log::warn!("[DataPlane] Drop invalid neighbours control from {pair}");
+                        self.malformed_count += 1;
+                    }
                 }
             }
             #[cfg(feature = "vpn")]
@@ -210,6 +479,7 @@ where
                     .on_input(&mut self.feature_ctx, feature, now_ms, FeatureWorkerInput::FromController(is_broadcast, to));
             }
             Input::Event(LogicEvent::Service(service, to)) => {
+                return_if_none!(self.check_service(service, None));
                 self.services
                     .input(&mut self.switcher)
                     .on_input(&self.service_ctx, now_ms, service, ServiceWorkerInput::FromController(to));
@@ -225,27 +495,34 @@ where
             Input::Event(LogicEvent::NetNeighbour(pair, control)) => {
                 let buf: Result<Vec<u8>, ()> = (&control).try_into();
                 if let Ok(buf) = buf {
-                    self.queue.push_back(NetOutput::UdpPacket(pair, buf.into()).into());
+                    self.push_net(now_ms, Some(Features::Neighbours), TrafficClass::NetworkControl, NetOutput::UdpPacket(pair, buf.into()));
                 }
             }
-            Input::Event(LogicEvent::NetDirect(feature, pair, _conn, meta, buf)) => {
+            Input::Event(LogicEvent::NetDirect(feature, pair, conn_id, mut meta, buf)) => {
+                self.clamp_ttl(&mut meta);
                 let header = meta.to_header(feature as u8, RouteRule::Direct, self.feature_ctx.node_id);
-                let conn = return_if_none!(self.conns.get_mut(&pair));
+                let Some(conn) = self.conns.get_mut(&pair) else {
+                    self.on_direct_undeliverable(feature, conn_id);
+                    return;
+                };
                 let msg = TransportMsg::build_raw(header, buf);
                 if let Some(pkt) = Self::build_send_to_from_mut(now_ms, conn, pair, msg.take()) {
-                    self.queue.push_back(pkt.into());
+                    self.push_net(now_ms, Some(feature), meta.class, pkt);
                 }
             }
             Input::Event(LogicEvent::NetRoute(feature, rule, meta, buf)) => self.outgoing_route(now_ms, feature, rule, meta, buf),
-            Input::Event(LogicEvent::Pin(conn, node, pair, secure)) => {
-                self.conns.insert(pair, DataPlaneConnection::new(node, conn, pair, secure));
-                self.conns_reverse.insert(conn, pair);
+            Input::Event(LogicEvent::Pin(conn, node, pair, secure)) => self.pin_conn(now_ms, conn, node, pair, secure),
+            Input::Event(LogicEvent::UnPin(conn, reason)) => self.unpin_conn(conn, reason),
+            Input::Event(LogicEvent::Migrate(conn, old_pair, new_pair)) => self.move_conn(conn, old_pair, new_pair),
+            Input::Event(LogicEvent::Rekey(conn, epoch, secure, activate)) => {
+                let pair = return_if_none!(self.conns_reverse.get(&conn));
+                let conn = return_if_none!(self.conns.get_mut(pair));
+                conn.install_key(now_ms, epoch, secure, activate);
             }
-            Input::Event(LogicEvent::UnPin(conn)) => {
-                if let Some(addr) = self.conns_reverse.remove(&conn) {
-                    log::info!("UnPin: conn: {} <--> addr: {}", conn, addr);
-                    self.conns.remove(&addr);
-                }
+            Input::Event(LogicEvent::RekeyActivate(conn, epoch)) => {
+                let pair = return_if_none!(self.conns_reverse.get(&conn));
+                let conn = return_if_none!(self.conns.get_mut(pair));
+                conn.activate_key(epoch);
             }
         }
     }
@@ -257,40 +534,279 @@ where
         log::info!("[DataPlane] Shutdown");
         self.features.input(&mut self.switcher).on_shutdown(&mut self.feature_ctx, now_ms);
         self.services.input(&mut self.switcher).on_shutdown(&self.service_ctx, now_ms);
+        //deferred packets are not paced anymore, so shutdown does not wait for the tick loop
+        for shaper in self.shapers.values_mut() {
+            while let Some(out) = shaper.pop_front() {
+                self.bulk_queue.push_back(out);
+            }
+        }
         self.shutdown = true;
     }
 
+    /// Return None if the service is not registered, after applying the configured UnknownServicePolicy.
+    /// The sender is only set when the message comes from an external actor on this worker.
+    fn check_service(&mut self, service: ServiceId, sender: Option<UserData>) -> Option<()> {
+        if self.services.has_service(service) {
+            return Some(());
+        }
+        self.unknown_service_count += 1;
+        log::warn!("[DataPlane] message for unknown service {service} => apply policy {:?}", self.unknown_service);
+        if let (UnknownServicePolicy::Reply, Some(userdata)) = (self.unknown_service, sender) {
+            self.queue.push_back(Output::Ext(ExtOut::ServiceUnavailable(service, userdata)));
+        }
+        None
+    }
+
+    /// A remote message routed to a service which is not registered here, and no other node has it.
+    /// With UnknownServicePolicy::Reply the source node is told about it, messages without source can't be replied.
+    fn reply_unknown_service(&mut self, now_ms: u64, source: Option<NodeId>, service: ServiceId) {
+        self.unknown_service_count += 1;
+        log::warn!("[DataPlane] remote message from {source:?} for unknown service {service} => apply policy {:?}", self.unknown_service);
+        let source = return_if_none!(source.filter(|s| *s != self.feature_ctx.node_id));
+        if self.unknown_service != UnknownServicePolicy::Reply {
+            return;
+        }
+        let remote = match self.feature_ctx.router.path_to_node(source) {
+            RouteAction::Next(remote) => remote,
+            _ => return,
+        };
+        let header = TransportMsgHeader::build(SERVICE_UNAVAILABLE_FEATURE_ID, 0, RouteRule::ToNode(source)).set_from_node(Some(self.feature_ctx.node_id));
+        let msg = TransportMsg::build_raw(header, Buffer::from(vec![*service]));
+        let conn = return_if_none!(self.conns.get_mut(&remote));
+        if let Some(out) = Self::build_send_to_from_mut(now_ms, conn, remote, msg.take()) {
+            self.queue.push_back(out.into());
+        }
+    }
+
+    fn pin_conn(&mut self, now_ms: u64, conn: ConnId, node: NodeId, pair: NetPair, secure: SecureContext) {
+        log::info!("Pin: conn: {} <--> addr: {} with {:?}", conn, pair, secure.cipher);
+        if let Some(old_pair) = self.conns_reverse.remove(&conn) {
+            if old_pair != pair {
+                log::warn!("[DataPlane] Pin conn {conn} moved from {old_pair} to {pair} without UnPin => evict old addr");
+                self.conns_inconsistency += 1;
+                self.conns.remove(&old_pair);
+            }
+        }
+        if let Some(old) = self.conns.insert(pair, DataPlaneConnection::new(now_ms, node, conn, pair, secure, self.rekey_policy)) {
+            if old.conn() != conn {
+                log::warn!("[DataPlane] Pin addr {pair} overwrite conn {} with {conn} without UnPin => evict old conn", old.conn());
+                self.conns_inconsistency += 1;
+                self.conns_reverse.remove(&old.conn());
+            }
+        }
+        self.conns_reverse.insert(conn, pair);
+        self.ensure_conns_consistency();
+    }
+
+    fn unpin_conn(&mut self, conn: ConnId, reason: DisconnectReason) {
+        if let Some(addr) = self.conns_reverse.remove(&conn) {
+            log::info!("UnPin: conn: {} <--> addr: {}, reason {:?}", conn, addr, reason);
+            if self.conns.get(&addr).map(|c| c.conn()) == Some(conn) {
+                self.conns.remove(&addr);
+            } else {
+                log::warn!("[DataPlane] UnPin conn {conn} but addr {addr} is owned by other conn => keep it");
+                self.conns_inconsistency += 1;
+            }
+        } else {
+            let before = self.conns.len();
+            self.conns.retain(|_, c| c.conn() != conn);
+            if self.conns.len() != before {
+                log::warn!("[DataPlane] UnPin conn {conn} not in reverse map but has orphaned addr => evicted");
+                self.conns_inconsistency += 1;
+            } else {
+                log::debug!("[DataPlane] UnPin unknown conn {conn}");
+            }
+        }
+        self.ensure_conns_consistency();
+    }
+
+    /// A secure packet from an unknown pair may belong to a connection whose remote addr changed, for example after a NAT rebind.
+    /// The connection is only moved if the packet is authenticated by its session key, so spoofed packets can't take it over.
+    /// Only connections on the same local addr are tried, because the socket of a connection doesn't change.
+    /// Trials are limited per remote addr and tick, so a flood of unknown packets can't burn the worker with decrypts
+    fn migrate_conn(&mut self, now_ms: u64, pair: NetPair, buf: &Buffer) -> Option<()> {
+        if !TransportMsgHeader::is_secure(buf[0]) {
+            return None;
+        }
+        if !self.migrate_trials.contains_key(&pair.remote) && self.migrate_trials.len() >= MIGRATE_SOURCES_PER_TICK {
+            log::debug!("[DataPlane] Too many unknown sources in this tick => skip migration trial from {pair}");
+            return None;
+        }
+        let trials = self.migrate_trials.entry(pair.remote).or_default();
+        if *trials >= MIGRATE_TRIALS_PER_TICK {
+            log::debug!("[DataPlane] Too many migration trials from {pair} in this tick => skip");
+            return None;
+        }
+        *trials += 1;
+        let (old_pair, conn) = self
+            .conns
+            .iter_mut()
+            .filter(|(old_pair, _)| old_pair.local == pair.local)
+            .find_map(|(old_pair, conn)| conn.authenticate(now_ms, buf).then_some((*old_pair, conn.conn())))?;
+        log::info!("[DataPlane] Migrate conn {conn} from {old_pair} to {pair}");
+        self.migrated_count += 1;
+        self.move_conn(conn, old_pair, pair);
+        self.queue.push_back(LogicControl::ConnectionMigrated(conn, old_pair, pair).into());
+        Some(())
+    }
+
+    /// Move a connection to a new pair in place, its keys, counters and routes are kept.
+    /// It is applied again when the controller broadcasts the migration, which also fixes routes synced with the old pair in between
+    fn move_conn(&mut self, conn: ConnId, old_pair: NetPair, new_pair: NetPair) {
+        if self.conns_reverse.get(&conn) == Some(&old_pair) && !self.conns.contains_key(&new_pair) {
+            if let Some(mut dp_conn) = self.conns.remove(&old_pair) {
+                dp_conn.set_pair(new_pair);
+                self.conns.insert(new_pair, dp_conn);
+                self.conns_reverse.insert(conn, new_pair);
+            }
+        }
+        self.feature_ctx.router.apply_delta(ShadowRouterDelta::ReplaceRemote { old: old_pair, new: new_pair });
+        self.ensure_conns_consistency();
+    }
+
+    /// Each addr in `conns` must be pointed back by exactly its own conn in `conns_reverse`.
+    /// Pin and UnPin repair known drifts in place, the full scan is O(n) so it only runs in debug builds.
+    fn ensure_conns_consistency(&self) {
+        debug_assert!(
+            self.conns.len() == self.conns_reverse.len() && self.conns_reverse.iter().all(|(conn, pair)| self.conns.get(pair).map(|c| c.conn()) == Some(*conn)),
+            "[DataPlane] conns and conns_reverse are inconsistent"
+        );
+    }
+
+    /// Find connection by ConnId, only when both maps agree with each other.
+    fn conn_by_id(&mut self, conn: ConnId) -> Option<(NetPair, &mut DataPlaneConnection)> {
+        let pair = *self.conns_reverse.get(&conn)?;
+        let dp_conn = self.conns.get_mut(&pair).filter(|c| c.conn() == conn)?;
+        Some((pair, dp_conn))
+    }
+
+    /// Hash which identifies a flow, so all packets of a flow stick to the same path when multiple equal-cost paths exist.
+    /// It only uses fields which are carried in the header, therefore relay nodes compute the same value as the source.
+    /// The fields are hashed in their wire encoding with FNV-1a, so nodes of other builds or toolchains agree on it.
+    fn flow_hash(from_node: Option<NodeId>, feature: u8, meta: u8, rule: &RouteRule) -> u64 {
+        let header = TransportMsgHeader::build(feature, meta, rule.clone()).set_from_node(from_node);
+        let mut buf = [0u8; 64];
+        let len = header.to_bytes(&mut buf).expect("Should serialize header");
+        fnv1a_64(&buf[..len])
+    }
+
+    /// Check the length of a packet before indexing into it: a neighbours control needs its framing and
+    /// other packets at least a plain header. Secure packets are checked again with the cipher of their connection
+    fn is_truncated(buf: &[u8]) -> bool {
+        match buf.first() {
+            None => true,
+            Some(first) if NeighboursControl::is_control(*first) => buf.len() < NEIGHBOURS_CONTROL_MIN_LEN,
+            Some(_) => buf.len() < TransportMsgHeader::MIN_SIZE,
+        }
+    }
+
     fn incoming_route(&mut self, now_ms: u64, pair: NetPair, mut buf: Buffer) {
+        if !self.conns.contains_key(&pair) {
+            return_if_none!(self.migrate_conn(now_ms, pair, &buf));
+        }
         let conn = return_if_none!(self.conns.get_mut(&pair));
+        conn.count_recv(now_ms, &buf);
         if TransportMsgHeader::is_secure(buf[0]) {
+            if buf.len() < conn.min_secure_len() {
+                log::debug!("[DataPlane] Drop truncated secure packet from {pair} with {} bytes", buf.len());
+                conn.count_drop(DropReason::Decrypt);
+                self.malformed_count += 1;
+                return;
+            }
             return_if_none!(conn.decrypt_if_need(now_ms, &mut buf));
         }
-        let header = return_if_err!(TransportMsgHeader::try_from(&buf as &[u8]));
-        let action = self.feature_ctx.router.derive_action(&header.route, header.from_node, Some(conn.node()));
+        let mut header = match TransportMsgHeader::try_from(&buf as &[u8]) {
+            Ok(header) => header,
+            Err(err) => {
+                if err == TransportMsgHeaderError::InvalidVersion {
+                    log::error!("[DataPlane] Drop packet from {pair} with unsupported header version {}", buf[0] >> 6);
+                }
+                conn.count_drop(DropReason::InvalidHeader);
+                return;
+            }
+        };
+        if header.ttl > self.max_ttl {
+            log::debug!("[DataPlane] Incoming packet from {pair} with ttl {} above max {}", header.ttl, self.max_ttl);
+            conn.count_drop(DropReason::TtlAboveMax);
+            return;
+        }
+        if header.route == RouteRule::Direct && header.feature == PMTU_FEATURE_ID {
+            let (ack, changed) = conn.on_pmtu_msg(now_ms, &buf[header.serialize_size()..]);
+            if changed {
+                self.queue.push_back(LogicControl::ConnectionMtu(conn.conn(), conn.mtu()).into());
+            }
+            if let Some(out) = ack.and_then(|ack| Self::build_send_to(now_ms, conn, pair, ack)) {
+                self.queue.push_back(out.into());
+            }
+            return;
+        }
+        if let Some(hops) = header.hops.as_mut() {
+            if hops.contains(self.feature_ctx.node_id) {
+                log::debug!("[DataPlane] Incoming {:?} from {pair} already visited this node, drop", header.route);
+                conn.count_drop(DropReason::Loop);
+                return;
+            }
+            hops.push(self.feature_ctx.node_id);
+            header.rewrite_hops(&mut buf);
+        }
+        let flow = Self::flow_hash(header.from_node, header.feature, header.meta, &header.route);
+        let conn_node = conn.node();
+        let action = self.feature_ctx.router.derive_action(&header.route, header.from_node, Some(conn_node));
+        let action = self.pick_live_flow(&header.route, action, flow, Some(pair));
+        let conn = return_if_none!(self.conns.get_mut(&pair));
         log::debug!("[DataPlane] Incoming rule: {:?} from: {pair}, node {:?} => action {:?}", header.route, header.from_node, action);
         match action {
-            RouteAction::Reject => {}
+            RouteAction::Reject => {
+                conn.count_drop(DropReason::Rejected);
+                if let RouteRule::ToService(service) = header.route {
+                    self.reply_unknown_service(now_ms, header.from_node, ServiceId(service));
+                }
+            }
             RouteAction::Local => {
-                let feature = return_if_none!(header.feature.try_into().ok());
+                if header.feature == SERVICE_UNAVAILABLE_FEATURE_ID {
+                    let (node, service) = (return_if_none!(header.from_node), *return_if_none!(buf.get(header.serialize_size())));
+                    log::warn!("[DataPlane] service {service} is unavailable in remote node {node}");
+                    self.queue.push_back(Output::Ext(ExtOut::RemoteServiceUnavailable(node, ServiceId(service))));
+                    return;
+                }
+                let feature = match header.feature.try_into() {
+                    Ok(feature) => feature,
+                    Err(_) => {
+                        conn.count_drop(DropReason::UnknownFeature);
+                        return;
+                    }
+                };
                 log::debug!("Incoming message for feature: {feature:?} from: {pair}");
                 self.features
                     .input(&mut self.switcher)
                     .on_network_raw(&mut self.feature_ctx, feature, now_ms, conn.conn(), pair, header, buf);
             }
-            RouteAction::Next(pair) => {
+            RouteAction::Next(next) => {
                 if !TransportMsgHeader::decrease_ttl(&mut buf) {
                     log::debug!("TTL is 0, drop packet");
+                    conn.count_drop(DropReason::TtlExpired);
+                    return;
                 }
-                let target_conn = return_if_none!(self.conns.get_mut(&pair));
-                if let Some(out) = Self::build_send_to_from_mut(now_ms, target_conn, pair, buf) {
-                    self.queue.push_back(out.into());
+                let target_conn = match self.conns.get_mut(&next) {
+                    Some(target_conn) => target_conn,
+                    None => {
+                        if let Some(conn) = self.conns.get_mut(&pair) {
+                            conn.count_drop(DropReason::NoRoute);
+                        }
+                        return;
+                    }
+                };
+                if let Some(out) = Self::build_send_to_from_mut(now_ms, target_conn, next, buf) {
+                    //the class is not carried in the header, so relayed packets are not marked
+                    self.push_net(now_ms, header.feature.try_into().ok(), TrafficClass::BestEffort, out);
                 }
             }
+            RouteAction::NextMulti(_) => unreachable!("multi paths are resolved by pick_flow"),
             RouteAction::Broadcast(local, pairs) => {
-                if !TransportMsgHeader::decrease_ttl(&mut buf) {
-                    log::debug!("TTL is 0, drop packet");
-                    return;
-                }
+                //derive_action already recorded (source, service, seq) in the history, so later copies over other
+                //paths are rejected. This copy must be delivered here even when its ttl doesn't allow relaying further
+                let relay = TransportMsgHeader::decrease_ttl(&mut buf);
+                let feature_id = header.feature;
                 if local {
                     if let Ok(feature) = header.feature.try_into() {
                         log::debug!("Incoming broadcast feature: {feature:?} from: {pair}");
@@ -299,19 +815,103 @@ where
                             .on_network_raw(&mut self.feature_ctx, feature, now_ms, conn.conn(), pair, header, buf.clone());
                     }
                 }
+                if !relay && !pairs.is_empty() {
+                    log::debug!("TTL is 0, drop relaying broadcast");
+                    conn.count_drop(DropReason::TtlExpired);
+                    return;
+                }
                 if !pairs.is_empty() {
                     if let Some(out) = self.build_send_to_multi_from_mut(now_ms, pairs, buf) {
-                        self.queue.push_back(out.into());
+                        self.push_net(now_ms, feature_id.try_into().ok(), TrafficClass::BestEffort, out);
                     }
                 }
             }
         }
     }
 
+    /// Class of raw packets which don't carry an outgoing meta
+    fn feature_class(feature: Features) -> TrafficClass {
+        match feature.priority() {
+            FeaturePriority::Control => TrafficClass::NetworkControl,
+            FeaturePriority::Bulk => TrafficClass::BestEffort,
+        }
+    }
+
+    /// Packets of marked classes are split into one UdpMarked per destination, because the DSCP is set per send
+    fn push_net(&mut self, now_ms: u64, feature: Option<Features>, class: TrafficClass, out: NetOutput) {
+        let dscp = self.dscp.get(class);
+        if dscp == DSCP_DEFAULT {
+            self.push_net_queue(now_ms, feature, out);
+            return;
+        }
+        match out {
+            NetOutput::UdpPacket(pair, buf) => self.push_net_queue(now_ms, feature, NetOutput::UdpMarked(pair, dscp, buf)),
+            NetOutput::UdpPackets(pairs, buf) => {
+                for pair in pairs {
+                    self.push_net_queue(now_ms, feature, NetOutput::UdpMarked(pair, dscp, buf.clone()));
+                }
+            }
+            NetOutput::UdpBatch(batch) => {
+                for (pair, buf) in batch {
+                    self.push_net_queue(now_ms, feature, NetOutput::UdpMarked(pair, dscp, buf));
+                }
+            }
+            out => self.push_net_queue(now_ms, feature, out),
+        }
+    }
+
+    /// Packets of control features and unknown senders go to the main queue, others to the bounded bulk queue
+    /// after the shaper of the feature if it has one
+    fn push_net_queue(&mut self, now_ms: u64, feature: Option<Features>, out: NetOutput) {
+        let feature = match feature {
+            Some(feature) if feature.priority() == FeaturePriority::Bulk => feature,
+            _ => {
+                self.queue.push_back(out.into());
+                return;
+            }
+        };
+        let out = match self.shapers.get_mut(&feature) {
+            Some(shaper) => match shaper.shape(now_ms, out) {
+                (Some(out), _) => out,
+                (None, queued) => {
+                    if !queued {
+                        log::debug!("[DataPlane] shaper queue of bulk feature {feature:?} is full, drop output");
+                        metrics::DROPPED_OUTPUTS.inc();
+                    }
+                    return;
+                }
+            },
+            None => out,
+        };
+        if !self.bulk_queue.push_back(out) {
+            log::debug!("[DataPlane] output queue of bulk feature {feature:?} is full, drop output");
+            metrics::DROPPED_OUTPUTS.inc();
+        }
+    }
+
+    fn clamp_ttl(&self, meta: &mut NetOutgoingMeta) {
+        if *meta.ttl > self.max_ttl {
+            log::debug!("[DataPlane] Clamp outgoing ttl {} to max {}", *meta.ttl, self.max_ttl);
+            meta.ttl = Ttl(self.max_ttl);
+        }
+    }
+
     fn outgoing_route(&mut self, now_ms: u64, feature: Features, rule: RouteRule, mut meta: NetOutgoingMeta, buf: Buffer) {
-        match self.feature_ctx.router.derive_action(&rule, Some(self.feature_ctx.node_id), None) {
+        self.clamp_ttl(&mut meta);
+        if let RouteRule::BroadcastScoped(_, _, radius) = rule {
+            //neighbours get ttl radius - 1, so nodes at the radius deliver the message but don't relay it
+            meta.ttl = Ttl((*meta.ttl).min(radius.saturating_sub(1)));
+        }
+        let from_node = meta.source.then_some(self.feature_ctx.node_id);
+        let flow = meta.flow.unwrap_or_else(|| Self::flow_hash(from_node, feature as u8, meta.meta, &rule));
+        let action = self.feature_ctx.router.derive_action(&rule, Some(self.feature_ctx.node_id), None);
+        match self.pick_live_flow(&rule, action, flow, None) {
             RouteAction::Reject => {
                 log::debug!("[DataPlane] outgoing route rule {:?} is rejected", rule);
+                //broadcast without receivers is not a delivery failure
+                if !matches!(rule, RouteRule::ToServices(..) | RouteRule::BroadcastScoped(..)) {
+                    self.on_undeliverable(feature, rule, buf);
+                }
             }
             RouteAction::Local => {
                 log::debug!("[DataPlane] outgoing route rule {:?} is processed locally", rule);
@@ -322,17 +922,28 @@ where
             }
             RouteAction::Next(remote) => {
                 log::debug!("[DataPlane] outgoing route rule {:?} is go with remote {remote}", rule);
+                let conn = match self.conns.get_mut(&remote) {
+                    Some(conn) => conn,
+                    None => {
+                        log::debug!("[DataPlane] outgoing route rule {:?} next hop {remote} has no connection", rule);
+                        self.on_undeliverable(feature, rule, buf);
+                        return;
+                    }
+                };
                 let header = meta.to_header(feature as u8, rule, self.feature_ctx.node_id);
                 let msg = TransportMsg::build_raw(header, buf);
-                let conn = return_if_none!(self.conns.get_mut(&remote));
                 if let Some(out) = Self::build_send_to_from_mut(now_ms, conn, remote, msg.take()) {
-                    self.queue.push_back(out.into());
+                    self.push_net(now_ms, Some(feature), meta.class, out);
                 }
             }
-            RouteAction::Broadcast(local, remotes) => {
+            RouteAction::NextMulti(_) => unreachable!("multi paths are resolved by pick_flow"),
+            RouteAction::Broadcast(local, mut remotes) => {
                 log::debug!("[DataPlane] outgoing route rule {:?} is go with local {local} and remotes {:?}", rule, remotes);
                 meta.source = true; //Force enable source for broadcast
-
+                                    //radius 0 is local only
+                if matches!(rule, RouteRule::BroadcastScoped(_, _, 0)) {
+                    remotes.clear();
+                }
                 let header = meta.to_header(feature as u8, rule, self.feature_ctx.node_id);
                 if local {
                     let meta = meta.to_incoming(self.feature_ctx.node_id);
@@ -340,14 +951,63 @@ where
                         .input(&mut self.switcher)
                         .on_input(&mut self.feature_ctx, feature, now_ms, FeatureWorkerInput::Local(meta, buf.clone()));
                 }
+                if remotes.is_empty() {
+                    return;
+                }
                 let msg = TransportMsg::build_raw(header, buf);
                 if let Some(out) = self.build_send_to_multi_from_mut(now_ms, remotes, msg.take()) {
-                    self.queue.push_back(out.into());
+                    self.push_net(now_ms, Some(feature), meta.class, out);
+                }
+            }
+        }
+    }
+
+    /// Pick the path of a flow like `RouteAction::pick_flow`, weighted by the bandwidth of each path. Next hops which have
+    /// no connection are left out while another one has, so their flows move to the other paths instead of being dropped.
+    /// The pick is a consistent hash, flows of the paths which are still live keep their path.
+    /// When no next hop is live, the next best path of the table is used, except the pair the packet came from
+    fn pick_live_flow(&self, rule: &RouteRule, action: RouteAction<NetPair>, flow: u64, came_from: Option<NetPair>) -> RouteAction<NetPair> {
+        match action {
+            RouteAction::NextMulti(remotes) => {
+                let live: Vec<(NetPair, u32)> = remotes.iter().filter(|(remote, _)| self.conns.contains_key(remote)).copied().collect();
+                if live.is_empty() {
+                    self.pick_alternate(rule, came_from).unwrap_or_else(|| RouteAction::NextMulti(remotes).pick_flow(flow))
+                } else if live.len() == remotes.len() {
+                    RouteAction::NextMulti(remotes).pick_flow(flow)
+                } else {
+                    log::debug!("[DataPlane] some next hops of {:?} have no connection, pick over alternate paths {:?}", remotes, live);
+                    RouteAction::NextMulti(live).pick_flow(flow)
                 }
             }
+            RouteAction::Next(remote) if !self.conns.contains_key(&remote) => self.pick_alternate(rule, came_from).unwrap_or(RouteAction::Next(remote)),
+            action => action.pick_flow(flow),
         }
     }
 
+    /// First next best path to the dest of the rule which has a connection
+    fn pick_alternate(&self, rule: &RouteRule, came_from: Option<NetPair>) -> Option<RouteAction<NetPair>> {
+        let dest = match rule {
+            RouteRule::ToNode(dest) => *dest,
+            _ => return None,
+        };
+        let alternates = self.feature_ctx.router.alternates_to_node(dest);
+        let remote = alternates.iter().find(|remote| Some(**remote) != came_from && self.conns.contains_key(remote))?;
+        log::debug!("[DataPlane] next hops to {dest} have no connection, reroute over next best path {remote}");
+        Some(RouteAction::Next(*remote))
+    }
+
+    /// Local message which can't be sent, the controller hands it back to the feature which sent it
+    fn on_undeliverable(&mut self, feature: Features, rule: RouteRule, buf: Buffer) {
+        self.undeliverable_count += 1;
+        self.queue.push_back(LogicControl::NetUndeliverable(feature, rule, buf).into());
+    }
+
+    /// Direct send to a connection which is not pinned, the controller hands it back to the feature which sent it
+    fn on_direct_undeliverable(&mut self, feature: Features, conn: ConnId) {
+        log::debug!("[DataPlane] direct send of {feature:?} to not pinned conn {conn}");
+        self.queue.push_back(LogicControl::NetDirectUndeliverable(feature, conn, DirectSendError::NotPinned).into());
+    }
+
     fn pop_features(&mut self, now_ms: u64) {
         let out = return_if_none!(self.features.pop_output(now_ms, &mut self.switcher));
         let (feature, out) = match out {
@@ -372,45 +1032,53 @@ where
                     }
                 }
                 FeatureControlActor::Service(service) => {
+                    return_if_none!(self.check_service(service, None));
                     self.services
                         .input(&mut self.switcher)
                         .on_input(&self.service_ctx, now_ms, service, ServiceWorkerInput::FeatureEvent(event));
                 }
             },
-            FeatureWorkerOutput::SendDirect(conn, meta, buf) => {
-                if let Some(addr) = self.conns_reverse.get(&conn) {
-                    let conn = self.conns.get_mut(addr).expect("Should have");
-                    let header = meta.to_header(feature as u8, RouteRule::Direct, self.feature_ctx.node_id);
+            FeatureWorkerOutput::SendDirect(conn_id, meta, buf) => {
+                let header = meta.to_header(feature as u8, RouteRule::Direct, self.feature_ctx.node_id);
+                if let Some((addr, conn)) = self.conn_by_id(conn_id) {
                     let msg = TransportMsg::build_raw(header, buf);
-                    self.queue.push_back(Self::build_send_to_from_mut(now_ms, conn, *addr, msg.take()).expect("Should have output").into())
+                    let out = Self::build_send_to_from_mut(now_ms, conn, addr, msg.take()).expect("Should have output");
+                    self.push_net(now_ms, Some(feature), meta.class, out);
+                } else {
+                    self.on_direct_undeliverable(feature, conn_id);
                 }
             }
             FeatureWorkerOutput::SendRoute(rule, ttl, buf) => {
                 log::info!("SendRoute: {:?}", rule);
                 self.outgoing_route(now_ms, feature, rule, ttl, buf);
             }
-            FeatureWorkerOutput::RawDirect(conn, buf) => {
-                if let Some(pair) = self.conns_reverse.get(&conn) {
-                    let conn = self.conns.get_mut(pair).expect("Should have conn");
-                    self.queue.push_back(Self::build_send_to(now_ms, conn, *pair, buf).expect("Should ok for convert RawDirect").into());
+            FeatureWorkerOutput::RawDirect(conn_id, buf) => {
+                if let Some((pair, conn)) = self.conn_by_id(conn_id) {
+                    let out = Self::build_send_to(now_ms, conn, pair, buf).expect("Should ok for convert RawDirect");
+                    self.push_net(now_ms, Some(feature), Self::feature_class(feature), out);
+                } else {
+                    self.on_direct_undeliverable(feature, conn_id);
                 }
             }
             FeatureWorkerOutput::RawBroadcast(conns, buf) => {
-                let addrs = conns.iter().filter_map(|conn| self.conns_reverse.get(conn)).cloned().collect();
-                let out = self.build_send_to_multi(now_ms, addrs, buf).map(|e| e.into()).unwrap_or(Output::Continue);
-                self.queue.push_back(out);
+                let addrs = conns.iter().filter_map(|conn| self.conn_by_id(*conn).map(|(pair, _)| pair)).collect();
+                if let Some(out) = self.build_send_to_multi(now_ms, addrs, buf) {
+                    self.push_net(now_ms, Some(feature), Self::feature_class(feature), out);
+                }
             }
             FeatureWorkerOutput::RawDirect2(pair, buf) => {
                 if let Some(conn) = self.conns.get_mut(&pair) {
-                    self.queue.push_back(Self::build_send_to(now_ms, conn, pair, buf).expect("Should ok for convert RawDirect2").into());
+                    let out = Self::build_send_to(now_ms, conn, pair, buf).expect("Should ok for convert RawDirect2");
+                    self.push_net(now_ms, Some(feature), Self::feature_class(feature), out);
                 }
             }
             FeatureWorkerOutput::RawBroadcast2(pairs, buf) => {
-                let out = self.build_send_to_multi(now_ms, pairs, buf).map(|e| e.into()).unwrap_or(Output::Continue);
-                self.queue.push_back(out);
+                if let Some(out) = self.build_send_to_multi(now_ms, pairs, buf) {
+                    self.push_net(now_ms, Some(feature), Self::feature_class(feature), out);
+                }
             }
             #[cfg(feature = "vpn")]
-            FeatureWorkerOutput::TunPkt(pkt) => self.queue.push_back(NetOutput::TunPacket(pkt).into()),
+            FeatureWorkerOutput::TunPkt(pkt) => self.push_net_queue(now_ms, Some(feature), NetOutput::TunPacket(pkt)),
             FeatureWorkerOutput::OnResourceEmpty => {
                 log::info!("[DataPlane] Feature {feature:?} OnResourceEmpty");
             }
@@ -454,43 +1122,67 @@ where
 
     fn build_send_to_from_mut(now: u64, conn: &mut DataPlaneConnection, pair: NetPair, mut buf: Buffer) -> Option<NetOutput> {
         conn.encrypt_if_need(now, &mut buf)?;
+        conn.count_sent(now, &buf);
         Some(NetOutput::UdpPacket(pair, buf))
     }
 
     fn build_send_to_multi_from_mut(&mut self, now: u64, mut pairs: Vec<NetPair>, mut buf: Buffer) -> Option<NetOutput> {
         if TransportMsgHeader::is_secure(buf[0]) {
-            let first = pairs.pop()?;
+            //each remote has its own key, so the encrypted packets are batched instead of sharing one buffer
+            let last = pairs.pop()?;
+            let mut batch = Vec::with_capacity(pairs.len() + 1);
             for pair in pairs {
                 if let Some(conn) = self.conns.get_mut(&pair) {
-                    let mut buf = Buffer::build(&buf, 0, 12 + 16);
+                    let mut buf = self.pool.build(&buf, MAX_SECURE_OVERHEAD);
                     if conn.encrypt_if_need(now, &mut buf).is_some() {
-                        let out = NetOutput::UdpPacket(pair, buf);
-                        self.queue.push_back(Output::Net(out));
+                        conn.count_sent(now, &buf);
+                        batch.push((pair, buf));
                     }
                 }
             }
-            let conn = self.conns.get_mut(&first)?;
-            conn.encrypt_if_need(now, &mut buf)?;
-            Some(NetOutput::UdpPacket(first, buf))
+            if let Some(conn) = self.conns.get_mut(&last) {
+                if conn.encrypt_if_need(now, &mut buf).is_some() {
+                    conn.count_sent(now, &buf);
+                    batch.push((last, buf));
+                }
+            }
+            match batch.len() {
+                0 => None,
+                1 => batch.pop().map(|(pair, buf)| NetOutput::UdpPacket(pair, buf)),
+                _ => Some(NetOutput::UdpBatch(batch)),
+            }
         } else {
+            self.count_sent_multi(now, &pairs, &buf);
             Some(NetOutput::UdpPackets(pairs, buf))
         }
     }
 
     fn build_send_to_multi(&mut self, now: u64, pairs: Vec<NetPair>, buf: Buffer) -> Option<NetOutput> {
         if TransportMsgHeader::is_secure(buf[0]) {
-            let buf = Buffer::build(&buf, 0, 12 + 16);
-            self.build_send_to_multi_from_mut(now, pairs, buf)
+            let copy = self.pool.build(&buf, MAX_SECURE_OVERHEAD);
+            //the source is not sent, so the copies for the other remotes can reuse it
+            self.pool.recycle(buf);
+            self.build_send_to_multi_from_mut(now, pairs, copy)
         } else {
+            self.count_sent_multi(now, &pairs, &buf);
             Some(NetOutput::UdpPackets(pairs, buf))
         }
     }
 
+    fn count_sent_multi(&mut self, now: u64, pairs: &[NetPair], buf: &[u8]) {
+        for pair in pairs {
+            if let Some(conn) = self.conns.get_mut(pair) {
+                conn.count_sent(now, buf);
+            }
+        }
+    }
+
     fn build_send_to(now: u64, conn: &mut DataPlaneConnection, pair: NetPair, buf: Buffer) -> Option<NetOutput> {
         if TransportMsgHeader::is_secure(buf[0]) {
-            let buf = Buffer::build(&buf, 0, 12 + 16);
+            let buf = Buffer::build(&buf, 0, MAX_SECURE_OVERHEAD);
             Self::build_send_to_from_mut(now, conn, pair, buf)
         } else {
+            conn.count_sent(now, &buf);
             Some(NetOutput::UdpPacket(pair, buf))
         }
     }
@@ -507,11 +1199,13 @@ where
     }
 
     fn is_empty(&self) -> bool {
-        self.shutdown && self.queue.is_empty() && self.features.is_empty() && self.services.is_empty()
+        self.shutdown && self.queue.is_empty() && self.bulk_queue.is_empty() && self.shapers.values().all(|s| s.is_empty()) && self.features.is_empty() && self.services.is_empty()
     }
 
     fn pop_output(&mut self, now: u64) -> Option<Output<UserData, SC, SE, TC>> {
+        return_if_some!(self.backlog_changed());
         return_if_some!(self.queue.pop_front());
+        return_if_some!(self.bulk_queue.pop_front().map(Output::Net));
 
         while let Some(current) = self.switcher.current() {
             match current.try_into().ok()? {
@@ -519,9 +1213,1193 @@ where
                 TaskType::Service => self.pop_services(now),
             }
 
+            return_if_some!(self.backlog_changed());
             return_if_some!(self.queue.pop_front());
+            return_if_some!(self.bulk_queue.pop_front().map(Output::Net));
         }
 
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use atm0s_sdn_identity::{ConnId, NodeId};
+    use atm0s_sdn_router::{
+        shadow::{LruShadowHistory, MockShadowRouterHistory, ShadowRouter, ShadowRouterDelta},
+        RouteAction, RouteRule, ServiceBroadcastLevel,
+    };
+
+    use crate::{
+        base::{
+            Buffer, CipherSuite, DecryptionError, DirectSendError, DisconnectReason, HandshakeBuilder, HopList, MockDecryptor, MockEncryptor, NetOutgoingMeta, RekeyReason, RekeyStats, SecureContext,
+            ServiceId, TrafficClass, TransportMsg, TransportMsgHeader, Ttl, UnknownServicePolicy, DEFAULT_MSG_TTL,
+        },
+        features::Features,
+        secure::HandshakeBuilderXDA,
+        ExtIn, ExtOut, LogicControl, LogicEvent,
+    };
+    use rand::{
+        rngs::{mock::StepRng, StdRng},
+        Rng, RngCore, SeedableRng,
+    };
+    use sans_io_runtime::TaskSwitcherChild;
+
+    use super::{
+        queue::BulkQueue, shaper::Shaper, BufferPoolStats, DataPlane, DataPlaneCfg, DataPlaneConnection, DropReason, DscpMap, Input, NetInput, NetOutput, NetPair, Output, OutputQueueCfg,
+        OverflowPolicy, ShaperCfg, CONN_STATS_TICKS, DSCP_CS6, MAX_SECURE_OVERHEAD, MIGRATE_TRIALS_PER_TICK, PMTU_DEFAULT,
+    };
+
+    type TestDataPlane = DataPlane<(), (), (), (), ()>;
+
+    fn create_data_plane_with(unknown_service: UnknownServicePolicy) -> TestDataPlane {
+        create_node_data_plane(1, unknown_service)
+    }
+
+    fn create_node_data_plane(node: NodeId, unknown_service: UnknownServicePolicy) -> TestDataPlane {
+        DataPlane::new(
+            node,
+            DataPlaneCfg {
+                worker_id: 0,
+                services: vec![],
+                history: Arc::new(MockShadowRouterHistory::new()),
+                unknown_service,
+                random: Box::new(StepRng::new(0, 1)),
+                rekey: Default::default(),
+                max_ttl: DEFAULT_MSG_TTL,
+                output_queue: Default::default(),
+                feature_weights: Default::default(),
+                dscp: Default::default(),
+                shapers: Default::default(),
+                features: Default::default(),
+                max_payloads: Default::default(),
+            },
+        )
+    }
+
+    fn create_data_plane() -> TestDataPlane {
+        create_data_plane_with(UnknownServicePolicy::Drop)
+    }
+
+    fn secure() -> SecureContext {
+        SecureContext {
+            cipher: CipherSuite::Aes256Gcm,
+            encryptor: Box::new(MockEncryptor::new()),
+            decryptor: Box::new(MockDecryptor::new()),
+            peer_identity: None,
+        }
+    }
+
+    fn pin(conn: ConnId, node: u32, pair: NetPair) -> Input<(), (), (), ()> {
+        Input::Event(LogicEvent::Pin(conn, node, pair, secure()))
+    }
+
+    fn assert_consistent(plane: &TestDataPlane) {
+        assert_eq!(plane.conns.len(), plane.conns_reverse.len());
+        for (conn, pair) in plane.conns_reverse.iter() {
+            assert_eq!(plane.conns.get(pair).map(|c| c.conn()), Some(*conn));
+        }
+    }
+
+    #[test]
+    fn double_pin_same_addr_should_evict_old_conn() {
+        let mut plane = create_data_plane();
+        let pair = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let conn1 = ConnId::from_out(0, 1);
+        let conn2 = ConnId::from_out(0, 2);
+
+        plane.on_event(0, pin(conn1, 2, pair));
+        plane.on_event(0, pin(conn2, 2, pair));
+        assert_consistent(&plane);
+        assert_eq!(plane.conns_inconsistency(), 1);
+        assert_eq!(plane.conns_reverse.get(&conn1), None);
+        assert_eq!(plane.conns_reverse.get(&conn2), Some(&pair));
+
+        //old conn is already evicted, so UnPin it must not touch the new one
+        plane.on_event(0, Input::Event(LogicEvent::UnPin(conn1, DisconnectReason::Graceful)));
+        plane.on_event(0, Input::Event(LogicEvent::UnPin(conn1, DisconnectReason::Graceful)));
+        assert_consistent(&plane);
+        assert!(plane.conn_by_id(conn2).is_some());
+
+        plane.on_event(0, Input::Event(LogicEvent::UnPin(conn2, DisconnectReason::Graceful)));
+        assert_consistent(&plane);
+        assert!(plane.conns.is_empty());
+        assert_eq!(plane.conns_inconsistency(), 1);
+    }
+
+    #[test]
+    fn double_pin_same_conn_should_evict_old_addr() {
+        let mut plane = create_data_plane();
+        let pair1 = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let pair2 = NetPair::new_str("1.1.1.1:1000", "3.3.3.3:3000").expect("Should parse pair");
+        let conn = ConnId::from_out(0, 1);
+
+        plane.on_event(0, pin(conn, 2, pair1));
+        plane.on_event(0, pin(conn, 2, pair1));
+        assert_eq!(plane.conns_inconsistency(), 0);
+
+        plane.on_event(0, pin(conn, 2, pair2));
+        assert_consistent(&plane);
+        assert_eq!(plane.conns_inconsistency(), 1);
+        assert!(!plane.conns.contains_key(&pair1));
+        assert_eq!(plane.conn_by_id(conn).map(|(pair, _)| pair), Some(pair2));
+    }
+
+    #[test]
+    fn unpin_should_evict_orphaned_addr() {
+        let mut plane = create_data_plane();
+        let pair = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let conn = ConnId::from_out(0, 1);
+
+        //inject an orphan which is not tracked by the reverse map
+        plane.conns.insert(pair, DataPlaneConnection::new(0, 2, conn, pair, secure(), Default::default()));
+        plane.on_event(0, Input::Event(LogicEvent::UnPin(conn, DisconnectReason::Graceful)));
+        assert_consistent(&plane);
+        assert!(plane.conns.is_empty());
+        assert_eq!(plane.conns_inconsistency(), 1);
+    }
+
+    #[test]
+    fn direct_send_to_stale_conn_should_be_reported() {
+        let mut plane = create_data_plane();
+        let pair = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let conn = ConnId::from_out(0, 1);
+        plane.on_event(0, pin(conn, 2, pair));
+        plane.on_event(0, Input::Event(LogicEvent::UnPin(conn, DisconnectReason::Graceful)));
+        while plane.pop_output(0).is_some() {}
+
+        plane.on_event(0, Input::Event(LogicEvent::NetDirect(Features::Data, pair, conn, NetOutgoingMeta::default(), vec![1, 2, 3].into())));
+        assert!(matches!(
+            plane.pop_output(0),
+            Some(Output::Control(LogicControl::NetDirectUndeliverable(Features::Data, c, DirectSendError::NotPinned))) if c == conn
+        ));
+        assert!(plane.pop_output(0).is_none());
+    }
+
+    #[test]
+    fn orphaned_reverse_entry_should_not_resolve() {
+        let mut plane = create_data_plane();
+        let pair = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let conn1 = ConnId::from_out(0, 1);
+        let conn2 = ConnId::from_out(0, 2);
+
+        plane.on_event(0, pin(conn2, 2, pair));
+        //inject a stale reverse entry which point to addr now owned by conn2
+        plane.conns_reverse.insert(conn1, pair);
+        assert!(plane.conn_by_id(conn1).is_none());
+        assert!(plane.conn_by_id(conn2).is_some());
+    }
+
+    #[test]
+    fn pinned_conn_should_expose_peer_identity() {
+        let mut plane = create_data_plane();
+        let pair1 = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let pair2 = NetPair::new_str("1.1.1.1:1000", "3.3.3.3:3000").expect("Should parse pair");
+        let conn1 = ConnId::from_out(0, 1);
+        let conn2 = ConnId::from_out(0, 2);
+
+        let mut secure1 = secure();
+        secure1.peer_identity = Some([7; 32]);
+        plane.on_event(0, Input::Event(LogicEvent::Pin(conn1, 2, pair1, secure1)));
+        plane.on_event(0, pin(conn2, 3, pair2));
+        assert_eq!(plane.connection_peer_identity(conn1), Some([7; 32]));
+        assert_eq!(plane.connection_peer_identity(conn2), None);
+
+        plane.on_event(0, Input::Event(LogicEvent::UnPin(conn1, DisconnectReason::Graceful)));
+        assert_eq!(plane.connection_peer_identity(conn1), None);
+    }
+
+    #[test]
+    fn drop_stats_should_count_per_conn_per_reason() {
+        let mut plane = create_data_plane();
+        let pair1 = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let pair2 = NetPair::new_str("1.1.1.1:1000", "3.3.3.3:3000").expect("Should parse pair");
+        let conn1 = ConnId::from_out(0, 1);
+        let conn2 = ConnId::from_out(0, 2);
+
+        let mut decryptor = MockDecryptor::new();
+        decryptor.expect_decrypt().returning(|_, _| Err(DecryptionError::DecryptError));
+        let secure1 = SecureContext {
+            cipher: CipherSuite::Aes256Gcm,
+            encryptor: Box::new(MockEncryptor::new()),
+            decryptor: Box::new(decryptor),
+            peer_identity: None,
+        };
+        plane.on_event(0, Input::Event(LogicEvent::Pin(conn1, 2, pair1, secure1)));
+        plane.on_event(0, pin(conn2, 3, pair2));
+        plane.feature_ctx.router.apply_delta(ShadowRouterDelta::SetTable { layer: 0, index: 3, next: pair2 });
+
+        let relay_msg = |ttl: u8| TransportMsg::build_raw(TransportMsgHeader::build(0, 0, RouteRule::ToNode(3)).set_ttl(ttl), Buffer::from(vec![1, 2, 3])).take();
+        let secure_msg = TransportMsg::build_raw(TransportMsgHeader::build(0, 0, RouteRule::Direct).set_encrypt(true), Buffer::from(vec![1, 2, 3])).take();
+
+        plane.on_event(0, Input::Net(NetInput::UdpPacket(pair1, relay_msg(0))));
+        plane.on_event(0, Input::Net(NetInput::UdpPacket(pair1, relay_msg(0))));
+        plane.on_event(0, Input::Net(NetInput::UdpPacket(pair1, secure_msg)));
+        assert!(plane.pop_output(0).is_none());
+
+        let stats1 = plane.conn_drop_stats(conn1).expect("Should have stats for conn1");
+        assert_eq!(stats1.get(DropReason::TtlExpired), 2);
+        assert_eq!(stats1.get(DropReason::Decrypt), 1);
+        assert_eq!(stats1.total(), 3);
+        assert_eq!(plane.conn_drop_stats(conn2).map(|s| s.total()), Some(0));
+
+        //relay with remaining ttl is forwarded and not counted
+        plane.on_event(0, Input::Net(NetInput::UdpPacket(pair1, relay_msg(2))));
+        assert!(matches!(plane.pop_output(0), Some(Output::Net(super::NetOutput::UdpPacket(pair, _))) if pair == pair2));
+        assert_eq!(plane.conn_drop_stats(conn1).map(|s| s.total()), Some(3));
+
+        plane.on_event(0, Input::Event(LogicEvent::UnPin(conn1, DisconnectReason::Graceful)));
+        assert_eq!(plane.conn_drop_stats(conn1), None);
+    }
+
+    #[test]
+    fn ttl_above_max_should_be_clamped_or_dropped() {
+        let mut plane = create_data_plane();
+        plane.max_ttl = 8;
+        let pair1 = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let pair2 = NetPair::new_str("1.1.1.1:1000", "3.3.3.3:3000").expect("Should parse pair");
+        let conn1 = ConnId::from_out(0, 1);
+        let conn2 = ConnId::from_out(0, 2);
+        plane.on_event(0, pin(conn1, 2, pair1));
+        plane.on_event(0, pin(conn2, 3, pair2));
+        plane.feature_ctx.router.apply_delta(ShadowRouterDelta::SetTable { layer: 0, index: 3, next: pair2 });
+
+        //outgoing ttl is clamped and the packet still routes to next hop
+        plane.outgoing_route(0, Features::Data, RouteRule::ToNode(3), NetOutgoingMeta::new(false, Ttl(255), 0, false), Buffer::from(vec![1, 2, 3]));
+        match plane.pop_output(0) {
+            Some(Output::Net(super::NetOutput::UdpPacket(pair, buf))) => {
+                assert_eq!(pair, pair2);
+                let header = TransportMsgHeader::try_from(&buf as &[u8]).expect("Should parse header");
+                assert_eq!(header.ttl, 8);
+            }
+            _ => panic!("Should route to next hop"),
+        }
+
+        let relay_msg = |ttl: u8| TransportMsg::build_raw(TransportMsgHeader::build(0, 0, RouteRule::ToNode(3)).set_ttl(ttl), Buffer::from(vec![1, 2, 3])).take();
+        plane.on_event(0, Input::Net(NetInput::UdpPacket(pair1, relay_msg(9))));
+        assert!(plane.pop_output(0).is_none());
+        assert_eq!(plane.conn_drop_stats(conn1).map(|s| s.get(DropReason::TtlAboveMax)), Some(1));
+
+        plane.on_event(0, Input::Net(NetInput::UdpPacket(pair1, relay_msg(8))));
+        assert!(matches!(plane.pop_output(0), Some(Output::Net(super::NetOutput::UdpPacket(pair, _))) if pair == pair2));
+    }
+
+    /// Queue 10 data packets and then one router_sync packet without popping
+    fn flood_output_queue(policy: OverflowPolicy) -> TestDataPlane {
+        let mut plane = create_data_plane();
+        plane.bulk_queue = BulkQueue::new(OutputQueueCfg {
+            capacity: 4,
+            policy,
+            ..Default::default()
+        });
+        let pair = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        plane.on_event(0, pin(ConnId::from_out(0, 1), 2, pair));
+        plane.feature_ctx.router.apply_delta(ShadowRouterDelta::SetTable { layer: 0, index: 2, next: pair });
+
+        let meta = NetOutgoingMeta::new(false, Ttl::default(), 0, false);
+        for i in 0..10 {
+            plane.outgoing_route(0, Features::Data, RouteRule::ToNode(2), meta.clone(), Buffer::from(vec![i]));
+        }
+        plane.outgoing_route(0, Features::RouterSync, RouteRule::ToNode(2), meta, Buffer::from(vec![100]));
+        plane
+    }
+
+    /// Payloads of all outputs in pop order
+    fn drain_payloads(plane: &mut TestDataPlane) -> Vec<u8> {
+        let mut payloads = vec![];
+        while let Some(out) = plane.pop_output(0) {
+            match out {
+                Output::Net(NetOutput::UdpPacket(_, buf)) => payloads.push(buf[buf.len() - 1]),
+                _ => panic!("Should only output packets"),
+            }
+        }
+        payloads
+    }
+
+    #[test]
+    fn full_output_queue_should_apply_policy_to_bulk_only() {
+        let mut plane = flood_output_queue(OverflowPolicy::DropNewest);
+        assert_eq!(plane.dropped_outputs(), 6);
+        assert_eq!(drain_payloads(&mut plane), vec![100, 0, 1, 2, 3]);
+
+        let mut plane = flood_output_queue(OverflowPolicy::DropOldest);
+        assert_eq!(plane.dropped_outputs(), 6);
+        assert_eq!(drain_payloads(&mut plane), vec![100, 6, 7, 8, 9]);
+
+        let mut plane = flood_output_queue(OverflowPolicy::Block);
+        assert_eq!(plane.dropped_outputs(), 0);
+        assert!(plane.is_blocked());
+        assert_eq!(drain_payloads(&mut plane), vec![100, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        assert!(!plane.is_blocked());
+    }
+
+    #[test]
+    fn backlog_should_be_reported_over_watermark() {
+        let mut plane = flood_output_queue(OverflowPolicy::Block);
+        plane.high_watermark = Some(6);
+        assert_eq!(plane.queue_len(), 11);
+        assert!(matches!(plane.pop_output(0), Some(Output::Backlog(true))));
+
+        let batch = plane.pop_batch(0, 4);
+        assert_eq!(batch.len(), 4);
+        assert!(batch.iter().all(|out| matches!(out, Output::Net(NetOutput::UdpPacket(..)))));
+        assert_eq!(plane.queue_len(), 7);
+
+        //cleared at half of the watermark
+        for _ in 0..4 {
+            assert!(matches!(plane.pop_output(0), Some(Output::Net(..))));
+        }
+        assert!(matches!(plane.pop_output(0), Some(Output::Backlog(false))));
+        assert_eq!(drain_payloads(&mut plane), vec![7, 8, 9]);
+    }
+
+    /// Payloads of the data packets of `len` bytes, other outputs are skipped
+    fn sent_payloads(plane: &mut TestDataPlane, len: usize) -> Vec<u8> {
+        std::iter::from_fn(|| plane.pop_output(0))
+            .filter_map(|out| match out {
+                Output::Net(NetOutput::UdpPacket(_, buf)) if buf.len() == len => Some(buf[len - 1]),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn shaper_should_spread_bulk_burst_over_ticks() {
+        let mut plane = create_data_plane();
+        let pair = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        plane.on_event(0, pin(ConnId::from_out(0, 1), 2, pair));
+        plane.feature_ctx.router.apply_delta(ShadowRouterDelta::SetTable { layer: 0, index: 2, next: pair });
+        let meta = NetOutgoingMeta::new(false, Ttl::default(), 0, false);
+        plane.outgoing_route(0, Features::Data, RouteRule::ToNode(2), meta.clone(), Buffer::from(vec![0]));
+        let len = match plane.pop_output(0) {
+            Some(Output::Net(NetOutput::UdpPacket(_, buf))) => buf.len(),
+            _ => panic!("Should output a packet"),
+        };
+
+        //two packets at once, then one packet each 100ms
+        let cfg = ShaperCfg {
+            bytes_per_sec: len as u64 * 10,
+            burst_bytes: len as u64 * 2,
+        };
+        plane.shapers.insert(Features::Data, Shaper::new(cfg, OutputQueueCfg::default()));
+        for i in 0..6 {
+            plane.outgoing_route(0, Features::Data, RouteRule::ToNode(2), meta.clone(), Buffer::from(vec![i]));
+        }
+        plane.outgoing_route(0, Features::RouterSync, RouteRule::ToNode(2), meta, Buffer::from(vec![100]));
+        //control features are not delayed
+        assert_eq!(sent_payloads(&mut plane, len), vec![100, 0, 1]);
+        assert_eq!(plane.shaped_bytes(), 4 * len as u64);
+
+        for (now, expected) in [(50, vec![]), (100, vec![2]), (200, vec![3]), (250, vec![]), (300, vec![4]), (400, vec![5])] {
+            plane.on_tick(now);
+            assert_eq!(sent_payloads(&mut plane, len), expected, "at {now}");
+        }
+        assert_eq!(plane.dropped_outputs(), 0);
+    }
+
+    #[test]
+    fn network_control_class_should_be_marked() {
+        let mut plane = create_data_plane();
+        let pair = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let pair2 = NetPair::new_str("1.1.1.1:1000", "3.3.3.3:3000").expect("Should parse pair");
+        plane.on_event(0, pin(ConnId::from_out(0, 1), 2, pair));
+        plane.feature_ctx.router.apply_delta(ShadowRouterDelta::SetTable { layer: 0, index: 2, next: pair });
+
+        let meta = NetOutgoingMeta::new(false, Ttl::default(), 0, false);
+        plane.outgoing_route(0, Features::Data, RouteRule::ToNode(2), meta.clone(), Buffer::from(vec![1]));
+        assert!(matches!(plane.pop_output(0), Some(Output::Net(NetOutput::UdpPacket(dest, _))) if dest == pair));
+        plane.outgoing_route(0, Features::RouterSync, RouteRule::ToNode(2), meta.with_class(TrafficClass::NetworkControl), Buffer::from(vec![2]));
+        assert!(matches!(plane.pop_output(0), Some(Output::Net(NetOutput::UdpMarked(dest, DSCP_CS6, _))) if dest == pair));
+
+        //one marked packet per destination
+        plane.push_net(
+            0,
+            Some(Features::RouterSync),
+            TrafficClass::NetworkControl,
+            NetOutput::UdpPackets(vec![pair, pair2], Buffer::from(vec![3])),
+        );
+        assert!(matches!(plane.pop_output(0), Some(Output::Net(NetOutput::UdpMarked(dest, DSCP_CS6, _))) if dest == pair));
+        assert!(matches!(plane.pop_output(0), Some(Output::Net(NetOutput::UdpMarked(dest, DSCP_CS6, _))) if dest == pair2));
+
+        plane.dscp = DscpMap::disabled();
+        plane.push_net(0, Some(Features::RouterSync), TrafficClass::NetworkControl, NetOutput::UdpPacket(pair, Buffer::from(vec![4])));
+        assert!(matches!(plane.pop_output(0), Some(Output::Net(NetOutput::UdpPacket(dest, _))) if dest == pair));
+    }
+
+    #[test]
+    fn looped_broadcast_should_be_dropped() {
+        let mut history = MockShadowRouterHistory::new();
+        history.expect_already_received_broadcast().return_const(false);
+        let mut plane: TestDataPlane = DataPlane::new(
+            1,
+            DataPlaneCfg {
+                worker_id: 0,
+                services: vec![],
+                history: Arc::new(history),
+                unknown_service: UnknownServicePolicy::Drop,
+                random: Box::new(StepRng::new(0, 1)),
+                rekey: Default::default(),
+                max_ttl: DEFAULT_MSG_TTL,
+                output_queue: Default::default(),
+                feature_weights: Default::default(),
+                dscp: Default::default(),
+                shapers: Default::default(),
+                features: Default::default(),
+                max_payloads: Default::default(),
+            },
+        );
+        let pair1 = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let pair2 = NetPair::new_str("1.1.1.1:1000", "3.3.3.3:3000").expect("Should parse pair");
+        let conn1 = ConnId::from_out(0, 1);
+        let conn2 = ConnId::from_out(0, 2);
+        plane.on_event(0, pin(conn1, 2, pair1));
+        plane.on_event(0, pin(conn2, 3, pair2));
+        plane.feature_ctx.router.apply_delta(ShadowRouterDelta::SetServiceRemote {
+            service: 1,
+            conn: pair2,
+            next: 3,
+            dest: 3,
+            score: 1,
+        });
+
+        let broadcast_msg = |hops: &[u32]| {
+            let mut list = HopList::new(hops[0]);
+            hops[1..].iter().for_each(|node| list.push(*node));
+            let header = TransportMsgHeader::build(0, 0, RouteRule::ToServices(1, ServiceBroadcastLevel::Global, 0))
+                .set_from_node(Some(hops[0]))
+                .set_hops(Some(list));
+            TransportMsg::build_raw(header, Buffer::from(vec![1, 2, 3])).take()
+        };
+
+        plane.on_event(0, Input::Net(NetInput::UdpPacket(pair1, broadcast_msg(&[3, 1, 2]))));
+        assert!(plane.pop_output(0).is_none());
+        assert_eq!(plane.conn_drop_stats(conn1).map(|s| s.get(DropReason::Loop)), Some(1));
+
+        //not visited yet, relay with this node appended
+        plane.on_event(0, Input::Net(NetInput::UdpPacket(pair1, broadcast_msg(&[4, 2]))));
+        match plane.pop_output(0) {
+            Some(Output::Net(super::NetOutput::UdpPackets(pairs, buf))) => {
+                assert_eq!(pairs, vec![pair2]);
+                let header = TransportMsgHeader::try_from(&buf as &[u8]).expect("Should parse header");
+                assert!(header.hops.is_some_and(|hops| hops.contains(4) && hops.contains(2) && hops.contains(1)));
+            }
+            _ => panic!("Should relay to next hop"),
+        }
+    }
+
+    #[test]
+    fn duplicated_broadcast_should_deliver_once() {
+        let mut plane: TestDataPlane = DataPlane::new(
+            1,
+            DataPlaneCfg {
+                worker_id: 0,
+                services: vec![],
+                history: Arc::new(LruShadowHistory::default()),
+                unknown_service: UnknownServicePolicy::Drop,
+                random: Box::new(StepRng::new(0, 1)),
+                rekey: Default::default(),
+                max_ttl: DEFAULT_MSG_TTL,
+                output_queue: Default::default(),
+                feature_weights: Default::default(),
+                dscp: Default::default(),
+                shapers: Default::default(),
+                features: Default::default(),
+                max_payloads: Default::default(),
+            },
+        );
+        let pair1 = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let pair2 = NetPair::new_str("1.1.1.1:1000", "3.3.3.3:3000").expect("Should parse pair");
+        let conn1 = ConnId::from_out(0, 1);
+        let conn2 = ConnId::from_out(0, 2);
+        plane.on_event(0, pin(conn1, 2, pair1));
+        plane.on_event(0, pin(conn2, 3, pair2));
+        plane.feature_ctx.router.apply_delta(ShadowRouterDelta::SetServiceLocal { service: 1 });
+        plane.feature_ctx.router.apply_delta(ShadowRouterDelta::SetServiceRemote {
+            service: 1,
+            conn: pair2,
+            next: 3,
+            dest: 3,
+            score: 1,
+        });
+
+        let broadcast_msg = |ttl: u8| {
+            let header = TransportMsgHeader::build(Features::Data.into(), 0, RouteRule::ToServices(1, ServiceBroadcastLevel::Global, 10))
+                .set_from_node(Some(4))
+                .set_ttl(ttl);
+            TransportMsg::build_raw(header, Buffer::from(vec![1, 2, 3])).take()
+        };
+
+        //first copy can't be relayed anymore but it is still delivered locally
+        plane.on_event(0, Input::Net(NetInput::UdpPacket(pair1, broadcast_msg(0))));
+        assert!(matches!(plane.pop_output(0), Some(Output::Control(LogicControl::NetRemote(Features::Data, conn, _, _))) if conn == conn1));
+        assert!(plane.pop_output(0).is_none());
+        assert_eq!(plane.conn_drop_stats(conn1).map(|s| s.get(DropReason::TtlExpired)), Some(1));
+
+        //same broadcast over another path is neither delivered nor relayed again
+        plane.on_event(0, Input::Net(NetInput::UdpPacket(pair2, broadcast_msg(5))));
+        assert!(plane.pop_output(0).is_none());
+        assert_eq!(plane.conn_drop_stats(conn2).map(|s| s.get(DropReason::Rejected)), Some(1));
+    }
+
+    #[test]
+    fn connection_stats_should_count_traffic() {
+        let mut plane = create_data_plane();
+        let pair1 = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let pair2 = NetPair::new_str("1.1.1.1:1000", "3.3.3.3:3000").expect("Should parse pair");
+        let conn1 = ConnId::from_out(0, 1);
+        let conn2 = ConnId::from_out(0, 2);
+        plane.on_event(0, pin(conn1, 2, pair1));
+        plane.on_event(0, pin(conn2, 3, pair2));
+        plane.feature_ctx.router.apply_delta(ShadowRouterDelta::SetTable { layer: 0, index: 3, next: pair2 });
+
+        let relay_msg = TransportMsg::build_raw(TransportMsgHeader::build(0, 0, RouteRule::ToNode(3)).set_ttl(2), Buffer::from(vec![1, 2, 3])).take();
+        let secure_msg = TransportMsg::build_raw(TransportMsgHeader::build(0, 0, RouteRule::Direct).set_encrypt(true), Buffer::from(vec![1, 2, 3, 4])).take();
+        let (relay_len, secure_len) = (relay_msg.len() as u64, secure_msg.len() as u64);
+
+        plane.on_event(5, Input::Net(NetInput::UdpPacket(pair1, relay_msg)));
+        assert!(matches!(plane.pop_output(5), Some(Output::Net(super::NetOutput::UdpPacket(pair, _))) if pair == pair2));
+        //secure packet without nonce can't be decrypted, but it is still received
+        plane.on_event(7, Input::Net(NetInput::UdpPacket(pair1, secure_msg)));
+
+        let stats1 = plane.connection_stats(conn1).expect("Should have stats for conn1");
+        assert_eq!((stats1.recv_packets, stats1.recv_plain_bytes, stats1.recv_secure_bytes), (2, relay_len, secure_len));
+        assert_eq!(stats1.sent_packets, 0);
+        assert_eq!(stats1.drops.get(DropReason::Decrypt), 1);
+        assert_eq!(stats1.last_activity_ms, 7);
+        let stats2 = plane.connection_stats(conn2).expect("Should have stats for conn2");
+        assert_eq!((stats2.sent_packets, stats2.sent_bytes(), stats2.sent_secure_bytes), (1, relay_len, 0));
+        assert_eq!(stats2.last_activity_ms, 5);
+
+        //reported on first tick, then every CONN_STATS_TICKS
+        let mut reports = vec![];
+        for tick in 0..(CONN_STATS_TICKS + 1) {
+            plane.on_tick(10 + tick);
+            while let Some(out) = plane.pop_output(10 + tick) {
+                if let Output::Control(LogicControl::ConnectionStats(worker, mut stats)) = out {
+                    stats.sort_by_key(|(conn, _)| conn.session());
+                    reports.push((tick, worker, stats));
+                }
+            }
+        }
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0], (0, 0, vec![(conn1, stats1), (conn2, stats2)]));
+        assert_eq!(reports[1].0, CONN_STATS_TICKS);
+
+        plane.on_event(20, Input::Event(LogicEvent::UnPin(conn1, DisconnectReason::Graceful)));
+        assert_eq!(plane.connection_stats(conn1), None);
+    }
+
+    #[test]
+    fn secure_fan_out_should_be_batched() {
+        let mut plane = create_data_plane();
+        let pairs: Vec<_> = (2..5).map(|i| NetPair::new_str("1.1.1.1:1000", &format!("{i}.{i}.{i}.{i}:1000")).expect("Should parse pair")).collect();
+        for (i, pair) in pairs.iter().enumerate() {
+            let mut encryptor = MockEncryptor::new();
+            encryptor.expect_encrypt().returning(|_, _| Ok(()));
+            encryptor.expect_key_epoch().returning(|| 0);
+            let secure = SecureContext {
+                cipher: CipherSuite::Aes256Gcm,
+                encryptor: Box::new(encryptor),
+                decryptor: Box::new(MockDecryptor::new()),
+                peer_identity: None,
+            };
+            plane.on_event(0, Input::Event(LogicEvent::Pin(ConnId::from_out(0, i as u64), i as u32 + 2, *pair, secure)));
+        }
+        //a pair without connection is skipped
+        let unknown = NetPair::new_str("1.1.1.1:1000", "9.9.9.9:1000").expect("Should parse pair");
+
+        let secure_msg = TransportMsg::build_raw(TransportMsgHeader::build(0, 0, RouteRule::Direct).set_encrypt(true), Buffer::from(vec![1, 2, 3])).take();
+        let mut targets = pairs.clone();
+        targets.insert(1, unknown);
+        match plane.build_send_to_multi(0, targets, secure_msg.clone()) {
+            Some(super::NetOutput::UdpBatch(batch)) => {
+                assert_eq!(batch.iter().map(|(pair, _)| *pair).collect::<Vec<_>>(), pairs);
+                for (_, buf) in batch {
+                    plane.recycle_buffer(buf);
+                }
+            }
+            out => panic!("Should batch secure fan-out, got {out:?}"),
+        }
+        assert!(plane.pop_output(0).is_none());
+        //the source buffer is reused for the first copy
+        assert_eq!(plane.buffer_pool_stats(), BufferPoolStats { hit: 1, miss: 2, overflow: 0 });
+
+        //sent buffers are reused by the next fan-out
+        assert!(matches!(plane.build_send_to_multi(0, pairs.clone(), secure_msg.clone()), Some(super::NetOutput::UdpBatch(batch)) if batch.len() == 3));
+        assert_eq!(plane.buffer_pool_stats(), BufferPoolStats { hit: 4, miss: 2, overflow: 0 });
+
+        //without returned buffers, like the runner whose backend drops sent packets, only the source is reused
+        assert!(matches!(plane.build_send_to_multi(0, pairs.clone(), secure_msg), Some(super::NetOutput::UdpBatch(batch)) if batch.len() == 3));
+        assert_eq!(plane.buffer_pool_stats(), BufferPoolStats { hit: 6, miss: 3, overflow: 0 });
+
+        //plain packets still share one buffer
+        let plain_msg = TransportMsg::build_raw(TransportMsgHeader::build(0, 0, RouteRule::Direct), Buffer::from(vec![1, 2, 3])).take();
+        assert!(matches!(plane.build_send_to_multi(0, pairs.clone(), plain_msg), Some(super::NetOutput::UdpPackets(dests, _)) if dests == pairs));
+    }
+
+    #[test]
+    fn multi_paths_should_balance_by_flow() {
+        let mut plane = create_data_plane();
+        let pair1 = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let pair2 = NetPair::new_str("1.1.1.1:1000", "3.3.3.3:3000").expect("Should parse pair");
+        let pair3 = NetPair::new_str("1.1.1.1:1000", "4.4.4.4:4000").expect("Should parse pair");
+        plane.on_event(0, pin(ConnId::from_out(0, 1), 2, pair1));
+        plane.on_event(0, pin(ConnId::from_out(0, 2), 3, pair2));
+        plane.on_event(0, pin(ConnId::from_out(0, 3), 4, pair3));
+        plane.feature_ctx.router.apply_delta(ShadowRouterDelta::SetTable { layer: 0, index: 5, next: pair2 });
+
+        let relay_to = |plane: &mut TestDataPlane, flow: u8| {
+            let msg = TransportMsg::build_raw(TransportMsgHeader::build(0, flow, RouteRule::ToNode(5)).set_ttl(2), Buffer::from(vec![1, 2, 3])).take();
+            plane.on_event(0, Input::Net(NetInput::UdpPacket(pair1, msg)));
+            match plane.pop_output(0) {
+                Some(Output::Net(super::NetOutput::UdpPacket(pair, _))) => pair,
+                _ => panic!("Should forward packet"),
+            }
+        };
+
+        //single path keeps using the best one
+        assert!((0..16).all(|flow| relay_to(&mut plane, flow) == pair2));
+
+        plane.feature_ctx.router.apply_delta(ShadowRouterDelta::SetTableMulti {
+            layer: 0,
+            index: 5,
+            nexts: vec![(pair2, 1000), (pair3, 1000)],
+        });
+
+        //same flow stick to same path, different flows spread over all paths
+        let picked: Vec<_> = (0..16).map(|flow| relay_to(&mut plane, flow)).collect();
+        assert!(picked.contains(&pair2));
+        assert!(picked.contains(&pair3));
+        for (flow, pair) in picked.iter().enumerate() {
+            assert_eq!(relay_to(&mut plane, flow as u8), *pair);
+        }
+
+        //locally generated messages follow the same flow selection as relayed ones
+        let picked_out: Vec<_> = (0..16)
+            .map(|flow| {
+                plane.outgoing_route(
+                    0,
+                    Features::Data,
+                    RouteRule::ToNode(5),
+                    NetOutgoingMeta::new(false, Default::default(), flow, false),
+                    Buffer::from(vec![1, 2, 3]),
+                );
+                match plane.pop_output(0) {
+                    Some(Output::Net(super::NetOutput::UdpPacket(pair, _))) => pair,
+                    _ => panic!("Should send packet"),
+                }
+            })
+            .collect();
+        assert!(picked_out.contains(&pair2));
+        assert!(picked_out.contains(&pair3));
+    }
+
+    #[test]
+    fn flow_hash_should_be_stable_across_builds() {
+        //known FNV-1a vectors
+        assert_eq!(super::fnv1a_64(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(super::fnv1a_64(b"a"), 0xaf63_dc4c_8601_ec8c);
+        //pinned values, other nodes pick the same path only if these never change
+        assert_eq!(TestDataPlane::flow_hash(None, 0, 0, &RouteRule::ToNode(5)), 0xde6c_10bd_921f_a14b);
+        assert_eq!(TestDataPlane::flow_hash(Some(1), 1, 2, &RouteRule::ToService(3)), 0x8244_fc74_a947_ac88);
+    }
+
+    #[test]
+    fn flow_key_should_pin_path_until_paths_change() {
+        let mut plane = create_data_plane();
+        let pair1 = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let pair2 = NetPair::new_str("1.1.1.1:1000", "3.3.3.3:3000").expect("Should parse pair");
+        let pair3 = NetPair::new_str("1.1.1.1:1000", "4.4.4.4:4000").expect("Should parse pair");
+        plane.on_event(0, pin(ConnId::from_out(0, 1), 2, pair1));
+        plane.on_event(0, pin(ConnId::from_out(0, 2), 3, pair2));
+        plane.on_event(0, pin(ConnId::from_out(0, 3), 4, pair3));
+        plane.feature_ctx.router.apply_delta(ShadowRouterDelta::SetTable { layer: 0, index: 5, next: pair2 });
+        plane.feature_ctx.router.apply_delta(ShadowRouterDelta::SetTableMulti {
+            layer: 0,
+            index: 5,
+            nexts: vec![(pair2, 1000), (pair3, 1000)],
+        });
+
+        let send = |plane: &mut TestDataPlane, key: u64, meta: u8| {
+            plane.outgoing_route(
+                0,
+                Features::Data,
+                RouteRule::ToNode(5),
+                NetOutgoingMeta::new(false, Default::default(), meta, false).with_flow(key),
+                Buffer::from(vec![1, 2, 3]),
+            );
+            match plane.pop_output(0) {
+                Some(Output::Net(super::NetOutput::UdpPacket(pair, _))) => pair,
+                _ => panic!("Should send packet"),
+            }
+        };
+
+        //the key decides the path, not the header fields
+        let picked: Vec<_> = (0..64).map(|key| send(&mut plane, key, 0)).collect();
+        assert!(picked.contains(&pair2));
+        assert!(picked.contains(&pair3));
+        for (key, pair) in picked.iter().enumerate() {
+            assert_eq!(send(&mut plane, key as u64, 0), *pair);
+            assert_eq!(send(&mut plane, key as u64, 7), *pair);
+        }
+
+        //a new path only takes flows, the others keep their path
+        plane.feature_ctx.router.apply_delta(ShadowRouterDelta::SetTableMulti {
+            layer: 0,
+            index: 5,
+            nexts: vec![(pair2, 1000), (pair3, 1000), (pair1, 1000)],
+        });
+        let moved: Vec<_> = (0..64).filter(|key| send(&mut plane, *key, 0) != picked[*key as usize]).collect();
+        assert!(!moved.is_empty());
+        assert!(moved.iter().all(|key| send(&mut plane, *key, 0) == pair1));
+    }
+
+    #[test]
+    fn missing_next_hop_should_reroute_or_report() {
+        let mut plane = create_data_plane();
+        let pair1 = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let pair2 = NetPair::new_str("1.1.1.1:1000", "3.3.3.3:3000").expect("Should parse pair");
+        let pair3 = NetPair::new_str("1.1.1.1:1000", "4.4.4.4:4000").expect("Should parse pair");
+        plane.on_event(0, pin(ConnId::from_out(0, 1), 2, pair1));
+        plane.on_event(0, pin(ConnId::from_out(0, 2), 3, pair2));
+        let mut history = MockShadowRouterHistory::new();
+        history.expect_already_received_broadcast().return_const(false);
+        plane.feature_ctx.router = ShadowRouter::new(1, Arc::new(history));
+        //pair3 is already closed but the router is not updated yet
+        plane.feature_ctx.router.apply_delta(ShadowRouterDelta::SetTable { layer: 0, index: 5, next: pair3 });
+
+        let send = |plane: &mut TestDataPlane, flow: u8| {
+            plane.outgoing_route(
+                0,
+                Features::Data,
+                RouteRule::ToNode(5),
+                NetOutgoingMeta::new(false, Default::default(), flow, false),
+                Buffer::from(vec![1, 2, 3]),
+            );
+            plane.pop_output(0)
+        };
+
+        assert!(matches!(
+            send(&mut plane, 0),
+            Some(Output::Control(LogicControl::NetUndeliverable(Features::Data, RouteRule::ToNode(5), buf))) if buf.to_vec() == vec![1, 2, 3]
+        ));
+        assert_eq!(plane.undeliverable_count(), 1);
+
+        //no route at all is reported too, but not a broadcast without receivers
+        plane.outgoing_route(0, Features::Data, RouteRule::ToNode(6), NetOutgoingMeta::default(), Buffer::from(vec![1]));
+        assert!(matches!(
+            plane.pop_output(0),
+            Some(Output::Control(LogicControl::NetUndeliverable(Features::Data, RouteRule::ToNode(6), _)))
+        ));
+        plane.outgoing_route(
+            0,
+            Features::Data,
+            RouteRule::ToServices(1, ServiceBroadcastLevel::Global, 0),
+            NetOutgoingMeta::default(),
+            Buffer::from(vec![1]),
+        );
+        assert!(plane.pop_output(0).is_none());
+        assert_eq!(plane.undeliverable_count(), 2);
+
+        //the next best live path of the table is used when the best one is closed
+        plane.feature_ctx.router.apply_delta(ShadowRouterDelta::SetTableAlternates {
+            layer: 0,
+            index: 5,
+            nexts: vec![pair3, pair1],
+        });
+        assert!(matches!(send(&mut plane, 0), Some(Output::Net(NetOutput::UdpPacket(pair, _))) if pair == pair1));
+        //but a relayed packet is not sent back where it came from
+        let msg = TransportMsg::build_raw(TransportMsgHeader::build(0, 0, RouteRule::ToNode(5)).set_ttl(2), Buffer::from(vec![1, 2, 3])).take();
+        plane.on_event(0, Input::Net(NetInput::UdpPacket(pair1, msg)));
+        assert!(plane.pop_output(0).is_none());
+        plane.feature_ctx.router.apply_delta(ShadowRouterDelta::SetTableAlternates { layer: 0, index: 5, nexts: vec![] });
+
+        //flows of the closed path move to the alternate path
+        plane.feature_ctx.router.apply_delta(ShadowRouterDelta::SetTableMulti {
+            layer: 0,
+            index: 5,
+            nexts: vec![(pair2, 1000), (pair3, 1000)],
+        });
+        for flow in 0..16 {
+            assert!(matches!(send(&mut plane, flow), Some(Output::Net(NetOutput::UdpPacket(pair, _))) if pair == pair2));
+        }
+
+        let msg = TransportMsg::build_raw(TransportMsgHeader::build(0, 0, RouteRule::ToNode(5)).set_ttl(2), Buffer::from(vec![1, 2, 3])).take();
+        plane.on_event(0, Input::Net(NetInput::UdpPacket(pair1, msg)));
+        assert!(matches!(plane.pop_output(0), Some(Output::Net(NetOutput::UdpPacket(pair, _))) if pair == pair2));
+        assert_eq!(plane.undeliverable_count(), 2);
+    }
+
+    #[test]
+    fn unknown_service_should_drop_and_count() {
+        let mut plane = create_data_plane();
+        plane.on_event(0, Input::Ext(ExtIn::ServicesControl(ServiceId(10), (), ())));
+        plane.on_event(0, Input::Event(LogicEvent::Service(ServiceId(10), ())));
+        assert_eq!(plane.unknown_service_count(), 2);
+        assert!(plane.pop_output(0).is_none());
+    }
+
+    #[test]
+    fn unknown_service_should_reply_unavailable() {
+        let mut plane = create_data_plane_with(UnknownServicePolicy::Reply);
+        plane.on_event(0, Input::Ext(ExtIn::ServicesControl(ServiceId(10), (), ())));
+        assert_eq!(plane.unknown_service_count(), 1);
+        assert!(matches!(plane.pop_output(0), Some(Output::Ext(ExtOut::ServiceUnavailable(ServiceId(10), ())))));
+        assert!(plane.pop_output(0).is_none());
+
+        //internal messages don't have any sender to reply to
+        plane.on_event(0, Input::Event(LogicEvent::Service(ServiceId(10), ())));
+        assert_eq!(plane.unknown_service_count(), 2);
+        assert!(plane.pop_output(0).is_none());
+    }
+
+    #[test]
+    fn unknown_service_should_reply_to_remote_sender() {
+        let mut node1 = create_node_data_plane(1, UnknownServicePolicy::Drop);
+        let mut node2 = create_node_data_plane(2, UnknownServicePolicy::Reply);
+        let pair12 = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let pair21 = NetPair::new_str("2.2.2.2:2000", "1.1.1.1:1000").expect("Should parse pair");
+        node1.on_event(0, pin(ConnId::from_out(0, 1), 2, pair12));
+        node2.on_event(0, pin(ConnId::from_in(0, 1), 1, pair21));
+
+        //node1 still has a route to service 10 over node2, but node2 doesn't have it
+        node1.feature_ctx.router.apply_delta(ShadowRouterDelta::SetServiceRemote {
+            service: 10,
+            conn: pair12,
+            next: 2,
+            dest: 2,
+            score: 1,
+        });
+        node2.feature_ctx.router.apply_delta(ShadowRouterDelta::SetTable { layer: 0, index: 1, next: pair21 });
+
+        node1.outgoing_route(
+            0,
+            Features::Data,
+            RouteRule::ToService(10),
+            NetOutgoingMeta::new(true, Default::default(), 0, false),
+            Buffer::from(vec![1, 2, 3]),
+        );
+        let buf = match node1.pop_output(0) {
+            Some(Output::Net(NetOutput::UdpPacket(pair, buf))) if pair == pair12 => buf,
+            _ => panic!("Should send to node2"),
+        };
+
+        node2.on_event(0, Input::Net(NetInput::UdpPacket(pair21, buf)));
+        assert_eq!(node2.unknown_service_count(), 1);
+        let buf = match node2.pop_output(0) {
+            Some(Output::Net(NetOutput::UdpPacket(pair, buf))) if pair == pair21 => buf,
+            _ => panic!("Should reply to node1"),
+        };
+        assert!(node2.pop_output(0).is_none());
+
+        node1.on_event(0, Input::Net(NetInput::UdpPacket(pair12, buf)));
+        assert!(matches!(node1.pop_output(0), Some(Output::Ext(ExtOut::RemoteServiceUnavailable(2, ServiceId(10))))));
+        assert!(node1.pop_output(0).is_none());
+    }
+
+    #[test]
+    fn rekey_should_report_metadata() {
+        let mut plane = create_data_plane();
+        let pair = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let conn = ConnId::from_out(0, 1);
+
+        let mut encryptor = MockEncryptor::new();
+        encryptor.expect_encrypt().returning(|_, _| Ok(()));
+        let mut encrypted = 0;
+        encryptor.expect_key_epoch().returning(move || {
+            encrypted += 1;
+            if encrypted > 2 {
+                1
+            } else {
+                0
+            }
+        });
+        let secure = SecureContext {
+            cipher: CipherSuite::Aes256Gcm,
+            encryptor: Box::new(encryptor),
+            decryptor: Box::new(MockDecryptor::new()),
+            peer_identity: None,
+        };
+        plane.on_event(0, Input::Event(LogicEvent::Pin(conn, 2, pair, secure)));
+
+        let meta = NetOutgoingMeta::new(false, Default::default(), 0, true);
+        let mut sent_bytes = 0;
+        for now in [0, 100, 200] {
+            plane.on_event(now, Input::Event(LogicEvent::NetDirect(Features::Data, pair, conn, meta.clone(), vec![1, 2, 3].into())));
+            match plane.pop_output(now) {
+                Some(Output::Net(super::NetOutput::UdpPacket(_, buf))) if now < 200 => sent_bytes += buf.len() as u64,
+                Some(Output::Net(super::NetOutput::UdpPacket(_, _))) => {}
+                _ => panic!("Should send packet"),
+            }
+        }
+
+        plane.on_tick(300);
+        let mut rekeys = vec![];
+        while let Some(out) = plane.pop_output(300) {
+            if let Output::Control(LogicControl::ConnectionRekey(conn, stats)) = out {
+                rekeys.push((conn, stats));
+            }
+        }
+        assert_eq!(
+            rekeys,
+            vec![(
+                conn,
+                RekeyStats {
+                    at_ms: 200,
+                    epoch: 1,
+                    rekey_count: 1,
+                    bytes_since_last: sent_bytes,
+                    reason: RekeyReason::Interval,
+                }
+            )]
+        );
+    }
+
+    /// Deliver all packets of `from` to `to` which fit the path, and collect reported mtu changes
+    fn deliver_pmtu(now: u64, from: &mut TestDataPlane, to: &mut TestDataPlane, to_pair: NetPair, path_mtu: usize, changes: &mut Vec<(ConnId, usize)>) -> bool {
+        let mut delivered = false;
+        while let Some(out) = from.pop_output(now) {
+            match out {
+                Output::Net(NetOutput::UdpPacket(_, buf)) if buf.len() <= path_mtu => {
+                    to.on_event(now, Input::Net(NetInput::UdpPacket(to_pair, buf)));
+                    delivered = true;
+                }
+                Output::Control(LogicControl::ConnectionMtu(conn, mtu)) => changes.push((conn, mtu)),
+                _ => {}
+            }
+        }
+        delivered
+    }
+
+    #[test]
+    fn pmtu_probe_should_find_path_mtu() {
+        const PATH_MTU: usize = 1300;
+        let mut plane1 = create_data_plane();
+        let mut plane2 = create_data_plane();
+        let pair1 = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let pair2 = NetPair::new_str("2.2.2.2:2000", "1.1.1.1:1000").expect("Should parse pair");
+        let conn1 = ConnId::from_out(0, 1);
+        let conn2 = ConnId::from_in(0, 1);
+        plane1.on_event(0, pin(conn1, 2, pair1));
+        plane2.on_event(0, pin(conn2, 1, pair2));
+        assert_eq!(plane1.connection_mtu(conn1), Some(PMTU_DEFAULT));
+
+        let mut changes = vec![];
+        for now in (0..30_000).step_by(100) {
+            plane1.on_tick(now);
+            plane2.on_tick(now);
+            while deliver_pmtu(now, &mut plane1, &mut plane2, pair2, PATH_MTU, &mut changes) | deliver_pmtu(now, &mut plane2, &mut plane1, pair1, PATH_MTU, &mut changes) {}
+        }
+
+        //each side probes its own direction and reports once when done
+        changes.sort();
+        assert_eq!(changes.len(), 2, "{changes:?}");
+        for (conn, mtu) in changes {
+            assert!(mtu <= PATH_MTU && mtu > PATH_MTU - 16, "{mtu}");
+            let plane = if conn == conn1 {
+                &plane1
+            } else {
+                &plane2
+            };
+            assert_eq!(plane.connection_mtu(conn), Some(mtu));
+        }
+        assert_eq!(plane1.connection_stats(conn1).expect("Should have stats").drops.total(), 0);
+    }
+
+    fn secure_pair() -> (SecureContext, SecureContext) {
+        let cipher = CipherSuite::ChaCha20Poly1305;
+        let mut requester = HandshakeBuilderXDA.requester();
+        let mut responder = HandshakeBuilderXDA.responder();
+        let (s_encryptor, s_decryptor, res) = responder
+            .process_public_request(&requester.create_public_request().expect("Should create"), &[cipher], cipher)
+            .expect("Should ok");
+        let (c_encryptor, c_decryptor) = requester.process_public_response(&res, &[cipher], cipher).expect("Should ok");
+        let sender = SecureContext {
+            cipher,
+            encryptor: c_encryptor,
+            decryptor: c_decryptor,
+            peer_identity: None,
+        };
+        let receiver = SecureContext {
+            cipher,
+            encryptor: s_encryptor,
+            decryptor: s_decryptor,
+            peer_identity: None,
+        };
+        (sender, receiver)
+    }
+
+    fn is_migrated(plane: &mut TestDataPlane, now: u64, conn: ConnId, old_pair: NetPair, new_pair: NetPair) -> bool {
+        let mut migrated = false;
+        while let Some(out) = plane.pop_output(now) {
+            if let Output::Control(LogicControl::ConnectionMigrated(c, old, new)) = out {
+                migrated |= c == conn && old == old_pair && new == new_pair;
+            }
+        }
+        migrated
+    }
+
+    #[test]
+    fn address_change_should_migrate_authenticated_conn() {
+        let mut plane1 = create_data_plane();
+        let mut plane2 = create_data_plane();
+        let pair1 = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let old_pair = NetPair::new_str("2.2.2.2:2000", "1.1.1.1:1000").expect("Should parse pair");
+        let new_pair = NetPair::new_str("2.2.2.2:2000", "5.5.5.5:5000").expect("Should parse pair");
+        let spoofed_pair = NetPair::new_str("2.2.2.2:2000", "6.6.6.6:6000").expect("Should parse pair");
+        let conn1 = ConnId::from_out(0, 1);
+        let conn2 = ConnId::from_in(0, 1);
+        let (sender, receiver) = secure_pair();
+        plane1.on_event(0, Input::Event(LogicEvent::Pin(conn1, 2, pair1, sender)));
+        plane2.on_event(0, Input::Event(LogicEvent::Pin(conn2, 1, old_pair, receiver)));
+        plane2.feature_ctx.router.apply_delta(ShadowRouterDelta::SetTable { layer: 0, index: 3, next: old_pair });
+
+        let meta = NetOutgoingMeta::new(false, Default::default(), 0, true);
+        let send = |plane: &mut TestDataPlane, now: u64| -> Buffer {
+            plane.on_event(now, Input::Event(LogicEvent::NetDirect(Features::Data, pair1, conn1, meta.clone(), vec![1, 2, 3].into())));
+            match plane.pop_output(now) {
+                Some(Output::Net(NetOutput::UdpPacket(_, buf))) => buf,
+                _ => panic!("Should send packet"),
+            }
+        };
+
+        let before = send(&mut plane1, 100);
+        plane2.on_event(100, Input::Net(NetInput::UdpPacket(old_pair, before.clone())));
+        assert!(!is_migrated(&mut plane2, 100, conn2, old_pair, new_pair));
+
+        //replayed and tampered packets from other addrs are not authenticated
+        let after = send(&mut plane1, 200);
+        let mut tampered = after.to_vec();
+        tampered[2] ^= 1;
+        plane2.on_event(200, Input::Net(NetInput::UdpPacket(spoofed_pair, before)));
+        plane2.on_event(200, Input::Net(NetInput::UdpPacket(spoofed_pair, Buffer::from(tampered))));
+        assert_eq!(plane2.migrated_count(), 0);
+        assert_eq!(plane2.conns_reverse.get(&conn2), Some(&old_pair));
+
+        plane2.on_event(200, Input::Net(NetInput::UdpPacket(new_pair, after)));
+        assert!(is_migrated(&mut plane2, 200, conn2, old_pair, new_pair));
+        assert_eq!(plane2.migrated_count(), 1);
+        assert_eq!(plane2.conns_reverse.get(&conn2), Some(&new_pair));
+        assert_eq!(plane2.route(RouteRule::ToNode(3), None, None), RouteAction::Next(new_pair));
+        let stats = plane2.connection_stats(conn2).expect("Should have stats");
+        assert_eq!(stats.recv_packets, 2);
+        assert_eq!(stats.drops.total(), 0);
+        assert_consistent(&plane2);
+
+        //a delta synced with the old pair before the controller knew is fixed by the broadcast migration
+        plane2.feature_ctx.router.apply_delta(ShadowRouterDelta::SetTable { layer: 0, index: 3, next: old_pair });
+        plane2.on_event(300, Input::Event(LogicEvent::Migrate(conn2, old_pair, new_pair)));
+        assert_eq!(plane2.route(RouteRule::ToNode(3), None, None), RouteAction::Next(new_pair));
+        assert_eq!(plane2.conns_reverse.get(&conn2), Some(&new_pair));
+        assert_consistent(&plane2);
+
+        //other workers only move the conn when the controller broadcasts it
+        let mut worker = create_data_plane();
+        let (_, receiver) = secure_pair();
+        worker.on_event(0, Input::Event(LogicEvent::Pin(conn2, 1, old_pair, receiver)));
+        worker.on_event(300, Input::Event(LogicEvent::Migrate(conn2, old_pair, new_pair)));
+        assert_eq!(worker.conns_reverse.get(&conn2), Some(&new_pair));
+        assert_consistent(&worker);
+    }
+
+    #[test]
+    fn migration_trials_should_be_limited_per_source() {
+        let mut plane1 = create_data_plane();
+        let mut plane2 = create_data_plane();
+        let pair1 = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let old_pair = NetPair::new_str("2.2.2.2:2000", "1.1.1.1:1000").expect("Should parse pair");
+        let new_pair = NetPair::new_str("2.2.2.2:2000", "5.5.5.5:5000").expect("Should parse pair");
+        let conn1 = ConnId::from_out(0, 1);
+        let conn2 = ConnId::from_in(0, 1);
+        let (sender, receiver) = secure_pair();
+        plane1.on_event(0, Input::Event(LogicEvent::Pin(conn1, 2, pair1, sender)));
+        plane2.on_event(0, Input::Event(LogicEvent::Pin(conn2, 1, old_pair, receiver)));
+
+        let meta = NetOutgoingMeta::new(false, Default::default(), 0, true);
+        plane1.on_event(100, Input::Event(LogicEvent::NetDirect(Features::Data, pair1, conn1, meta, vec![1, 2, 3].into())));
+        let valid = match plane1.pop_output(100) {
+            Some(Output::Net(NetOutput::UdpPacket(_, buf))) => buf,
+            _ => panic!("Should send packet"),
+        };
+        let mut tampered = valid.to_vec();
+        tampered[2] ^= 1;
+
+        //the source used all its trials in this tick, so even a valid packet is not tried
+        for _ in 0..MIGRATE_TRIALS_PER_TICK {
+            plane2.on_event(100, Input::Net(NetInput::UdpPacket(new_pair, Buffer::from(tampered.clone()))));
+        }
+        plane2.on_event(100, Input::Net(NetInput::UdpPacket(new_pair, valid.clone())));
+        assert!(!is_migrated(&mut plane2, 100, conn2, old_pair, new_pair));
+        assert_eq!(plane2.migrated_count(), 0);
+
+        plane2.on_tick(1000);
+        plane2.on_event(1000, Input::Net(NetInput::UdpPacket(new_pair, valid)));
+        assert!(is_migrated(&mut plane2, 1000, conn2, old_pair, new_pair));
+        assert_eq!(plane2.migrated_count(), 1);
+        assert_consistent(&plane2);
+    }
+
+    fn failing_secure() -> SecureContext {
+        let mut decryptor = MockDecryptor::new();
+        decryptor.expect_decrypt().returning(|_, _| Err(DecryptionError::DecryptError));
+        SecureContext {
+            cipher: CipherSuite::Aes256Gcm,
+            encryptor: Box::new(MockEncryptor::new()),
+            decryptor: Box::new(decryptor),
+            peer_identity: None,
+        }
+    }
+
+    #[test]
+    fn truncated_packets_should_be_dropped() {
+        let mut plane = create_data_plane();
+        let pair = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let conn = ConnId::from_out(0, 1);
+        plane.on_event(0, Input::Event(LogicEvent::Pin(conn, 2, pair, failing_secure())));
+
+        for buf in [vec![], vec![255], vec![255, 254], vec![0, 64, 0]] {
+            plane.on_event(0, Input::Net(NetInput::UdpPacket(pair, Buffer::from(buf))));
+        }
+        assert_eq!(plane.malformed_count(), 4);
+        //dropped before reaching the connection
+        assert_eq!(plane.connection_stats(conn).map(|s| s.recv_packets), Some(0));
+
+        //secure packet which has a header but no room for the cipher overhead
+        let secure_msg = TransportMsg::build_raw(TransportMsgHeader::build(0, 0, RouteRule::Direct).set_encrypt(true), Buffer::from(vec![1, 2, 3])).take();
+        plane.on_event(0, Input::Net(NetInput::UdpPacket(pair, secure_msg)));
+        assert_eq!(plane.malformed_count(), 5);
+        assert_eq!(plane.conn_drop_stats(conn).map(|s| s.get(DropReason::Decrypt)), Some(1));
+        assert!(plane.pop_output(0).is_none());
+    }
+
+    #[test]
+    fn random_short_packets_should_not_panic() {
+        let mut plane = create_data_plane();
+        let pinned = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let unknown = NetPair::new_str("1.1.1.1:1000", "3.3.3.3:3000").expect("Should parse pair");
+        plane.on_event(0, Input::Event(LogicEvent::Pin(ConnId::from_out(0, 1), 2, pinned, failing_secure())));
+
+        let mut rng = StdRng::seed_from_u64(1311);
+        for i in 0..2000 {
+            let mut buf = vec![0; rng.gen_range(0..=2 * MAX_SECURE_OVERHEAD)];
+            rng.fill_bytes(&mut buf);
+            //pinned connection only gets secure or control packets, so they stop at decrypt instead of being routed
+            let pair = match (buf.first_mut(), i % 3) {
+                (Some(first), 0) => {
+                    *first = 0b0010_0000 | (*first & 0b0000_1111);
+                    pinned
+                }
+                (Some(first), 1) => {
+                    *first = 255;
+                    pinned
+                }
+                _ => unknown,
+            };
+            plane.on_event(0, Input::Net(NetInput::UdpPacket(pair, Buffer::from(buf))));
+            while plane.pop_output(0).is_some() {}
+        }
+        assert!(plane.malformed_count() > 0);
+    }
+}
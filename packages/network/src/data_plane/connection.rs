@@ -1,20 +1,180 @@
 use atm0s_sdn_identity::{ConnId, NodeId};
+use atm0s_sdn_router::RouteRule;
+use atm0s_sdn_utils::metrics;
 
-use crate::base::{Buffer, SecureContext, TransportMsgHeader};
+use crate::base::{Buffer, CipherSuite, Decryptor, Encryptor, RekeyPolicy, RekeyReason, RekeyStats, SecureContext, TransportMsg, TransportMsgHeader};
 
-use super::NetPair;
+use super::{
+    pmtu::{PmtuMsg, PmtuProbe, PMTU_FEATURE_ID},
+    replay_window::{ReplayCheck, ReplayWindow},
+    NetPair,
+};
+
+const DROP_REASONS: usize = 10;
+/// How long the previous key still decrypts after a new key is installed
+const KEY_OVERLAP_MS: u64 = 10000;
+/// Minimum time between rekey requests of a connection, in case the exchange is lost
+const REKEY_RETRY_MS: u64 = 5000;
+
+/// Space after the payload of a secure packet: the cipher overhead then one key epoch byte
+pub const MAX_SECURE_OVERHEAD: usize = CipherSuite::MAX_OVERHEAD + 1;
+
+/// Why a packet was dropped while routing through a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    /// Incoming secure packet can not be decrypted
+    Decrypt = 0,
+    /// Incoming packet header can not be parsed
+    InvalidHeader = 1,
+    /// Router rejected the packet
+    Rejected = 2,
+    /// Packet targets a feature which is not known by this node
+    UnknownFeature = 3,
+    /// Packet need to be relayed but TTL is already 0
+    TtlExpired = 4,
+    /// Router selected a next hop which is not connected anymore
+    NoRoute = 5,
+    /// Outgoing packet can not be encrypted
+    Encrypt = 6,
+    /// Incoming secure packet is a duplicate or too old for the replay window
+    Replay = 7,
+    /// Incoming packet has a TTL above the configured max, which no honest sender produces
+    TtlAboveMax = 8,
+    /// Incoming broadcast already visited this node
+    Loop = 9,
+}
+
+/// Per-connection dropped packet counters, indexed by [`DropReason`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnDropStats {
+    counters: [u64; DROP_REASONS],
+}
+
+impl ConnDropStats {
+    pub fn get(&self, reason: DropReason) -> u64 {
+        self.counters[reason as usize]
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counters.iter().sum()
+    }
+
+    fn inc(&mut self, reason: DropReason) {
+        self.counters[reason as usize] += 1;
+    }
+
+    fn merge(&mut self, other: &ConnDropStats) {
+        for (counter, other) in self.counters.iter_mut().zip(other.counters.iter()) {
+            *counter += other;
+        }
+    }
+}
+
+/// Per-connection traffic counters as seen on the wire, secure bytes include the encryption overhead.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnStats {
+    pub sent_packets: u64,
+    pub sent_secure_bytes: u64,
+    pub sent_plain_bytes: u64,
+    pub recv_packets: u64,
+    pub recv_secure_bytes: u64,
+    pub recv_plain_bytes: u64,
+    pub drops: ConnDropStats,
+    /// Last time a packet was sent or received, or when the connection was pinned
+    pub last_activity_ms: u64,
+}
+
+impl ConnStats {
+    pub fn sent_bytes(&self) -> u64 {
+        self.sent_secure_bytes + self.sent_plain_bytes
+    }
+
+    pub fn recv_bytes(&self) -> u64 {
+        self.recv_secure_bytes + self.recv_plain_bytes
+    }
+
+    /// Add counters of the same connection from other worker
+    pub fn merge(&mut self, other: &ConnStats) {
+        self.sent_packets += other.sent_packets;
+        self.sent_secure_bytes += other.sent_secure_bytes;
+        self.sent_plain_bytes += other.sent_plain_bytes;
+        self.recv_packets += other.recv_packets;
+        self.recv_secure_bytes += other.recv_secure_bytes;
+        self.recv_plain_bytes += other.recv_plain_bytes;
+        self.drops.merge(&other.drops);
+        self.last_activity_ms = self.last_activity_ms.max(other.last_activity_ms);
+    }
+}
+
+/// Decrypting state of one exchanged key, sequences restart with each key so each has its own window
+struct KeySlot {
+    epoch: u8,
+    decryptor: Box<dyn Decryptor>,
+    replay: ReplayWindow,
+}
+
+impl KeySlot {
+    fn new(epoch: u8, decryptor: Box<dyn Decryptor>) -> Self {
+        Self {
+            epoch,
+            decryptor,
+            replay: ReplayWindow::default(),
+        }
+    }
+}
 
 pub struct DataPlaneConnection {
     node: NodeId,
     conn: ConnId,
     #[allow(unused)]
     pair: NetPair,
-    secure: SecureContext,
+    cipher: CipherSuite,
+    /// Verified in the handshake, it stays the same after rekeys
+    peer_identity: Option<[u8; 32]>,
+    policy: RekeyPolicy,
+    encryptor: (u8, Box<dyn Encryptor>),
+    /// Key installed by a rekey as responder, used for encrypting after the requester confirms
+    pending_encryptor: Option<(u8, Box<dyn Encryptor>)>,
+    current: KeySlot,
+    /// The key before the latest exchange and when it stops being accepted
+    previous: Option<(KeySlot, u64)>,
+    key_installed_ms: u64,
+    key_bytes: u64,
+    rekey_requested_ms: Option<u64>,
+    interval_epoch: Option<u64>,
+    rekey_count: u64,
+    bytes_since_rekey: u64,
+    rekey: Option<RekeyStats>,
+    stats: ConnStats,
+    pmtu: PmtuProbe,
 }
 
 impl DataPlaneConnection {
-    pub fn new(node: NodeId, conn: ConnId, pair: NetPair, secure: SecureContext) -> Self {
-        Self { node, conn, pair, secure }
+    pub fn new(now_ms: u64, node: NodeId, conn: ConnId, pair: NetPair, secure: SecureContext, policy: RekeyPolicy) -> Self {
+        Self {
+            node,
+            conn,
+            pair,
+            cipher: secure.cipher,
+            peer_identity: secure.peer_identity,
+            policy,
+            encryptor: (0, secure.encryptor),
+            pending_encryptor: None,
+            current: KeySlot::new(0, secure.decryptor),
+            previous: None,
+            key_installed_ms: now_ms,
+            key_bytes: 0,
+            rekey_requested_ms: None,
+            interval_epoch: None,
+            rekey_count: 0,
+            bytes_since_rekey: 0,
+            rekey: None,
+            stats: ConnStats {
+                last_activity_ms: now_ms,
+                ..Default::default()
+            },
+            pmtu: PmtuProbe::new(now_ms),
+        }
     }
 
     pub fn node(&self) -> NodeId {
@@ -25,7 +185,153 @@ impl DataPlaneConnection {
         self.conn
     }
 
-    /// This will encrypt without first byte, which is used for TransportMsgHeader meta
+    /// Ed25519 public key of the peer, see [`SecureContext::peer_identity`]
+    pub fn peer_identity(&self) -> Option<&[u8; 32]> {
+        self.peer_identity.as_ref()
+    }
+
+    /// The remote addr changed, see [`DataPlaneConnection::authenticate`]
+    pub fn set_pair(&mut self, pair: NetPair) {
+        self.pair = pair;
+    }
+
+    pub fn drop_stats(&self) -> &ConnDropStats {
+        &self.stats.drops
+    }
+
+    pub fn stats(&self) -> &ConnStats {
+        &self.stats
+    }
+
+    /// Largest UDP payload which is known to pass the path, see [`PmtuProbe`]
+    pub fn mtu(&self) -> usize {
+        self.pmtu.mtu()
+    }
+
+    /// Padded probe message which should be sent now, `nonce` is carried in the probe if it is a new one
+    pub fn pmtu_probe(&mut self, now: u64, nonce: u32) -> Option<Buffer> {
+        let (nonce, size) = self.pmtu.on_tick(now, nonce)?;
+        let header = TransportMsgHeader::build(PMTU_FEATURE_ID, 0, RouteRule::Direct);
+        let mut payload = bincode::serialize(&PmtuMsg::Probe { nonce, size: size as u16 }).expect("Should serialize");
+        payload.resize(size.saturating_sub(header.serialize_size()).max(payload.len()), 0);
+        Some(TransportMsg::build_raw(header, payload.into()).take())
+    }
+
+    /// Handle a probe message, returns the ack which should be sent back for a probe and whether the mtu changed for an ack
+    pub fn on_pmtu_msg(&mut self, now: u64, payload: &[u8]) -> (Option<Buffer>, bool) {
+        match bincode::deserialize::<PmtuMsg>(payload) {
+            Ok(PmtuMsg::Probe { nonce, size }) => {
+                let msg = TransportMsg::from_payload_bincode(TransportMsgHeader::build(PMTU_FEATURE_ID, 0, RouteRule::Direct), &PmtuMsg::Ack { nonce, size });
+                (Some(msg.take()), false)
+            }
+            Ok(PmtuMsg::Ack { nonce, size }) => (None, self.pmtu.on_ack(now, nonce, size as usize)),
+            Err(_) => {
+                self.count_drop(DropReason::InvalidHeader);
+                (None, false)
+            }
+        }
+    }
+
+    pub fn count_drop(&mut self, reason: DropReason) {
+        log::debug!("[DataPlaneConnection] conn {} drop packet by {reason:?}", self.conn);
+        if reason == DropReason::Decrypt {
+            metrics::DECRYPT_FAILURES.inc();
+        }
+        self.stats.drops.inc(reason);
+    }
+
+    /// Count a packet which is ready to send, after encryption
+    pub fn count_sent(&mut self, now: u64, pkt: &[u8]) {
+        self.stats.sent_packets += 1;
+        metrics::BYTES_OUT.add(pkt.len() as u64);
+        if TransportMsgHeader::is_secure(pkt[0]) {
+            self.stats.sent_secure_bytes += pkt.len() as u64;
+        } else {
+            self.stats.sent_plain_bytes += pkt.len() as u64;
+        }
+        self.stats.last_activity_ms = now;
+    }
+
+    /// Count a packet as received from the socket, before decryption
+    pub fn count_recv(&mut self, now: u64, pkt: &[u8]) {
+        self.stats.recv_packets += 1;
+        metrics::BYTES_IN.add(pkt.len() as u64);
+        if TransportMsgHeader::is_secure(pkt[0]) {
+            self.stats.recv_secure_bytes += pkt.len() as u64;
+        } else {
+            self.stats.recv_plain_bytes += pkt.len() as u64;
+        }
+        self.stats.last_activity_ms = now;
+    }
+
+    /// Retire the previous key after the overlap and check the rekey policy.
+    /// Return true if a new key exchange should be requested
+    pub fn on_tick(&mut self, now: u64) -> bool {
+        if matches!(self.previous, Some((_, retire_at)) if now >= retire_at) {
+            if let Some((slot, _)) = self.previous.take() {
+                log::debug!("[DataPlaneConnection] conn {} retire key epoch {}", self.conn, slot.epoch);
+            }
+        }
+        let by_bytes = matches!(self.policy.after_bytes, Some(limit) if self.key_bytes >= limit);
+        let by_time = matches!(self.policy.after_ms, Some(limit) if now >= self.key_installed_ms + limit);
+        if !by_bytes && !by_time || matches!(self.rekey_requested_ms, Some(at) if now < at + REKEY_RETRY_MS) {
+            return false;
+        }
+        log::info!(
+            "[DataPlaneConnection] conn {} request rekey after {} bytes, {} ms",
+            self.conn,
+            self.key_bytes,
+            now - self.key_installed_ms
+        );
+        self.rekey_requested_ms = Some(now);
+        true
+    }
+
+    /// Install key `epoch` from a key exchange. The decryptor is used right away, the previous key is kept
+    /// for packets still in flight. The encryptor is only used immediately with `activate`,
+    /// otherwise after [`Self::activate_key`] or the first packet received with the new key.
+    pub fn install_key(&mut self, now: u64, epoch: u8, secure: SecureContext, activate: bool) {
+        let old = std::mem::replace(&mut self.current, KeySlot::new(epoch, secure.decryptor));
+        if old.epoch != epoch {
+            self.previous = Some((old, now + KEY_OVERLAP_MS));
+        } else {
+            //an unconfirmed exchange of the same epoch was restarted, its key was never used for encrypting by remote
+            log::warn!("[DataPlaneConnection] conn {} replace unconfirmed key epoch {epoch}", self.conn);
+        }
+        if activate {
+            self.encryptor = (epoch, secure.encryptor);
+            self.pending_encryptor = None;
+        } else {
+            self.pending_encryptor = Some((epoch, secure.encryptor));
+        }
+        self.rekey_count += 1;
+        log::info!("[DataPlaneConnection] conn {} installed key epoch {epoch} after {} bytes", self.conn, self.key_bytes);
+        self.rekey = Some(RekeyStats {
+            at_ms: now,
+            epoch: epoch as u64,
+            rekey_count: self.rekey_count,
+            bytes_since_last: self.bytes_since_rekey,
+            reason: RekeyReason::Exchange,
+        });
+        self.bytes_since_rekey = 0;
+        self.key_bytes = 0;
+        self.key_installed_ms = now;
+        self.rekey_requested_ms = None;
+        self.interval_epoch = None;
+    }
+
+    /// Switch the encryptor to the pending key `epoch`
+    pub fn activate_key(&mut self, epoch: u8) {
+        if matches!(self.pending_encryptor, Some((pending, _)) if pending == epoch) {
+            if let Some(encryptor) = self.pending_encryptor.take() {
+                log::info!("[DataPlaneConnection] conn {} encrypt with key epoch {epoch}", self.conn);
+                self.encryptor = encryptor;
+            }
+        }
+    }
+
+    /// This will encrypt without first byte, which is used for TransportMsgHeader meta.
+    /// The epoch byte of the key is appended after the encrypted part
     pub fn encrypt_if_need(&mut self, now: u64, buf: &mut Buffer) -> Option<()> {
         if buf.len() < 1 {
             return None;
@@ -33,13 +339,49 @@ impl DataPlaneConnection {
         if !TransportMsgHeader::is_secure(buf[0]) {
             return Some(());
         }
-        buf.ensure_back(12 + 16); //TODO remove magic numbers
+        buf.ensure_back(self.cipher.overhead() + 1);
         buf.move_front_right(1);
-        self.secure.encryptor.encrypt(now, buf).ok()?;
+        if self.encryptor.1.encrypt(now, buf).is_err() {
+            self.count_drop(DropReason::Encrypt);
+            return None;
+        }
         buf.move_front_left(1);
+        buf.push_back(&[self.encryptor.0]);
+        self.track_rekey(now, buf.len());
         Some(())
     }
 
+    /// Take the latest rekey which is not reported yet
+    pub fn pop_rekey(&mut self) -> Option<RekeyStats> {
+        self.rekey.take()
+    }
+
+    /// Any change of encryptor key epoch since the previous encrypt is recorded as a rekey.
+    fn track_rekey(&mut self, now: u64, bytes: usize) {
+        let epoch = self.encryptor.1.key_epoch();
+        if matches!(self.interval_epoch, Some(last) if last != epoch) {
+            self.rekey_count += 1;
+            log::info!("[DataPlaneConnection] conn {} rekey to epoch {epoch} after {} bytes", self.conn, self.bytes_since_rekey);
+            self.rekey = Some(RekeyStats {
+                at_ms: now,
+                epoch,
+                rekey_count: self.rekey_count,
+                bytes_since_last: self.bytes_since_rekey,
+                reason: RekeyReason::Interval,
+            });
+            self.bytes_since_rekey = 0;
+        }
+        self.interval_epoch = Some(epoch);
+        self.bytes_since_rekey += bytes as u64;
+        self.key_bytes += bytes as u64;
+    }
+
+    /// Secure packets shorter than this can't be decrypted: the plain first byte, the encrypted rest of a header,
+    /// the cipher overhead and the key epoch
+    pub fn min_secure_len(&self) -> usize {
+        TransportMsgHeader::MIN_SIZE + self.cipher.overhead() + 1
+    }
+
     /// This will encrypt without first byte, which is used for TransportMsgHeader meta
     pub fn decrypt_if_need(&mut self, now: u64, buf: &mut Buffer) -> Option<()> {
         if buf.len() < 1 {
@@ -48,9 +390,237 @@ impl DataPlaneConnection {
         if !TransportMsgHeader::is_secure(buf[0]) {
             return Some(());
         }
+        match self.decrypt(now, buf, false) {
+            Ok(()) => Some(()),
+            Err(reason) => {
+                self.count_drop(reason);
+                None
+            }
+        }
+    }
+
+    /// Check if a secure packet from an unknown addr belongs to this connection, by decrypting a copy of it.
+    /// Only the newest packet of the current key is accepted, so old captured packets can't move the connection.
+    /// Nothing is counted or marked, the packet is decrypted again when it is routed from the new addr
+    pub fn authenticate(&mut self, now: u64, buf: &Buffer) -> bool {
+        if buf.len() < self.min_secure_len() || !TransportMsgHeader::is_secure(buf[0]) {
+            return false;
+        }
+        let mut probe = buf.clone();
+        self.decrypt(now, &mut probe, true).is_ok()
+    }
+
+    fn decrypt(&mut self, now: u64, buf: &mut Buffer, probe: bool) -> Result<(), DropReason> {
+        let epoch = match buf.len() {
+            2.. => buf[buf.len() - 1],
+            _ => return Err(DropReason::Decrypt),
+        };
+        let seq = self.cipher.packet_seq(&buf[1..buf.len() - 1]).ok_or(DropReason::Decrypt)?;
+        if probe && (epoch != self.current.epoch || !self.current.replay.is_newest(seq)) {
+            return Err(DropReason::Replay);
+        }
+        let check = match self.slot_mut(epoch) {
+            Some(slot) => slot.replay.check(seq),
+            None => {
+                log::debug!("[DataPlaneConnection] conn {} drop packet with unknown key epoch {epoch}", self.conn);
+                return Err(DropReason::Decrypt);
+            }
+        };
+        if check != ReplayCheck::Accept {
+            log::debug!("[DataPlaneConnection] conn {} reject packet seq {seq} by {check:?}", self.conn);
+            return Err(DropReason::Replay);
+        }
+        buf.pop_back(1);
         buf.move_front_right(1);
-        self.secure.decryptor.decrypt(now, buf).ok()?;
+        let slot = self.slot_mut(epoch).ok_or(DropReason::Decrypt)?;
+        slot.decryptor.decrypt(now, buf).map_err(|_| DropReason::Decrypt)?;
+        if probe {
+            return Ok(());
+        }
+        //only authenticated packets move the window, so forged sequences can't shift it
+        slot.replay.mark(seq);
         buf.move_front_left(1);
-        Some(())
+        if epoch == self.current.epoch {
+            //remote already encrypts with the new key, so it is ready to decrypt with it too
+            self.activate_key(epoch);
+        }
+        Ok(())
+    }
+
+    fn slot_mut(&mut self, epoch: u8) -> Option<&mut KeySlot> {
+        if self.current.epoch == epoch {
+            return Some(&mut self.current);
+        }
+        match &mut self.previous {
+            Some((slot, _)) if slot.epoch == epoch => Some(slot),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use atm0s_sdn_identity::ConnId;
+    use atm0s_sdn_router::RouteRule;
+
+    use crate::{
+        base::{Buffer, CipherSuite, HandshakeBuilder, RekeyPolicy, RekeyReason, SecureContext, TransportMsg, TransportMsgHeader},
+        data_plane::{replay_window::REPLAY_WINDOW_SIZE, NetPair},
+        secure::HandshakeBuilderXDA,
+    };
+
+    use super::{DataPlaneConnection, DropReason, KEY_OVERLAP_MS, MAX_SECURE_OVERHEAD, REKEY_RETRY_MS};
+
+    fn connection_pair() -> (DataPlaneConnection, DataPlaneConnection) {
+        let (sender, receiver) = secure_pair();
+        let pair = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        (
+            DataPlaneConnection::new(0, 2, ConnId::from_out(0, 1), pair, sender, Default::default()),
+            DataPlaneConnection::new(0, 1, ConnId::from_in(0, 1), pair, receiver, Default::default()),
+        )
+    }
+
+    fn secure_pair() -> (SecureContext, SecureContext) {
+        let cipher = CipherSuite::ChaCha20Poly1305;
+        let mut requester = HandshakeBuilderXDA.requester();
+        let mut responder = HandshakeBuilderXDA.responder();
+        let (s_encryptor, s_decryptor, res) = responder
+            .process_public_request(&requester.create_public_request().expect("Should create"), &[cipher], cipher)
+            .expect("Should ok");
+        let (c_encryptor, c_decryptor) = requester.process_public_response(&res, &[cipher], cipher).expect("Should ok");
+        let sender = SecureContext {
+            cipher,
+            encryptor: c_encryptor,
+            decryptor: c_decryptor,
+            peer_identity: None,
+        };
+        let receiver = SecureContext {
+            cipher,
+            encryptor: s_encryptor,
+            decryptor: s_decryptor,
+            peer_identity: None,
+        };
+        (sender, receiver)
+    }
+
+    fn secure_packet(sender: &mut DataPlaneConnection, now: u64) -> Buffer {
+        let msg = TransportMsg::build_raw(TransportMsgHeader::build(0, 0, RouteRule::Direct).set_encrypt(true), Buffer::from(vec![1, 2, 3])).take();
+        let mut buf = Buffer::build(&msg, 0, MAX_SECURE_OVERHEAD);
+        sender.encrypt_if_need(now, &mut buf).expect("Should encrypt");
+        buf
+    }
+
+    #[test]
+    fn replayed_packet_should_be_rejected() {
+        let (mut sender, mut receiver) = connection_pair();
+        let packet = secure_packet(&mut sender, 100);
+
+        let mut first = packet.clone();
+        assert_eq!(receiver.decrypt_if_need(100, &mut first), Some(()));
+        let mut replay = packet.clone();
+        assert_eq!(receiver.decrypt_if_need(100, &mut replay), None);
+        assert_eq!(receiver.drop_stats().get(DropReason::Replay), 1);
+
+        //other packets in the same ms are still accepted
+        let mut next = secure_packet(&mut sender, 100);
+        assert_eq!(receiver.decrypt_if_need(100, &mut next), Some(()));
+    }
+
+    #[test]
+    fn packet_out_of_window_should_be_rejected() {
+        let (mut sender, mut receiver) = connection_pair();
+        let mut reordered = secure_packet(&mut sender, 100);
+        for _ in 0..REPLAY_WINDOW_SIZE {
+            secure_packet(&mut sender, 100);
+        }
+
+        let mut newer = secure_packet(&mut sender, 101);
+        assert_eq!(receiver.decrypt_if_need(101, &mut newer), Some(()));
+        assert_eq!(receiver.decrypt_if_need(101, &mut reordered), None);
+        assert_eq!(receiver.drop_stats().get(DropReason::Replay), 1);
+        assert_eq!(receiver.drop_stats().get(DropReason::Decrypt), 0);
+    }
+
+    #[test]
+    fn reordered_packet_inside_window_should_be_accepted() {
+        let (mut sender, mut receiver) = connection_pair();
+        let mut reordered = secure_packet(&mut sender, 100);
+        let mut newer = secure_packet(&mut sender, 101);
+        assert_eq!(receiver.decrypt_if_need(101, &mut newer), Some(()));
+        assert_eq!(receiver.decrypt_if_need(101, &mut reordered), Some(()));
+        assert_eq!(receiver.drop_stats().total(), 0);
+    }
+
+    #[test]
+    fn authenticate_should_only_accept_newest_authentic_packet() {
+        let (mut sender, mut receiver) = connection_pair();
+        let (mut other, _) = connection_pair();
+        let reordered = secure_packet(&mut sender, 100);
+        let mut newest = secure_packet(&mut sender, 100);
+
+        assert!(!receiver.authenticate(100, &secure_packet(&mut other, 100)));
+        assert!(receiver.authenticate(100, &newest));
+        //authenticate doesn't mark the window, the packet is still accepted after migration
+        assert_eq!(receiver.decrypt_if_need(100, &mut newest), Some(()));
+        assert!(!receiver.authenticate(100, &reordered));
+        assert_eq!(receiver.drop_stats().total(), 0);
+    }
+
+    #[test]
+    fn both_keys_should_decrypt_during_overlap() {
+        let (mut sender, mut receiver) = connection_pair();
+        let mut old = secure_packet(&mut sender, 100);
+        let mut old_late = secure_packet(&mut sender, 100);
+        assert_eq!(old[old.len() - 1], 0);
+
+        //requester switches immediately, responder waits for the confirm
+        let (new_sender, new_receiver) = secure_pair();
+        sender.install_key(200, 1, new_sender, true);
+        receiver.install_key(200, 1, new_receiver, false);
+        assert_eq!(secure_packet(&mut receiver, 200)[old.len() - 1], 0);
+
+        let mut new = secure_packet(&mut sender, 200);
+        assert_eq!(new[new.len() - 1], 1);
+        assert_eq!(receiver.decrypt_if_need(200, &mut new), Some(()));
+        assert_eq!(receiver.decrypt_if_need(200, &mut old), Some(()));
+        assert_eq!(&new[..], &old[..]);
+        //a packet with the new key activates the pending encryptor too
+        let mut reply = secure_packet(&mut receiver, 200);
+        assert_eq!(reply[reply.len() - 1], 1);
+        assert_eq!(sender.decrypt_if_need(200, &mut reply), Some(()));
+
+        let mut unknown = secure_packet(&mut sender, 200);
+        let last = unknown.len() - 1;
+        unknown[last] = 5;
+        assert_eq!(receiver.decrypt_if_need(200, &mut unknown), None);
+
+        //old key is retired after the overlap
+        assert!(!receiver.on_tick(200 + KEY_OVERLAP_MS));
+        assert_eq!(receiver.decrypt_if_need(200, &mut old_late), None);
+        assert_eq!(receiver.drop_stats().get(DropReason::Decrypt), 2);
+        assert_eq!(receiver.pop_rekey().map(|stats| (stats.epoch, stats.reason)), Some((1, RekeyReason::Exchange)));
+    }
+
+    #[test]
+    fn rekey_policy_should_request_with_retry() {
+        let (sender, _) = secure_pair();
+        let pair = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let policy = RekeyPolicy {
+            after_bytes: Some(10),
+            after_ms: Some(60000),
+        };
+        let mut conn = DataPlaneConnection::new(0, 2, ConnId::from_out(0, 1), pair, sender, policy);
+        assert!(!conn.on_tick(100));
+
+        secure_packet(&mut conn, 100);
+        assert!(conn.on_tick(100));
+        assert!(!conn.on_tick(200));
+        assert!(conn.on_tick(100 + REKEY_RETRY_MS));
+
+        //new key resets the counters, only the age limit is left
+        let (new_sender, _) = secure_pair();
+        conn.install_key(10000, 1, new_sender, true);
+        assert!(!conn.on_tick(10000));
+        assert!(conn.on_tick(70000));
     }
 }
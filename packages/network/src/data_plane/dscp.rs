@@ -0,0 +1,72 @@
+//! DSCP marking of outgoing packets.
+//!
+//! Each `TrafficClass` maps to a DSCP value. Packets with a non zero value are emitted as `NetOutput::UdpMarked`
+//! and the transport sets the value on the socket before sending them, so managed networks can prioritize them.
+
+use crate::base::TrafficClass;
+
+/// Class Selector 6, recommended for network control traffic by RFC 4594
+pub const DSCP_CS6: u8 = 48;
+/// Default forwarding, the packets are sent without a mark
+pub const DSCP_DEFAULT: u8 = 0;
+/// DSCP is a 6 bits field
+pub const DSCP_MAX: u8 = 63;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DscpMap {
+    pub network_control: u8,
+    pub best_effort: u8,
+}
+
+impl Default for DscpMap {
+    fn default() -> Self {
+        Self {
+            network_control: DSCP_CS6,
+            best_effort: DSCP_DEFAULT,
+        }
+    }
+}
+
+impl DscpMap {
+    /// All classes are sent without a mark
+    pub fn disabled() -> Self {
+        Self {
+            network_control: DSCP_DEFAULT,
+            best_effort: DSCP_DEFAULT,
+        }
+    }
+
+    /// DSCP value of the class, values above DSCP_MAX are clamped
+    pub fn get(&self, class: TrafficClass) -> u8 {
+        let dscp = match class {
+            TrafficClass::BestEffort => self.best_effort,
+            TrafficClass::NetworkControl => self.network_control,
+        };
+        dscp.min(DSCP_MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::base::TrafficClass;
+
+    use super::{DscpMap, DSCP_CS6, DSCP_MAX};
+
+    #[test]
+    fn default_marks_only_network_control() {
+        let map = DscpMap::default();
+        assert_eq!(map.get(TrafficClass::NetworkControl), DSCP_CS6);
+        assert_eq!(map.get(TrafficClass::BestEffort), 0);
+        assert_eq!(DscpMap::disabled().get(TrafficClass::NetworkControl), 0);
+    }
+
+    #[test]
+    fn clamp_to_six_bits() {
+        let map = DscpMap {
+            network_control: 255,
+            best_effort: 10,
+        };
+        assert_eq!(map.get(TrafficClass::NetworkControl), DSCP_MAX);
+        assert_eq!(map.get(TrafficClass::BestEffort), 10);
+    }
+}
@@ -1,6 +1,8 @@
+use std::collections::HashMap;
 use std::fmt::Debug;
 
 use atm0s_sdn_identity::ConnId;
+use atm0s_sdn_utils::metrics;
 use sans_io_runtime::{TaskSwitcher, TaskSwitcherBranch, TaskSwitcherChild};
 
 use crate::base::{Buffer, FeatureWorker, FeatureWorkerContext, FeatureWorkerInput, FeatureWorkerOutput, TransportMsgHeader};
@@ -8,6 +10,9 @@ use crate::features::*;
 
 use super::NetPair;
 
+/// Largest incoming payload of features which have no limit in DataPlaneCfg::max_payloads, bigger than any udp packet
+pub const DEFAULT_MAX_PAYLOAD: usize = 64 * 1024;
+
 pub type FeaturesWorkerInput<UserData> = FeatureWorkerInput<UserData, FeaturesControl, FeaturesToWorker<UserData>>;
 pub type FeaturesWorkerOutput<UserData> = FeatureWorkerOutput<UserData, FeaturesControl, FeaturesEvent, FeaturesToController>;
 
@@ -26,92 +31,289 @@ pub struct FeatureWorkerManager<UserData> {
     neighbours: TaskSwitcherBranch<neighbours::NeighboursFeatureWorker<UserData>, neighbours::WorkerOutput<UserData>>,
     data: TaskSwitcherBranch<data::DataFeatureWorker<UserData>, data::WorkerOutput<UserData>>,
     router_sync: TaskSwitcherBranch<router_sync::RouterSyncFeatureWorker<UserData>, router_sync::WorkerOutput<UserData>>,
-    vpn: TaskSwitcherBranch<vpn::VpnFeatureWorker<UserData>, vpn::WorkerOutput<UserData>>,
-    dht_kv: TaskSwitcherBranch<dht_kv::DhtKvFeatureWorker<UserData>, dht_kv::WorkerOutput<UserData>>,
-    pubsub: TaskSwitcherBranch<pubsub::PubSubFeatureWorker<UserData>, pubsub::WorkerOutput<UserData>>,
-    alias: TaskSwitcherBranch<alias::AliasFeatureWorker<UserData>, alias::WorkerOutput<UserData>>,
-    socket: TaskSwitcherBranch<socket::SocketFeatureWorker<UserData>, socket::WorkerOutput<UserData>>,
+    vpn: Option<TaskSwitcherBranch<vpn::VpnFeatureWorker<UserData>, vpn::WorkerOutput<UserData>>>,
+    dht_kv: Option<TaskSwitcherBranch<dht_kv::DhtKvFeatureWorker<UserData>, dht_kv::WorkerOutput<UserData>>>,
+    pubsub: Option<TaskSwitcherBranch<pubsub::PubSubFeatureWorker<UserData>, pubsub::WorkerOutput<UserData>>>,
+    alias: Option<TaskSwitcherBranch<alias::AliasFeatureWorker<UserData>, alias::WorkerOutput<UserData>>>,
+    socket: Option<TaskSwitcherBranch<socket::SocketFeatureWorker<UserData>, socket::WorkerOutput<UserData>>>,
+    rpc: Option<TaskSwitcherBranch<rpc::RpcFeatureWorker<UserData>, rpc::WorkerOutput<UserData>>>,
+    hole_punch: Option<TaskSwitcherBranch<hole_punch::HolePunchFeatureWorker<UserData>, hole_punch::WorkerOutput<UserData>>>,
     switcher: TaskSwitcher,
+    scheduler: FeatureScheduler,
+    features: FeaturesConfig,
+    max_payloads: HashMap<Features, usize>,
+    unavailable_count: u64,
+    oversized_count: u64,
     shutdown: bool,
 }
 
 impl<UserData: Eq + Debug + Copy> FeatureWorkerManager<UserData> {
-    pub fn new() -> Self {
+    /// Only the features enabled in `features` are constructed, inputs of the others are dropped.
+    /// Network payloads above `max_payloads`, or DEFAULT_MAX_PAYLOAD, are dropped before the feature decodes them
+    pub fn new(weights: &HashMap<Features, u8>, features: FeaturesConfig, max_payloads: HashMap<Features, usize>) -> Self {
+        let scheduler = FeatureScheduler::new(weights);
         Self {
-            neighbours: TaskSwitcherBranch::default(Features::Neighbours as usize),
-            data: TaskSwitcherBranch::default(Features::Data as usize),
-            router_sync: TaskSwitcherBranch::default(Features::RouterSync as usize),
-            vpn: TaskSwitcherBranch::default(Features::Vpn as usize),
-            dht_kv: TaskSwitcherBranch::default(Features::DhtKv as usize),
-            pubsub: TaskSwitcherBranch::default(Features::PubSub as usize),
-            alias: TaskSwitcherBranch::default(Features::Alias as usize),
-            socket: TaskSwitcherBranch::default(Features::Socket as usize),
-            switcher: TaskSwitcher::new(8),
+            neighbours: TaskSwitcherBranch::default(scheduler.slot(Features::Neighbours)),
+            data: TaskSwitcherBranch::default(scheduler.slot(Features::Data)),
+            router_sync: TaskSwitcherBranch::default(scheduler.slot(Features::RouterSync)),
+            vpn: features.is_enabled(Features::Vpn).then(|| TaskSwitcherBranch::default(scheduler.slot(Features::Vpn))),
+            dht_kv: features.is_enabled(Features::DhtKv).then(|| TaskSwitcherBranch::default(scheduler.slot(Features::DhtKv))),
+            pubsub: features.is_enabled(Features::PubSub).then(|| TaskSwitcherBranch::default(scheduler.slot(Features::PubSub))),
+            alias: features.is_enabled(Features::Alias).then(|| TaskSwitcherBranch::default(scheduler.slot(Features::Alias))),
+            socket: features.is_enabled(Features::Socket).then(|| TaskSwitcherBranch::default(scheduler.slot(Features::Socket))),
+            rpc: features.is_enabled(Features::Rpc).then(|| TaskSwitcherBranch::default(scheduler.slot(Features::Rpc))),
+            hole_punch: features.is_enabled(Features::HolePunch).then(|| TaskSwitcherBranch::default(scheduler.slot(Features::HolePunch))),
+            switcher: TaskSwitcher::new(FEATURES_COUNT),
+            scheduler,
+            features,
+            max_payloads,
+            unavailable_count: 0,
+            oversized_count: 0,
             shutdown: false,
         }
     }
 
+    /// Number of packets and controls dropped because they target a disabled feature
+    pub fn unavailable_count(&self) -> u64 {
+        self.unavailable_count
+    }
+
+    /// Number of network packets dropped because the payload is above the limit of the feature
+    pub fn oversized_count(&self) -> u64 {
+        self.oversized_count
+    }
+
+    fn is_available(&mut self, feature: Features) -> bool {
+        if self.features.is_enabled(feature) {
+            return true;
+        }
+        log::debug!("[FeatureWorkerManager] drop input for disabled feature {:?}", feature);
+        self.unavailable_count += 1;
+        false
+    }
+
     pub fn on_tick(&mut self, ctx: &mut FeatureWorkerContext, now_ms: u64, tick_count: u64) {
         self.neighbours.input(&mut self.switcher).on_tick(ctx, now_ms, tick_count);
         self.data.input(&mut self.switcher).on_tick(ctx, now_ms, tick_count);
         self.router_sync.input(&mut self.switcher).on_tick(ctx, now_ms, tick_count);
-        self.vpn.input(&mut self.switcher).on_tick(ctx, now_ms, tick_count);
-        self.dht_kv.input(&mut self.switcher).on_tick(ctx, now_ms, tick_count);
-        self.pubsub.input(&mut self.switcher).on_tick(ctx, now_ms, tick_count);
-        self.alias.input(&mut self.switcher).on_tick(ctx, now_ms, tick_count);
-        self.socket.input(&mut self.switcher).on_tick(ctx, now_ms, tick_count);
+        if let Some(vpn) = &mut self.vpn {
+            vpn.input(&mut self.switcher).on_tick(ctx, now_ms, tick_count);
+        }
+        if let Some(dht_kv) = &mut self.dht_kv {
+            dht_kv.input(&mut self.switcher).on_tick(ctx, now_ms, tick_count);
+        }
+        if let Some(pubsub) = &mut self.pubsub {
+            pubsub.input(&mut self.switcher).on_tick(ctx, now_ms, tick_count);
+        }
+        if let Some(alias) = &mut self.alias {
+            alias.input(&mut self.switcher).on_tick(ctx, now_ms, tick_count);
+        }
+        if let Some(socket) = &mut self.socket {
+            socket.input(&mut self.switcher).on_tick(ctx, now_ms, tick_count);
+        }
+        if let Some(rpc) = &mut self.rpc {
+            rpc.input(&mut self.switcher).on_tick(ctx, now_ms, tick_count);
+        }
+        if let Some(hole_punch) = &mut self.hole_punch {
+            hole_punch.input(&mut self.switcher).on_tick(ctx, now_ms, tick_count);
+        }
     }
 
     #[allow(clippy::too_many_arguments)]
     pub fn on_network_raw(&mut self, ctx: &mut FeatureWorkerContext, feature: Features, now_ms: u64, conn: ConnId, pair: NetPair, header: TransportMsgHeader, buf: Buffer) {
+        if !self.is_available(feature) {
+            return;
+        }
+        let max_payload = self.max_payloads.get(&feature).copied().unwrap_or(DEFAULT_MAX_PAYLOAD);
+        let payload_len = buf.len().saturating_sub(header.serialize_size());
+        if payload_len > max_payload {
+            log::warn!("[FeatureWorkerManager] drop {payload_len} bytes payload for {:?} from {pair}, limit is {max_payload}", feature);
+            self.oversized_count += 1;
+            metrics::OVERSIZED_PAYLOADS.inc();
+            return;
+        }
         match feature {
             Features::Neighbours => self.neighbours.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, buf),
             Features::Data => self.data.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, buf),
             Features::RouterSync => self.router_sync.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, buf),
-            Features::Vpn => self.vpn.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, buf),
-            Features::DhtKv => self.dht_kv.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, buf),
-            Features::PubSub => self.pubsub.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, buf),
-            Features::Alias => self.alias.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, buf),
-            Features::Socket => self.socket.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, buf),
+            Features::Vpn => {
+                if let Some(vpn) = &mut self.vpn {
+                    vpn.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, buf);
+                }
+            }
+            Features::DhtKv => {
+                if let Some(dht_kv) = &mut self.dht_kv {
+                    dht_kv.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, buf);
+                }
+            }
+            Features::PubSub => {
+                if let Some(pubsub) = &mut self.pubsub {
+                    pubsub.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, buf);
+                }
+            }
+            Features::Alias => {
+                if let Some(alias) = &mut self.alias {
+                    alias.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, buf);
+                }
+            }
+            Features::Socket => {
+                if let Some(socket) = &mut self.socket {
+                    socket.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, buf);
+                }
+            }
+            Features::Rpc => {
+                if let Some(rpc) = &mut self.rpc {
+                    rpc.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, buf);
+                }
+            }
+            Features::HolePunch => {
+                if let Some(hole_punch) = &mut self.hole_punch {
+                    hole_punch.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, buf);
+                }
+            }
         }
     }
 
     pub fn on_input(&mut self, ctx: &mut FeatureWorkerContext, feature: Features, now_ms: u64, input: FeaturesWorkerInput<UserData>) {
+        let target = match &input {
+            FeatureWorkerInput::Control(_, control) => control.to_feature(),
+            FeatureWorkerInput::FromController(_, to) => to.to_feature(),
+            #[cfg(feature = "vpn")]
+            FeatureWorkerInput::TunPkt(_) => Features::Vpn,
+            _ => feature,
+        };
+        if !self.is_available(target) {
+            return;
+        }
         match input {
             FeatureWorkerInput::Control(actor, control) => match control {
                 FeaturesControl::Neighbours(control) => self.neighbours.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Control(actor, control)),
                 FeaturesControl::Data(control) => self.data.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Control(actor, control)),
                 FeaturesControl::RouterSync(control) => self.router_sync.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Control(actor, control)),
-                FeaturesControl::Vpn(control) => self.vpn.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Control(actor, control)),
-                FeaturesControl::DhtKv(control) => self.dht_kv.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Control(actor, control)),
-                FeaturesControl::PubSub(control) => self.pubsub.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Control(actor, control)),
-                FeaturesControl::Alias(control) => self.alias.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Control(actor, control)),
-                FeaturesControl::Socket(control) => self.socket.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Control(actor, control)),
+                FeaturesControl::Vpn(control) => {
+                    if let Some(vpn) = &mut self.vpn {
+                        vpn.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Control(actor, control));
+                    }
+                }
+                FeaturesControl::DhtKv(control) => {
+                    if let Some(dht_kv) = &mut self.dht_kv {
+                        dht_kv.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Control(actor, control));
+                    }
+                }
+                FeaturesControl::PubSub(control) => {
+                    if let Some(pubsub) = &mut self.pubsub {
+                        pubsub.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Control(actor, control));
+                    }
+                }
+                FeaturesControl::Alias(control) => {
+                    if let Some(alias) = &mut self.alias {
+                        alias.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Control(actor, control));
+                    }
+                }
+                FeaturesControl::Socket(control) => {
+                    if let Some(socket) = &mut self.socket {
+                        socket.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Control(actor, control));
+                    }
+                }
+                FeaturesControl::Rpc(control) => {
+                    if let Some(rpc) = &mut self.rpc {
+                        rpc.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Control(actor, control));
+                    }
+                }
+                FeaturesControl::HolePunch(control) => {
+                    if let Some(hole_punch) = &mut self.hole_punch {
+                        hole_punch.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Control(actor, control));
+                    }
+                }
             },
             FeatureWorkerInput::FromController(is_broadcast, to) => match to {
                 FeaturesToWorker::Neighbours(to) => self.neighbours.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::FromController(is_broadcast, to)),
                 FeaturesToWorker::Data(to) => self.data.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::FromController(is_broadcast, to)),
                 FeaturesToWorker::RouterSync(to) => self.router_sync.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::FromController(is_broadcast, to)),
-                FeaturesToWorker::Vpn(to) => self.vpn.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::FromController(is_broadcast, to)),
-                FeaturesToWorker::DhtKv(to) => self.dht_kv.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::FromController(is_broadcast, to)),
-                FeaturesToWorker::PubSub(to) => self.pubsub.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::FromController(is_broadcast, to)),
-                FeaturesToWorker::Alias(to) => self.alias.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::FromController(is_broadcast, to)),
-                FeaturesToWorker::Socket(to) => self.socket.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::FromController(is_broadcast, to)),
+                FeaturesToWorker::Vpn(to) => {
+                    if let Some(vpn) = &mut self.vpn {
+                        vpn.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::FromController(is_broadcast, to));
+                    }
+                }
+                FeaturesToWorker::DhtKv(to) => {
+                    if let Some(dht_kv) = &mut self.dht_kv {
+                        dht_kv.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::FromController(is_broadcast, to));
+                    }
+                }
+                FeaturesToWorker::PubSub(to) => {
+                    if let Some(pubsub) = &mut self.pubsub {
+                        pubsub.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::FromController(is_broadcast, to));
+                    }
+                }
+                FeaturesToWorker::Alias(to) => {
+                    if let Some(alias) = &mut self.alias {
+                        alias.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::FromController(is_broadcast, to));
+                    }
+                }
+                FeaturesToWorker::Socket(to) => {
+                    if let Some(socket) = &mut self.socket {
+                        socket.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::FromController(is_broadcast, to));
+                    }
+                }
+                FeaturesToWorker::Rpc(to) => {
+                    if let Some(rpc) = &mut self.rpc {
+                        rpc.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::FromController(is_broadcast, to));
+                    }
+                }
+                FeaturesToWorker::HolePunch(to) => {
+                    if let Some(hole_punch) = &mut self.hole_punch {
+                        hole_punch.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::FromController(is_broadcast, to));
+                    }
+                }
             },
             FeatureWorkerInput::Network(..) => {
                 panic!("should call above on_network_raw")
             }
             #[cfg(feature = "vpn")]
-            FeatureWorkerInput::TunPkt(pkt) => self.vpn.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::TunPkt(pkt)),
+            FeatureWorkerInput::TunPkt(pkt) => {
+                if let Some(vpn) = &mut self.vpn {
+                    vpn.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::TunPkt(pkt));
+                }
+            }
             FeatureWorkerInput::Local(header, buf) => match feature {
                 Features::Neighbours => self.neighbours.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Local(header, buf)),
                 Features::Data => self.data.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Local(header, buf)),
                 Features::RouterSync => self.router_sync.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Local(header, buf)),
-                Features::Vpn => self.vpn.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Local(header, buf)),
-                Features::DhtKv => self.dht_kv.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Local(header, buf)),
-                Features::PubSub => self.pubsub.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Local(header, buf)),
-                Features::Alias => self.alias.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Local(header, buf)),
-                Features::Socket => self.socket.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Local(header, buf)),
+                Features::Vpn => {
+                    if let Some(vpn) = &mut self.vpn {
+                        vpn.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Local(header, buf));
+                    }
+                }
+                Features::DhtKv => {
+                    if let Some(dht_kv) = &mut self.dht_kv {
+                        dht_kv.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Local(header, buf));
+                    }
+                }
+                Features::PubSub => {
+                    if let Some(pubsub) = &mut self.pubsub {
+                        pubsub.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Local(header, buf));
+                    }
+                }
+                Features::Alias => {
+                    if let Some(alias) = &mut self.alias {
+                        alias.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Local(header, buf));
+                    }
+                }
+                Features::Socket => {
+                    if let Some(socket) = &mut self.socket {
+                        socket.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Local(header, buf));
+                    }
+                }
+                Features::Rpc => {
+                    if let Some(rpc) = &mut self.rpc {
+                        rpc.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Local(header, buf));
+                    }
+                }
+                Features::HolePunch => {
+                    if let Some(hole_punch) = &mut self.hole_punch {
+                        hole_punch.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Local(header, buf));
+                    }
+                }
             },
         }
     }
@@ -123,11 +325,27 @@ impl<UserData: Eq + Debug + Copy> FeatureWorkerManager<UserData> {
         self.neighbours.input(&mut self.switcher).on_shutdown(ctx, now_ms);
         self.data.input(&mut self.switcher).on_shutdown(ctx, now_ms);
         self.router_sync.input(&mut self.switcher).on_shutdown(ctx, now_ms);
-        self.vpn.input(&mut self.switcher).on_shutdown(ctx, now_ms);
-        self.dht_kv.input(&mut self.switcher).on_shutdown(ctx, now_ms);
-        self.pubsub.input(&mut self.switcher).on_shutdown(ctx, now_ms);
-        self.alias.input(&mut self.switcher).on_shutdown(ctx, now_ms);
-        self.socket.input(&mut self.switcher).on_shutdown(ctx, now_ms);
+        if let Some(vpn) = &mut self.vpn {
+            vpn.input(&mut self.switcher).on_shutdown(ctx, now_ms);
+        }
+        if let Some(dht_kv) = &mut self.dht_kv {
+            dht_kv.input(&mut self.switcher).on_shutdown(ctx, now_ms);
+        }
+        if let Some(pubsub) = &mut self.pubsub {
+            pubsub.input(&mut self.switcher).on_shutdown(ctx, now_ms);
+        }
+        if let Some(alias) = &mut self.alias {
+            alias.input(&mut self.switcher).on_shutdown(ctx, now_ms);
+        }
+        if let Some(socket) = &mut self.socket {
+            socket.input(&mut self.switcher).on_shutdown(ctx, now_ms);
+        }
+        if let Some(rpc) = &mut self.rpc {
+            rpc.input(&mut self.switcher).on_shutdown(ctx, now_ms);
+        }
+        if let Some(hole_punch) = &mut self.hole_punch {
+            hole_punch.input(&mut self.switcher).on_shutdown(ctx, now_ms);
+        }
         self.shutdown = true;
     }
 }
@@ -144,57 +362,194 @@ impl<UserData> TaskSwitcherChild<Output<UserData>> for FeatureWorkerManager<User
             && self.neighbours.is_empty()
             && self.data.is_empty()
             && self.router_sync.is_empty()
-            && self.vpn.is_empty()
-            && self.dht_kv.is_empty()
-            && self.pubsub.is_empty()
-            && self.alias.is_empty()
-            && self.socket.is_empty()
+            && self.vpn.as_ref().map_or(true, |vpn| vpn.is_empty())
+            && self.dht_kv.as_ref().map_or(true, |dht_kv| dht_kv.is_empty())
+            && self.pubsub.as_ref().map_or(true, |pubsub| pubsub.is_empty())
+            && self.alias.as_ref().map_or(true, |alias| alias.is_empty())
+            && self.socket.as_ref().map_or(true, |socket| socket.is_empty())
+            && self.rpc.as_ref().map_or(true, |rpc| rpc.is_empty())
+            && self.hole_punch.as_ref().map_or(true, |hole_punch| hole_punch.is_empty())
     }
 
     fn pop_output(&mut self, now: u64) -> Option<Output<UserData>> {
         loop {
-            match (self.switcher.current()? as u8).try_into().ok()? {
+            match self.scheduler.current(&mut self.switcher)? {
                 Features::Neighbours => {
                     if let Some(out) = self.neighbours.pop_output(now, &mut self.switcher) {
+                        self.scheduler.on_output();
                         return Some(Output::Output(Features::Neighbours, out.into2()));
                     }
                 }
                 Features::Data => {
                     if let Some(out) = self.data.pop_output(now, &mut self.switcher) {
+                        self.scheduler.on_output();
                         return Some(Output::Output(Features::Data, out.into2()));
                     }
                 }
                 Features::RouterSync => {
                     if let Some(out) = self.router_sync.pop_output(now, &mut self.switcher) {
+                        self.scheduler.on_output();
                         return Some(Output::Output(Features::RouterSync, out.into2()));
                     }
                 }
                 Features::Vpn => {
-                    if let Some(out) = self.vpn.pop_output(now, &mut self.switcher) {
+                    if let Some(out) = self.vpn.as_mut().and_then(|vpn| vpn.pop_output(now, &mut self.switcher)) {
+                        self.scheduler.on_output();
                         return Some(Output::Output(Features::Vpn, out.into2()));
                     }
                 }
                 Features::DhtKv => {
-                    if let Some(out) = self.dht_kv.pop_output(now, &mut self.switcher) {
+                    if let Some(out) = self.dht_kv.as_mut().and_then(|dht_kv| dht_kv.pop_output(now, &mut self.switcher)) {
+                        self.scheduler.on_output();
                         return Some(Output::Output(Features::DhtKv, out.into2()));
                     }
                 }
                 Features::PubSub => {
-                    if let Some(out) = self.pubsub.pop_output(now, &mut self.switcher) {
+                    if let Some(out) = self.pubsub.as_mut().and_then(|pubsub| pubsub.pop_output(now, &mut self.switcher)) {
+                        self.scheduler.on_output();
                         return Some(Output::Output(Features::PubSub, out.into2()));
                     }
                 }
                 Features::Alias => {
-                    if let Some(out) = self.alias.pop_output(now, &mut self.switcher) {
+                    if let Some(out) = self.alias.as_mut().and_then(|alias| alias.pop_output(now, &mut self.switcher)) {
+                        self.scheduler.on_output();
                         return Some(Output::Output(Features::Alias, out.into2()));
                     }
                 }
                 Features::Socket => {
-                    if let Some(out) = self.socket.pop_output(now, &mut self.switcher) {
+                    if let Some(out) = self.socket.as_mut().and_then(|socket| socket.pop_output(now, &mut self.switcher)) {
+                        self.scheduler.on_output();
                         return Some(Output::Output(Features::Socket, out.into2()));
                     }
                 }
+                Features::Rpc => {
+                    if let Some(out) = self.rpc.as_mut().and_then(|rpc| rpc.pop_output(now, &mut self.switcher)) {
+                        self.scheduler.on_output();
+                        return Some(Output::Output(Features::Rpc, out.into2()));
+                    }
+                }
+                Features::HolePunch => {
+                    if let Some(out) = self.hole_punch.as_mut().and_then(|hole_punch| hole_punch.pop_output(now, &mut self.switcher)) {
+                        self.scheduler.on_output();
+                        return Some(Output::Output(Features::HolePunch, out.into2()));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use atm0s_sdn_identity::ConnId;
+    use atm0s_sdn_router::{
+        shadow::{MockShadowRouterHistory, ShadowRouter},
+        RouteRule,
+    };
+    use rand::rngs::mock::StepRng;
+    use sans_io_runtime::TaskSwitcherChild;
+
+    use crate::{
+        base::{Buffer, FeatureControlActor, FeatureWorkerContext, FeatureWorkerInput, TransportMsg, TransportMsgHeader},
+        data_plane::NetPair,
+        features::{data, pubsub, router_sync, Features, FeaturesConfig, FeaturesControl, DEFAULT_CONTROL_WEIGHT},
+    };
+
+    use super::{FeatureWorkerManager, Output};
+
+    fn popped_features(manager: &mut FeatureWorkerManager<()>) -> Vec<Features> {
+        let mut features = vec![];
+        while let Some(out) = manager.pop_output(0) {
+            if let Output::Output(feature, _) = out {
+                features.push(feature);
             }
         }
+        features
+    }
+
+    #[test]
+    fn router_sync_should_pop_before_data_backlog() {
+        let mut ctx = FeatureWorkerContext {
+            node_id: 1,
+            router: ShadowRouter::new(1, Arc::new(MockShadowRouterHistory::new())),
+            random: Box::new(StepRng::new(0, 1)),
+        };
+        let actor = FeatureControlActor::Worker(0, ());
+        let mut manager = FeatureWorkerManager::new(&HashMap::new(), FeaturesConfig::default(), HashMap::new());
+        for _ in 0..20 {
+            let control = FeaturesControl::Data(data::Control::DataListen(1));
+            manager.on_input(&mut ctx, Features::Data, 0, FeatureWorkerInput::Control(actor, control));
+        }
+        let control = FeaturesControl::RouterSync(router_sync::Control::DumpRouter);
+        manager.on_input(&mut ctx, Features::RouterSync, 0, FeatureWorkerInput::Control(actor, control));
+
+        let mut expected = vec![Features::RouterSync];
+        expected.extend([Features::Data; 20]);
+        assert_eq!(popped_features(&mut manager), expected);
+
+        //a router_sync backlog still lets data through after each turn
+        for _ in 0..20 {
+            let control = FeaturesControl::RouterSync(router_sync::Control::DumpRouter);
+            manager.on_input(&mut ctx, Features::RouterSync, 0, FeatureWorkerInput::Control(actor, control));
+        }
+        let control = FeaturesControl::Data(data::Control::DataListen(1));
+        manager.on_input(&mut ctx, Features::Data, 0, FeatureWorkerInput::Control(actor, control));
+        let popped = popped_features(&mut manager);
+        assert_eq!(popped.iter().position(|f| *f == Features::Data), Some(DEFAULT_CONTROL_WEIGHT as usize));
+        assert_eq!(popped.len(), 21);
+    }
+
+    #[test]
+    fn disabled_feature_should_drop_inputs() {
+        let mut ctx = FeatureWorkerContext {
+            node_id: 1,
+            router: ShadowRouter::new(1, Arc::new(MockShadowRouterHistory::new())),
+            random: Box::new(StepRng::new(0, 1)),
+        };
+        let actor = FeatureControlActor::Worker(0, ());
+        let mut manager = FeatureWorkerManager::new(&HashMap::new(), FeaturesConfig::core(), HashMap::new());
+
+        let control = FeaturesControl::PubSub(pubsub::Control(pubsub::ChannelId(1), pubsub::ChannelControl::SubAuto));
+        manager.on_input(&mut ctx, Features::PubSub, 0, FeatureWorkerInput::Control(actor, control));
+        assert_eq!(manager.unavailable_count(), 1);
+        assert_eq!(popped_features(&mut manager), vec![]);
+
+        let control = FeaturesControl::Data(data::Control::DataListen(1));
+        manager.on_input(&mut ctx, Features::Data, 0, FeatureWorkerInput::Control(actor, control));
+        assert_eq!(popped_features(&mut manager), vec![Features::Data]);
+        assert_eq!(manager.unavailable_count(), 1);
+    }
+
+    #[test]
+    fn oversized_payload_should_be_dropped_before_feature() {
+        let mut ctx = FeatureWorkerContext {
+            node_id: 1,
+            router: ShadowRouter::new(1, Arc::new(MockShadowRouterHistory::new())),
+            random: Box::new(StepRng::new(0, 1)),
+        };
+        let mut manager = FeatureWorkerManager::new(&HashMap::new(), FeaturesConfig::default(), HashMap::from([(Features::Data, 100)]));
+        let pair = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let conn = ConnId::from_in(0, 1);
+        let packet = |len: usize| {
+            let header = TransportMsgHeader::build(Features::Data as u8, 0, RouteRule::Direct);
+            (header.clone(), TransportMsg::build_raw(header, Buffer::from(vec![0; len])).take())
+        };
+
+        let (header, buf) = packet(101);
+        manager.on_network_raw(&mut ctx, Features::Data, 0, conn, pair, header, buf);
+        assert_eq!(manager.oversized_count(), 1);
+        assert_eq!(popped_features(&mut manager), vec![]);
+
+        let (header, buf) = packet(100);
+        manager.on_network_raw(&mut ctx, Features::Data, 0, conn, pair, header, buf);
+        assert_eq!(popped_features(&mut manager), vec![Features::Data]);
+
+        //other features keep the default limit
+        let (mut header, buf) = packet(1000);
+        header.feature = Features::RouterSync as u8;
+        manager.on_network_raw(&mut ctx, Features::RouterSync, 0, conn, pair, header, buf);
+        assert_eq!(manager.oversized_count(), 1);
     }
 }
@@ -0,0 +1,197 @@
+//! Path MTU discovery of a connection.
+//!
+//! Padded probes are sent as plain Direct messages of a reserved feature id, the remote data plane answers each one
+//! with a small ack echoing its nonce. The search starts optimistic with `PMTU_MAX`, then bisects between the largest
+//! acked size and the smallest size which got no ack after `PROBE_ATTEMPTS`. Until a search is done the last known
+//! value is used, which is `PMTU_DEFAULT` for a new connection or when no probe is acked.
+
+use serde::{Deserialize, Serialize};
+
+/// Conservative UDP payload size which is used before probing and when probing fails
+pub const PMTU_DEFAULT: usize = 1200;
+/// Biggest probed UDP payload size, a 1500 bytes ethernet MTU without IPv4 and UDP headers
+pub const PMTU_MAX: usize = 1472;
+/// Feature id of probe messages. They are handled by the data plane itself, and it is never a `Features` value
+pub const PMTU_FEATURE_ID: u8 = 255;
+/// Paths can change, so the search is repeated after this
+pub const PMTU_REPROBE_MS: u64 = 600_000;
+/// First search starts after the connection is pinned for this time, so it does not compete with the connect traffic
+const PMTU_START_MS: u64 = 1000;
+/// Search stops when the range between acked and failed size is smaller than this
+const PMTU_STEP: usize = 16;
+const PROBE_TIMEOUT_MS: u64 = 1000;
+const PROBE_ATTEMPTS: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PmtuMsg {
+    Probe { nonce: u32, size: u16 },
+    Ack { nonce: u32, size: u16 },
+}
+
+struct InFlight {
+    nonce: u32,
+    size: usize,
+    sent_ms: u64,
+    attempts: u8,
+}
+
+pub struct PmtuProbe {
+    mtu: usize,
+    /// Largest acked size of the current search
+    low: usize,
+    /// Smallest failed size of the current search
+    high: usize,
+    searching: bool,
+    in_flight: Option<InFlight>,
+    next_search_ms: u64,
+}
+
+impl PmtuProbe {
+    pub fn new(now_ms: u64) -> Self {
+        Self {
+            mtu: PMTU_DEFAULT,
+            low: PMTU_DEFAULT,
+            high: PMTU_MAX + 1,
+            searching: false,
+            in_flight: None,
+            next_search_ms: now_ms + PMTU_START_MS,
+        }
+    }
+
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// Size of a probe which should be sent now, `nonce` is used when it is a new probe. Returns None if no probe
+    /// is needed or one is still waiting for its ack
+    pub fn on_tick(&mut self, now_ms: u64, nonce: u32) -> Option<(u32, usize)> {
+        if !self.searching {
+            if now_ms < self.next_search_ms {
+                return None;
+            }
+            self.searching = true;
+            self.low = PMTU_DEFAULT;
+            self.high = PMTU_MAX + 1;
+        }
+        if let Some(in_flight) = &mut self.in_flight {
+            if now_ms < in_flight.sent_ms + PROBE_TIMEOUT_MS {
+                return None;
+            }
+            if in_flight.attempts < PROBE_ATTEMPTS {
+                in_flight.attempts += 1;
+                in_flight.sent_ms = now_ms;
+                return Some((in_flight.nonce, in_flight.size));
+            }
+            log::debug!("[PmtuProbe] no ack for probe size {}", in_flight.size);
+            self.high = in_flight.size;
+            self.in_flight = None;
+            if self.finish_search(now_ms) {
+                return None;
+            }
+        }
+        let size = if self.high > PMTU_MAX {
+            PMTU_MAX
+        } else {
+            (self.low + self.high) / 2
+        };
+        self.in_flight = Some(InFlight {
+            nonce,
+            size,
+            sent_ms: now_ms,
+            attempts: 1,
+        });
+        Some((nonce, size))
+    }
+
+    /// Handle the ack of a probe, returns true if the mtu changed
+    pub fn on_ack(&mut self, now_ms: u64, nonce: u32, size: usize) -> bool {
+        if !matches!(&self.in_flight, Some(in_flight) if in_flight.nonce == nonce && in_flight.size == size) {
+            log::debug!("[PmtuProbe] ignore unknown ack size {size}");
+            return false;
+        }
+        self.in_flight = None;
+        self.low = size;
+        let before = self.mtu;
+        self.finish_search(now_ms);
+        before != self.mtu
+    }
+
+    fn finish_search(&mut self, now_ms: u64) -> bool {
+        if self.low < PMTU_MAX && self.high - self.low > PMTU_STEP {
+            return false;
+        }
+        log::info!("[PmtuProbe] path mtu {} => {}", self.mtu, self.low);
+        self.mtu = self.low;
+        self.searching = false;
+        self.next_search_ms = now_ms + PMTU_REPROBE_MS;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PmtuProbe, PMTU_DEFAULT, PMTU_MAX, PMTU_REPROBE_MS, PMTU_START_MS, PMTU_STEP, PROBE_TIMEOUT_MS};
+
+    /// Run probing against a path which drops packets above `path_mtu`, returns the time when it finished
+    fn run(probe: &mut PmtuProbe, start_ms: u64, path_mtu: usize) -> u64 {
+        let mut now = start_ms;
+        let mut nonce = 0;
+        while now < start_ms + 60_000 {
+            nonce += 1;
+            if let Some((nonce, size)) = probe.on_tick(now, nonce) {
+                if size <= path_mtu {
+                    probe.on_ack(now + 10, nonce, size);
+                }
+            } else if !probe.searching {
+                return now;
+            }
+            now += 100;
+        }
+        panic!("Should finish probing");
+    }
+
+    #[test]
+    fn full_path_is_found_by_first_probe() {
+        let mut probe = PmtuProbe::new(0);
+        assert_eq!(probe.mtu(), PMTU_DEFAULT);
+        assert_eq!(probe.on_tick(0, 6), None);
+        let now = PMTU_START_MS;
+        assert_eq!(probe.on_tick(now, 7), Some((7, PMTU_MAX)));
+        assert_eq!(probe.on_tick(now + 100, 8), None);
+        assert!(!probe.on_ack(now + 110, 8, PMTU_MAX));
+        assert!(probe.on_ack(now + 110, 7, PMTU_MAX));
+        assert_eq!(probe.mtu(), PMTU_MAX);
+        //late duplicated ack is ignored
+        assert!(!probe.on_ack(now + 120, 7, PMTU_MAX));
+        assert_eq!(probe.on_tick(now + PMTU_REPROBE_MS, 9), None);
+        assert_eq!(probe.on_tick(now + 110 + PMTU_REPROBE_MS, 9), Some((9, PMTU_MAX)));
+    }
+
+    #[test]
+    fn smaller_path_is_bisected() {
+        let mut probe = PmtuProbe::new(0);
+        run(&mut probe, PMTU_START_MS, 1350);
+        assert!(probe.mtu() <= 1350 && probe.mtu() > 1350 - PMTU_STEP, "{}", probe.mtu());
+    }
+
+    #[test]
+    fn lost_probe_is_retried() {
+        let mut probe = PmtuProbe::new(0);
+        assert_eq!(probe.on_tick(PMTU_START_MS, 1), Some((1, PMTU_MAX)));
+        assert_eq!(probe.on_tick(PMTU_START_MS + PROBE_TIMEOUT_MS, 2), Some((1, PMTU_MAX)));
+        assert!(probe.on_ack(PMTU_START_MS + PROBE_TIMEOUT_MS + 10, 1, PMTU_MAX));
+    }
+
+    #[test]
+    fn fallback_to_default_when_probes_fail() {
+        let mut probe = PmtuProbe::new(0);
+        let done = run(&mut probe, PMTU_START_MS, 0);
+        assert_eq!(probe.mtu(), PMTU_DEFAULT);
+
+        //path grows then shrinks, each re-probe follows it
+        run(&mut probe, done + PMTU_REPROBE_MS, 1500);
+        assert_eq!(probe.mtu(), PMTU_MAX);
+        run(&mut probe, done + 3 * PMTU_REPROBE_MS, 1300);
+        assert!(probe.mtu() <= 1300 && probe.mtu() > 1300 - PMTU_STEP, "{}", probe.mtu());
+    }
+}
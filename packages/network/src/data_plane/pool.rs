@@ -0,0 +1,117 @@
+//! Pool of packet buffers for the secure broadcast paths of the data plane.
+//!
+//! Each remote of a secure broadcast needs its own copy of the packet, because it is encrypted with the key of
+//! that connection. `Buffer` is owned by whoever pops the output, so sent buffers only come back when the caller
+//! returns them with `recycle` after sending, otherwise the pool falls back to allocating. The source packet of a
+//! fan-out is not sent and goes back to the pool by itself.
+
+use sans_io_runtime::Buffer;
+
+/// How many returned buffers are kept for reuse by default
+pub const BUFFER_POOL_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BufferPoolStats {
+    /// Buffers built by reusing a returned buffer
+    pub hit: u64,
+    /// Buffers built with a new allocation because the pool was empty
+    pub miss: u64,
+    /// Returned buffers which were dropped because the pool was full
+    pub overflow: u64,
+}
+
+pub struct BufferPool {
+    capacity: usize,
+    free: Vec<Buffer>,
+    stats: BufferPoolStats,
+}
+
+impl BufferPool {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            free: Vec::new(),
+            stats: BufferPoolStats::default(),
+        }
+    }
+
+    /// Same as `Buffer::build(data, 0, back)` but reuses a returned buffer if any
+    pub fn build(&mut self, data: &[u8], back: usize) -> Buffer {
+        match self.free.pop() {
+            Some(mut buf) => {
+                self.stats.hit += 1;
+                let len = buf.len();
+                let _ = buf.pop_back(len);
+                buf.ensure_back(data.len() + back);
+                buf.push_back(data);
+                buf
+            }
+            None => {
+                self.stats.miss += 1;
+                Buffer::build(data, 0, back)
+            }
+        }
+    }
+
+    /// Return a buffer which is not needed anymore, for example after it was sent
+    pub fn recycle(&mut self, buf: Buffer) {
+        if self.free.len() < self.capacity {
+            self.free.push(buf);
+        } else {
+            self.stats.overflow += 1;
+        }
+    }
+
+    /// Number of buffers ready for reuse
+    pub fn len(&self) -> usize {
+        self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.free.is_empty()
+    }
+
+    pub fn stats(&self) -> BufferPoolStats {
+        self.stats
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(BUFFER_POOL_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sans_io_runtime::Buffer;
+
+    use super::{BufferPool, BufferPoolStats};
+
+    #[test]
+    fn reuse_returned_buffers() {
+        let mut pool = BufferPool::new(1);
+        let buf = pool.build(&[1, 2, 3], 16);
+        assert_eq!(&buf as &[u8], &[1, 2, 3]);
+        assert_eq!(pool.stats(), BufferPoolStats { hit: 0, miss: 1, overflow: 0 });
+
+        pool.recycle(buf);
+        pool.recycle(Buffer::from(vec![9, 9]));
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.stats().overflow, 1);
+
+        let buf = pool.build(&[4, 5], 16);
+        assert_eq!(&buf as &[u8], &[4, 5]);
+        assert_eq!(pool.stats(), BufferPoolStats { hit: 1, miss: 1, overflow: 1 });
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn reused_buffer_can_grow() {
+        let mut pool = BufferPool::new(1);
+        pool.recycle(Buffer::from(vec![1]));
+        let mut buf = pool.build(&[1, 2, 3, 4, 5, 6, 7, 8], 4);
+        buf.push_back(&[9, 10, 11, 12]);
+        assert_eq!(&buf as &[u8], &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+    }
+}
@@ -0,0 +1,145 @@
+//! Bounded queue for bulk outputs of the data plane.
+//!
+//! Packets of bulk features wait here until the transport pops them, so a send burst which is not drained can't
+//! grow the worker memory without limit. Control features don't use it, their packets are never dropped.
+
+use std::collections::VecDeque;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// The new output is dropped
+    #[default]
+    DropNewest,
+    /// The oldest queued output is dropped for the new one
+    DropOldest,
+    /// Nothing is dropped, `DataPlane::is_blocked` is true until the queue drains below capacity and callers
+    /// should stop feeding new sends until then
+    Block,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputQueueCfg {
+    pub capacity: usize,
+    pub policy: OverflowPolicy,
+    /// `DataPlane::queue_len` over which `Output::Backlog(true)` is emitted, None disables backlog events
+    pub high_watermark: Option<usize>,
+}
+
+impl Default for OutputQueueCfg {
+    fn default() -> Self {
+        Self {
+            capacity: 4096,
+            policy: OverflowPolicy::default(),
+            high_watermark: None,
+        }
+    }
+}
+
+pub struct BulkQueue<T> {
+    cfg: OutputQueueCfg,
+    queue: VecDeque<T>,
+    dropped: u64,
+}
+
+impl<T> BulkQueue<T> {
+    pub fn new(cfg: OutputQueueCfg) -> Self {
+        Self {
+            cfg,
+            queue: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Queue an output, returns false if an output was dropped for it
+    pub fn push_back(&mut self, item: T) -> bool {
+        if self.queue.len() < self.cfg.capacity {
+            self.queue.push_back(item);
+            return true;
+        }
+        match self.cfg.policy {
+            OverflowPolicy::DropNewest => {}
+            OverflowPolicy::DropOldest => {
+                self.queue.pop_front();
+                self.queue.push_back(item);
+            }
+            OverflowPolicy::Block => {
+                self.queue.push_back(item);
+                return true;
+            }
+        }
+        self.dropped += 1;
+        false
+    }
+
+    pub fn pop_front(&mut self) -> Option<T> {
+        self.queue.pop_front()
+    }
+
+    pub fn front(&self) -> Option<&T> {
+        self.queue.front()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_blocked(&self) -> bool {
+        self.queue.len() >= self.cfg.capacity
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BulkQueue, OutputQueueCfg, OverflowPolicy};
+
+    fn flood(policy: OverflowPolicy) -> (BulkQueue<u32>, Vec<bool>) {
+        let mut queue = BulkQueue::new(OutputQueueCfg {
+            capacity: 3,
+            policy,
+            ..Default::default()
+        });
+        let accepted = (0..5).map(|i| queue.push_back(i)).collect();
+        (queue, accepted)
+    }
+
+    fn drain(queue: &mut BulkQueue<u32>) -> Vec<u32> {
+        std::iter::from_fn(|| queue.pop_front()).collect()
+    }
+
+    #[test]
+    fn drop_newest_keeps_first() {
+        let (mut queue, accepted) = flood(OverflowPolicy::DropNewest);
+        assert_eq!(accepted, vec![true, true, true, false, false]);
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(drain(&mut queue), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn drop_oldest_keeps_last() {
+        let (mut queue, _) = flood(OverflowPolicy::DropOldest);
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(drain(&mut queue), vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn block_keeps_all_and_reports() {
+        let (mut queue, accepted) = flood(OverflowPolicy::Block);
+        assert!(accepted.iter().all(|a| *a));
+        assert!(queue.is_blocked());
+        assert_eq!(queue.dropped(), 0);
+        assert_eq!(queue.pop_front(), Some(0));
+        assert_eq!(queue.pop_front(), Some(1));
+        assert!(queue.is_blocked());
+        assert_eq!(queue.pop_front(), Some(2));
+        assert!(!queue.is_blocked());
+        assert_eq!(drain(&mut queue), vec![3, 4]);
+    }
+}
@@ -0,0 +1,134 @@
+/// Number of packets behind the highest received sequence which are still accepted
+pub const REPLAY_WINDOW_SIZE: u64 = 1024;
+const WORDS: usize = (REPLAY_WINDOW_SIZE / 64) as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayCheck {
+    Accept,
+    Duplicate,
+    TooOld,
+}
+
+/// Sliding window of received sequence numbers, bit `i` is set if `highest - i` was received.
+#[derive(Debug, Default)]
+pub struct ReplayWindow {
+    highest: Option<u64>,
+    bits: [u64; WORDS],
+}
+
+impl ReplayWindow {
+    /// Check a sequence without updating the window, it should be marked after the packet is authenticated
+    pub fn check(&self, seq: u64) -> ReplayCheck {
+        let highest = match self.highest {
+            Some(highest) => highest,
+            None => return ReplayCheck::Accept,
+        };
+        if seq > highest {
+            return ReplayCheck::Accept;
+        }
+        let offset = highest - seq;
+        if offset >= REPLAY_WINDOW_SIZE {
+            ReplayCheck::TooOld
+        } else if self.get(offset) {
+            ReplayCheck::Duplicate
+        } else {
+            ReplayCheck::Accept
+        }
+    }
+
+    /// True if the sequence is above all received ones
+    pub fn is_newest(&self, seq: u64) -> bool {
+        self.highest.map_or(true, |highest| seq > highest)
+    }
+
+    pub fn mark(&mut self, seq: u64) {
+        let highest = match self.highest {
+            Some(highest) => highest,
+            None => {
+                self.highest = Some(seq);
+                self.set(0);
+                return;
+            }
+        };
+        if seq > highest {
+            self.shift(seq - highest);
+            self.highest = Some(seq);
+            self.set(0);
+        } else if highest - seq < REPLAY_WINDOW_SIZE {
+            self.set(highest - seq);
+        }
+    }
+
+    fn get(&self, offset: u64) -> bool {
+        self.bits[(offset / 64) as usize] & (1 << (offset % 64)) != 0
+    }
+
+    fn set(&mut self, offset: u64) {
+        self.bits[(offset / 64) as usize] |= 1 << (offset % 64);
+    }
+
+    /// Move all bits to higher offsets, bits which move out of the window are dropped
+    fn shift(&mut self, by: u64) {
+        if by >= REPLAY_WINDOW_SIZE {
+            self.bits = [0; WORDS];
+            return;
+        }
+        let words = (by / 64) as usize;
+        let bits = (by % 64) as u32;
+        for i in (0..WORDS).rev() {
+            let src = i.checked_sub(words);
+            let mut value = src.map(|s| self.bits[s] << bits).unwrap_or(0);
+            if bits > 0 {
+                if let Some(prev) = src.and_then(|s| s.checked_sub(1)) {
+                    value |= self.bits[prev] >> (64 - bits);
+                }
+            }
+            self.bits[i] = value;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ReplayCheck, ReplayWindow, REPLAY_WINDOW_SIZE};
+
+    #[test]
+    fn reject_duplicate() {
+        let mut window = ReplayWindow::default();
+        assert_eq!(window.check(10), ReplayCheck::Accept);
+        window.mark(10);
+        assert_eq!(window.check(10), ReplayCheck::Duplicate);
+        assert_eq!(window.check(11), ReplayCheck::Accept);
+        //older but not received yet
+        assert_eq!(window.check(5), ReplayCheck::Accept);
+        window.mark(5);
+        assert_eq!(window.check(5), ReplayCheck::Duplicate);
+    }
+
+    #[test]
+    fn keep_history_after_shift() {
+        let mut window = ReplayWindow::default();
+        window.mark(1);
+        window.mark(3);
+        window.mark(1 + 100);
+        assert_eq!(window.check(1), ReplayCheck::Duplicate);
+        assert_eq!(window.check(2), ReplayCheck::Accept);
+        assert_eq!(window.check(3), ReplayCheck::Duplicate);
+        assert_eq!(window.check(101), ReplayCheck::Duplicate);
+    }
+
+    #[test]
+    fn reject_out_of_window() {
+        let mut window = ReplayWindow::default();
+        window.mark(1);
+        window.mark(REPLAY_WINDOW_SIZE);
+        assert_eq!(window.check(1), ReplayCheck::Duplicate);
+        window.mark(REPLAY_WINDOW_SIZE + 1);
+        assert_eq!(window.check(1), ReplayCheck::TooOld);
+        assert_eq!(window.check(2), ReplayCheck::Accept);
+        //jump far ahead clear all history
+        window.mark(10 * REPLAY_WINDOW_SIZE);
+        assert_eq!(window.check(REPLAY_WINDOW_SIZE + 1), ReplayCheck::TooOld);
+        assert_eq!(window.check(10 * REPLAY_WINDOW_SIZE - 1), ReplayCheck::Accept);
+    }
+}
@@ -6,6 +6,7 @@ use sans_io_runtime::{TaskSwitcher, TaskSwitcherBranch, TaskSwitcherChild};
 
 use crate::base::{ServiceBuilder, ServiceId, ServiceWorker, ServiceWorkerCtx, ServiceWorkerInput, ServiceWorkerOutput};
 use crate::features::{FeaturesControl, FeaturesEvent};
+use crate::services::ServiceScheduler;
 
 pub enum Output<UserData, ServiceControl, ServiceEvent, ToController> {
     Output(ServiceId, ServiceWorkerOutput<UserData, FeaturesControl, FeaturesEvent, ServiceControl, ServiceEvent, ToController>),
@@ -20,6 +21,7 @@ type ServiceSwitcher<UserData, ServiceControl, ServiceEvent, ToController, ToWor
 struct ServiceSlot<UserData, ServiceControl, ServiceEvent, ToController, ToWorker> {
     service: ServiceSwitcher<UserData, ServiceControl, ServiceEvent, ToController, ToWorker>,
     is_empty: bool,
+    budget: u8,
 }
 
 /// To manage the services we need to create an object that will hold the services
@@ -28,6 +30,7 @@ pub struct ServiceWorkerManager<UserData, ServiceControl, ServiceEvent, ToContro
     services: [Option<ServiceSlot<UserData, ServiceControl, ServiceEvent, ToController, ToWorker>>; 256],
     services_count: usize,
     switcher: TaskSwitcher,
+    scheduler: ServiceScheduler,
     empty_services: HashSet<ServiceId>,
     shutdown: bool,
     _tmp: PhantomData<ServiceControl>,
@@ -43,9 +46,11 @@ impl<UserData, ServiceControl, ServiceEvent, ToController, ToWorker> ServiceWork
                 services.iter().find(|s| s.service_id() == index as u8).map(|s| ServiceSlot {
                     service: TaskSwitcherBranch::new(s.create_worker(), index),
                     is_empty: true,
+                    budget: s.output_budget(),
                 })
             }),
             switcher: TaskSwitcher::new(max_service_id as usize + 1),
+            scheduler: ServiceScheduler::new((0..=max_service_id).map(|id| services.iter().find(|s| s.service_id() == id).map_or(1, |s| s.output_budget())).collect()),
             empty_services: HashSet::new(),
             shutdown: false,
             _tmp: PhantomData,
@@ -58,6 +63,50 @@ impl<UserData, ServiceControl, ServiceEvent, ToController, ToWorker> ServiceWork
         }
     }
 
+    pub fn has_service(&self, id: ServiceId) -> bool {
+        self.services[*id as usize].is_some()
+    }
+
+    /// Install a service at runtime, a running service with the same id is replaced
+    pub fn register(&mut self, builder: Arc<dyn ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, ServiceControl, ServiceEvent, ToController, ToWorker>>) {
+        let id = builder.service_id();
+        log::info!("[DataPlane] register service {} {}", id, builder.service_name());
+        if self.services[id as usize].is_none() {
+            self.services_count += 1;
+        }
+        self.empty_services.remove(&id.into());
+        self.services[id as usize] = Some(ServiceSlot {
+            service: TaskSwitcherBranch::new(builder.create_worker(), id as usize),
+            is_empty: true,
+            budget: builder.output_budget(),
+        });
+        self.reset_switcher();
+    }
+
+    /// Remove a service at runtime, its queued outputs are dropped. Returns false if it is not registered
+    pub fn unregister(&mut self, id: ServiceId) -> bool {
+        if self.services[*id as usize].take().is_none() {
+            return false;
+        }
+        log::info!("[DataPlane] unregister service {id}");
+        self.services_count -= 1;
+        self.empty_services.remove(&id);
+        true
+    }
+
+    /// The switcher and scheduler are sized by the largest service id, so they are rebuilt when a service is added.
+    /// All services are flagged again, none of their pending outputs is lost
+    fn reset_switcher(&mut self) {
+        let max_service_id = self.services.iter().rposition(Option::is_some).unwrap_or(0);
+        self.switcher = TaskSwitcher::new(max_service_id + 1);
+        self.scheduler = ServiceScheduler::new((0..=max_service_id).map(|id| self.services[id].as_ref().map_or(1, |s| s.budget)).collect());
+        for id in 0..=max_service_id {
+            if self.services[id].is_some() {
+                self.switcher.flag_task(id);
+            }
+        }
+    }
+
     pub fn on_input(&mut self, ctx: &ServiceWorkerCtx, now: u64, id: ServiceId, input: ServiceWorkerInput<UserData, FeaturesEvent, ServiceControl, ToWorker>) {
         if let Some(service) = self.services[*id as usize].as_mut() {
             self.switcher.flag_task(*id as usize);
@@ -91,9 +140,10 @@ impl<UserData, ServiceControl, ServiceEvent, ToController, ToWorker> TaskSwitche
 
     fn pop_output(&mut self, now: u64) -> Option<Output<UserData, ServiceControl, ServiceEvent, ToController>> {
         loop {
-            let index = self.switcher.current()?;
+            let index = self.scheduler.current(&mut self.switcher)?;
             if let Some(Some(slot)) = self.services.get_mut(index) {
                 if let Some(output) = slot.service.pop_output(now, &mut self.switcher) {
+                    self.scheduler.on_output();
                     return Some(Output::Output((index as u8).into(), output));
                 } else {
                     if !slot.is_empty {
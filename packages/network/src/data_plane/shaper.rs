@@ -0,0 +1,180 @@
+//! Token bucket pacing of bulk outputs.
+//!
+//! A shaped bulk feature spends tokens of its bucket for each sent byte. When the bucket is empty its packets wait
+//! in the shaper and are released by the tick loop at the configured rate, so a send burst does not hit a thin link
+//! at once. Tokens are counted in thousandths of a byte for slow rates. A packet larger than the burst is sent when
+//! the bucket is full, and the debt is paid by the next refills.
+//! Control features are never shaped.
+
+use atm0s_sdn_utils::metrics;
+
+use super::{queue::BulkQueue, NetOutput, OutputQueueCfg};
+
+const TOKEN: i64 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShaperCfg {
+    pub bytes_per_sec: u64,
+    /// Bytes which can be sent at once after an idle period
+    pub burst_bytes: u64,
+}
+
+pub struct Shaper {
+    cfg: ShaperCfg,
+    tokens: i64,
+    updated_ms: u64,
+    deferred: BulkQueue<NetOutput>,
+    deferred_bytes: u64,
+}
+
+impl Shaper {
+    /// Deferred packets are bounded like the bulk output queue
+    pub fn new(cfg: ShaperCfg, queue: OutputQueueCfg) -> Self {
+        Self {
+            cfg,
+            tokens: Self::capacity(&cfg),
+            updated_ms: 0,
+            deferred: BulkQueue::new(queue),
+            deferred_bytes: 0,
+        }
+    }
+
+    fn capacity(cfg: &ShaperCfg) -> i64 {
+        (cfg.burst_bytes as i64).saturating_mul(TOKEN)
+    }
+
+    pub fn refill(&mut self, now_ms: u64) {
+        let elapsed = now_ms.saturating_sub(self.updated_ms);
+        self.updated_ms = self.updated_ms.max(now_ms);
+        let gained = elapsed.saturating_mul(self.cfg.bytes_per_sec).min(i64::MAX as u64) as i64;
+        self.tokens = self.tokens.saturating_add(gained).min(Self::capacity(&self.cfg));
+    }
+
+    /// Returns the output if it can be sent now, otherwise it is deferred. The flag is false if a deferred output
+    /// was dropped because the shaper queue is full
+    pub fn shape(&mut self, now_ms: u64, out: NetOutput) -> (Option<NetOutput>, bool) {
+        self.refill(now_ms);
+        if self.deferred.is_empty() && self.fits(&out) {
+            self.spend(&out);
+            return (Some(out), true);
+        }
+        let len = output_len(&out) as u64;
+        self.deferred_bytes += len;
+        metrics::SHAPED_BYTES.add(len);
+        (None, self.deferred.push_back(out))
+    }
+
+    /// Next deferred output which fits the budget, `refill` must be called before
+    pub fn pop_ready(&mut self) -> Option<NetOutput> {
+        if !self.fits(self.deferred.front()?) {
+            return None;
+        }
+        let out = self.deferred.pop_front()?;
+        self.spend(&out);
+        Some(out)
+    }
+
+    /// Deferred output without checking the budget, used on shutdown
+    pub fn pop_front(&mut self) -> Option<NetOutput> {
+        self.deferred.pop_front()
+    }
+
+    fn fits(&self, out: &NetOutput) -> bool {
+        self.tokens >= cost(out).min(Self::capacity(&self.cfg))
+    }
+
+    fn spend(&mut self, out: &NetOutput) {
+        self.tokens -= cost(out);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deferred.is_empty()
+    }
+
+    pub fn is_blocked(&self) -> bool {
+        self.deferred.is_blocked()
+    }
+
+    /// Total bytes which had to wait for tokens
+    pub fn deferred_bytes(&self) -> u64 {
+        self.deferred_bytes
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.deferred.dropped()
+    }
+}
+
+fn cost(out: &NetOutput) -> i64 {
+    (output_len(out) as i64).saturating_mul(TOKEN)
+}
+
+/// Bytes which the transport sends for the output
+fn output_len(out: &NetOutput) -> usize {
+    match out {
+        NetOutput::UdpPacket(_, buf) | NetOutput::UdpMarked(_, _, buf) => buf.len(),
+        NetOutput::UdpPackets(pairs, buf) => pairs.len() * buf.len(),
+        NetOutput::UdpBatch(batch) => batch.iter().map(|(_, buf)| buf.len()).sum(),
+        #[cfg(feature = "vpn")]
+        NetOutput::TunPacket(buf) => buf.len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        base::Buffer,
+        data_plane::{NetOutput, NetPair, OutputQueueCfg},
+    };
+
+    use super::{Shaper, ShaperCfg};
+
+    fn packet(len: usize) -> NetOutput {
+        let pair = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        NetOutput::UdpPacket(pair, Buffer::from(vec![0; len]))
+    }
+
+    #[test]
+    fn should_send_burst_then_pace() {
+        let mut shaper = Shaper::new(
+            ShaperCfg {
+                bytes_per_sec: 10_000,
+                burst_bytes: 1000,
+            },
+            OutputQueueCfg::default(),
+        );
+        let sent = (0..5).filter(|_| shaper.shape(0, packet(500)).0.is_some()).count();
+        assert_eq!(sent, 2);
+        assert_eq!(shaper.deferred_bytes(), 1500);
+        assert!(!shaper.is_empty());
+
+        //10 bytes per ms, so one packet each 50ms
+        shaper.refill(49);
+        assert!(shaper.pop_ready().is_none());
+        shaper.refill(50);
+        assert!(shaper.pop_ready().is_some());
+        assert!(shaper.pop_ready().is_none());
+
+        //new packets wait behind the deferred ones
+        shaper.refill(90);
+        assert!(shaper.shape(100, packet(10)).0.is_none());
+        assert!(shaper.pop_ready().is_some());
+        assert!(shaper.pop_ready().is_none());
+    }
+
+    #[test]
+    fn should_pass_packet_larger_than_burst() {
+        let mut shaper = Shaper::new(
+            ShaperCfg {
+                bytes_per_sec: 1000,
+                burst_bytes: 100,
+            },
+            OutputQueueCfg::default(),
+        );
+        assert!(shaper.shape(0, packet(1000)).0.is_some());
+        //the debt of 900 bytes is paid first
+        assert!(shaper.shape(899, packet(10)).0.is_none());
+        shaper.refill(910);
+        assert!(shaper.pop_ready().is_some());
+    }
+}
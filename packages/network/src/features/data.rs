@@ -3,31 +3,80 @@ use std::{
     fmt::Debug,
 };
 
-use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_identity::{ConnId, NodeId};
 use atm0s_sdn_router::RouteRule;
 use derivative::Derivative;
 use sans_io_runtime::{collections::DynamicDeque, TaskSwitcherChild};
 use serde::{Deserialize, Serialize};
 
-use crate::base::{
-    Feature, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerInput, FeatureWorkerOutput, NetIncomingMeta, NetOutgoingMeta,
+use crate::{
+    base::{
+        ConnectionEvent, Feature, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerInput, FeatureWorkerOutput, NetIncomingMeta,
+        NetOutgoingMeta, PendingRequests,
+    },
+    data_plane::PMTU_DEFAULT,
 };
 
+use self::flow::{FlowReceiver, FlowSender};
+pub use self::flow::{FlowStats, DEFAULT_FLOW_WINDOW, FLOW_BUFFER_BYTES};
+pub use self::fragment::MAX_FRAGMENTS;
+use self::fragment::{Fragment, Reassembly};
+
+mod flow;
+mod fragment;
+
+const PING_TIMEOUT_MS: u64 = 2000;
+/// Upper bound of bytes added to fragment data on the wire: transport header with the longest route and hop list,
+/// encryption, and fragment framing
+const FRAGMENT_OVERHEAD: usize = 128;
+
 pub const FEATURE_ID: u8 = 1;
 pub const FEATURE_NAME: &str = "data_transfer";
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataCfg {
+    /// Upper limit of fragment size, None sends data as a single message. With a limit, data sent with `Control::DataSendRule`
+    /// is split into fragments which fit the path MTU of the connections, or this limit when it is smaller.
+    /// Older nodes drop fragments, so it should only be set after all nodes run a version which reassembles them
+    pub fragment_mtu: Option<usize>,
+    /// Fragmented messages which are not complete within this time are discarded by the receiver
+    pub reassembly_timeout_ms: u64,
+}
+
+impl Default for DataCfg {
+    fn default() -> Self {
+        Self {
+            fragment_mtu: None,
+            reassembly_timeout_ms: 5000,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Control {
     Ping(NodeId),
     DataListen(u16),
     DataUnlisten(u16),
     DataSendRule(u16, RouteRule, NetOutgoingMeta, Vec<u8>),
+    /// Send to a port of a node with flow control. Data is buffered while the receiver window is full
+    /// and rejected with `Event::WouldBlock` when the buffer is full
+    DataSendFlow(u16, NodeId, Vec<u8>),
+    /// Window in bytes which senders with flow control can have in flight to a local port
+    DataSetWindow(u16, u32),
+    DataFlowStats(u16, NodeId),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Event {
     Pong(NodeId, Option<u16>),
     Recv(u16, NetIncomingMeta, Vec<u8>),
+    /// Flow control buffer to the node is full and the data is dropped
+    WouldBlock(u16, NodeId),
+    /// Flow control buffer to the node has room again after a `WouldBlock`
+    Writable(u16, NodeId),
+    FlowStats(u16, NodeId, FlowStats),
+    /// Data of `DataSendRule` needs more than [`MAX_FRAGMENTS`] fragments and is dropped, with its length
+    TooLarge(u16, usize),
 }
 
 #[derive(Debug, Clone)]
@@ -41,46 +90,110 @@ enum DataMsg {
     Ping { id: u64, ts: u64, from: NodeId },
     Pong { id: u64, ts: u64 },
     Data(u16, Vec<u8>),
+    Fragment { port: u16, from: NodeId, fragment: Fragment },
+    FlowData { port: u16, from: NodeId, data: Vec<u8> },
+    Credit { port: u16, from: NodeId, consumed: u32, window: u32 },
 }
 
 pub type Output<UserData> = FeatureOutput<UserData, Event, ToWorker>;
 pub type WorkerOutput<UserData> = FeatureWorkerOutput<UserData, Control, Event, ToController>;
 
 pub struct DataFeature<UserData> {
-    waits: HashMap<u64, (u64, FeatureControlActor<UserData>, NodeId)>,
-    ping_seq: u64,
+    cfg: DataCfg,
+    pings: PendingRequests<UserData, NodeId>,
     queue: VecDeque<Output<UserData>>,
     data_dest: HashMap<u16, FeatureControlActor<UserData>>,
+    flows: HashMap<(NodeId, u16), (FeatureControlActor<UserData>, FlowSender)>,
+    flow_receiver: FlowReceiver,
+    fragment_seq: u32,
+    reassembly: Reassembly,
+    conn_mtus: HashMap<ConnId, (NodeId, usize)>,
     shutdown: bool,
 }
 
-impl<UserData> Default for DataFeature<UserData> {
-    fn default() -> Self {
+impl<UserData> DataFeature<UserData> {
+    pub fn new(cfg: DataCfg) -> Self {
         Self {
-            waits: HashMap::new(),
-            ping_seq: 0,
+            cfg,
+            pings: PendingRequests::new(PING_TIMEOUT_MS),
             queue: VecDeque::new(),
             data_dest: HashMap::new(),
+            flows: HashMap::new(),
+            flow_receiver: FlowReceiver::default(),
+            fragment_seq: 0,
+            reassembly: Reassembly::new(cfg.reassembly_timeout_ms),
+            conn_mtus: HashMap::new(),
             shutdown: false,
         }
     }
 }
 
+impl<UserData: Copy> DataFeature<UserData> {
+    /// Path MTU of the connection to the destination node without headers. Other destinations can be reached over any connection
+    /// and their later hops are unknown, so the smallest connection MTU is used. None if fragmentation is disabled
+    fn fragment_size(&self, rule: &RouteRule) -> Option<usize> {
+        let limit = self.cfg.fragment_mtu?;
+        let direct = match rule {
+            RouteRule::ToNode(dest) => self.conn_mtus.values().filter(|(node, _)| node == dest).map(|(_, mtu)| *mtu).min(),
+            _ => None,
+        };
+        let mtu = direct.or_else(|| self.conn_mtus.values().map(|(_, mtu)| *mtu).min()).unwrap_or(PMTU_DEFAULT);
+        Some(mtu.saturating_sub(FRAGMENT_OVERHEAD).min(limit).max(1))
+    }
+
+    fn send_msg(queue: &mut VecDeque<Output<UserData>>, dest: NodeId, msg: &DataMsg) {
+        let buf = bincode::serialize(msg).expect("should work");
+        queue.push_back(FeatureOutput::SendRoute(RouteRule::ToNode(dest), NetOutgoingMeta::default(), buf.into()));
+    }
+
+    /// Send what fits inside the window, then notify the actor if the buffer has room again
+    fn pump_flow(queue: &mut VecDeque<Output<UserData>>, node: NodeId, dest: NodeId, port: u16, actor: FeatureControlActor<UserData>, flow: &mut FlowSender) {
+        while let Some(data) = flow.pop_sendable() {
+            Self::send_msg(queue, dest, &DataMsg::FlowData { port, from: node, data });
+        }
+        if flow.take_writable() {
+            queue.push_back(FeatureOutput::Event(actor, Event::Writable(port, dest)));
+        }
+    }
+}
+
 impl<UserData: Copy> Feature<UserData, Control, Event, ToController, ToWorker> for DataFeature<UserData> {
-    fn on_shared_input(&mut self, _ctx: &FeatureContext, now: u64, input: FeatureSharedInput) {
+    fn on_shared_input(&mut self, ctx: &FeatureContext, now: u64, input: FeatureSharedInput) {
+        match &input {
+            FeatureSharedInput::Connection(ConnectionEvent::Connected(conn, _)) => {
+                self.conn_mtus.insert(conn.conn, (conn.node, PMTU_DEFAULT));
+            }
+            FeatureSharedInput::Connection(ConnectionEvent::Mtu(conn, mtu)) => {
+                self.conn_mtus.insert(conn.conn, (conn.node, *mtu));
+            }
+            FeatureSharedInput::Connection(ConnectionEvent::Disconnected(conn, _)) => {
+                self.conn_mtus.remove(&conn.conn);
+            }
+            _ => {}
+        }
         if let FeatureSharedInput::Tick(_) = input {
-            //clean timeout ping
-            let mut timeout_list = Vec::new();
-            for (id, (sent_ms, _, _)) in self.waits.iter() {
-                if now >= sent_ms + 2000 {
-                    timeout_list.push(*id);
-                }
+            for (actor, dest) in self.pings.pop_timeouts(now) {
+                self.queue.push_back(FeatureOutput::Event(actor, Event::Pong(dest, None)));
             }
 
-            for id in timeout_list {
-                let (_, actor, dest) = self.waits.remove(&id).expect("Should have");
-                self.queue.push_back(FeatureOutput::Event(actor, Event::Pong(dest, None)));
+            for (from, port, consumed, window) in self.flow_receiver.flush() {
+                Self::send_msg(
+                    &mut self.queue,
+                    from,
+                    &DataMsg::Credit {
+                        port,
+                        from: ctx.node_id,
+                        consumed,
+                        window,
+                    },
+                );
             }
+            for ((dest, port), (actor, flow)) in self.flows.iter_mut() {
+                flow.on_tick(now);
+                Self::pump_flow(&mut self.queue, ctx.node_id, *dest, *port, *actor, flow);
+            }
+            self.flows.retain(|_, (_, flow)| !flow.is_idle(now));
+            self.reassembly.on_tick(now);
         }
     }
 
@@ -89,9 +202,7 @@ impl<UserData: Copy> Feature<UserData, Control, Event, ToController, ToWorker> f
             FeatureInput::Control(actor, control) => match control {
                 Control::Ping(dest) => {
                     log::info!("[DataFeature] send ping to: {}", dest);
-                    let seq = self.ping_seq;
-                    self.ping_seq += 1;
-                    self.waits.insert(seq, (now_ms, actor, dest));
+                    let seq = self.pings.add(now_ms, actor, dest);
                     let msg = bincode::serialize(&DataMsg::Ping {
                         id: seq,
                         ts: now_ms,
@@ -107,10 +218,43 @@ impl<UserData: Copy> Feature<UserData, Control, Event, ToController, ToWorker> f
                 Control::DataUnlisten(port) => {
                     self.data_dest.remove(&port);
                 }
-                Control::DataSendRule(port, rule, ttl, data) => {
-                    let data = DataMsg::Data(port, data);
-                    let msg = bincode::serialize(&data).expect("should work");
-                    self.queue.push_back(FeatureOutput::SendRoute(rule, ttl, msg.into()));
+                Control::DataSendRule(port, rule, meta, data) => {
+                    let fragment_size = match self.fragment_size(&rule) {
+                        Some(size) if data.len() > size => size,
+                        _ => {
+                            let msg = bincode::serialize(&DataMsg::Data(port, data)).expect("should work");
+                            self.queue.push_back(FeatureOutput::SendRoute(rule, meta, msg.into()));
+                            return;
+                        }
+                    };
+                    let id = self.fragment_seq;
+                    self.fragment_seq = self.fragment_seq.wrapping_add(1);
+                    let Some(fragments) = fragment::split(id, &data, fragment_size) else {
+                        log::warn!("[DataFeature] data len {} need more than {MAX_FRAGMENTS} fragments, drop", data.len());
+                        self.queue.push_back(FeatureOutput::Event(actor, Event::TooLarge(port, data.len())));
+                        return;
+                    };
+                    //each fragment is routed as its own packet
+                    for fragment in fragments {
+                        let msg = bincode::serialize(&DataMsg::Fragment { port, from: ctx.node_id, fragment }).expect("should work");
+                        self.queue.push_back(FeatureOutput::SendRoute(rule.clone(), meta.clone(), msg.into()));
+                    }
+                }
+                Control::DataSendFlow(port, dest, data) => {
+                    let (flow_actor, flow) = self.flows.entry((dest, port)).or_insert_with(|| (actor, FlowSender::new(now_ms)));
+                    *flow_actor = actor;
+                    if !flow.push(data) {
+                        log::debug!("[DataFeature] flow to {dest}:{port} buffer full, reject data");
+                        self.queue.push_back(FeatureOutput::Event(actor, Event::WouldBlock(port, dest)));
+                    }
+                    Self::pump_flow(&mut self.queue, ctx.node_id, dest, port, actor, flow);
+                }
+                Control::DataSetWindow(port, window) => {
+                    self.flow_receiver.set_window(port, window);
+                }
+                Control::DataFlowStats(port, dest) => {
+                    let stats = self.flows.get(&(dest, port)).map(|(_, flow)| flow.stats()).unwrap_or_default();
+                    self.queue.push_back(FeatureOutput::Event(actor, Event::FlowStats(port, dest, stats)));
                 }
             },
             FeatureInput::Net(_, meta, buf) | FeatureInput::Local(meta, buf) => {
@@ -118,7 +262,7 @@ impl<UserData: Copy> Feature<UserData, Control, Event, ToController, ToWorker> f
                 if let Ok(msg) = bincode::deserialize::<DataMsg>(&buf) {
                     match msg {
                         DataMsg::Pong { id, ts } => {
-                            if let Some((_, actor, dest)) = self.waits.remove(&id) {
+                            if let Some((actor, dest, _)) = self.pings.take(id) {
                                 self.queue.push_back(FeatureOutput::Event(actor, Event::Pong(dest, Some((now_ms - ts) as u16))));
                             } else {
                                 log::warn!("[DataFeature] pong with unknown id: {}", id);
@@ -135,6 +279,46 @@ impl<UserData: Copy> Feature<UserData, Control, Event, ToController, ToWorker> f
                                 self.queue.push_back(FeatureOutput::Event(*actor, Event::Recv(port, meta, data)));
                             }
                         }
+                        DataMsg::Fragment { port, from, fragment } => {
+                            if let Some(data) = self.reassembly.on_fragment(now_ms, from, port, fragment) {
+                                if let Some(actor) = self.data_dest.get(&port) {
+                                    self.queue.push_back(FeatureOutput::Event(*actor, Event::Recv(port, meta, data)));
+                                }
+                            }
+                        }
+                        DataMsg::FlowData { port, from, data } => {
+                            //credits are given back even without listener, otherwise the sender would stall
+                            if let Some((consumed, window)) = self.flow_receiver.on_data(from, port, data.len()) {
+                                Self::send_msg(
+                                    &mut self.queue,
+                                    from,
+                                    &DataMsg::Credit {
+                                        port,
+                                        from: ctx.node_id,
+                                        consumed,
+                                        window,
+                                    },
+                                );
+                            }
+                            if let Some(actor) = self.data_dest.get(&port) {
+                                self.queue.push_back(FeatureOutput::Event(*actor, Event::Recv(port, meta, data)));
+                            }
+                        }
+                        DataMsg::Credit { port, from, consumed, window } => {
+                            if let Some((actor, flow)) = self.flows.get_mut(&(from, port)) {
+                                flow.on_credit(now_ms, consumed, window);
+                                Self::pump_flow(&mut self.queue, ctx.node_id, from, port, *actor, flow);
+                            }
+                        }
+                    }
+                }
+            }
+            FeatureInput::Undeliverable(_, buf) => {
+                //unreachable ping is answered now instead of waiting for its timeout
+                if let Ok(DataMsg::Ping { id, .. }) = bincode::deserialize::<DataMsg>(&buf) {
+                    if let Some((actor, dest, _)) = self.pings.take(id) {
+                        log::debug!("[DataFeature] ping to {dest} is undeliverable");
+                        self.queue.push_back(FeatureOutput::Event(actor, Event::Pong(dest, None)));
                     }
                 }
             }
@@ -205,3 +389,95 @@ impl<UserData> TaskSwitcherChild<WorkerOutput<UserData>> for DataFeatureWorker<U
         self.queue.pop_front()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use atm0s_sdn_identity::ConnId;
+    use atm0s_sdn_router::RouteRule;
+    use sans_io_runtime::TaskSwitcherChild;
+
+    use crate::{
+        base::{ConnectionCtx, ConnectionEvent, DisconnectReason, Feature, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput, NetOutgoingMeta},
+        data_plane::{NetPair, PMTU_MAX},
+    };
+
+    use super::{Control, DataCfg, DataFeature, Event, MAX_FRAGMENTS};
+
+    fn sent_packets(feature: &mut DataFeature<()>, ctx: &FeatureContext, data_len: usize) -> Vec<usize> {
+        let control = Control::DataSendRule(1, RouteRule::ToNode(2), NetOutgoingMeta::default(), vec![0; data_len]);
+        feature.on_input(ctx, 0, FeatureInput::Control(FeatureControlActor::Controller(()), control));
+        let mut sizes = vec![];
+        while let Some(out) = feature.pop_output(0) {
+            match out {
+                FeatureOutput::SendRoute(RouteRule::ToNode(2), _, buf) => sizes.push(buf.len()),
+                out => panic!("unexpected output {out:?}"),
+            }
+        }
+        sizes
+    }
+
+    #[test]
+    fn fragment_size_should_follow_path_mtu() {
+        let ctx = FeatureContext { node_id: 1, session: 0 };
+        let mut feature = DataFeature::new(DataCfg {
+            fragment_mtu: Some(1400),
+            ..Default::default()
+        });
+        //without a probed connection the conservative default is used
+        assert_eq!(sent_packets(&mut feature, &ctx, 2500).len(), 3);
+
+        let conn = ConnectionCtx {
+            conn: ConnId::from_out(0, 1),
+            node: 2,
+            pair: NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair"),
+            features: Default::default(),
+            codec: Default::default(),
+            peer_identity: None,
+        };
+        feature.on_shared_input(&ctx, 0, FeatureSharedInput::Connection(ConnectionEvent::Mtu(conn.clone(), PMTU_MAX)));
+        let sizes = sent_packets(&mut feature, &ctx, 2500);
+        assert_eq!(sizes.len(), 2);
+        //transport header and encryption still fit the path
+        assert!(sizes.iter().all(|size| *size + 64 <= PMTU_MAX), "{sizes:?}");
+
+        //configured limit still caps the fragments
+        let mut feature = DataFeature::new(DataCfg {
+            fragment_mtu: Some(500),
+            ..Default::default()
+        });
+        feature.on_shared_input(&ctx, 0, FeatureSharedInput::Connection(ConnectionEvent::Mtu(conn.clone(), PMTU_MAX)));
+        assert_eq!(sent_packets(&mut feature, &ctx, 2500).len(), 5);
+
+        feature.on_shared_input(&ctx, 0, FeatureSharedInput::Connection(ConnectionEvent::Disconnected(conn, DisconnectReason::Timeout)));
+        assert_eq!(sent_packets(&mut feature, &ctx, 400).len(), 1);
+    }
+
+    #[test]
+    fn fragmentation_should_be_opt_in() {
+        let ctx = FeatureContext { node_id: 1, session: 0 };
+        let mut feature = DataFeature::new(DataCfg::default());
+        let sizes = sent_packets(&mut feature, &ctx, 2500);
+        assert_eq!(sizes.len(), 1);
+        assert!(sizes[0] > 2500, "{sizes:?}");
+
+        let mut feature = DataFeature::new(DataCfg {
+            fragment_mtu: Some(1000),
+            ..Default::default()
+        });
+        assert_eq!(sent_packets(&mut feature, &ctx, 2500).len(), 3);
+    }
+
+    #[test]
+    fn oversize_data_should_be_rejected() {
+        let ctx = FeatureContext { node_id: 1, session: 0 };
+        let mut feature = DataFeature::new(DataCfg {
+            fragment_mtu: Some(10),
+            ..Default::default()
+        });
+        let len = MAX_FRAGMENTS as usize * 10 + 1;
+        let control = Control::DataSendRule(1, RouteRule::ToNode(2), NetOutgoingMeta::default(), vec![0; len]);
+        feature.on_input(&ctx, 0, FeatureInput::Control(FeatureControlActor::Controller(()), control));
+        assert!(matches!(feature.pop_output(0), Some(FeatureOutput::Event(FeatureControlActor::Controller(()), Event::TooLarge(1, l))) if l == len));
+        assert!(feature.pop_output(0).is_none());
+    }
+}
@@ -0,0 +1,215 @@
+//! Credit based flow control for data sent with `Control::DataSendFlow`.
+//!
+//! The sender keeps at most `window` bytes in flight for each destination and port. The receiver gives credits back
+//! for delivered bytes together with its window, and data above the window is buffered up to `FLOW_BUFFER_BYTES`.
+
+use std::collections::{HashMap, VecDeque};
+
+use atm0s_sdn_identity::NodeId;
+
+/// Window used by a sender until the receiver advertises its own
+pub const DEFAULT_FLOW_WINDOW: u32 = 16 * 1024;
+/// Bytes buffered by a sender while it has no window, more data is rejected
+pub const FLOW_BUFFER_BYTES: usize = 128 * 1024;
+/// In flight bytes without any credit back within this time are assumed lost
+pub const FLOW_STALL_MS: u64 = 2000;
+const FLOW_IDLE_MS: u64 = 30_000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FlowStats {
+    pub window: u32,
+    pub in_flight: u32,
+    pub buffered: usize,
+}
+
+pub struct FlowSender {
+    window: u32,
+    in_flight: u32,
+    buffer: VecDeque<Vec<u8>>,
+    buffered: usize,
+    blocked: bool,
+    last_progress_ms: u64,
+}
+
+impl FlowSender {
+    pub fn new(now_ms: u64) -> Self {
+        Self {
+            window: DEFAULT_FLOW_WINDOW,
+            in_flight: 0,
+            buffer: VecDeque::new(),
+            buffered: 0,
+            blocked: false,
+            last_progress_ms: now_ms,
+        }
+    }
+
+    /// Queue data for sending, false if the buffer is full and the data is dropped
+    pub fn push(&mut self, data: Vec<u8>) -> bool {
+        if self.buffered + data.len() > FLOW_BUFFER_BYTES {
+            self.blocked = true;
+            return false;
+        }
+        self.buffered += data.len();
+        self.buffer.push_back(data);
+        true
+    }
+
+    /// Next data which fits inside the window, data bigger than the whole window is sent only when nothing is in flight
+    pub fn pop_sendable(&mut self) -> Option<Vec<u8>> {
+        let len = self.buffer.front()?.len() as u32;
+        if self.in_flight > 0 && self.in_flight + len > self.window {
+            return None;
+        }
+        self.in_flight += len;
+        self.buffered -= len as usize;
+        self.buffer.pop_front()
+    }
+
+    pub fn on_credit(&mut self, now_ms: u64, consumed: u32, window: u32) {
+        self.in_flight = self.in_flight.saturating_sub(consumed);
+        self.window = window;
+        self.last_progress_ms = now_ms;
+    }
+
+    /// Forget in flight bytes of a stalled flow, their data or credits are lost
+    pub fn on_tick(&mut self, now_ms: u64) {
+        if self.in_flight > 0 && now_ms >= self.last_progress_ms + FLOW_STALL_MS {
+            log::warn!("[FlowSender] no credit for {} in flight bytes, assume lost", self.in_flight);
+            self.in_flight = 0;
+            self.last_progress_ms = now_ms;
+        }
+    }
+
+    /// True once after data was rejected and the buffer is at most half full again
+    pub fn take_writable(&mut self) -> bool {
+        if self.blocked && self.buffered <= FLOW_BUFFER_BYTES / 2 {
+            self.blocked = false;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn is_idle(&self, now_ms: u64) -> bool {
+        self.in_flight == 0 && self.buffer.is_empty() && now_ms >= self.last_progress_ms + FLOW_IDLE_MS
+    }
+
+    pub fn stats(&self) -> FlowStats {
+        FlowStats {
+            window: self.window,
+            in_flight: self.in_flight,
+            buffered: self.buffered,
+        }
+    }
+}
+
+/// Credits owed to senders, they are returned when a quarter of the window is consumed or on tick
+#[derive(Default)]
+pub struct FlowReceiver {
+    windows: HashMap<u16, u32>,
+    pending: HashMap<(NodeId, u16), u32>,
+}
+
+impl FlowReceiver {
+    pub fn set_window(&mut self, port: u16, window: u32) {
+        self.windows.insert(port, window);
+    }
+
+    pub fn window(&self, port: u16) -> u32 {
+        self.windows.get(&port).copied().unwrap_or(DEFAULT_FLOW_WINDOW)
+    }
+
+    /// Return (consumed, window) credit which should be sent back to the sender now
+    pub fn on_data(&mut self, from: NodeId, port: u16, len: usize) -> Option<(u32, u32)> {
+        let window = self.window(port);
+        let pending = self.pending.entry((from, port)).or_default();
+        *pending += len as u32;
+        if *pending >= window / 4 {
+            let consumed = *pending;
+            self.pending.remove(&(from, port));
+            Some((consumed, window))
+        } else {
+            None
+        }
+    }
+
+    /// All owed credits as (sender, port, consumed, window)
+    pub fn flush(&mut self) -> Vec<(NodeId, u16, u32, u32)> {
+        let pending = std::mem::take(&mut self.pending);
+        pending.into_iter().map(|((from, port), consumed)| (from, port, consumed, self.window(port))).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FlowReceiver, FlowSender, FlowStats, DEFAULT_FLOW_WINDOW, FLOW_BUFFER_BYTES, FLOW_STALL_MS};
+
+    #[test]
+    fn sender_keep_in_flight_inside_window() {
+        let mut sender = FlowSender::new(0);
+        for _ in 0..3 {
+            assert!(sender.push(vec![0; 6000]));
+        }
+        assert!(sender.pop_sendable().is_some());
+        assert!(sender.pop_sendable().is_some());
+        //third one would exceed the 16KB window
+        assert_eq!(sender.pop_sendable(), None);
+        assert_eq!(
+            sender.stats(),
+            FlowStats {
+                window: DEFAULT_FLOW_WINDOW,
+                in_flight: 12000,
+                buffered: 6000
+            }
+        );
+
+        sender.on_credit(10, 6000, 4000);
+        //smaller advertised window still allow one message when nothing else in flight
+        assert_eq!(sender.pop_sendable(), None);
+        sender.on_credit(20, 6000, 4000);
+        assert!(sender.pop_sendable().is_some());
+        assert_eq!(sender.stats().in_flight, 6000);
+    }
+
+    #[test]
+    fn sender_reject_when_buffer_full() {
+        let mut sender = FlowSender::new(0);
+        let chunk = FLOW_BUFFER_BYTES / 4;
+        for _ in 0..4 {
+            assert!(sender.push(vec![0; chunk]));
+        }
+        assert!(!sender.push(vec![0; 1]));
+        assert!(!sender.take_writable());
+
+        sender.pop_sendable();
+        sender.on_credit(10, chunk as u32, DEFAULT_FLOW_WINDOW);
+        sender.pop_sendable();
+        assert!(sender.take_writable());
+        assert!(!sender.take_writable());
+    }
+
+    #[test]
+    fn sender_reset_stalled_flow() {
+        let mut sender = FlowSender::new(0);
+        sender.push(vec![0; 1000]);
+        sender.pop_sendable();
+        sender.on_tick(FLOW_STALL_MS - 1);
+        assert_eq!(sender.stats().in_flight, 1000);
+        sender.on_tick(FLOW_STALL_MS);
+        assert_eq!(sender.stats().in_flight, 0);
+    }
+
+    #[test]
+    fn receiver_batch_credits() {
+        let mut receiver = FlowReceiver::default();
+        receiver.set_window(1, 4000);
+        assert_eq!(receiver.on_data(2, 1, 600), None);
+        assert_eq!(receiver.on_data(2, 1, 600), Some((1200, 4000)));
+        assert_eq!(receiver.on_data(2, 1, 100), None);
+        assert_eq!(receiver.on_data(3, 2, 100), None);
+        let mut flushed = receiver.flush();
+        flushed.sort();
+        assert_eq!(flushed, vec![(2, 1, 100, 4000), (3, 2, 100, DEFAULT_FLOW_WINDOW)]);
+        assert_eq!(receiver.flush(), vec![]);
+    }
+}
@@ -0,0 +1,164 @@
+//! Fragmentation of data which is bigger than the configured MTU.
+//!
+//! Each fragment carries the message id with its index and the fragment count, the receiver keeps the parts until all
+//! of them arrived in any order. Incomplete messages are discarded after the reassembly timeout.
+
+use std::collections::HashMap;
+
+use atm0s_sdn_identity::NodeId;
+use serde::{Deserialize, Serialize};
+
+/// Messages needing more fragments are rejected by the sender and ignored by the receiver
+pub const MAX_FRAGMENTS: u16 = 256;
+/// Incomplete messages kept at the same time, new ones are ignored above this
+const MAX_PARTIALS: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fragment {
+    pub id: u32,
+    pub index: u16,
+    pub count: u16,
+    pub data: Vec<u8>,
+}
+
+/// Split data of message `id` into MTU sized fragments, None if it would need more than `MAX_FRAGMENTS`
+pub fn split(id: u32, data: &[u8], mtu: usize) -> Option<Vec<Fragment>> {
+    let mtu = mtu.max(1);
+    let count = data.len().div_ceil(mtu);
+    if count > MAX_FRAGMENTS as usize {
+        return None;
+    }
+    let fragments = data.chunks(mtu).enumerate().map(|(index, chunk)| Fragment {
+        id,
+        index: index as u16,
+        count: count as u16,
+        data: chunk.to_vec(),
+    });
+    Some(fragments.collect())
+}
+
+struct Partial {
+    started_ms: u64,
+    received: usize,
+    parts: Vec<Option<Vec<u8>>>,
+}
+
+pub struct Reassembly {
+    timeout_ms: u64,
+    partials: HashMap<(NodeId, u16, u32), Partial>,
+}
+
+impl Reassembly {
+    pub fn new(timeout_ms: u64) -> Self {
+        Self { timeout_ms, partials: HashMap::new() }
+    }
+
+    /// Store a fragment sent from `from` to `port`, returns the whole data once the last missing part arrived
+    pub fn on_fragment(&mut self, now_ms: u64, from: NodeId, port: u16, fragment: Fragment) -> Option<Vec<u8>> {
+        let Fragment { id, index, count, data } = fragment;
+        if index >= count || count > MAX_FRAGMENTS {
+            log::warn!("[Reassembly] invalid fragment {index}/{count} of msg {id} from {from}");
+            return None;
+        }
+        let key = (from, port, id);
+        if !self.partials.contains_key(&key) && self.partials.len() >= MAX_PARTIALS {
+            log::warn!("[Reassembly] too many incomplete messages, drop fragment of msg {id} from {from}");
+            return None;
+        }
+        let partial = self.partials.entry(key).or_insert_with(|| Partial {
+            started_ms: now_ms,
+            received: 0,
+            parts: vec![None; count as usize],
+        });
+        if partial.parts.len() != count as usize {
+            log::warn!("[Reassembly] fragment count mismatch for msg {id} from {from}");
+            return None;
+        }
+        let slot = &mut partial.parts[index as usize];
+        if slot.is_none() {
+            *slot = Some(data);
+            partial.received += 1;
+        }
+        if partial.received < partial.parts.len() {
+            return None;
+        }
+        let partial = self.partials.remove(&key)?;
+        Some(partial.parts.into_iter().flatten().flatten().collect())
+    }
+
+    /// Discard incomplete messages older than the timeout, returns how many were discarded
+    pub fn on_tick(&mut self, now_ms: u64) -> usize {
+        let before = self.partials.len();
+        let timeout_ms = self.timeout_ms;
+        self.partials.retain(|(from, _, id), partial| {
+            let alive = now_ms < partial.started_ms + timeout_ms;
+            if !alive {
+                log::debug!("[Reassembly] msg {id} from {from} timeout with {}/{} fragments", partial.received, partial.parts.len());
+            }
+            alive
+        });
+        before - self.partials.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{split, Fragment, Reassembly, MAX_FRAGMENTS};
+
+    fn frag(id: u32, index: u16, count: u16, data: Vec<u8>) -> Fragment {
+        Fragment { id, index, count, data }
+    }
+
+    #[test]
+    fn split_by_mtu() {
+        let data: Vec<u8> = (0..250).map(|i| i as u8).collect();
+        let parts = split(5, &data, 100).expect("Should split");
+        assert_eq!(
+            parts.iter().map(|p| (p.id, p.index, p.count, p.data.len())).collect::<Vec<_>>(),
+            vec![(5, 0, 3, 100), (5, 1, 3, 100), (5, 2, 3, 50)]
+        );
+        assert_eq!(parts.into_iter().flat_map(|p| p.data).collect::<Vec<_>>(), data);
+        assert_eq!(split(5, &[], 100), Some(vec![]));
+        assert_eq!(split(5, &vec![0; 100 * MAX_FRAGMENTS as usize + 1], 100), None);
+    }
+
+    #[test]
+    fn reassemble_out_of_order() {
+        let data: Vec<u8> = (0..250).map(|i| i as u8).collect();
+        let parts = split(5, &data, 100).expect("Should split");
+        let mut reassembly = Reassembly::new(1000);
+        assert_eq!(reassembly.on_fragment(0, 1, 10, parts[2].clone()), None);
+        assert_eq!(reassembly.on_fragment(1, 1, 10, parts[0].clone()), None);
+        //duplicated fragment is ignored
+        assert_eq!(reassembly.on_fragment(2, 1, 10, parts[0].clone()), None);
+        //same id from other node is another message
+        assert_eq!(reassembly.on_fragment(2, 2, 10, parts[1].clone()), None);
+        assert_eq!(reassembly.on_fragment(3, 1, 10, parts[1].clone()), Some(data));
+        assert_eq!(reassembly.on_tick(10_000), 1);
+    }
+
+    #[test]
+    fn lost_last_fragment_timeout() {
+        let parts = split(1, &[1; 250], 100).expect("Should split");
+        let mut reassembly = Reassembly::new(1000);
+        assert_eq!(reassembly.on_fragment(0, 1, 10, parts[0].clone()), None);
+        assert_eq!(reassembly.on_fragment(10, 1, 10, parts[1].clone()), None);
+        assert_eq!(reassembly.on_tick(999), 0);
+        assert_eq!(reassembly.on_tick(1000), 1);
+
+        //the late last fragment alone cannot complete the message
+        assert_eq!(reassembly.on_fragment(1100, 1, 10, parts[2].clone()), None);
+        assert_eq!(reassembly.on_tick(2100), 1);
+    }
+
+    #[test]
+    fn reject_invalid_fragment() {
+        let mut reassembly = Reassembly::new(1000);
+        assert_eq!(reassembly.on_fragment(0, 1, 10, frag(1, 3, 3, vec![1])), None);
+        assert_eq!(reassembly.on_fragment(0, 1, 10, frag(1, 0, MAX_FRAGMENTS + 1, vec![1])), None);
+        assert_eq!(reassembly.on_tick(10_000), 0);
+        assert_eq!(reassembly.on_fragment(0, 1, 10, frag(1, 0, 2, vec![1])), None);
+        assert_eq!(reassembly.on_fragment(0, 1, 10, frag(1, 1, 3, vec![1])), None);
+        assert_eq!(reassembly.on_fragment(0, 1, 10, frag(1, 1, 2, vec![2])), Some(vec![1, 2]));
+    }
+}
@@ -1,4 +1,5 @@
 use atm0s_sdn_router::RouteRule;
+use serde::{Deserialize, Serialize};
 use std::{
     collections::{HashMap, VecDeque},
     fmt::Debug,
@@ -6,13 +7,17 @@ use std::{
 
 use crate::base::FeatureControlActor;
 
-use self::map::{LocalMap, LocalMapOutput};
+use self::map::{LocalMap, LocalMapOutput, SnapshotSlot};
 
 const MAP_GET_TIMEOUT_MS: u64 = 5000;
+/// Digest is sent a bit after a new connection, when routes through it are synced
+const RECONNECT_DIGEST_DELAY_MS: u64 = 100;
+/// Bumped when the snapshot format changes, older snapshots are rejected
+const SNAPSHOT_VERSION: u8 = 1;
 
 use super::{
-    msg::{ClientCommand, NodeSession, ServerEvent},
-    Control, Event, Map,
+    msg::{ClientCommand, ClientMapCommand, NodeSession, ServerEvent},
+    Control, Event, GetError, Map, SnapshotError,
 };
 
 mod map;
@@ -21,6 +26,18 @@ fn route(key: Map) -> RouteRule {
     RouteRule::ToKey(key.0 as u32)
 }
 
+/// Routes to replicas other than the relay, each one is the closest node to a key derived from the map key,
+/// so they only depend on the key and every hop picks the same replica
+fn replica_routes(key: Map, replicas: usize) -> impl Iterator<Item = RouteRule> {
+    (1..replicas).map(move |replica| RouteRule::ToKeyReplica(key.0 as u32, replica as u8))
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Snapshot {
+    version: u8,
+    maps: Vec<(Map, Vec<SnapshotSlot>)>,
+}
+
 pub enum LocalStorageOutput<UserData> {
     Local(FeatureControlActor<UserData>, Event),
     Remote(RouteRule, ClientCommand),
@@ -29,28 +46,50 @@ pub enum LocalStorageOutput<UserData> {
 pub struct LocalStorage<UserData> {
     session: NodeSession,
     maps: HashMap<Map, LocalMap<UserData>>,
-    map_get_waits: HashMap<(Map, u64), (FeatureControlActor<UserData>, u64)>,
+    replication_factor: usize,
+    /// Actor, started time and replica which is asked now
+    map_get_waits: HashMap<(Map, u64), (FeatureControlActor<UserData>, u64, usize)>,
+    map_scan_waits: HashMap<(Map, u64), (FeatureControlActor<UserData>, u64)>,
+    digest_at: Option<u64>,
     queue: VecDeque<LocalStorageOutput<UserData>>,
     req_id_seed: u64,
 }
 
 impl<UserData: Eq + Debug + Copy> LocalStorage<UserData> {
-    pub fn new(session: NodeSession) -> Self {
+    pub fn new(session: NodeSession, replication_factor: usize, req_id_seed: u64) -> Self {
         Self {
             session,
             maps: HashMap::new(),
+            replication_factor,
             map_get_waits: HashMap::new(),
+            map_scan_waits: HashMap::new(),
+            digest_at: None,
             queue: VecDeque::new(),
-            req_id_seed: 0,
+            req_id_seed,
         }
     }
 
+    pub fn maps(&self) -> usize {
+        self.maps.len()
+    }
+
+    pub fn has_pending_sync(&self) -> bool {
+        self.maps.values().any(|map| map.has_pending_sync())
+    }
+
     pub fn on_tick(&mut self, now: u64) {
         // tick all maps and finding out if any of them should be removed
+        let reconnect = self.digest_at.is_some_and(|at| now >= at);
+        if reconnect {
+            self.digest_at = None;
+        }
         let mut to_remove = vec![];
         for (key, map) in self.maps.iter_mut() {
+            if reconnect {
+                map.on_reconnect(now);
+            }
             map.on_tick(now);
-            Self::pop_map_actions(*key, map, &mut self.queue);
+            Self::pop_map_actions(*key, map, self.replication_factor, &mut self.queue);
             if map.should_cleanup() {
                 to_remove.push(*key);
             }
@@ -71,6 +110,24 @@ impl<UserData: Eq + Debug + Copy> LocalStorage<UserData> {
         for key in to_remove {
             self.map_get_waits.remove(&key);
         }
+
+        let mut to_remove = vec![];
+        for (key, info) in self.map_scan_waits.iter() {
+            if now >= info.1 + MAP_GET_TIMEOUT_MS {
+                to_remove.push(*key);
+            }
+        }
+
+        for key in to_remove {
+            if let Some((actor, _)) = self.map_scan_waits.remove(&key) {
+                self.queue.push_back(LocalStorageOutput::Local(actor, Event::MapScanRes(key.0, Err(GetError::Timeout))));
+            }
+        }
+    }
+
+    /// Schedule an anti-entropy digest of all maps, connections which come together are handled by one digest
+    pub fn on_connected(&mut self, now: u64) {
+        self.digest_at.get_or_insert(now + RECONNECT_DIGEST_DELAY_MS);
     }
 
     pub fn on_local(&mut self, now: u64, actor: FeatureControlActor<UserData>, control: Control) {
@@ -78,35 +135,105 @@ impl<UserData: Eq + Debug + Copy> LocalStorage<UserData> {
             Control::MapCmd(key, control) => {
                 if let Some(map) = Self::get_map(&mut self.maps, self.session, key, control.is_creator()) {
                     if let Some(event) = map.on_control(now, actor, control) {
-                        self.queue.push_back(LocalStorageOutput::Remote(route(key), ClientCommand::MapCmd(key, event)));
+                        Self::push_map_cmd(self.replication_factor, key, event, &mut self.queue);
                     }
-                    Self::pop_map_actions(key, map, &mut self.queue);
+                    Self::pop_map_actions(key, map, self.replication_factor, &mut self.queue);
+                }
+            }
+            Control::MapScan { key, prefix, limit, cursor } => {
+                let req_id = self.req_id_seed;
+                self.req_id_seed += 1;
+                self.map_scan_waits.insert((key, req_id), (actor, now));
+                self.queue.push_back(LocalStorageOutput::Remote(route(key), ClientCommand::MapScan(key, req_id, prefix, limit, cursor)));
+            }
+            Control::MapCmdCas {
+                key,
+                sub_key,
+                expected_version,
+                value,
+            } => {
+                let map = Self::get_map(&mut self.maps, self.session, key, true).expect("Must have map with auto_create");
+                if let Some(event) = map.on_cas(now, actor, sub_key, expected_version, value) {
+                    Self::push_map_cmd(self.replication_factor, key, event, &mut self.queue);
                 }
+                Self::pop_map_actions(key, map, self.replication_factor, &mut self.queue);
             }
             Control::MapGet(key) => {
                 let req_id = self.req_id_seed;
                 self.req_id_seed += 1;
-                self.map_get_waits.insert((key, req_id), (actor, req_id));
+                self.map_get_waits.insert((key, req_id), (actor, now, 0));
                 self.queue.push_back(LocalStorageOutput::Remote(route(key), ClientCommand::MapGet(key, req_id)));
             }
+            Control::ExportSnapshot => {
+                let snapshot = self.export_snapshot(now);
+                self.queue.push_back(LocalStorageOutput::Local(actor, Event::Snapshot(snapshot)));
+            }
+            Control::ImportSnapshot(bytes) => {
+                let res = self.import_snapshot(now, &bytes);
+                self.queue.push_back(LocalStorageOutput::Local(actor, Event::SnapshotImported(res)));
+            }
         }
     }
 
+    /// Sub-keys which this node has set, with their versions and remaining ttl
+    pub fn export_snapshot(&self, now: u64) -> Vec<u8> {
+        let mut maps: Vec<(Map, Vec<SnapshotSlot>)> = self.maps.iter().map(|(key, map)| (*key, map.export_slots(now))).filter(|(_, slots)| !slots.is_empty()).collect();
+        maps.sort_by_key(|(key, _)| key.0);
+        let snapshot = Snapshot { version: SNAPSHOT_VERSION, maps };
+        bincode::serialize(&snapshot).expect("Should serialize snapshot")
+    }
+
+    /// Restore sub-keys with their versions, so relays and replicas don't take them as older than their copy.
+    /// Sub-keys which are already newer here are kept. Each restored map sends a digest, so relays which miss
+    /// the sub-keys or have an older version ask for them, and relays which advanced keep their newer version
+    pub fn import_snapshot(&mut self, now: u64, bytes: &[u8]) -> Result<usize, SnapshotError> {
+        let snapshot: Snapshot = bincode::deserialize(bytes).map_err(|_| SnapshotError::Decode)?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::Version(snapshot.version));
+        }
+        let mut restored = 0;
+        for (key, slots) in snapshot.maps {
+            let map = Self::get_map(&mut self.maps, self.session, key, true).expect("Must have map with auto_create");
+            for slot in slots {
+                if map.import_slot(now, slot) {
+                    restored += 1;
+                }
+            }
+            map.on_reconnect(now);
+            Self::pop_map_actions(key, map, self.replication_factor, &mut self.queue);
+        }
+        log::info!("[DhtKvClient] Restored {restored} sub-keys from snapshot");
+        Ok(restored)
+    }
+
     pub fn on_server(&mut self, now: u64, remote: NodeSession, cmd: ServerEvent) {
         match cmd {
             ServerEvent::MapEvent(key, cmd) => {
                 if let Some(map) = self.maps.get_mut(&key) {
                     if let Some(cmd) = map.on_server(now, remote, cmd) {
-                        self.queue.push_back(LocalStorageOutput::Remote(route(key), ClientCommand::MapCmd(key, cmd)));
+                        Self::push_map_cmd(self.replication_factor, key, cmd, &mut self.queue);
                     }
-                    Self::pop_map_actions(key, map, &mut self.queue);
+                    Self::pop_map_actions(key, map, self.replication_factor, &mut self.queue);
                 } else {
                     log::warn!("Received remote command for unknown map: {:?}", key);
                 }
             }
             ServerEvent::MapGetRes(key, req_id, res) => {
-                if let Some((actor, _time_ms)) = self.map_get_waits.remove(&(key, req_id)) {
-                    self.queue.push_back(LocalStorageOutput::Local(actor, Event::MapGetRes(key, Ok(res))));
+                if let Some((actor, time_ms, replica)) = self.map_get_waits.remove(&(key, req_id)) {
+                    if res.is_empty() && replica + 1 < self.replication_factor {
+                        //node which has the key now can be a new one without the data, the next replica is asked
+                        let replica = replica + 1;
+                        self.map_get_waits.insert((key, req_id), (actor, time_ms, replica));
+                        self.queue
+                            .push_back(LocalStorageOutput::Remote(RouteRule::ToKeyReplica(key.0 as u32, replica as u8), ClientCommand::MapGet(key, req_id)));
+                    } else {
+                        self.queue.push_back(LocalStorageOutput::Local(actor, Event::MapGetRes(key, Ok(res))));
+                    }
+                }
+            }
+            ServerEvent::MapScanRes(key, req_id, res, next) => {
+                if let Some((actor, _time_ms)) = self.map_scan_waits.remove(&(key, req_id)) {
+                    self.queue.push_back(LocalStorageOutput::Local(actor, Event::MapScanRes(key, Ok((res, next)))));
                 }
             }
         }
@@ -124,12 +251,133 @@ impl<UserData: Eq + Debug + Copy> LocalStorage<UserData> {
         maps.get_mut(&key)
     }
 
-    fn pop_map_actions(key: Map, map: &mut LocalMap<UserData>, queue: &mut VecDeque<LocalStorageOutput<UserData>>) {
+    fn pop_map_actions(key: Map, map: &mut LocalMap<UserData>, replicas: usize, queue: &mut VecDeque<LocalStorageOutput<UserData>>) {
         while let Some(out) = map.pop_action() {
-            queue.push_back(match out {
-                LocalMapOutput::Local(actor, event) => LocalStorageOutput::Local(actor, Event::MapEvent(key, event)),
-                LocalMapOutput::Remote(cmd) => LocalStorageOutput::Remote(route(key), ClientCommand::MapCmd(key, cmd)),
-            });
+            match out {
+                LocalMapOutput::Local(actor, event) => queue.push_back(LocalStorageOutput::Local(actor, Event::MapEvent(key, event))),
+                LocalMapOutput::Remote(cmd) => Self::push_map_cmd(replicas, key, cmd, queue),
+                LocalMapOutput::CasRes(actor, sub_key, res) => queue.push_back(LocalStorageOutput::Local(actor, Event::MapCasRes(key, sub_key, res))),
+            }
+        }
+    }
+
+    fn push_map_cmd(replicas: usize, key: Map, cmd: ClientMapCommand, queue: &mut VecDeque<LocalStorageOutput<UserData>>) {
+        if cmd.is_replicated() {
+            for rule in replica_routes(key, replicas) {
+                queue.push_back(LocalStorageOutput::Remote(rule, ClientCommand::MapCmd(key, cmd.clone())));
+            }
+        }
+        queue.push_back(LocalStorageOutput::Remote(route(key), ClientCommand::MapCmd(key, cmd)));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use atm0s_sdn_router::RouteRule;
+
+    use crate::{
+        base::FeatureControlActor,
+        features::dht_kv::{
+            msg::{ClientCommand, ClientMapCommand, NodeSession, ServerEvent},
+            Control, Event, Key, MapControl, SnapshotError, Version,
+        },
+    };
+
+    use super::{replica_routes, LocalStorage, LocalStorageOutput, Map};
+
+    fn set(storage: &mut LocalStorage<()>, now: u64, map: Map, key: u64, value: u8) {
+        storage.on_local(now, FeatureControlActor::Controller(()), Control::MapCmd(map, MapControl::Set(Key(key), vec![value], None)));
+        while storage.pop_action().is_some() {}
+    }
+
+    fn digests(storage: &mut LocalStorage<()>) -> Vec<(Map, Vec<(Key, Version)>)> {
+        let mut digests = vec![];
+        while let Some(out) = storage.pop_action() {
+            if let LocalStorageOutput::Remote(_, ClientCommand::MapCmd(map, ClientMapCommand::Digest(mut entries))) = out {
+                entries.sort_by_key(|(key, _)| key.0);
+                digests.push((map, entries));
+            }
         }
+        digests
+    }
+
+    #[test]
+    fn replica_routes_should_skip_relay() {
+        let key = Map(0x0102_0304);
+        assert_eq!(
+            replica_routes(key, 3).collect::<Vec<_>>(),
+            vec![RouteRule::ToKeyReplica(0x0102_0304, 1), RouteRule::ToKeyReplica(0x0102_0304, 2)]
+        );
+        assert_eq!(replica_routes(key, 1).count(), 0);
+    }
+
+    #[test]
+    fn map_get_should_ask_next_replica_when_empty() {
+        let session = NodeSession(1, 2);
+        let key = Map(1000);
+        let mut storage = LocalStorage::<()>::new(session, 2, 0);
+        storage.on_local(0, FeatureControlActor::Controller(()), Control::MapGet(key));
+        assert!(matches!(storage.pop_action(), Some(LocalStorageOutput::Remote(RouteRule::ToKey(1000), ClientCommand::MapGet(_, 0)))));
+
+        //relay doesn't have the data, the read goes to the replica
+        storage.on_server(10, NodeSession(3, 4), ServerEvent::MapGetRes(key, 0, vec![]));
+        assert!(matches!(
+            storage.pop_action(),
+            Some(LocalStorageOutput::Remote(RouteRule::ToKeyReplica(1000, 1), ClientCommand::MapGet(_, 0)))
+        ));
+        assert!(storage.pop_action().is_none());
+
+        //last replica answer is returned even if empty
+        storage.on_server(20, NodeSession(5, 6), ServerEvent::MapGetRes(key, 0, vec![]));
+        assert!(matches!(storage.pop_action(), Some(LocalStorageOutput::Local(_, Event::MapGetRes(_, Ok(values)))) if values.is_empty()));
+        assert!(storage.pop_action().is_none());
+    }
+
+    #[test]
+    fn snapshot_round_trip_should_keep_versions() {
+        let session = NodeSession(1, 2);
+        let (map1, map2) = (Map(1000), Map(2000));
+        let mut storage = LocalStorage::<()>::new(session, 1, 0);
+        set(&mut storage, 100, map1, 1, 1);
+        set(&mut storage, 200, map1, 2, 2);
+        set(&mut storage, 300, map2, 3, 3);
+        let snapshot = storage.export_snapshot(400);
+
+        //restarted node has an older clock, restored keys keep the versions which relays already have
+        let mut restarted = LocalStorage::<()>::new(session, 1, 0);
+        assert_eq!(restarted.import_snapshot(0, &snapshot), Ok(3));
+        assert_eq!(restarted.export_snapshot(0), snapshot);
+        assert_eq!(
+            digests(&mut restarted),
+            vec![(map1, vec![(Key(1), Version(100)), (Key(2), Version(200))]), (map2, vec![(Key(3), Version(300))])]
+        );
+
+        //a newer set after the restore is still newer than the restored version
+        set(&mut restarted, 10, map1, 1, 5);
+        restarted.on_local(20, FeatureControlActor::Controller(()), Control::ExportSnapshot);
+        let exported = match restarted.pop_action() {
+            Some(LocalStorageOutput::Local(_, Event::Snapshot(bytes))) => bytes,
+            _ => panic!("Should answer snapshot"),
+        };
+        let mut check = LocalStorage::<()>::new(session, 1, 0);
+        assert_eq!(check.import_snapshot(0, &exported), Ok(3));
+        assert_eq!(digests(&mut check)[0], (map1, vec![(Key(1), Version(101)), (Key(2), Version(200))]));
+    }
+
+    #[test]
+    fn snapshot_import_should_keep_newer_versions() {
+        let session = NodeSession(1, 2);
+        let map = Map(1000);
+        let mut storage = LocalStorage::<()>::new(session, 1, 0);
+        set(&mut storage, 100, map, 1, 1);
+        set(&mut storage, 100, map, 2, 2);
+        let snapshot = storage.export_snapshot(100);
+
+        let mut restarted = LocalStorage::<()>::new(session, 1, 0);
+        set(&mut restarted, 500, map, 1, 9);
+        assert_eq!(restarted.import_snapshot(600, &snapshot), Ok(1));
+        assert_eq!(digests(&mut restarted), vec![(map, vec![(Key(1), Version(500)), (Key(2), Version(100))])]);
+
+        assert_eq!(restarted.import_snapshot(600, &[1, 2, 3]), Err(SnapshotError::Decode));
     }
 }
@@ -3,17 +3,29 @@ use std::{
     fmt::Debug,
 };
 
+use serde::{Deserialize, Serialize};
+
 use crate::{
     base::FeatureControlActor,
     features::dht_kv::{
         msg::{ClientMapCommand, NodeSession, ServerMapEvent, Version},
-        Key, MapControl, MapEvent,
+        CasError, Key, MapControl, MapEvent,
     },
 };
 
 const RESEND_MS: u64 = 200; //We will resend set or del command if we don't get ack in this time
 const SYNC_MS: u64 = 1500; //We will predict send current state for avoiding out-of-sync, this can be waste of bandwidth but it's better than out-of-sync
 const UNSUB_TIMEOUT_MS: u64 = 10000; //We will remove the slot if it's not synced in this time
+const CAS_TIMEOUT_MS: u64 = 5000; //CAS is not resent because it isn't idempotent, the result is unknown after this time
+
+/// Local sub-key inside a snapshot, `ttl` is the remaining one when the snapshot was taken
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotSlot {
+    pub key: Key,
+    pub version: Version,
+    pub value: Vec<u8>,
+    pub ttl: Option<u64>,
+}
 
 /// MapSlot manage state of single sub-key inside a map.
 enum MapSlot {
@@ -24,6 +36,7 @@ enum MapSlot {
         key: Key,
         version: Version,
         value: Option<Vec<u8>>,
+        expire_at: Option<u64>,
     },
     Local {
         key: Key,
@@ -31,6 +44,7 @@ enum MapSlot {
         version: Version,
         syncing: bool,
         last_sync: u64,
+        expire_at: Option<u64>,
     },
 }
 
@@ -48,7 +62,8 @@ impl MapSlot {
     }
 
     /// This method is called when a new value is set to the map, this will overwrite the old value even if it's not synced or from remote.
-    pub fn set(&mut self, now: u64, new_data: Vec<u8>) -> Option<ClientMapCommand> {
+    /// The ttl is restarted with each set.
+    pub fn set(&mut self, now: u64, new_data: Vec<u8>, ttl: Option<u64>) -> Option<ClientMapCommand> {
         match self {
             MapSlot::Unspecific { key } | MapSlot::Remote { key, .. } => {
                 let version = Version(now); //TODO use real version
@@ -59,8 +74,9 @@ impl MapSlot {
                     version,
                     syncing: true,
                     last_sync: now,
+                    expire_at: ttl.map(|ttl| now + ttl),
                 };
-                Some(ClientMapCommand::Set(key, version, new_data))
+                Some(ClientMapCommand::Set(key, version, new_data, ttl))
             }
             MapSlot::Local {
                 key,
@@ -68,12 +84,15 @@ impl MapSlot {
                 version,
                 syncing,
                 last_sync,
+                expire_at,
             } => {
                 *value = Some(new_data.clone());
-                *version = Version(now); //TODO use real version
+                //a restored version can be ahead of the clock, the new one must still be newer
+                *version = Version(now.max(version.0 + 1)); //TODO use real version
                 *syncing = true;
                 *last_sync = now;
-                Some(ClientMapCommand::Set(*key, *version, new_data))
+                *expire_at = ttl.map(|ttl| now + ttl);
+                Some(ClientMapCommand::Set(*key, *version, new_data, ttl))
             }
         }
     }
@@ -108,11 +127,14 @@ impl MapSlot {
                 version,
                 syncing,
                 last_sync,
+                expire_at,
             } => {
                 if (*syncing && now >= *last_sync + RESEND_MS) || now >= *last_sync + SYNC_MS || force {
                     *last_sync = now;
                     if let Some(value) = value {
-                        Some(ClientMapCommand::Set(*key, *version, value.clone()))
+                        //resend remaining ttl, so syncing doesn't extend the deadline
+                        let ttl = expire_at.map(|at| at.saturating_sub(now));
+                        Some(ClientMapCommand::Set(*key, *version, value.clone(), ttl))
                     } else {
                         Some(ClientMapCommand::Del(*key, *version))
                     }
@@ -123,6 +145,51 @@ impl MapSlot {
         }
     }
 
+    /// Entry of the digest, only for local slots which have a value
+    pub fn digest(&self) -> Option<(Key, Version)> {
+        match self {
+            MapSlot::Local { key, value: Some(_), version, .. } => Some((*key, *version)),
+            _ => None,
+        }
+    }
+
+    pub fn snapshot(&self, now: u64) -> Option<SnapshotSlot> {
+        match self {
+            MapSlot::Local {
+                key,
+                value: Some(value),
+                version,
+                expire_at,
+                ..
+            } if !self.is_expired(now) => Some(SnapshotSlot {
+                key: *key,
+                version: *version,
+                value: value.clone(),
+                ttl: expire_at.map(|at| at.saturating_sub(now)),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Restore a local sub-key from a snapshot, false if the slot already has the same or a newer version.
+    /// The slot is not marked as syncing, the relay asks for it after the digest if it misses it
+    pub fn restore(&mut self, now: u64, snapshot: SnapshotSlot) -> bool {
+        match self {
+            MapSlot::Local { version, .. } | MapSlot::Remote { version, .. } if version.0 >= snapshot.version.0 => false,
+            _ => {
+                *self = MapSlot::Local {
+                    key: snapshot.key,
+                    value: Some(snapshot.value),
+                    version: snapshot.version,
+                    syncing: false,
+                    last_sync: now,
+                    expire_at: snapshot.ttl.map(|ttl| now + ttl),
+                };
+                true
+            }
+        }
+    }
+
     pub fn set_ok(&mut self, version: Version) {
         match self {
             MapSlot::Unspecific { .. } | MapSlot::Remote { .. } => {}
@@ -161,15 +228,26 @@ impl MapSlot {
     ///     Option(ClientMapCommand, bool)
     ///         - ClientMapCommand: OnSetAck
     ///         - bool: true if the slot is updated
-    pub fn on_set(&mut self, _now: u64, key: Key, source: NodeSession, version: Version, data: Vec<u8>) -> Option<(ClientMapCommand, bool)> {
+    pub fn on_set(&mut self, now: u64, key: Key, source: NodeSession, version: Version, data: Vec<u8>, ttl: Option<u64>) -> Option<(ClientMapCommand, bool)> {
+        let expire_at = ttl.map(|ttl| now + ttl);
         match self {
             MapSlot::Unspecific { .. } => {
-                *self = MapSlot::Remote { key, version, value: Some(data) };
+                *self = MapSlot::Remote {
+                    key,
+                    version,
+                    value: Some(data),
+                    expire_at,
+                };
                 Some((ClientMapCommand::OnSetAck(key, source, version), true))
             }
             MapSlot::Remote { version: old_version, .. } => match old_version.0.cmp(&version.0) {
                 std::cmp::Ordering::Less => {
-                    *self = MapSlot::Remote { key, version, value: Some(data) };
+                    *self = MapSlot::Remote {
+                        key,
+                        version,
+                        value: Some(data),
+                        expire_at,
+                    };
                     Some((ClientMapCommand::OnSetAck(key, source, version), true))
                 }
                 std::cmp::Ordering::Equal => Some((ClientMapCommand::OnSetAck(key, source, version), false)),
@@ -193,6 +271,49 @@ impl MapSlot {
         }
     }
 
+    /// The ttl has lapsed, a local slot is deleted as by `del` and a remote slot is dropped without waiting for the relay,
+    /// which may be unreachable.
+    pub fn expire(&mut self, now: u64) -> Option<ClientMapCommand> {
+        match self {
+            MapSlot::Local { .. } => self.del(now),
+            MapSlot::Remote { key, .. } => {
+                *self = MapSlot::Unspecific { key: *key };
+                None
+            }
+            MapSlot::Unspecific { .. } => None,
+        }
+    }
+
+    /// Local set or del which is not acked by the server yet
+    pub fn is_syncing(&self) -> bool {
+        matches!(self, MapSlot::Local { syncing: true, .. })
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        match self {
+            MapSlot::Unspecific { .. } => false,
+            MapSlot::Remote { value, expire_at, .. } | MapSlot::Local { value, expire_at, .. } => value.is_some() && expire_at.map_or(false, |at| now >= at),
+        }
+    }
+
+    /// CAS is accepted by relay, the slot is owned by local now and synced like a normal set.
+    /// Return false if the slot already has a newer local version
+    pub fn cas_ok(&mut self, now: u64, new_version: Version, new_data: Vec<u8>) -> bool {
+        let key = match self {
+            MapSlot::Local { version, .. } if version.0 >= new_version.0 => return false,
+            MapSlot::Unspecific { key } | MapSlot::Remote { key, .. } | MapSlot::Local { key, .. } => *key,
+        };
+        *self = MapSlot::Local {
+            key,
+            value: Some(new_data),
+            version: new_version,
+            syncing: false,
+            last_sync: now,
+            expire_at: None,
+        };
+        true
+    }
+
     pub fn should_cleanup(&self) -> bool {
         match self {
             MapSlot::Unspecific { .. } => true,
@@ -206,6 +327,14 @@ impl MapSlot {
 pub enum LocalMapOutput<UserData> {
     Remote(ClientMapCommand),
     Local(FeatureControlActor<UserData>, MapEvent),
+    CasRes(FeatureControlActor<UserData>, Key, Result<Version, CasError>),
+}
+
+struct CasWait<UserData> {
+    actor: FeatureControlActor<UserData>,
+    version: Version,
+    value: Vec<u8>,
+    started_at: u64,
 }
 
 enum SubState {
@@ -222,6 +351,7 @@ pub struct LocalMap<UserData> {
     slots: HashMap<(Key, NodeSession), MapSlot>,
     subscribers: Vec<FeatureControlActor<UserData>>,
     sub_state: SubState,
+    cas_waits: HashMap<Key, CasWait<UserData>>,
     queue: VecDeque<LocalMapOutput<UserData>>,
 }
 
@@ -232,6 +362,7 @@ impl<UserData: Eq + Copy + Debug> LocalMap<UserData> {
             slots: HashMap::new(),
             subscribers: Vec::new(),
             sub_state: SubState::NotSub,
+            cas_waits: HashMap::new(),
             queue: VecDeque::new(),
         }
     }
@@ -265,6 +396,35 @@ impl<UserData: Eq + Copy + Debug> LocalMap<UserData> {
             }
         }
 
+        let mut timeout = vec![];
+        for (key, wait) in self.cas_waits.iter() {
+            if now >= wait.started_at + CAS_TIMEOUT_MS {
+                timeout.push(*key);
+            }
+        }
+
+        for key in timeout {
+            let wait = self.cas_waits.remove(&key).expect("Must have cas wait");
+            log::warn!("[ClientMap] Cas key {key} timeout after {CAS_TIMEOUT_MS} ms");
+            self.queue.push_back(LocalMapOutput::CasRes(wait.actor, key, Err(CasError::Timeout)));
+        }
+
+        let mut expired = vec![];
+        for (key, slot) in self.slots.iter() {
+            if slot.is_expired(now) {
+                expired.push(*key);
+            }
+        }
+
+        for (key, source) in expired {
+            log::debug!("[ClientMap] Expire key {key} from source {}", source.0);
+            let slot = self.slots.get_mut(&(key, source)).expect("Must have slot for expire");
+            if let Some(cmd) = slot.expire(now) {
+                self.queue.push_back(LocalMapOutput::Remote(cmd));
+            }
+            self.fire_event(MapEvent::OnDel(key, source.0));
+        }
+
         self.sync_slots(now, false);
 
         // remove all empty slots
@@ -281,11 +441,51 @@ impl<UserData: Eq + Copy + Debug> LocalMap<UserData> {
         }
     }
 
+    /// A new connection may change the relay or bring back a relay which missed our writes. We send a digest of local
+    /// slots, so only mismatched ones are resent, and resend Sub without waiting for SYNC_MS
+    pub fn on_reconnect(&mut self, now: u64) {
+        let digest: Vec<(Key, Version)> = self.slots.iter().filter(|((_, source), _)| *source == self.session).filter_map(|(_, slot)| slot.digest()).collect();
+        if !digest.is_empty() {
+            log::debug!("[ClientMap] Send digest of {} local slots after reconnect", digest.len());
+            self.queue.push_back(LocalMapOutput::Remote(ClientMapCommand::Digest(digest)));
+        }
+        if let SubState::Subscribed { id, remote, sync_ts } = &mut self.sub_state {
+            log::debug!("[ClientMap] Resend sub command after reconnect");
+            self.queue.push_back(LocalMapOutput::Remote(ClientMapCommand::Sub(*id, Some(*remote))));
+            *sync_ts = now;
+        }
+    }
+
+    /// Local sub-keys which have a value, ordered by sub-key
+    pub fn export_slots(&self, now: u64) -> Vec<SnapshotSlot> {
+        let mut slots: Vec<SnapshotSlot> = self
+            .slots
+            .iter()
+            .filter(|((_, source), _)| *source == self.session)
+            .filter_map(|(_, slot)| slot.snapshot(now))
+            .collect();
+        slots.sort_by_key(|slot| slot.key.0);
+        slots
+    }
+
+    /// Restore a sub-key of this node, subscribers get OnSet if it is restored
+    pub fn import_slot(&mut self, now: u64, snapshot: SnapshotSlot) -> bool {
+        let (key, data) = (snapshot.key, snapshot.value.clone());
+        let slot = self.get_slot(key, self.session, true).expect("Must have slot for restore");
+        if !slot.restore(now, snapshot) {
+            log::debug!("[ClientMap] Skip restore key {key}, local version is newer");
+            return false;
+        }
+        log::debug!("[ClientMap] Restored key {key} with data len {}", data.len());
+        self.fire_event(MapEvent::OnSet(key, self.session.0, data));
+        true
+    }
+
     pub fn on_control(&mut self, now: u64, actor: FeatureControlActor<UserData>, control: MapControl) -> Option<ClientMapCommand> {
         match control {
-            MapControl::Set(key, data) => {
+            MapControl::Set(key, data, ttl) => {
                 let slot = self.get_slot(key, self.session, true).expect("Must have slot for set");
-                if let Some(out) = slot.set(now, data.clone()) {
+                if let Some(out) = slot.set(now, data.clone(), ttl) {
                     log::debug!("[ClientMap] Set key {} with data len {}", key, data.len());
                     self.fire_event(MapEvent::OnSet(key, self.session.0, data));
                     Some(out)
@@ -367,6 +567,28 @@ impl<UserData: Eq + Copy + Debug> LocalMap<UserData> {
         }
     }
 
+    /// Only one CAS per sub-key can wait for relay at a time, the new version is chosen to be newer than expected one
+    /// even if the writer's clock is behind.
+    pub fn on_cas(&mut self, now: u64, actor: FeatureControlActor<UserData>, key: Key, expected: Option<Version>, value: Vec<u8>) -> Option<ClientMapCommand> {
+        if self.cas_waits.contains_key(&key) {
+            log::warn!("[ClientMap] Cas key {key} rejected, other cas is pending");
+            self.queue.push_back(LocalMapOutput::CasRes(actor, key, Err(CasError::Pending)));
+            return None;
+        }
+        let version = Version(expected.map_or(now, |expected| now.max(expected.0 + 1)));
+        log::debug!("[ClientMap] Cas key {key} expected {:?} new version {version}", expected);
+        self.cas_waits.insert(
+            key,
+            CasWait {
+                actor,
+                version,
+                value: value.clone(),
+                started_at: now,
+            },
+        );
+        Some(ClientMapCommand::Cas(key, expected, version, value))
+    }
+
     /// For OnSet and OnDel event, we need to solve problems: key moved to other server or source server changed.
     /// In case 1 key moved to other server:
     pub fn on_server(&mut self, now: u64, remote: NodeSession, cmd: ServerMapEvent) -> Option<ClientMapCommand> {
@@ -424,6 +646,31 @@ impl<UserData: Eq + Copy + Debug> LocalMap<UserData> {
                 }
                 None
             }
+            ServerMapEvent::DigestRes(keys) => {
+                log::debug!("[ClientMap] Received DigestRes from {} with {} missing keys => resync them now", remote.0, keys.len());
+                for key in keys {
+                    if let Some(cmd) = self.get_slot(key, self.session, false).and_then(|slot| slot.sync(now, true)) {
+                        self.queue.push_back(LocalMapOutput::Remote(cmd));
+                    }
+                }
+                None
+            }
+            ServerMapEvent::CasOk(key, version) => {
+                let wait = self.take_cas_wait(key, version)?;
+                log::debug!("[ClientMap] CasOk for key {key} with version {version}");
+                let slot = self.get_slot(key, self.session, true).expect("Must have slot for cas");
+                if slot.cas_ok(now, version, wait.value.clone()) {
+                    self.fire_event(MapEvent::OnSet(key, self.session.0, wait.value));
+                }
+                self.queue.push_back(LocalMapOutput::CasRes(wait.actor, key, Ok(version)));
+                None
+            }
+            ServerMapEvent::CasFailed(key, version, current) => {
+                let wait = self.take_cas_wait(key, version)?;
+                log::debug!("[ClientMap] CasFailed for key {key} with version {version}, current {:?}", current);
+                self.queue.push_back(LocalMapOutput::CasRes(wait.actor, key, Err(CasError::Conflict(current))));
+                None
+            }
             ServerMapEvent::UnsubOk(id) => {
                 if let SubState::Unsubscribing { id: sub_id, remote: locked, .. } = &self.sub_state {
                     if *sub_id == id && (*locked).unwrap_or(remote) == remote {
@@ -444,13 +691,13 @@ impl<UserData: Eq + Copy + Debug> LocalMap<UserData> {
                 }
                 None
             }
-            ServerMapEvent::OnSet { key, version, source, data } => {
+            ServerMapEvent::OnSet { key, version, source, data, ttl } => {
                 if !self.accept_event(remote) {
                     log::warn!("[ClientMap] Received OnSet {key} but state or remote is not correct");
                     return None;
                 }
                 let slot = self.get_slot(key, source, true).expect("Must have slot for set");
-                let (event, updated) = slot.on_set(now, key, source, version, data.clone())?;
+                let (event, updated) = slot.on_set(now, key, source, version, data.clone(), ttl)?;
                 log::debug!("[ClientMap] Received OnSet for key {}", key);
                 if updated {
                     self.fire_event(MapEvent::OnSet(key, source.0, data));
@@ -476,8 +723,20 @@ impl<UserData: Eq + Copy + Debug> LocalMap<UserData> {
         self.queue.pop_front()
     }
 
+    pub fn has_pending_sync(&self) -> bool {
+        self.slots.values().any(|slot| slot.is_syncing())
+    }
+
     pub fn should_cleanup(&self) -> bool {
-        self.slots.is_empty() && self.subscribers.is_empty() && matches!(self.sub_state, SubState::NotSub)
+        self.slots.is_empty() && self.subscribers.is_empty() && self.cas_waits.is_empty() && matches!(self.sub_state, SubState::NotSub)
+    }
+
+    fn take_cas_wait(&mut self, key: Key, version: Version) -> Option<CasWait<UserData>> {
+        if self.cas_waits.get(&key)?.version != version {
+            log::warn!("[ClientMap] Received cas result for key {key} with unknown version {version}");
+            return None;
+        }
+        self.cas_waits.remove(&key)
     }
 
     fn get_slot(&mut self, key: Key, source: NodeSession, auto_create: bool) -> Option<&mut MapSlot> {
@@ -531,9 +790,9 @@ mod test {
     use crate::{
         base::FeatureControlActor,
         features::dht_kv::{
-            client::map::{LocalMapOutput, RESEND_MS, SYNC_MS},
+            client::map::{LocalMapOutput, CAS_TIMEOUT_MS, RESEND_MS, SYNC_MS},
             msg::{ClientMapCommand, Key, NodeSession, ServerMapEvent, Version},
-            MapControl, MapEvent,
+            CasError, MapControl, MapEvent,
         },
     };
 
@@ -545,7 +804,7 @@ mod test {
         let mut slot = MapSlot::new(key);
 
         //we must output set command with new slot, with Version is now_ms
-        assert_eq!(slot.set(100, vec![1, 2, 3, 4]), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4])));
+        assert_eq!(slot.set(100, vec![1, 2, 3, 4], None), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4], None)));
 
         //we can delete the slot
         assert_eq!(slot.del(200), Some(ClientMapCommand::Del(key, Version(100))));
@@ -557,16 +816,16 @@ mod test {
         let mut slot = MapSlot::new(key);
 
         //we must output set command with new slot, with Version is now_ms
-        assert_eq!(slot.set(100, vec![1, 2, 3, 4]), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4])));
+        assert_eq!(slot.set(100, vec![1, 2, 3, 4], None), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4], None)));
 
         assert_eq!(slot.sync(101, false), None);
-        assert_eq!(slot.sync(100 + RESEND_MS, false), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4])));
+        assert_eq!(slot.sync(100 + RESEND_MS, false), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4], None)));
 
         slot.set_ok(Version(100));
         assert_eq!(slot.sync(100 + RESEND_MS * 2, false), None);
 
         //after set_ok we only resend with SYNC_MS
-        assert_eq!(slot.sync(100 + RESEND_MS + SYNC_MS, false), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4])));
+        assert_eq!(slot.sync(100 + RESEND_MS + SYNC_MS, false), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4], None)));
     }
 
     #[test]
@@ -575,10 +834,37 @@ mod test {
         let mut slot = MapSlot::new(key);
 
         //we must output set command with new slot, with Version is now_ms
-        assert_eq!(slot.set(100, vec![1, 2, 3, 4]), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4])));
+        assert_eq!(slot.set(100, vec![1, 2, 3, 4], None), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4], None)));
 
         assert_eq!(slot.sync(101, false), None);
-        assert_eq!(slot.sync(101, true), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4])));
+        assert_eq!(slot.sync(101, true), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4], None)));
+    }
+
+    #[test]
+    fn map_slot_sync_should_keep_ttl_deadline() {
+        let key = Key(1);
+        let mut slot = MapSlot::new(key);
+
+        assert_eq!(
+            slot.set(100, vec![1, 2, 3, 4], Some(1000)),
+            Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4], Some(1000)))
+        );
+        //resend only carries the remaining ttl
+        assert_eq!(
+            slot.sync(100 + RESEND_MS, false),
+            Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4], Some(1000 - RESEND_MS)))
+        );
+        assert!(!slot.is_expired(1099));
+        assert!(slot.is_expired(1100));
+
+        //set again will refresh ttl
+        assert_eq!(
+            slot.set(1000, vec![1, 2, 3, 5], Some(1000)),
+            Some(ClientMapCommand::Set(key, Version(1000), vec![1, 2, 3, 5], Some(1000)))
+        );
+        assert!(!slot.is_expired(1100));
+        assert_eq!(slot.expire(2000), Some(ClientMapCommand::Del(key, Version(1000))));
+        assert!(!slot.is_expired(2000));
     }
 
     #[test]
@@ -587,7 +873,7 @@ mod test {
         let mut slot = MapSlot::new(key);
 
         //we must output set command with new slot, with Version is now_ms
-        assert_eq!(slot.set(100, vec![1, 2, 3, 4]), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4])));
+        assert_eq!(slot.set(100, vec![1, 2, 3, 4], None), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4], None)));
         slot.set_ok(Version(100));
         assert_eq!(slot.sync(100 + RESEND_MS, false), None);
 
@@ -603,7 +889,7 @@ mod test {
         let mut slot = MapSlot::new(key);
 
         //we must output set command with new slot, with Version is now_ms
-        assert_eq!(slot.set(100, vec![1, 2, 3, 4]), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4])));
+        assert_eq!(slot.set(100, vec![1, 2, 3, 4], None), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4], None)));
 
         //we missing set_ok, but we delete now
         //we must output del command with new slot, with Version is now_ms
@@ -618,7 +904,7 @@ mod test {
         let mut slot = MapSlot::new(key);
 
         //we must output set command with new slot, with Version is now_ms
-        assert_eq!(slot.set(100, vec![1, 2, 3, 4]), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4])));
+        assert_eq!(slot.set(100, vec![1, 2, 3, 4], None), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4], None)));
         slot.set_ok(Version(101));
         assert_ne!(slot.sync(100 + RESEND_MS, false), None);
 
@@ -634,7 +920,10 @@ mod test {
         let mut slot = MapSlot::new(key);
 
         let version = Version(100);
-        assert_eq!(slot.on_set(100, key, source, version, vec![1, 2, 3, 4]), Some((ClientMapCommand::OnSetAck(key, source, version), true)));
+        assert_eq!(
+            slot.on_set(100, key, source, version, vec![1, 2, 3, 4], None),
+            Some((ClientMapCommand::OnSetAck(key, source, version), true))
+        );
         assert!(!slot.should_cleanup());
 
         assert_eq!(slot.on_del(200, key, source, version), Some(ClientMapCommand::OnDelAck(key, source, version)));
@@ -647,10 +936,10 @@ mod test {
         let mut slot = MapSlot::new(key);
 
         let version = Version(100);
-        assert_eq!(slot.set(100, vec![1, 2, 3, 4]), Some(ClientMapCommand::Set(key, version, vec![1, 2, 3, 4])));
+        assert_eq!(slot.set(100, vec![1, 2, 3, 4], None), Some(ClientMapCommand::Set(key, version, vec![1, 2, 3, 4], None)));
 
         let source = NodeSession(1, 2);
-        assert_eq!(slot.on_set(100, key, source, version, vec![1, 2, 3, 4]), None);
+        assert_eq!(slot.on_set(100, key, source, version, vec![1, 2, 3, 4], None), None);
         assert_eq!(slot.on_del(200, key, source, version), None);
         assert!(!slot.should_cleanup());
     }
@@ -662,15 +951,15 @@ mod test {
 
         let source = NodeSession(1, 2);
         assert_eq!(
-            slot.on_set(100, key, source, Version(100), vec![1, 2, 3, 4]),
+            slot.on_set(100, key, source, Version(100), vec![1, 2, 3, 4], None),
             Some((ClientMapCommand::OnSetAck(key, source, Version(100)), true))
         );
         //same version will only send ack, not update the slot
         assert_eq!(
-            slot.on_set(100, key, source, Version(100), vec![1, 2, 3, 4]),
+            slot.on_set(100, key, source, Version(100), vec![1, 2, 3, 4], None),
             Some((ClientMapCommand::OnSetAck(key, source, Version(100)), false))
         );
-        assert_eq!(slot.on_set(100, key, source, Version(90), vec![1, 2, 3]), None);
+        assert_eq!(slot.on_set(100, key, source, Version(90), vec![1, 2, 3], None), None);
         assert_eq!(slot.on_del(200, key, source, Version(90)), None);
         assert!(!slot.should_cleanup());
     }
@@ -684,8 +973,8 @@ mod test {
         let key = Key(1);
 
         assert_eq!(
-            map.on_control(100, actor, MapControl::Set(key, vec![1, 2, 3, 4])),
-            Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4]))
+            map.on_control(100, actor, MapControl::Set(key, vec![1, 2, 3, 4], None)),
+            Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4], None))
         );
         assert_eq!(map.on_control(102, actor, MapControl::Sub), Some(ClientMapCommand::Sub(102, None))); //Sub will be sent include time ms
         assert_eq!(map.pop_action(), Some(LocalMapOutput::Local(actor, MapEvent::OnSet(key, session.0, vec![1, 2, 3, 4]))));
@@ -706,8 +995,8 @@ mod test {
 
         assert_eq!(map.on_control(102, actor, MapControl::Sub), Some(ClientMapCommand::Sub(102, None)));
         assert_eq!(
-            map.on_control(103, actor, MapControl::Set(key, vec![1, 2, 3, 4])),
-            Some(ClientMapCommand::Set(key, Version(103), vec![1, 2, 3, 4]))
+            map.on_control(103, actor, MapControl::Set(key, vec![1, 2, 3, 4], None)),
+            Some(ClientMapCommand::Set(key, Version(103), vec![1, 2, 3, 4], None))
         );
         assert_eq!(map.pop_action(), Some(LocalMapOutput::Local(actor, MapEvent::OnSet(key, session.0, vec![1, 2, 3, 4]))));
         assert_eq!(map.pop_action(), None);
@@ -783,7 +1072,8 @@ mod test {
                     key,
                     source,
                     version: Version(2000),
-                    data: vec![1, 2, 3, 4]
+                    data: vec![1, 2, 3, 4],
+                    ttl: None,
                 }
             ),
             None
@@ -814,7 +1104,8 @@ mod test {
                     key,
                     source,
                     version: Version(2000),
-                    data: vec![1, 2, 3, 4]
+                    data: vec![1, 2, 3, 4],
+                    ttl: None,
                 }
             ),
             Some(ClientMapCommand::OnSetAck(key, source, Version(2000)))
@@ -853,7 +1144,8 @@ mod test {
                     key,
                     source,
                     version: Version(2000),
-                    data: vec![1, 2, 3, 4]
+                    data: vec![1, 2, 3, 4],
+                    ttl: None,
                 }
             ),
             Some(ClientMapCommand::OnSetAck(key, source, Version(2000)))
@@ -878,7 +1170,8 @@ mod test {
                     key,
                     source,
                     version: Version(2000),
-                    data: vec![1, 2, 3, 4]
+                    data: vec![1, 2, 3, 4],
+                    ttl: None,
                 }
             ),
             Some(ClientMapCommand::OnSetAck(key, source, Version(2000)))
@@ -898,8 +1191,8 @@ mod test {
         assert_eq!(map.on_server(100, relay, ServerMapEvent::SubOk(100)), None);
 
         assert_eq!(
-            map.on_control(100, FeatureControlActor::Controller(()), MapControl::Set(key, vec![1, 2, 3, 4])),
-            Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4]))
+            map.on_control(100, FeatureControlActor::Controller(()), MapControl::Set(key, vec![1, 2, 3, 4], None)),
+            Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4], None))
         );
 
         assert_eq!(
@@ -910,7 +1203,8 @@ mod test {
                     key,
                     source: other_source,
                     version: Version(1000),
-                    data: vec![2, 3, 4]
+                    data: vec![2, 3, 4],
+                    ttl: None,
                 }
             ),
             Some(ClientMapCommand::OnSetAck(key, other_source, Version(1000)))
@@ -918,4 +1212,104 @@ mod test {
 
         assert_eq!(map.slots.len(), 2);
     }
+
+    #[test]
+    fn map_should_expire_local_and_remote_slots() {
+        let session = NodeSession(1, 2);
+        let actor = FeatureControlActor::Controller(());
+        let mut map = LocalMap::new(session);
+
+        let local_key = Key(1);
+        let remote_key = Key(2);
+        let source = NodeSession(3, 4);
+        let relay = NodeSession(5, 6);
+
+        assert_eq!(
+            map.on_control(100, actor, MapControl::Set(local_key, vec![1, 2, 3, 4], Some(1000))),
+            Some(ClientMapCommand::Set(local_key, Version(100), vec![1, 2, 3, 4], Some(1000)))
+        );
+        assert_eq!(map.on_control(100, actor, MapControl::Sub), Some(ClientMapCommand::Sub(100, None)));
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::Local(actor, MapEvent::OnSet(local_key, session.0, vec![1, 2, 3, 4]))));
+        assert_eq!(map.on_server(100, relay, ServerMapEvent::SubOk(100)), None);
+        assert_eq!(map.on_server(100, relay, ServerMapEvent::SetOk(local_key, Version(100))), None);
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::Local(actor, MapEvent::OnRelaySelected(relay.0))));
+
+        assert_eq!(
+            map.on_server(
+                200,
+                relay,
+                ServerMapEvent::OnSet {
+                    key: remote_key,
+                    source,
+                    version: Version(2000),
+                    data: vec![5, 6],
+                    ttl: Some(500),
+                }
+            ),
+            Some(ClientMapCommand::OnSetAck(remote_key, source, Version(2000)))
+        );
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::Local(actor, MapEvent::OnSet(remote_key, source.0, vec![5, 6]))));
+
+        //remote slot expires by itself, even if the relay never sends OnDel
+        map.on_tick(700);
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::Local(actor, MapEvent::OnDel(remote_key, source.0))));
+        assert_eq!(map.pop_action(), None);
+
+        //local slot is deleted at relay too
+        map.on_tick(1100);
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::Remote(ClientMapCommand::Del(local_key, Version(100)))));
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::Local(actor, MapEvent::OnDel(local_key, session.0))));
+        assert_eq!(map.pop_action(), None);
+    }
+
+    #[test]
+    fn map_handle_cas_result() {
+        let session = NodeSession(1, 2);
+        let actor = FeatureControlActor::Controller(());
+        let mut map = LocalMap::new(session);
+
+        let key = Key(1);
+        let relay = NodeSession(5, 6);
+
+        assert_eq!(map.on_control(100, actor, MapControl::Sub), Some(ClientMapCommand::Sub(100, None)));
+        assert_eq!(map.on_server(100, relay, ServerMapEvent::SubOk(100)), None);
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::Local(actor, MapEvent::OnRelaySelected(relay.0))));
+
+        //version must be newer than expected even if the local clock is behind
+        assert_eq!(
+            map.on_cas(100, actor, key, Some(Version(200)), vec![1]),
+            Some(ClientMapCommand::Cas(key, Some(Version(200)), Version(201), vec![1]))
+        );
+        assert_eq!(map.on_cas(100, actor, key, None, vec![2]), None);
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::CasRes(actor, key, Err(CasError::Pending))));
+
+        //result with other version is ignored
+        assert_eq!(map.on_server(101, relay, ServerMapEvent::CasOk(key, Version(100))), None);
+        assert_eq!(map.pop_action(), None);
+
+        assert_eq!(map.on_server(101, relay, ServerMapEvent::CasOk(key, Version(201))), None);
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::Local(actor, MapEvent::OnSet(key, session.0, vec![1]))));
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::CasRes(actor, key, Ok(Version(201)))));
+        assert_eq!(map.pop_action(), None);
+
+        //accepted value is synced like a normal set
+        map.on_tick(101 + SYNC_MS);
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::Remote(ClientMapCommand::Sub(100, Some(relay)))));
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::Remote(ClientMapCommand::Set(key, Version(201), vec![1], None))));
+
+        assert_eq!(
+            map.on_cas(2000, actor, key, Some(Version(100)), vec![3]),
+            Some(ClientMapCommand::Cas(key, Some(Version(100)), Version(2000), vec![3]))
+        );
+        assert_eq!(map.on_server(2001, relay, ServerMapEvent::CasFailed(key, Version(2000), Some(Version(201)))), None);
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::CasRes(actor, key, Err(CasError::Conflict(Some(Version(201)))))));
+
+        assert_eq!(
+            map.on_cas(3000, actor, key, Some(Version(201)), vec![4]),
+            Some(ClientMapCommand::Cas(key, Some(Version(201)), Version(3000), vec![4]))
+        );
+        map.on_tick(3000 + CAS_TIMEOUT_MS);
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::Remote(ClientMapCommand::Sub(100, Some(relay)))));
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::CasRes(actor, key, Err(CasError::Timeout))));
+    }
 }
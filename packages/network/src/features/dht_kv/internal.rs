@@ -23,10 +23,10 @@ pub struct DhtKvInternal<UserData> {
 }
 
 impl<UserData: Eq + Debug + Copy> DhtKvInternal<UserData> {
-    pub fn new(session: NodeSession) -> Self {
+    pub fn new(session: NodeSession, replication_factor: usize, req_id_seed: u64) -> Self {
         Self {
             session,
-            local: LocalStorage::new(session),
+            local: LocalStorage::new(session, replication_factor, req_id_seed),
             remote: RemoteStorage::new(session),
         }
     }
@@ -36,6 +36,20 @@ impl<UserData: Eq + Debug + Copy> DhtKvInternal<UserData> {
         self.remote.on_tick(now);
     }
 
+    /// Count of (local, remote) maps
+    pub fn map_counts(&self) -> (usize, usize) {
+        (self.local.maps(), self.remote.maps())
+    }
+
+    /// Some local sets or dels are still resent until the server acks them
+    pub fn has_pending_sync(&self) -> bool {
+        self.local.has_pending_sync()
+    }
+
+    pub fn on_connected(&mut self, now: u64) {
+        self.local.on_connected(now);
+    }
+
     pub fn on_local(&mut self, now: u64, actor: FeatureControlActor<UserData>, control: Control) {
         self.local.on_local(now, actor, control);
     }
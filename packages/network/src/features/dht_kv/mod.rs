@@ -9,29 +9,43 @@
 use std::fmt::Debug;
 
 use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_utils::metrics;
 use derivative::Derivative;
 use sans_io_runtime::{collections::DynamicDeque, TaskSwitcherChild};
 
-use crate::base::{Feature, FeatureContext, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerInput, FeatureWorkerOutput, NetOutgoingMeta};
+use crate::base::{ConnectionEvent, Feature, FeatureContext, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerInput, FeatureWorkerOutput, NetOutgoingMeta};
 
-use self::{
-    internal::InternalOutput,
-    msg::{NodeSession, Version},
-};
+use self::{internal::InternalOutput, msg::NodeSession};
 
 mod client;
 mod internal;
 mod msg;
 mod server;
 
-pub use self::msg::{Key, Map};
+pub use self::msg::{Key, KeyPrefix, Map, ScanCursor, Version};
 
 pub const FEATURE_ID: u8 = 4;
 pub const FEATURE_NAME: &str = "dht_kv";
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DhtKvCfg {
+    /// Number of nodes which store each map. Writes are sent to the relay and to the closest nodes of keys derived
+    /// from the map key, any ack is success and the others are synced later by resend.
+    /// In small networks some replicas can be the same node
+    pub replication_factor: u8,
+}
+
+impl Default for DhtKvCfg {
+    fn default() -> Self {
+        Self { replication_factor: 1 }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum MapControl {
-    Set(Key, Vec<u8>),
+    /// Set a sub-key with optional ttl in ms, the entry is deleted everywhere after it lapses.
+    /// Setting again refreshes the ttl
+    Set(Key, Vec<u8>, Option<u64>),
     Del(Key),
     Sub,
     Unsub,
@@ -39,7 +53,7 @@ pub enum MapControl {
 
 impl MapControl {
     pub fn is_creator(&self) -> bool {
-        matches!(self, MapControl::Set(_, _) | MapControl::Sub)
+        matches!(self, MapControl::Set(_, _, _) | MapControl::Sub)
     }
 }
 
@@ -47,6 +61,27 @@ impl MapControl {
 pub enum Control {
     MapCmd(Map, MapControl),
     MapGet(Map),
+    /// List at most `limit` entries with sub-key matching `prefix`, ordered by sub-key.
+    /// The result carries a cursor for the next page when there are more entries
+    MapScan {
+        key: Map,
+        prefix: KeyPrefix,
+        limit: u32,
+        cursor: Option<ScanCursor>,
+    },
+    /// Set `sub_key` only if the newest version of it, from any source, is `expected_version`.
+    /// `None` means the sub-key must not exist. The result is returned as `Event::MapCasRes`
+    MapCmdCas {
+        key: Map,
+        sub_key: Key,
+        expected_version: Option<Version>,
+        value: Vec<u8>,
+    },
+    /// Serialize the sub-keys which this node has set, for restoring them after a restart. Answered with `Event::Snapshot`
+    ExportSnapshot,
+    /// Restore sub-keys from `Event::Snapshot` with their versions, sub-keys which are already newer here are kept.
+    /// Answered with `Event::SnapshotImported` carrying the number of restored sub-keys
+    ImportSnapshot(Vec<u8>),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -55,6 +90,23 @@ pub enum GetError {
     NotFound,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CasError {
+    /// The relay didn't answer in time, the value may or may not be set
+    Timeout,
+    /// Other CAS for the same sub-key is still waiting for the relay
+    Pending,
+    /// The newest version in relay didn't match, it is returned for retrying
+    Conflict(Option<Version>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotError {
+    Decode,
+    /// Snapshot is written by an incompatible version of the feature
+    Version(u8),
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum MapEvent {
     OnSet(Key, NodeId, Vec<u8>),
@@ -63,11 +115,16 @@ pub enum MapEvent {
 }
 
 type MapGetRs = Result<Vec<(Key, NodeSession, Version, Vec<u8>)>, GetError>;
+type MapScanRs = Result<(Vec<(Key, NodeSession, Version, Vec<u8>)>, Option<ScanCursor>), GetError>;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Event {
     MapEvent(Map, MapEvent),
     MapGetRes(Map, MapGetRs),
+    MapScanRes(Map, MapScanRs),
+    MapCasRes(Map, Key, Result<Version, CasError>),
+    Snapshot(Vec<u8>),
+    SnapshotImported(Result<usize, SnapshotError>),
 }
 
 #[derive(Debug, Clone)]
@@ -82,21 +139,31 @@ pub type WorkerOutput<UserData> = FeatureWorkerOutput<UserData, Control, Event,
 pub struct DhtKvFeature<UserData> {
     internal: internal::DhtKvInternal<UserData>,
     shutdown: bool,
+    reported_maps: (usize, usize),
 }
 
 impl<UserData: Eq + Copy + Debug> DhtKvFeature<UserData> {
-    pub fn new(node_id: NodeId, session: u64) -> Self {
+    /// `req_id_seed` is the first id of requests, it should be random for avoiding collision between restarts
+    pub fn new(node_id: NodeId, session: u64, cfg: DhtKvCfg, req_id_seed: u64) -> Self {
         Self {
-            internal: internal::DhtKvInternal::new(NodeSession(node_id, session)),
+            internal: internal::DhtKvInternal::new(NodeSession(node_id, session), cfg.replication_factor.max(1) as usize, req_id_seed),
             shutdown: false,
+            reported_maps: (0, 0),
         }
     }
 }
 
 impl<UserData: Eq + Copy + Debug> Feature<UserData, Control, Event, ToController, ToWorker> for DhtKvFeature<UserData> {
     fn on_shared_input(&mut self, _ctx: &FeatureContext, now: u64, input: FeatureSharedInput) {
-        if let FeatureSharedInput::Tick(_) = input {
-            self.internal.on_tick(now);
+        match input {
+            FeatureSharedInput::Tick(_) => {
+                self.internal.on_tick(now);
+                let (local, remote) = self.internal.map_counts();
+                metrics::DHT_KV_LOCAL_MAPS.track(&mut self.reported_maps.0, local);
+                metrics::DHT_KV_REMOTE_MAPS.track(&mut self.reported_maps.1, remote);
+            }
+            FeatureSharedInput::Connection(ConnectionEvent::Connected(..)) => self.internal.on_connected(now),
+            _ => {}
         }
     }
 
@@ -125,6 +192,7 @@ impl<UserData: Eq + Copy + Debug> Feature<UserData, Control, Event, ToController
         }
     }
 
+    /// The feature is empty after the pending syncs are acked, the controller stops waiting at its shutdown deadline
     fn on_shutdown(&mut self, _ctx: &FeatureContext, _now: u64) {
         log::info!("[DhtKvFeature] Shutdown");
         self.shutdown = true;
@@ -135,7 +203,7 @@ impl<UserData: Eq + Copy + Debug> TaskSwitcherChild<FeatureOutput<UserData, Even
     type Time = u64;
 
     fn is_empty(&self) -> bool {
-        self.shutdown
+        self.shutdown && !self.internal.has_pending_sync()
     }
 
     fn empty_event(&self) -> FeatureOutput<UserData, Event, ToWorker> {
@@ -154,6 +222,13 @@ impl<UserData: Eq + Copy + Debug> TaskSwitcherChild<FeatureOutput<UserData, Even
     }
 }
 
+impl<UserData> Drop for DhtKvFeature<UserData> {
+    fn drop(&mut self) {
+        metrics::DHT_KV_LOCAL_MAPS.track(&mut self.reported_maps.0, 0);
+        metrics::DHT_KV_REMOTE_MAPS.track(&mut self.reported_maps.1, 0);
+    }
+}
+
 #[derive(Derivative)]
 #[derivative(Default(bound = ""))]
 pub struct DhtKvFeatureWorker<UserData> {
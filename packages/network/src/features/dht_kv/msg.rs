@@ -10,6 +10,37 @@ simple_pub_type!(Seq, u64);
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, Hash, PartialEq, Eq)]
 pub struct NodeSession(pub NodeId, pub u64);
 
+/// Match sub-keys which have the same highest `bits` bits as `key`, with `bits` 0 matching all sub-keys
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct KeyPrefix {
+    pub key: Key,
+    pub bits: u8,
+}
+
+impl KeyPrefix {
+    pub fn new(key: Key, bits: u8) -> Self {
+        Self { key, bits }
+    }
+
+    pub fn matches(&self, key: Key) -> bool {
+        match self.bits {
+            0 => true,
+            bits if bits >= 64 => self.key == key,
+            bits => (self.key.0 ^ key.0) >> (64 - bits) == 0,
+        }
+    }
+}
+
+/// Position of a scan, the next page starts after this entry
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ScanCursor(pub(crate) Key, pub(crate) NodeSession);
+
+impl ScanCursor {
+    pub(crate) fn order(&self) -> (u64, NodeId, u64) {
+        (self.0 .0, self.1 .0, self.1 .1)
+    }
+}
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 pub enum DelReason {
     Timeout,
@@ -24,26 +55,40 @@ pub(crate) enum RemoteCommand {
 
 // This part is for client related messages
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum ClientMapCommand {
-    Set(Key, Version, Vec<u8>),
+    Set(Key, Version, Vec<u8>, Option<u64>), //remaining ttl in ms
     Del(Key, Version),
     Sub(u64, Option<NodeSession>), //
     Unsub(u64),
-    OnSetAck(Key, NodeSession, Version), //Seq from OnHSet
-    OnDelAck(Key, NodeSession, Version), //Seq from OnHDel
+    Cas(Key, Option<Version>, Version, Vec<u8>), //expected version, new version
+    OnSetAck(Key, NodeSession, Version),         //Seq from OnHSet
+    OnDelAck(Key, NodeSession, Version),         //Seq from OnHDel
+    /// Versions of all sub-keys set by the sender, the receiver answers with the keys it misses.
+    /// Sub-keys of the sender which are not in the digest are deleted
+    Digest(Vec<(Key, Version)>),
 }
 
 impl ClientMapCommand {
     pub fn is_creator(&self) -> bool {
-        matches!(self, ClientMapCommand::Set(_, _, _) | ClientMapCommand::Sub(_, _))
+        matches!(
+            self,
+            ClientMapCommand::Set(_, _, _, _) | ClientMapCommand::Cas(_, _, _, _) | ClientMapCommand::Sub(_, _) | ClientMapCommand::Digest(_)
+        )
+    }
+
+    /// Writes which are also sent to replicas, Cas must be decided by the relay only
+    pub fn is_replicated(&self) -> bool {
+        matches!(self, ClientMapCommand::Set(_, _, _, _) | ClientMapCommand::Del(_, _) | ClientMapCommand::Digest(_))
     }
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[allow(clippy::enum_variant_names)]
 pub(crate) enum ClientCommand {
     MapCmd(Map, ClientMapCommand),
     MapGet(Map, u64),
+    MapScan(Map, u64, KeyPrefix, u32, Option<ScanCursor>),
 }
 
 // This part is for server related messages
@@ -54,12 +99,75 @@ pub(crate) enum ServerMapEvent {
     DelOk(Key, Version),
     SubOk(u64),
     UnsubOk(u64),
-    OnSet { key: Key, source: NodeSession, version: Version, data: Vec<u8> },
-    OnDel { key: Key, source: NodeSession, version: Version },
+    CasOk(Key, Version),
+    CasFailed(Key, Version, Option<Version>), //requested version, current version
+    OnSet {
+        key: Key,
+        source: NodeSession,
+        version: Version,
+        data: Vec<u8>,
+        ttl: Option<u64>,
+    },
+    OnDel {
+        key: Key,
+        source: NodeSession,
+        version: Version,
+    },
+    /// Keys of a digest which are missing or older in the receiver
+    DigestRes(Vec<Key>),
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[allow(clippy::enum_variant_names)]
 pub(crate) enum ServerEvent {
     MapEvent(Map, ServerMapEvent),
     MapGetRes(Map, u64, Vec<(Key, NodeSession, Version, Vec<u8>)>),
+    MapScanRes(Map, u64, Vec<(Key, NodeSession, Version, Vec<u8>)>, Option<ScanCursor>),
+}
+
+#[cfg(test)]
+mod tests {
+    use atm0s_sdn_router::RouteRule;
+
+    use crate::base::{TransportMsg, TransportMsgHeader, WireCodec};
+
+    use super::{ClientCommand, ClientMapCommand, Key, KeyPrefix, Map, NodeSession, RemoteCommand, ScanCursor, ServerEvent, ServerMapEvent, Version};
+
+    #[test]
+    fn remote_command_roundtrip_all_codecs() {
+        let session = NodeSession(1, 1000);
+        let cmds = [
+            RemoteCommand::Client(session, ClientCommand::MapCmd(Map(1), ClientMapCommand::Set(Key(2), Version(3), vec![1, 2, 3], Some(5000)))),
+            RemoteCommand::Client(session, ClientCommand::MapScan(Map(1), 10, KeyPrefix::new(Key(0xff00), 8), 100, Some(ScanCursor(Key(2), session)))),
+            RemoteCommand::Server(
+                session,
+                ServerEvent::MapEvent(
+                    Map(1),
+                    ServerMapEvent::OnSet {
+                        key: Key(2),
+                        source: session,
+                        version: Version(3),
+                        data: vec![4, 5],
+                        ttl: None,
+                    },
+                ),
+            ),
+            RemoteCommand::Server(session, ServerEvent::MapEvent(Map(1), ServerMapEvent::CasFailed(Key(2), Version(4), None))),
+        ];
+        for codec in [WireCodec::Bincode, WireCodec::Postcard, WireCodec::Cbor] {
+            for cmd in cmds.iter() {
+                let header = TransportMsgHeader::build(super::super::FEATURE_ID, 0, RouteRule::ToKey(2));
+                let buf = TransportMsg::from_payload(header, codec, cmd).take();
+                let msg = TransportMsg::try_from(&buf as &[u8]).expect("Should parse header");
+                assert_eq!(msg.get_payload::<RemoteCommand>(codec).as_ref(), Ok(cmd), "codec {codec:?}");
+            }
+        }
+
+        //bincode is the same encoding as the feature helpers
+        let header = TransportMsgHeader::build(super::super::FEATURE_ID, 0, RouteRule::ToKey(2));
+        assert_eq!(
+            TransportMsg::from_payload(header.clone(), WireCodec::Bincode, &cmds[0]),
+            TransportMsg::from_payload_bincode(header, &cmds[0])
+        );
+    }
 }
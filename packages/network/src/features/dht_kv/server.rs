@@ -9,6 +9,9 @@ use super::{
 
 mod map;
 
+/// Cap of entries in a scan response, which must fit in a single message
+const MAX_SCAN_LIMIT: usize = 64;
+
 pub struct RemoteStorage {
     session: NodeSession,
     maps: HashMap<Map, RemoteMap>,
@@ -24,6 +27,10 @@ impl RemoteStorage {
         }
     }
 
+    pub fn maps(&self) -> usize {
+        self.maps.len()
+    }
+
     pub fn on_tick(&mut self, now: u64) {
         let mut to_remove = vec![];
         for (key, map) in self.maps.iter_mut() {
@@ -65,6 +72,11 @@ impl RemoteStorage {
                 let values = self.maps.get_mut(&key).map(|map| map.dump()).unwrap_or_default();
                 self.queue.push_back((remote, ServerEvent::MapGetRes(key, id, values)));
             }
+            ClientCommand::MapScan(key, id, prefix, limit, cursor) => {
+                let limit = (limit as usize).clamp(1, MAX_SCAN_LIMIT);
+                let (values, next) = self.maps.get(&key).map(|map| map.scan(prefix, limit, cursor)).unwrap_or_default();
+                self.queue.push_back((remote, ServerEvent::MapScanRes(key, id, values, next)));
+            }
         }
     }
 
@@ -1,13 +1,13 @@
 use std::collections::{HashMap, VecDeque};
 
-use crate::features::dht_kv::msg::{ClientMapCommand, Key, NodeSession, ServerMapEvent, Version};
+use crate::features::dht_kv::msg::{ClientMapCommand, Key, KeyPrefix, NodeSession, ScanCursor, ServerMapEvent, Version};
 
 const RESEND_MS: u64 = 200; //We will resend set or del command if we don't get ack in this time
 const TIMEOUT_MS: u64 = 10000; //We will remove sub if we don't get any message from it in this time
 
 enum MapSlot {
     Unspecific,
-    Set { data: Vec<u8>, version: Version, live_at: u64 },
+    Set { data: Vec<u8>, version: Version, live_at: u64, expire_at: Option<u64> },
 }
 
 impl MapSlot {
@@ -15,21 +15,23 @@ impl MapSlot {
         Self::Unspecific
     }
 
-    fn set(&mut self, now: u64, new_version: Version, new_data: Vec<u8>) -> bool {
+    fn set(&mut self, now: u64, new_version: Version, new_data: Vec<u8>, ttl: Option<u64>) -> bool {
         match self {
             MapSlot::Unspecific => {
                 *self = MapSlot::Set {
                     data: new_data,
                     version: new_version,
                     live_at: now,
+                    expire_at: ttl.map(|ttl| now + ttl),
                 };
                 true
             }
-            MapSlot::Set { version, data, live_at } => {
+            MapSlot::Set { version, data, live_at, expire_at } => {
                 if version.0 <= new_version.0 {
                     *version = new_version;
                     *data = new_data;
                     *live_at = now;
+                    *expire_at = ttl.map(|ttl| now + ttl);
                     true
                 } else {
                     false
@@ -53,12 +55,35 @@ impl MapSlot {
         }
     }
 
+    fn version(&self) -> Option<Version> {
+        match self {
+            MapSlot::Unspecific => None,
+            MapSlot::Set { version, .. } => Some(*version),
+        }
+    }
+
     fn dump(&self) -> Option<(Version, Vec<u8>)> {
         match self {
             MapSlot::Unspecific => None,
             MapSlot::Set { version, data, .. } => Some((*version, data.clone())),
         }
     }
+
+    /// Remaining ttl, this is sent instead of the deadline because nodes don't share a clock
+    fn ttl(&self, now: u64) -> Option<u64> {
+        match self {
+            MapSlot::Unspecific => None,
+            MapSlot::Set { expire_at, .. } => expire_at.map(|at| at.saturating_sub(now)),
+        }
+    }
+
+    /// Return version of the slot if it has just lapsed
+    fn expired(&self, now: u64) -> Option<Version> {
+        match self {
+            MapSlot::Set { version, expire_at: Some(at), .. } if now >= *at => Some(*version),
+            _ => None,
+        }
+    }
 }
 
 struct WaitAcksEvent {
@@ -93,6 +118,20 @@ impl RemoteMap {
     }
 
     pub fn on_tick(&mut self, now: u64) {
+        //expire slots with ttl, the source may be gone so we must not wait for its Del
+        let mut expired = vec![];
+        for (key, slot) in self.slots.iter() {
+            if let Some(version) = slot.expired(now) {
+                expired.push((*key, version));
+            }
+        }
+
+        for ((key, source), version) in expired {
+            log::debug!("[ServerMap] Expire key {key} from {} with version {version}", source.0);
+            self.slots.remove(&(key, source));
+            self.fire_event(now, key, source, ServerMapEvent::OnDel { key, version, source });
+        }
+
         //clean-up timeout subs
         let mut to_remove = vec![];
         for (node, slot) in self.subs.iter() {
@@ -139,13 +178,45 @@ impl RemoteMap {
             .collect()
     }
 
+    /// Entries matching prefix which are after cursor, ordered by sub-key then source.
+    /// A cursor is returned when there may be more entries
+    #[allow(clippy::type_complexity)]
+    pub fn scan(&self, prefix: KeyPrefix, limit: usize, cursor: Option<ScanCursor>) -> (Vec<(Key, NodeSession, Version, Vec<u8>)>, Option<ScanCursor>) {
+        let after = cursor.map(|cursor| cursor.order());
+        let mut entries: Vec<_> = self
+            .slots
+            .iter()
+            .filter(|((key, session), _)| prefix.matches(*key) && after.map_or(true, |after| (key.0, session.0, session.1) > after))
+            .filter_map(|((key, session), slot)| slot.dump().map(|(version, data)| (*key, *session, version, data)))
+            .collect();
+        entries.sort_by_key(|(key, session, _, _)| (key.0, session.0, session.1));
+        let next = if entries.len() > limit {
+            entries.truncate(limit);
+            entries.last().map(|(key, session, _, _)| ScanCursor(*key, *session))
+        } else {
+            None
+        };
+        (entries, next)
+    }
+
     pub fn on_client(&mut self, now: u64, remote: NodeSession, cmd: ClientMapCommand) -> Option<ServerMapEvent> {
         match cmd {
-            ClientMapCommand::Set(key, version, data) => {
+            ClientMapCommand::Set(key, version, data, ttl) => {
                 let slot = self.get_slot(key, remote, true).expect("must have slot with auto_create");
-                if slot.set(now, version, data.clone()) {
-                    log::debug!("[ServerMap] Set key {} from {} with version {}", key, remote.0, version.0);
-                    self.fire_event(now, key, remote, ServerMapEvent::OnSet { key, version, source: remote, data });
+                if slot.set(now, version, data.clone(), ttl) {
+                    log::debug!("[ServerMap] Set key {} from {} with version {} ttl {:?}", key, remote.0, version.0, ttl);
+                    self.fire_event(
+                        now,
+                        key,
+                        remote,
+                        ServerMapEvent::OnSet {
+                            key,
+                            version,
+                            source: remote,
+                            data,
+                            ttl,
+                        },
+                    );
                     Some(ServerMapEvent::SetOk(key, version))
                 } else {
                     log::warn!("[ServerMap] Set key {} from {} with version {} failed", key, remote.0, version.0);
@@ -164,6 +235,55 @@ impl RemoteMap {
                     None
                 }
             }
+            ClientMapCommand::Cas(key, expected, version, data) => {
+                let current = self.newest_version(key);
+                //new version must be newer than expected, otherwise the next CAS would compare with a stale version
+                if current != expected || expected.map_or(false, |expected| version.0 <= expected.0) {
+                    log::debug!("[ServerMap] Cas key {key} from {} failed, expected {:?} vs current {:?}", remote.0, expected, current);
+                    return Some(ServerMapEvent::CasFailed(key, version, current));
+                }
+                let slot = self.get_slot(key, remote, true).expect("must have slot with auto_create");
+                if slot.set(now, version, data.clone(), None) {
+                    log::debug!("[ServerMap] Cas key {key} from {} with version {version}", remote.0);
+                    self.fire_event(
+                        now,
+                        key,
+                        remote,
+                        ServerMapEvent::OnSet {
+                            key,
+                            version,
+                            source: remote,
+                            data,
+                            ttl: None,
+                        },
+                    );
+                    Some(ServerMapEvent::CasOk(key, version))
+                } else {
+                    Some(ServerMapEvent::CasFailed(key, version, current))
+                }
+            }
+            ClientMapCommand::Digest(entries) => {
+                //the source doesn't have these anymore, it may have deleted them while we were unreachable
+                let stale: Vec<(Key, Version)> = self
+                    .slots
+                    .iter()
+                    .filter(|((key, source), _)| *source == remote && !entries.iter().any(|(digest_key, _)| digest_key == key))
+                    .filter_map(|((key, _), slot)| slot.version().map(|version| (*key, version)))
+                    .collect();
+                for (key, version) in stale {
+                    log::debug!("[ServerMap] Digest from {} doesn't have key {key}, delete version {version}", remote.0);
+                    self.slots.remove(&(key, remote));
+                    self.fire_event(now, key, remote, ServerMapEvent::OnDel { key, version, source: remote });
+                }
+                //newer version wins, so only keys which are missing or older here are requested
+                let missing: Vec<Key> = entries
+                    .into_iter()
+                    .filter(|(key, version)| self.slots.get(&(*key, remote)).and_then(|slot| slot.version()).map_or(true, |current| current.0 < version.0))
+                    .map(|(key, _)| key)
+                    .collect();
+                log::debug!("[ServerMap] Digest from {} misses {} keys", remote.0, missing.len());
+                (!missing.is_empty()).then_some(ServerMapEvent::DigestRes(missing))
+            }
             ClientMapCommand::Sub(id, locked_session) => {
                 let old = self.subs.insert(remote, SubSlot { last_ts: now, id });
                 if old.is_none() || locked_session != Some(self.session) {
@@ -236,6 +356,15 @@ impl RemoteMap {
         self.slots.is_empty() && self.subs.is_empty() && self.slots_event.is_empty()
     }
 
+    /// Newest version of a sub-key between all sources
+    fn newest_version(&self, key: Key) -> Option<Version> {
+        self.slots
+            .iter()
+            .filter(|((slot_key, _), _)| *slot_key == key)
+            .filter_map(|(_, slot)| slot.dump().map(|(version, _)| version))
+            .max()
+    }
+
     fn get_slot(&mut self, key: Key, source: NodeSession, auto_create: bool) -> Option<&mut MapSlot> {
         if !self.slots.contains_key(&(key, source)) && auto_create {
             log::debug!("[ServerMap] Create new slot for key {key} from node {}", source.0);
@@ -281,6 +410,7 @@ impl RemoteMap {
                     version,
                     source: key.1,
                     data,
+                    ttl: slot.ttl(now),
                 };
                 let entry = self.slots_event.entry(*key).or_insert_with(|| WaitAcksEvent {
                     event: event.clone(),
@@ -301,7 +431,7 @@ impl RemoteMap {
 mod test {
     use super::{MapSlot, RemoteMap};
     use crate::features::dht_kv::{
-        msg::{ClientMapCommand, Key, NodeSession, ServerMapEvent, Version},
+        msg::{ClientMapCommand, Key, KeyPrefix, NodeSession, ServerMapEvent, Version},
         server::map::{RESEND_MS, TIMEOUT_MS},
     };
 
@@ -311,6 +441,7 @@ mod test {
             version: Version(version),
             source,
             data,
+            ttl: None,
         }
     }
 
@@ -326,9 +457,9 @@ mod test {
     fn map_slot_set_del_correct() {
         let mut slot = MapSlot::new();
 
-        assert_eq!(slot.set(0, Version(0), vec![1, 2, 3]), true);
+        assert_eq!(slot.set(0, Version(0), vec![1, 2, 3], None), true);
         assert_eq!(slot.dump(), Some((Version(0), vec![1, 2, 3])));
-        assert_eq!(slot.set(0, Version(1), vec![1, 2, 4]), true);
+        assert_eq!(slot.set(0, Version(1), vec![1, 2, 4], None), true);
         assert_eq!(slot.dump(), Some((Version(1), vec![1, 2, 4])));
         assert_eq!(slot.del(0, Version(1)), Some(Version(1)));
         assert_eq!(slot.dump(), None);
@@ -338,7 +469,7 @@ mod test {
     fn map_slot_set_del_newer_version_correct() {
         let mut slot = MapSlot::new();
 
-        assert_eq!(slot.set(0, Version(0), vec![1, 2, 3]), true);
+        assert_eq!(slot.set(0, Version(0), vec![1, 2, 3], None), true);
         assert_eq!(slot.dump(), Some((Version(0), vec![1, 2, 3])));
         assert_eq!(slot.del(0, Version(100)), Some(Version(0)));
         assert_eq!(slot.dump(), None);
@@ -348,14 +479,41 @@ mod test {
     fn map_slot_set_del_invalid() {
         let mut slot = MapSlot::new();
 
-        assert_eq!(slot.set(0, Version(100), vec![1, 2, 3]), true);
+        assert_eq!(slot.set(0, Version(100), vec![1, 2, 3], None), true);
         assert_eq!(slot.dump(), Some((Version(100), vec![1, 2, 3])));
-        assert_eq!(slot.set(0, Version(1), vec![1, 2, 4]), false);
+        assert_eq!(slot.set(0, Version(1), vec![1, 2, 4], None), false);
         assert_eq!(slot.dump(), Some((Version(100), vec![1, 2, 3])));
         assert_eq!(slot.del(0, Version(1)), None);
         assert_eq!(slot.dump(), Some((Version(100), vec![1, 2, 3])));
     }
 
+    #[test]
+    fn map_digest_should_request_only_mismatched_keys() {
+        let relay = NodeSession(1, 2);
+        let mut map = RemoteMap::new(relay);
+        let source = NodeSession(3, 4);
+
+        for (key, version) in [(1000, 10), (1001, 10), (1002, 10), (1003, 10)] {
+            assert_eq!(
+                map.on_client(0, source, ClientMapCommand::Set(Key(key), Version(version), vec![1], None)),
+                Some(ServerMapEvent::SetOk(Key(key), Version(version)))
+            );
+        }
+
+        //1000 is same, 1001 is newer in source, 1002 is older in source so we keep ours, 1003 is deleted, 1004 is new
+        let digest = vec![(Key(1000), Version(10)), (Key(1001), Version(20)), (Key(1002), Version(5)), (Key(1004), Version(20))];
+        assert_eq!(
+            map.on_client(100, source, ClientMapCommand::Digest(digest)),
+            Some(ServerMapEvent::DigestRes(vec![Key(1001), Key(1004)]))
+        );
+        assert_eq!(map.dump().iter().filter(|(key, ..)| *key == Key(1003)).count(), 0);
+        assert_eq!(map.dump().len(), 3);
+
+        //nothing is missing, no answer
+        assert_eq!(map.on_client(200, source, ClientMapCommand::Digest(vec![(Key(1000), Version(10))])), None);
+        assert_eq!(map.dump().len(), 1);
+    }
+
     #[test]
     fn map_correct_set_update_del_event() {
         let relay = NodeSession(1, 2);
@@ -366,13 +524,13 @@ mod test {
 
         assert_eq!(map.on_client(0, consumer, ClientMapCommand::Sub(1, None)), Some(ServerMapEvent::SubOk(1)));
         assert_eq!(
-            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1, 2, 3, 4])),
+            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1, 2, 3, 4], None)),
             Some(ServerMapEvent::SetOk(Key(1000), Version(1)))
         );
         assert_eq!(map.pop_action(), Some((consumer, on_set(1000, 1, source, vec![1, 2, 3, 4]),)));
 
         assert_eq!(
-            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(2), vec![1, 2, 3, 5])),
+            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(2), vec![1, 2, 3, 5], None)),
             Some(ServerMapEvent::SetOk(Key(1000), Version(2)))
         );
         assert_eq!(map.pop_action(), Some((consumer, on_set(1000, 2, source, vec![1, 2, 3, 5]),)));
@@ -393,7 +551,7 @@ mod test {
         let consumer = NodeSession(5, 6);
 
         assert_eq!(
-            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1, 2, 3, 4])),
+            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1, 2, 3, 4], None)),
             Some(ServerMapEvent::SetOk(Key(1000), Version(1)))
         );
         assert_eq!(map.pop_action(), None);
@@ -415,7 +573,7 @@ mod test {
         let consumer = NodeSession(5, 6);
 
         assert_eq!(
-            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1, 2, 3, 4])),
+            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1, 2, 3, 4], None)),
             Some(ServerMapEvent::SetOk(Key(1000), Version(1)))
         );
         assert_eq!(map.pop_action(), None);
@@ -445,7 +603,7 @@ mod test {
         assert_eq!(map.pop_action(), None);
 
         assert_eq!(
-            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1, 2, 3, 4])),
+            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1, 2, 3, 4], None)),
             Some(ServerMapEvent::SetOk(Key(1000), Version(1)))
         );
         assert_eq!(map.pop_action(), None);
@@ -466,7 +624,7 @@ mod test {
         assert_eq!(map.pop_action(), None);
 
         assert_eq!(
-            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1, 2, 3, 4])),
+            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1, 2, 3, 4], None)),
             Some(ServerMapEvent::SetOk(Key(1000), Version(1)))
         );
         assert_eq!(map.pop_action(), Some((consumer, on_set(1000, 1, source, vec![1, 2, 3, 4]))));
@@ -485,14 +643,14 @@ mod test {
         assert_eq!(map.pop_action(), None);
 
         assert_eq!(
-            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1, 2, 3, 4])),
+            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1, 2, 3, 4], None)),
             Some(ServerMapEvent::SetOk(Key(1000), Version(1)))
         );
         assert_eq!(map.pop_action(), Some((consumer, on_set(1000, 1, source, vec![1, 2, 3, 4]))));
         assert_eq!(map.pop_action(), None);
 
         //set with older version should not affected
-        assert_eq!(map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(0), vec![1, 2, 3, 4])), None);
+        assert_eq!(map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(0), vec![1, 2, 3, 4], None)), None);
         assert_eq!(map.pop_action(), None);
     }
 
@@ -508,7 +666,7 @@ mod test {
         assert_eq!(map.pop_action(), None);
 
         assert_eq!(
-            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1, 2, 3, 4])),
+            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1, 2, 3, 4], None)),
             Some(ServerMapEvent::SetOk(Key(1000), Version(1)))
         );
         assert_eq!(map.pop_action(), Some((consumer, on_set(1000, 1, source, vec![1, 2, 3, 4]))));
@@ -532,7 +690,7 @@ mod test {
         assert_eq!(map.pop_action(), None);
 
         assert_eq!(
-            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1, 2, 3, 4])),
+            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1, 2, 3, 4], None)),
             Some(ServerMapEvent::SetOk(Key(1000), Version(1)))
         );
         assert_eq!(map.pop_action(), Some((consumer, on_set(1000, 1, source, vec![1, 2, 3, 4]))));
@@ -560,7 +718,7 @@ mod test {
         assert_eq!(map.pop_action(), None);
 
         assert_eq!(
-            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1, 2, 3, 4])),
+            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1, 2, 3, 4], None)),
             Some(ServerMapEvent::SetOk(Key(1000), Version(1)))
         );
         assert_eq!(map.pop_action(), Some((consumer, on_set(1000, 1, source, vec![1, 2, 3, 4]))));
@@ -581,7 +739,7 @@ mod test {
         let consumer = NodeSession(5, 6);
 
         assert_eq!(
-            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1, 2, 3, 4])),
+            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1, 2, 3, 4], None)),
             Some(ServerMapEvent::SetOk(Key(1000), Version(1)))
         );
         assert_eq!(map.pop_action(), None);
@@ -608,7 +766,7 @@ mod test {
         assert_eq!(map.pop_action(), None);
 
         assert_eq!(
-            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1, 2, 3, 4])),
+            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1, 2, 3, 4], None)),
             Some(ServerMapEvent::SetOk(Key(1000), Version(1)))
         );
         assert_eq!(map.pop_action(), Some((consumer, on_set(1000, 1, source, vec![1, 2, 3, 4]))));
@@ -633,7 +791,7 @@ mod test {
         assert_eq!(map.pop_action(), None);
 
         assert_eq!(
-            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1, 2, 3, 4])),
+            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1, 2, 3, 4], None)),
             Some(ServerMapEvent::SetOk(Key(1000), Version(1)))
         );
         assert_eq!(map.pop_action(), Some((consumer, on_set(1000, 1, source, vec![1, 2, 3, 4]))));
@@ -664,7 +822,7 @@ mod test {
         assert_eq!(map.pop_action(), None);
 
         assert_eq!(
-            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1, 2, 3, 4])),
+            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1, 2, 3, 4], None)),
             Some(ServerMapEvent::SetOk(Key(1000), Version(1)))
         );
         assert_eq!(map.pop_action(), None);
@@ -678,7 +836,7 @@ mod test {
         let source = NodeSession(3, 4);
 
         assert_eq!(
-            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1, 2, 3, 4])),
+            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1, 2, 3, 4], None)),
             Some(ServerMapEvent::SetOk(Key(1000), Version(1)))
         );
         assert_eq!(map.pop_action(), None);
@@ -686,4 +844,149 @@ mod test {
         assert_eq!(map.on_client(0, source, ClientMapCommand::Sub(1, None)), Some(ServerMapEvent::SubOk(1)));
         assert_eq!(map.pop_action(), None);
     }
+
+    #[test]
+    fn map_slot_should_expire_after_ttl() {
+        let relay = NodeSession(1, 2);
+        let mut map = RemoteMap::new(relay);
+
+        let source = NodeSession(3, 4);
+        let consumer = NodeSession(5, 6);
+
+        assert_eq!(
+            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1, 2, 3, 4], Some(1000))),
+            Some(ServerMapEvent::SetOk(Key(1000), Version(1)))
+        );
+        //late subscriber only gets remaining ttl
+        assert_eq!(map.on_client(400, consumer, ClientMapCommand::Sub(1, None)), Some(ServerMapEvent::SubOk(1)));
+        assert_eq!(
+            map.pop_action(),
+            Some((
+                consumer,
+                ServerMapEvent::OnSet {
+                    key: Key(1000),
+                    version: Version(1),
+                    source,
+                    data: vec![1, 2, 3, 4],
+                    ttl: Some(600),
+                }
+            ))
+        );
+        assert_eq!(map.on_client(400, consumer, ClientMapCommand::OnSetAck(Key(1000), source, Version(1))), None);
+
+        map.on_tick(999);
+        assert_eq!(map.pop_action(), None);
+
+        //source didn't send Del, but the slot still expires
+        map.on_tick(1000);
+        assert_eq!(map.pop_action(), Some((consumer, on_del(1000, 1, source))));
+        assert_eq!(map.pop_action(), None);
+        assert_eq!(map.slots.len(), 0);
+    }
+
+    #[test]
+    fn map_slot_set_again_should_refresh_ttl() {
+        let relay = NodeSession(1, 2);
+        let mut map = RemoteMap::new(relay);
+
+        let source = NodeSession(3, 4);
+
+        assert_eq!(
+            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1, 2, 3, 4], Some(1000))),
+            Some(ServerMapEvent::SetOk(Key(1000), Version(1)))
+        );
+        assert_eq!(
+            map.on_client(800, source, ClientMapCommand::Set(Key(1000), Version(2), vec![1, 2, 3, 4], Some(1000))),
+            Some(ServerMapEvent::SetOk(Key(1000), Version(2)))
+        );
+        map.on_tick(1000);
+        assert_eq!(map.slots.len(), 1);
+        map.on_tick(1800);
+        assert_eq!(map.slots.len(), 0);
+
+        //set without ttl never expires
+        assert_eq!(
+            map.on_client(2000, source, ClientMapCommand::Set(Key(1000), Version(3), vec![1, 2, 3, 4], None)),
+            Some(ServerMapEvent::SetOk(Key(1000), Version(3)))
+        );
+        map.on_tick(u64::MAX);
+        assert_eq!(map.slots.len(), 1);
+    }
+
+    #[test]
+    fn map_cas_should_compare_newest_version_of_all_sources() {
+        let relay = NodeSession(1, 2);
+        let mut map = RemoteMap::new(relay);
+
+        let source1 = NodeSession(3, 4);
+        let source2 = NodeSession(5, 6);
+
+        assert_eq!(
+            map.on_client(0, source1, ClientMapCommand::Cas(Key(1000), None, Version(1), vec![1])),
+            Some(ServerMapEvent::CasOk(Key(1000), Version(1)))
+        );
+        //sub-key already exists
+        assert_eq!(
+            map.on_client(0, source2, ClientMapCommand::Cas(Key(1000), None, Version(2), vec![2])),
+            Some(ServerMapEvent::CasFailed(Key(1000), Version(2), Some(Version(1))))
+        );
+        //new version must be newer than expected
+        assert_eq!(
+            map.on_client(0, source2, ClientMapCommand::Cas(Key(1000), Some(Version(1)), Version(1), vec![2])),
+            Some(ServerMapEvent::CasFailed(Key(1000), Version(1), Some(Version(1))))
+        );
+        assert_eq!(
+            map.on_client(0, source2, ClientMapCommand::Cas(Key(1000), Some(Version(1)), Version(2), vec![2])),
+            Some(ServerMapEvent::CasOk(Key(1000), Version(2)))
+        );
+        //source1 is now stale
+        assert_eq!(
+            map.on_client(0, source1, ClientMapCommand::Cas(Key(1000), Some(Version(1)), Version(3), vec![3])),
+            Some(ServerMapEvent::CasFailed(Key(1000), Version(3), Some(Version(2))))
+        );
+        assert_eq!(map.newest_version(Key(1000)), Some(Version(2)));
+        assert_eq!(map.newest_version(Key(1001)), None);
+    }
+
+    #[test]
+    fn key_prefix_match() {
+        let prefix = KeyPrefix::new(Key(0xAB00_0000_0000_0000), 8);
+        assert!(prefix.matches(Key(0xAB00_0000_0000_0001)));
+        assert!(prefix.matches(Key(0xABFF_FFFF_FFFF_FFFF)));
+        assert!(!prefix.matches(Key(0xAC00_0000_0000_0000)));
+        assert!(KeyPrefix::new(Key(0), 0).matches(Key(u64::MAX)));
+        assert!(KeyPrefix::new(Key(5), 64).matches(Key(5)));
+        assert!(!KeyPrefix::new(Key(5), 64).matches(Key(4)));
+    }
+
+    #[test]
+    fn map_scan_with_prefix_and_cursor() {
+        let relay = NodeSession(1, 2);
+        let mut map = RemoteMap::new(relay);
+
+        let source1 = NodeSession(3, 4);
+        let source2 = NodeSession(5, 6);
+        let channel = 0x0100_0000_0000_0000;
+
+        for (sub_key, source) in [(channel + 2, source1), (channel + 1, source2), (channel + 1, source1), (0x0200_0000_0000_0000, source1)] {
+            assert_eq!(
+                map.on_client(0, source, ClientMapCommand::Set(Key(sub_key), Version(1), vec![1], None)),
+                Some(ServerMapEvent::SetOk(Key(sub_key), Version(1)))
+            );
+        }
+
+        let prefix = KeyPrefix::new(Key(channel), 8);
+        let (page1, cursor) = map.scan(prefix, 2, None);
+        assert_eq!(page1, vec![(Key(channel + 1), source1, Version(1), vec![1]), (Key(channel + 1), source2, Version(1), vec![1])]);
+        assert!(cursor.is_some());
+
+        let (page2, cursor) = map.scan(prefix, 2, cursor);
+        assert_eq!(page2, vec![(Key(channel + 2), source1, Version(1), vec![1])]);
+        assert_eq!(cursor, None);
+
+        //exact page size doesn't need next page
+        let (page, cursor) = map.scan(prefix, 3, None);
+        assert_eq!(page.len(), 3);
+        assert_eq!(cursor, None);
+    }
 }
@@ -0,0 +1,541 @@
+//! Coordinated UDP hole punching between nodes which can't connect to each other directly.
+//!
+//! The requester sends an `Offer` to a rendezvous node which is connected to both peers. The rendezvous answers both
+//! of them with an `Introduce` carrying the public address it observes for the other peer, then both peers connect
+//! to that address at the same time so each NAT opens a mapping for the other side. A successful punch is a normal
+//! neighbour connection. When punching fails the peers keep talking over the route through the rendezvous, which is
+//! reported as `Event::Relayed`.
+//!
+//! The NAT type is detected by asking two neighbours which source address they observe for this node. Different
+//! ports, or addresses, mean a symmetric NAT, which maps each destination to another port, so the address of the
+//! introduction is useless and punches go straight to relaying.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    net::{IpAddr, SocketAddr},
+};
+
+use atm0s_sdn_identity::{ConnId, NodeAddr, NodeAddrBuilder, NodeId, Protocol};
+use atm0s_sdn_router::RouteRule;
+use derivative::Derivative;
+use sans_io_runtime::{collections::DynamicDeque, TaskSwitcherChild};
+use serde::{Deserialize, Serialize};
+
+use crate::base::{
+    ConnectionCtx, ConnectionEvent, Feature, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerInput, FeatureWorkerOutput,
+    NeighboursConnectError, NetOutgoingMeta, Ttl,
+};
+
+pub const FEATURE_ID: u8 = 9;
+pub const FEATURE_NAME: &str = "hole_punch";
+/// Offers are resent at this interval until the rendezvous introduces the peer
+pub const OFFER_RESEND_MS: u64 = 1000;
+/// How long a punch can take, including the introduction
+pub const PUNCH_TIMEOUT_MS: u64 = 10_000;
+/// How long the neighbours can take to report the observed address
+pub const NAT_PROBE_TIMEOUT_MS: u64 = 5000;
+/// Number of neighbours which are asked for the observed address
+const NAT_PROBE_PEERS: usize = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Control {
+    /// Punch a direct connection to `node` with the help of `via`, which must be connected to both nodes
+    Punch { node: NodeId, via: NodeId },
+    /// Ask the neighbours for the observed address and classify the NAT, answered with `Event::Nat`
+    DetectNat,
+    /// Last detected NAT type, without probing
+    GetNat,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum NatType {
+    /// Not detected yet, or the probe failed
+    #[default]
+    Unknown,
+    /// Neighbours observe the local address
+    Open,
+    /// All neighbours observe the same public address
+    Cone,
+    /// Each neighbour observes another public address, punching can't work
+    Symmetric,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayReason {
+    /// The rendezvous node has no connection to the peer
+    PeerUnreachable,
+    /// The direct connection is refused
+    Rejected(NeighboursConnectError),
+    /// No direct connection within PUNCH_TIMEOUT_MS
+    Timeout,
+    /// This node is behind a symmetric NAT, punching is not tried
+    SymmetricNat,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// A direct connection to the node is established
+    Punched(NodeId, ConnId),
+    /// Punching failed, traffic to the node keeps going through the rendezvous node
+    Relayed(NodeId, RelayReason),
+    Nat(NatType),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ToWorker;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ToController;
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Message {
+    /// Ask the rendezvous node to introduce the sender to the peer
+    Offer { peer: NodeId },
+    /// Public addresses of the peer as observed by the rendezvous node, sent to both sides
+    Introduce { peer: NodeId, addrs: Vec<SocketAddr> },
+    /// The rendezvous node has no connection to the peer
+    Unreachable { peer: NodeId },
+    /// Ask a neighbour for the source address of this connection
+    WhoAmI,
+    /// Source address of the connection as observed by the neighbour
+    YouAre { addr: SocketAddr },
+}
+
+#[derive(Debug)]
+struct NatProbe<UserData> {
+    waiters: Vec<FeatureControlActor<UserData>>,
+    started_ms: u64,
+    peers: Vec<NodeId>,
+    /// Observed and local address of the connection to each peer which answered
+    reports: HashMap<NodeId, (SocketAddr, SocketAddr)>,
+}
+
+#[derive(Debug)]
+struct PunchSlot<UserData> {
+    waiters: Vec<FeatureControlActor<UserData>>,
+    via: NodeId,
+    started_ms: u64,
+    offer_ms: u64,
+    introduced: bool,
+}
+
+pub type Output<UserData> = FeatureOutput<UserData, Event, ToWorker>;
+pub type WorkerOutput<UserData> = FeatureWorkerOutput<UserData, Control, Event, ToController>;
+
+#[derive(Debug, Derivative)]
+#[derivative(Default(bound = ""))]
+pub struct HolePunchFeature<UserData> {
+    /// Remote address of each direct connection, this is the public address when the node is behind a NAT
+    observed: HashMap<NodeId, HashMap<ConnId, SocketAddr>>,
+    punches: HashMap<NodeId, PunchSlot<UserData>>,
+    nat: NatType,
+    nat_probe: Option<NatProbe<UserData>>,
+    queue: VecDeque<Output<UserData>>,
+    shutdown: bool,
+}
+
+impl<UserData: Debug + Copy> HolePunchFeature<UserData> {
+    fn process_control(&mut self, now_ms: u64, actor: FeatureControlActor<UserData>, control: Control) {
+        match control {
+            Control::Punch { node, via } => {
+                if let Some(conn) = self.observed.get(&node).and_then(|conns| conns.keys().next()) {
+                    log::debug!("[HolePunchFeature] Already connected to {node}");
+                    self.queue.push_back(FeatureOutput::Event(actor, Event::Punched(node, *conn)));
+                } else if self.nat == NatType::Symmetric {
+                    log::info!("[HolePunchFeature] Behind symmetric NAT => relay to {node} via {via}");
+                    self.queue.push_back(FeatureOutput::Event(actor, Event::Relayed(node, RelayReason::SymmetricNat)));
+                } else if let Some(slot) = self.punches.get_mut(&node) {
+                    log::debug!("[HolePunchFeature] Punch to {node} is in progress => push to wait queue");
+                    slot.waiters.push(actor);
+                } else {
+                    log::info!("[HolePunchFeature] Offer punch to {node} via {via}");
+                    self.punches.insert(
+                        node,
+                        PunchSlot {
+                            waiters: vec![actor],
+                            via,
+                            started_ms: now_ms,
+                            offer_ms: now_ms,
+                            introduced: false,
+                        },
+                    );
+                    Self::send_to(&mut self.queue, via, Message::Offer { peer: node });
+                }
+            }
+            Control::DetectNat => {
+                if let Some(probe) = self.nat_probe.as_mut() {
+                    log::debug!("[HolePunchFeature] NAT probe is in progress => push to wait queue");
+                    probe.waiters.push(actor);
+                    return;
+                }
+                let mut nodes: Vec<NodeId> = self.observed.keys().copied().collect();
+                nodes.sort();
+                nodes.truncate(NAT_PROBE_PEERS);
+                if nodes.len() < NAT_PROBE_PEERS {
+                    log::warn!("[HolePunchFeature] Need {NAT_PROBE_PEERS} neighbours to detect NAT, got {}", nodes.len());
+                    self.queue.push_back(FeatureOutput::Event(actor, Event::Nat(NatType::Unknown)));
+                    return;
+                }
+                log::info!("[HolePunchFeature] Detect NAT with neighbours {:?}", nodes);
+                for node in &nodes {
+                    if let Some(conn) = self.observed.get(node).and_then(|conns| conns.keys().next()) {
+                        let msg = bincode::serialize(&Message::WhoAmI).expect("Should to bytes");
+                        self.queue.push_back(FeatureOutput::SendDirect(*conn, NetOutgoingMeta::new(true, Ttl::default(), 0, true), msg.into()));
+                    }
+                }
+                self.nat_probe = Some(NatProbe {
+                    waiters: vec![actor],
+                    started_ms: now_ms,
+                    peers: nodes,
+                    reports: HashMap::new(),
+                });
+            }
+            Control::GetNat => self.queue.push_back(FeatureOutput::Event(actor, Event::Nat(self.nat))),
+        }
+    }
+
+    /// Probe messages are answered over the connection they came from, so they are only accepted from neighbours
+    fn process_probe(&mut self, ctx: &ConnectionCtx, msg: Message) {
+        match msg {
+            Message::WhoAmI => {
+                let msg = bincode::serialize(&Message::YouAre { addr: ctx.pair.remote }).expect("Should to bytes");
+                self.queue
+                    .push_back(FeatureOutput::SendDirect(ctx.conn, NetOutgoingMeta::new(true, Ttl::default(), 0, true), msg.into()));
+            }
+            Message::YouAre { addr } => {
+                let Some(probe) = self.nat_probe.as_mut() else {
+                    return;
+                };
+                if !probe.peers.contains(&ctx.node) {
+                    return;
+                }
+                log::debug!("[HolePunchFeature] Neighbour {} observes {addr} for local {}", ctx.node, ctx.pair.local);
+                probe.reports.insert(ctx.node, (addr, ctx.pair.local));
+                if probe.reports.len() == probe.peers.len() {
+                    let nat = classify_nat(probe.reports.values());
+                    log::info!("[HolePunchFeature] Detected NAT {:?}", nat);
+                    self.nat = nat;
+                    self.finish_nat_probe(nat);
+                }
+            }
+            msg => self.process_remote(ctx.node, msg),
+        }
+    }
+
+    fn finish_nat_probe(&mut self, nat: NatType) {
+        if let Some(probe) = self.nat_probe.take() {
+            for actor in probe.waiters {
+                self.queue.push_back(FeatureOutput::Event(actor, Event::Nat(nat)));
+            }
+        }
+    }
+
+    fn process_remote(&mut self, from: NodeId, msg: Message) {
+        log::debug!("[HolePunchFeature] Received message from {from}: {:?}", msg);
+        match msg {
+            Message::Offer { peer } => match (self.observed_addrs(from), self.observed_addrs(peer)) {
+                (Some(from_addrs), Some(peer_addrs)) => {
+                    log::info!("[HolePunchFeature] Introduce {from} {:?} and {peer} {:?}", from_addrs, peer_addrs);
+                    Self::send_to(&mut self.queue, from, Message::Introduce { peer, addrs: peer_addrs });
+                    Self::send_to(&mut self.queue, peer, Message::Introduce { peer: from, addrs: from_addrs });
+                }
+                _ => {
+                    log::warn!("[HolePunchFeature] Can't introduce {from} to {peer}, one of them is not a neighbour");
+                    Self::send_to(&mut self.queue, from, Message::Unreachable { peer });
+                }
+            },
+            Message::Introduce { peer, addrs } => {
+                if let Some(slot) = self.punches.get_mut(&peer) {
+                    if slot.via != from {
+                        log::warn!("[HolePunchFeature] Reject Introduce of {peer} from wrong rendezvous {from} vs {}", slot.via);
+                        return;
+                    }
+                    if slot.introduced {
+                        return;
+                    }
+                    slot.introduced = true;
+                }
+                if self.observed.contains_key(&peer) || addrs.is_empty() {
+                    return;
+                }
+                log::info!("[HolePunchFeature] Punch to {peer} at {:?}, introduced by {from}", addrs);
+                self.queue.push_back(FeatureOutput::NeighboursConnectTo(build_addr(peer, &addrs)));
+            }
+            Message::Unreachable { peer } => {
+                if self.punches.get(&peer).map(|slot| slot.via) == Some(from) {
+                    log::warn!("[HolePunchFeature] Rendezvous {from} has no connection to {peer} => relayed");
+                    self.finish(peer, |_| Event::Relayed(peer, RelayReason::PeerUnreachable));
+                }
+            }
+            Message::WhoAmI | Message::YouAre { .. } => {
+                log::warn!("[HolePunchFeature] Reject NAT probe from {from} which is not sent over a connection");
+            }
+        }
+    }
+
+    fn observed_addrs(&self, node: NodeId) -> Option<Vec<SocketAddr>> {
+        let conns = self.observed.get(&node)?;
+        let mut addrs: Vec<SocketAddr> = conns.values().copied().collect();
+        addrs.sort();
+        addrs.dedup();
+        Some(addrs)
+    }
+
+    fn finish(&mut self, node: NodeId, event: impl Fn(NodeId) -> Event) {
+        if let Some(slot) = self.punches.remove(&node) {
+            for actor in slot.waiters {
+                self.queue.push_back(FeatureOutput::Event(actor, event(node)));
+            }
+        }
+    }
+
+    fn send_to(queue: &mut VecDeque<Output<UserData>>, node: NodeId, msg: Message) {
+        let msg = bincode::serialize(&msg).expect("Should to bytes");
+        queue.push_back(FeatureOutput::SendRoute(RouteRule::ToNode(node), NetOutgoingMeta::new(true, Ttl::default(), 0, true), msg.into()));
+    }
+}
+
+impl<UserData: Debug + Copy> Feature<UserData, Control, Event, ToController, ToWorker> for HolePunchFeature<UserData> {
+    fn on_shared_input(&mut self, _ctx: &FeatureContext, now: u64, input: FeatureSharedInput) {
+        match input {
+            FeatureSharedInput::Tick(_) => {
+                let timeout: Vec<NodeId> = self.punches.iter().filter(|(_, slot)| now >= slot.started_ms + PUNCH_TIMEOUT_MS).map(|(node, _)| *node).collect();
+                for node in timeout {
+                    log::warn!("[HolePunchFeature] Punch to {node} timeout => relayed");
+                    self.finish(node, |node| Event::Relayed(node, RelayReason::Timeout));
+                }
+                if self.nat_probe.as_ref().is_some_and(|probe| now >= probe.started_ms + NAT_PROBE_TIMEOUT_MS) {
+                    log::warn!("[HolePunchFeature] NAT probe timeout");
+                    self.finish_nat_probe(NatType::Unknown);
+                }
+                for (node, slot) in self.punches.iter_mut() {
+                    if !slot.introduced && now >= slot.offer_ms + OFFER_RESEND_MS {
+                        log::debug!("[HolePunchFeature] Resend offer to {node} via {}", slot.via);
+                        slot.offer_ms = now;
+                        Self::send_to(&mut self.queue, slot.via, Message::Offer { peer: *node });
+                    }
+                }
+            }
+            FeatureSharedInput::Connection(ConnectionEvent::Connected(ctx, _)) => {
+                self.observed.entry(ctx.node).or_default().insert(ctx.conn, ctx.pair.remote);
+                self.finish(ctx.node, |node| Event::Punched(node, ctx.conn));
+            }
+            FeatureSharedInput::Connection(ConnectionEvent::Disconnected(ctx, _)) => {
+                if let Some(conns) = self.observed.get_mut(&ctx.node) {
+                    conns.remove(&ctx.conn);
+                    if conns.is_empty() {
+                        self.observed.remove(&ctx.node);
+                    }
+                }
+            }
+            FeatureSharedInput::Connection(ConnectionEvent::ConnectRejected(node, err)) => {
+                if self.punches.get(&node).map(|slot| slot.introduced) == Some(true) {
+                    log::warn!("[HolePunchFeature] Punch to {node} rejected {:?} => relayed", err);
+                    self.finish(node, |node| Event::Relayed(node, RelayReason::Rejected(err)));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn on_input(&mut self, _ctx: &FeatureContext, now_ms: u64, input: FeatureInput<'_, UserData, Control, ToController>) {
+        match input {
+            FeatureInput::Control(actor, control) => self.process_control(now_ms, actor, control),
+            FeatureInput::Net(ctx, meta, msg) => {
+                if !meta.secure {
+                    log::warn!("[HolePunchFeature] reject unsecure message");
+                    return;
+                }
+                match (meta.source, bincode::deserialize::<Message>(&msg)) {
+                    //direct messages come from the neighbour itself
+                    (Some(from), Ok(msg)) if from == ctx.node => self.process_probe(ctx, msg),
+                    (Some(from), Ok(msg)) => self.process_remote(from, msg),
+                    _ => {}
+                }
+            }
+            FeatureInput::Local(meta, msg) => {
+                if !meta.secure {
+                    log::warn!("[HolePunchFeature] reject unsecure message");
+                    return;
+                }
+                if let (Some(from), Ok(msg)) = (meta.source, bincode::deserialize::<Message>(&msg)) {
+                    self.process_remote(from, msg)
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn on_shutdown(&mut self, _ctx: &FeatureContext, _now: u64) {
+        self.shutdown = true;
+    }
+}
+
+impl<UserData> TaskSwitcherChild<Output<UserData>> for HolePunchFeature<UserData> {
+    type Time = u64;
+
+    fn is_empty(&self) -> bool {
+        self.shutdown && self.queue.is_empty()
+    }
+
+    fn empty_event(&self) -> Output<UserData> {
+        Output::OnResourceEmpty
+    }
+
+    fn pop_output(&mut self, _now: u64) -> Option<Output<UserData>> {
+        self.queue.pop_front()
+    }
+}
+
+/// Observed and local address pairs of the probe answers
+fn classify_nat<'a>(reports: impl Iterator<Item = &'a (SocketAddr, SocketAddr)>) -> NatType {
+    let reports: Vec<&(SocketAddr, SocketAddr)> = reports.collect();
+    let Some((first, _)) = reports.first() else {
+        return NatType::Unknown;
+    };
+    if reports.iter().all(|(observed, local)| observed == local) {
+        NatType::Open
+    } else if reports.iter().all(|(observed, _)| observed == first) {
+        NatType::Cone
+    } else {
+        NatType::Symmetric
+    }
+}
+
+/// NodeAddr with an udp address for each observed address
+fn build_addr(node: NodeId, addrs: &[SocketAddr]) -> NodeAddr {
+    let mut builder = NodeAddrBuilder::new(node);
+    for addr in addrs {
+        match addr.ip() {
+            IpAddr::V4(ip) => builder.add_protocol(Protocol::Ip4(ip)),
+            IpAddr::V6(ip) => builder.add_protocol(Protocol::Ip6(ip)),
+        }
+        builder.add_protocol(Protocol::Udp(addr.port()));
+    }
+    builder.addr()
+}
+
+#[derive(Derivative)]
+#[derivative(Default(bound = ""))]
+pub struct HolePunchFeatureWorker<UserData> {
+    queue: DynamicDeque<WorkerOutput<UserData>, 1>,
+    shutdown: bool,
+}
+
+impl<UserData> FeatureWorker<UserData, Control, Event, ToController, ToWorker> for HolePunchFeatureWorker<UserData> {
+    fn on_input(&mut self, _ctx: &mut crate::base::FeatureWorkerContext, _now: u64, input: crate::base::FeatureWorkerInput<UserData, Control, ToWorker>) {
+        match input {
+            FeatureWorkerInput::Control(actor, control) => self.queue.push_back(FeatureWorkerOutput::ForwardControlToController(actor, control)),
+            FeatureWorkerInput::Network(conn, header, buf) => self.queue.push_back(FeatureWorkerOutput::ForwardNetworkToController(conn, header, buf)),
+            #[cfg(feature = "vpn")]
+            FeatureWorkerInput::TunPkt(..) => {}
+            FeatureWorkerInput::FromController(..) => {
+                log::warn!("No handler for FromController");
+            }
+            FeatureWorkerInput::Local(header, buf) => self.queue.push_back(FeatureWorkerOutput::ForwardLocalToController(header, buf)),
+        }
+    }
+
+    fn on_shutdown(&mut self, _ctx: &mut crate::base::FeatureWorkerContext, _now: u64) {
+        log::info!("[HolePunchFeatureWorker] Shutdown");
+        self.shutdown = true;
+    }
+}
+
+impl<UserData> TaskSwitcherChild<WorkerOutput<UserData>> for HolePunchFeatureWorker<UserData> {
+    type Time = u64;
+
+    fn is_empty(&self) -> bool {
+        self.shutdown && self.queue.is_empty()
+    }
+
+    fn empty_event(&self) -> WorkerOutput<UserData> {
+        WorkerOutput::OnResourceEmpty
+    }
+
+    fn pop_output(&mut self, _now: u64) -> Option<WorkerOutput<UserData>> {
+        self.queue.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use atm0s_sdn_router::RouteRule;
+    use sans_io_runtime::TaskSwitcherChild;
+
+    use crate::base::{Feature, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput};
+
+    use super::{build_addr, classify_nat, Control, Event, HolePunchFeature, Message, NatType, RelayReason, ToWorker, OFFER_RESEND_MS, PUNCH_TIMEOUT_MS};
+
+    fn decode_msg(msg: Option<FeatureOutput<(), Event, ToWorker>>) -> Option<(RouteRule, Message)> {
+        match msg? {
+            FeatureOutput::SendRoute(rule, _, msg) => Some((rule, bincode::deserialize(&msg).expect("Should decode"))),
+            _ => panic!("Should be SendRoute"),
+        }
+    }
+
+    #[test]
+    fn rendezvous_without_peer_should_answer_unreachable() {
+        let mut feature = HolePunchFeature::<()>::default();
+        feature.process_remote(1, Message::Offer { peer: 2 });
+        assert_eq!(decode_msg(feature.pop_output(0)), Some((RouteRule::ToNode(1), Message::Unreachable { peer: 2 })));
+        assert_eq!(feature.pop_output(0), None);
+    }
+
+    #[test]
+    fn introduce_should_connect_to_observed_addrs() {
+        let mut feature = HolePunchFeature::<()>::default();
+        let ctx = FeatureContext { node_id: 1, session: 0 };
+        let actor = FeatureControlActor::Controller(());
+        feature.on_input(&ctx, 0, FeatureInput::Control(actor, Control::Punch { node: 2, via: 3 }));
+        assert_eq!(decode_msg(feature.pop_output(0)), Some((RouteRule::ToNode(3), Message::Offer { peer: 2 })));
+        assert_eq!(feature.pop_output(0), None);
+
+        //offer is resent until introduced
+        feature.on_shared_input(&ctx, OFFER_RESEND_MS, FeatureSharedInput::Tick(1));
+        assert_eq!(decode_msg(feature.pop_output(0)), Some((RouteRule::ToNode(3), Message::Offer { peer: 2 })));
+
+        let addrs: Vec<SocketAddr> = vec!["1.2.3.4:1000".parse().expect("Should parse")];
+        //only the rendezvous which we asked can introduce
+        feature.process_remote(4, Message::Introduce { peer: 2, addrs: addrs.clone() });
+        assert_eq!(feature.pop_output(0), None);
+
+        feature.process_remote(3, Message::Introduce { peer: 2, addrs: addrs.clone() });
+        assert_eq!(feature.pop_output(0), Some(FeatureOutput::NeighboursConnectTo(build_addr(2, &addrs))));
+        assert_eq!(feature.pop_output(0), None);
+
+        feature.on_shared_input(&ctx, PUNCH_TIMEOUT_MS, FeatureSharedInput::Tick(2));
+        assert_eq!(feature.pop_output(0), Some(FeatureOutput::Event(actor, Event::Relayed(2, RelayReason::Timeout))));
+        assert_eq!(feature.pop_output(0), None);
+    }
+
+    #[test]
+    fn should_classify_nat_by_observed_addrs() {
+        let addr = |s: &str| -> SocketAddr { s.parse().expect("Should parse") };
+        let local = addr("10.0.0.1:1000");
+        assert_eq!(classify_nat([].iter()), NatType::Unknown);
+        assert_eq!(classify_nat([(local, local), (local, local)].iter()), NatType::Open);
+        assert_eq!(classify_nat([(addr("1.2.3.4:2000"), local), (addr("1.2.3.4:2000"), local)].iter()), NatType::Cone);
+        assert_eq!(classify_nat([(addr("1.2.3.4:2000"), local), (addr("1.2.3.4:2001"), local)].iter()), NatType::Symmetric);
+    }
+
+    #[test]
+    fn symmetric_nat_should_relay_without_offer() {
+        let mut feature = HolePunchFeature::<()>::default();
+        let ctx = FeatureContext { node_id: 1, session: 0 };
+        let actor = FeatureControlActor::Controller(());
+        feature.nat = NatType::Symmetric;
+        feature.on_input(&ctx, 0, FeatureInput::Control(actor, Control::Punch { node: 2, via: 3 }));
+        assert_eq!(feature.pop_output(0), Some(FeatureOutput::Event(actor, Event::Relayed(2, RelayReason::SymmetricNat))));
+        assert_eq!(feature.pop_output(0), None);
+
+        //without enough neighbours the NAT stays unknown
+        feature.on_input(&ctx, 0, FeatureInput::Control(actor, Control::DetectNat));
+        assert_eq!(feature.pop_output(0), Some(FeatureOutput::Event(actor, Event::Nat(NatType::Unknown))));
+        feature.on_input(&ctx, 0, FeatureInput::Control(actor, Control::GetNat));
+        assert_eq!(feature.pop_output(0), Some(FeatureOutput::Event(actor, Event::Nat(NatType::Symmetric))));
+    }
+}
@@ -1,18 +1,29 @@
+use std::collections::HashMap;
+
+use crate::base::FeatureVersions;
+
 pub mod alias;
 pub mod data;
 pub mod dht_kv;
+pub mod hole_punch;
 pub mod neighbours;
 pub mod pubsub;
 pub mod router_sync;
+pub mod rpc;
 pub mod socket;
 pub mod vpn;
 
+mod scheduler;
+
+pub use scheduler::DEFAULT_CONTROL_WEIGHT;
+pub(crate) use scheduler::{FeatureScheduler, FEATURES_COUNT};
+
 ///
 /// FeatureManager need wrap child features in a struct to manage them
 /// This is a helper struct to help FeatureManager to manage the features
 ///
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, num_enum::TryFromPrimitive, num_enum::IntoPrimitive)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, num_enum::TryFromPrimitive, num_enum::IntoPrimitive)]
 #[repr(u8)]
 pub enum Features {
     Neighbours = neighbours::FEATURE_ID,
@@ -23,6 +34,85 @@ pub enum Features {
     PubSub = pubsub::FEATURE_ID,
     Alias = alias::FEATURE_ID,
     Socket = socket::FEATURE_ID,
+    Rpc = rpc::FEATURE_ID,
+    HolePunch = hole_punch::FEATURE_ID,
+}
+
+/// Traffic class of a feature, outputs of control features are never dropped under load
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeaturePriority {
+    Control,
+    Bulk,
+}
+
+impl Features {
+    pub fn priority(&self) -> FeaturePriority {
+        match self {
+            Self::Neighbours | Self::RouterSync => FeaturePriority::Control,
+            _ => FeaturePriority::Bulk,
+        }
+    }
+}
+
+/// Wire versions which the node advertises in the neighbour handshake. `overrides` can pin a feature to an older
+/// version, so upgraded nodes keep the old format until the whole network is upgraded
+pub(crate) fn local_feature_versions(overrides: &HashMap<Features, u8>) -> FeatureVersions {
+    let mut versions = FeatureVersions::new([(router_sync::FEATURE_ID, router_sync::FEATURE_VERSION)]);
+    for (feature, version) in overrides {
+        versions.set(*feature as u8, *version);
+    }
+    versions
+}
+
+/// Which features a node runs. Disabled features are not constructed, and their packets and controls are dropped.
+/// Neighbours, Data and RouterSync are the core of a node, they can't be disabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeaturesConfig {
+    mask: u16,
+}
+
+impl FeaturesConfig {
+    const CORE: [Features; 3] = [Features::Neighbours, Features::Data, Features::RouterSync];
+
+    /// All features enabled, which is the default
+    pub fn all() -> Self {
+        Self { mask: (1 << FEATURES_COUNT) - 1 }
+    }
+
+    /// Only Neighbours, Data and RouterSync
+    pub fn core() -> Self {
+        Self {
+            mask: Self::CORE.iter().fold(0, |mask, feature| mask | Self::bit(*feature)),
+        }
+    }
+
+    pub fn enable(mut self, feature: Features) -> Self {
+        self.mask |= Self::bit(feature);
+        self
+    }
+
+    pub fn disable(mut self, feature: Features) -> Self {
+        if Self::CORE.contains(&feature) {
+            log::warn!("[FeaturesConfig] {:?} is a core feature, it can't be disabled", feature);
+        } else {
+            self.mask &= !Self::bit(feature);
+        }
+        self
+    }
+
+    pub fn is_enabled(&self, feature: Features) -> bool {
+        self.mask & Self::bit(feature) != 0
+    }
+
+    fn bit(feature: Features) -> u16 {
+        1 << (feature as u8)
+    }
+}
+
+impl Default for FeaturesConfig {
+    fn default() -> Self {
+        Self::all()
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, convert_enum::From)]
@@ -35,6 +125,8 @@ pub enum FeaturesControl {
     PubSub(pubsub::Control),
     Alias(alias::Control),
     Socket(socket::Control),
+    Rpc(rpc::Control),
+    HolePunch(hole_punch::Control),
 }
 
 impl FeaturesControl {
@@ -48,6 +140,8 @@ impl FeaturesControl {
             Self::PubSub(_) => Features::PubSub,
             Self::Alias(_) => Features::Alias,
             Self::Socket(_) => Features::Socket,
+            Self::Rpc(_) => Features::Rpc,
+            Self::HolePunch(_) => Features::HolePunch,
         }
     }
 }
@@ -62,6 +156,8 @@ pub enum FeaturesEvent {
     PubSub(pubsub::Event),
     Alias(alias::Event),
     Socket(socket::Event),
+    Rpc(rpc::Event),
+    HolePunch(hole_punch::Event),
 }
 
 #[derive(Debug, Clone, convert_enum::From)]
@@ -74,6 +170,8 @@ pub enum FeaturesToController {
     PubSub(pubsub::ToController),
     Alias(alias::ToController),
     Socket(socket::ToController),
+    Rpc(rpc::ToController),
+    HolePunch(hole_punch::ToController),
 }
 
 impl FeaturesToController {
@@ -87,6 +185,8 @@ impl FeaturesToController {
             Self::PubSub(_) => Features::PubSub,
             Self::Alias(_) => Features::Alias,
             Self::Socket(_) => Features::Socket,
+            Self::Rpc(_) => Features::Rpc,
+            Self::HolePunch(_) => Features::HolePunch,
         }
     }
 }
@@ -101,6 +201,8 @@ pub enum FeaturesToWorker<UserData> {
     PubSub(pubsub::ToWorker<UserData>),
     Alias(alias::ToWorker),
     Socket(socket::ToWorker<UserData>),
+    Rpc(rpc::ToWorker),
+    HolePunch(hole_punch::ToWorker),
 }
 
 impl<UserData> FeaturesToWorker<UserData> {
@@ -114,6 +216,30 @@ impl<UserData> FeaturesToWorker<UserData> {
             Self::PubSub(_) => Features::PubSub,
             Self::Alias(_) => Features::Alias,
             Self::Socket(_) => Features::Socket,
+            Self::Rpc(_) => Features::Rpc,
+            Self::HolePunch(_) => Features::HolePunch,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Features, FeaturesConfig};
+
+    #[test]
+    fn features_config_keeps_core() {
+        let all = FeaturesConfig::default();
+        assert!(all.is_enabled(Features::Vpn) && all.is_enabled(Features::HolePunch));
+
+        let cfg = FeaturesConfig::all().disable(Features::Vpn).disable(Features::DhtKv).disable(Features::Neighbours);
+        assert!(!cfg.is_enabled(Features::Vpn));
+        assert!(!cfg.is_enabled(Features::DhtKv));
+        assert!(cfg.is_enabled(Features::Neighbours));
+        assert!(cfg.is_enabled(Features::PubSub));
+
+        let core = FeaturesConfig::core();
+        assert!(core.is_enabled(Features::Neighbours) && core.is_enabled(Features::Data) && core.is_enabled(Features::RouterSync));
+        assert!(!core.is_enabled(Features::PubSub));
+        assert!(core.enable(Features::PubSub).is_enabled(Features::PubSub));
+    }
+}
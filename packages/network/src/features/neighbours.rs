@@ -4,7 +4,10 @@ use atm0s_sdn_identity::{ConnId, NodeAddr, NodeId};
 use derivative::Derivative;
 use sans_io_runtime::{collections::DynamicDeque, TaskSwitcherChild};
 
-use crate::base::{ConnectionEvent, Feature, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerInput, FeatureWorkerOutput};
+use crate::base::{
+    ConnectionEvent, DisconnectReason, Feature, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerInput, FeatureWorkerOutput,
+    NeighboursConnectError, RekeyStats,
+};
 
 pub const FEATURE_ID: u8 = 0;
 pub const FEATURE_NAME: &str = "neighbours_api";
@@ -15,12 +18,92 @@ pub enum Control {
     UnSub,
     ConnectTo(NodeAddr),
     DisconnectFrom(NodeId),
+    /// Never connect to or accept the node again, the current connections to it are closed
+    Blacklist(NodeId),
+    /// Switch to allowlist mode if not yet and allow the node, connections to other nodes are closed
+    AllowlistOnly(NodeId),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Event {
     Connected(NodeId, ConnId),
-    Disconnected(NodeId, ConnId),
+    Rekey(NodeId, ConnId, RekeyStats),
+    Disconnected(NodeId, ConnId, DisconnectReason),
+    Rejected(NodeId, NeighboursConnectError),
+}
+
+pub const DEFAULT_KEEPALIVE_INTERVAL_MS: u64 = 1000;
+pub const DEFAULT_KEEPALIVE_MISS_LIMIT: u32 = 10;
+pub const DEFAULT_HANDSHAKE_TIMEOUT_MS: u64 = 30000;
+
+/// Limits of connections accepted by the neighbours manager, None is unlimited.
+/// Only new incoming connections are rejected, existing and outgoing ones are kept.
+///
+/// Established connections are pinged every `keepalive_interval_ms` and closed with a `Disconnected`
+/// event after `keepalive_miss_limit` pings in a row are not answered.
+///
+/// A connection which is not established after `handshake_timeout_ms` is dropped. With `require_node_id_proof`,
+/// incoming requesters must also sign a fresh nonce with their node id, see `NeighbourConnection`. Peers are rejected
+/// with `NeighboursConnectError::InvalidProof` if the signature is not valid for the `Authorization`. With a shared key
+/// authorization any member can sign for any node id, use `require_identity` to bind node ids to their own keys.
+///
+/// Identity proofs of peers which have a `NodeIdentity` are always verified. Peers without one are still accepted
+/// unless `require_identity` is set, so a network with bare numeric node ids can move to identities node by node
+///
+/// With `handshake_rate`, connect requests from an ip which ran out of tokens are dropped before any handshake work
+///
+/// `ip_preference` selects the family of outgoing connections when both this node and the peer have IPv4 and IPv6
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NeighboursCfg {
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    pub keepalive_interval_ms: u64,
+    pub keepalive_miss_limit: u32,
+    pub handshake_timeout_ms: u64,
+    pub require_node_id_proof: bool,
+    pub require_identity: bool,
+    pub handshake_rate: Option<HandshakeRateCfg>,
+    pub ip_preference: IpPreference,
+}
+
+impl Default for NeighboursCfg {
+    fn default() -> Self {
+        Self {
+            max_connections: None,
+            max_connections_per_ip: None,
+            keepalive_interval_ms: DEFAULT_KEEPALIVE_INTERVAL_MS,
+            keepalive_miss_limit: DEFAULT_KEEPALIVE_MISS_LIMIT,
+            handshake_timeout_ms: DEFAULT_HANDSHAKE_TIMEOUT_MS,
+            require_node_id_proof: false,
+            require_identity: false,
+            handshake_rate: None,
+            ip_preference: IpPreference::Any,
+        }
+    }
+}
+
+/// Token bucket of inbound handshakes per source ip, `burst` is handled as at least 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakeRateCfg {
+    pub per_sec: u32,
+    pub burst: u32,
+}
+
+/// Family of outgoing connections. With a preference, the other family is only dialed if the preferred one has no
+/// local bind or no peer address. `Any` dials all pairs of the same family
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IpPreference {
+    #[default]
+    Any,
+    PreferIpv4,
+    PreferIpv6,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionCounts {
+    /// All connections, including ones in handshake, this is what the limits count
+    pub total: usize,
+    pub established: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -49,10 +132,22 @@ impl<UserData: Debug + Copy + Hash + Eq> Feature<UserData, Control, Event, ToCon
                     self.output.push_back(FeatureOutput::Event(*sub, Event::Connected(ctx.node, ctx.conn)));
                 }
             }
-            FeatureSharedInput::Connection(ConnectionEvent::Disconnected(ctx)) => {
-                log::debug!("[Neighbours] Disconnected {}, fire event to {:?}", ctx.pair, self.subs);
+            FeatureSharedInput::Connection(ConnectionEvent::Rekey(ctx, stats)) => {
+                log::info!("[Neighbours] Rekey {} epoch {}, fire event to {:?}", ctx.pair, stats.epoch, self.subs);
+                for sub in self.subs.iter() {
+                    self.output.push_back(FeatureOutput::Event(*sub, Event::Rekey(ctx.node, ctx.conn, stats.clone())));
+                }
+            }
+            FeatureSharedInput::Connection(ConnectionEvent::ConnectRejected(node, err)) => {
+                log::info!("[Neighbours] Connect to {node} rejected {:?}, fire event to {:?}", err, self.subs);
                 for sub in self.subs.iter() {
-                    self.output.push_back(FeatureOutput::Event(*sub, Event::Disconnected(ctx.node, ctx.conn)));
+                    self.output.push_back(FeatureOutput::Event(*sub, Event::Rejected(node, err)));
+                }
+            }
+            FeatureSharedInput::Connection(ConnectionEvent::Disconnected(ctx, reason)) => {
+                log::debug!("[Neighbours] Disconnected {} because {:?}, fire event to {:?}", ctx.pair, reason, self.subs);
+                for sub in self.subs.iter() {
+                    self.output.push_back(FeatureOutput::Event(*sub, Event::Disconnected(ctx.node, ctx.conn, reason)));
                 }
             }
             _ => {}
@@ -80,6 +175,12 @@ impl<UserData: Debug + Copy + Hash + Eq> Feature<UserData, Control, Event, ToCon
                 Control::DisconnectFrom(node) => {
                     self.output.push_back(FeatureOutput::NeighboursDisconnectFrom(node));
                 }
+                Control::Blacklist(node) => {
+                    self.output.push_back(FeatureOutput::NeighboursBlacklist(node));
+                }
+                Control::AllowlistOnly(node) => {
+                    self.output.push_back(FeatureOutput::NeighboursAllowlistOnly(node));
+                }
             }
         }
     }
@@ -0,0 +1,42 @@
+use std::{collections::HashMap, sync::Arc};
+
+use atm0s_sdn_identity::NodeId;
+
+use super::{ChannelId, RetainCfg};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChannelAccess {
+    Subscribe,
+    Publish,
+}
+
+/// Decide which nodes can subscribe or publish a channel.
+/// Local consumers and publishers are checked with the local node id. A Sub from a neighbour is checked with the
+/// neighbour id, so the decision is per hop, and a source is checked when its register reaches this node
+pub trait ChannelAuthorizer: Send + Sync {
+    fn authorize(&self, node: NodeId, channel: ChannelId, access: ChannelAccess) -> bool;
+}
+
+pub struct AllowAllChannels;
+
+impl ChannelAuthorizer for AllowAllChannels {
+    fn authorize(&self, _node: NodeId, _channel: ChannelId, _access: ChannelAccess) -> bool {
+        true
+    }
+}
+
+#[derive(Clone)]
+pub struct PubSubCfg {
+    pub authorizer: Arc<dyn ChannelAuthorizer>,
+    /// Channels whose relays replay the last frames to new consumers. Each node only retains the channels of its own config
+    pub retain: HashMap<ChannelId, RetainCfg>,
+}
+
+impl Default for PubSubCfg {
+    fn default() -> Self {
+        Self {
+            authorizer: Arc::new(AllowAllChannels),
+            retain: HashMap::new(),
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     fmt::Debug,
+    sync::Arc,
 };
 
 use crate::{
@@ -11,8 +12,8 @@ use crate::{
 use self::source_hint::SourceHintLogic;
 
 use super::{
-    msg::{ChannelId, Feedback, RelayControl, RelayId, SourceHint},
-    ChannelControl, ChannelEvent, Control, Event, RelayWorkerControl, ToController, ToWorker,
+    msg::{ChannelId, Feedback, FeedbackPolicy, RelayControl, RelayId, SourceHint},
+    ChannelAccess, ChannelAuthorizer, ChannelControl, ChannelEvent, Control, Event, PubSubCfg, RelayWorkerControl, RetainCfg, ToController, ToWorker,
 };
 
 pub const RELAY_TIMEOUT: u64 = 10_000;
@@ -25,6 +26,7 @@ mod remote_relay;
 mod source_hint;
 
 use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_utils::metrics;
 use local_relay::LocalRelay;
 use remote_relay::RemoteRelay;
 use sans_io_runtime::TaskSwitcherChild;
@@ -33,12 +35,12 @@ use sans_io_runtime::TaskSwitcherChild;
 pub enum GenericRelayOutput<UserData> {
     ToWorker(RelayWorkerControl<UserData>),
     RouteChanged(FeatureControlActor<UserData>),
-    Feedback(Vec<FeatureControlActor<UserData>>, Feedback),
+    Feedback(Vec<(FeatureControlActor<UserData>, Option<FeedbackPolicy>)>, Feedback),
 }
 
 pub trait GenericRelay<UserData> {
     fn on_tick(&mut self, now: u64);
-    fn on_pub_start(&mut self, actor: FeatureControlActor<UserData>);
+    fn on_pub_start(&mut self, actor: FeatureControlActor<UserData>, policy: Option<FeedbackPolicy>);
     fn on_pub_stop(&mut self, actor: FeatureControlActor<UserData>);
     fn on_local_sub(&mut self, now: u64, actor: FeatureControlActor<UserData>);
     fn on_local_feedback(&mut self, now: u64, actor: FeatureControlActor<UserData>, feedback: Feedback);
@@ -53,23 +55,34 @@ pub trait GenericRelay<UserData> {
 pub struct PubSubFeature<UserData> {
     relays: HashMap<RelayId, Box<dyn GenericRelay<UserData>>>,
     source_hints: HashMap<ChannelId, SourceHintLogic<UserData>>,
+    authorizer: Arc<dyn ChannelAuthorizer>,
+    retain: HashMap<ChannelId, RetainCfg>,
+    /// Node of each connection, for authorizing Sub from neighbours
+    nodes: HashMap<NetPair, NodeId>,
     queue: VecDeque<FeatureOutput<UserData, Event, ToWorker<UserData>>>,
     shutdown: bool,
+    reported_channels: usize,
+    reported_consumers: usize,
 }
 
 impl<UserData: 'static + Eq + Copy + Debug> Default for PubSubFeature<UserData> {
     fn default() -> Self {
-        Self::new()
+        Self::new(PubSubCfg::default())
     }
 }
 
 impl<UserData: 'static + Eq + Copy + Debug> PubSubFeature<UserData> {
-    pub fn new() -> Self {
+    pub fn new(cfg: PubSubCfg) -> Self {
         Self {
             relays: HashMap::new(),
             source_hints: HashMap::new(),
+            authorizer: cfg.authorizer,
+            retain: cfg.retain,
+            nodes: HashMap::new(),
             queue: VecDeque::new(),
             shutdown: false,
+            reported_channels: 0,
+            reported_consumers: 0,
         }
     }
 
@@ -80,13 +93,25 @@ impl<UserData: 'static + Eq + Copy + Debug> PubSubFeature<UserData> {
                 Box::new(LocalRelay::default())
             } else {
                 log::info!("[PubSubFeatureController] Creating new RemoteRelay: {:?}", relay_id);
-                Box::new(RemoteRelay::new(ctx.session))
+                Box::new(RemoteRelay::new(ctx.session, self.retain.contains_key(&relay_id.0)))
             };
             self.relays.insert(relay_id, relay);
+            if let Some(cfg) = self.retain.get(&relay_id.0) {
+                self.queue
+                    .push_back(FeatureOutput::ToWorker(true, ToWorker::RelayControl(relay_id, RelayWorkerControl::RetainSet(*cfg))));
+            }
         }
         self.relays.get_mut(&relay_id)
     }
 
+    /// Workers drop the retained frames together with the relay
+    fn remove_relay(&mut self, relay_id: RelayId) {
+        self.relays.remove(&relay_id);
+        if self.retain.contains_key(&relay_id.0) {
+            self.queue.push_back(FeatureOutput::ToWorker(true, ToWorker::RelayControl(relay_id, RelayWorkerControl::RetainDel)));
+        }
+    }
+
     fn get_source_hint(&mut self, node_id: NodeId, session: u64, channel: ChannelId, auto_create: bool) -> Option<&mut SourceHintLogic<UserData>> {
         if !self.source_hints.contains_key(&channel) && auto_create {
             log::info!("[PubSubFeatureController] Creating new SourceHintLogic: {}", channel);
@@ -95,9 +120,29 @@ impl<UserData: 'static + Eq + Copy + Debug> PubSubFeature<UserData> {
         self.source_hints.get_mut(&channel)
     }
 
+    fn authorize(&self, node: NodeId, channel: ChannelId, access: ChannelAccess) -> bool {
+        let allowed = self.authorizer.authorize(node, channel, access);
+        if !allowed {
+            log::warn!("[PubSubFeatureController] {:?} of {} by node {} is denied", access, channel, node);
+        }
+        allowed
+    }
+
+    /// Check the access of a local actor, a denied actor gets `ChannelEvent::Denied`
+    fn authorize_local(&mut self, ctx: &FeatureContext, actor: FeatureControlActor<UserData>, channel: ChannelId, access: ChannelAccess) -> bool {
+        if self.authorize(ctx.node_id, channel, access) {
+            return true;
+        }
+        self.queue.push_back(FeatureOutput::Event(actor, Event(channel, ChannelEvent::Denied(access, ctx.node_id))));
+        false
+    }
+
     fn on_local(&mut self, ctx: &FeatureContext, now: u64, actor: FeatureControlActor<UserData>, channel: ChannelId, control: ChannelControl) {
         match control {
             ChannelControl::SubAuto => {
+                if !self.authorize_local(ctx, actor, channel, ChannelAccess::Subscribe) {
+                    return;
+                }
                 log::info!("[PubSubFeatureController] SubAuto for {} from {:?}", channel, actor);
                 let sh = self.get_source_hint(ctx.node_id, ctx.session, channel, true).expect("Should create");
                 sh.on_local(now, actor, source_hint::LocalCmd::Subscribe);
@@ -110,11 +155,18 @@ impl<UserData: 'static + Eq + Copy + Debug> PubSubFeature<UserData> {
                     self.pop_single_source_hint(ctx, now, channel);
                 }
             }
-            ChannelControl::PubStart => {
-                log::info!("[PubSubFeatureController] PubStart for {} from {:?}", channel, actor);
+            ChannelControl::PubStart | ChannelControl::PubStartWith(_) => {
+                let policy = match control {
+                    ChannelControl::PubStartWith(policy) => Some(policy),
+                    _ => None,
+                };
+                log::info!("[PubSubFeatureController] PubStart for {} from {:?} with feedback policy {:?}", channel, actor, policy);
+                if !self.authorize_local(ctx, actor, channel, ChannelAccess::Publish) {
+                    return;
+                }
                 let relay_id = RelayId(channel, ctx.node_id);
                 let relay = self.get_relay(ctx, relay_id, true).expect("Should create");
-                relay.on_pub_start(actor);
+                relay.on_pub_start(actor, policy);
                 Self::pop_single_relay(relay_id, self.relays.get_mut(&relay_id).expect("Should have"), &mut self.queue);
 
                 let sh = self.get_source_hint(ctx.node_id, ctx.session, channel, true).expect("Should create");
@@ -136,6 +188,9 @@ impl<UserData: 'static + Eq + Copy + Debug> PubSubFeature<UserData> {
             }
             ChannelControl::SubSource(source) => {
                 log::info!("[PubSubFeatureController] SubSource(source) for {} from {:?}", channel, actor);
+                if !self.authorize_local(ctx, actor, channel, ChannelAccess::Subscribe) {
+                    return;
+                }
                 let relay_id = RelayId(channel, source);
                 let relay = self.get_relay(ctx, relay_id, true).expect("Should create");
                 log::debug!("[PubSubFeatureController] Sub for {:?} from {:?}", relay_id, actor);
@@ -161,12 +216,33 @@ impl<UserData: 'static + Eq + Copy + Debug> PubSubFeature<UserData> {
                     relay.on_local_unsub(now, actor);
                     Self::pop_single_relay(relay_id, relay, &mut self.queue);
                     if relay.should_clear() {
-                        self.relays.remove(&relay_id);
+                        self.remove_relay(relay_id);
                     }
                 } else {
-                    log::warn!("[PubSubFeatureController] Unsub for unknown relay {:?}", relay_id);
+                    log::debug!("[PubSubFeatureController] Unsub for unknown relay {:?} => already released", relay_id);
                 }
             }
+            ChannelControl::UnsubAll => {
+                log::info!("[PubSubFeatureController] UnsubAll for {} from {:?}", channel, actor);
+                self.on_local(ctx, now, actor, channel, ChannelControl::UnsubAuto);
+                let sources: Vec<NodeId> = self
+                    .relays
+                    .iter()
+                    .filter(|(relay_id, relay)| relay_id.0 == channel && relay.relay_dests().map(|(locals, _)| locals.contains(&actor)).unwrap_or(false))
+                    .map(|(relay_id, _)| relay_id.1)
+                    .collect();
+                for source in sources {
+                    self.on_local(ctx, now, actor, channel, ChannelControl::UnsubSource(source));
+                }
+            }
+            ChannelControl::SetConsumerQueue(ref cfg) => {
+                //queues are pinned to the controller's own worker, the others only forward data of queued consumers to it
+                self.queue.push_back(FeatureOutput::ToWorker(true, ToWorker::ConsumerQueued(actor, channel, cfg.is_some())));
+                self.queue.push_back(FeatureOutput::ToWorker(false, ToWorker::ConsumerControl(actor, channel, control)));
+            }
+            ChannelControl::Pull(_) | ChannelControl::GetConsumerStats => {
+                self.queue.push_back(FeatureOutput::ToWorker(false, ToWorker::ConsumerControl(actor, channel, control)));
+            }
             ChannelControl::PubData(data) => {
                 let relay_id = RelayId(channel, ctx.node_id);
                 if let Some(relay) = self.relays.get(&relay_id) {
@@ -177,8 +253,9 @@ impl<UserData: 'static + Eq + Copy + Debug> PubSubFeature<UserData> {
                             actor,
                             locals.len()
                         );
-                        for local in locals {
-                            self.queue.push_back(FeatureOutput::Event(*local, Event(channel, ChannelEvent::SourceData(ctx.node_id, data.clone()))));
+                        //the worker which delivers to locals also retains the frame
+                        if !locals.is_empty() || self.retain.contains_key(&channel) {
+                            self.queue.push_back(FeatureOutput::ToWorker(false, ToWorker::LocalData(relay_id, data.clone())));
                         }
 
                         if has_remote {
@@ -195,20 +272,92 @@ impl<UserData: 'static + Eq + Copy + Debug> PubSubFeature<UserData> {
     }
 
     fn on_remote_relay_control(&mut self, ctx: &FeatureContext, now: u64, remote: NetPair, relay_id: RelayId, control: RelayControl) {
+        match &control {
+            RelayControl::Sub(uuid) => {
+                let uuid = *uuid;
+                let node = self.nodes.get(&remote).copied();
+                if !node.is_some_and(|node| self.authorize(node, relay_id.0, ChannelAccess::Subscribe)) {
+                    log::info!("[PubSubFeatureController] Sub for {:?} from {:?} node {:?} is denied", relay_id, remote, node);
+                    self.queue
+                        .push_back(FeatureOutput::ToWorker(true, ToWorker::RelayControl(relay_id, RelayWorkerControl::SendSubDenied(uuid, remote))));
+                    return;
+                }
+            }
+            RelayControl::SubDenied(_) => {
+                self.on_remote_sub_denied(now, remote, relay_id);
+                return;
+            }
+            _ => {}
+        }
         if self.get_relay(ctx, relay_id, control.should_create()).is_some() {
             let relay: &mut Box<dyn GenericRelay<UserData>> = self.relays.get_mut(&relay_id).expect("Should have relay");
             log::debug!("[PubSubFeatureController] Remote control for {:?} from {:?}: {:?}", relay_id, remote, control);
             relay.on_remote(now, remote, control);
             Self::pop_single_relay(relay_id, relay, &mut self.queue);
             if relay.should_clear() {
-                self.relays.remove(&relay_id);
+                self.remove_relay(relay_id);
             }
         } else {
-            log::warn!("[PubSubFeatureController] Remote control for unknown relay {:?}", relay_id);
+            match control {
+                RelayControl::SubOK(uuid) => {
+                    // the relay was released while its Sub was in flight, unsub so the source doesn't keep us until timeout
+                    log::debug!("[PubSubFeatureController] SubOK for released relay {:?} from {:?} => send Unsub", relay_id, remote);
+                    self.queue
+                        .push_back(FeatureOutput::ToWorker(true, ToWorker::RelayControl(relay_id, RelayWorkerControl::SendUnsub(uuid, remote))));
+                }
+                RelayControl::Unsub(uuid) => {
+                    log::debug!("[PubSubFeatureController] Unsub for released relay {:?} from {:?} => ack again", relay_id, remote);
+                    self.queue
+                        .push_back(FeatureOutput::ToWorker(true, ToWorker::RelayControl(relay_id, RelayWorkerControl::SendUnsubOk(uuid, remote))));
+                }
+                RelayControl::UnsubOK(_) => {
+                    log::debug!("[PubSubFeatureController] UnsubOK for released relay {:?} from {:?}", relay_id, remote);
+                }
+                _ => {
+                    log::warn!("[PubSubFeatureController] Remote control for unknown relay {:?}", relay_id);
+                }
+            }
+        }
+    }
+
+    /// The next hop rejected our Sub, local consumers are released and notified. Remote consumers keep retrying
+    /// until they are released by their own consumers
+    fn on_remote_sub_denied(&mut self, now: u64, remote: NetPair, relay_id: RelayId) {
+        let node = match self.nodes.get(&remote) {
+            Some(node) => *node,
+            None => {
+                log::debug!("[PubSubFeatureController] SubDenied for {:?} from unknown remote {:?}", relay_id, remote);
+                return;
+            }
+        };
+        let relay = match self.relays.get_mut(&relay_id) {
+            Some(relay) => relay,
+            None => {
+                log::debug!("[PubSubFeatureController] SubDenied for released relay {:?} from {:?}", relay_id, remote);
+                return;
+            }
+        };
+        log::warn!("[PubSubFeatureController] Sub for {:?} is denied by node {}", relay_id, node);
+        let locals = relay.relay_dests().map(|(locals, _)| locals.to_vec()).unwrap_or_default();
+        for actor in locals {
+            relay.on_local_unsub(now, actor);
+            self.queue
+                .push_back(FeatureOutput::Event(actor, Event(relay_id.0, ChannelEvent::Denied(ChannelAccess::Subscribe, node))));
+        }
+        Self::pop_single_relay(relay_id, relay, &mut self.queue);
+        if relay.should_clear() {
+            self.remove_relay(relay_id);
         }
     }
 
     fn on_remote_source_hint_control(&mut self, ctx: &FeatureContext, now: u64, remote: NetPair, channel: ChannelId, control: SourceHint) {
+        if let SourceHint::Register { source, .. } = &control {
+            let source = *source;
+            if !self.authorize(source, channel, ChannelAccess::Publish) {
+                log::info!("[PubSubFeatureController] Register of source {} for {} from {:?} is denied", source, channel, remote);
+                return;
+            }
+        }
         if let Some(sh) = self.get_source_hint(ctx.node_id, ctx.session, channel, control.should_create()) {
             log::debug!("[PubSubFeatureController] SourceHint control for {:?} from {:?}: {:?}", channel, remote, control);
             sh.on_remote(now, remote, control);
@@ -223,6 +372,13 @@ impl<UserData: 'static + Eq + Copy + Debug> PubSubFeature<UserData> {
         }
     }
 
+    fn update_metrics(&mut self) {
+        let channels = self.relays.keys().map(|relay_id| relay_id.0).collect::<HashSet<_>>().len();
+        let consumers = self.relays.values().filter_map(|relay| relay.relay_dests()).map(|(locals, _)| locals.len()).sum();
+        metrics::PUBSUB_CHANNELS.track(&mut self.reported_channels, channels);
+        metrics::PUBSUB_CONSUMERS.track(&mut self.reported_consumers, consumers);
+    }
+
     fn pop_single_relay(relay_id: RelayId, relay: &mut Box<dyn GenericRelay<UserData>>, queue: &mut VecDeque<FeatureOutput<UserData, Event, ToWorker<UserData>>>) {
         while let Some(control) = relay.pop_output() {
             match control {
@@ -230,8 +386,12 @@ impl<UserData: 'static + Eq + Copy + Debug> PubSubFeature<UserData> {
                 GenericRelayOutput::RouteChanged(actor) => queue.push_back(FeatureOutput::Event(actor, Event(relay_id.0, ChannelEvent::RouteChanged(relay_id.1)))),
                 GenericRelayOutput::Feedback(actors, fb) => {
                     log::debug!("[PubsubController] Feedback for {:?} {:?} to actors {:?}", relay_id, fb, actors);
-                    for actor in actors {
-                        queue.push_back(FeatureOutput::Event(actor, Event(relay_id.0, ChannelEvent::FeedbackData(fb))));
+                    for (actor, policy) in actors {
+                        let event = match policy {
+                            Some(policy) => ChannelEvent::FeedbackValue(fb.kind, fb.value(policy)),
+                            None => ChannelEvent::FeedbackData(fb),
+                        };
+                        queue.push_back(FeatureOutput::Event(actor, Event(relay_id.0, event)));
                     }
                 }
             };
@@ -265,6 +425,13 @@ impl<UserData: 'static + Eq + Copy + Debug> PubSubFeature<UserData> {
     }
 }
 
+impl<UserData> Drop for PubSubFeature<UserData> {
+    fn drop(&mut self) {
+        metrics::PUBSUB_CHANNELS.track(&mut self.reported_channels, 0);
+        metrics::PUBSUB_CONSUMERS.track(&mut self.reported_consumers, 0);
+    }
+}
+
 impl<UserData: 'static + Eq + Copy + Debug> Feature<UserData, Control, Event, ToController, ToWorker<UserData>> for PubSubFeature<UserData> {
     fn on_shared_input(&mut self, ctx: &FeatureContext, now: u64, input: FeatureSharedInput) {
         match input {
@@ -279,8 +446,9 @@ impl<UserData: 'static + Eq + Copy + Debug> Feature<UserData, Control, Event, To
                     }
                 }
                 for relay_id in clears {
-                    self.relays.remove(&relay_id);
+                    self.remove_relay(relay_id);
                 }
+                self.update_metrics();
 
                 let mut clears = vec![];
                 let mut not_clears = vec![];
@@ -300,9 +468,28 @@ impl<UserData: 'static + Eq + Copy + Debug> Feature<UserData, Control, Event, To
                 }
             }
             FeatureSharedInput::Connection(event) => {
-                if let ConnectionEvent::Disconnected(ctx) = event {
+                match &event {
+                    ConnectionEvent::Connected(ctx, _) => {
+                        self.nodes.insert(ctx.pair, ctx.node);
+                    }
+                    ConnectionEvent::Migrated(ctx, old_pair) => {
+                        self.nodes.remove(old_pair);
+                        self.nodes.insert(ctx.pair, ctx.node);
+                    }
+                    ConnectionEvent::Disconnected(ctx, _) => {
+                        self.nodes.remove(&ctx.pair);
+                    }
+                    _ => {}
+                }
+                //relays which were subscribed through the old pair are refreshed by the remote with the new one
+                let pair = match event {
+                    ConnectionEvent::Disconnected(ctx, _) => Some(ctx.pair),
+                    ConnectionEvent::Migrated(_, old_pair) => Some(old_pair),
+                    _ => None,
+                };
+                if let Some(pair) = pair {
                     for (relay_id, relay) in self.relays.iter_mut() {
-                        relay.conn_disconnected(now, ctx.pair);
+                        relay.conn_disconnected(now, pair);
                         Self::pop_single_relay(*relay_id, relay, &mut self.queue);
                     }
                 }
@@ -318,15 +505,41 @@ impl<UserData: 'static + Eq + Copy + Debug> Feature<UserData, Control, Event, To
             FeatureInput::FromWorker(ToController::SourceHint(remote, channel, control)) => {
                 self.on_remote_source_hint_control(ctx, now_ms, remote, channel, control);
             }
+            FeatureInput::FromWorker(ToController::ConsumerData(relay_id, data)) => {
+                self.queue.push_back(FeatureOutput::ToWorker(false, ToWorker::ConsumerData(relay_id, data)));
+            }
             FeatureInput::Control(actor, Control(channel, control)) => {
                 self.on_local(ctx, now_ms, actor, channel, control);
             }
+            //relay controls are resent by their own timers
+            FeatureInput::Undeliverable(..) | FeatureInput::DirectUndeliverable(..) => {}
             _ => panic!("Unexpected input"),
         }
     }
 
-    fn on_shutdown(&mut self, _ctx: &FeatureContext, _now: u64) {
+    /// Local actors are released like they left, so neighbours get the Unregister and Unsub before the connections are closed
+    fn on_shutdown(&mut self, ctx: &FeatureContext, now: u64) {
         log::info!("[PubSubFeatureWorker] Shutdown");
+        let mut leaves = vec![];
+        for (channel, sh) in self.source_hints.iter() {
+            let (sources, subscribers) = sh.local_actors();
+            leaves.extend(sources.iter().map(|actor| (*actor, *channel, ChannelControl::PubStop)));
+            leaves.extend(subscribers.iter().map(|actor| (*actor, *channel, ChannelControl::UnsubAuto)));
+        }
+        for (actor, channel, control) in leaves {
+            self.on_local(ctx, now, actor, channel, control);
+        }
+
+        //manual subscribers which are not released by the source hints
+        let mut leaves = vec![];
+        for (relay_id, relay) in self.relays.iter() {
+            if let Some((locals, _)) = relay.relay_dests() {
+                leaves.extend(locals.iter().map(|actor| (*actor, relay_id.0, ChannelControl::UnsubSource(relay_id.1))));
+            }
+        }
+        for (actor, channel, control) in leaves {
+            self.on_local(ctx, now, actor, channel, control);
+        }
         self.shutdown = true;
     }
 }
@@ -346,3 +559,148 @@ impl<UserData> TaskSwitcherChild<FeatureOutput<UserData, Event, ToWorker<UserDat
         self.queue.pop_front()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        base::{Feature, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput},
+        data_plane::NetPair,
+        features::pubsub::{
+            msg::{ChannelId, Feedback, FeedbackPolicy, RelayControl, RelayId, SourceHint},
+            ChannelControl, ChannelEvent, Control, Event, RelayWorkerControl, ToController, ToWorker,
+        },
+    };
+    use sans_io_runtime::TaskSwitcherChild;
+    use std::collections::HashMap;
+
+    use super::PubSubFeature;
+
+    #[test]
+    fn consumer_queue_should_be_pinned_to_controller_worker() {
+        let ctx = FeatureContext { node_id: 1, session: 1000 };
+        let actor = FeatureControlActor::Worker(1, ());
+        let channel = ChannelId(1);
+        let relay_id = RelayId(channel, 2);
+        let mut feature = PubSubFeature::<()>::default();
+
+        feature.on_input(&ctx, 0, FeatureInput::Control(actor, Control(channel, ChannelControl::SetConsumerQueue(None))));
+        assert_eq!(feature.pop_output(0), Some(FeatureOutput::ToWorker(true, ToWorker::ConsumerQueued(actor, channel, false))));
+        assert_eq!(
+            feature.pop_output(0),
+            Some(FeatureOutput::ToWorker(false, ToWorker::ConsumerControl(actor, channel, ChannelControl::SetConsumerQueue(None))))
+        );
+
+        feature.on_input(&ctx, 0, FeatureInput::Control(actor, Control(channel, ChannelControl::Pull(1))));
+        assert_eq!(
+            feature.pop_output(0),
+            Some(FeatureOutput::ToWorker(false, ToWorker::ConsumerControl(actor, channel, ChannelControl::Pull(1))))
+        );
+
+        feature.on_input(&ctx, 0, FeatureInput::FromWorker(ToController::ConsumerData(relay_id, vec![1])));
+        assert_eq!(feature.pop_output(0), Some(FeatureOutput::ToWorker(false, ToWorker::ConsumerData(relay_id, vec![1]))));
+        assert_eq!(feature.pop_output(0), None);
+    }
+
+    #[test]
+    fn consumer_churn_should_not_leak_relays() {
+        let ctx = FeatureContext { node_id: 1, session: 1000 };
+        let actor = FeatureControlActor::Controller(());
+        let channel = ChannelId(1);
+        let relay_id = RelayId(channel, 2);
+        let remote = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let mut feature = PubSubFeature::<()>::default();
+
+        for i in 0..100 {
+            let now = i * 10;
+            feature.on_input(&ctx, now, FeatureInput::Control(actor, Control(channel, ChannelControl::SubSource(2))));
+            feature.on_input(&ctx, now, FeatureInput::Control(actor, Control(channel, ChannelControl::UnsubSource(2))));
+            //duplicated destroy is ignored
+            feature.on_input(&ctx, now, FeatureInput::Control(actor, Control(channel, ChannelControl::UnsubSource(2))));
+            feature.on_input(&ctx, now, FeatureInput::Control(actor, Control(channel, ChannelControl::SubAuto)));
+            feature.on_input(&ctx, now, FeatureInput::Control(actor, Control(channel, ChannelControl::UnsubAuto)));
+            assert!(feature.relays.is_empty());
+        }
+        while feature.pop_output(0).is_some() {}
+
+        //SubOK of a released relay is answered with Unsub, so the source releases us too
+        feature.on_input(&ctx, 1000, FeatureInput::FromWorker(ToController::RelayControl(remote, relay_id, RelayControl::SubOK(1000))));
+        assert_eq!(
+            feature.pop_output(1000),
+            Some(FeatureOutput::ToWorker(true, ToWorker::RelayControl(relay_id, RelayWorkerControl::SendUnsub(1000, remote))))
+        );
+        feature.on_input(&ctx, 1000, FeatureInput::FromWorker(ToController::RelayControl(remote, relay_id, RelayControl::UnsubOK(1000))));
+        assert_eq!(feature.pop_output(1000), None);
+        assert!(feature.relays.is_empty());
+
+        feature.on_shared_input(&ctx, 2000, FeatureSharedInput::Tick(0));
+        while feature.pop_output(2000).is_some() {}
+        feature.on_shared_input(&ctx, 3000, FeatureSharedInput::Tick(1));
+        assert!(feature.relays.is_empty());
+        assert!(feature.source_hints.is_empty());
+    }
+
+    #[test]
+    fn feedbacks_should_be_aggregated_by_publisher_policy() {
+        let ctx = FeatureContext { node_id: 1, session: 1000 };
+        let channel = ChannelId(1);
+        let mut feature = PubSubFeature::<u8>::default();
+        let policies = [FeedbackPolicy::Sum, FeedbackPolicy::Max, FeedbackPolicy::Min, FeedbackPolicy::Avg];
+        for (publisher, policy) in policies.iter().enumerate() {
+            feature.on_input(
+                &ctx,
+                0,
+                FeatureInput::Control(FeatureControlActor::Controller(publisher as u8), Control(channel, ChannelControl::PubStartWith(*policy))),
+            );
+        }
+        let raw_publisher = FeatureControlActor::Controller(100);
+        feature.on_input(&ctx, 0, FeatureInput::Control(raw_publisher, Control(channel, ChannelControl::PubStart)));
+
+        for (consumer, value) in [(10, 10), (11, 30), (12, 20)] {
+            let fb = Feedback::simple(0, value, 1000, 2000);
+            feature.on_input(
+                &ctx,
+                100,
+                FeatureInput::Control(FeatureControlActor::Controller(consumer), Control(channel, ChannelControl::FeedbackAuto(fb))),
+            );
+        }
+
+        let mut last = HashMap::new();
+        while let Some(out) = feature.pop_output(100) {
+            if let FeatureOutput::Event(actor, Event(_, event @ (ChannelEvent::FeedbackValue(..) | ChannelEvent::FeedbackData(_)))) = out {
+                last.insert(actor, event);
+            }
+        }
+        assert_eq!(last.remove(&FeatureControlActor::Controller(0)), Some(ChannelEvent::FeedbackValue(0, 60)));
+        assert_eq!(last.remove(&FeatureControlActor::Controller(1)), Some(ChannelEvent::FeedbackValue(0, 30)));
+        assert_eq!(last.remove(&FeatureControlActor::Controller(2)), Some(ChannelEvent::FeedbackValue(0, 10)));
+        assert_eq!(last.remove(&FeatureControlActor::Controller(3)), Some(ChannelEvent::FeedbackValue(0, 20)));
+        //publishers without policy still get the raw aggregate
+        match last.remove(&raw_publisher) {
+            Some(ChannelEvent::FeedbackData(fb)) => assert_eq!((fb.count, fb.sum, fb.max, fb.min), (3, 60, 30, 10)),
+            event => panic!("unexpected event {event:?}"),
+        }
+        assert!(last.is_empty());
+    }
+
+    #[test]
+    fn shutdown_should_release_local_actors() {
+        let ctx = FeatureContext { node_id: 1, session: 1000 };
+        let actor = FeatureControlActor::Controller(());
+        let channel = ChannelId(1);
+        let mut feature = PubSubFeature::<()>::default();
+
+        feature.on_input(&ctx, 0, FeatureInput::Control(actor, Control(channel, ChannelControl::PubStart)));
+        feature.on_input(&ctx, 0, FeatureInput::Control(actor, Control(channel, ChannelControl::SubSource(2))));
+        while feature.pop_output(0).is_some() {}
+
+        feature.on_shutdown(&ctx, 100);
+        assert!(!feature.is_empty());
+        let mut outputs = vec![];
+        while let Some(out) = feature.pop_output(100) {
+            outputs.push(out);
+        }
+        assert!(outputs.contains(&FeatureOutput::ToWorker(true, ToWorker::SourceHint(channel, None, SourceHint::Unregister { source: 1, to_root: true }))));
+        assert!(feature.relays.values().all(|relay| relay.relay_dests().map(|(locals, _)| locals.is_empty()).unwrap_or(true)));
+        assert!(feature.is_empty());
+    }
+}
@@ -57,7 +57,7 @@ impl<UserData: Eq + Debug + Copy> RelayConsumers<UserData> {
             self.queue.push_back(RelayWorkerControl::RouteDelLocal(actor));
             self.locals.swap_remove(pos);
         } else {
-            log::warn!("[Relay] Unsub for unknown local actor {:?}", actor);
+            log::debug!("[RelayConsumers] Unsub for unknown local actor {:?} => already released", actor);
         }
     }
 
@@ -89,7 +89,17 @@ impl<UserData: Eq + Debug + Copy> RelayConsumers<UserData> {
                         log::warn!("[Relay] Unsub for wrong session remote {remote}, {uuid} vs {}", slot.uuid);
                     }
                 } else {
-                    log::warn!("[Relay] Unsub for unknown remote {:?}", remote);
+                    // duplicated Unsub after the remote is released, ack again so the remote stops retrying
+                    log::debug!("[Relay] Unsub for unknown remote {:?} => already released", remote);
+                    self.queue.push_back(RelayWorkerControl::SendUnsubOk(uuid, remote));
+                }
+            }
+            RelayControl::Replay(uuid) => {
+                if self.remotes.get(&remote).is_some_and(|slot| slot.uuid == uuid) {
+                    log::debug!("[PubSubConsumers] Replay for remote {remote} with uuid {uuid}");
+                    self.queue.push_back(RelayWorkerControl::SendRetained(remote));
+                } else {
+                    log::debug!("[PubSubConsumers] Replay for unknown remote {remote} with uuid {uuid}");
                 }
             }
             _ => {}
@@ -203,6 +213,26 @@ mod tests {
         assert_eq!(consumers.should_clear(), true);
     }
 
+    #[test]
+    fn relay_duplicated_unsub_should_be_noop() {
+        let mut consumers = RelayConsumers::<()>::default();
+
+        let remote = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+
+        consumers.on_local_unsub(0, FeatureControlActor::Controller(()));
+        assert_eq!(consumers.pop_output(), None);
+
+        consumers.on_remote(0, remote, RelayControl::Sub(1000));
+        consumers.on_remote(100, remote, RelayControl::Unsub(1000));
+        while consumers.pop_output().is_some() {}
+
+        //only ack again, the route is already removed
+        consumers.on_remote(200, remote, RelayControl::Unsub(1000));
+        assert_eq!(consumers.pop_output(), Some(RelayWorkerControl::SendUnsubOk(1000, remote)));
+        assert_eq!(consumers.pop_output(), None);
+        assert_eq!(consumers.should_clear(), true);
+    }
+
     #[test]
     fn relay_remote_should_work_multi_subs() {
         let mut consumers = RelayConsumers::<()>::default();
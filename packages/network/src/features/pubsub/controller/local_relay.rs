@@ -5,7 +5,7 @@ use derivative::Derivative;
 use crate::{
     base::FeatureControlActor,
     data_plane::NetPair,
-    features::pubsub::msg::{Feedback, RelayControl},
+    features::pubsub::msg::{Feedback, FeedbackPolicy, RelayControl},
 };
 
 use super::{consumers::RelayConsumers, feedbacks::FeedbacksAggerator, GenericRelay, GenericRelayOutput};
@@ -15,7 +15,7 @@ use super::{consumers::RelayConsumers, feedbacks::FeedbacksAggerator, GenericRel
 pub struct LocalRelay<UserData> {
     consumers: RelayConsumers<UserData>,
     feedbacks: FeedbacksAggerator<UserData>,
-    publishers: Vec<FeatureControlActor<UserData>>,
+    publishers: Vec<(FeatureControlActor<UserData>, Option<FeedbackPolicy>)>,
 }
 
 impl<UserData: Eq + Debug + Copy> GenericRelay<UserData> for LocalRelay<UserData> {
@@ -24,15 +24,18 @@ impl<UserData: Eq + Debug + Copy> GenericRelay<UserData> for LocalRelay<UserData
         self.consumers.on_tick(now);
     }
 
-    fn on_pub_start(&mut self, actor: FeatureControlActor<UserData>) {
-        if !self.publishers.contains(&actor) {
-            log::debug!("[LocalRelay] on_pub_start {:?}", actor);
-            self.publishers.push(actor);
+    fn on_pub_start(&mut self, actor: FeatureControlActor<UserData>, policy: Option<FeedbackPolicy>) {
+        match self.publishers.iter_mut().find(|(a, _)| *a == actor) {
+            Some((_, old)) => *old = policy,
+            None => {
+                log::debug!("[LocalRelay] on_pub_start {:?} with feedback policy {:?}", actor, policy);
+                self.publishers.push((actor, policy));
+            }
         }
     }
 
     fn on_pub_stop(&mut self, actor: FeatureControlActor<UserData>) {
-        if let Some(index) = self.publishers.iter().position(|x| *x == actor) {
+        if let Some(index) = self.publishers.iter().position(|(a, _)| *a == actor) {
             log::debug!("[LocalRelay] on_pub_stop {:?}", actor);
             self.publishers.swap_remove(index);
         }
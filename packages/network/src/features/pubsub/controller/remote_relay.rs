@@ -8,7 +8,7 @@ use crate::{
     base::FeatureControlActor,
     data_plane::NetPair,
     features::pubsub::{
-        msg::{Feedback, RelayControl},
+        msg::{Feedback, FeedbackPolicy, RelayControl},
         RelayWorkerControl,
     },
 };
@@ -37,14 +37,17 @@ enum RelayState<UserData> {
 
 pub struct RemoteRelay<UserData> {
     uuid: u64,
+    /// Ask the next hop for retained frames when bound
+    retain: bool,
     state: RelayState<UserData>,
     queue: VecDeque<GenericRelayOutput<UserData>>,
 }
 
 impl<UserData: Eq + Copy + Debug> RemoteRelay<UserData> {
-    pub fn new(uuid: u64) -> Self {
+    pub fn new(uuid: u64, retain: bool) -> Self {
         Self {
             uuid,
+            retain,
             state: RelayState::New,
             queue: VecDeque::new(),
         }
@@ -139,7 +142,7 @@ impl<UserData: Eq + Copy + Debug> GenericRelay<UserData> for RemoteRelay<UserDat
         }
     }
 
-    fn on_pub_start(&mut self, _actor: FeatureControlActor<UserData>) {
+    fn on_pub_start(&mut self, _actor: FeatureControlActor<UserData>, _policy: Option<FeedbackPolicy>) {
         panic!("Should not be called");
     }
 
@@ -196,10 +199,10 @@ impl<UserData: Eq + Copy + Debug> GenericRelay<UserData> for RemoteRelay<UserDat
     fn on_local_unsub(&mut self, now: u64, actor: FeatureControlActor<UserData>) {
         match &mut self.state {
             RelayState::New | RelayState::Unbound => {
-                log::warn!("[Relay] Unsub for unknown relay {:?}", self.uuid);
+                log::debug!("[Relay] Unsub for unknown relay {:?} => already released", self.uuid);
             }
             RelayState::Unbinding { .. } => {
-                log::warn!("[Relay] Unsub for relay in unbinding state {:?}", self.uuid);
+                log::debug!("[Relay] Unsub for relay in unbinding state {:?} => already released", self.uuid);
             }
             RelayState::Binding { consumers, .. } => {
                 consumers.on_local_unsub(now, actor);
@@ -231,6 +234,9 @@ impl<UserData: Eq + Copy + Debug> GenericRelay<UserData> for RemoteRelay<UserDat
                     RelayState::Binding { consumers, feedbacks } => {
                         log::info!("[Relay] SubOK for binding relay {} from {remote} => switched to Bound with this remote", self.uuid);
                         self.queue.push_back(GenericRelayOutput::ToWorker(RelayWorkerControl::RouteSetSource(remote)));
+                        if self.retain {
+                            self.queue.push_back(GenericRelayOutput::ToWorker(RelayWorkerControl::SendReplay(self.uuid, remote)));
+                        }
                         let consumers = std::mem::take(consumers);
                         let feedbacks = std::mem::take(feedbacks);
                         self.state = RelayState::Bound {
@@ -298,6 +304,12 @@ impl<UserData: Eq + Copy + Debug> GenericRelay<UserData> for RemoteRelay<UserDat
                 }
                 _ => {}
             },
+            RelayControl::Replay(_) => {
+                if let RelayState::Binding { consumers, .. } | RelayState::Bound { consumers, .. } = &mut self.state {
+                    consumers.on_remote(now, remote, control);
+                    Self::pop_consumers_out(consumers, &mut self.queue);
+                }
+            }
             _ => match &mut self.state {
                 RelayState::New | RelayState::Unbound => {
                     let mut consumers = RelayConsumers::default();
@@ -366,7 +378,7 @@ mod tests {
     use super::RemoteRelay;
 
     fn create_local_bound_relay(uuid: u64, actor: FeatureControlActor<()>, remote: NetPair) -> RemoteRelay<()> {
-        let mut relay = RemoteRelay::new(uuid);
+        let mut relay = RemoteRelay::new(uuid, false);
 
         relay.on_local_sub(0, actor);
 
@@ -383,7 +395,7 @@ mod tests {
 
     #[test]
     fn on_local_sub_unsub() {
-        let mut relay = RemoteRelay::new(1000);
+        let mut relay = RemoteRelay::new(1000, false);
 
         relay.on_local_sub(100, FeatureControlActor::Controller(()));
 
@@ -420,7 +432,7 @@ mod tests {
 
     #[test]
     fn on_remote_sub_unsub() {
-        let mut relay = RemoteRelay::<()>::new(1000);
+        let mut relay = RemoteRelay::<()>::new(1000, false);
 
         let consumer = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2001").expect("Should parse pair");
 
@@ -455,7 +467,7 @@ mod tests {
 
     #[test]
     fn retry_sending_sub() {
-        let mut relay = RemoteRelay::new(1000);
+        let mut relay = RemoteRelay::new(1000, false);
 
         relay.on_local_sub(100, FeatureControlActor::Controller(()));
 
@@ -520,7 +532,7 @@ mod tests {
 
     #[test]
     fn consumer_disconnected_should_unsub_if_empty() {
-        let mut relay = RemoteRelay::<()>::new(1000);
+        let mut relay = RemoteRelay::<()>::new(1000, false);
 
         let consumer = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2001").expect("Should parse pair");
 
@@ -593,4 +605,33 @@ mod tests {
         assert_eq!(relay.pop_output(), Some(GenericRelayOutput::RouteChanged(FeatureControlActor::Controller(()))));
         assert_eq!(relay.pop_output(), None);
     }
+
+    #[test]
+    fn retained_relay_should_ask_replay_when_bound() {
+        let actor = FeatureControlActor::Controller(());
+        let remote = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let consumer = NetPair::new_str("1.1.1.1:1000", "3.3.3.3:3000").expect("Should parse pair");
+        let mut relay = RemoteRelay::new(1000, true);
+
+        relay.on_local_sub(0, actor);
+        assert_eq!(relay.pop_output(), Some(GenericRelayOutput::ToWorker(RelayWorkerControl::RouteSetLocal(actor))));
+        assert_eq!(relay.pop_output(), Some(GenericRelayOutput::ToWorker(RelayWorkerControl::SendSub(1000, None))));
+        assert_eq!(relay.pop_output(), None);
+
+        //the replay is asked after the source is set, so the frames are not dropped as untrusted
+        relay.on_remote(0, remote, RelayControl::SubOK(1000));
+        assert_eq!(relay.pop_output(), Some(GenericRelayOutput::ToWorker(RelayWorkerControl::RouteSetSource(remote))));
+        assert_eq!(relay.pop_output(), Some(GenericRelayOutput::ToWorker(RelayWorkerControl::SendReplay(1000, remote))));
+        assert_eq!(relay.pop_output(), None);
+
+        //a remote consumer behind this relay asks for the frames retained here
+        relay.on_remote(10, consumer, RelayControl::Sub(2000));
+        assert_eq!(relay.pop_output(), Some(GenericRelayOutput::ToWorker(RelayWorkerControl::SendSubOk(2000, consumer))));
+        assert_eq!(relay.pop_output(), Some(GenericRelayOutput::ToWorker(RelayWorkerControl::RouteSetRemote(consumer, 2000))));
+        relay.on_remote(20, consumer, RelayControl::Replay(2001));
+        assert_eq!(relay.pop_output(), None);
+        relay.on_remote(20, consumer, RelayControl::Replay(2000));
+        assert_eq!(relay.pop_output(), Some(GenericRelayOutput::ToWorker(RelayWorkerControl::SendRetained(consumer))));
+        assert_eq!(relay.pop_output(), None);
+    }
 }
@@ -76,6 +76,11 @@ impl<UserData: Eq + Copy + Debug> SourceHintLogic<UserData> {
         }
     }
 
+    /// Local publishers and subscribers of the channel
+    pub fn local_actors(&self) -> (&[FeatureControlActor<UserData>], &[FeatureControlActor<UserData>]) {
+        (&self.local_sources, &self.local_subscribers)
+    }
+
     pub fn on_tick(&mut self, now_ms: u64) {
         let mut timeout_subscribes = vec![];
         for (remote, last_tick) in &self.remote_subscribers {
@@ -7,13 +7,15 @@ use crate::{
 
 use self::msg::{RelayControl, RelayId, SourceHint};
 
+mod auth;
 mod controller;
 mod msg;
 mod worker;
 
+pub use auth::{AllowAllChannels, ChannelAccess, ChannelAuthorizer, PubSubCfg};
 pub use controller::PubSubFeature;
-pub use msg::{ChannelId, Feedback};
-pub use worker::PubSubFeatureWorker;
+pub use msg::{ChannelId, Feedback, FeedbackPolicy};
+pub use worker::{ConsumerQueueCfg, ConsumerStats, OverflowPolicy, PubSubFeatureWorker, RetainCfg};
 
 pub const FEATURE_ID: u8 = 5;
 pub const FEATURE_NAME: &str = "pubsub";
@@ -25,7 +27,16 @@ pub enum ChannelControl {
     UnsubAuto,
     SubSource(NodeId),
     UnsubSource(NodeId),
+    /// Unsubscribe both auto and from all sources
+    UnsubAll,
+    /// Keep frames in a queue until the consumer pulls them, `None` delivers them immediately again
+    SetConsumerQueue(Option<ConsumerQueueCfg>),
+    /// Deliver up to this number of queued frames
+    Pull(u32),
+    GetConsumerStats,
     PubStart,
+    /// Like `PubStart`, but feedbacks are delivered as `ChannelEvent::FeedbackValue` reduced with the policy
+    PubStartWith(FeedbackPolicy),
     PubData(Vec<u8>),
     PubStop,
 }
@@ -38,6 +49,13 @@ pub enum ChannelEvent {
     RouteChanged(NodeId),
     SourceData(NodeId, Vec<u8>),
     FeedbackData(Feedback),
+    /// Kind and aggregated value of feedbacks, for publishers started with `PubStartWith`
+    FeedbackValue(u8, u64),
+    ConsumerStats(ConsumerStats),
+    /// The consumer queue reached its limit, it is unsubscribed from the channel
+    SlowConsumer,
+    /// The `ChannelAuthorizer` of the node rejected the access, the actor is unsubscribed or not registered as publisher
+    Denied(ChannelAccess, NodeId),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -49,6 +67,10 @@ pub enum RelayWorkerControl<UserData> {
     SendUnsub(u64, NetPair),
     SendSubOk(u64, NetPair),
     SendUnsubOk(u64, NetPair),
+    SendSubDenied(u64, NetPair),
+    SendReplay(u64, NetPair),
+    /// Each worker sends the frames it retained
+    SendRetained(NetPair),
     SendRouteChanged,
     SendFeedback(Feedback, NetPair),
     RouteSetSource(NetPair),
@@ -57,6 +79,9 @@ pub enum RelayWorkerControl<UserData> {
     RouteDelLocal(FeatureControlActor<UserData>),
     RouteSetRemote(NetPair, u64),
     RouteDelRemote(NetPair),
+    /// Keep the last frames of the relay for new consumers, until `RetainDel`
+    RetainSet(RetainCfg),
+    RetainDel,
 }
 
 impl<UserData> RelayWorkerControl<UserData> {
@@ -67,22 +92,34 @@ impl<UserData> RelayWorkerControl<UserData> {
                 | RelayWorkerControl::SendUnsub(_, _)
                 | RelayWorkerControl::SendSubOk(_, _)
                 | RelayWorkerControl::SendUnsubOk(_, _)
+                | RelayWorkerControl::SendSubDenied(_, _)
+                | RelayWorkerControl::SendReplay(_, _)
                 | RelayWorkerControl::SendRouteChanged
         )
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ToWorker<UserData> {
     RelayControl(RelayId, RelayWorkerControl<UserData>),
     SourceHint(ChannelId, Option<NetPair>, SourceHint),
     RelayData(RelayId, Vec<u8>),
+    /// Data from a publisher in controller to local consumers, only one worker should deliver it
+    LocalData(RelayId, Vec<u8>),
+    /// Consumer queue controls are handled by the controller's own worker, which owns all queues
+    ConsumerControl(FeatureControlActor<UserData>, ChannelId, ChannelControl),
+    /// Broadcast to all workers, so the others forward data of a queued consumer to the owner worker
+    ConsumerQueued(FeatureControlActor<UserData>, ChannelId, bool),
+    /// Data of queued consumers which is forwarded from other workers, only the owner worker gets it
+    ConsumerData(RelayId, Vec<u8>),
 }
 
 #[derive(Debug, Clone)]
 pub enum ToController {
     RelayControl(NetPair, RelayId, RelayControl),
     SourceHint(NetPair, ChannelId, SourceHint),
+    /// Data which a worker received for queued consumers, it is passed to the owner worker of the queues
+    ConsumerData(RelayId, Vec<u8>),
 }
 
 pub type Output<UserData> = FeatureOutput<UserData, Event, ToWorker<UserData>>;
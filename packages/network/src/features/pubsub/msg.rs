@@ -38,6 +38,27 @@ impl Feedback {
     }
 }
 
+/// How the feedbacks of all consumers are reduced to one value for a publisher
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FeedbackPolicy {
+    Sum,
+    Max,
+    Min,
+    Avg,
+}
+
+impl Feedback {
+    /// Aggregated value of the feedback under the policy, avg is rounded down
+    pub fn value(&self, policy: FeedbackPolicy) -> u64 {
+        match policy {
+            FeedbackPolicy::Sum => self.sum,
+            FeedbackPolicy::Max => self.max,
+            FeedbackPolicy::Min => self.min,
+            FeedbackPolicy::Avg => self.sum / self.count.max(1),
+        }
+    }
+}
+
 ///implement add to Feedback
 impl std::ops::Add for Feedback {
     type Output = Self;
@@ -61,8 +82,12 @@ pub enum RelayControl {
     Unsub(u64),
     SubOK(u64),
     UnsubOK(u64),
+    /// The Sub was rejected by the authorizer of the receiver
+    SubDenied(u64),
     RouteChanged(u64),
     Feedback(Feedback),
+    /// Ask for the retained frames of the channel, sent after the SubOK is applied so the frames are trusted
+    Replay(u64),
 }
 
 impl RelayControl {
@@ -120,6 +145,15 @@ impl TryFrom<&[u8]> for PubsubMessage {
     }
 }
 
+impl PubsubMessage {
+    /// Builds the buffer which forwards a received message to other remotes.
+    /// Only the header is written again, the payload is reused without decode and encode again.
+    pub fn forward(msg: TransportMsg) -> Buffer {
+        let header = TransportMsgHeader::build(FEATURE_ID, 0, RouteRule::Direct);
+        TransportMsg::build_raw(header, msg.take_payload()).take()
+    }
+}
+
 impl From<PubsubMessage> for Buffer {
     fn from(val: PubsubMessage) -> Self {
         let header = TransportMsgHeader::build(FEATURE_ID, 0, RouteRule::Direct);
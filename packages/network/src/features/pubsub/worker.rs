@@ -1,19 +1,30 @@
 use std::{collections::HashMap, fmt::Debug};
 
-use atm0s_sdn_identity::ConnId;
+use atm0s_sdn_identity::{ConnId, NodeId};
 use atm0s_sdn_router::{RouteAction, RouterTable};
 use sans_io_runtime::{collections::DynamicDeque, return_if_err, return_if_none, TaskSwitcherChild};
 
 use crate::{
-    base::{Buffer, FeatureControlActor, FeatureWorker, FeatureWorkerContext, FeatureWorkerInput, FeatureWorkerOutput, TransportMsgHeader},
+    base::{Buffer, FeatureControlActor, FeatureWorker, FeatureWorkerContext, FeatureWorkerInput, FeatureWorkerOutput, TransportMsg, TransportMsgHeader},
     data_plane::NetPair,
 };
 
+use self::{
+    queue::{ConsumerQueue, QueueOverflow},
+    retain::RetainCache,
+};
+
 use super::{
-    msg::{PubsubMessage, RelayControl, RelayId},
+    msg::{ChannelId, PubsubMessage, RelayControl, RelayId},
     ChannelControl, ChannelEvent, Control, Event, RelayWorkerControl, ToController, ToWorker,
 };
 
+mod queue;
+mod retain;
+
+pub use queue::{ConsumerQueueCfg, ConsumerStats, OverflowPolicy};
+pub use retain::RetainCfg;
+
 struct WorkerRelay<UserData> {
     source: Option<NetPair>,
     locals: Vec<FeatureControlActor<UserData>>,
@@ -27,9 +38,97 @@ impl<UserData> WorkerRelay<UserData> {
     }
 }
 
+type WorkerQueue<UserData> = DynamicDeque<FeatureWorkerOutput<UserData, Control, Event, ToController>, 16>;
+
+/// Local consumers which pull frames instead of receiving them immediately.
+/// Queues only live in the controller's own worker, other workers mark queued consumers as forwarded and pass their data to it
+struct ConsumerQueues<UserData> {
+    channels: HashMap<ChannelId, Vec<(FeatureControlActor<UserData>, ConsumerQueue)>>,
+    forwarded: HashMap<ChannelId, Vec<FeatureControlActor<UserData>>>,
+}
+
+impl<UserData: Eq + Copy + Debug> ConsumerQueues<UserData> {
+    fn get_mut(&mut self, channel: ChannelId, actor: FeatureControlActor<UserData>) -> Option<&mut ConsumerQueue> {
+        self.channels.get_mut(&channel)?.iter_mut().find(|(a, _)| *a == actor).map(|(_, q)| q)
+    }
+
+    fn set(&mut self, channel: ChannelId, actor: FeatureControlActor<UserData>, cfg: ConsumerQueueCfg) {
+        if let Some(queue) = self.get_mut(channel, actor) {
+            queue.set_cfg(cfg);
+        } else {
+            self.channels.entry(channel).or_default().push((actor, ConsumerQueue::new(cfg)));
+        }
+    }
+
+    fn set_forwarded(&mut self, channel: ChannelId, actor: FeatureControlActor<UserData>, forwarded: bool) {
+        let actors = self.forwarded.entry(channel).or_default();
+        let pos = actors.iter().position(|a| *a == actor);
+        match (forwarded, pos) {
+            (true, None) => actors.push(actor),
+            (false, Some(pos)) => {
+                actors.swap_remove(pos);
+            }
+            _ => {}
+        }
+        if actors.is_empty() {
+            self.forwarded.remove(&channel);
+        }
+    }
+
+    fn is_forwarded(&self, channel: ChannelId, actor: FeatureControlActor<UserData>) -> bool {
+        self.forwarded.get(&channel).map(|actors| actors.contains(&actor)).unwrap_or(false)
+    }
+
+    fn has_queue(&self, channel: ChannelId, actor: FeatureControlActor<UserData>) -> bool {
+        self.channels.get(&channel).map(|consumers| consumers.iter().any(|(a, _)| *a == actor)).unwrap_or(false)
+    }
+
+    /// The consumer is queued in another worker, so its data must be passed to the owner worker
+    fn should_forward(&self, channel: ChannelId, actor: FeatureControlActor<UserData>) -> bool {
+        self.is_forwarded(channel, actor) && !self.has_queue(channel, actor)
+    }
+
+    fn remove(&mut self, channel: ChannelId, actor: FeatureControlActor<UserData>) -> Option<ConsumerQueue> {
+        let consumers = self.channels.get_mut(&channel)?;
+        let pos = consumers.iter().position(|(a, _)| *a == actor)?;
+        let (_, queue) = consumers.swap_remove(pos);
+        if consumers.is_empty() {
+            self.channels.remove(&channel);
+        }
+        Some(queue)
+    }
+
+    /// Queue the frame if the consumer has a queue, otherwise deliver it now.
+    /// An evicted consumer gets `SlowConsumer` and is unsubscribed from the channel, its queue drops frames until the unsubscribe is done
+    fn deliver(&mut self, channel: ChannelId, actor: FeatureControlActor<UserData>, source: NodeId, data: Vec<u8>, out: &mut WorkerQueue<UserData>) {
+        let queue = match self.get_mut(channel, actor) {
+            Some(queue) => queue,
+            None => {
+                out.push_back(FeatureWorkerOutput::Event(actor, Event(channel, ChannelEvent::SourceData(source, data))));
+                return;
+            }
+        };
+        match queue.push(source, data) {
+            None => {}
+            Some(QueueOverflow::SlowDown(fb)) => {
+                log::debug!("[PubSubWorker] consumer {:?} of {} above high-water mark => send feedback {:?}", actor, channel, fb);
+                out.push_back(FeatureWorkerOutput::ForwardControlToController(actor, Control(channel, ChannelControl::FeedbackAuto(fb))));
+            }
+            Some(QueueOverflow::Evict) => {
+                log::warn!("[PubSubWorker] consumer {:?} of {} is too slow => evict", actor, channel);
+                out.push_back(FeatureWorkerOutput::Event(actor, Event(channel, ChannelEvent::SlowConsumer)));
+                out.push_back(FeatureWorkerOutput::ForwardControlToController(actor, Control(channel, ChannelControl::UnsubAll)));
+            }
+        }
+    }
+}
+
 pub struct PubSubFeatureWorker<UserData> {
     relays: HashMap<RelayId, WorkerRelay<UserData>>,
-    queue: DynamicDeque<FeatureWorkerOutput<UserData, Control, Event, ToController>, 16>,
+    consumers: ConsumerQueues<UserData>,
+    /// Kept apart from relays, a source retains frames before it has any consumer
+    retained: HashMap<RelayId, RetainCache>,
+    queue: WorkerQueue<UserData>,
     shutdown: bool,
 }
 
@@ -37,16 +136,83 @@ impl<UserData> Default for PubSubFeatureWorker<UserData> {
     fn default() -> Self {
         Self {
             relays: HashMap::new(),
+            consumers: ConsumerQueues {
+                channels: HashMap::new(),
+                forwarded: HashMap::new(),
+            },
+            retained: HashMap::new(),
             queue: Default::default(),
             shutdown: false,
         }
     }
 }
 
+impl<UserData: Eq + Copy + Debug> PubSubFeatureWorker<UserData> {
+    /// Deliver data to local consumers of the relay, data of consumers queued in the owner worker is sent once to the controller
+    /// The last receiver takes `data`, only the others need a copy
+    fn deliver_locals(&mut self, relay_id: RelayId, data: Vec<u8>) {
+        let relay = return_if_none!(self.relays.get(&relay_id));
+        let mut forward = false;
+        let mut last = None;
+        for actor in &relay.locals {
+            if self.consumers.should_forward(relay_id.0, *actor) {
+                forward = true;
+            } else if let Some(prev) = last.replace(*actor) {
+                self.consumers.deliver(relay_id.0, prev, relay_id.1, data.clone(), &mut self.queue);
+            }
+        }
+        match (last, forward) {
+            (Some(actor), true) => {
+                self.consumers.deliver(relay_id.0, actor, relay_id.1, data.clone(), &mut self.queue);
+                self.queue.push_back(FeatureWorkerOutput::ToController(ToController::ConsumerData(relay_id, data)));
+            }
+            (Some(actor), false) => self.consumers.deliver(relay_id.0, actor, relay_id.1, data, &mut self.queue),
+            (None, true) => self.queue.push_back(FeatureWorkerOutput::ToController(ToController::ConsumerData(relay_id, data))),
+            (None, false) => {}
+        }
+    }
+
+    fn retain(&mut self, relay_id: RelayId, data: &[u8]) {
+        if let Some(cache) = self.retained.get_mut(&relay_id) {
+            cache.push(data);
+        }
+    }
+
+    fn on_consumer_control(&mut self, actor: FeatureControlActor<UserData>, channel: ChannelId, control: ChannelControl) {
+        match control {
+            ChannelControl::SetConsumerQueue(Some(cfg)) => {
+                log::info!("[PubSubWorker] consumer {:?} of {} use queue {:?}", actor, channel, cfg);
+                self.consumers.set(channel, actor, cfg);
+            }
+            ChannelControl::SetConsumerQueue(None) => {
+                if let Some(mut queue) = self.consumers.remove(channel, actor) {
+                    log::info!("[PubSubWorker] consumer {:?} of {} back to direct delivery", actor, channel);
+                    while let Some((source, data)) = queue.pop() {
+                        self.queue.push_back(FeatureWorkerOutput::Event(actor, Event(channel, ChannelEvent::SourceData(source, data))));
+                    }
+                }
+            }
+            ChannelControl::Pull(max) => {
+                let queue = return_if_none!(self.consumers.get_mut(channel, actor));
+                for _ in 0..max {
+                    let (source, data) = return_if_none!(queue.pop());
+                    self.queue.push_back(FeatureWorkerOutput::Event(actor, Event(channel, ChannelEvent::SourceData(source, data))));
+                }
+            }
+            ChannelControl::GetConsumerStats => {
+                let stats = self.consumers.get_mut(channel, actor).map(|q| q.stats()).unwrap_or_default();
+                self.queue.push_back(FeatureWorkerOutput::Event(actor, Event(channel, ChannelEvent::ConsumerStats(stats))));
+            }
+            _ => {}
+        }
+    }
+}
+
 impl<UserData: Eq + Copy + Debug> FeatureWorker<UserData, Control, Event, ToController, ToWorker<UserData>> for PubSubFeatureWorker<UserData> {
     fn on_network_raw(&mut self, _ctx: &mut FeatureWorkerContext, _now: u64, _conn: ConnId, remote: NetPair, _header: TransportMsgHeader, buf: Buffer) {
         log::debug!("[PubSubWorker] on_network_raw from {}", remote);
-        let msg = return_if_err!(PubsubMessage::try_from(&buf as &[u8]));
+        let transport = return_if_err!(TransportMsg::try_from(buf));
+        let msg: PubsubMessage = return_if_err!(transport.get_payload_bincode());
         match msg {
             PubsubMessage::Control(relay_id, control) => {
                 log::debug!("[PubSubWorker] received PubsubMessage::RelayControl({:?}, {:?})", relay_id, control);
@@ -61,16 +227,11 @@ impl<UserData: Eq + Copy + Debug> FeatureWorker<UserData, Control, Event, ToCont
                 let relay = return_if_none!(self.relays.get(&relay_id));
                 // only relay from trusted source
                 if relay.source == Some(remote) {
-                    for actor in &relay.locals {
-                        self.queue
-                            .push_back(FeatureWorkerOutput::Event(*actor, Event(relay_id.0, ChannelEvent::SourceData(relay_id.1, data.to_vec()))));
-                    }
-
                     if !relay.remotes.is_empty() {
-                        let control = PubsubMessage::Data(relay_id, data);
-                        //TODO avoid copy
-                        self.queue.push_back(FeatureWorkerOutput::RawBroadcast2(relay.remotes.clone(), control.into()));
+                        self.queue.push_back(FeatureWorkerOutput::RawBroadcast2(relay.remotes.clone(), PubsubMessage::forward(transport)));
                     }
+                    self.retain(relay_id, &data);
+                    self.deliver_locals(relay_id, data);
                 } else {
                     log::warn!("[PubsubWorker] Relay from untrusted source local {:?} != remote {}", relay.source, remote);
                 }
@@ -115,6 +276,24 @@ impl<UserData: Eq + Copy + Debug> FeatureWorker<UserData, Control, Event, ToCont
                     let control = PubsubMessage::Control(relay_id, RelayControl::UnsubOK(uuid));
                     self.queue.push_back(FeatureWorkerOutput::RawDirect2(remote, control.into()));
                 }
+                RelayWorkerControl::SendSubDenied(uuid, remote) => {
+                    log::debug!("[PubsubWorker] SendSubDenied for {:?} to {:?}", relay_id, remote);
+                    let control = PubsubMessage::Control(relay_id, RelayControl::SubDenied(uuid));
+                    self.queue.push_back(FeatureWorkerOutput::RawDirect2(remote, control.into()));
+                }
+                RelayWorkerControl::SendReplay(uuid, remote) => {
+                    log::debug!("[PubsubWorker] SendReplay for {:?} to {:?}", relay_id, remote);
+                    let control = PubsubMessage::Control(relay_id, RelayControl::Replay(uuid));
+                    self.queue.push_back(FeatureWorkerOutput::RawDirect2(remote, control.into()));
+                }
+                RelayWorkerControl::SendRetained(remote) => {
+                    let cache = return_if_none!(self.retained.get(&relay_id));
+                    log::debug!("[PubsubWorker] SendRetained {} frames of {:?} to {:?}", cache.frames().count(), relay_id, remote);
+                    for data in cache.frames() {
+                        let msg = PubsubMessage::Data(relay_id, data.clone());
+                        self.queue.push_back(FeatureWorkerOutput::RawDirect2(remote, msg.into()));
+                    }
+                }
                 RelayWorkerControl::SendRouteChanged => {
                     let relay = return_if_none!(self.relays.get(&relay_id));
                     log::debug!("[PubsubWorker] SendRouteChanged for {:?} to remotes {:?}", relay_id, relay.remotes);
@@ -143,7 +322,8 @@ impl<UserData: Eq + Copy + Debug> FeatureWorker<UserData, Control, Event, ToCont
                             log::warn!("[PubsubWorker] RelayDel: relay {:?} source mismatch locked {:?} vs {}", relay_id, entry.source, source);
                         }
                     } else {
-                        log::warn!("[PubsubWorker] RelayDel: relay not found {:?}", relay_id);
+                        //entry is removed with its last consumer, before the controller releases the source
+                        log::debug!("[PubsubWorker] RelayDel: relay {:?} already released", relay_id);
                     }
                 }
                 RelayWorkerControl::RouteSetLocal(actor) => {
@@ -156,6 +336,16 @@ impl<UserData: Eq + Copy + Debug> FeatureWorker<UserData, Control, Event, ToCont
                     });
 
                     entry.locals.push(actor);
+                    //a consumer queued in another worker gets the replay from there
+                    if self.consumers.should_forward(relay_id.0, actor) {
+                        return;
+                    }
+                    if let Some(cache) = self.retained.get(&relay_id) {
+                        log::debug!("[PubsubWorker] replay {} retained frames of {:?} to {:?}", cache.frames().count(), relay_id, actor);
+                        for data in cache.frames() {
+                            self.consumers.deliver(relay_id.0, actor, relay_id.1, data.clone(), &mut self.queue);
+                        }
+                    }
                 }
                 RelayWorkerControl::RouteDelLocal(actor) => {
                     log::debug!("[PubsubWorker] RouteDelLocal for {:?} to {:?}", relay_id, actor);
@@ -166,6 +356,11 @@ impl<UserData: Eq + Copy + Debug> FeatureWorker<UserData, Control, Event, ToCont
                         if entry.is_empty() {
                             self.relays.remove(&relay_id);
                         }
+                        //queued frames are useless after the consumer is gone from all sources of the channel
+                        if !self.relays.iter().any(|(id, relay)| id.0 == relay_id.0 && relay.locals.contains(&actor)) {
+                            self.consumers.remove(relay_id.0, actor);
+                            self.consumers.set_forwarded(relay_id.0, actor, false);
+                        }
                     } else {
                         log::warn!("[PubsubWorker] RelayDelLocal: relay not found {:?}", relay_id);
                     }
@@ -195,6 +390,14 @@ impl<UserData: Eq + Copy + Debug> FeatureWorker<UserData, Control, Event, ToCont
                         log::warn!("RelayDelSub: relay not found {:?}", relay_id);
                     }
                 }
+                RelayWorkerControl::RetainSet(cfg) => {
+                    log::debug!("[PubsubWorker] RetainSet for {:?} with {:?}", relay_id, cfg);
+                    self.retained.entry(relay_id).or_insert_with(|| RetainCache::new(cfg));
+                }
+                RelayWorkerControl::RetainDel => {
+                    log::debug!("[PubsubWorker] RetainDel for {:?}", relay_id);
+                    self.retained.remove(&relay_id);
+                }
             },
             FeatureWorkerInput::FromController(_, ToWorker::SourceHint(channel, remote, data)) => {
                 if let Some(remote) = remote {
@@ -208,6 +411,20 @@ impl<UserData: Eq + Copy + Debug> FeatureWorker<UserData, Control, Event, ToCont
                     }
                 }
             }
+            FeatureWorkerInput::FromController(_, ToWorker::LocalData(relay_id, data)) => {
+                self.retain(relay_id, &data);
+                self.deliver_locals(relay_id, data);
+            }
+            FeatureWorkerInput::FromController(_, ToWorker::ConsumerControl(actor, channel, control)) => self.on_consumer_control(actor, channel, control),
+            FeatureWorkerInput::FromController(_, ToWorker::ConsumerQueued(actor, channel, queued)) => self.consumers.set_forwarded(channel, actor, queued),
+            FeatureWorkerInput::FromController(_, ToWorker::ConsumerData(relay_id, data)) => {
+                let relay = return_if_none!(self.relays.get(&relay_id));
+                for actor in &relay.locals {
+                    if self.consumers.is_forwarded(relay_id.0, *actor) || self.consumers.has_queue(relay_id.0, *actor) {
+                        self.consumers.deliver(relay_id.0, *actor, relay_id.1, data.clone(), &mut self.queue);
+                    }
+                }
+            }
             FeatureWorkerInput::FromController(_, ToWorker::RelayData(relay_id, data)) => {
                 let relay = return_if_none!(self.relays.get(&relay_id));
                 if relay.remotes.is_empty() {
@@ -220,18 +437,15 @@ impl<UserData: Eq + Copy + Debug> FeatureWorker<UserData, Control, Event, ToCont
             FeatureWorkerInput::Control(actor, control) => match control {
                 Control(channel, ChannelControl::PubData(data)) => {
                     let relay_id = RelayId(channel, ctx.node_id);
+                    self.retain(relay_id, &data);
                     let relay = return_if_none!(self.relays.get(&relay_id));
-
-                    for actor in &relay.locals {
-                        self.queue
-                            .push_back(FeatureWorkerOutput::Event(*actor, Event(channel, ChannelEvent::SourceData(ctx.node_id, data.clone()))));
-                    }
-
                     if !relay.remotes.is_empty() {
-                        let control = PubsubMessage::Data(relay_id, data);
+                        let control = PubsubMessage::Data(relay_id, data.clone());
                         self.queue.push_back(FeatureWorkerOutput::RawBroadcast2(relay.remotes.clone(), control.into()));
                     }
+                    self.deliver_locals(relay_id, data);
                 }
+                //consumer queue controls also go to the controller, which sends them to the worker owning the queues
                 _ => self.queue.push_back(FeatureWorkerOutput::ForwardControlToController(actor, control)),
             },
             _ => {}
@@ -258,3 +472,85 @@ impl<UserData> TaskSwitcherChild<FeatureWorkerOutput<UserData, Control, Event, T
         self.queue.pop_front()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use atm0s_sdn_router::shadow::{MockShadowRouterHistory, ShadowRouter};
+    use rand::rngs::mock::StepRng;
+    use sans_io_runtime::TaskSwitcherChild;
+
+    use crate::{
+        base::{FeatureControlActor, FeatureWorker, FeatureWorkerContext, FeatureWorkerInput, FeatureWorkerOutput},
+        features::pubsub::{
+            msg::{ChannelId, RelayId},
+            ChannelControl, ChannelEvent, Event, RelayWorkerControl, ToController, ToWorker,
+        },
+    };
+
+    use super::{ConsumerQueueCfg, OverflowPolicy, PubSubFeatureWorker};
+
+    #[test]
+    fn queued_consumer_data_should_be_forwarded_to_owner_worker() {
+        let mut ctx = FeatureWorkerContext {
+            node_id: 1,
+            router: ShadowRouter::new(1, Arc::new(MockShadowRouterHistory::new())),
+            random: Box::new(StepRng::new(0, 1)),
+        };
+        let channel = ChannelId(1);
+        let relay_id = RelayId(channel, 1);
+        let queued = FeatureControlActor::Worker(0, ());
+        let direct = FeatureControlActor::Worker(1, ());
+        let cfg = ConsumerQueueCfg {
+            high_water: 10,
+            policy: OverflowPolicy::DropOldest,
+        };
+        let mut owner = PubSubFeatureWorker::<()>::default();
+        let mut other = PubSubFeatureWorker::<()>::default();
+
+        for worker in [&mut owner, &mut other] {
+            for actor in [queued, direct] {
+                worker.on_input(
+                    &mut ctx,
+                    0,
+                    FeatureWorkerInput::FromController(true, ToWorker::RelayControl(relay_id, RelayWorkerControl::RouteSetLocal(actor))),
+                );
+            }
+            worker.on_input(&mut ctx, 0, FeatureWorkerInput::FromController(true, ToWorker::ConsumerQueued(queued, channel, true)));
+        }
+        owner.on_input(
+            &mut ctx,
+            0,
+            FeatureWorkerInput::FromController(false, ToWorker::ConsumerControl(queued, channel, ChannelControl::SetConsumerQueue(Some(cfg)))),
+        );
+        assert!(owner.pop_output(0).is_none());
+
+        //the other worker delivers direct consumers and passes the data once to the owner
+        other.on_input(&mut ctx, 0, FeatureWorkerInput::FromController(false, ToWorker::LocalData(relay_id, vec![1])));
+        assert!(matches!(
+            other.pop_output(0),
+            Some(FeatureWorkerOutput::Event(actor, Event(_, ChannelEvent::SourceData(1, data)))) if actor == direct && data == vec![1]
+        ));
+        let data = match other.pop_output(0) {
+            Some(FeatureWorkerOutput::ToController(ToController::ConsumerData(id, data))) if id == relay_id => data,
+            _ => panic!("Should forward data to owner"),
+        };
+        assert!(other.pop_output(0).is_none());
+
+        //the owner only queues it for the queued consumer
+        owner.on_input(&mut ctx, 0, FeatureWorkerInput::FromController(false, ToWorker::ConsumerData(relay_id, data)));
+        assert!(owner.pop_output(0).is_none());
+
+        owner.on_input(
+            &mut ctx,
+            0,
+            FeatureWorkerInput::FromController(false, ToWorker::ConsumerControl(queued, channel, ChannelControl::Pull(10))),
+        );
+        assert!(matches!(
+            owner.pop_output(0),
+            Some(FeatureWorkerOutput::Event(actor, Event(_, ChannelEvent::SourceData(1, data)))) if actor == queued && data == vec![1]
+        ));
+        assert!(owner.pop_output(0).is_none());
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::VecDeque;
+
+use atm0s_sdn_identity::NodeId;
+
+use crate::features::pubsub::msg::Feedback;
+
+const SLOW_DOWN_INTERVAL_MS: u16 = 1000;
+const SLOW_DOWN_TIMEOUT_MS: u16 = 2000;
+
+/// What a queued consumer does when its frames go above the high-water mark
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drop the oldest frames, for lossy channels where only fresh data matters
+    DropOldest,
+    /// Keep all frames and send a feedback of `kind` with the queue length to publishers.
+    /// The consumer is evicted when the queue reaches twice the high-water mark
+    SlowDown { kind: u8 },
+}
+
+/// Frames for the consumer are kept until it pulls them with `ChannelControl::Pull`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConsumerQueueCfg {
+    pub high_water: usize,
+    pub policy: OverflowPolicy,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConsumerStats {
+    pub queued: usize,
+    pub dropped: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum QueueOverflow {
+    SlowDown(Feedback),
+    Evict,
+}
+
+pub struct ConsumerQueue {
+    cfg: ConsumerQueueCfg,
+    frames: VecDeque<(NodeId, Vec<u8>)>,
+    dropped: u64,
+    over: bool,
+    evicted: bool,
+}
+
+impl ConsumerQueue {
+    pub fn new(cfg: ConsumerQueueCfg) -> Self {
+        Self {
+            cfg,
+            frames: VecDeque::new(),
+            dropped: 0,
+            over: false,
+            evicted: false,
+        }
+    }
+
+    pub fn set_cfg(&mut self, cfg: ConsumerQueueCfg) {
+        self.cfg = cfg;
+    }
+
+    fn high_water(&self) -> usize {
+        self.cfg.high_water.max(1)
+    }
+
+    /// Queue a frame, the feedback is only returned when the queue crosses the high-water mark, not for each frame above it
+    pub fn push(&mut self, source: NodeId, data: Vec<u8>) -> Option<QueueOverflow> {
+        if self.evicted {
+            self.dropped += 1;
+            return None;
+        }
+        self.frames.push_back((source, data));
+        match self.cfg.policy {
+            OverflowPolicy::DropOldest => {
+                while self.frames.len() > self.high_water() {
+                    self.frames.pop_front();
+                    self.dropped += 1;
+                }
+                None
+            }
+            OverflowPolicy::SlowDown { kind } => {
+                if self.frames.len() >= 2 * self.high_water() {
+                    self.evicted = true;
+                    self.dropped += self.frames.len() as u64;
+                    self.frames.clear();
+                    Some(QueueOverflow::Evict)
+                } else if self.frames.len() > self.high_water() && !self.over {
+                    self.over = true;
+                    Some(QueueOverflow::SlowDown(Feedback::simple(kind, self.frames.len() as u64, SLOW_DOWN_INTERVAL_MS, SLOW_DOWN_TIMEOUT_MS)))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<(NodeId, Vec<u8>)> {
+        let frame = self.frames.pop_front()?;
+        if self.frames.len() <= self.high_water() {
+            self.over = false;
+        }
+        Some(frame)
+    }
+
+    pub fn stats(&self) -> ConsumerStats {
+        ConsumerStats {
+            queued: self.frames.len(),
+            dropped: self.dropped,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::features::pubsub::msg::Feedback;
+
+    use super::{ConsumerQueue, ConsumerQueueCfg, ConsumerStats, OverflowPolicy, QueueOverflow};
+
+    #[test]
+    fn drop_oldest_above_high_water() {
+        let mut queue = ConsumerQueue::new(ConsumerQueueCfg {
+            high_water: 2,
+            policy: OverflowPolicy::DropOldest,
+        });
+        for i in 0..5 {
+            assert_eq!(queue.push(1, vec![i]), None);
+        }
+        assert_eq!(queue.stats(), ConsumerStats { queued: 2, dropped: 3 });
+        assert_eq!(queue.pop(), Some((1, vec![3])));
+        assert_eq!(queue.pop(), Some((1, vec![4])));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn slow_down_once_then_evict() {
+        let mut queue = ConsumerQueue::new(ConsumerQueueCfg {
+            high_water: 2,
+            policy: OverflowPolicy::SlowDown { kind: 1 },
+        });
+        assert_eq!(queue.push(1, vec![0]), None);
+        assert_eq!(queue.push(1, vec![1]), None);
+        assert_eq!(queue.push(1, vec![2]), Some(QueueOverflow::SlowDown(Feedback::simple(1, 3, 1000, 2000))));
+
+        //drained below the mark, so crossing it again sends new feedback
+        queue.pop();
+        assert_eq!(queue.push(1, vec![3]), Some(QueueOverflow::SlowDown(Feedback::simple(1, 3, 1000, 2000))));
+        assert_eq!(queue.push(1, vec![4]), Some(QueueOverflow::Evict));
+        assert_eq!(queue.stats(), ConsumerStats { queued: 0, dropped: 4 });
+        //evicted queue drops everything
+        assert_eq!(queue.push(1, vec![5]), None);
+        assert_eq!(queue.stats(), ConsumerStats { queued: 0, dropped: 5 });
+    }
+}
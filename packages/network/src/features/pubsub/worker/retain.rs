@@ -0,0 +1,64 @@
+use std::collections::VecDeque;
+
+/// Last frames of a channel which are replayed to each new consumer, like MQTT retain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetainCfg {
+    /// Number of frames, 1 keeps only the last value
+    pub depth: usize,
+    /// Older frames are dropped when retained frames are above this size
+    pub max_bytes: usize,
+}
+
+pub struct RetainCache {
+    cfg: RetainCfg,
+    frames: VecDeque<Vec<u8>>,
+    bytes: usize,
+}
+
+impl RetainCache {
+    pub fn new(cfg: RetainCfg) -> Self {
+        Self {
+            cfg,
+            frames: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    /// A frame bigger than `max_bytes` clears the cache, it would drop all older frames anyway
+    pub fn push(&mut self, data: &[u8]) {
+        self.frames.push_back(data.to_vec());
+        self.bytes += data.len();
+        while self.frames.len() > self.cfg.depth || self.bytes > self.cfg.max_bytes {
+            let frame = match self.frames.pop_front() {
+                Some(frame) => frame,
+                None => break,
+            };
+            self.bytes -= frame.len();
+        }
+    }
+
+    pub fn frames(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.frames.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RetainCache, RetainCfg};
+
+    #[test]
+    fn keep_last_frames_in_bounds() {
+        let mut cache = RetainCache::new(RetainCfg { depth: 2, max_bytes: 6 });
+        cache.push(&[1]);
+        cache.push(&[2]);
+        cache.push(&[3]);
+        assert_eq!(cache.frames().cloned().collect::<Vec<_>>(), vec![vec![2], vec![3]]);
+
+        //the size bound drops older frames before the depth does
+        cache.push(&[4; 5]);
+        assert_eq!(cache.frames().cloned().collect::<Vec<_>>(), vec![vec![3], vec![4; 5]]);
+
+        cache.push(&[5; 7]);
+        assert_eq!(cache.frames().count(), 0);
+    }
+}
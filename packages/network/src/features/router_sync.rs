@@ -1,32 +1,158 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 
 use atm0s_sdn_identity::{ConnId, NodeId};
 use atm0s_sdn_router::{
-    core::{DestDelta, Metric, RegistryDelta, RegistryDestDelta, Router, RouterDelta, RouterDump, RouterSync, TableDelta},
+    core::{
+        DestDelta, FlapDampingCfg, Metric, MetricCompareMode, RegistryDelta, RegistryDestDelta, RouteExplanation, Router, RouterDelta, RouterDump, RouterSync, RouterSyncDelta, TableDelta,
+        MAX_LATENCY_MS,
+    },
     shadow::ShadowRouterDelta,
 };
 use derivative::Derivative;
-use sans_io_runtime::{collections::DynamicDeque, TaskSwitcherChild};
+use sans_io_runtime::{collections::DynamicDeque, return_if_none, TaskSwitcherChild};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    base::{ConnectionEvent, Feature, FeatureContext, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerContext, FeatureWorkerInput, FeatureWorkerOutput, NetOutgoingMeta},
+    base::{
+        ConnectionEvent, Feature, FeatureContext, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerContext, FeatureWorkerInput, FeatureWorkerOutput, NetOutgoingMeta,
+        TrafficClass,
+    },
     data_plane::NetPair,
 };
 
+pub use self::service_load::{pick_by_inverse_load, ServiceLoad};
+use self::service_load::{ServiceLoads, MAX_LOADS_PER_MSG};
+
+mod service_load;
+
 pub const FEATURE_ID: u8 = 2;
 pub const FEATURE_NAME: &str = "router_sync";
+/// Version 2 adds delta syncs, they are only sent over connections where both sides advertise it
+pub const FEATURE_VERSION: u8 = 2;
 
 const INIT_RTT_MS: u16 = 1000;
+/// Next best paths of each destination which workers keep when `max_alternates` is not set
+const DEFAULT_MAX_ALTERNATES: usize = 2;
 const INIT_BW: u32 = 100_000_000;
+/// Local service load weights are refreshed with a new seq every this many advertise rounds, other rounds only carry changes
+const SERVICE_LOAD_REFRESH_ROUNDS: u64 = 4;
+
+/// Bounds of the adaptive sync interval.
+///
+/// The interval is halved on each tick which saw route changes, and grows by half after each
+/// sync round without changes, always staying inside `[min_ms, max_ms]`.
+/// The default keeps the fixed 1 second interval, adaptivity is enabled by setting `max_ms` above `min_ms`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncIntervalCfg {
+    pub min_ms: u64,
+    pub max_ms: u64,
+}
+
+impl Default for SyncIntervalCfg {
+    fn default() -> Self {
+        Self { min_ms: 1_000, max_ms: 1_000 }
+    }
+}
+
+/// Routing behaviour of the RouterSync feature
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RouterSyncCfg {
+    pub sync_interval: SyncIntervalCfg,
+    /// Extra score allowed over the best path for a path to join the equal-cost set, None disables multi-path
+    pub ecmp_tolerance: Option<u32>,
+    /// How paths are ordered, must be the same in whole network. Syncs from nodes with other mode are ignored
+    pub compare_mode: MetricCompareMode,
+    /// Stop advertising destinations which toggle too often, None disables damping
+    pub flap_damping: Option<FlapDampingCfg>,
+    /// Learned routes which are not refreshed by any sync within this time are evicted, None keeps them until disconnect.
+    /// Should be greater than `sync_interval.max_ms`
+    pub route_timeout_ms: Option<u64>,
+    /// Paths longer than this are rejected instead of kept with saturated metric, None allows any length
+    pub max_hops: Option<usize>,
+    /// How often changed service load weights are advertised to neighbours, None disables weighted service discovery.
+    /// Unchanged weights are refreshed every 4 intervals and removed when they are not refreshed within 12 intervals
+    pub service_load_interval_ms: Option<u64>,
+    /// Send only changed table slots after the first sync, with a full sync after each `n` deltas. None always sends full syncs.
+    /// Neighbours must run the same version, older nodes ignore delta messages
+    pub delta_sync_full_every: Option<u32>,
+    /// Measured RTT of a neighbour only replaces the latency of its direct metric when it differs by more than this.
+    /// Each replacement is a route change, so a low value makes noisy links re-advertise often
+    pub rtt_threshold_ms: u16,
+    /// Next best paths of each destination which workers reroute over when the connections of the best paths are gone,
+    /// None is 2 and Some(0) disables rerouting
+    pub max_alternates: Option<usize>,
+    /// Routing is converged after the table did not change for this long, None is twice `sync_interval.min_ms`
+    pub convergence_quiet_ms: Option<u64>,
+}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Control {
     DumpRouter,
+    GetSyncInterval,
+    /// Set the load weight of a local discoverable service, lower is less loaded
+    SetServiceLoad(u8, u32),
+    FindService(u8),
+    GetConvergence,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Event {
     DumpRouter(Box<RouterDump>),
+    SyncInterval(u64),
+    /// Nodes which run the service with their load weight, see [`pick_by_inverse_load`]
+    ServiceNodes(u8, Vec<(NodeId, u32)>),
+    Convergence(Convergence),
+}
+
+/// Stability of the routing table, so tests and tools can wait for topology changes instead of sleeping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Convergence {
+    /// Incremented on each change of the routing table or the service registry
+    pub generation: u64,
+    /// No change for `RouterSyncCfg::convergence_quiet_ms`. Connections which are still in handshake are not counted
+    pub converged: bool,
+}
+
+/// Tagged messages start with `[MSG_MARK, MSG_VERSION]`. Older nodes send a bare RouterSync, which starts with the u64 LE
+/// length of its service list: at most 256, so its second byte is always 0 or 1
+const MSG_MARK: u8 = 0xFF;
+/// Version 1 is the bare RouterSync of older nodes
+const MSG_VERSION: u8 = 2;
+
+/// Sync message on the wire, the sender compare mode is included so mixed-mode neighbours don't mix their orderings
+#[derive(Debug, Serialize, Deserialize)]
+struct SyncMsg(MetricCompareMode, RouterSync);
+
+/// Body of tagged messages, older nodes fail to decode variants they don't know and drop them
+#[derive(Debug, Serialize, Deserialize)]
+enum RouterSyncMsg {
+    Sync(SyncMsg),
+    ServiceLoads(Vec<ServiceLoad>),
+    SyncDelta(MetricCompareMode, RouterSyncDelta),
+    /// The receiver missed a delta, the next sync to it must be full
+    RequestFullSync,
+}
+
+impl RouterSyncMsg {
+    /// Syncs in the default compare mode are sent bare, older nodes only know that mode and keep understanding them
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            Self::Sync(SyncMsg(MetricCompareMode::Score, sync)) => bincode::serialize(sync).expect("Should serialize"),
+            msg => {
+                let mut buf = vec![MSG_MARK, MSG_VERSION];
+                bincode::serialize_into(&mut buf, msg).expect("Should serialize");
+                buf
+            }
+        }
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        match buf {
+            [MSG_MARK, MSG_VERSION, msg @ ..] => bincode::deserialize(msg).ok(),
+            [_, 0 | 1, ..] => bincode::deserialize::<RouterSync>(buf).ok().map(|sync| Self::Sync(SyncMsg(MetricCompareMode::Score, sync))),
+            _ => None,
+        }
+    }
 }
 
 pub type ToWorker = ShadowRouterDelta<NetPair>;
@@ -40,34 +166,218 @@ pub struct RouterSyncFeature<UserData> {
     conns: HashMap<ConnId, (NodeId, NetPair, Metric)>,
     queue: VecDeque<Output<UserData>>,
     services: Vec<u8>,
+    interval_cfg: SyncIntervalCfg,
+    interval_ms: u64,
+    last_sync_ms: Option<u64>,
+    route_changes: u32,
+    changed_since_sync: bool,
+    local_services: Vec<u8>,
+    service_loads: ServiceLoads,
+    service_load_interval_ms: Option<u64>,
+    last_service_load_ms: Option<u64>,
+    service_load_rounds: u64,
+    delta_full_every: Option<u32>,
+    /// Generations sent in the last sync to each connection, with number of deltas since the last full sync
+    delta_sent: HashMap<ConnId, ([u64; 4], u32)>,
+    /// Connections whose peer only speaks version 1, they always get full syncs
+    full_sync_conns: HashSet<ConnId>,
+    rtt_threshold_ms: u16,
+    generation: u64,
+    last_change_ms: u64,
+    convergence_quiet_ms: u64,
     shutdown: bool,
 }
 
 impl<UserData> RouterSyncFeature<UserData> {
-    pub fn new(node: NodeId, services: Vec<u8>) -> Self {
-        log::info!("[RouterSync] started node {} with public services {:?}", node, services);
+    pub fn new(node: NodeId, services: Vec<u8>, cfg: RouterSyncCfg) -> Self {
+        log::info!("[RouterSync] started node {} with public services {:?}, cfg {:?}", node, services, cfg);
+        let interval_cfg = SyncIntervalCfg {
+            min_ms: cfg.sync_interval.min_ms,
+            max_ms: cfg.sync_interval.max_ms.max(cfg.sync_interval.min_ms),
+        };
+
+        let mut router = Router::new(node);
+        router.set_ecmp_tolerance(cfg.ecmp_tolerance);
+        router.set_max_alternates(cfg.max_alternates.unwrap_or(DEFAULT_MAX_ALTERNATES));
+        router.set_compare_mode(cfg.compare_mode);
+        if let Some(damping) = cfg.flap_damping {
+            router.set_flap_damping(damping);
+        }
+        if let Some(timeout_ms) = cfg.route_timeout_ms {
+            router.set_route_timeout(timeout_ms);
+        }
+        router.set_max_hops(cfg.max_hops);
+
+        let mut service_loads = ServiceLoads::new(node);
+        for service in &services {
+            service_loads.set_local(*service, 0);
+        }
 
         Self {
-            router: Router::new(node),
+            router,
+            local_services: services.clone(),
             services,
             conns: HashMap::new(),
             queue: VecDeque::new(),
+            interval_cfg,
+            interval_ms: interval_cfg.min_ms,
+            last_sync_ms: None,
+            route_changes: 0,
+            changed_since_sync: false,
+            service_loads,
+            service_load_interval_ms: cfg.service_load_interval_ms,
+            last_service_load_ms: None,
+            service_load_rounds: 0,
+            delta_full_every: cfg.delta_sync_full_every,
+            delta_sent: HashMap::new(),
+            full_sync_conns: HashSet::new(),
+            rtt_threshold_ms: cfg.rtt_threshold_ms,
+            generation: 0,
+            last_change_ms: 0,
+            convergence_quiet_ms: cfg.convergence_quiet_ms.unwrap_or(2 * interval_cfg.min_ms),
             shutdown: false,
         }
     }
 
-    fn send_sync_to(router: &Router, queue: &mut VecDeque<Output<UserData>>, conn: ConnId, node: NodeId) {
-        let sync = router.create_sync(node);
+    /// Advertise a discoverable service which is registered at runtime, it joins the router on the next tick
+    pub fn register_local_service(&mut self, service: u8) {
+        if self.local_services.contains(&service) {
+            return;
+        }
+        self.local_services.push(service);
+        self.services.push(service);
+        self.service_loads.set_local(service, 0);
+    }
+
+    /// Stop advertising a local service, remote nodes drop it with the next sync
+    pub fn unregister_local_service(&mut self, service: u8) {
+        if !self.local_services.contains(&service) {
+            return;
+        }
+        log::info!("[RouterSync] unregister local service {}", service);
+        self.local_services.retain(|s| *s != service);
+        self.service_loads.remove_local(service);
+        //it may not be registered to the router yet
+        if let Some(pos) = self.services.iter().position(|s| *s == service) {
+            self.services.remove(pos);
+        } else {
+            self.router.unregister_service(service);
+        }
+    }
+
+    /// Nodes which run the service with their load weight, sorted by node id. Empty if weighted service discovery is disabled
+    pub fn find_service(&self, service: u8) -> Vec<(NodeId, u32)> {
+        if self.service_load_interval_ms.is_none() {
+            return vec![];
+        }
+        self.service_loads.find(service)
+    }
+
+    /// Copy of the current router state, same as the `DumpRouter` control
+    pub fn router_dump(&self) -> RouterDump {
+        self.router.dump()
+    }
+
+    /// Paths to dest and why the best one is selected, from the same router which is synced to workers
+    pub fn explain_route(&self, dest: NodeId) -> Option<RouteExplanation> {
+        self.router.explain(dest, &[])
+    }
+
+    /// Current interval between two sync rounds
+    pub fn sync_interval_ms(&self) -> u64 {
+        self.interval_ms
+    }
+
+    /// Same as the `GetConvergence` control
+    pub fn convergence(&self, now_ms: u64) -> Convergence {
+        Convergence {
+            generation: self.generation,
+            converged: now_ms >= self.last_change_ms + self.convergence_quiet_ms,
+        }
+    }
+
+    /// Adapt the interval to route changes seen since the previous tick, then return true if a sync round is due.
+    fn should_sync(&mut self, now: u64) -> bool {
+        if self.route_changes > 0 {
+            self.interval_ms = (self.interval_ms / 2).max(self.interval_cfg.min_ms);
+            self.route_changes = 0;
+            self.changed_since_sync = true;
+        }
+
+        if matches!(self.last_sync_ms, Some(last) if now < last + self.interval_ms) {
+            return false;
+        }
+
+        if !self.changed_since_sync {
+            self.interval_ms = (self.interval_ms + self.interval_ms / 2).clamp(self.interval_cfg.min_ms, self.interval_cfg.max_ms);
+        }
+        self.last_sync_ms = Some(now);
+        self.changed_since_sync = false;
+        true
+    }
+
+    fn advertise_service_loads(&mut self, now: u64) {
+        let interval = return_if_none!(self.service_load_interval_ms);
+        self.service_loads.on_tick(now);
+        if matches!(self.last_service_load_ms, Some(last) if now < last + interval) {
+            return;
+        }
+        self.last_service_load_ms = Some(now);
+
+        let refresh = self.service_load_rounds % SERVICE_LOAD_REFRESH_ROUNDS == 0;
+        self.service_load_rounds += 1;
+        let loads = self.service_loads.advertise(now, Self::service_load_ttl(interval), refresh);
+        for chunk in loads.chunks(MAX_LOADS_PER_MSG) {
+            let buf = RouterSyncMsg::ServiceLoads(chunk.to_vec()).encode();
+            for conn in self.conns.keys() {
+                self.queue.push_back(FeatureOutput::SendDirect(
+                    *conn,
+                    NetOutgoingMeta::new(false, 1.into(), 0, true).with_class(TrafficClass::NetworkControl),
+                    buf.clone().into(),
+                ));
+            }
+        }
+    }
+
+    /// The whole service load table for a new neighbour, later rounds only carry changes
+    fn send_service_loads_to(&mut self, now: u64, conn: ConnId) {
+        let interval = return_if_none!(self.service_load_interval_ms);
+        let loads = self.service_loads.snapshot(now, Self::service_load_ttl(interval));
+        for chunk in loads.chunks(MAX_LOADS_PER_MSG) {
+            Self::send_to(&mut self.queue, conn, &RouterSyncMsg::ServiceLoads(chunk.to_vec()));
+        }
+    }
+
+    fn service_load_ttl(interval: u64) -> u32 {
+        (3 * SERVICE_LOAD_REFRESH_ROUNDS * interval) as u32
+    }
+
+    fn send_sync_to(&mut self, conn: ConnId, node: NodeId) {
+        let sync = match self.delta_full_every.filter(|_| !self.full_sync_conns.contains(&conn)) {
+            Some(full_every) => {
+                let since = match self.delta_sent.get(&conn) {
+                    Some((gens, deltas)) if *deltas < full_every => Some((*gens, *deltas + 1)),
+                    _ => None,
+                };
+                self.delta_sent.insert(conn, (self.router.generations(), since.map(|(_, deltas)| deltas).unwrap_or(0)));
+                RouterSyncMsg::SyncDelta(self.router.compare_mode(), self.router.create_sync_delta(node, since.map(|(gens, _)| gens)))
+            }
+            None => RouterSyncMsg::Sync(SyncMsg(self.router.compare_mode(), self.router.create_sync(node))),
+        };
+        Self::send_to(&mut self.queue, conn, &sync);
+    }
+
+    fn send_to(queue: &mut VecDeque<Output<UserData>>, conn: ConnId, msg: &RouterSyncMsg) {
         queue.push_back(FeatureOutput::SendDirect(
             conn,
-            NetOutgoingMeta::new(false, 1.into(), 0, true),
-            bincode::serialize(&sync).expect("").into(),
+            NetOutgoingMeta::new(false, 1.into(), 0, true).with_class(TrafficClass::NetworkControl),
+            msg.encode().into(),
         ));
     }
 }
 
 impl<UserData> Feature<UserData, Control, Event, ToController, ToWorker> for RouterSyncFeature<UserData> {
-    fn on_shared_input(&mut self, _ctx: &FeatureContext, _now: u64, input: FeatureSharedInput) {
+    fn on_shared_input(&mut self, _ctx: &FeatureContext, now: u64, input: FeatureSharedInput) {
         match input {
             FeatureSharedInput::Tick(tick_count) => {
                 if tick_count < 1 {
@@ -75,13 +385,21 @@ impl<UserData> Feature<UserData, Control, Event, ToController, ToWorker> for Rou
                     return;
                 }
 
+                self.router.on_tick(now);
+                self.advertise_service_loads(now);
+
                 while let Some(service) = self.services.pop() {
                     log::info!("[RouterSync] register local service {}", service);
                     self.router.register_service(service);
                 }
 
-                for (conn, (node, _, _)) in self.conns.iter() {
-                    Self::send_sync_to(&self.router, &mut self.queue, *conn, *node);
+                if !self.should_sync(now) {
+                    return;
+                }
+
+                let conns: Vec<_> = self.conns.iter().map(|(conn, (node, _, _))| (*conn, *node)).collect();
+                for (conn, node) in conns {
+                    self.send_sync_to(conn, node);
                 }
             }
             FeatureSharedInput::Connection(event) => match event {
@@ -90,47 +408,117 @@ impl<UserData> Feature<UserData, Control, Event, ToController, ToWorker> for Rou
                     let metric = Metric::new(INIT_RTT_MS, vec![ctx.node], INIT_BW);
                     self.conns.insert(ctx.conn, (ctx.node, ctx.pair, metric.clone()));
                     self.router.set_direct(ctx.conn, metric);
-                    Self::send_sync_to(&self.router, &mut self.queue, ctx.conn, ctx.node);
+                    self.route_changes += 1;
+                    self.delta_sent.remove(&ctx.conn);
+                    if ctx.feature_version(FEATURE_ID) < 2 {
+                        self.full_sync_conns.insert(ctx.conn);
+                    } else {
+                        self.full_sync_conns.remove(&ctx.conn);
+                    }
+                    self.send_sync_to(ctx.conn, ctx.node);
+                    self.send_service_loads_to(now, ctx.conn);
                 }
                 ConnectionEvent::Stats(ctx, stats) => {
                     log::debug!("[RouterSync] Connection {} stats rtt_ms {}", ctx.pair, stats.rtt_ms);
-                    let metric = Metric::new(stats.rtt_ms as u16, vec![ctx.node], INIT_BW);
+                    let rtt_ms = stats.rtt_ms.min(MAX_LATENCY_MS as u32) as u16;
+                    if let Some((_, _, metric)) = self.conns.get(&ctx.conn) {
+                        if metric.latency.abs_diff(rtt_ms) <= self.rtt_threshold_ms {
+                            return;
+                        }
+                    }
+                    log::info!("[RouterSync] Connection {} latency changed to {rtt_ms} ms", ctx.pair);
+                    let metric = Metric::new(rtt_ms, vec![ctx.node], INIT_BW);
                     self.conns.insert(ctx.conn, (ctx.node, ctx.pair, metric.clone()));
                     self.router.set_direct(ctx.conn, metric);
+                    self.route_changes += 1;
                 }
-                ConnectionEvent::Disconnected(ctx) => {
-                    log::info!("[RouterSync] Connection {} disconnected", ctx.pair);
+                ConnectionEvent::Rekey(..) | ConnectionEvent::Mtu(..) | ConnectionEvent::ConnectRejected(..) => {}
+                ConnectionEvent::Migrated(ctx, old_pair) => {
+                    //workers already replaced the pair in their routers, only later deltas need the new one
+                    log::info!("[RouterSync] Connection {} migrated from {old_pair}", ctx.pair);
+                    if let Some((_, pair, _)) = self.conns.get_mut(&ctx.conn) {
+                        *pair = ctx.pair;
+                    }
+                }
+                ConnectionEvent::Disconnected(ctx, reason) => {
+                    log::info!("[RouterSync] Connection {} disconnected, reason {:?}", ctx.pair, reason);
                     self.conns.remove(&ctx.conn);
+                    self.delta_sent.remove(&ctx.conn);
+                    self.full_sync_conns.remove(&ctx.conn);
                     self.router.del_direct(ctx.conn);
+                    self.route_changes += 1;
                 }
             },
         }
     }
 
-    fn on_input(&mut self, _ctx: &FeatureContext, _now_ms: u64, input: FeatureInput<'_, UserData, Control, ToController>) {
+    fn on_input(&mut self, _ctx: &FeatureContext, now_ms: u64, input: FeatureInput<'_, UserData, Control, ToController>) {
         match input {
             FeatureInput::FromWorker(_) => {}
             FeatureInput::Control(actor, control) => match control {
                 Control::DumpRouter => {
                     self.queue.push_back(FeatureOutput::Event(actor, Event::DumpRouter(Box::new(self.router.dump()))));
                 }
+                Control::GetSyncInterval => {
+                    self.queue.push_back(FeatureOutput::Event(actor, Event::SyncInterval(self.interval_ms)));
+                }
+                Control::SetServiceLoad(service, weight) => {
+                    if self.local_services.contains(&service) {
+                        log::debug!("[RouterSync] set local service {service} load weight {weight}");
+                        self.service_loads.set_local(service, weight);
+                    } else {
+                        log::warn!("[RouterSync] reject load weight for service {service} which is not a local discoverable service");
+                    }
+                }
+                Control::FindService(service) => {
+                    self.queue.push_back(FeatureOutput::Event(actor, Event::ServiceNodes(service, self.find_service(service))));
+                }
+                Control::GetConvergence => {
+                    self.queue.push_back(FeatureOutput::Event(actor, Event::Convergence(self.convergence(now_ms))));
+                }
             },
             FeatureInput::Net(ctx, meta, buf) => {
                 if !meta.secure {
                     log::warn!("[RouterSync] reject unsecure message");
                     return;
                 }
-                if let Some((_node, _remote, metric)) = self.conns.get(&ctx.conn) {
-                    if let Ok(sync) = bincode::deserialize::<RouterSync>(&buf) {
-                        self.router.apply_sync(ctx.conn, metric.clone(), sync);
-                    } else {
-                        log::warn!("[RouterSync] Receive invalid sync from {}", ctx.pair);
+                if let Some((node, _remote, metric)) = self.conns.get(&ctx.conn) {
+                    match RouterSyncMsg::decode(&buf) {
+                        Some(RouterSyncMsg::Sync(SyncMsg(mode, sync))) => {
+                            if mode == self.router.compare_mode() {
+                                self.router.apply_sync(ctx.conn, metric.clone(), sync);
+                            } else {
+                                log::warn!("[RouterSync] Reject sync from {} with compare mode {:?}, local mode {:?}", ctx.pair, mode, self.router.compare_mode());
+                            }
+                        }
+                        Some(RouterSyncMsg::SyncDelta(mode, sync)) => {
+                            if mode != self.router.compare_mode() {
+                                log::warn!("[RouterSync] Reject sync from {} with compare mode {:?}, local mode {:?}", ctx.pair, mode, self.router.compare_mode());
+                            } else if !self.router.apply_sync_delta(ctx.conn, metric.clone(), sync) {
+                                log::info!("[RouterSync] Missed sync delta from {}, request full sync", ctx.pair);
+                                Self::send_to(&mut self.queue, ctx.conn, &RouterSyncMsg::RequestFullSync);
+                            }
+                        }
+                        Some(RouterSyncMsg::RequestFullSync) => {
+                            log::info!("[RouterSync] {} request full sync", ctx.pair);
+                            let node = *node;
+                            self.delta_sent.remove(&ctx.conn);
+                            self.send_sync_to(ctx.conn, node);
+                        }
+                        Some(RouterSyncMsg::ServiceLoads(loads)) => {
+                            if self.service_load_interval_ms.is_some() {
+                                self.service_loads.on_remote(now_ms, loads);
+                            }
+                        }
+                        None => {
+                            log::warn!("[RouterSync] Receive invalid sync from {}", ctx.pair);
+                        }
                     }
                 } else {
                     log::warn!("[RouterSync] Receive sync from unknown connection {}", ctx.pair);
                 }
             }
-            FeatureInput::Local(..) => {}
+            FeatureInput::Local(..) | FeatureInput::Undeliverable(..) | FeatureInput::DirectUndeliverable(..) => {}
         }
     }
 
@@ -151,9 +539,12 @@ impl<UserData> TaskSwitcherChild<Output<UserData>> for RouterSyncFeature<UserDat
         Output::OnResourceEmpty
     }
 
-    fn pop_output(&mut self, _now: u64) -> Option<Output<UserData>> {
+    fn pop_output(&mut self, now: u64) -> Option<Output<UserData>> {
         if let Some(rule) = self.router.pop_delta() {
             log::debug!("[RouterSync] broadcast to all workers {:?}", rule);
+            self.route_changes += 1;
+            self.generation += 1;
+            self.last_change_ms = now;
             let rule = match rule {
                 RouterDelta::Table(layer, TableDelta(index, DestDelta::SetBestPath(conn))) => ShadowRouterDelta::SetTable {
                     layer,
@@ -161,6 +552,16 @@ impl<UserData> TaskSwitcherChild<Output<UserData>> for RouterSyncFeature<UserDat
                     next: self.conns.get(&conn)?.1,
                 },
                 RouterDelta::Table(layer, TableDelta(index, DestDelta::DelBestPath)) => ShadowRouterDelta::DelTable { layer, index },
+                RouterDelta::Table(layer, TableDelta(index, DestDelta::SetEcmpPaths(conns))) => ShadowRouterDelta::SetTableMulti {
+                    layer,
+                    index,
+                    nexts: conns.iter().filter_map(|(conn, bandwidth)| self.conns.get(conn).map(|c| (c.1, *bandwidth))).collect(),
+                },
+                RouterDelta::Table(layer, TableDelta(index, DestDelta::SetAlternatePaths(conns))) => ShadowRouterDelta::SetTableAlternates {
+                    layer,
+                    index,
+                    nexts: conns.iter().filter_map(|conn| self.conns.get(conn).map(|c| c.1)).collect(),
+                },
                 RouterDelta::Registry(RegistryDelta::SetServiceLocal(service)) => ShadowRouterDelta::SetServiceLocal { service },
                 RouterDelta::Registry(RegistryDelta::DelServiceLocal(service)) => ShadowRouterDelta::DelServiceLocal { service },
                 RouterDelta::Registry(RegistryDelta::ServiceRemote(service, RegistryDestDelta::SetServicePath(conn, dest, score))) => {
@@ -234,7 +635,153 @@ impl<UserData> TaskSwitcherChild<WorkerOutput<UserData>> for RouterSyncFeatureWo
 
 #[cfg(test)]
 mod tests {
-    use atm0s_sdn_router::core::{Metric, RegistrySync, RouterSync, TableSync};
+    use atm0s_sdn_identity::ConnId;
+    use atm0s_sdn_router::core::{Metric, MetricCompareMode, RegistrySync, RouterSync, TableSync};
+
+    use crate::{
+        base::{
+            CipherSuite, ConnectionCtx, ConnectionEvent, ConnectionStats, Feature, FeatureContext, FeatureOutput, FeatureSharedInput, FeatureVersions, MockDecryptor, MockEncryptor, SecureContext,
+        },
+        data_plane::NetPair,
+    };
+
+    use super::{RouterSyncCfg, RouterSyncFeature, RouterSyncMsg, ServiceLoad, SyncMsg, FEATURE_ID, FEATURE_VERSION, INIT_BW, MAX_LOADS_PER_MSG, MSG_MARK, MSG_VERSION};
+
+    fn sample_sync() -> RouterSync {
+        let mut table_sync = [None, None, None, None];
+        table_sync[0] = Some(TableSync(vec![(2, Metric::new(10, vec![2, 1], 1000))]));
+        RouterSync(RegistrySync(vec![(1, Metric::new(20, vec![3, 2, 1], 1000))]), table_sync)
+    }
+
+    #[test]
+    fn default_mode_sync_should_be_bare() {
+        let buf = RouterSyncMsg::Sync(SyncMsg(MetricCompareMode::Score, sample_sync())).encode();
+        assert_eq!(buf, bincode::serialize(&sample_sync()).expect("Should serialize"));
+        match RouterSyncMsg::decode(&buf) {
+            Some(RouterSyncMsg::Sync(SyncMsg(mode, sync))) => {
+                assert_eq!(mode, MetricCompareMode::Score);
+                assert_eq!(sync, sample_sync());
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn other_mode_sync_should_be_tagged() {
+        let buf = RouterSyncMsg::Sync(SyncMsg(MetricCompareMode::HopsFirst, sample_sync())).encode();
+        assert_eq!(&buf[..2], &[MSG_MARK, MSG_VERSION]);
+        assert!(bincode::deserialize::<RouterSync>(&buf).is_err(), "older nodes should drop it");
+        match RouterSyncMsg::decode(&buf) {
+            Some(RouterSyncMsg::Sync(SyncMsg(mode, sync))) => {
+                assert_eq!(mode, MetricCompareMode::HopsFirst);
+                assert_eq!(sync, sample_sync());
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+
+        let mut future = buf.clone();
+        future[1] = MSG_VERSION + 1;
+        assert!(RouterSyncMsg::decode(&future).is_none());
+    }
+
+    fn connected(conn: &ConnectionCtx) -> FeatureSharedInput {
+        let secure = SecureContext {
+            cipher: CipherSuite::Aes256Gcm,
+            encryptor: Box::new(MockEncryptor::new()),
+            decryptor: Box::new(MockDecryptor::new()),
+            peer_identity: None,
+        };
+        FeatureSharedInput::Connection(ConnectionEvent::Connected(conn.clone(), secure))
+    }
+
+    fn sent_syncs(feature: &mut RouterSyncFeature<()>) -> Vec<(ConnId, RouterSyncMsg)> {
+        feature
+            .queue
+            .drain(..)
+            .filter_map(|out| match out {
+                FeatureOutput::SendDirect(conn, _, buf) => Some((conn, RouterSyncMsg::decode(&buf).expect("Should decode"))),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rtt_change_over_threshold_should_update_metric() {
+        let cfg = RouterSyncCfg {
+            rtt_threshold_ms: 10,
+            ..Default::default()
+        };
+        let mut feature = RouterSyncFeature::<()>::new(1, vec![], cfg);
+        let ctx = FeatureContext { node_id: 1, session: 0 };
+        let conn = ConnectionCtx {
+            conn: ConnId::from_out(0, 1000),
+            node: 2,
+            pair: NetPair::new_str("1.1.1.1:1000", "1.2.3.4:1000").expect("Should parse"),
+            features: Default::default(),
+            codec: Default::default(),
+            peer_identity: None,
+        };
+        let metric = Metric::new(100, vec![2], INIT_BW);
+        feature.conns.insert(conn.conn, (2, conn.pair, metric.clone()));
+        feature.router.set_direct(conn.conn, metric);
+        let latency = |feature: &RouterSyncFeature<()>| feature.router.table_snapshot(0).get(2).map(|(_, metric)| metric.latency);
+
+        //small jitter keeps the advertised latency
+        feature.on_shared_input(&ctx, 0, FeatureSharedInput::Connection(ConnectionEvent::Stats(conn.clone(), ConnectionStats { rtt_ms: 108 })));
+        assert_eq!(latency(&feature), Some(100));
+        assert_eq!(feature.route_changes, 0);
+
+        feature.on_shared_input(&ctx, 0, FeatureSharedInput::Connection(ConnectionEvent::Stats(conn.clone(), ConnectionStats { rtt_ms: 150 })));
+        assert_eq!(latency(&feature), Some(150));
+        assert_eq!(feature.route_changes, 1);
+
+        //threshold is relative to the last applied rtt
+        feature.on_shared_input(&ctx, 0, FeatureSharedInput::Connection(ConnectionEvent::Stats(conn.clone(), ConnectionStats { rtt_ms: 141 })));
+        assert_eq!(latency(&feature), Some(150));
+        feature.on_shared_input(&ctx, 0, FeatureSharedInput::Connection(ConnectionEvent::Stats(conn, ConnectionStats { rtt_ms: 20 })));
+        assert_eq!(latency(&feature), Some(20));
+        assert_eq!(feature.route_changes, 2);
+    }
+
+    #[test]
+    fn delta_sync_should_fallback_to_full_for_old_peers() {
+        let cfg = RouterSyncCfg {
+            delta_sync_full_every: Some(10),
+            ..Default::default()
+        };
+        let mut feature = RouterSyncFeature::<()>::new(1, vec![], cfg);
+        let ctx = FeatureContext { node_id: 1, session: 0 };
+        let old = ConnectionCtx {
+            conn: ConnId::from_out(0, 1000),
+            node: 2,
+            pair: NetPair::new_str("1.1.1.1:1000", "1.2.3.4:1000").expect("Should parse"),
+            features: FeatureVersions::default(),
+            codec: Default::default(),
+            peer_identity: None,
+        };
+        let new = ConnectionCtx {
+            conn: ConnId::from_out(0, 1001),
+            node: 3,
+            pair: NetPair::new_str("1.1.1.1:1000", "1.2.3.5:1000").expect("Should parse"),
+            features: FeatureVersions::new([(FEATURE_ID, FEATURE_VERSION)]),
+            codec: Default::default(),
+            peer_identity: None,
+        };
+        feature.on_shared_input(&ctx, 0, connected(&old));
+        feature.on_shared_input(&ctx, 0, connected(&new));
+        feature.send_sync_to(old.conn, old.node);
+        feature.send_sync_to(new.conn, new.node);
+
+        let syncs = sent_syncs(&mut feature);
+        assert_eq!(syncs.len(), 4);
+        for (conn, msg) in syncs {
+            if conn == old.conn {
+                assert!(matches!(msg, RouterSyncMsg::Sync(..)));
+            } else {
+                assert!(matches!(msg, RouterSyncMsg::SyncDelta(..)));
+            }
+        }
+    }
 
     #[test]
     fn router_sync_should_fit_udp() {
@@ -258,8 +805,25 @@ mod tests {
             *i = Some(table);
         }
 
-        let sync = RouterSync(service_sync, table_sync);
-        let sync_msg_len = bincode::serialize(&sync).expect("").len();
+        let sync = RouterSyncMsg::Sync(SyncMsg(MetricCompareMode::LatencyFirst, RouterSync(service_sync, table_sync)));
+        let sync_msg_len = sync.encode().len();
         assert!(sync_msg_len <= MAX_SIZE, "SYNC msg not fit in UDP {} vs {}", sync_msg_len, MAX_SIZE);
     }
+
+    #[test]
+    fn service_loads_should_fit_udp() {
+        const MAX_SIZE: usize = 1200;
+        let loads = vec![
+            ServiceLoad {
+                service: u8::MAX,
+                node: u32::MAX,
+                weight: u32::MAX,
+                seq: u64::MAX,
+                ttl_ms: u32::MAX,
+            };
+            MAX_LOADS_PER_MSG
+        ];
+        let msg_len = RouterSyncMsg::ServiceLoads(loads).encode().len();
+        assert!(msg_len <= MAX_SIZE, "ServiceLoads msg not fit in UDP {} vs {}", msg_len, MAX_SIZE);
+    }
 }
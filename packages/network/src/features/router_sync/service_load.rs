@@ -0,0 +1,286 @@
+//! Load weights of service instances, gossiped between neighbours together with router sync.
+//!
+//! The registry in router only keeps the best path for each service, so it can't tell which nodes run a service.
+//! Each node advertises a weight for its local services with an increasing seq, and relays weights it learned to all neighbours.
+//! Only entries with a newer seq are accepted, so relayed copies which loop back are ignored.
+//! Each round only carries changed weights, local weights are refreshed with a new seq every few rounds and a new neighbour gets the whole table once.
+//! The seq follows the node clock, so a restarted node still advertises above the seq of its previous run.
+//! Relayed entries carry their remaining lifetime, so a weight of a gone node expires at the same time in whole network
+//! instead of being learned again from neighbours which still have it.
+
+use std::collections::{HashMap, HashSet};
+
+use atm0s_sdn_identity::NodeId;
+use serde::{Deserialize, Serialize};
+
+/// Max entries in a single message, for fitting inside one UDP packet
+pub const MAX_LOADS_PER_MSG: usize = 48;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ServiceLoad {
+    pub service: u8,
+    pub node: NodeId,
+    /// Lower is less loaded
+    pub weight: u32,
+    pub seq: u64,
+    /// Remaining lifetime of this entry
+    pub ttl_ms: u32,
+}
+
+struct RemoteLoad {
+    weight: u32,
+    seq: u64,
+    expires_at: u64,
+}
+
+pub struct ServiceLoads {
+    node: NodeId,
+    seq: u64,
+    locals: HashMap<u8, u32>,
+    remotes: HashMap<(u8, NodeId), RemoteLoad>,
+    /// Local services whose weight changed since the last round
+    changed_locals: HashSet<u8>,
+    /// Remote entries accepted since the last round, they are relayed once
+    changed_remotes: HashSet<(u8, NodeId)>,
+}
+
+impl ServiceLoads {
+    pub fn new(node: NodeId) -> Self {
+        Self {
+            node,
+            seq: 0,
+            locals: HashMap::new(),
+            remotes: HashMap::new(),
+            changed_locals: HashSet::new(),
+            changed_remotes: HashSet::new(),
+        }
+    }
+
+    pub fn set_local(&mut self, service: u8, weight: u32) {
+        if self.locals.insert(service, weight) != Some(weight) {
+            self.changed_locals.insert(service);
+        }
+    }
+
+    pub fn remove_local(&mut self, service: u8) {
+        self.locals.remove(&service);
+    }
+
+    pub fn on_remote(&mut self, now: u64, loads: Vec<ServiceLoad>) {
+        for load in loads {
+            if load.node == self.node {
+                continue;
+            }
+            let key = (load.service, load.node);
+            if matches!(self.remotes.get(&key), Some(current) if current.seq >= load.seq) {
+                continue;
+            }
+            self.changed_remotes.insert(key);
+            self.remotes.insert(
+                key,
+                RemoteLoad {
+                    weight: load.weight,
+                    seq: load.seq,
+                    expires_at: now + load.ttl_ms as u64,
+                },
+            );
+        }
+    }
+
+    /// Remove weights which are not refreshed by their node, the node or its service is gone
+    pub fn on_tick(&mut self, now: u64) {
+        self.remotes.retain(|(service, node), load| {
+            let keep = now < load.expires_at;
+            if !keep {
+                log::info!("[ServiceLoads] service {service} weight from node {node} timeout");
+            }
+            keep
+        });
+    }
+
+    /// Changed entries for the next advertise round. Changed local weights get a new seq and full `ttl_ms`,
+    /// with `refresh` all local weights get it, so they don't expire in other nodes
+    pub fn advertise(&mut self, now: u64, ttl_ms: u32, refresh: bool) -> Vec<ServiceLoad> {
+        if refresh {
+            self.changed_locals.extend(self.locals.keys());
+        }
+        if !self.changed_locals.is_empty() {
+            self.seq = (self.seq + 1).max(now);
+        }
+        let locals = self.changed_locals.drain().filter_map(|service| {
+            self.locals.get(&service).map(|weight| ServiceLoad {
+                service,
+                node: self.node,
+                weight: *weight,
+                seq: self.seq,
+                ttl_ms,
+            })
+        });
+        let remotes = self.changed_remotes.drain().filter_map(|key| self.remotes.get(&key).map(|load| Self::remote_entry(key, load, now)));
+        locals.chain(remotes).collect()
+    }
+
+    /// All entries for a new neighbour, local weights keep their last seq
+    pub fn snapshot(&self, now: u64, ttl_ms: u32) -> Vec<ServiceLoad> {
+        let locals = self.locals.iter().map(|(service, weight)| ServiceLoad {
+            service: *service,
+            node: self.node,
+            weight: *weight,
+            seq: self.seq,
+            ttl_ms,
+        });
+        let remotes = self.remotes.iter().map(|(key, load)| Self::remote_entry(*key, load, now));
+        locals.chain(remotes).collect()
+    }
+
+    fn remote_entry((service, node): (u8, NodeId), load: &RemoteLoad, now: u64) -> ServiceLoad {
+        ServiceLoad {
+            service,
+            node,
+            weight: load.weight,
+            seq: load.seq,
+            ttl_ms: load.expires_at.saturating_sub(now) as u32,
+        }
+    }
+
+    /// Nodes which run the service with their weight, including the local node, sorted by node id
+    pub fn find(&self, service: u8) -> Vec<(NodeId, u32)> {
+        let mut nodes: Vec<_> = self.remotes.iter().filter(|((s, _), _)| *s == service).map(|((_, node), load)| (*node, load.weight)).collect();
+        if let Some(weight) = self.locals.get(&service) {
+            nodes.push((self.node, *weight));
+        }
+        nodes.sort();
+        nodes
+    }
+}
+
+/// Pick a node with probability proportional to `1 / (weight + 1)`, `random` is a uniform random value
+pub fn pick_by_inverse_load(nodes: &[(NodeId, u32)], random: u64) -> Option<NodeId> {
+    let total: f64 = nodes.iter().map(|(_, weight)| 1.0 / (*weight as f64 + 1.0)).sum();
+    let mut point = random as f64 / u64::MAX as f64 * total;
+    for (node, weight) in nodes {
+        point -= 1.0 / (*weight as f64 + 1.0);
+        if point < 0.0 {
+            return Some(*node);
+        }
+    }
+    nodes.last().map(|(node, _)| *node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pick_by_inverse_load, ServiceLoad, ServiceLoads};
+
+    fn load(node: u32, weight: u32, seq: u64) -> ServiceLoad {
+        ServiceLoad {
+            service: 1,
+            node,
+            weight,
+            seq,
+            ttl_ms: 1000,
+        }
+    }
+
+    #[test]
+    fn keep_newer_seq_only() {
+        let mut loads = ServiceLoads::new(1);
+        loads.set_local(1, 5);
+        loads.on_remote(0, vec![load(2, 10, 2)]);
+        //older seq relayed back by another neighbour
+        loads.on_remote(0, vec![load(2, 20, 1)]);
+        //own entry relayed back
+        loads.on_remote(0, vec![load(1, 30, 100)]);
+        assert_eq!(loads.find(1), vec![(1, 5), (2, 10)]);
+        assert_eq!(loads.find(2), vec![]);
+
+        loads.on_remote(0, vec![load(2, 20, 3)]);
+        assert_eq!(loads.find(1), vec![(1, 5), (2, 20)]);
+    }
+
+    #[test]
+    fn advertise_bump_local_seq() {
+        let mut loads = ServiceLoads::new(1);
+        loads.set_local(1, 5);
+        loads.on_remote(0, vec![load(2, 10, 7)]);
+        let mut adv = loads.advertise(0, 1000, false);
+        adv.sort_by_key(|l| l.node);
+        assert_eq!(adv, vec![load(1, 5, 1), load(2, 10, 7)]);
+        assert_eq!(loads.advertise(0, 1000, true), vec![load(1, 5, 2)]);
+    }
+
+    #[test]
+    fn advertise_only_changes() {
+        let mut loads = ServiceLoads::new(1);
+        loads.set_local(1, 5);
+        loads.on_remote(0, vec![load(2, 10, 7)]);
+        assert_eq!(loads.advertise(0, 1000, false).len(), 2);
+        assert_eq!(loads.advertise(0, 1000, false), vec![]);
+
+        //same weight and older seq are not changes
+        loads.set_local(1, 5);
+        loads.on_remote(0, vec![load(2, 20, 6)]);
+        assert_eq!(loads.advertise(0, 1000, false), vec![]);
+
+        loads.set_local(1, 6);
+        assert_eq!(loads.advertise(0, 1000, false), vec![load(1, 6, 2)]);
+
+        //a new neighbour still gets every entry
+        let mut snapshot = loads.snapshot(0, 1000);
+        snapshot.sort_by_key(|l| l.node);
+        assert_eq!(snapshot, vec![load(1, 6, 2), load(2, 10, 7)]);
+    }
+
+    #[test]
+    fn seq_should_follow_clock() {
+        let mut loads = ServiceLoads::new(1);
+        loads.set_local(1, 5);
+        assert_eq!(loads.advertise(5000, 1000, false), vec![load(1, 5, 5000)]);
+        assert_eq!(loads.advertise(5000, 1000, true), vec![load(1, 5, 5001)]);
+
+        //restarted node starts from its clock, above the seq of the previous run
+        let mut other = ServiceLoads::new(2);
+        other.on_remote(0, vec![load(1, 5, 5001)]);
+        let mut restarted = ServiceLoads::new(1);
+        restarted.set_local(1, 7);
+        other.on_remote(0, restarted.advertise(6000, 1000, false));
+        assert_eq!(other.find(1), vec![(1, 7)]);
+    }
+
+    #[test]
+    fn expire_not_refreshed() {
+        let mut loads = ServiceLoads::new(1);
+        loads.on_remote(0, vec![load(2, 10, 1)]);
+        loads.on_remote(500, vec![load(3, 10, 1)]);
+        loads.on_tick(1000);
+        assert_eq!(loads.find(1), vec![(3, 10)]);
+    }
+
+    #[test]
+    fn relay_remaining_ttl() {
+        let mut loads = ServiceLoads::new(1);
+        loads.on_remote(0, vec![load(2, 10, 1)]);
+        assert_eq!(loads.advertise(400, 1000, false), vec![ServiceLoad { ttl_ms: 600, ..load(2, 10, 1) }]);
+
+        //a neighbour learned it again from our relay, it must not outlive the original
+        let mut other = ServiceLoads::new(3);
+        other.on_remote(400, loads.snapshot(400, 1000));
+        other.on_tick(1000);
+        assert_eq!(other.find(1), vec![]);
+    }
+
+    #[test]
+    fn pick_prefer_less_loaded() {
+        let nodes = [(1, 0), (2, 9)];
+        assert_eq!(pick_by_inverse_load(&[], 0), None);
+        assert_eq!(pick_by_inverse_load(&nodes, 0), Some(1));
+        assert_eq!(pick_by_inverse_load(&nodes, u64::MAX), Some(2));
+
+        let mut picked = [0; 2];
+        for i in 0..100u64 {
+            let node = pick_by_inverse_load(&nodes, i * (u64::MAX / 100)).expect("Should pick");
+            picked[node as usize - 1] += 1;
+        }
+        //weight 0 vs 9 is 10 times more likely
+        assert!(picked[0] > 85 && picked[1] > 5, "{picked:?}");
+    }
+}
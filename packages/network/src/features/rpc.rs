@@ -0,0 +1,434 @@
+//! Request/response between nodes over any route rule.
+//!
+//! A node handles a method after `Control::Listen`, each incoming call is delivered to the listener as `Event::Request`
+//! and must be answered with `Control::Answer`. Calls which are not answered are resent until `retries` is used up,
+//! the callee keeps its answers for a while so a resent call is answered again without reaching the handler twice.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+};
+
+use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_router::RouteRule;
+use derivative::Derivative;
+use sans_io_runtime::{collections::DynamicDeque, TaskSwitcherChild};
+use serde::{Deserialize, Serialize};
+
+use crate::base::{
+    Feature, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerInput, FeatureWorkerOutput, NetOutgoingMeta, PendingRequests,
+    RemoteReply, RemoteRequest,
+};
+
+pub const FEATURE_ID: u8 = 8;
+pub const FEATURE_NAME: &str = "rpc";
+/// How long the callee keeps answers for resent calls
+pub const ANSWER_CACHE_MS: u64 = 30_000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RpcError {
+    /// No answer within the call timeout, including all retries
+    Timeout,
+    /// Nothing listens to the method at the destination
+    NoHandler,
+    /// Error answered by the handler
+    Remote(String),
+}
+
+/// Identify an incoming call, it must be given back in `Control::Answer`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RpcReqId {
+    pub from: NodeId,
+    pub id: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Control {
+    Listen(u16),
+    Unlisten(u16),
+    /// `id` is only for the caller and is returned in the response. The call is resent `retries` times
+    /// if not answered, with `timeout_ms` shared between all attempts
+    Call {
+        id: u64,
+        dest: RouteRule,
+        method: u16,
+        payload: Vec<u8>,
+        timeout_ms: u64,
+        retries: u8,
+    },
+    Answer(RpcReqId, Result<Vec<u8>, String>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Request(RpcReqId, u16, Vec<u8>),
+    Response(u64, Result<Vec<u8>, RpcError>),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ToWorker;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ToController;
+
+#[derive(Debug, Serialize, Deserialize)]
+enum RpcMsg {
+    Request(RemoteRequest<(u16, Vec<u8>)>),
+    Response(RemoteReply<Result<Vec<u8>, RpcError>>),
+}
+
+struct Call {
+    id: u64,
+    dest: RouteRule,
+    method: u16,
+    payload: Vec<u8>,
+    resend_ms: u64,
+    next_resend_ms: u64,
+    retries: u8,
+}
+
+struct Answer {
+    ts: u64,
+    /// None while the handler has not answered yet
+    result: Option<Result<Vec<u8>, RpcError>>,
+}
+
+pub type Output<UserData> = FeatureOutput<UserData, Event, ToWorker>;
+pub type WorkerOutput<UserData> = FeatureWorkerOutput<UserData, Control, Event, ToController>;
+
+pub struct RpcFeature<UserData> {
+    listeners: HashMap<u16, FeatureControlActor<UserData>>,
+    calls: PendingRequests<UserData, Call>,
+    answers: HashMap<RpcReqId, Answer>,
+    queue: VecDeque<Output<UserData>>,
+    shutdown: bool,
+}
+
+impl<UserData> Default for RpcFeature<UserData> {
+    fn default() -> Self {
+        Self {
+            listeners: HashMap::new(),
+            //each call has its own timeout
+            calls: PendingRequests::new(0),
+            answers: HashMap::new(),
+            queue: VecDeque::new(),
+            shutdown: false,
+        }
+    }
+}
+
+impl<UserData: Copy> RpcFeature<UserData> {
+    fn send(queue: &mut VecDeque<Output<UserData>>, rule: RouteRule, msg: &RpcMsg) {
+        let buf = bincode::serialize(msg).expect("should work");
+        queue.push_back(FeatureOutput::SendRoute(rule, NetOutgoingMeta::default(), buf.into()));
+    }
+
+    fn send_call(queue: &mut VecDeque<Output<UserData>>, node: NodeId, req: u64, call: &Call) {
+        let msg = RpcMsg::Request(RemoteRequest {
+            id: req,
+            from: node,
+            body: (call.method, call.payload.clone()),
+        });
+        Self::send(queue, call.dest.clone(), &msg);
+    }
+
+    fn answer(&mut self, req: RpcReqId, result: Result<Vec<u8>, RpcError>) {
+        let msg = RpcMsg::Response(RemoteReply { id: req.id, body: result });
+        Self::send(&mut self.queue, RouteRule::ToNode(req.from), &msg);
+    }
+
+    fn on_request(&mut self, now_ms: u64, req: RemoteRequest<(u16, Vec<u8>)>) {
+        let req_id = RpcReqId { from: req.from, id: req.id };
+        if let Some(answer) = self.answers.get(&req_id) {
+            log::debug!("[RpcFeature] resent call {:?}", req_id);
+            if let Some(result) = answer.result.clone() {
+                self.answer(req_id, result);
+            }
+            return;
+        }
+        let (method, payload) = req.body;
+        if let Some(actor) = self.listeners.get(&method) {
+            self.answers.insert(req_id, Answer { ts: now_ms, result: None });
+            self.queue.push_back(FeatureOutput::Event(*actor, Event::Request(req_id, method, payload)));
+        } else {
+            log::warn!("[RpcFeature] call to method {method} without handler from {}", req.from);
+            self.answer(req_id, Err(RpcError::NoHandler));
+        }
+    }
+
+    fn on_response(&mut self, reply: RemoteReply<Result<Vec<u8>, RpcError>>) {
+        if let Some((actor, call, _)) = self.calls.take(reply.id) {
+            self.queue.push_back(FeatureOutput::Event(actor, Event::Response(call.id, reply.body)));
+        } else {
+            log::debug!("[RpcFeature] response with unknown or timeout id {}", reply.id);
+        }
+    }
+}
+
+impl<UserData: Copy> Feature<UserData, Control, Event, ToController, ToWorker> for RpcFeature<UserData> {
+    fn on_shared_input(&mut self, ctx: &FeatureContext, now: u64, input: FeatureSharedInput) {
+        if let FeatureSharedInput::Tick(_) = input {
+            for (actor, call) in self.calls.pop_timeouts(now) {
+                self.queue.push_back(FeatureOutput::Event(actor, Event::Response(call.id, Err(RpcError::Timeout))));
+            }
+            for (req, call) in self.calls.iter_mut() {
+                if call.retries > 0 && now >= call.next_resend_ms {
+                    call.retries -= 1;
+                    call.next_resend_ms = now + call.resend_ms;
+                    Self::send_call(&mut self.queue, ctx.node_id, req, call);
+                }
+            }
+            self.answers.retain(|_, answer| now < answer.ts + ANSWER_CACHE_MS);
+        }
+    }
+
+    fn on_input(&mut self, ctx: &FeatureContext, now_ms: u64, input: FeatureInput<'_, UserData, Control, ToController>) {
+        match input {
+            FeatureInput::Control(actor, control) => match control {
+                Control::Listen(method) => {
+                    self.listeners.insert(method, actor);
+                }
+                Control::Unlisten(method) => {
+                    self.listeners.remove(&method);
+                }
+                Control::Call {
+                    id,
+                    dest,
+                    method,
+                    payload,
+                    timeout_ms,
+                    retries,
+                } => {
+                    let resend_ms = timeout_ms / (retries as u64 + 1);
+                    let call = Call {
+                        id,
+                        dest,
+                        method,
+                        payload,
+                        resend_ms,
+                        next_resend_ms: now_ms + resend_ms,
+                        retries,
+                    };
+                    Self::send_call(&mut self.queue, ctx.node_id, self.calls.next_id(), &call);
+                    self.calls.add_with_timeout(now_ms, timeout_ms, actor, call);
+                }
+                Control::Answer(req, result) => {
+                    if let Some(answer) = self.answers.get_mut(&req) {
+                        let result = result.map_err(RpcError::Remote);
+                        answer.result = Some(result.clone());
+                        self.answer(req, result);
+                    } else {
+                        log::warn!("[RpcFeature] answer for unknown call {:?}", req);
+                    }
+                }
+            },
+            FeatureInput::Net(_, _, buf) | FeatureInput::Local(_, buf) => match bincode::deserialize::<RpcMsg>(&buf) {
+                Ok(RpcMsg::Request(req)) => self.on_request(now_ms, req),
+                Ok(RpcMsg::Response(reply)) => self.on_response(reply),
+                Err(_) => log::warn!("[RpcFeature] invalid message"),
+            },
+            //calls are resent until their timeout, the route can come back in between
+            FeatureInput::FromWorker(_) | FeatureInput::Undeliverable(..) | FeatureInput::DirectUndeliverable(..) => {}
+        }
+    }
+
+    fn on_shutdown(&mut self, _ctx: &FeatureContext, _now: u64) {
+        log::info!("[RpcFeature] Shutdown");
+        self.shutdown = true;
+    }
+}
+
+impl<UserData> TaskSwitcherChild<Output<UserData>> for RpcFeature<UserData> {
+    type Time = u64;
+
+    fn is_empty(&self) -> bool {
+        self.shutdown && self.queue.is_empty()
+    }
+
+    fn empty_event(&self) -> Output<UserData> {
+        Output::OnResourceEmpty
+    }
+
+    fn pop_output(&mut self, _now: u64) -> Option<Output<UserData>> {
+        self.queue.pop_front()
+    }
+}
+
+#[derive(Derivative)]
+#[derivative(Default(bound = ""))]
+pub struct RpcFeatureWorker<UserData> {
+    queue: DynamicDeque<WorkerOutput<UserData>, 1>,
+    shutdown: bool,
+}
+
+impl<UserData: Debug> FeatureWorker<UserData, Control, Event, ToController, ToWorker> for RpcFeatureWorker<UserData> {
+    fn on_input(&mut self, _ctx: &mut crate::base::FeatureWorkerContext, _now: u64, input: FeatureWorkerInput<UserData, Control, ToWorker>) {
+        match input {
+            FeatureWorkerInput::Control(actor, control) => self.queue.push_back(FeatureWorkerOutput::ForwardControlToController(actor, control)),
+            FeatureWorkerInput::Network(conn, header, buf) => self.queue.push_back(FeatureWorkerOutput::ForwardNetworkToController(conn, header, buf)),
+            FeatureWorkerInput::Local(header, buf) => self.queue.push_back(FeatureWorkerOutput::ForwardLocalToController(header, buf)),
+            FeatureWorkerInput::FromController(..) => {
+                log::warn!("No handler for FromController in {}", FEATURE_NAME);
+            }
+            #[cfg(feature = "vpn")]
+            FeatureWorkerInput::TunPkt(..) => {}
+        }
+    }
+
+    fn on_shutdown(&mut self, _ctx: &mut crate::base::FeatureWorkerContext, _now: u64) {
+        log::info!("[RpcFeatureWorker] Shutdown");
+        self.shutdown = true;
+    }
+}
+
+impl<UserData> TaskSwitcherChild<WorkerOutput<UserData>> for RpcFeatureWorker<UserData> {
+    type Time = u64;
+
+    fn is_empty(&self) -> bool {
+        self.shutdown && self.queue.is_empty()
+    }
+
+    fn empty_event(&self) -> WorkerOutput<UserData> {
+        WorkerOutput::OnResourceEmpty
+    }
+
+    fn pop_output(&mut self, _now: u64) -> Option<WorkerOutput<UserData>> {
+        self.queue.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use atm0s_sdn_router::RouteRule;
+    use sans_io_runtime::TaskSwitcherChild;
+
+    use crate::base::{Feature, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput, NetIncomingMeta};
+
+    use super::{Control, Event, RpcError, RpcFeature, RpcMsg, RpcReqId, ToWorker, ANSWER_CACHE_MS};
+
+    type Out = Option<FeatureOutput<(), Event, ToWorker>>;
+
+    fn pop_msg(out: Out) -> (RouteRule, Vec<u8>) {
+        match out {
+            Some(FeatureOutput::SendRoute(rule, _, buf)) => (rule, buf.to_vec()),
+            out => panic!("Should be SendRoute {out:?}"),
+        }
+    }
+
+    fn on_msg(rpc: &mut RpcFeature<()>, ctx: &FeatureContext, now: u64, buf: Vec<u8>) {
+        rpc.on_input(ctx, now, FeatureInput::Local(NetIncomingMeta::default(), buf.into()));
+    }
+
+    fn call(id: u64, dest: u32, retries: u8) -> Control {
+        Control::Call {
+            id,
+            dest: RouteRule::ToNode(dest),
+            method: 1,
+            payload: vec![1, 2, 3],
+            timeout_ms: 3000,
+            retries,
+        }
+    }
+
+    #[test]
+    fn call_and_answer() {
+        let ctx1 = FeatureContext { node_id: 1, session: 0 };
+        let ctx2 = FeatureContext { node_id: 2, session: 0 };
+        let (mut node1, mut node2) = (RpcFeature::<()>::default(), RpcFeature::<()>::default());
+        node2.on_input(&ctx2, 0, FeatureInput::Control(FeatureControlActor::Controller(()), Control::Listen(1)));
+
+        node1.on_input(&ctx1, 0, FeatureInput::Control(FeatureControlActor::Worker(1, ()), call(100, 2, 0)));
+        let (rule, req) = pop_msg(node1.pop_output(0));
+        assert_eq!(rule, RouteRule::ToNode(2));
+
+        on_msg(&mut node2, &ctx2, 10, req);
+        let req_id = RpcReqId { from: 1, id: 0 };
+        assert_eq!(
+            node2.pop_output(10),
+            Some(FeatureOutput::Event(FeatureControlActor::Controller(()), Event::Request(req_id, 1, vec![1, 2, 3])))
+        );
+        node2.on_input(&ctx2, 10, FeatureInput::Control(FeatureControlActor::Controller(()), Control::Answer(req_id, Ok(vec![4]))));
+        let (rule, res) = pop_msg(node2.pop_output(10));
+        assert_eq!(rule, RouteRule::ToNode(1));
+
+        on_msg(&mut node1, &ctx1, 20, res);
+        assert_eq!(node1.pop_output(20), Some(FeatureOutput::Event(FeatureControlActor::Worker(1, ()), Event::Response(100, Ok(vec![4])))));
+    }
+
+    #[test]
+    fn resend_answered_once() {
+        let ctx1 = FeatureContext { node_id: 1, session: 0 };
+        let ctx2 = FeatureContext { node_id: 2, session: 0 };
+        let (mut node1, mut node2) = (RpcFeature::<()>::default(), RpcFeature::<()>::default());
+        node2.on_input(&ctx2, 0, FeatureInput::Control(FeatureControlActor::Controller(()), Control::Listen(1)));
+
+        node1.on_input(&ctx1, 0, FeatureInput::Control(FeatureControlActor::Controller(()), call(100, 2, 2)));
+        let (_, req) = pop_msg(node1.pop_output(0));
+        //resent each 1000ms
+        node1.on_shared_input(&ctx1, 999, FeatureSharedInput::Tick(1));
+        assert_eq!(node1.pop_output(999), None);
+        node1.on_shared_input(&ctx1, 1000, FeatureSharedInput::Tick(2));
+        let (_, resent) = pop_msg(node1.pop_output(1000));
+        assert_eq!(resent, req);
+
+        on_msg(&mut node2, &ctx2, 1000, req);
+        assert!(matches!(node2.pop_output(1000), Some(FeatureOutput::Event(_, Event::Request(..)))));
+        //resent before the handler answered is dropped
+        on_msg(&mut node2, &ctx2, 1000, resent.clone());
+        assert_eq!(node2.pop_output(1000), None);
+
+        node2.on_input(
+            &ctx2,
+            1000,
+            FeatureInput::Control(FeatureControlActor::Controller(()), Control::Answer(RpcReqId { from: 1, id: 0 }, Err("busy".to_string()))),
+        );
+        let (_, res) = pop_msg(node2.pop_output(1000));
+        //resent after answered gets the same answer without reaching the handler
+        on_msg(&mut node2, &ctx2, 1500, resent.clone());
+        assert_eq!(pop_msg(node2.pop_output(1500)).1, res);
+
+        on_msg(&mut node1, &ctx1, 1500, res.clone());
+        assert_eq!(
+            node1.pop_output(1500),
+            Some(FeatureOutput::Event(
+                FeatureControlActor::Controller(()),
+                Event::Response(100, Err(RpcError::Remote("busy".to_string())))
+            ))
+        );
+        //duplicated response is ignored
+        on_msg(&mut node1, &ctx1, 1500, res);
+        assert_eq!(node1.pop_output(1500), None);
+
+        //answer cache expired, so it is a new call for the handler
+        node2.on_shared_input(&ctx2, 1000 + ANSWER_CACHE_MS, FeatureSharedInput::Tick(3));
+        on_msg(&mut node2, &ctx2, 1000 + ANSWER_CACHE_MS, resent);
+        assert!(matches!(node2.pop_output(0), Some(FeatureOutput::Event(_, Event::Request(..)))));
+    }
+
+    #[test]
+    fn no_handler_and_timeout() {
+        let ctx1 = FeatureContext { node_id: 1, session: 0 };
+        let ctx2 = FeatureContext { node_id: 2, session: 0 };
+        let (mut node1, mut node2) = (RpcFeature::<()>::default(), RpcFeature::<()>::default());
+
+        node1.on_input(&ctx1, 0, FeatureInput::Control(FeatureControlActor::Controller(()), call(100, 2, 0)));
+        let (_, req) = pop_msg(node1.pop_output(0));
+        on_msg(&mut node2, &ctx2, 0, req);
+        let (_, res) = pop_msg(node2.pop_output(0));
+        assert!(matches!(bincode::deserialize::<RpcMsg>(&res), Ok(RpcMsg::Response(reply)) if reply.body == Err(RpcError::NoHandler)));
+        on_msg(&mut node1, &ctx1, 0, res);
+        assert_eq!(
+            node1.pop_output(0),
+            Some(FeatureOutput::Event(FeatureControlActor::Controller(()), Event::Response(100, Err(RpcError::NoHandler))))
+        );
+
+        node1.on_input(&ctx1, 0, FeatureInput::Control(FeatureControlActor::Controller(()), call(101, 3, 0)));
+        pop_msg(node1.pop_output(0));
+        node1.on_shared_input(&ctx1, 3000, FeatureSharedInput::Tick(1));
+        assert_eq!(
+            node1.pop_output(3000),
+            Some(FeatureOutput::Event(FeatureControlActor::Controller(()), Event::Response(101, Err(RpcError::Timeout))))
+        );
+        assert_eq!(node1.pop_output(3000), None);
+    }
+}
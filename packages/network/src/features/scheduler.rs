@@ -0,0 +1,149 @@
+//! Weighted order of popping feature outputs.
+//!
+//! A `TaskSwitcher` always serves its lowest flagged slot until that branch has no more output. Features get their
+//! slot by weight, so higher weights go first, and each one pops at most `weight` outputs per turn. A feature which
+//! used its turn is unflagged until all other flagged features had their turn, then the next turn starts, which
+//! keeps a busy high weight feature from starving the others.
+
+use std::collections::HashMap;
+
+use sans_io_runtime::TaskSwitcher;
+
+use super::{FeaturePriority, Features};
+
+pub const FEATURES_COUNT: usize = 10;
+/// Weight of control priority features which have no configured weight, bulk features use 1
+pub const DEFAULT_CONTROL_WEIGHT: u8 = 8;
+
+const ALL_FEATURES: [Features; FEATURES_COUNT] = [
+    Features::Neighbours,
+    Features::Data,
+    Features::RouterSync,
+    Features::Vpn,
+    Features::DhtKv,
+    Features::PubSub,
+    Features::Alias,
+    Features::Socket,
+    Features::Rpc,
+    Features::HolePunch,
+];
+
+pub struct FeatureScheduler {
+    /// Feature of each switcher slot
+    order: [Features; FEATURES_COUNT],
+    /// Weight of each switcher slot
+    weights: [u8; FEATURES_COUNT],
+    /// Slot which is in its turn and how many outputs it popped
+    turn: Option<(usize, u8)>,
+    deferred: Vec<usize>,
+}
+
+impl FeatureScheduler {
+    /// Features which are missing in `weights` use DEFAULT_CONTROL_WEIGHT for control priority and 1 for bulk
+    pub fn new(weights: &HashMap<Features, u8>) -> Self {
+        let weight_of = |feature: &Features| {
+            let default = match feature.priority() {
+                FeaturePriority::Control => DEFAULT_CONTROL_WEIGHT,
+                FeaturePriority::Bulk => 1,
+            };
+            weights.get(feature).copied().unwrap_or(default).max(1)
+        };
+        let mut order = ALL_FEATURES;
+        //stable sort, same weights keep the feature id order
+        order.sort_by_key(|feature| std::cmp::Reverse(weight_of(feature)));
+        Self {
+            order,
+            weights: order.map(|feature| weight_of(&feature)),
+            turn: None,
+            deferred: Vec::with_capacity(FEATURES_COUNT),
+        }
+    }
+
+    /// Switcher slot of the feature branch
+    pub fn slot(&self, feature: Features) -> usize {
+        self.order.iter().position(|f| *f == feature).expect("Should have all features")
+    }
+
+    /// Feature which should pop the next output, None if no feature is flagged
+    pub fn current(&mut self, switcher: &mut TaskSwitcher) -> Option<Features> {
+        loop {
+            match switcher.current() {
+                Some(slot) => match self.turn {
+                    Some((turn_slot, used)) if turn_slot == slot && used >= self.weights[slot] => {
+                        switcher.finished(slot);
+                        self.deferred.push(slot);
+                        self.turn = None;
+                    }
+                    Some((turn_slot, _)) if turn_slot == slot => return Some(self.order[slot]),
+                    _ => {
+                        self.turn = Some((slot, 0));
+                        return Some(self.order[slot]);
+                    }
+                },
+                None => {
+                    if self.deferred.is_empty() {
+                        return None;
+                    }
+                    for slot in self.deferred.drain(..) {
+                        switcher.flag_task(slot);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The current feature popped an output
+    pub fn on_output(&mut self) {
+        if let Some((_, used)) = &mut self.turn {
+            *used = used.saturating_add(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use sans_io_runtime::TaskSwitcher;
+
+    use super::{FeatureScheduler, DEFAULT_CONTROL_WEIGHT, FEATURES_COUNT};
+    use crate::features::Features;
+
+    #[test]
+    fn control_features_first() {
+        let scheduler = FeatureScheduler::new(&HashMap::new());
+        assert_eq!(scheduler.slot(Features::Neighbours), 0);
+        assert_eq!(scheduler.slot(Features::RouterSync), 1);
+        assert_eq!(scheduler.slot(Features::Data), 2);
+        assert_eq!(scheduler.slot(Features::HolePunch), FEATURES_COUNT - 1);
+
+        let scheduler = FeatureScheduler::new(&HashMap::from([(Features::Data, 10)]));
+        assert_eq!(scheduler.slot(Features::Data), 0);
+    }
+
+    #[test]
+    fn busy_feature_yields_after_weight() {
+        let mut scheduler = FeatureScheduler::new(&HashMap::new());
+        let mut switcher = TaskSwitcher::new(FEATURES_COUNT);
+        let router_sync = scheduler.slot(Features::RouterSync);
+        let data = scheduler.slot(Features::Data);
+        switcher.flag_task(data);
+        switcher.flag_task(router_sync);
+
+        let mut popped = vec![];
+        for _ in 0..(DEFAULT_CONTROL_WEIGHT as usize + 3) {
+            let feature = scheduler.current(&mut switcher).expect("Should have flagged feature");
+            scheduler.on_output();
+            popped.push(feature);
+        }
+        let mut expected = vec![Features::RouterSync; DEFAULT_CONTROL_WEIGHT as usize];
+        expected.extend([Features::Data, Features::RouterSync, Features::RouterSync]);
+        assert_eq!(popped, expected);
+
+        //branches unflag themselves when they have no more output
+        switcher.finished(router_sync);
+        assert_eq!(scheduler.current(&mut switcher), Some(Features::Data));
+        switcher.finished(data);
+        assert_eq!(scheduler.current(&mut switcher), None);
+    }
+}
@@ -1,45 +1,287 @@
-use std::marker::PhantomData;
+use std::{
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    net::Ipv4Addr,
+    str::FromStr,
+};
 
 #[cfg(feature = "vpn")]
 use crate::base::TransportMsg;
-#[cfg(feature = "vpn")]
 use atm0s_sdn_identity::{NodeId, NodeIdType};
 #[cfg(feature = "vpn")]
-use atm0s_sdn_router::{RouteAction, RouteRule, RouterTable};
+use atm0s_sdn_router::{RouteAction, RouterTable};
+use atm0s_sdn_router::{RouteRule, ServiceBroadcastLevel};
 use derivative::Derivative;
-use sans_io_runtime::{collections::DynamicDeque, TaskSwitcherChild};
+use sans_io_runtime::{collections::DynamicDeque, return_if_none, TaskSwitcherChild};
+use serde::{Deserialize, Serialize};
 
-use crate::base::{Buffer, Feature, FeatureContext, FeatureInput, FeatureOutput, FeatureWorker, FeatureWorkerContext, FeatureWorkerInput, FeatureWorkerOutput};
+use crate::base::{
+    Buffer, Feature, FeatureContext, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerContext, FeatureWorkerInput, FeatureWorkerOutput, NetOutgoingMeta, Ttl,
+};
 
 pub const FEATURE_ID: u8 = 3;
 pub const FEATURE_NAME: &str = "vpn";
+/// How long a resolved owner of a virtual ip is kept before asking again
+pub const RESOLVE_TTL_MS: u64 = 60_000;
+/// How long to wait for a claim after broadcasting a resolve query
+pub const RESOLVE_TIMEOUT_MS: u64 = 2000;
+/// Meta of the resolve messages, tunneled packets are sent with meta 0
+const RESOLVE_META: u8 = 1;
+
+/// Virtual ip of a node which has no configured one, same as the default ip of the tun device
+pub fn default_virtual_ip(node: NodeId) -> Ipv4Addr {
+    Ipv4Addr::new(10, 33, 33, node as u8)
+}
+
+/// IPv4 range in CIDR notation, like `10.10.0.0/16`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Cidr {
+    network: u32,
+    prefix: u8,
+}
+
+impl Ipv4Cidr {
+    /// Returns None if prefix is above 32, host bits of `addr` are ignored
+    pub fn new(addr: Ipv4Addr, prefix: u8) -> Option<Self> {
+        if prefix > 32 {
+            return None;
+        }
+        Some(Self {
+            network: u32::from(addr) & Self::mask(prefix),
+            prefix,
+        })
+    }
+
+    pub fn network(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.network)
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        u32::from(ip) & Self::mask(self.prefix) == self.network
+    }
+
+    fn mask(prefix: u8) -> u32 {
+        if prefix == 0 {
+            0
+        } else {
+            u32::MAX << (32 - prefix)
+        }
+    }
+}
+
+impl FromStr for Ipv4Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = s.split_once('/').ok_or("Missing prefix length".to_string())?;
+        let addr = addr.parse::<Ipv4Addr>().map_err(|e| e.to_string())?;
+        let prefix = prefix.parse::<u8>().map_err(|e| e.to_string())?;
+        Self::new(addr, prefix).ok_or("Prefix length above 32".to_string())
+    }
+}
+
+/// Tunneled packets to an ip inside `cidr` are sent to `node`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VpnRoute {
+    pub cidr: Ipv4Cidr,
+    pub node: NodeId,
+}
+
+/// Resolve queries are broadcast to the nodes which run `service`, so every node of the overlay should run it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VpnResolveCfg {
+    pub service: u8,
+    pub level: ServiceBroadcastLevel,
+}
+
+/// Without routes the destination of a tunneled packet is the node which has the last ip byte as last node id byte,
+/// in the same geo and group as this node. With routes only matched destinations are allowed, other packets are dropped.
+/// With `resolve` and no routes, the owner of a destination ip is asked over the network instead.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VpnCfg {
+    pub routes: Vec<VpnRoute>,
+    /// Ip claimed by this node when resolving, `default_virtual_ip` if not set
+    pub virtual_ip: Option<Ipv4Addr>,
+    pub resolve: Option<VpnResolveCfg>,
+}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Control {}
+pub enum Control {
+    /// Replace the routes of all workers, empty routes go back to the default mapping
+    SetRoutes(Vec<VpnRoute>),
+}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Event {}
 
-#[derive(Debug, Clone)]
-pub struct ToWorker;
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ToWorker {
+    SetRoutes(Vec<VpnRoute>),
+    /// Ask the controller for the owner of destinations instead of the default mapping
+    EnableResolve,
+    Resolved(Ipv4Addr, NodeId),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ToController {
+    Resolve(Ipv4Addr),
+}
 
-#[derive(Debug, Clone)]
-pub struct ToController;
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Message {
+    /// Broadcast query for the owner of an ip
+    WhoHas(Ipv4Addr),
+    /// Reply to WhoHas by the owner, also broadcast once when the node starts
+    Claim(Ipv4Addr),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct ResolveSlot {
+    node: NodeId,
+    ts: u64,
+}
 
 pub type Output<UserData> = FeatureOutput<UserData, Event, ToWorker>;
 pub type WorkerOutput<UserData> = FeatureWorkerOutput<UserData, Control, Event, ToController>;
 
-#[derive(Debug, Derivative)]
-#[derivative(Default(bound = ""))]
 pub struct VpnFeature<UserData> {
-    _tmp: PhantomData<UserData>,
+    virtual_ip: Option<Ipv4Addr>,
+    resolve: Option<VpnResolveCfg>,
+    announced: bool,
+    resolved: HashMap<Ipv4Addr, ResolveSlot>,
+    pending: HashMap<Ipv4Addr, u64>,
+    seq: u16,
+    queue: VecDeque<Output<UserData>>,
     shutdown: bool,
 }
 
+impl<UserData> VpnFeature<UserData> {
+    pub fn new(cfg: VpnCfg) -> Self {
+        let mut queue = VecDeque::new();
+        if !cfg.routes.is_empty() {
+            queue.push_back(FeatureOutput::ToWorker(true, ToWorker::SetRoutes(cfg.routes)));
+        }
+        if cfg.resolve.is_some() {
+            queue.push_back(FeatureOutput::ToWorker(true, ToWorker::EnableResolve));
+        }
+        Self {
+            virtual_ip: cfg.virtual_ip,
+            resolve: cfg.resolve,
+            announced: false,
+            resolved: HashMap::new(),
+            pending: HashMap::new(),
+            seq: 0,
+            queue,
+            shutdown: false,
+        }
+    }
+
+    fn local_ip(&self, ctx: &FeatureContext) -> Ipv4Addr {
+        self.virtual_ip.unwrap_or_else(|| default_virtual_ip(ctx.node_id))
+    }
+
+    fn process_resolve(&mut self, ctx: &FeatureContext, now_ms: u64, ip: Ipv4Addr) {
+        let resolve = return_if_none!(self.resolve);
+        if ip == self.local_ip(ctx) {
+            self.queue.push_back(FeatureOutput::ToWorker(true, ToWorker::Resolved(ip, ctx.node_id)));
+        } else if let Some(slot) = self.resolved.get(&ip).filter(|slot| slot.ts + RESOLVE_TTL_MS > now_ms) {
+            log::debug!("[VpnFeature] ip {ip} is already resolved to {}", slot.node);
+            self.queue.push_back(FeatureOutput::ToWorker(true, ToWorker::Resolved(ip, slot.node)));
+        } else if let Entry::Vacant(entry) = self.pending.entry(ip) {
+            log::debug!("[VpnFeature] ip {ip} is not resolved => broadcast WhoHas");
+            entry.insert(now_ms);
+            self.broadcast(resolve, Message::WhoHas(ip));
+        }
+    }
+
+    fn process_remote(&mut self, ctx: &FeatureContext, now_ms: u64, from: NodeId, msg: Message) {
+        log::debug!("[VpnFeature] Received message from {from}: {:?}", msg);
+        let local_ip = self.local_ip(ctx);
+        match msg {
+            Message::WhoHas(ip) => {
+                if ip == local_ip {
+                    Self::send_to(&mut self.queue, RouteRule::ToNode(from), Message::Claim(ip));
+                }
+            }
+            Message::Claim(ip) => {
+                if ip == local_ip && from != ctx.node_id {
+                    let winner = from.min(ctx.node_id);
+                    log::warn!("[VpnFeature] virtual ip {ip} conflict between local {} and {from}, prefer {winner}", ctx.node_id);
+                }
+                let winner = match self.resolved.get_mut(&ip) {
+                    Some(slot) if slot.node != from && slot.ts + RESOLVE_TTL_MS > now_ms => {
+                        let winner = slot.node.min(from);
+                        log::warn!("[VpnFeature] ip {ip} is claimed by both {} and {from}, prefer {winner}", slot.node);
+                        slot.node = winner;
+                        slot.ts = now_ms;
+                        winner
+                    }
+                    _ => {
+                        self.resolved.insert(ip, ResolveSlot { node: from, ts: now_ms });
+                        from
+                    }
+                };
+                self.pending.remove(&ip);
+                self.queue.push_back(FeatureOutput::ToWorker(true, ToWorker::Resolved(ip, winner)));
+            }
+        }
+    }
+
+    fn broadcast(&mut self, resolve: VpnResolveCfg, msg: Message) {
+        let seq = self.seq;
+        self.seq = self.seq.wrapping_add(1);
+        Self::send_to(&mut self.queue, RouteRule::ToServices(resolve.service, resolve.level, seq), msg);
+    }
+
+    fn send_to(queue: &mut VecDeque<Output<UserData>>, rule: RouteRule, msg: Message) {
+        let msg = bincode::serialize(&msg).expect("Should to bytes");
+        queue.push_back(FeatureOutput::SendRoute(rule, NetOutgoingMeta::new(true, Ttl::default(), RESOLVE_META, true), msg.into()));
+    }
+}
+
 impl<UserData> Feature<UserData, Control, Event, ToController, ToWorker> for VpnFeature<UserData> {
-    fn on_shared_input(&mut self, _ctx: &FeatureContext, _now: u64, _input: crate::base::FeatureSharedInput) {}
+    fn on_shared_input(&mut self, ctx: &FeatureContext, now: u64, input: FeatureSharedInput) {
+        if let FeatureSharedInput::Tick(_) = input {
+            let resolve = return_if_none!(self.resolve);
+            if !self.announced {
+                self.announced = true;
+                let ip = self.local_ip(ctx);
+                log::info!("[VpnFeature] announce virtual ip {ip}");
+                self.broadcast(resolve, Message::Claim(ip));
+            }
+            self.pending.retain(|ip, started_at| {
+                let alive = now < *started_at + RESOLVE_TIMEOUT_MS;
+                if !alive {
+                    log::debug!("[VpnFeature] resolve {ip} timeout");
+                }
+                alive
+            });
+            self.resolved.retain(|_, slot| slot.ts + RESOLVE_TTL_MS > now);
+        }
+    }
 
-    fn on_input(&mut self, _ctx: &FeatureContext, _now_ms: u64, _input: FeatureInput<'_, UserData, Control, ToController>) {}
+    fn on_input(&mut self, ctx: &FeatureContext, now_ms: u64, input: FeatureInput<'_, UserData, Control, ToController>) {
+        match input {
+            FeatureInput::Control(_, Control::SetRoutes(routes)) => {
+                log::info!("[VpnFeature] set {} routes", routes.len());
+                self.queue.push_back(FeatureOutput::ToWorker(true, ToWorker::SetRoutes(routes)));
+            }
+            FeatureInput::FromWorker(ToController::Resolve(ip)) => self.process_resolve(ctx, now_ms, ip),
+            FeatureInput::Local(meta, msg) | FeatureInput::Net(_, meta, msg) => {
+                if !meta.secure {
+                    log::warn!("[VpnFeature] reject unsecure message");
+                    return;
+                }
+                if let (Some(from), Ok(msg)) = (meta.source, bincode::deserialize::<Message>(&msg)) {
+                    self.process_remote(ctx, now_ms, from, msg)
+                }
+            }
+            _ => {}
+        }
+    }
 
     fn on_shutdown(&mut self, _ctx: &FeatureContext, _now: u64) {
         self.shutdown = true;
@@ -50,7 +292,7 @@ impl<UserData> TaskSwitcherChild<Output<UserData>> for VpnFeature<UserData> {
     type Time = u64;
 
     fn is_empty(&self) -> bool {
-        self.shutdown
+        self.shutdown && self.queue.is_empty()
     }
 
     fn empty_event(&self) -> Output<UserData> {
@@ -58,7 +300,22 @@ impl<UserData> TaskSwitcherChild<Output<UserData>> for VpnFeature<UserData> {
     }
 
     fn pop_output(&mut self, _now: u64) -> Option<Output<UserData>> {
-        None
+        self.queue.pop_front()
+    }
+}
+
+/// Routes sorted by prefix length, so the first match is the longest prefix
+#[derive(Debug, Default)]
+struct VpnRoutes(Vec<VpnRoute>);
+
+impl VpnRoutes {
+    fn new(mut routes: Vec<VpnRoute>) -> Self {
+        routes.sort_by(|a, b| b.cidr.prefix().cmp(&a.cidr.prefix()));
+        Self(routes)
+    }
+
+    fn lookup(&self, ip: Ipv4Addr) -> Option<NodeId> {
+        self.0.iter().find(|route| route.cidr.contains(ip)).map(|route| route.node)
     }
 }
 
@@ -66,22 +323,71 @@ impl<UserData> TaskSwitcherChild<Output<UserData>> for VpnFeature<UserData> {
 #[derivative(Default(bound = ""))]
 pub struct VpnFeatureWorker<UserData> {
     queue: DynamicDeque<WorkerOutput<UserData>, 16>,
+    /// None keeps the default mapping of destination ip to node
+    routes: Option<VpnRoutes>,
+    /// Used instead of the default mapping when the controller has resolving enabled
+    resolve: bool,
+    resolved: HashMap<Ipv4Addr, ResolveSlot>,
+    /// Resolve requests sent to the controller and not answered yet
+    pending: HashMap<Ipv4Addr, u64>,
+    dropped: u64,
     shutdown: bool,
 }
 
 impl<UserData> VpnFeatureWorker<UserData> {
+    /// Number of tunneled packets dropped because no route matched their destination or it is not resolved yet
+    pub fn dropped_packets(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Node of a tunneled packet destination, None if the routes don't allow it or the owner is not resolved yet
+    #[cfg_attr(not(feature = "vpn"), allow(dead_code))]
+    fn destination(&mut self, local: NodeId, now_ms: u64, to_ip: Ipv4Addr) -> Option<NodeId> {
+        let dest = if let Some(routes) = &self.routes {
+            let dest = routes.lookup(to_ip);
+            if dest.is_none() {
+                log::debug!("[VpnFeatureWorker] drop packet to {to_ip}, no route matched");
+            }
+            dest
+        } else if self.resolve {
+            self.resolved_destination(now_ms, to_ip)
+        } else {
+            Some(NodeId::build(local.geo1(), local.geo2(), local.group(), to_ip.octets()[3]))
+        };
+        if dest.is_none() {
+            self.dropped += 1;
+        }
+        dest
+    }
+
+    /// Expired owners are still used while the controller is asked again
+    fn resolved_destination(&mut self, now_ms: u64, to_ip: Ipv4Addr) -> Option<NodeId> {
+        let dest = self.resolved.get(&to_ip).map(|slot| (slot.node, slot.ts + RESOLVE_TTL_MS <= now_ms));
+        let need_request = dest.map_or(true, |(_, expired)| expired);
+        if need_request && self.pending.get(&to_ip).map_or(true, |started_at| now_ms >= started_at + RESOLVE_TIMEOUT_MS) {
+            log::debug!("[VpnFeatureWorker] ask controller for owner of {to_ip}");
+            self.pending.insert(to_ip, now_ms);
+            self.queue.push_back(FeatureWorkerOutput::ToController(ToController::Resolve(to_ip)));
+        }
+        if dest.is_none() {
+            log::debug!("[VpnFeatureWorker] drop packet to {to_ip}, owner not resolved yet");
+        }
+        dest.map(|(node, _)| node)
+    }
+
     #[cfg(feature = "vpn")]
-    fn process_tun(&mut self, ctx: &FeatureWorkerContext, mut pkt: Buffer) {
+    fn process_tun(&mut self, ctx: &FeatureWorkerContext, now_ms: u64, mut pkt: Buffer) {
         #[cfg(any(target_os = "macos", target_os = "ios"))]
         let to_ip = &pkt[20..24];
         #[cfg(any(target_os = "linux", target_os = "android"))]
         let to_ip = &pkt[16..20];
-        let dest = NodeId::build(ctx.node_id.geo1(), ctx.node_id.geo2(), ctx.node_id.group(), to_ip[3]);
+        let to_ip = Ipv4Addr::new(to_ip[0], to_ip[1], to_ip[2], to_ip[3]);
+        let dest = return_if_none!(self.destination(ctx.node_id, now_ms, to_ip));
         if dest == ctx.node_id {
             //This is for current node, just echo back
             rewrite_tun_pkt(&mut pkt);
             self.queue.push_back(FeatureWorkerOutput::TunPkt(pkt));
-        } else if let RouteAction::Next(remote) = ctx.router.path_to_node(dest) {
+        } else if let RouteAction::Next(remote) = ctx.router.path_to_node(dest).pick_flow(dest as u64) {
             //TODO decrease TTL
             //TODO how to avoid copy data here
             self.queue
@@ -98,11 +404,28 @@ impl<UserData> VpnFeatureWorker<UserData> {
 }
 
 impl<UserData> FeatureWorker<UserData, Control, Event, ToController, ToWorker> for VpnFeatureWorker<UserData> {
-    fn on_input(&mut self, ctx: &mut FeatureWorkerContext, _now: u64, input: FeatureWorkerInput<UserData, Control, ToWorker>) {
+    fn on_input(&mut self, ctx: &mut FeatureWorkerContext, now: u64, input: FeatureWorkerInput<UserData, Control, ToWorker>) {
         match input {
             #[cfg(feature = "vpn")]
-            FeatureWorkerInput::TunPkt(pkt) => self.process_tun(ctx, pkt),
+            FeatureWorkerInput::TunPkt(pkt) => self.process_tun(ctx, now, pkt),
+            FeatureWorkerInput::Network(conn, header, pkt) if header.meta == RESOLVE_META => {
+                self.queue.push_back(FeatureWorkerOutput::ForwardNetworkToController(conn, header, pkt));
+            }
             FeatureWorkerInput::Network(_conn, _header, pkt) => self.process_udp(ctx, pkt),
+            FeatureWorkerInput::Local(header, pkt) => self.queue.push_back(FeatureWorkerOutput::ForwardLocalToController(header, pkt)),
+            FeatureWorkerInput::FromController(_, ToWorker::SetRoutes(routes)) => {
+                log::info!("[VpnFeatureWorker] set {} routes", routes.len());
+                self.routes = (!routes.is_empty()).then(|| VpnRoutes::new(routes));
+            }
+            FeatureWorkerInput::FromController(_, ToWorker::EnableResolve) => {
+                log::info!("[VpnFeatureWorker] enable resolve");
+                self.resolve = true;
+            }
+            FeatureWorkerInput::FromController(_, ToWorker::Resolved(ip, node)) => {
+                log::debug!("[VpnFeatureWorker] {ip} resolved to {node}");
+                self.pending.remove(&ip);
+                self.resolved.insert(ip, ResolveSlot { node, ts: now });
+            }
             _ => {}
         }
     }
@@ -142,3 +465,161 @@ fn rewrite_tun_pkt(payload: &mut [u8]) {
         payload[3] = 0;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use atm0s_sdn_identity::{NodeId, NodeIdType};
+    use atm0s_sdn_router::{RouteRule, ServiceBroadcastLevel};
+    use sans_io_runtime::TaskSwitcherChild;
+
+    use crate::base::{Feature, FeatureContext, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorkerOutput};
+
+    use super::{default_virtual_ip, Event, Ipv4Cidr, Message, ToController, ToWorker, VpnCfg, VpnFeature, VpnFeatureWorker, VpnResolveCfg, VpnRoute, VpnRoutes, RESOLVE_TIMEOUT_MS, RESOLVE_TTL_MS};
+
+    const RESOLVE: VpnResolveCfg = VpnResolveCfg {
+        service: 1,
+        level: ServiceBroadcastLevel::Global,
+    };
+
+    fn decode_msg(msg: Option<FeatureOutput<(), Event, ToWorker>>) -> Option<(RouteRule, Message)> {
+        match msg? {
+            FeatureOutput::SendRoute(rule, _, msg) => Some((rule, bincode::deserialize(&msg).expect("Should decode"))),
+            _ => panic!("Should be SendRoute"),
+        }
+    }
+
+    fn resolve_feature() -> VpnFeature<()> {
+        let mut vpn = VpnFeature::new(VpnCfg {
+            resolve: Some(RESOLVE),
+            ..Default::default()
+        });
+        assert_eq!(vpn.pop_output(0), Some(FeatureOutput::ToWorker(true, ToWorker::EnableResolve)));
+        vpn
+    }
+
+    fn route(cidr: &str, node: NodeId) -> VpnRoute {
+        VpnRoute {
+            cidr: cidr.parse().expect("Should parse cidr"),
+            node,
+        }
+    }
+
+    #[test]
+    fn parse_cidr() {
+        let cidr: Ipv4Cidr = "10.1.2.3/16".parse().expect("Should parse");
+        assert_eq!(cidr.network(), Ipv4Addr::new(10, 1, 0, 0));
+        assert_eq!(cidr.prefix(), 16);
+        assert!(cidr.contains(Ipv4Addr::new(10, 1, 200, 1)));
+        assert!(!cidr.contains(Ipv4Addr::new(10, 2, 0, 1)));
+
+        let all: Ipv4Cidr = "0.0.0.0/0".parse().expect("Should parse");
+        assert!(all.contains(Ipv4Addr::new(192, 168, 1, 1)));
+
+        assert!("10.0.0.0".parse::<Ipv4Cidr>().is_err());
+        assert!("10.0.0.0/33".parse::<Ipv4Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Ipv4Cidr>().is_err());
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let routes = VpnRoutes::new(vec![route("10.0.0.0/8", 1), route("10.1.1.0/24", 3), route("10.1.0.0/16", 2)]);
+        assert_eq!(routes.lookup(Ipv4Addr::new(10, 1, 1, 5)), Some(3));
+        assert_eq!(routes.lookup(Ipv4Addr::new(10, 1, 2, 5)), Some(2));
+        assert_eq!(routes.lookup(Ipv4Addr::new(10, 2, 0, 1)), Some(1));
+        assert_eq!(routes.lookup(Ipv4Addr::new(192, 168, 0, 1)), None);
+    }
+
+    #[test]
+    fn unmatched_destination_should_drop() {
+        let local = NodeId::build(1, 2, 3, 4);
+        let mut worker = VpnFeatureWorker::<()>::default();
+        //default mapping uses the last ip byte
+        assert_eq!(worker.destination(local, 0, Ipv4Addr::new(192, 168, 0, 10)), Some(NodeId::build(1, 2, 3, 10)));
+
+        worker.routes = Some(VpnRoutes::new(vec![route("10.10.0.0/16", 100)]));
+        assert_eq!(worker.destination(local, 0, Ipv4Addr::new(10, 10, 5, 1)), Some(100));
+        assert_eq!(worker.destination(local, 0, Ipv4Addr::new(192, 168, 0, 10)), None);
+        assert_eq!(worker.destination(local, 0, Ipv4Addr::new(10, 11, 0, 1)), None);
+        assert_eq!(worker.dropped_packets(), 2);
+    }
+
+    #[test]
+    fn announce_and_reply_virtual_ip() {
+        let ctx = FeatureContext { node_id: 10, session: 0 };
+        let mut vpn = resolve_feature();
+        let local_ip = default_virtual_ip(10);
+
+        vpn.on_shared_input(&ctx, 0, FeatureSharedInput::Tick(0));
+        assert_eq!(
+            decode_msg(vpn.pop_output(0)),
+            Some((RouteRule::ToServices(1, ServiceBroadcastLevel::Global, 0), Message::Claim(local_ip)))
+        );
+        vpn.on_shared_input(&ctx, 1000, FeatureSharedInput::Tick(1));
+        assert_eq!(vpn.pop_output(0), None);
+
+        vpn.process_remote(&ctx, 1000, 20, Message::WhoHas(local_ip));
+        assert_eq!(decode_msg(vpn.pop_output(0)), Some((RouteRule::ToNode(20), Message::Claim(local_ip))));
+        vpn.process_remote(&ctx, 1000, 20, Message::WhoHas(Ipv4Addr::new(10, 33, 33, 20)));
+        assert_eq!(vpn.pop_output(0), None);
+    }
+
+    #[test]
+    fn resolve_prefers_lower_node_on_conflict() {
+        let ctx = FeatureContext { node_id: 1, session: 0 };
+        let mut vpn = resolve_feature();
+        let ip = Ipv4Addr::new(10, 33, 33, 5);
+        vpn.on_shared_input(&ctx, 0, FeatureSharedInput::Tick(0));
+        assert_eq!(
+            decode_msg(vpn.pop_output(0)),
+            Some((RouteRule::ToServices(1, ServiceBroadcastLevel::Global, 0), Message::Claim(default_virtual_ip(1))))
+        );
+
+        vpn.on_input(&ctx, 0, FeatureInput::FromWorker(ToController::Resolve(ip)));
+        assert_eq!(decode_msg(vpn.pop_output(0)), Some((RouteRule::ToServices(1, ServiceBroadcastLevel::Global, 1), Message::WhoHas(ip))));
+        //already waiting for a claim
+        vpn.on_input(&ctx, 0, FeatureInput::FromWorker(ToController::Resolve(ip)));
+        assert_eq!(vpn.pop_output(0), None);
+
+        vpn.process_remote(&ctx, 100, 5, Message::Claim(ip));
+        assert_eq!(vpn.pop_output(0), Some(FeatureOutput::ToWorker(true, ToWorker::Resolved(ip, 5))));
+        vpn.process_remote(&ctx, 100, 3, Message::Claim(ip));
+        assert_eq!(vpn.pop_output(0), Some(FeatureOutput::ToWorker(true, ToWorker::Resolved(ip, 3))));
+        vpn.process_remote(&ctx, 100, 7, Message::Claim(ip));
+        assert_eq!(vpn.pop_output(0), Some(FeatureOutput::ToWorker(true, ToWorker::Resolved(ip, 3))));
+
+        //cached result is reused until ttl
+        vpn.on_input(&ctx, 200, FeatureInput::FromWorker(ToController::Resolve(ip)));
+        assert_eq!(vpn.pop_output(0), Some(FeatureOutput::ToWorker(true, ToWorker::Resolved(ip, 3))));
+        vpn.on_shared_input(&ctx, 100 + RESOLVE_TTL_MS, FeatureSharedInput::Tick(1));
+        vpn.on_input(&ctx, 100 + RESOLVE_TTL_MS, FeatureInput::FromWorker(ToController::Resolve(ip)));
+        assert_eq!(decode_msg(vpn.pop_output(0)), Some((RouteRule::ToServices(1, ServiceBroadcastLevel::Global, 2), Message::WhoHas(ip))));
+    }
+
+    #[test]
+    fn worker_resolve_destination() {
+        let local = NodeId::build(1, 2, 3, 4);
+        let ip = Ipv4Addr::new(10, 33, 33, 5);
+        let mut worker = VpnFeatureWorker::<()> { resolve: true, ..Default::default() };
+
+        assert_eq!(worker.destination(local, 0, ip), None);
+        assert!(matches!(worker.queue.pop_front(), Some(FeatureWorkerOutput::ToController(ToController::Resolve(i))) if i == ip));
+        //don't ask again while waiting
+        assert_eq!(worker.destination(local, 100, ip), None);
+        assert!(worker.queue.pop_front().is_none());
+        assert_eq!(worker.dropped_packets(), 2);
+
+        assert_eq!(worker.destination(local, RESOLVE_TIMEOUT_MS, ip), None);
+        assert!(matches!(worker.queue.pop_front(), Some(FeatureWorkerOutput::ToController(ToController::Resolve(_)))));
+
+        worker.resolved.insert(ip, super::ResolveSlot { node: 1000, ts: RESOLVE_TIMEOUT_MS });
+        worker.pending.clear();
+        assert_eq!(worker.destination(local, RESOLVE_TIMEOUT_MS, ip), Some(1000));
+        assert!(worker.queue.pop_front().is_none());
+
+        //expired owner is still used while asking again
+        assert_eq!(worker.destination(local, RESOLVE_TIMEOUT_MS + RESOLVE_TTL_MS, ip), Some(1000));
+        assert!(matches!(worker.queue.pop_front(), Some(FeatureWorkerOutput::ToController(ToController::Resolve(_)))));
+    }
+}
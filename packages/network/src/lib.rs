@@ -1,9 +1,14 @@
 #![allow(clippy::bool_assert_comparison)]
 
-use atm0s_sdn_identity::{ConnId, NodeAddr, NodeId};
-use atm0s_sdn_router::RouteRule;
-use base::{FeatureControlActor, NeighboursControl, NetIncomingMeta, NetOutgoingMeta, SecureContext, ServiceControlActor, ServiceId};
-use data_plane::NetPair;
+use std::net::SocketAddr;
+
+use atm0s_sdn_identity::{ConnDirection, ConnId, NodeAddr, NodeId};
+use atm0s_sdn_router::{
+    core::{RouteExplanation, RouterDump},
+    RouteRule,
+};
+use base::{CipherSuite, DirectSendError, DisconnectReason, FeatureControlActor, NeighboursControl, NetIncomingMeta, NetOutgoingMeta, RekeyStats, SecureContext, ServiceControlActor, ServiceId};
+use data_plane::{ConnStats, NetPair};
 use features::{Features, FeaturesControl, FeaturesEvent, FeaturesToController, FeaturesToWorker};
 use sans_io_runtime::Buffer;
 
@@ -13,22 +18,63 @@ pub mod base;
 pub mod controller_plane;
 pub mod data_plane;
 pub mod features;
+pub mod memory;
+pub mod node;
 pub mod secure;
 pub mod services;
 pub mod worker;
 
+pub use atm0s_sdn_utils::metrics;
+
 #[derive(Debug, Clone)]
 pub enum ExtIn<UserData, ServicesControl> {
     ConnectTo(NodeAddr),
     DisconnectFrom(NodeId),
     FeaturesControl(UserData, FeaturesControl),
     ServicesControl(ServiceId, UserData, ServicesControl),
+    /// Ask the controller for its neighbours and routes, it is answered with `ExtOut::Topology`
+    QueryTopology(UserData),
+    /// Ask the controller why it routes to the node over its next hop, it is answered with `ExtOut::RouteExplanation`
+    ExplainRoute(UserData, NodeId),
+}
+
+/// Established neighbour connection in a topology query
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NeighbourInfo {
+    pub node: NodeId,
+    pub conn: ConnId,
+    pub remote: SocketAddr,
+    /// Outgoing if this node sent the connect request which won the handshake
+    pub direction: ConnDirection,
+    pub cipher: CipherSuite,
+    /// Keepalive round trip time
+    pub rtt_ms: Option<u32>,
+    /// Controller time when the handshake completed
+    pub established_ms: u64,
+    pub uptime_ms: u64,
+}
+
+/// Live topology of a node as seen by its controller
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Topology {
+    /// Established connections, sorted by node then addr
+    pub neighbours: Vec<NeighbourInfo>,
+    pub routes: RouterDump,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ExtOut<UserData, ServicesEvent> {
     FeaturesEvent(UserData, FeaturesEvent),
     ServicesEvent(ServiceId, UserData, ServicesEvent),
+    /// The control was sent to a service which is not registered in this node.
+    /// This is only emitted with UnknownServicePolicy::Reply
+    ServiceUnavailable(ServiceId, UserData),
+    /// A message routed to a service was dropped by a remote node, because the service is not registered there.
+    /// This is only emitted when the remote node uses UnknownServicePolicy::Reply
+    RemoteServiceUnavailable(NodeId, ServiceId),
+    Topology(UserData, Box<Topology>),
+    /// None if the node is the local node
+    RouteExplanation(UserData, NodeId, Option<Box<RouteExplanation>>),
 }
 
 #[derive(Debug, Clone)]
@@ -36,8 +82,21 @@ pub enum LogicControl<UserData, SC, SE, TC> {
     Feature(FeaturesToController),
     Service(ServiceId, TC),
     NetNeighbour(NetPair, NeighboursControl),
+    ConnectionRekey(ConnId, RekeyStats),
+    /// Data plane asks for a key exchange because the rekey policy of the connection was hit
+    ConnectionRekeyRequest(ConnId),
+    /// Path MTU of the connection changed after probing
+    ConnectionMtu(ConnId, usize),
+    /// Remote addr of the connection changed from the first pair to the second, it is already moved in the reporting worker
+    ConnectionMigrated(ConnId, NetPair, NetPair),
+    /// Periodic traffic counters of all connections pinned in a worker, the u16 is worker id
+    ConnectionStats(u16, Vec<(ConnId, ConnStats)>),
     NetRemote(Features, ConnId, NetIncomingMeta, Buffer),
     NetLocal(Features, NetIncomingMeta, Buffer),
+    /// Local message which the data plane can't send because there is no usable next hop
+    NetUndeliverable(Features, RouteRule, Buffer),
+    /// Direct send which the data plane can't send because the connection is not pinned in the worker
+    NetDirectUndeliverable(Features, ConnId, DirectSendError),
     FeaturesControl(FeatureControlActor<UserData>, FeaturesControl),
     ServicesControl(ServiceControlActor<UserData>, ServiceId, SC),
     ServiceEvent(ServiceId, FeaturesEvent),
@@ -52,7 +111,13 @@ pub enum LogicEvent<UserData, SE, TW> {
     NetRoute(Features, RouteRule, NetOutgoingMeta, Buffer),
 
     Pin(ConnId, NodeId, NetPair, SecureContext),
-    UnPin(ConnId),
+    UnPin(ConnId, DisconnectReason),
+    /// Move a connection from the first pair to the second without dropping its routes
+    Migrate(ConnId, NetPair, NetPair),
+    /// Install key `epoch` of a connection, the flag is set if the encryptor should switch to it now
+    Rekey(ConnId, u8, SecureContext, bool),
+    /// Switch the encryptor of a connection to the already installed key `epoch`
+    RekeyActivate(ConnId, u8),
     /// first bool is flag for broadcast or not
     Feature(bool, FeaturesToWorker<UserData>),
     Service(ServiceId, TW),
@@ -73,6 +138,9 @@ impl<UserData, SE, TW> LogicEvent<UserData, SE, TW> {
         match self {
             LogicEvent::Pin(..) => LogicEventDest::Broadcast,
             LogicEvent::UnPin(..) => LogicEventDest::Broadcast,
+            LogicEvent::Migrate(..) => LogicEventDest::Broadcast,
+            LogicEvent::Rekey(..) => LogicEventDest::Broadcast,
+            LogicEvent::RekeyActivate(..) => LogicEventDest::Broadcast,
             LogicEvent::Service(..) => LogicEventDest::Broadcast,
             LogicEvent::Feature(true, ..) => LogicEventDest::Broadcast,
             LogicEvent::Feature(false, ..) => LogicEventDest::Any,
@@ -0,0 +1,235 @@
+//! In-process transport, nodes of one process form a live mesh over channels.
+//!
+//! Each [`MemoryNode`] runs a [`Node`] on its own thread, bound to a `Protocol::Memory` port of a shared
+//! [`MemoryNetwork`]. Unlike the test simulator, nodes are not stepped together: packets, controls and ticks are
+//! handled as soon as the node thread gets them. Memory ports are mapped to socket addrs in the `100::/64` discard
+//! prefix, so the neighbours and data planes handle them like udp pairs. Packets to a port which is not bound are dropped.
+
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    hash::Hash,
+    io,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use atm0s_sdn_identity::{NodeAddr, NodeAddrBuilder, NodeId, Protocol};
+use parking_lot::Mutex;
+
+use crate::{
+    base::Buffer,
+    data_plane::{NetOutput, NetPair},
+    node::{Node, NodeCfg, NodeOutput},
+    ExtIn, ExtOut,
+};
+
+const MEMORY_PREFIX: u128 = 0x0100 << 112;
+const MEMORY_SOCKET_PORT: u16 = 1;
+
+/// Socket addr which stands for a memory port in the planes
+pub fn memory_socket_addr(port: u64) -> SocketAddr {
+    SocketAddr::new(IpAddr::V6(Ipv6Addr::from(MEMORY_PREFIX | port as u128)), MEMORY_SOCKET_PORT)
+}
+
+pub fn memory_node_addr(node_id: NodeId, port: u64) -> NodeAddr {
+    let mut builder = NodeAddrBuilder::new(node_id);
+    builder.add_protocol(Protocol::Memory(port));
+    builder.addr()
+}
+
+enum MemoryIn<UserData, SC> {
+    Udp(NetPair, Buffer),
+    Ext(ExtIn<UserData, SC>),
+    Shutdown,
+}
+
+type MemoryPorts<UserData, SC> = Arc<Mutex<HashMap<SocketAddr, Sender<MemoryIn<UserData, SC>>>>>;
+
+/// Shared switch of memory ports, clones are handles of the same network
+pub struct MemoryNetwork<UserData, SC> {
+    ports: MemoryPorts<UserData, SC>,
+}
+
+impl<UserData, SC> Clone for MemoryNetwork<UserData, SC> {
+    fn clone(&self) -> Self {
+        Self { ports: self.ports.clone() }
+    }
+}
+
+impl<UserData, SC> Default for MemoryNetwork<UserData, SC> {
+    fn default() -> Self {
+        Self { ports: Default::default() }
+    }
+}
+
+impl<UserData, SC> MemoryNetwork<UserData, SC> {
+    fn bind(&self, addr: SocketAddr, tx: Sender<MemoryIn<UserData, SC>>) -> io::Result<()> {
+        let mut ports = self.ports.lock();
+        if ports.contains_key(&addr) {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("memory addr {addr} is already bound")));
+        }
+        ports.insert(addr, tx);
+        Ok(())
+    }
+
+    fn unbind(&self, addr: SocketAddr) {
+        self.ports.lock().remove(&addr);
+    }
+
+    fn send(&self, pair: NetPair, buf: Buffer) {
+        let ports = self.ports.lock();
+        match ports.get(&pair.remote) {
+            Some(tx) => {
+                let _ = tx.send(MemoryIn::Udp(NetPair::new(pair.remote, pair.local), buf));
+            }
+            None => log::debug!("[MemoryNetwork] Drop packet from {} to unbound {}", pair.local, pair.remote),
+        }
+    }
+
+    fn send_out(&self, out: NetOutput) {
+        match out {
+            NetOutput::UdpPacket(pair, buf) | NetOutput::UdpMarked(pair, _, buf) => self.send(pair, buf),
+            NetOutput::UdpPackets(pairs, buf) => {
+                for pair in pairs {
+                    self.send(pair, buf.clone());
+                }
+            }
+            NetOutput::UdpBatch(batch) => {
+                for (pair, buf) in batch {
+                    self.send(pair, buf);
+                }
+            }
+            #[cfg(feature = "vpn")]
+            NetOutput::TunPacket(_) => {}
+        }
+    }
+}
+
+/// Node running on its own thread over a [`MemoryNetwork`], it is shut down when dropped
+pub struct MemoryNode<UserData, SC, SE> {
+    node_id: NodeId,
+    port: u64,
+    input: Sender<MemoryIn<UserData, SC>>,
+    output: Receiver<ExtOut<UserData, SE>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<UserData, SC, SE> MemoryNode<UserData, SC, SE>
+where
+    UserData: 'static + Send + Eq + Copy + Debug + Hash,
+    SC: 'static + Send + Debug,
+    SE: 'static + Send + Debug,
+{
+    /// The node only binds the memory port, `bind_addrs` of the controller config are replaced
+    pub fn spawn<TC, TW>(network: &MemoryNetwork<UserData, SC>, port: u64, mut cfg: NodeCfg<UserData, SC, SE, TC, TW>) -> io::Result<Self>
+    where
+        TC: 'static + Send + Debug,
+        TW: 'static + Send + Debug,
+    {
+        let local = memory_socket_addr(port);
+        let (input, rx) = mpsc::channel();
+        let (tx, output) = mpsc::channel();
+        network.bind(local, input.clone())?;
+        let node_id = cfg.node_id;
+        cfg.controller.bind_addrs = vec![local];
+        let network = network.clone();
+        let thread = thread::Builder::new().name(format!("memory-node-{node_id}")).spawn(move || {
+            let tick = Duration::from_millis(cfg.tick_ms.max(1));
+            let mut node = Node::new(cfg);
+            run(&mut node, tick, &rx, &tx, &network);
+            network.unbind(local);
+        })?;
+        Ok(Self {
+            node_id,
+            port,
+            input,
+            output,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        self.node_id
+    }
+
+    pub fn addr(&self) -> NodeAddr {
+        memory_node_addr(self.node_id, self.port)
+    }
+
+    pub fn send(&self, ext: ExtIn<UserData, SC>) {
+        let _ = self.input.send(MemoryIn::Ext(ext));
+    }
+
+    /// Next output of the controller or the worker, None after `timeout`
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<ExtOut<UserData, SE>> {
+        self.output.recv_timeout(timeout).ok()
+    }
+}
+
+impl<UserData, SC, SE> Drop for MemoryNode<UserData, SC, SE> {
+    /// Shuts the node down and waits for its last outputs to be sent
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = self.input.send(MemoryIn::Shutdown);
+            let _ = thread.join();
+        }
+    }
+}
+
+fn run<UserData, SC, SE, TC, TW>(
+    node: &mut Node<UserData, SC, SE, TC, TW>,
+    tick: Duration,
+    rx: &Receiver<MemoryIn<UserData, SC>>,
+    tx: &Sender<ExtOut<UserData, SE>>,
+    network: &MemoryNetwork<UserData, SC>,
+) where
+    UserData: 'static + Eq + Copy + Debug + Hash,
+    SC: Debug,
+    SE: Debug,
+    TC: Debug,
+    TW: Debug,
+{
+    let mut next_tick = Instant::now();
+    loop {
+        if Instant::now() >= next_tick {
+            node.on_tick(node.now_ms());
+            next_tick = (next_tick + tick).max(Instant::now());
+        } else {
+            match rx.recv_timeout(next_tick.saturating_duration_since(Instant::now())) {
+                Ok(MemoryIn::Udp(pair, buf)) => node.on_udp(node.now_ms(), pair, buf),
+                Ok(MemoryIn::Ext(ext)) => node.on_ext(node.now_ms(), ext),
+                Ok(MemoryIn::Shutdown) | Err(RecvTimeoutError::Disconnected) => break,
+                Err(RecvTimeoutError::Timeout) => continue,
+            }
+        }
+        drain(node, tx, network);
+    }
+    node.on_shutdown(node.now_ms());
+    drain(node, tx, network);
+}
+
+fn drain<UserData, SC, SE, TC, TW>(node: &mut Node<UserData, SC, SE, TC, TW>, tx: &Sender<ExtOut<UserData, SE>>, network: &MemoryNetwork<UserData, SC>)
+where
+    UserData: 'static + Eq + Copy + Debug + Hash,
+    SC: Debug,
+    SE: Debug,
+    TC: Debug,
+    TW: Debug,
+{
+    while let Some(out) = node.pop_output(node.now_ms()) {
+        match out {
+            NodeOutput::Ext(out) | NodeOutput::ExtWorker(out) => {
+                let _ = tx.send(out);
+            }
+            NodeOutput::Net(out) => network.send_out(out),
+            //channels are unbounded, the node thread drains all outputs anyway
+            NodeOutput::Backlog(_) => {}
+        }
+    }
+}
@@ -0,0 +1,311 @@
+//! Single worker node for embedding the SDN in an own event loop.
+//!
+//! [`Node`] runs the controller plane and the data plane in one [`SdnWorker`] and loops the events between them
+//! back internally, so the caller only feeds time, packets and external inputs, then drains [`NodeOutput`].
+//! Nothing is driven by wall-clock, the same inputs always produce the same outputs.
+
+use std::{collections::HashMap, fmt::Debug, hash::Hash, sync::Arc};
+
+use atm0s_sdn_identity::{ConnId, NodeId};
+use sans_io_runtime::{Buffer, TaskSwitcherChild};
+
+use crate::{
+    base::{Clock, FeatureEventTarget, ServiceBuilder, ServiceId},
+    controller_plane::ControllerPlaneCfg,
+    data_plane::{BufferPoolStats, ConnStats, DataPlaneCfg, NetInput, NetOutput, NetPair},
+    features::{neighbours::ConnectionCounts, router_sync::Convergence, Features, FeaturesControl, FeaturesEvent},
+    worker::{SdnWorker, SdnWorkerCfg, SdnWorkerInput, SdnWorkerOutput},
+    ExtIn, ExtOut,
+};
+
+pub struct NodeCfg<UserData, SC, SE, TC, TW> {
+    pub node_id: NodeId,
+    /// Ticks closer than this are ignored, so `on_tick` can be called more often
+    pub tick_ms: u64,
+    pub controller: ControllerPlaneCfg<UserData, SC, SE, TC, TW>,
+    pub data: DataPlaneCfg<UserData, SC, SE, TC, TW>,
+    /// Features which are missing here use FeatureEventTarget::Controller
+    pub feature_targets: HashMap<Features, FeatureEventTarget>,
+    /// Time source of the caller loop, see [`Node::now_ms`]
+    pub clock: Arc<dyn Clock>,
+}
+
+#[derive(Debug)]
+pub enum NodeOutput<UserData, SE> {
+    /// Output for actors of the controller
+    Ext(ExtOut<UserData, SE>),
+    /// Output for actors of the worker, from features with FeatureEventTarget::Worker and worker side services
+    ExtWorker(ExtOut<UserData, SE>),
+    Net(NetOutput),
+    /// Outputs of the worker are backed up, see `data_plane::Output::Backlog`
+    Backlog(bool),
+}
+
+pub struct Node<UserData, SC, SE, TC, TW> {
+    node_id: NodeId,
+    /// Boxed because the worker is large and nodes are often moved or kept in collections
+    worker: Box<SdnWorker<UserData, SC, SE, TC, TW>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl<UserData, SC: Debug, SE: Debug, TC: Debug, TW: Debug> Node<UserData, SC, SE, TC, TW>
+where
+    UserData: 'static + Eq + Copy + Debug + Hash,
+{
+    pub fn new(cfg: NodeCfg<UserData, SC, SE, TC, TW>) -> Self {
+        Self {
+            node_id: cfg.node_id,
+            worker: Box::new(SdnWorker::new(SdnWorkerCfg {
+                node_id: cfg.node_id,
+                tick_ms: cfg.tick_ms,
+                controller: Some(cfg.controller),
+                data: cfg.data,
+                feature_targets: cfg.feature_targets,
+            })),
+            clock: cfg.clock,
+        }
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        self.node_id
+    }
+
+    /// Current time of the configured clock, the node itself only uses the `now_ms` passed to each call.
+    /// Loops which don't have an own time source pass this value
+    pub fn now_ms(&self) -> u64 {
+        self.clock.now_ms()
+    }
+
+    pub fn connection_counts(&self) -> ConnectionCounts {
+        self.worker.connection_counts().expect("Should have controller")
+    }
+
+    pub fn connection_rtt_ms(&self, conn: ConnId) -> Option<u32> {
+        self.worker.connection_rtt_ms(conn)
+    }
+
+    /// Routing table convergence at the time of the configured clock
+    pub fn router_convergence(&self) -> Convergence {
+        self.worker.router_convergence(self.clock.now_ms()).expect("Should have controller")
+    }
+
+    pub fn is_converged(&self) -> bool {
+        self.router_convergence().converged
+    }
+
+    pub fn connection_stats(&self, conn: ConnId) -> Option<ConnStats> {
+        self.worker.connection_stats(conn)
+    }
+
+    pub fn feature_unavailable_count(&self) -> u64 {
+        self.worker.feature_unavailable_count()
+    }
+
+    pub fn buffer_pool_stats(&self) -> BufferPoolStats {
+        self.worker.buffer_pool_stats()
+    }
+
+    /// Give back the buffer of a packet after sending it, later secure broadcasts reuse it instead of allocating
+    pub fn recycle_buffer(&mut self, buf: Buffer) {
+        self.worker.recycle_buffer(buf);
+    }
+
+    pub fn on_tick(&mut self, now_ms: u64) {
+        self.worker.on_tick(now_ms);
+    }
+
+    pub fn on_udp(&mut self, now_ms: u64, pair: NetPair, buf: Buffer) {
+        self.worker.on_event(now_ms, SdnWorkerInput::Net(NetInput::UdpPacket(pair, buf)));
+    }
+
+    #[cfg(feature = "vpn")]
+    pub fn on_tun(&mut self, now_ms: u64, buf: Buffer) {
+        self.worker.on_event(now_ms, SdnWorkerInput::Net(NetInput::TunPacket(buf)));
+    }
+
+    pub fn on_ext(&mut self, now_ms: u64, ext: ExtIn<UserData, SC>) {
+        self.worker.on_event(now_ms, SdnWorkerInput::Ext(ext));
+    }
+
+    /// Input from actors of the worker, answers are sent as NodeOutput::ExtWorker
+    pub fn on_ext_worker(&mut self, now_ms: u64, ext: ExtIn<UserData, SC>) {
+        self.worker.on_event(now_ms, SdnWorkerInput::ExtWorker(ext));
+    }
+
+    pub fn on_shutdown(&mut self, now_ms: u64) {
+        self.worker.on_shutdown(now_ms);
+    }
+
+    /// Install a service at runtime, see `ControllerPlane::register_service`
+    pub fn register_service(&mut self, builder: Arc<dyn ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>) {
+        self.worker.register_service(builder);
+    }
+
+    pub fn unregister_service(&mut self, service: ServiceId) -> bool {
+        self.worker.unregister_service(service)
+    }
+
+    /// True if the output queue is full with OverflowPolicy::Block, new sends should wait until outputs are popped
+    pub fn is_blocked(&self) -> bool {
+        self.worker.is_blocked()
+    }
+
+    /// True after shutdown when all connections are closed
+    pub fn is_empty(&self) -> bool {
+        self.worker.is_empty()
+    }
+
+    /// Next output, should be called until None after each input
+    pub fn pop_output(&mut self, now_ms: u64) -> Option<NodeOutput<UserData, SE>> {
+        loop {
+            match self.worker.pop_output(now_ms)? {
+                SdnWorkerOutput::Ext(ext) => return Some(NodeOutput::Ext(ext)),
+                SdnWorkerOutput::ExtWorker(ext) => return Some(NodeOutput::ExtWorker(ext)),
+                SdnWorkerOutput::Net(net) => return Some(NodeOutput::Net(net)),
+                SdnWorkerOutput::Backlog(backlogged) => return Some(NodeOutput::Backlog(backlogged)),
+                SdnWorkerOutput::Bus(bus) => self.worker.on_event(now_ms, SdnWorkerInput::Bus(bus)),
+                SdnWorkerOutput::OnResourceEmpty | SdnWorkerOutput::Continue => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+        sync::Arc,
+    };
+
+    use atm0s_sdn_identity::{NodeAddrBuilder, NodeId, Protocol};
+    use atm0s_sdn_router::shadow::MockShadowRouterHistory;
+    use rand::rngs::mock::StepRng;
+
+    use crate::{
+        base::{AcceptAll, CipherSuite, ManualClock, WireCodec, DEFAULT_MSG_TTL},
+        controller_plane::ControllerPlaneCfg,
+        data_plane::{DataPlaneCfg, NetOutput, NetPair},
+        secure::{HandshakeBuilderXDA, StaticKeyAuthorization},
+        ExtIn,
+    };
+
+    use super::{Node, NodeCfg, NodeOutput};
+
+    type TestNode = Node<(), (), (), (), ()>;
+
+    fn addr(node: NodeId) -> SocketAddr {
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, node as u16))
+    }
+
+    fn create_node(node_id: NodeId, clock: Arc<ManualClock>) -> TestNode {
+        let mut history = MockShadowRouterHistory::new();
+        history.expect_already_received_broadcast().return_const(false);
+        history.expect_set_ts().return_const(());
+        let history = Arc::new(history);
+        Node::new(NodeCfg {
+            node_id,
+            tick_ms: 1,
+            controller: ControllerPlaneCfg {
+                session: node_id as u64,
+                bind_addrs: vec![addr(node_id)],
+                services: vec![],
+                authorization: Arc::new(StaticKeyAuthorization::new("demo-key")),
+                handshake_builder: Arc::new(HandshakeBuilderXDA),
+                identity: None,
+                accept_policy: Arc::new(AcceptAll),
+                random: Box::new(StepRng::new(node_id as u64 * 1000, 1)),
+                history: history.clone(),
+                unknown_service: Default::default(),
+                router_sync: Default::default(),
+                dht_kv: Default::default(),
+                data: Default::default(),
+                neighbours: Default::default(),
+                vpn: Default::default(),
+                pubsub: Default::default(),
+                cipher_suites: CipherSuite::DEFAULT_PREFERENCE.to_vec(),
+                codecs: WireCodec::DEFAULT_PREFERENCE.to_vec(),
+                feature_weights: Default::default(),
+                feature_versions: Default::default(),
+                features: Default::default(),
+                resolver: None,
+            },
+            data: DataPlaneCfg {
+                worker_id: 0,
+                services: vec![],
+                history,
+                unknown_service: Default::default(),
+                random: Box::new(StepRng::new(0, 1)),
+                rekey: Default::default(),
+                max_ttl: DEFAULT_MSG_TTL,
+                output_queue: Default::default(),
+                feature_weights: Default::default(),
+                dscp: Default::default(),
+                shapers: Default::default(),
+                features: Default::default(),
+                max_payloads: Default::default(),
+            },
+            feature_targets: HashMap::new(),
+            clock,
+        })
+    }
+
+    /// Drain outputs of all nodes and deliver their packets, returns how many packets were delivered
+    fn exchange(now: u64, nodes: &mut [TestNode]) -> usize {
+        let mut delivered = 0;
+        loop {
+            let mut packets = vec![];
+            for node in nodes.iter_mut() {
+                while let Some(out) = node.pop_output(now) {
+                    match out {
+                        NodeOutput::Net(NetOutput::UdpPacket(pair, buf)) => packets.push((pair, buf)),
+                        NodeOutput::Net(NetOutput::UdpPackets(pairs, buf)) => packets.extend(pairs.into_iter().map(|pair| (pair, buf.clone()))),
+                        NodeOutput::Net(NetOutput::UdpBatch(batch)) => packets.extend(batch),
+                        NodeOutput::Net(NetOutput::UdpMarked(pair, _dscp, buf)) => packets.push((pair, buf)),
+                        out => panic!("unexpected output {out:?}"),
+                    }
+                }
+            }
+            if packets.is_empty() {
+                return delivered;
+            }
+            for (pair, buf) in packets {
+                let node = nodes.iter_mut().find(|node| addr(node.node_id()) == pair.remote).expect("Should have dest node");
+                node.on_udp(now, NetPair::new(pair.remote, pair.local), buf);
+                delivered += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn nodes_connect_by_stepping() {
+        //both nodes follow the same external clock
+        let clock = Arc::new(ManualClock::default());
+        let mut nodes = [create_node(1, clock.clone()), create_node(2, clock.clone())];
+        let mut builder = NodeAddrBuilder::new(2);
+        builder.add_protocol(Protocol::Ip4(Ipv4Addr::LOCALHOST));
+        builder.add_protocol(Protocol::Udp(2));
+        nodes[0].on_ext(0, ExtIn::ConnectTo(builder.addr()));
+
+        let mut delivered = 0;
+        for _ in 0..10 {
+            let now = nodes[0].now_ms();
+            for node in nodes.iter_mut() {
+                node.on_tick(now);
+            }
+            delivered += exchange(now, &mut nodes);
+            clock.advance_ms(100);
+        }
+
+        assert!(delivered > 0);
+        assert_eq!(nodes[0].connection_counts().established, 1);
+        assert_eq!(nodes[1].connection_counts().established, 1);
+
+        assert_eq!(nodes[1].now_ms(), 1000);
+        for node in nodes.iter_mut() {
+            node.on_shutdown(1000);
+        }
+        exchange(1000, &mut nodes);
+        assert!(nodes.iter().all(|node| node.is_empty()));
+    }
+}
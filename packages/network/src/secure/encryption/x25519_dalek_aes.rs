@@ -1,18 +1,77 @@
 use std::{
     fmt::Debug,
     ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
 };
 
 use aes_gcm::{
-    aead::{AeadMutInPlace, Buffer},
-    AeadCore, Aes256Gcm, Key, KeyInit, Nonce,
+    aead::{consts::U12, AeadMutInPlace, Buffer},
+    Aes256Gcm, KeyInit, Nonce,
 };
-use rand::rngs::OsRng;
+use chacha20poly1305::ChaCha20Poly1305;
+use sha2::{Digest, Sha256};
 use x25519_dalek::{EphemeralSecret, PublicKey};
 
-use crate::base::{Buffer as BufferMut, DecryptionError, Decryptor, EncryptionError, Encryptor, HandshakeBuilder, HandshakeError, HandshakeRequester, HandshakeResponder};
+use crate::base::{Buffer as BufferMut, CipherSuite, DecryptionError, Decryptor, EncryptionError, Encryptor, HandshakeBuilder, HandshakeError, HandshakeRequester, HandshakeResponder};
 
 const MSG_TIMEOUT_MS: u64 = 5000; // after 5 seconds message is considered expired
+const REKEY_INTERVAL_MS: u64 = 10 * 60 * 1000; // each 10 minutes both sides switch to a new derived key
+/// Set in the first nonce byte by the responder side. Both directions share the key and their sequences both start
+/// at 0, so without it two packets sent in the same ms from each side would use the same nonce.
+/// The timestamp only needs 47 bits until year 6429
+const RESPONDER_NONCE_FLAG: u8 = 0x80;
+
+/// Derive the cipher key of an epoch from the handshake shared key.
+/// The epoch is taken from the sender timestamp, which is already carried inside the nonce, so both sides agree without any extra message.
+/// Epoch keys only limit how much traffic is sealed by one key, they are not forward secret:
+/// anyone who learns the shared key can derive the key of every epoch.
+fn derive_cipher(suite: CipherSuite, shared_key: &[u8], epoch: u64) -> Aead {
+    let mut hasher = Sha256::new();
+    hasher.update(b"atm0s-sdn-rekey");
+    hasher.update(shared_key);
+    hasher.update(epoch.to_be_bytes());
+    let key = hasher.finalize();
+    match suite {
+        CipherSuite::Aes256Gcm => Aead::Aes(Box::new(Aes256Gcm::new(&key))),
+        CipherSuite::ChaCha20Poly1305 => Aead::ChaCha(Box::new(ChaCha20Poly1305::new(&key))),
+    }
+}
+
+/// Bind the cipher negotiation into the key, so a changed offer or selection on the way makes both sides derive different keys
+fn session_key(shared_key: &[u8; 32], offered: &[CipherSuite], selected: CipherSuite) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"atm0s-sdn-handshake");
+    hasher.update(shared_key);
+    hasher.update([offered.len() as u8]);
+    hasher.update(offered.iter().map(|suite| *suite as u8).collect::<Vec<_>>());
+    hasher.update([selected as u8]);
+    hasher.finalize().into()
+}
+
+#[derive(Clone)]
+enum Aead {
+    Aes(Box<Aes256Gcm>),
+    ChaCha(Box<ChaCha20Poly1305>),
+}
+
+impl Aead {
+    fn encrypt_in_place(&mut self, nonce: &Nonce<U12>, buf: &mut BufferMut2) -> aes_gcm::aead::Result<()> {
+        match self {
+            Aead::Aes(aead) => aead.encrypt_in_place(nonce, &[], buf),
+            Aead::ChaCha(aead) => aead.encrypt_in_place(nonce, &[], buf),
+        }
+    }
+
+    fn decrypt_in_place(&mut self, nonce: &Nonce<U12>, buf: &mut BufferMut2) -> aes_gcm::aead::Result<()> {
+        match self {
+            Aead::Aes(aead) => aead.decrypt_in_place(nonce, &[], buf),
+            Aead::ChaCha(aead) => aead.decrypt_in_place(nonce, &[], buf),
+        }
+    }
+}
 
 pub struct HandshakeBuilderXDA;
 
@@ -42,11 +101,12 @@ impl HandshakeRequester for HandshakeRequesterXDA {
         Ok(PublicKey::from(key).as_bytes().to_vec())
     }
 
-    fn process_public_response(&mut self, response: &[u8]) -> Result<(Box<dyn Encryptor>, Box<dyn Decryptor>), HandshakeError> {
+    fn process_public_response(&mut self, response: &[u8], offered: &[CipherSuite], cipher: CipherSuite) -> Result<(Box<dyn Encryptor>, Box<dyn Decryptor>), HandshakeError> {
         let buf: [u8; 32] = response.try_into().map_err(|_| HandshakeError::InvalidPublicKey)?;
         let public = PublicKey::from(buf);
         let shared_key = self.key.take().ok_or(HandshakeError::InvalidState)?.diffie_hellman(&public);
-        Ok((Box::new(EncryptorXDA::new(shared_key.as_bytes())), Box::new(DecryptorXDA::new(shared_key.as_bytes()))))
+        let key = session_key(shared_key.as_bytes(), offered, cipher);
+        Ok((Box::new(EncryptorXDA::new(cipher, &key, false)), Box::new(DecryptorXDA::new(cipher, &key, true))))
     }
 }
 
@@ -61,72 +121,125 @@ impl Default for HandshakeResponderXDA {
 }
 
 impl HandshakeResponder for HandshakeResponderXDA {
-    fn process_public_request(&mut self, request: &[u8]) -> Result<(Box<dyn Encryptor>, Box<dyn Decryptor>, Vec<u8>), HandshakeError> {
+    fn process_public_request(&mut self, request: &[u8], offered: &[CipherSuite], cipher: CipherSuite) -> Result<(Box<dyn Encryptor>, Box<dyn Decryptor>, Vec<u8>), HandshakeError> {
         let buf: [u8; 32] = request.try_into().map_err(|_| HandshakeError::InvalidPublicKey)?;
         let key = self.key.take().ok_or(HandshakeError::InvalidState)?;
         let public = PublicKey::from(buf);
         let response = PublicKey::from(&key).as_bytes().to_vec();
         let shared_key = key.diffie_hellman(&public);
-        Ok((Box::new(EncryptorXDA::new(shared_key.as_bytes())), Box::new(DecryptorXDA::new(shared_key.as_bytes())), response))
+        let key = session_key(shared_key.as_bytes(), offered, cipher);
+        Ok((Box::new(EncryptorXDA::new(cipher, &key, true)), Box::new(DecryptorXDA::new(cipher, &key, false)), response))
     }
 }
 
 struct EncryptorXDA {
+    suite: CipherSuite,
     key: Vec<u8>,
-    aes: Aes256Gcm,
+    /// RESPONDER_NONCE_FLAG if this side is the responder of the handshake, else 0
+    nonce_flag: u8,
+    epoch: u64,
+    aead: Aead,
+    /// Packet sequence, shared between clones so packets sent by different workers never reuse a nonce
+    seq: Arc<AtomicU64>,
 }
 
 impl Debug for EncryptorXDA {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str("EncryptorXDA")
+        write!(f, "EncryptorXDA({:?})", self.suite)
     }
 }
 
 impl EncryptorXDA {
-    pub fn new(shared_key: &[u8; 32]) -> Self {
-        let key = Key::<Aes256Gcm>::from_slice(shared_key);
+    pub fn new(suite: CipherSuite, shared_key: &[u8; 32], responder: bool) -> Self {
         Self {
+            suite,
             key: shared_key.to_vec(),
-            aes: Aes256Gcm::new(key),
+            nonce_flag: if responder {
+                RESPONDER_NONCE_FLAG
+            } else {
+                0
+            },
+            epoch: 0,
+            aead: derive_cipher(suite, shared_key, 0),
+            seq: Default::default(),
         }
     }
 }
 
 impl Encryptor for EncryptorXDA {
     fn encrypt<'a>(&mut self, now_ms: u64, buf: &mut BufferMut) -> Result<(), EncryptionError> {
-        let mut nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-        nonce[4..].copy_from_slice(&now_ms.to_be_bytes());
-        self.aes.encrypt_in_place(&nonce, &[], &mut BufferMut2(buf)).map_err(|_| EncryptionError::EncryptFailed)?;
+        let epoch = now_ms / REKEY_INTERVAL_MS;
+        if epoch != self.epoch {
+            self.aead = derive_cipher(self.suite, &self.key, epoch);
+            self.epoch = epoch;
+        }
+        //nonce is 1 bit sender side, 47 bits sender timestamp then 48 bits packet sequence
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        let mut nonce = Nonce::<U12>::default();
+        nonce[0..6].copy_from_slice(&now_ms.to_be_bytes()[2..]);
+        nonce[0] |= self.nonce_flag;
+        nonce[6..].copy_from_slice(&seq.to_be_bytes()[2..]);
+        self.aead.encrypt_in_place(&nonce, &mut BufferMut2(buf)).map_err(|_| EncryptionError::EncryptFailed)?;
         buf.push_back(&nonce);
         Ok(())
     }
 
+    fn key_epoch(&self) -> u64 {
+        self.epoch
+    }
+
     fn clone_box(&self) -> Box<dyn Encryptor> {
         Box::new(Self {
-            aes: self.aes.clone(),
+            suite: self.suite,
+            aead: self.aead.clone(),
+            epoch: self.epoch,
             key: self.key.clone(),
+            nonce_flag: self.nonce_flag,
+            seq: self.seq.clone(),
         })
     }
 }
 
 struct DecryptorXDA {
+    suite: CipherSuite,
     key: Vec<u8>,
-    aes: Aes256Gcm,
+    /// Nonce flag of the remote side, packets with our own flag are reflected and rejected
+    nonce_flag: u8,
+    current: (u64, Aead),
+    /// Keep the previous epoch key for packets which are still in-flight around the switching time
+    previous: Option<(u64, Aead)>,
 }
 
 impl DecryptorXDA {
-    pub fn new(shared_key: &[u8; 32]) -> Self {
-        let key = Key::<Aes256Gcm>::from_slice(shared_key);
+    pub fn new(suite: CipherSuite, shared_key: &[u8; 32], remote_responder: bool) -> Self {
         Self {
+            suite,
             key: shared_key.to_vec(),
-            aes: Aes256Gcm::new(key),
+            nonce_flag: if remote_responder {
+                RESPONDER_NONCE_FLAG
+            } else {
+                0
+            },
+            current: (0, derive_cipher(suite, shared_key, 0)),
+            previous: None,
+        }
+    }
+
+    fn aead_for(&mut self, epoch: u64) -> &mut Aead {
+        if self.current.0 != epoch && self.previous.as_ref().map(|p| p.0) != Some(epoch) {
+            let old = std::mem::replace(&mut self.current, (epoch, derive_cipher(self.suite, &self.key, epoch)));
+            self.previous = Some(old);
+        }
+        match &mut self.previous {
+            Some((prev_epoch, aes)) if *prev_epoch == epoch => aes,
+            _ => &mut self.current.1,
         }
     }
 }
 
 impl Debug for DecryptorXDA {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str("EncryptorXDA")
+        write!(f, "DecryptorXDA({:?})", self.suite)
     }
 }
 
@@ -137,19 +250,30 @@ impl Decryptor for DecryptorXDA {
         } else {
             return Err(DecryptionError::TooSmall);
         };
-        let sent_ts = u64::from_be_bytes(nonce[4..12].try_into().expect("should be 8 bytes"));
+        if nonce[0] & RESPONDER_NONCE_FLAG != self.nonce_flag {
+            return Err(DecryptionError::DecryptError);
+        }
+        let mut sent_ts = [0; 8];
+        sent_ts[2..].copy_from_slice(&nonce[0..6]);
+        sent_ts[2] &= !RESPONDER_NONCE_FLAG;
+        let sent_ts = u64::from_be_bytes(sent_ts);
         if sent_ts + MSG_TIMEOUT_MS < now_ms {
             return Err(DecryptionError::TooOld);
         }
         let nonce = Nonce::from_slice(&nonce);
-        self.aes.decrypt_in_place(nonce, &[], &mut BufferMut2(data)).map_err(|_| DecryptionError::DecryptError)?;
+        self.aead_for(sent_ts / REKEY_INTERVAL_MS)
+            .decrypt_in_place(nonce, &mut BufferMut2(data))
+            .map_err(|_| DecryptionError::DecryptError)?;
         Ok(())
     }
 
     fn clone_box(&self) -> Box<dyn Decryptor> {
         Box::new(Self {
-            aes: self.aes.clone(),
+            suite: self.suite,
             key: self.key.clone(),
+            nonce_flag: self.nonce_flag,
+            current: self.current.clone(),
+            previous: self.previous.clone(),
         })
     }
 }
@@ -191,17 +315,21 @@ impl<'a> AsMut<[u8]> for BufferMut2<'a> {
 mod tests {
     use std::ops::Deref;
 
-    use crate::base::{Buffer as BufferMut, HandshakeRequester, HandshakeResponder};
+    use crate::base::{Buffer as BufferMut, CipherSuite, HandshakeRequester, HandshakeResponder};
 
-    use super::{HandshakeRequesterXDA, HandshakeResponderXDA};
+    use super::{HandshakeRequesterXDA, HandshakeResponderXDA, REKEY_INTERVAL_MS};
 
     #[test]
     fn simple_encryption() {
         let mut client = HandshakeRequesterXDA::default();
         let mut server = HandshakeResponderXDA::default();
 
-        let (mut s_encrypt, mut s_decrypt, res) = server.process_public_request(client.create_public_request().expect("").as_slice()).expect("Should ok");
-        let (mut c_encrypt, mut c_decrypt) = client.process_public_response(res.as_slice()).expect("Should ok");
+        let (mut s_encrypt, mut s_decrypt, res) = server
+            .process_public_request(client.create_public_request().expect("").as_slice(), &CipherSuite::DEFAULT_PREFERENCE, CipherSuite::Aes256Gcm)
+            .expect("Should ok");
+        let (mut c_encrypt, mut c_decrypt) = client
+            .process_public_response(res.as_slice(), &CipherSuite::DEFAULT_PREFERENCE, CipherSuite::Aes256Gcm)
+            .expect("Should ok");
 
         let msg = [1, 2, 3, 4];
 
@@ -218,13 +346,45 @@ mod tests {
         assert_eq!(buf2.deref(), msg);
     }
 
+    #[test]
+    fn both_sides_should_use_different_nonces() {
+        let mut client = HandshakeRequesterXDA::default();
+        let mut server = HandshakeResponderXDA::default();
+
+        let (mut s_encrypt, mut s_decrypt, res) = server
+            .process_public_request(client.create_public_request().expect("").as_slice(), &CipherSuite::DEFAULT_PREFERENCE, CipherSuite::Aes256Gcm)
+            .expect("Should ok");
+        let (mut c_encrypt, mut c_decrypt) = client
+            .process_public_response(res.as_slice(), &CipherSuite::DEFAULT_PREFERENCE, CipherSuite::Aes256Gcm)
+            .expect("Should ok");
+
+        let mut buf1 = BufferMut::build(&[1, 2, 3, 4], 0, 1000);
+        s_encrypt.encrypt(123, &mut buf1).expect("Should ok");
+        let mut buf2 = BufferMut::build(&[1, 2, 3, 4], 0, 1000);
+        c_encrypt.encrypt(123, &mut buf2).expect("Should ok");
+        assert_ne!(buf1[buf1.len() - 12..], buf2[buf2.len() - 12..]);
+
+        //a packet reflected back to its sender is rejected
+        let mut reflected = BufferMut::from(buf1.to_vec());
+        assert!(s_decrypt.decrypt(124, &mut reflected).is_err());
+
+        c_decrypt.decrypt(124, &mut buf1).expect("Should ok");
+        s_decrypt.decrypt(124, &mut buf2).expect("Should ok");
+        assert_eq!(buf1.deref(), &[1, 2, 3, 4]);
+        assert_eq!(buf2.deref(), &[1, 2, 3, 4]);
+    }
+
     #[test]
     fn unordered_encryption() {
         let mut client = HandshakeRequesterXDA::default();
         let mut server = HandshakeResponderXDA::default();
 
-        let (mut s_encrypt, _s_decrypt, res) = server.process_public_request(client.create_public_request().expect("").as_slice()).expect("Should ok");
-        let (_c_encrypt, mut c_decrypt) = client.process_public_response(res.as_slice()).expect("Should ok");
+        let (mut s_encrypt, _s_decrypt, res) = server
+            .process_public_request(client.create_public_request().expect("").as_slice(), &CipherSuite::DEFAULT_PREFERENCE, CipherSuite::Aes256Gcm)
+            .expect("Should ok");
+        let (_c_encrypt, mut c_decrypt) = client
+            .process_public_response(res.as_slice(), &CipherSuite::DEFAULT_PREFERENCE, CipherSuite::Aes256Gcm)
+            .expect("Should ok");
 
         let mut buf1 = BufferMut::build(&[0, 0, 0, 1], 0, 1000);
         s_encrypt.encrypt(123, &mut buf1).expect("Should ok");
@@ -247,8 +407,12 @@ mod tests {
         let mut client = HandshakeRequesterXDA::default();
         let mut server = HandshakeResponderXDA::default();
 
-        let (s_encrypt, _s_decrypt, res) = server.process_public_request(client.create_public_request().expect("").as_slice()).expect("Should ok");
-        let (_c_encrypt, c_decrypt) = client.process_public_response(res.as_slice()).expect("Should ok");
+        let (s_encrypt, _s_decrypt, res) = server
+            .process_public_request(client.create_public_request().expect("").as_slice(), &CipherSuite::DEFAULT_PREFERENCE, CipherSuite::Aes256Gcm)
+            .expect("Should ok");
+        let (_c_encrypt, c_decrypt) = client
+            .process_public_response(res.as_slice(), &CipherSuite::DEFAULT_PREFERENCE, CipherSuite::Aes256Gcm)
+            .expect("Should ok");
 
         let mut s_enc_threads = Vec::new();
         let mut c_dec_threads = Vec::new();
@@ -273,4 +437,86 @@ mod tests {
             assert_eq!(buf.deref(), msg);
         }
     }
+
+    #[test]
+    fn rekey_after_interval() {
+        let mut client = HandshakeRequesterXDA::default();
+        let mut server = HandshakeResponderXDA::default();
+
+        let (mut s_encrypt, _s_decrypt, res) = server
+            .process_public_request(client.create_public_request().expect("").as_slice(), &CipherSuite::DEFAULT_PREFERENCE, CipherSuite::Aes256Gcm)
+            .expect("Should ok");
+        let (_c_encrypt, mut c_decrypt) = client
+            .process_public_response(res.as_slice(), &CipherSuite::DEFAULT_PREFERENCE, CipherSuite::Aes256Gcm)
+            .expect("Should ok");
+
+        let mut buf1 = BufferMut::build(&[0, 0, 0, 1], 0, 1000);
+        s_encrypt.encrypt(REKEY_INTERVAL_MS - 1, &mut buf1).expect("Should ok");
+        assert_eq!(s_encrypt.key_epoch(), 0);
+
+        let mut buf2 = BufferMut::build(&[0, 0, 0, 2], 0, 1000);
+        s_encrypt.encrypt(REKEY_INTERVAL_MS, &mut buf2).expect("Should ok");
+        assert_eq!(s_encrypt.key_epoch(), 1);
+
+        //new epoch packet arrive first, older epoch packet still decrypt ok
+        c_decrypt.decrypt(REKEY_INTERVAL_MS, &mut buf2).expect("Should ok");
+        c_decrypt.decrypt(REKEY_INTERVAL_MS, &mut buf1).expect("Should ok");
+        assert_eq!(buf1.deref(), &[0, 0, 0, 1]);
+        assert_eq!(buf2.deref(), &[0, 0, 0, 2]);
+    }
+
+    #[test]
+    fn chacha20_encryption() {
+        let mut client = HandshakeRequesterXDA::default();
+        let mut server = HandshakeResponderXDA::default();
+
+        let (mut s_encrypt, _s_decrypt, res) = server
+            .process_public_request(client.create_public_request().expect("").as_slice(), &CipherSuite::DEFAULT_PREFERENCE, CipherSuite::ChaCha20Poly1305)
+            .expect("Should ok");
+        let (_c_encrypt, mut c_decrypt) = client
+            .process_public_response(res.as_slice(), &CipherSuite::DEFAULT_PREFERENCE, CipherSuite::ChaCha20Poly1305)
+            .expect("Should ok");
+
+        let msg = [1, 2, 3, 4];
+        let mut buf = BufferMut::build(&msg, 0, 1000);
+        s_encrypt.encrypt(123, &mut buf).expect("Should ok");
+        assert_eq!(buf.len(), msg.len() + CipherSuite::ChaCha20Poly1305.overhead());
+        c_decrypt.decrypt(124, &mut buf).expect("Should ok");
+        assert_eq!(buf.deref(), msg);
+    }
+
+    #[test]
+    fn mismatched_cipher_should_fail() {
+        let mut client = HandshakeRequesterXDA::default();
+        let mut server = HandshakeResponderXDA::default();
+
+        let (mut s_encrypt, _s_decrypt, res) = server
+            .process_public_request(client.create_public_request().expect("").as_slice(), &CipherSuite::DEFAULT_PREFERENCE, CipherSuite::ChaCha20Poly1305)
+            .expect("Should ok");
+        let (_c_encrypt, mut c_decrypt) = client
+            .process_public_response(res.as_slice(), &CipherSuite::DEFAULT_PREFERENCE, CipherSuite::Aes256Gcm)
+            .expect("Should ok");
+
+        let mut buf = BufferMut::build(&[1, 2, 3, 4], 0, 1000);
+        s_encrypt.encrypt(123, &mut buf).expect("Should ok");
+        assert!(c_decrypt.decrypt(124, &mut buf).is_err());
+    }
+
+    #[test]
+    fn tampered_offer_should_fail() {
+        let mut client = HandshakeRequesterXDA::default();
+        let mut server = HandshakeResponderXDA::default();
+
+        //offer is downgraded on the way, the server only sees ChaCha20-Poly1305
+        let (mut s_encrypt, _s_decrypt, res) = server
+            .process_public_request(client.create_public_request().expect("").as_slice(), &[CipherSuite::ChaCha20Poly1305], CipherSuite::ChaCha20Poly1305)
+            .expect("Should ok");
+        let (_c_encrypt, mut c_decrypt) = client
+            .process_public_response(res.as_slice(), &CipherSuite::DEFAULT_PREFERENCE, CipherSuite::ChaCha20Poly1305)
+            .expect("Should ok");
+
+        let mut buf = BufferMut::build(&[1, 2, 3, 4], 0, 1000);
+        s_encrypt.encrypt(123, &mut buf).expect("Should ok");
+        assert!(c_decrypt.decrypt(124, &mut buf).is_err());
+    }
 }
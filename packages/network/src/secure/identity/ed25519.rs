@@ -0,0 +1,99 @@
+//! Ed25519 node identity, the node id is derived from the public key.
+//!
+//! A node which has an identity signs its side of the neighbour handshake, so the peer can check that the node id
+//! belongs to the key which signed it. Each side signs a message which contains the ephemeral handshake of the
+//! requester, so a replayed handshake can't produce a connection without the ephemeral secret.
+//!
+//! The node id is only 32 bits of the key hash, this protects against nodes claiming ids of others but a determined
+//! attacker can still search a key for a chosen id.
+
+use atm0s_sdn_identity::NodeId;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha2::Digest;
+
+use crate::base::IdentityProof;
+
+pub struct NodeIdentity {
+    key: SigningKey,
+}
+
+impl NodeIdentity {
+    pub fn generate<R: rand::RngCore + rand::CryptoRng>(rng: &mut R) -> Self {
+        Self { key: SigningKey::generate(rng) }
+    }
+
+    pub fn from_secret(secret: [u8; 32]) -> Self {
+        Self { key: SigningKey::from_bytes(&secret) }
+    }
+
+    pub fn secret(&self) -> [u8; 32] {
+        self.key.to_bytes()
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        self.key.verifying_key().to_bytes()
+    }
+
+    /// Node id of this identity, the node must run with it for its proofs to be valid
+    pub fn node_id(&self) -> NodeId {
+        Self::node_id_of(&self.public_key())
+    }
+
+    /// First 4 bytes of the sha256 of the public key
+    pub fn node_id_of(public_key: &[u8; 32]) -> NodeId {
+        let hash = sha2::Sha256::digest(public_key);
+        NodeId::from_be_bytes([hash[0], hash[1], hash[2], hash[3]])
+    }
+
+    pub fn prove(&self, msg: &[u8]) -> IdentityProof {
+        IdentityProof {
+            public_key: self.public_key(),
+            signature: self.key.sign(msg).to_bytes().to_vec(),
+        }
+    }
+
+    /// Check that `proof` is a signature of `msg` by a key which `node` is derived from
+    pub fn verify(node: NodeId, msg: &[u8], proof: &IdentityProof) -> bool {
+        if Self::node_id_of(&proof.public_key) != node {
+            return false;
+        }
+        let key = match VerifyingKey::from_bytes(&proof.public_key) {
+            Ok(key) => key,
+            Err(_) => return false,
+        };
+        let signature = match Signature::from_slice(&proof.signature) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+        key.verify(msg, &signature).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_verify_own_proof() {
+        let identity = NodeIdentity::from_secret([1; 32]);
+        let proof = identity.prove(b"hello");
+        assert!(NodeIdentity::verify(identity.node_id(), b"hello", &proof));
+        assert!(!NodeIdentity::verify(identity.node_id(), b"other", &proof));
+        assert_eq!(NodeIdentity::from_secret(identity.secret()).node_id(), identity.node_id());
+    }
+
+    #[test]
+    fn should_reject_spoofed_node_id() {
+        let identity = NodeIdentity::from_secret([1; 32]);
+        let other = NodeIdentity::from_secret([2; 32]);
+        let proof = identity.prove(b"hello");
+        assert!(!NodeIdentity::verify(other.node_id(), b"hello", &proof));
+
+        //own key of the claimed id but a forged signature
+        let mut forged = other.prove(b"hello");
+        forged.signature = proof.signature.clone();
+        assert!(!NodeIdentity::verify(other.node_id(), b"hello", &forged));
+        forged.signature.truncate(10);
+        assert!(!NodeIdentity::verify(other.node_id(), b"hello", &forged));
+    }
+}
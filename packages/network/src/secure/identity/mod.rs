@@ -0,0 +1,2 @@
+mod ed25519;
+pub use ed25519::NodeIdentity;
@@ -1,5 +1,7 @@
 mod authorization;
 mod encryption;
+mod identity;
 
 pub use authorization::*;
 pub use encryption::*;
+pub use identity::*;
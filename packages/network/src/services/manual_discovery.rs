@@ -1,11 +1,12 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     fmt::Debug,
 };
 
-use atm0s_sdn_identity::{ConnId, NodeAddr, NodeId};
+use atm0s_sdn_identity::{ConnId, NodeAddr, NodeId, NodeIdType};
 use atm0s_sdn_utils::hash::hash_str;
-use sans_io_runtime::collections::DynamicDeque;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use sans_io_runtime::{collections::DynamicDeque, return_if_none};
 
 use crate::{
     base::{ConnectionEvent, Service, ServiceBuilder, ServiceCtx, ServiceInput, ServiceOutput, ServiceSharedInput, ServiceWorker, ServiceWorkerCtx, ServiceWorkerInput, ServiceWorkerOutput},
@@ -30,12 +31,60 @@ fn neighbour_control<UserData, SE, TW>(c: NeighbourControl) -> ServiceOutput<Use
     ServiceOutput::FeatureControl(FeaturesControl::Neighbours(c))
 }
 
+/// Pick up to `slots` nodes from `candidates` for connecting.
+///
+/// Candidates are grouped into buckets by xor distance bits to the local node. Only candidates
+/// in the least used buckets (counting `selected`) compete in each round, so connections spread
+/// over the id space instead of clustering around nearby ids. Inside a round, the pick is random
+/// and weighted by the square of the bucket index, which favors far nodes.
+fn select_spread(local: NodeId, selected: &[NodeId], mut candidates: Vec<NodeId>, slots: usize, rng: &mut StdRng) -> Vec<NodeId> {
+    let mut used = [0usize; 33];
+    for node in selected {
+        used[local.distance_bits(node) as usize] += 1;
+    }
+
+    let mut picked = vec![];
+    while picked.len() < slots && !candidates.is_empty() {
+        let min_used = candidates.iter().map(|node| used[local.distance_bits(node) as usize]).min().unwrap_or(0);
+        let weights = candidates
+            .iter()
+            .map(|node| {
+                let bucket = local.distance_bits(node) as u64;
+                if used[bucket as usize] == min_used {
+                    (bucket * bucket).max(1)
+                } else {
+                    0
+                }
+            })
+            .collect::<Vec<_>>();
+        let mut point = rng.gen_range(0..weights.iter().sum::<u64>());
+        let index = weights
+            .iter()
+            .position(|weight| {
+                if point < *weight {
+                    true
+                } else {
+                    point -= weight;
+                    false
+                }
+            })
+            .expect("should have a candidate for a point inside total weight");
+        let node = candidates.swap_remove(index);
+        used[local.distance_bits(&node) as usize] += 1;
+        picked.push(node);
+    }
+    picked
+}
+
 pub struct ManualDiscoveryService<UserData, SC, SE, TC, TW> {
     node_addr: NodeAddr,
     queue: VecDeque<ServiceOutput<UserData, FeaturesControl, SE, TW>>,
     nodes: HashMap<NodeId, NodeAddr>,
     conns: HashMap<NodeId, Vec<ConnId>>,
     removing_list: HashMap<NodeId, u64>,
+    max_conns: Option<usize>,
+    selected: HashSet<NodeId>,
+    rng: StdRng,
     last_retry_ms: u64,
     shutdown: bool,
     _tmp: std::marker::PhantomData<(SC, TC, TW)>,
@@ -50,7 +99,7 @@ impl<UserData, SC, SE, TC, TW> ManualDiscoveryService<UserData, SC, SE, TC, TW>
         for local_tag in local_tags.iter() {
             let map = Map(hash_str(local_tag));
             log::info!("Setting local tag: {local_tag} by set key {map}");
-            queue.push_back(kv_control(KvControl::MapCmd(map, MapControl::Set(Key(0), node_addr.to_vec()))));
+            queue.push_back(kv_control(KvControl::MapCmd(map, MapControl::Set(Key(0), node_addr.to_vec(), None))));
         }
 
         for connect_tag in connect_tags.iter() {
@@ -65,17 +114,53 @@ impl<UserData, SC, SE, TC, TW> ManualDiscoveryService<UserData, SC, SE, TC, TW>
             conns: HashMap::new(),
             queue,
             removing_list: HashMap::new(),
+            max_conns: None,
+            selected: HashSet::new(),
+            rng: StdRng::seed_from_u64(0),
             last_retry_ms: 0,
             shutdown: false,
             _tmp: std::marker::PhantomData,
         }
     }
 
+    /// Limit the number of discovered nodes to connect to. When more candidates are learned,
+    /// a spread-out subset is selected with a rng seeded by `seed`, so the result is repeatable.
+    pub fn set_max_connections(&mut self, max_conns: usize, seed: u64) {
+        self.max_conns = Some(max_conns);
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// Nodes currently selected for connecting, only tracked when max connections is set.
+    pub fn selected_nodes(&self) -> &HashSet<NodeId> {
+        &self.selected
+    }
+
+    fn is_selected(&self, node: &NodeId) -> bool {
+        self.max_conns.is_none() || self.selected.contains(node)
+    }
+
+    fn fill_slots(&mut self) {
+        let max_conns = return_if_none!(self.max_conns);
+        if self.selected.len() >= max_conns {
+            return;
+        }
+        let selected = self.selected.iter().copied().collect::<Vec<_>>();
+        let mut candidates = self.nodes.keys().filter(|node| !self.selected.contains(node)).copied().collect::<Vec<_>>();
+        candidates.sort_unstable();
+        for node in select_spread(self.node_addr.node_id(), &selected, candidates, max_conns - selected.len(), &mut self.rng) {
+            log::info!("ManualDiscoveryService selected node {node} => connect");
+            self.selected.insert(node);
+            if let Some(addr) = self.nodes.get(&node) {
+                self.queue.push_back(neighbour_control(NeighbourControl::ConnectTo(addr.clone())));
+            }
+        }
+    }
+
     fn check_nodes(&mut self, now: u64) {
         if self.last_retry_ms + RETRY_CONNECT_MS <= now {
             self.last_retry_ms = now;
             for (node, addr) in self.nodes.iter() {
-                if !self.conns.contains_key(node) {
+                if !self.conns.contains_key(node) && self.is_selected(node) {
                     log::info!("ManualDiscoveryService node {node} not connected, retry connect");
                     self.queue.push_back(neighbour_control(NeighbourControl::ConnectTo(addr.clone())));
                 }
@@ -93,7 +178,11 @@ impl<UserData, SC, SE, TC, TW> ManualDiscoveryService<UserData, SC, SE, TC, TW>
 
         for node in will_disconnect {
             self.removing_list.remove(&node);
+            self.selected.remove(&node);
         }
+
+        // selecting on tick instead of on each learned node lets the selection see a batch of candidates
+        self.fill_slots();
     }
 }
 
@@ -117,7 +206,7 @@ impl<UserData, SC, SE, TC: Debug, TW: Debug> Service<UserData, FeaturesControl,
                 let entry = self.conns.entry(ctx.node).or_default();
                 entry.push(ctx.conn);
             }
-            ServiceSharedInput::Connection(ConnectionEvent::Disconnected(ctx)) => {
+            ServiceSharedInput::Connection(ConnectionEvent::Disconnected(ctx, _)) => {
                 let entry = self.conns.entry(ctx.node).or_default();
                 entry.retain(|&conn| conn != ctx.conn);
 
@@ -140,8 +229,10 @@ impl<UserData, SC, SE, TC: Debug, TW: Debug> Service<UserData, FeaturesControl,
                     if let Some(addr) = NodeAddr::from_vec(&value) {
                         log::info!("ManualDiscoveryService node {source} added tag {map} => connect {addr}");
                         self.nodes.insert(source, addr.clone());
-                        self.queue.push_back(neighbour_control(NeighbourControl::ConnectTo(addr)));
                         self.removing_list.remove(&source);
+                        if self.is_selected(&source) {
+                            self.queue.push_back(neighbour_control(NeighbourControl::ConnectTo(addr)));
+                        }
                     }
                 }
                 MapEvent::OnDel(_, source) => {
@@ -211,6 +302,7 @@ pub struct ManualDiscoveryServiceBuilder<UserData, SC, SE, TC, TW> {
     node_addr: NodeAddr,
     local_tags: Vec<String>,
     connect_tags: Vec<String>,
+    max_conns: Option<(usize, u64)>,
 }
 
 impl<UserData, SC, SE, TC, TW> ManualDiscoveryServiceBuilder<UserData, SC, SE, TC, TW> {
@@ -220,8 +312,14 @@ impl<UserData, SC, SE, TC, TW> ManualDiscoveryServiceBuilder<UserData, SC, SE, T
             node_addr,
             local_tags,
             connect_tags,
+            max_conns: None,
         }
     }
+
+    /// Only connect to a spread-out subset of at most `max_conns` discovered nodes, see [`ManualDiscoveryService::set_max_connections`].
+    pub fn set_max_connections(&mut self, max_conns: usize, seed: u64) {
+        self.max_conns = Some((max_conns, seed));
+    }
 }
 
 impl<UserData, SC, SE, TC, TW> ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW> for ManualDiscoveryServiceBuilder<UserData, SC, SE, TC, TW>
@@ -241,7 +339,11 @@ where
     }
 
     fn create(&self) -> Box<dyn Service<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>> {
-        Box::new(ManualDiscoveryService::new(self.node_addr.clone(), self.local_tags.clone(), self.connect_tags.clone()))
+        let mut service = ManualDiscoveryService::new(self.node_addr.clone(), self.local_tags.clone(), self.connect_tags.clone());
+        if let Some((max_conns, seed)) = self.max_conns {
+            service.set_max_connections(max_conns, seed);
+        }
+        Box::new(service)
     }
 
     fn create_worker(&self) -> Box<dyn ServiceWorker<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>> {
@@ -254,16 +356,19 @@ where
 
 #[cfg(test)]
 mod test {
-    use atm0s_sdn_identity::{ConnId, NodeAddr, NodeAddrBuilder, Protocol};
+    use std::collections::HashSet;
+
+    use atm0s_sdn_identity::{ConnId, NodeAddr, NodeAddrBuilder, NodeId, NodeIdType, Protocol};
     use atm0s_sdn_utils::hash::hash_str;
 
     use crate::{
-        base::{Service, ServiceCtx, ServiceInput, ServiceOutput, ServiceSharedInput},
+        base::{DisconnectReason, Service, ServiceCtx, ServiceInput, ServiceOutput, ServiceSharedInput},
         features::{
             dht_kv::{self, Key, Map, MapControl, MapEvent},
             neighbours, FeaturesControl, FeaturesEvent,
         },
         services::manual_discovery::{RETRY_CONNECT_MS, WAIT_DISCONNECT_MS},
+        FeaturesControl as FC,
     };
 
     use super::ManualDiscoveryService;
@@ -291,6 +396,77 @@ mod test {
         ServiceInput::FeatureEvent(FeaturesEvent::Neighbours(event))
     }
 
+    fn limited_select(local: NodeId, candidates: &[NodeId], max_conns: usize, seed: u64) -> HashSet<NodeId> {
+        let ctx = ServiceCtx { node_id: local, session: 0 };
+        let mut service = ManualDiscoveryService::<(), (), (), (), ()>::new(node_addr(local), vec![], vec!["connect".into()]);
+        service.set_max_connections(max_conns, seed);
+        let connect_map = Map(hash_str("connect"));
+        assert_eq!(service.pop_output2(0), Some(map_cmd(connect_map, MapControl::Sub)));
+
+        let mut connected = HashSet::new();
+        for (i, node) in candidates.iter().enumerate() {
+            service.on_input(&ctx, 100, map_event(connect_map, MapEvent::OnSet(Key(i as u64), *node, node_addr(*node).to_vec())));
+        }
+        assert_eq!(service.pop_output2(100), None);
+
+        service.on_shared_input(&ctx, 1000, ServiceSharedInput::Tick(0));
+        while let Some(out) = service.pop_output2(1000) {
+            match out {
+                ServiceOutput::FeatureControl(FC::Neighbours(neighbours::Control::ConnectTo(addr))) => assert!(connected.insert(addr.node_id())),
+                _ => panic!("unexpected output {out:?}"),
+            }
+        }
+        assert_eq!(&connected, service.selected_nodes());
+        connected
+    }
+
+    #[test]
+    fn should_select_spread_out_subset_when_limited() {
+        let local: NodeId = 0x1000_0000;
+        let near = (1..=20).map(|i| local + i).collect::<Vec<_>>();
+        let far = [local ^ (1 << 31), local ^ (1 << 27), local ^ (1 << 23), local ^ (1 << 19)];
+        let candidates = near.iter().chain(far.iter()).copied().collect::<Vec<_>>();
+
+        let mut far_selected = 0;
+        for seed in 0..10 {
+            let selected = limited_select(local, &candidates, 4, seed);
+            assert_eq!(selected.len(), 4);
+            // never two connections in the same distance bucket while other buckets have candidates
+            let buckets = selected.iter().map(|node| local.distance_bits(node)).collect::<HashSet<_>>();
+            assert_eq!(buckets.len(), 4);
+            far_selected += selected.iter().filter(|node| far.contains(node)).count();
+            // same seed gives same selection
+            assert_eq!(limited_select(local, &candidates, 4, seed), selected);
+        }
+        assert!(far_selected >= 30, "far nodes should be favored, got {far_selected}/40");
+    }
+
+    #[test]
+    fn should_refill_slot_after_selected_node_removed() {
+        let local: NodeId = 0x1000_0000;
+        let ctx = ServiceCtx { node_id: local, session: 0 };
+        let mut service = ManualDiscoveryService::<(), (), (), (), ()>::new(node_addr(local), vec![], vec!["connect".into()]);
+        service.set_max_connections(1, 0);
+        let connect_map = Map(hash_str("connect"));
+        assert_eq!(service.pop_output2(0), Some(map_cmd(connect_map, MapControl::Sub)));
+
+        let node2 = local ^ (1 << 31);
+        let node3 = local ^ (1 << 30);
+        service.on_input(&ctx, 100, map_event(connect_map, MapEvent::OnSet(Key(1), node2, node_addr(node2).to_vec())));
+        service.on_shared_input(&ctx, 100, ServiceSharedInput::Tick(0));
+        assert_eq!(service.pop_output2(100), Some(neighbour_cmd(neighbours::Control::ConnectTo(node_addr(node2)))));
+        service.on_input(&ctx, 150, map_event(connect_map, MapEvent::OnSet(Key(2), node3, node_addr(node3).to_vec())));
+        service.on_shared_input(&ctx, 150, ServiceSharedInput::Tick(0));
+        assert_eq!(service.pop_output2(150), None);
+
+        // selected node removed => after wait disconnect, slot is refilled with other candidate
+        service.on_input(&ctx, 200, map_event(connect_map, MapEvent::OnDel(Key(1), node2)));
+        service.on_shared_input(&ctx, 200 + WAIT_DISCONNECT_MS, ServiceSharedInput::Tick(0));
+        assert_eq!(service.pop_output2(200 + WAIT_DISCONNECT_MS), Some(neighbour_cmd(neighbours::Control::DisconnectFrom(node2))));
+        assert_eq!(service.pop_output2(200 + WAIT_DISCONNECT_MS), Some(neighbour_cmd(neighbours::Control::ConnectTo(node_addr(node3)))));
+        assert_eq!(service.pop_output2(200 + WAIT_DISCONNECT_MS), None);
+    }
+
     #[test]
     fn should_send_connect() {
         let addr1 = node_addr(100);
@@ -301,7 +477,7 @@ mod test {
         let local_map = Map(hash_str("local"));
         let connect_map = Map(hash_str("connect"));
 
-        assert_eq!(service.pop_output2(0), Some(map_cmd(local_map, MapControl::Set(Key(0), addr1.to_vec()))));
+        assert_eq!(service.pop_output2(0), Some(map_cmd(local_map, MapControl::Set(Key(0), addr1.to_vec(), None))));
         assert_eq!(service.pop_output2(0), Some(map_cmd(connect_map, MapControl::Sub)));
 
         service.on_input(&ctx, 100, map_event(connect_map, MapEvent::OnSet(Key(1), 2, addr2.to_vec())));
@@ -318,7 +494,7 @@ mod test {
         let local_map = Map(hash_str("local"));
         let connect_map = Map(hash_str("connect"));
 
-        assert_eq!(service.pop_output2(0), Some(map_cmd(local_map, MapControl::Set(Key(0), addr1.to_vec()))));
+        assert_eq!(service.pop_output2(0), Some(map_cmd(local_map, MapControl::Set(Key(0), addr1.to_vec(), None))));
         assert_eq!(service.pop_output2(0), Some(map_cmd(connect_map, MapControl::Sub)));
 
         // add node
@@ -352,7 +528,7 @@ mod test {
         let local_map = Map(hash_str("local"));
         let connect_map = Map(hash_str("connect"));
 
-        assert_eq!(service.pop_output2(0), Some(map_cmd(local_map, MapControl::Set(Key(0), addr1.to_vec()))));
+        assert_eq!(service.pop_output2(0), Some(map_cmd(local_map, MapControl::Set(Key(0), addr1.to_vec(), None))));
         assert_eq!(service.pop_output2(0), Some(map_cmd(connect_map, MapControl::Sub)));
 
         service.on_input(&ctx, 100, map_event(connect_map, MapEvent::OnSet(Key(1), 2, addr2.to_vec())));
@@ -361,7 +537,11 @@ mod test {
         service.on_shared_input(&ctx, 200, ServiceSharedInput::Tick(0));
         assert_eq!(service.pop_output2(200), None);
 
-        service.on_input(&ctx, 300, neighbour_event(neighbours::Event::Disconnected(addr2.node_id(), ConnId::from_out(0, 0))));
+        service.on_input(
+            &ctx,
+            300,
+            neighbour_event(neighbours::Event::Disconnected(addr2.node_id(), ConnId::from_out(0, 0), DisconnectReason::Timeout)),
+        );
 
         service.on_shared_input(&ctx, 300, ServiceSharedInput::Tick(0));
         assert_eq!(service.pop_output2(300), None);
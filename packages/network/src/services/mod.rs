@@ -1,2 +1,5 @@
 pub mod manual_discovery;
+mod scheduler;
 pub mod visualization;
+
+pub(crate) use scheduler::ServiceScheduler;
@@ -0,0 +1,65 @@
+//! Fair order of popping service outputs.
+//!
+//! Like the `FeatureScheduler`, but switcher slots are the service ids and each service has its own output budget
+//! from `ServiceBuilder::output_budget`. A service pops at most `budget` outputs per turn, then it is unflagged until
+//! all other flagged services had their turn, so a service which floods outputs can't starve the others.
+
+use sans_io_runtime::TaskSwitcher;
+
+pub struct ServiceScheduler {
+    /// Budget of each switcher slot
+    budgets: Vec<u8>,
+    /// Slot which is in its turn and how many outputs it popped
+    turn: Option<(usize, u8)>,
+    deferred: Vec<usize>,
+}
+
+impl ServiceScheduler {
+    /// Budgets are indexed by service id, a budget of 0 is handled as 1
+    pub fn new(budgets: Vec<u8>) -> Self {
+        Self {
+            deferred: Vec::with_capacity(budgets.len()),
+            budgets: budgets.into_iter().map(|budget| budget.max(1)).collect(),
+            turn: None,
+        }
+    }
+
+    /// Slot which should pop the next output, None if no slot is flagged
+    pub fn current(&mut self, switcher: &mut TaskSwitcher) -> Option<usize> {
+        loop {
+            match switcher.current() {
+                Some(slot) => match self.turn {
+                    Some((turn_slot, used)) if turn_slot == slot && used >= self.budget(slot) => {
+                        switcher.finished(slot);
+                        self.deferred.push(slot);
+                        self.turn = None;
+                    }
+                    Some((turn_slot, _)) if turn_slot == slot => return Some(slot),
+                    _ => {
+                        self.turn = Some((slot, 0));
+                        return Some(slot);
+                    }
+                },
+                None => {
+                    if self.deferred.is_empty() {
+                        return None;
+                    }
+                    for slot in self.deferred.drain(..) {
+                        switcher.flag_task(slot);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The current slot popped an output
+    pub fn on_output(&mut self) {
+        if let Some((_, used)) = &mut self.turn {
+            *used = used.saturating_add(1);
+        }
+    }
+
+    fn budget(&self, slot: usize) -> u8 {
+        self.budgets.get(slot).copied().unwrap_or(1)
+    }
+}
@@ -173,8 +173,15 @@ where
                 });
                 entry.rtt_ms = stats.rtt_ms;
             }
-            ServiceSharedInput::Connection(ConnectionEvent::Disconnected(ctx)) => {
-                log::info!("[Visualization] Connection from {} to {} is disconnected", ctx.pair, ctx.node);
+            ServiceSharedInput::Connection(ConnectionEvent::Rekey(..) | ConnectionEvent::Mtu(..) | ConnectionEvent::ConnectRejected(..)) => {}
+            ServiceSharedInput::Connection(ConnectionEvent::Migrated(ctx, _)) => {
+                if let Some(entry) = self.conns.get_mut(&ctx.conn) {
+                    entry.local = ctx.pair.local;
+                    entry.remote = ctx.pair.remote;
+                }
+            }
+            ServiceSharedInput::Connection(ConnectionEvent::Disconnected(ctx, reason)) => {
+                log::info!("[Visualization] Connection from {} to {} is disconnected, reason {:?}", ctx.pair, ctx.node, reason);
                 self.conns.remove(&ctx.conn);
             }
         }
@@ -328,7 +335,10 @@ mod test {
     use serde::{Deserialize, Serialize};
 
     use crate::{
-        base::{ConnectionCtx, ConnectionEvent, MockDecryptor, MockEncryptor, NetIncomingMeta, NetOutgoingMeta, SecureContext, Service, ServiceCtx, ServiceInput, ServiceSharedInput, Ttl},
+        base::{
+            CipherSuite, ConnectionCtx, ConnectionEvent, DisconnectReason, MockDecryptor, MockEncryptor, NetIncomingMeta, NetOutgoingMeta, SecureContext, Service, ServiceCtx, ServiceInput,
+            ServiceSharedInput, Ttl,
+        },
         data_plane::NetPair,
         features::{
             data::{Control as DataControl, Event as DataEvent},
@@ -352,20 +362,31 @@ mod test {
                 conn: ConnId::from_in(0, node as u64),
                 node,
                 pair: NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair"),
+                features: Default::default(),
+                codec: Default::default(),
+                peer_identity: None,
             },
             SecureContext {
+                cipher: CipherSuite::Aes256Gcm,
                 encryptor: Box::new(MockEncryptor::new()),
                 decryptor: Box::new(MockDecryptor::new()),
+                peer_identity: None,
             },
         )
     }
 
     fn disconnected_event(node: NodeId) -> ConnectionEvent {
-        ConnectionEvent::Disconnected(ConnectionCtx {
-            conn: ConnId::from_in(0, node as u64),
-            node,
-            pair: NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair"),
-        })
+        ConnectionEvent::Disconnected(
+            ConnectionCtx {
+                conn: ConnId::from_in(0, node as u64),
+                node,
+                pair: NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair"),
+                features: Default::default(),
+                codec: Default::default(),
+                peer_identity: None,
+            },
+            DisconnectReason::Timeout,
+        )
     }
 
     #[test]
@@ -1,11 +1,13 @@
-use std::{fmt::Debug, hash::Hash};
+use std::{collections::HashMap, fmt::Debug, hash::Hash, sync::Arc};
 
-use atm0s_sdn_identity::NodeId;
-use sans_io_runtime::{TaskSwitcher, TaskSwitcherBranch, TaskSwitcherChild};
+use atm0s_sdn_identity::{ConnId, NodeId};
+use sans_io_runtime::{Buffer, TaskSwitcher, TaskSwitcherBranch, TaskSwitcherChild};
 
 use crate::{
+    base::{FeatureEventTarget, ServiceBuilder, ServiceId},
     controller_plane::{self, ControllerPlane, ControllerPlaneCfg},
-    data_plane::{self, CrossWorker, DataPlane, DataPlaneCfg, NetInput, NetOutput},
+    data_plane::{self, BufferPoolStats, ConnDropStats, ConnStats, CrossWorker, DataPlane, DataPlaneCfg, NetInput, NetOutput},
+    features::{neighbours::ConnectionCounts, router_sync::Convergence, Features, FeaturesControl, FeaturesEvent},
     ExtIn, ExtOut, LogicControl, LogicEvent, LogicEventDest,
 };
 
@@ -28,6 +30,8 @@ pub enum SdnWorkerOutput<UserData, SC, SE, TC, TW> {
     Ext(ExtOut<UserData, SE>),
     ExtWorker(ExtOut<UserData, SE>),
     Net(NetOutput),
+    /// See `data_plane::Output::Backlog`
+    Backlog(bool),
     Bus(SdnWorkerBusEvent<UserData, SC, SE, TC, TW>),
     OnResourceEmpty,
     Continue,
@@ -45,6 +49,8 @@ pub struct SdnWorkerCfg<UserData, SC, SE, TC, TW> {
     pub tick_ms: u64,
     pub controller: Option<ControllerPlaneCfg<UserData, SC, SE, TC, TW>>,
     pub data: DataPlaneCfg<UserData, SC, SE, TC, TW>,
+    /// Features which are missing here use FeatureEventTarget::Controller
+    pub feature_targets: HashMap<Features, FeatureEventTarget>,
 }
 
 pub struct SdnWorker<UserData, SC, SE, TC, TW> {
@@ -56,6 +62,7 @@ pub struct SdnWorker<UserData, SC, SE, TC, TW> {
     shutdown: bool,
     switcher: TaskSwitcher,
     last_tick: Option<u64>,
+    feature_targets: HashMap<Features, FeatureEventTarget>,
 }
 
 impl<UserData, SC: Debug, SE: Debug, TC: Debug, TW: Debug> SdnWorker<UserData, SC, SE, TC, TW>
@@ -72,6 +79,7 @@ where
             shutdown: false,
             switcher: TaskSwitcher::new(2),
             last_tick: None,
+            feature_targets: cfg.feature_targets,
         }
     }
 
@@ -83,6 +91,72 @@ where
         self.shutdown && self.controller.as_ref().map_or(true, |c| c.is_empty()) && self.data.is_empty()
     }
 
+    /// Dropped packet counters of a connection pinned to this worker.
+    pub fn conn_drop_stats(&self, conn: ConnId) -> Option<ConnDropStats> {
+        self.data.conn_drop_stats(conn)
+    }
+
+    /// Packets and controls dropped because they target a disabled feature, in the controller if this worker runs it and the data plane
+    pub fn feature_unavailable_count(&self) -> u64 {
+        self.controller.as_ref().map_or(0, |controller| controller.feature_unavailable_count()) + self.data.feature_unavailable_count()
+    }
+
+    /// Hit and miss counters of the data plane buffer pool
+    pub fn buffer_pool_stats(&self) -> BufferPoolStats {
+        self.data.buffer_pool_stats()
+    }
+
+    /// Give back the buffer of a sent packet for reuse by this worker
+    pub fn recycle_buffer(&mut self, buf: Buffer) {
+        self.data.input(&mut self.switcher).recycle_buffer(buf);
+    }
+
+    /// True if the data plane output queue is full with OverflowPolicy::Block
+    pub fn is_blocked(&self) -> bool {
+        self.data.is_blocked()
+    }
+
+    /// Install a service at runtime in the data plane, and in the controller if this worker runs it.
+    /// Each worker of the node needs the same call
+    pub fn register_service(&mut self, builder: Arc<dyn ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>) {
+        if let Some(controller) = &mut self.controller {
+            controller.input(&mut self.switcher).register_service(builder.clone());
+        }
+        self.data.input(&mut self.switcher).register_service(builder);
+    }
+
+    /// Remove a service at runtime, returns false if it is not registered in this worker
+    pub fn unregister_service(&mut self, service: ServiceId) -> bool {
+        if let Some(controller) = &mut self.controller {
+            controller.input(&mut self.switcher).unregister_service(service);
+        }
+        self.data.input(&mut self.switcher).unregister_service(service)
+    }
+
+    /// Neighbour connection counts, only the worker which runs the controller has them
+    pub fn connection_counts(&self) -> Option<ConnectionCounts> {
+        self.controller.as_ref().map(|controller| controller.connection_counts())
+    }
+
+    /// Routing table convergence, only the worker which runs the controller has it
+    pub fn router_convergence(&self, now_ms: u64) -> Option<Convergence> {
+        self.controller.as_ref().map(|controller| controller.router_convergence(now_ms))
+    }
+
+    /// Keepalive round trip time of a neighbour connection, only the worker which runs the controller has it
+    pub fn connection_rtt_ms(&self, conn: ConnId) -> Option<u32> {
+        self.controller.as_ref().and_then(|controller| controller.connection_rtt_ms(conn))
+    }
+
+    /// Traffic counters of a connection, summed over all workers if this worker runs the controller.
+    /// Otherwise only the counters of this worker.
+    pub fn connection_stats(&self, conn: ConnId) -> Option<ConnStats> {
+        match &self.controller {
+            Some(controller) => controller.connection_stats(conn),
+            None => self.data.connection_stats(conn),
+        }
+    }
+
     pub fn on_tick(&mut self, now_ms: u64) {
         if let Some(last_tick) = self.last_tick {
             if now_ms < last_tick + self.tick_ms {
@@ -99,6 +173,10 @@ where
 
     pub fn on_event(&mut self, now_ms: u64, input: SdnWorkerInput<UserData, SC, SE, TC, TW>) {
         match input {
+            SdnWorkerInput::Ext(ExtIn::FeaturesControl(userdata, control)) if self.feature_target(control.to_feature()) == FeatureEventTarget::Worker => {
+                log::debug!("[SdnWorker] feature {:?} control is handled in worker", control.to_feature());
+                self.data.input(&mut self.switcher).on_event(now_ms, data_plane::Input::Ext(ExtIn::FeaturesControl(userdata, control)));
+            }
             SdnWorkerInput::Ext(ext) => {
                 let controller = self.controller.as_mut().expect("Should have controller");
                 controller.input(&mut self.switcher).on_event(now_ms, controller_plane::Input::Ext(ext));
@@ -124,6 +202,10 @@ where
         }
     }
 
+    fn feature_target(&self, feature: Features) -> FeatureEventTarget {
+        self.feature_targets.get(&feature).copied().unwrap_or_default()
+    }
+
     pub fn on_shutdown(&mut self, now_ms: u64) {
         if self.shutdown {
             return;
@@ -183,6 +265,7 @@ where
         match out {
             data_plane::Output::Ext(ext) => SdnWorkerOutput::ExtWorker(ext),
             data_plane::Output::Net(out) => SdnWorkerOutput::Net(out),
+            data_plane::Output::Backlog(backlogged) => SdnWorkerOutput::Backlog(backlogged),
             data_plane::Output::Control(control) => {
                 if let Some(controller) = &mut self.controller {
                     log::debug!("[SdnWorker] send control to controller {:?}", control);
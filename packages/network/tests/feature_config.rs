@@ -0,0 +1,60 @@
+use atm0s_sdn_network::{
+    features::{data, socket, FeaturesConfig, FeaturesControl, FeaturesEvent},
+    ExtIn, ExtOut,
+};
+
+use crate::simulator::{NetworkSimulator, TestNode, TestNodeCfg};
+
+mod simulator;
+
+/// node1 only runs neighbours, router_sync and data, node2 runs all features
+#[test]
+fn feature_config_core_only_node() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let addr1 = sim.add_node(TestNode::with_cfg(node1, 1234, vec![], TestNodeCfg::default().features(FeaturesConfig::core())));
+    let _addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node2, ExtIn::ConnectTo(addr1));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    assert_eq!(sim.connection_counts(node1).established, 1);
+
+    //core features still work in both directions
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::Ping(node2))));
+    sim.control(node2, ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::Ping(node1))));
+    sim.process(10);
+    let mut pongs = vec![];
+    while let Some(res) = sim.pop_res() {
+        match res {
+            (node, ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::Pong(dest, Some(_))))) => pongs.push((node, dest)),
+            res => panic!("unexpected result {res:?}"),
+        }
+    }
+    pongs.sort();
+    assert_eq!(pongs, vec![(node1, node2), (node2, node1)]);
+
+    //controls and packets of a disabled feature are dropped
+    let dropped = sim.feature_unavailable_count(node1);
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Socket(socket::Control::Bind(10000))));
+    sim.control(node2, ExtIn::FeaturesControl((), FeaturesControl::Socket(socket::Control::Bind(10001))));
+    sim.control(
+        node2,
+        ExtIn::FeaturesControl((), FeaturesControl::Socket(socket::Control::SendTo(10001, node1, 10000, vec![1, 2, 3, 4].into(), 0))),
+    );
+    sim.process(10);
+    assert_eq!(sim.pop_res(), None);
+    assert_eq!(sim.feature_unavailable_count(node1), dropped + 2);
+    assert_eq!(sim.feature_unavailable_count(node2), 0);
+
+    //the node keeps working after dropping them
+    sim.control(node2, ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::Ping(node1))));
+    sim.process(10);
+    match sim.pop_res() {
+        Some((2, ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::Pong(1, Some(_)))))) => {}
+        res => panic!("unexpected result {res:?}"),
+    }
+}
@@ -0,0 +1,71 @@
+use atm0s_sdn_network::{
+    features::{
+        data::{self, DEFAULT_FLOW_WINDOW, FLOW_BUFFER_BYTES},
+        FeaturesControl, FeaturesEvent,
+    },
+    ExtIn, ExtOut,
+};
+
+use crate::simulator::{LinkModel, NetworkSimulator, TestNode};
+
+mod simulator;
+
+#[test]
+fn feature_data_flow_bounded_in_flight() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::with_seed(0, 1290);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    //slow link which would queue everything the sender writes without flow control
+    let model = LinkModel {
+        extra_latency_ms: 20,
+        bytes_per_ms: 20,
+        ..Default::default()
+    };
+    sim.set_link(node1, node2, model);
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    sim.control(node2, ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::DataListen(1))));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    const MSG_SIZE: usize = 1000;
+    const MSG_COUNT: usize = 200;
+    for i in 0..MSG_COUNT {
+        let mut data = vec![0; MSG_SIZE];
+        data[..2].copy_from_slice(&(i as u16).to_be_bytes());
+        sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::DataSendFlow(1, node2, data))));
+    }
+
+    let mut blocked = 0;
+    let mut received = vec![];
+    let mut max_in_flight = 0;
+    for _i in 0..150 {
+        sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::DataFlowStats(1, node2))));
+        sim.process(100);
+        while let Some(res) = sim.pop_res() {
+            match res {
+                (node, ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::Recv(1, _, data)))) if node == node2 => {
+                    received.push(u16::from_be_bytes([data[0], data[1]]) as usize);
+                }
+                (node, ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::WouldBlock(1, dest)))) if node == node1 && dest == node2 => blocked += 1,
+                (node, ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::Writable(1, dest)))) if node == node1 && dest == node2 => {}
+                (node, ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::FlowStats(1, dest, stats)))) if node == node1 && dest == node2 => {
+                    assert!(stats.buffered <= FLOW_BUFFER_BYTES);
+                    max_in_flight = max_in_flight.max(stats.in_flight);
+                }
+                res => panic!("unexpected result {res:?}"),
+            }
+        }
+    }
+
+    assert!(max_in_flight > 0 && max_in_flight <= DEFAULT_FLOW_WINDOW, "{max_in_flight}");
+    //data above window and buffer is rejected, all others arrive in order
+    assert!(blocked > 0);
+    assert_eq!(received.len() + blocked, MSG_COUNT);
+    assert_eq!(received, (0..received.len()).collect::<Vec<_>>());
+}
@@ -0,0 +1,67 @@
+use atm0s_sdn_network::{
+    base::NetOutgoingMeta,
+    features::{
+        data::{self, DataCfg},
+        FeaturesControl, FeaturesEvent,
+    },
+    ExtIn, ExtOut,
+};
+use atm0s_sdn_router::RouteRule;
+
+use crate::simulator::{LinkModel, NetworkSimulator, TestNode, TestNodeCfg};
+
+mod simulator;
+
+fn send(dest: u32, data: Vec<u8>) -> ExtIn<(), ()> {
+    ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::DataSendRule(1, RouteRule::ToNode(dest), NetOutgoingMeta::default(), data)))
+}
+
+#[test]
+fn feature_data_fragment_reorder_link() {
+    // node1 <-> node2 <-> node3
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::with_seed(0, 1291);
+    let cfg = DataCfg {
+        fragment_mtu: Some(100),
+        ..Default::default()
+    };
+
+    let _addr1 = sim.add_node(TestNode::with_cfg(node1, 1234, vec![], TestNodeCfg::default().data(cfg)));
+    //relay only forwards fragments, so it does not need fragmentation enabled
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+    let addr3 = sim.add_node(TestNode::with_cfg(node3, 1236, vec![], TestNodeCfg::default().data(cfg)));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    sim.control(node2, ExtIn::ConnectTo(addr3));
+    sim.control(node3, ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::DataListen(1))));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    let model = LinkModel {
+        reorder_pct: 50,
+        extra_latency_ms: 10,
+        ..Default::default()
+    };
+    sim.set_link(node1, node2, model);
+
+    let big: Vec<u8> = (0..1050).map(|i| i as u8).collect();
+    sim.control(node1, send(node3, big.clone()));
+    sim.control(node1, send(node3, vec![1, 2, 3]));
+
+    let mut received = vec![];
+    for _i in 0..10 {
+        sim.process(100);
+        while let Some(res) = sim.pop_res() {
+            match res {
+                (node, ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::Recv(1, _, data)))) if node == node3 => received.push(data),
+                res => panic!("unexpected result {res:?}"),
+            }
+        }
+    }
+
+    received.sort_by_key(|data| data.len());
+    assert_eq!(received, vec![vec![1, 2, 3], big]);
+}
@@ -1,12 +1,12 @@
 use atm0s_sdn_network::{
     features::{
-        dht_kv::{Control, Event, Key, Map, MapControl, MapEvent},
+        dht_kv::{CasError, Control, DhtKvCfg, Event, Key, KeyPrefix, Map, MapControl, MapEvent},
         FeaturesControl, FeaturesEvent,
     },
     ExtIn, ExtOut,
 };
 
-use crate::simulator::{NetworkSimulator, TestNode};
+use crate::simulator::{NetworkSimulator, TestNode, TestNodeCfg};
 
 mod simulator;
 
@@ -34,7 +34,7 @@ fn feature_dht_kv_single_node() {
     sim.process(100);
     assert_eq!(sim.pop_res(), Some((node_id, event(Event::MapEvent(key, MapEvent::OnRelaySelected(node_id))))));
 
-    sim.control(node_id, control(Control::MapCmd(key, MapControl::Set(sub_key, value.clone()))));
+    sim.control(node_id, control(Control::MapCmd(key, MapControl::Set(sub_key, value.clone(), None))));
     sim.process(100);
 
     assert_eq!(sim.pop_res(), Some((node_id, event(Event::MapEvent(key, MapEvent::OnSet(sub_key, node_id, value))))));
@@ -53,7 +53,7 @@ fn feature_dht_kv_single_node_sub_after() {
     let sub_key = Key(2000);
     let value = vec![1, 2, 3, 4];
 
-    sim.control(node_id, control(Control::MapCmd(key, MapControl::Set(sub_key, value.clone()))));
+    sim.control(node_id, control(Control::MapCmd(key, MapControl::Set(sub_key, value.clone(), None))));
     sim.process(100);
 
     assert_eq!(sim.pop_res(), None);
@@ -90,7 +90,7 @@ fn feature_dht_kv_two_nodes() {
     sim.process(100);
     assert_eq!(sim.pop_res(), Some((node1, event(Event::MapEvent(key, MapEvent::OnRelaySelected(node1))))));
 
-    sim.control(node2, control(Control::MapCmd(key, MapControl::Set(sub_key, value.clone()))));
+    sim.control(node2, control(Control::MapCmd(key, MapControl::Set(sub_key, value.clone(), None))));
     sim.process(100);
 
     assert_eq!(sim.pop_res(), Some((node1, event(Event::MapEvent(key, MapEvent::OnSet(sub_key, node2, value))))));
@@ -117,7 +117,7 @@ fn feature_dht_kv_two_nodes_sub_after() {
     let sub_key = Key(2000);
     let value = vec![1, 2, 3, 4];
 
-    sim.control(node2, control(Control::MapCmd(key, MapControl::Set(sub_key, value.clone()))));
+    sim.control(node2, control(Control::MapCmd(key, MapControl::Set(sub_key, value.clone(), None))));
     sim.process(100);
 
     assert_eq!(sim.pop_res(), None);
@@ -155,7 +155,7 @@ fn feature_dht_kv_move_key_other_relay() {
     assert_eq!(sim.pop_res(), Some((node1, event(Event::MapEvent(key, MapEvent::OnRelaySelected(node2))))));
     assert_eq!(sim.pop_res(), None);
 
-    sim.control(node2, control(Control::MapCmd(key, MapControl::Set(sub_key, value.clone()))));
+    sim.control(node2, control(Control::MapCmd(key, MapControl::Set(sub_key, value.clone(), None))));
     sim.process(100);
 
     assert_eq!(sim.pop_res(), Some((node1, event(Event::MapEvent(key, MapEvent::OnSet(sub_key, node2, value))))));
@@ -175,9 +175,269 @@ fn feature_dht_kv_move_key_other_relay() {
 
     // Now set new value should be relay to node3
     let value2 = vec![1, 2, 3, 4, 5];
-    sim.control(node2, control(Control::MapCmd(key, MapControl::Set(sub_key, value2.clone()))));
+    sim.control(node2, control(Control::MapCmd(key, MapControl::Set(sub_key, value2.clone(), None))));
     sim.process(100);
 
     assert_eq!(sim.pop_res(), Some((node1, event(Event::MapEvent(key, MapEvent::OnSet(sub_key, node2, value2))))));
     assert_eq!(sim.pop_res(), None);
 }
+
+#[test]
+fn feature_dht_kv_ttl_expire_after_source_disconnected() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    let key = Map(1);
+    let sub_key = Key(2000);
+    let value = vec![1, 2, 3, 4];
+
+    sim.control(node1, control(Control::MapCmd(key, MapControl::Sub)));
+    sim.process(100);
+    assert_eq!(sim.pop_res(), Some((node1, event(Event::MapEvent(key, MapEvent::OnRelaySelected(node1))))));
+
+    sim.control(node2, control(Control::MapCmd(key, MapControl::Set(sub_key, value.clone(), Some(3000)))));
+    sim.process(100);
+    assert_eq!(sim.pop_res(), Some((node1, event(Event::MapEvent(key, MapEvent::OnSet(sub_key, node2, value))))));
+
+    // node2 is gone, so it never sends Del, but the replicated copy still expires
+    sim.partition(vec![vec![node1], vec![node2]]);
+    for _i in 0..5 {
+        sim.process(500);
+    }
+    assert_eq!(sim.pop_res(), None);
+
+    sim.process(500);
+    assert_eq!(sim.pop_res(), Some((node1, event(Event::MapEvent(key, MapEvent::OnDel(sub_key, node2))))));
+    assert_eq!(sim.pop_res(), None);
+}
+
+#[test]
+fn feature_dht_kv_competing_cas_only_one_success() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    let key = Map(1);
+    let sub_key = Key(2000);
+    let cas = |value: Vec<u8>| {
+        control(Control::MapCmdCas {
+            key,
+            sub_key,
+            expected_version: None,
+            value,
+        })
+    };
+
+    sim.control(node1, cas(vec![1]));
+    sim.control(node2, cas(vec![2]));
+    sim.process(100);
+
+    let mut success = vec![];
+    let mut conflict = vec![];
+    while let Some((node, res)) = sim.pop_res() {
+        match res {
+            ExtOut::FeaturesEvent((), FeaturesEvent::DhtKv(Event::MapCasRes(res_key, res_sub_key, res))) if res_key == key && res_sub_key == sub_key => match res {
+                Ok(version) => success.push((node, version)),
+                Err(CasError::Conflict(current)) => conflict.push((node, current)),
+                Err(err) => panic!("unexpected cas error {err:?}"),
+            },
+            res => panic!("unexpected result {res:?}"),
+        }
+    }
+    assert_eq!(success.len(), 1);
+    assert_eq!(conflict.len(), 1);
+    assert_ne!(success[0].0, conflict[0].0);
+    assert_eq!(conflict[0].1, Some(success[0].1));
+
+    // loser can retry with the returned version
+    let version = success[0].1;
+    let loser = conflict[0].0;
+    sim.control(
+        loser,
+        control(Control::MapCmdCas {
+            key,
+            sub_key,
+            expected_version: Some(version),
+            value: vec![3],
+        }),
+    );
+    sim.process(100);
+    assert!(matches!(sim.pop_res(), Some((node, ExtOut::FeaturesEvent((), FeaturesEvent::DhtKv(Event::MapCasRes(_, _, Ok(new_version)))))) if node == loser && new_version > version));
+}
+
+#[test]
+fn feature_dht_kv_scan_prefix_with_pagination() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    let key = Map(1);
+    let channel = 0x0100_0000_0000_0000;
+    for sub_key in [channel + 1, channel + 2, channel + 3, 0x0200_0000_0000_0000] {
+        sim.control(node2, control(Control::MapCmd(key, MapControl::Set(Key(sub_key), vec![1], None))));
+    }
+    sim.process(100);
+
+    let prefix = KeyPrefix::new(Key(channel), 8);
+    let mut cursor = None;
+    let mut sub_keys = vec![];
+    let mut pages = 0;
+    loop {
+        sim.control(node1, control(Control::MapScan { key, prefix, limit: 2, cursor }));
+        sim.process(100);
+        match sim.pop_res() {
+            Some((res_node, ExtOut::FeaturesEvent((), FeaturesEvent::DhtKv(Event::MapScanRes(res_key, Ok((entries, next))))))) if res_node == node1 && res_key == key => {
+                assert!(entries.len() <= 2);
+                sub_keys.extend(entries.into_iter().map(|(sub_key, source, _, _)| {
+                    assert_eq!(source.0, node2);
+                    sub_key.0
+                }));
+                pages += 1;
+                match next {
+                    Some(next) => cursor = Some(next),
+                    None => break,
+                }
+            }
+            res => panic!("unexpected result {res:?}"),
+        }
+    }
+    assert_eq!(pages, 2);
+    assert_eq!(sub_keys, vec![channel + 1, channel + 2, channel + 3]);
+}
+
+#[test]
+fn feature_dht_kv_read_from_replica_after_relay_down() {
+    // node4 connects to all, node1 <-> node3
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let node4 = 4;
+    let cfg = DhtKvCfg { replication_factor: 2 };
+    //seeded for distinct connection sessions, node3 accepts both node1 and node4
+    let mut sim = NetworkSimulator::<(), (), (), ()>::with_seed(0, 1268);
+
+    let _addr1 = sim.add_node(TestNode::with_cfg(node1, 1234, vec![], TestNodeCfg::default().dht_kv(cfg)));
+    let addr2 = sim.add_node(TestNode::with_cfg(node2, 1235, vec![], TestNodeCfg::default().dht_kv(cfg)));
+    let addr3 = sim.add_node(TestNode::with_cfg(node3, 1236, vec![], TestNodeCfg::default().dht_kv(cfg)));
+    let addr4 = sim.add_node(TestNode::with_cfg(node4, 1237, vec![], TestNodeCfg::default().dht_kv(cfg)));
+
+    sim.control(node4, ExtIn::ConnectTo(addr2));
+    sim.control(node4, ExtIn::ConnectTo(addr3.clone()));
+    sim.control(node1, ExtIn::ConnectTo(addr4));
+    sim.control(node1, ExtIn::ConnectTo(addr3));
+
+    // For sync
+    for _i in 0..6 {
+        sim.process(500);
+    }
+
+    // node2 is relay of the key, node3 is next closest so it is the replica
+    let key = Map(2);
+    let sub_key = Key(2000);
+    let value = vec![1, 2, 3, 4];
+
+    sim.control(node4, control(Control::MapCmd(key, MapControl::Set(sub_key, value.clone(), None))));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    // both the relay and the source are gone
+    sim.partition(vec![vec![node1, node3], vec![node2], vec![node4]]);
+    for _i in 0..30 {
+        sim.process(500);
+    }
+    while sim.pop_res().is_some() {}
+
+    sim.control(node1, control(Control::MapGet(key)));
+    sim.process(100);
+    match sim.pop_res() {
+        Some((res_node, ExtOut::FeaturesEvent((), FeaturesEvent::DhtKv(Event::MapGetRes(res_key, Ok(entries)))))) if res_node == node1 && res_key == key => {
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].0, sub_key);
+            assert_eq!(entries[0].1 .0, node4);
+            assert_eq!(entries[0].3, value);
+        }
+        res => panic!("unexpected result {res:?}"),
+    }
+}
+
+#[test]
+fn feature_dht_kv_reconverge_soon_after_reconnect() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    let key = Map(1);
+    let sub_key = Key(2000);
+    let value = vec![1, 2, 3, 4];
+
+    sim.control(node1, control(Control::MapCmd(key, MapControl::Sub)));
+    sim.process(100);
+    assert_eq!(sim.pop_res(), Some((node1, event(Event::MapEvent(key, MapEvent::OnRelaySelected(node1))))));
+
+    sim.control(node2, control(Control::MapCmd(key, MapControl::Set(sub_key, value.clone(), None))));
+    sim.process(100);
+    assert_eq!(sim.pop_res(), Some((node1, event(Event::MapEvent(key, MapEvent::OnSet(sub_key, node2, value))))));
+
+    sim.partition(vec![vec![node1], vec![node2]]);
+    //connection times out without pong
+    for _i in 0..30 {
+        sim.process(500);
+    }
+
+    // node2 is the closest node it can reach, so it acks the write itself
+    let value2 = vec![5, 6, 7, 8];
+    sim.control(node2, control(Control::MapCmd(key, MapControl::Set(sub_key, value2.clone(), None))));
+    sim.process(100);
+    assert_eq!(sim.pop_res(), None);
+
+    sim.heal();
+    sim.control(node2, ExtIn::ConnectTo(addr1));
+    // the digest after reconnect fixes node1 long before the periodic sync
+    for _i in 0..5 {
+        sim.process(100);
+    }
+    assert_eq!(sim.pop_res(), Some((node1, event(Event::MapEvent(key, MapEvent::OnSet(sub_key, node2, value2))))));
+    assert_eq!(sim.pop_res(), None);
+}
@@ -0,0 +1,57 @@
+use std::collections::HashMap;
+
+use atm0s_sdn_network::{
+    base::FeatureEventTarget,
+    features::{data, neighbours, Features, FeaturesControl, FeaturesEvent},
+    ExtIn, ExtOut,
+};
+
+use crate::simulator::{NetworkSimulator, TestNode, TestNodeCfg};
+
+mod simulator;
+
+#[test]
+fn feature_event_target_worker_should_bypass_controller() {
+    let node1 = 1;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let targets = HashMap::from([(Features::Data, FeatureEventTarget::Worker)]);
+    let _addr1 = sim.add_node(TestNode::with_cfg(node1, 1234, vec![], TestNodeCfg::default().feature_targets(targets)));
+
+    sim.process(10);
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::Ping(node1))));
+    sim.process(10);
+    assert_eq!(sim.pop_res(), None);
+    assert_eq!(sim.pop_res_worker(), Some((node1, ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::Pong(node1, Some(0)))))));
+}
+
+#[test]
+fn feature_event_target_should_keep_explicit_actor() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let targets = HashMap::from([(Features::Data, FeatureEventTarget::Worker)]);
+    let _addr1 = sim.add_node(TestNode::with_cfg(node1, 1234, vec![], TestNodeCfg::default().feature_targets(targets)));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.process(10);
+
+    //not configured feature still deliver to controller
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::ConnectTo(addr2))));
+    for _ in 0..3 {
+        sim.process(10);
+    }
+    assert!(matches!(
+        sim.pop_res(),
+        Some((_, ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::Connected(node, _))))) if node == node2
+    ));
+
+    //explicit worker control is not affected
+    sim.control_worker(node1, ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::Ping(node1))));
+    sim.process(10);
+    assert_eq!(sim.pop_res(), None);
+    assert_eq!(sim.pop_res_worker(), Some((node1, ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::Pong(node1, Some(0)))))));
+}
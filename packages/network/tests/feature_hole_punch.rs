@@ -0,0 +1,160 @@
+use atm0s_sdn_network::{
+    features::{
+        data,
+        hole_punch::{self, NatType, RelayReason, PUNCH_TIMEOUT_MS},
+        FeaturesControl, FeaturesEvent,
+    },
+    ExtIn, ExtOut,
+};
+
+use crate::simulator::{LinkModel, NatModel, NetworkSimulator, TestNode};
+
+mod simulator;
+
+fn punch(node: u32, via: u32) -> ExtIn<(), ()> {
+    ExtIn::FeaturesControl((), FeaturesControl::HolePunch(hole_punch::Control::Punch { node, via }))
+}
+
+/// node1 and node2 are only connected through node3
+fn build_sim(seed: u64) -> NetworkSimulator<(), (), (), ()> {
+    let mut sim = NetworkSimulator::<(), (), (), ()>::with_seed(0, seed);
+
+    let _addr1 = sim.add_node(TestNode::new(1, 1234, vec![]));
+    let _addr2 = sim.add_node(TestNode::new(2, 1235, vec![]));
+    let addr3 = sim.add_node(TestNode::new(3, 1236, vec![]));
+
+    sim.control(1, ExtIn::ConnectTo(addr3.clone()));
+    sim.control(2, ExtIn::ConnectTo(addr3));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    sim
+}
+
+#[test]
+fn feature_hole_punch_through_rendezvous() {
+    let mut sim = build_sim(1300);
+    assert_eq!(sim.connection_counts(1).established, 1);
+    assert_eq!(sim.connection_counts(2).established, 1);
+
+    sim.control(1, punch(2, 3));
+    for _i in 0..4 {
+        sim.process(100);
+    }
+
+    match sim.pop_res() {
+        Some((1, ExtOut::FeaturesEvent((), FeaturesEvent::HolePunch(hole_punch::Event::Punched(2, _))))) => {}
+        res => panic!("unexpected result {res:?}"),
+    }
+    assert_eq!(sim.connection_counts(1).established, 2);
+    assert_eq!(sim.connection_counts(2).established, 2);
+
+    //punching again reuses the direct connection
+    sim.control(1, punch(2, 3));
+    sim.process(10);
+    match sim.pop_res() {
+        Some((1, ExtOut::FeaturesEvent((), FeaturesEvent::HolePunch(hole_punch::Event::Punched(2, _))))) => {}
+        res => panic!("unexpected result {res:?}"),
+    }
+}
+
+#[test]
+fn feature_hole_punch_peer_unreachable() {
+    let mut sim = build_sim(1301);
+
+    //node4 is not connected to the rendezvous
+    sim.control(1, punch(4, 3));
+    sim.process(100);
+    assert_eq!(
+        sim.pop_res(),
+        Some((1, ExtOut::FeaturesEvent((), FeaturesEvent::HolePunch(hole_punch::Event::Relayed(4, RelayReason::PeerUnreachable)))))
+    );
+}
+
+#[test]
+fn feature_hole_punch_fallback_to_relay() {
+    let mut sim = build_sim(1302);
+
+    //NATs which drop all direct packets between node1 and node2
+    let blocked = LinkModel { loss_pct: 100, ..Default::default() };
+    sim.set_link(1, 2, blocked);
+    sim.set_link(2, 1, blocked);
+
+    sim.control(1, punch(2, 3));
+    for _i in 0..(PUNCH_TIMEOUT_MS / 500 + 2) {
+        sim.process(500);
+    }
+    assert_eq!(
+        sim.pop_res(),
+        Some((1, ExtOut::FeaturesEvent((), FeaturesEvent::HolePunch(hole_punch::Event::Relayed(2, RelayReason::Timeout)))))
+    );
+    assert_eq!(sim.connection_counts(1).established, 1);
+
+    //traffic still goes through node3
+    sim.control(1, ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::Ping(2))));
+    sim.process(10);
+    match sim.pop_res() {
+        Some((1, ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::Pong(2, Some(_)))))) => {}
+        res => panic!("unexpected result {res:?}"),
+    }
+}
+
+/// node1, optionally behind a NAT, is connected to node2 and node3
+fn build_nat_sim(seed: u64, nat: Option<NatModel>) -> NetworkSimulator<(), (), (), ()> {
+    let mut sim = NetworkSimulator::<(), (), (), ()>::with_seed(0, seed);
+
+    let _addr1 = sim.add_node(TestNode::new(1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(2, 1235, vec![]));
+    let addr3 = sim.add_node(TestNode::new(3, 1236, vec![]));
+    if let Some(nat) = nat {
+        sim.set_nat(1, nat);
+    }
+
+    sim.control(1, ExtIn::ConnectTo(addr2));
+    sim.control(1, ExtIn::ConnectTo(addr3));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    assert_eq!(sim.connection_counts(1).established, 2);
+    sim
+}
+
+fn detect_nat(sim: &mut NetworkSimulator<(), (), (), ()>) -> Option<(u32, ExtOut<(), ()>)> {
+    sim.control(1, ExtIn::FeaturesControl((), FeaturesControl::HolePunch(hole_punch::Control::DetectNat)));
+    sim.process(100);
+    sim.pop_res()
+}
+
+fn nat_event(nat: NatType) -> Option<(u32, ExtOut<(), ()>)> {
+    Some((1, ExtOut::FeaturesEvent((), FeaturesEvent::HolePunch(hole_punch::Event::Nat(nat)))))
+}
+
+#[test]
+fn feature_hole_punch_detect_open() {
+    let mut sim = build_nat_sim(1303, None);
+    assert_eq!(detect_nat(&mut sim), nat_event(NatType::Open));
+}
+
+#[test]
+fn feature_hole_punch_detect_cone_nat() {
+    let mut sim = build_nat_sim(1304, Some(NatModel::Cone));
+    assert_eq!(detect_nat(&mut sim), nat_event(NatType::Cone));
+
+    sim.control(1, ExtIn::FeaturesControl((), FeaturesControl::HolePunch(hole_punch::Control::GetNat)));
+    sim.process(10);
+    assert_eq!(sim.pop_res(), nat_event(NatType::Cone));
+}
+
+#[test]
+fn feature_hole_punch_detect_symmetric_nat() {
+    let mut sim = build_nat_sim(1305, Some(NatModel::Symmetric));
+    assert_eq!(detect_nat(&mut sim), nat_event(NatType::Symmetric));
+
+    //punching can't work behind a symmetric NAT, so it goes straight to relaying
+    sim.control(1, punch(4, 2));
+    sim.process(10);
+    assert_eq!(
+        sim.pop_res(),
+        Some((1, ExtOut::FeaturesEvent((), FeaturesEvent::HolePunch(hole_punch::Event::Relayed(4, RelayReason::SymmetricNat)))))
+    );
+}
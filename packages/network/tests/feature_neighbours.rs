@@ -1,10 +1,26 @@
-use atm0s_sdn_identity::ConnId;
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use atm0s_sdn_identity::{ConnId, NodeAddr, NodeAddrBuilder, NodeId, Protocol};
 use atm0s_sdn_network::{
-    features::{neighbours, FeaturesControl, FeaturesEvent},
+    base::{AcceptPolicy, AllowlistPolicy, DisconnectReason, NameResolved, NameResolver, NeighboursConnectError, NetOutgoingMeta, RekeyPolicy, RekeyReason},
+    features::{
+        data,
+        neighbours::{self, ConnectionCounts, HandshakeRateCfg, IpPreference, NeighboursCfg},
+        FeaturesControl, FeaturesEvent,
+    },
     ExtIn, ExtOut,
 };
+use atm0s_sdn_router::RouteRule;
+use parking_lot::Mutex;
 
-use crate::simulator::{NetworkSimulator, TestNode};
+use crate::simulator::{LinkModel, NetworkSimulator, TestNode, TestNodeCfg};
 
 mod simulator;
 
@@ -48,3 +64,588 @@ fn feature_neighbours_two_nodes() {
         ]
     );
 }
+
+#[test]
+fn feature_neighbours_rekey_mid_stream() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let policy = RekeyPolicy {
+        after_bytes: Some(2000),
+        after_ms: None,
+    };
+    let _addr1 = sim.add_node(TestNode::with_cfg(node1, 1234, vec![], TestNodeCfg::default().rekey(policy)));
+    let addr2 = sim.add_node(TestNode::with_cfg(node2, 1235, vec![], TestNodeCfg::default().rekey(policy)));
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    sim.control(node2, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    sim.control(node2, ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::DataListen(1))));
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    while sim.pop_res().is_some() {}
+
+    let mut received = vec![];
+    let mut rekeys = vec![];
+    for i in 0..50u8 {
+        let rule = data::Control::DataSendRule(1, RouteRule::ToNode(node2), NetOutgoingMeta::secure(), vec![i; 200]);
+        sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Data(rule)));
+        sim.process(100);
+        while let Some((node, out)) = sim.pop_res() {
+            match out {
+                ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::Recv(1, _, data))) => received.push(data[0]),
+                ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::Rekey(_, _, stats))) => rekeys.push((node, i, stats)),
+                out => panic!("unexpected output {out:?}"),
+            }
+        }
+    }
+
+    //every message is delivered while keys rotate under it
+    assert_eq!(received, (0..50).collect::<Vec<_>>());
+    assert!(rekeys.iter().all(|(_, _, stats)| stats.reason == RekeyReason::Exchange));
+    //both sides install each key, the first one well before the stream ends
+    let node1_rekeys: Vec<_> = rekeys.iter().filter(|(node, _, _)| *node == node1).collect();
+    let node2_rekeys: Vec<_> = rekeys.iter().filter(|(node, _, _)| *node == node2).collect();
+    assert!(node1_rekeys.len() >= 2);
+    assert_eq!(node1_rekeys.len(), node2_rekeys.len());
+    assert!(node1_rekeys[0].1 < 25);
+    assert_eq!(node1_rekeys[0].2.epoch, 1);
+    assert_eq!(node1_rekeys[1].2.epoch, 2);
+}
+
+fn connected_nodes(sim: &mut NetworkSimulator<(), (), (), ()>, node: NodeId) -> Vec<NodeId> {
+    let mut nodes = vec![];
+    while let Some(res) = sim.pop_res() {
+        if let (res_node, ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::Connected(remote, _)))) = res {
+            if res_node == node {
+                nodes.push(remote);
+            }
+        }
+    }
+    nodes.sort();
+    nodes
+}
+
+#[test]
+fn feature_neighbours_reject_over_max_connections() {
+    let node1 = 1;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::with_seed(0, 1277);
+
+    let cfg = NeighboursCfg {
+        max_connections: Some(2),
+        max_connections_per_ip: None,
+        ..Default::default()
+    };
+    let addr1 = sim.add_node(TestNode::with_cfg(node1, 1234, vec![], TestNodeCfg::default().neighbours(cfg)));
+    for node in [2, 3, 4] {
+        sim.add_node(TestNode::new(node, 1234 + node as u64, vec![]));
+    }
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+
+    for node in [2, 3, 4] {
+        sim.control(node, ExtIn::ConnectTo(addr1.clone()));
+        sim.process(100);
+    }
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    //established connections are kept, the latest one is refused
+    assert_eq!(connected_nodes(&mut sim, node1), vec![2, 3]);
+    assert_eq!(sim.connection_counts(node1), ConnectionCounts { total: 2, established: 2 });
+    //rejected side stops retrying instead of waiting for timeout
+    assert_eq!(sim.connection_counts(4), ConnectionCounts { total: 0, established: 0 });
+
+    //a slot is free again after a neighbour leaves
+    sim.control(2, ExtIn::DisconnectFrom(node1));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    sim.control(4, ExtIn::ConnectTo(addr1));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    assert_eq!(connected_nodes(&mut sim, node1), vec![4]);
+    assert_eq!(sim.connection_counts(node1), ConnectionCounts { total: 2, established: 2 });
+}
+
+#[test]
+fn feature_neighbours_reject_over_max_connections_per_ip() {
+    let node1 = 1;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::with_seed(0, 1277);
+
+    let cfg = NeighboursCfg {
+        max_connections: None,
+        max_connections_per_ip: Some(1),
+        ..Default::default()
+    };
+    let addr1 = sim.add_node(TestNode::with_cfg(node1, 1234, vec![], TestNodeCfg::default().neighbours(cfg)));
+    sim.add_node(TestNode::new(2, 1235, vec![]));
+    sim.add_node(TestNode::new(3, 1236, vec![]));
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+
+    //all simulated nodes are on 127.0.0.1
+    sim.control(2, ExtIn::ConnectTo(addr1.clone()));
+    sim.process(100);
+    sim.control(3, ExtIn::ConnectTo(addr1));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    assert_eq!(connected_nodes(&mut sim, node1), vec![2]);
+    assert_eq!(sim.connection_counts(node1), ConnectionCounts { total: 1, established: 1 });
+    assert_eq!(sim.connection_counts(3), ConnectionCounts { total: 0, established: 0 });
+}
+
+#[test]
+fn feature_neighbours_connect_with_node_id_proof() {
+    let node1 = 1;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::with_seed(0, 1277);
+
+    let cfg = NeighboursCfg {
+        require_node_id_proof: true,
+        ..Default::default()
+    };
+    let addr1 = sim.add_node(TestNode::with_cfg(node1, 1234, vec![], TestNodeCfg::default().neighbours(cfg)));
+    sim.add_node(TestNode::new(2, 1235, vec![]));
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+
+    sim.control(2, ExtIn::ConnectTo(addr1));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    assert_eq!(connected_nodes(&mut sim, node1), vec![2]);
+    assert_eq!(sim.connection_counts(node1), ConnectionCounts { total: 1, established: 1 });
+}
+
+#[test]
+fn feature_neighbours_throttle_inbound_handshakes() {
+    let node1 = 1;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::with_seed(0, 1277);
+
+    let cfg = NeighboursCfg {
+        handshake_rate: Some(HandshakeRateCfg { per_sec: 1, burst: 2 }),
+        ..Default::default()
+    };
+    let addr1 = sim.add_node(TestNode::with_cfg(node1, 1234, vec![], TestNodeCfg::default().neighbours(cfg)));
+    for node in 2..=6 {
+        sim.add_node(TestNode::new(node, 1234 + node as u64, vec![]));
+    }
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+
+    //all simulated nodes are on 127.0.0.1, so they share one bucket
+    for node in 2..=6 {
+        sim.control(node, ExtIn::ConnectTo(addr1.clone()));
+    }
+    for _i in 0..5 {
+        sim.process(100);
+    }
+    assert_eq!(connected_nodes(&mut sim, node1).len(), 2);
+    assert_eq!(sim.connection_counts(node1), ConnectionCounts { total: 2, established: 2 });
+
+    //throttled requesters keep retrying and get in as tokens refill
+    for _i in 0..10 {
+        sim.process(500);
+    }
+    assert_eq!(connected_nodes(&mut sim, node1).len(), 3);
+    assert_eq!(sim.connection_counts(node1), ConnectionCounts { total: 5, established: 5 });
+}
+
+fn neighbours_control(control: neighbours::Control) -> ExtIn<(), ()> {
+    ExtIn::FeaturesControl((), FeaturesControl::Neighbours(control))
+}
+
+fn neighbours_events(sim: &mut NetworkSimulator<(), (), (), ()>) -> Vec<(NodeId, neighbours::Event)> {
+    let mut events = vec![];
+    while let Some(res) = sim.pop_res() {
+        if let (node, ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(event))) = res {
+            events.push((node, event));
+        }
+    }
+    events.sort_by_key(|(node, _)| *node);
+    events
+}
+
+#[test]
+fn feature_neighbours_blacklist() {
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::with_seed(0, 1278);
+
+    let addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+    let addr3 = sim.add_node(TestNode::new(node3, 1236, vec![]));
+    for node in [node1, node2, node3] {
+        sim.control(node, neighbours_control(neighbours::Control::Sub));
+    }
+
+    sim.control(node1, neighbours_control(neighbours::Control::Blacklist(node3)));
+    sim.process(1);
+    //outgoing is refused without dialing
+    sim.control(node1, ExtIn::ConnectTo(addr3));
+    sim.process(1);
+    assert_eq!(neighbours_events(&mut sim), vec![(node1, neighbours::Event::Rejected(node3, NeighboursConnectError::Blocked))]);
+    assert_eq!(sim.connection_counts(node1).total, 0);
+
+    //incoming is refused too, and the remote stops retrying
+    sim.control(node3, ExtIn::ConnectTo(addr1.clone()));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    assert_eq!(neighbours_events(&mut sim), vec![(node3, neighbours::Event::Rejected(node1, NeighboursConnectError::Blocked))]);
+    assert_eq!(sim.connection_counts(node1).total, 0);
+    assert_eq!(sim.connection_counts(node3).total, 0);
+
+    //established connection is closed when the node is blacklisted at runtime
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    assert_eq!(connected_nodes(&mut sim, node1), vec![node2]);
+    sim.control(node1, neighbours_control(neighbours::Control::Blacklist(node2)));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    //the reason is local, the blacklisted node only sees a disconnect request
+    let events = neighbours_events(&mut sim);
+    assert!(
+        matches!(
+            events.as_slice(),
+            [
+                (1, neighbours::Event::Disconnected(2, _, DisconnectReason::Rejected)),
+                (2, neighbours::Event::Disconnected(1, _, DisconnectReason::Graceful))
+            ]
+        ),
+        "{events:?}"
+    );
+    assert_eq!(sim.connection_counts(node1).total, 0);
+
+    sim.control(node2, ExtIn::ConnectTo(addr1));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    assert_eq!(neighbours_events(&mut sim), vec![(node2, neighbours::Event::Rejected(node1, NeighboursConnectError::Blocked))]);
+}
+
+/// Allowlist which counts how often it is asked
+struct CountingPolicy {
+    allowlist: AllowlistPolicy,
+    checks: AtomicUsize,
+}
+
+impl AcceptPolicy for CountingPolicy {
+    fn accept(&self, node: NodeId, conn: ConnId, remote: SocketAddr) -> bool {
+        self.checks.fetch_add(1, Ordering::Relaxed);
+        self.allowlist.accept(node, conn, remote)
+    }
+}
+
+#[test]
+fn feature_neighbours_accept_policy() {
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::with_seed(0, 1278);
+
+    let policy = Arc::new(CountingPolicy {
+        allowlist: AllowlistPolicy::new([node2]),
+        checks: AtomicUsize::new(0),
+    });
+    let addr1 = sim.add_node(TestNode::with_cfg(node1, 1234, vec![], TestNodeCfg::default().accept_policy(policy.clone())));
+    sim.add_node(TestNode::new(node2, 1235, vec![]));
+    let addr3 = sim.add_node(TestNode::new(node3, 1236, vec![]));
+    for node in [node1, node2, node3] {
+        sim.control(node, neighbours_control(neighbours::Control::Sub));
+    }
+
+    //outgoing is refused without dialing
+    sim.control(node1, ExtIn::ConnectTo(addr3));
+    sim.process(1);
+    assert_eq!(neighbours_events(&mut sim), vec![(node1, neighbours::Event::Rejected(node3, NeighboursConnectError::Blocked))]);
+    assert_eq!(sim.connection_counts(node1).total, 0);
+    assert_eq!(policy.checks.load(Ordering::Relaxed), 1);
+
+    //refused incoming never creates a connection, and the remote stops retrying
+    sim.control(node2, ExtIn::ConnectTo(addr1.clone()));
+    sim.control(node3, ExtIn::ConnectTo(addr1));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    let events = neighbours_events(&mut sim);
+    assert!(
+        matches!(
+            events.as_slice(),
+            [
+                (1, neighbours::Event::Connected(2, _)),
+                (2, neighbours::Event::Connected(1, _)),
+                (3, neighbours::Event::Rejected(1, NeighboursConnectError::Blocked))
+            ]
+        ),
+        "{events:?}"
+    );
+    assert_eq!(sim.connection_counts(node1), ConnectionCounts { total: 1, established: 1 });
+    assert_eq!(sim.connection_counts(node3).total, 0);
+    //only the first request of each requester is checked, later controls go to its connection
+    assert_eq!(policy.checks.load(Ordering::Relaxed), 3);
+}
+
+#[test]
+fn feature_neighbours_allowlist_only() {
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::with_seed(0, 1278);
+
+    let addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    sim.add_node(TestNode::new(node2, 1235, vec![]));
+    sim.add_node(TestNode::new(node3, 1236, vec![]));
+    for node in [node1, node2, node3] {
+        sim.control(node, neighbours_control(neighbours::Control::Sub));
+    }
+
+    sim.control(node1, neighbours_control(neighbours::Control::AllowlistOnly(node2)));
+    sim.process(1);
+    sim.control(node2, ExtIn::ConnectTo(addr1.clone()));
+    sim.control(node3, ExtIn::ConnectTo(addr1));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    let events = neighbours_events(&mut sim);
+    assert!(
+        matches!(
+            events.as_slice(),
+            [
+                (1, neighbours::Event::Connected(2, _)),
+                (2, neighbours::Event::Connected(1, _)),
+                (3, neighbours::Event::Rejected(1, NeighboursConnectError::Blocked))
+            ]
+        ),
+        "{events:?}"
+    );
+    assert_eq!(sim.connection_counts(node1), ConnectionCounts { total: 1, established: 1 });
+}
+
+/// Resolver with fixed records, a lookup is finished on the next tick
+#[derive(Default)]
+struct StaticResolver {
+    records: HashMap<String, Vec<IpAddr>>,
+    resolved: Mutex<VecDeque<NameResolved>>,
+    lookups: AtomicUsize,
+}
+
+impl NameResolver for StaticResolver {
+    fn resolve(&self, host: &str) {
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+        let addrs = self.records.get(host).cloned().unwrap_or_default();
+        self.resolved.lock().push_back(NameResolved {
+            host: host.to_string(),
+            addrs,
+            ttl_ms: 60_000,
+        });
+    }
+
+    fn pop_resolved(&self) -> Option<NameResolved> {
+        self.resolved.lock().pop_front()
+    }
+}
+
+fn dns_addr(node: NodeId, host: &str) -> NodeAddr {
+    let mut builder = NodeAddrBuilder::new(node);
+    builder.add_protocol(Protocol::Dns4(host.into()));
+    builder.add_protocol(Protocol::Udp(node as u16));
+    builder.addr()
+}
+
+#[test]
+fn feature_neighbours_connect_by_hostname() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::with_seed(0, 1299);
+
+    let resolver = Arc::new(StaticResolver {
+        records: HashMap::from([("node2.sdn".to_string(), vec![IpAddr::V4(Ipv4Addr::LOCALHOST)])]),
+        ..Default::default()
+    });
+    sim.add_node(TestNode::with_cfg(node1, 1234, vec![], TestNodeCfg::default().resolver(resolver.clone())));
+    sim.add_node(TestNode::new(node2, 1235, vec![]));
+    sim.control(node1, neighbours_control(neighbours::Control::Sub));
+
+    sim.control(node1, ExtIn::ConnectTo(dns_addr(node2, "node2.sdn")));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    assert_eq!(connected_nodes(&mut sim, node1), vec![node2]);
+    assert_eq!(resolver.lookups.load(Ordering::Relaxed), 1);
+
+    //cached record is used for the next connect
+    sim.control(node1, ExtIn::DisconnectFrom(node2));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    sim.control(node1, ExtIn::ConnectTo(dns_addr(node2, "node2.sdn")));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    assert_eq!(connected_nodes(&mut sim, node1), vec![node2]);
+    assert_eq!(resolver.lookups.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn feature_neighbours_hostname_not_found() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::with_seed(0, 1299);
+
+    sim.add_node(TestNode::with_cfg(node1, 1234, vec![], TestNodeCfg::default().resolver(Arc::new(StaticResolver::default()))));
+    sim.add_node(TestNode::new(node2, 1235, vec![]));
+    sim.control(node1, neighbours_control(neighbours::Control::Sub));
+
+    sim.control(node1, ExtIn::ConnectTo(dns_addr(node2, "unknown.sdn")));
+    sim.process(1);
+    sim.process(1);
+    assert_eq!(
+        neighbours_events(&mut sim),
+        vec![(node1, neighbours::Event::Rejected(node2, NeighboursConnectError::DestinationNotFound))]
+    );
+    assert_eq!(sim.connection_counts(node1).total, 0);
+}
+
+#[test]
+fn feature_neighbours_hostname_resolved_again_after_all_addresses_failed() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::with_seed(0, 1299);
+
+    let resolver = Arc::new(StaticResolver {
+        records: HashMap::from([("node2.sdn".to_string(), vec![IpAddr::V4(Ipv4Addr::LOCALHOST)])]),
+        ..Default::default()
+    });
+    sim.add_node(TestNode::with_cfg(node1, 1234, vec![], TestNodeCfg::default().resolver(resolver.clone())));
+    sim.add_node(TestNode::new(node2, 1235, vec![]));
+    sim.control(node1, neighbours_control(neighbours::Control::Sub));
+
+    sim.partition(vec![vec![node1], vec![node2]]);
+    sim.control(node1, ExtIn::ConnectTo(dns_addr(node2, "node2.sdn")));
+    for _i in 0..35 {
+        sim.process(1000);
+    }
+    assert_eq!(
+        neighbours_events(&mut sim),
+        vec![(node1, neighbours::Event::Rejected(node2, NeighboursConnectError::DestinationNotFound))]
+    );
+
+    sim.heal();
+    sim.control(node1, ExtIn::ConnectTo(dns_addr(node2, "node2.sdn")));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    assert_eq!(connected_nodes(&mut sim, node1), vec![node2]);
+    assert_eq!(resolver.lookups.load(Ordering::Relaxed), 2);
+}
+
+#[test]
+fn feature_neighbours_keepalive_detects_dead_connection() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::with_seed(0, 1302);
+
+    let cfg = NeighboursCfg {
+        keepalive_interval_ms: 200,
+        keepalive_miss_limit: 3,
+        ..Default::default()
+    };
+    sim.add_node(TestNode::with_cfg(node1, 1234, vec![], TestNodeCfg::default().neighbours(cfg)));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+    sim.control(node1, neighbours_control(neighbours::Control::Sub));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    let conn = match neighbours_events(&mut sim).as_slice() {
+        [(1, neighbours::Event::Connected(2, conn))] => *conn,
+        events => panic!("unexpected events {events:?}"),
+    };
+
+    //rtt is measured by keepalive pings
+    let slow = LinkModel {
+        extra_latency_ms: 20,
+        ..Default::default()
+    };
+    sim.set_link(node1, node2, slow);
+    sim.set_link(node2, node1, slow);
+    for _i in 0..50 {
+        sim.process(10);
+    }
+    let rtt = sim.connection_rtt_ms(node1, conn);
+    assert!(matches!(rtt, Some(40..=60)), "{rtt:?}");
+
+    //link dies silently, it is detected after 3 missed pongs
+    let dead = LinkModel { loss_pct: 100, ..Default::default() };
+    sim.set_link(node1, node2, dead);
+    sim.set_link(node2, node1, dead);
+    for _i in 0..5 {
+        sim.process(100);
+    }
+    assert_eq!(neighbours_events(&mut sim), vec![]);
+    assert_eq!(sim.connection_counts(node1).established, 1);
+    for _i in 0..4 {
+        sim.process(100);
+    }
+    assert_eq!(neighbours_events(&mut sim), vec![(node1, neighbours::Event::Disconnected(node2, conn, DisconnectReason::Timeout))]);
+    assert_eq!(sim.connection_counts(node1).established, 0);
+    assert_eq!(sim.connection_rtt_ms(node1, conn), None);
+}
+
+/// Remote addresses of the established neighbours of a node
+fn neighbour_remotes(sim: &mut NetworkSimulator<(), (), (), ()>, node: NodeId) -> Vec<SocketAddr> {
+    sim.control(node, ExtIn::QueryTopology(()));
+    sim.process(1);
+    let mut remotes = vec![];
+    while let Some((res_node, out)) = sim.pop_res() {
+        if let (true, ExtOut::Topology((), topology)) = (res_node == node, out) {
+            remotes = topology.neighbours.iter().map(|n| n.remote).collect();
+        }
+    }
+    remotes
+}
+
+#[test]
+fn feature_neighbours_ipv6() {
+    let (node1, node2, node3) = (1, 2, 3);
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    let prefer_ipv6 = NeighboursCfg {
+        ip_preference: IpPreference::PreferIpv6,
+        ..Default::default()
+    };
+    let addr1 = sim.add_node(TestNode::with_cfg(node1, 1234, vec![], TestNodeCfg::default().neighbours(prefer_ipv6).dual_stack()));
+    let addr2 = sim.add_node(TestNode::with_cfg(node2, 1235, vec![], TestNodeCfg::default().dual_stack()));
+    let addr3 = sim.add_node(TestNode::new(node3, 1236, vec![]));
+
+    //both families are reachable, only the preferred one is dialed
+    sim.control(node1, ExtIn::ConnectTo(addr2.clone()));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    assert_eq!(sim.connection_counts(node1), ConnectionCounts { total: 1, established: 1 });
+    assert_eq!(neighbour_remotes(&mut sim, node1), vec![SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), node2 as u16)]);
+
+    //node3 only has IPv4, so it is used anyway
+    sim.control(node1, ExtIn::ConnectTo(addr3));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    assert_eq!(sim.connection_counts(node3), ConnectionCounts { total: 1, established: 1 });
+
+    //without a preference each family has its own connection
+    sim.control(node2, ExtIn::ConnectTo(addr1));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    assert_eq!(sim.connection_counts(node2).established, 2);
+}
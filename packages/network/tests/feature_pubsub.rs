@@ -1,12 +1,15 @@
+use std::sync::Arc;
+
+use atm0s_sdn_identity::NodeId;
 use atm0s_sdn_network::{
     features::{
-        pubsub::{ChannelControl, ChannelEvent, ChannelId, Control, Event, Feedback},
+        pubsub::{ChannelAccess, ChannelAuthorizer, ChannelControl, ChannelEvent, ChannelId, ConsumerQueueCfg, ConsumerStats, Control, Event, Feedback, OverflowPolicy, PubSubCfg, RetainCfg},
         FeaturesControl, FeaturesEvent,
     },
     ExtIn, ExtOut,
 };
 
-use crate::simulator::{NetworkSimulator, TestNode};
+use crate::simulator::{NetworkSimulator, TestNode, TestNodeCfg};
 
 mod simulator;
 
@@ -293,3 +296,189 @@ fn feature_pubsub_auto_three_nodes_sub_after_start() {
     sim.process(1);
     assert_eq!(sim.pop_res(), None);
 }
+
+#[test]
+fn feature_pubsub_queue_drop_oldest() {
+    let node_id = 1;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    sim.add_node(TestNode::new(node_id, 1234, vec![]));
+
+    sim.process(100);
+
+    let channel = ChannelId(1000);
+    let cfg = ConsumerQueueCfg {
+        high_water: 2,
+        policy: OverflowPolicy::DropOldest,
+    };
+
+    sim.control(node_id, control(Control(channel, ChannelControl::SubSource(node_id))));
+    sim.control(node_id, control(Control(channel, ChannelControl::SetConsumerQueue(Some(cfg)))));
+    sim.process(1);
+    for i in 0..4 {
+        sim.control(node_id, control(Control(channel, ChannelControl::PubData(vec![i]))));
+    }
+    sim.process(1);
+    assert_eq!(sim.pop_res(), None);
+
+    sim.control(node_id, control(Control(channel, ChannelControl::GetConsumerStats)));
+    sim.process(1);
+    assert_eq!(
+        sim.pop_res(),
+        Some((node_id, event(Event(channel, ChannelEvent::ConsumerStats(ConsumerStats { queued: 2, dropped: 2 })))))
+    );
+
+    sim.control(node_id, control(Control(channel, ChannelControl::Pull(10))));
+    sim.process(1);
+    assert_eq!(sim.pop_res(), Some((node_id, event(Event(channel, ChannelEvent::SourceData(node_id, vec![2]))))));
+    assert_eq!(sim.pop_res(), Some((node_id, event(Event(channel, ChannelEvent::SourceData(node_id, vec![3]))))));
+    assert_eq!(sim.pop_res(), None);
+}
+
+#[test]
+fn feature_pubsub_queue_slow_down_then_evict() {
+    let node_id = 1;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    sim.add_node(TestNode::new(node_id, 1234, vec![]));
+
+    sim.process(100);
+
+    let channel = ChannelId(1000);
+    let cfg = ConsumerQueueCfg {
+        high_water: 2,
+        policy: OverflowPolicy::SlowDown { kind: 1 },
+    };
+
+    sim.control(node_id, control(Control(channel, ChannelControl::PubStart)));
+    sim.control(node_id, control(Control(channel, ChannelControl::SubAuto)));
+    sim.control(node_id, control(Control(channel, ChannelControl::SetConsumerQueue(Some(cfg)))));
+    sim.process(1);
+    for i in 0..3 {
+        sim.control(node_id, control(Control(channel, ChannelControl::PubData(vec![i]))));
+    }
+    //publisher is asked to slow down with the queue length
+    sim.process(1);
+    assert_eq!(sim.pop_res(), Some((node_id, event(Event(channel, ChannelEvent::FeedbackData(Feedback::simple(1, 3, 1000, 2000)))))));
+    assert_eq!(sim.pop_res(), None);
+
+    sim.control(node_id, control(Control(channel, ChannelControl::PubData(vec![3]))));
+    sim.process(1);
+    assert_eq!(sim.pop_res(), Some((node_id, event(Event(channel, ChannelEvent::SlowConsumer)))));
+    assert_eq!(sim.pop_res(), None);
+
+    //consumer is unsubscribed, so it doesn't get data or queue anymore
+    sim.control(node_id, control(Control(channel, ChannelControl::PubData(vec![4]))));
+    sim.control(node_id, control(Control(channel, ChannelControl::GetConsumerStats)));
+    sim.process(1);
+    assert_eq!(sim.pop_res(), Some((node_id, event(Event(channel, ChannelEvent::ConsumerStats(ConsumerStats::default()))))));
+    assert_eq!(sim.pop_res(), None);
+}
+
+/// Block one node from subscribing one channel
+struct DenyNode(NodeId, ChannelId);
+
+impl ChannelAuthorizer for DenyNode {
+    fn authorize(&self, node: NodeId, channel: ChannelId, access: ChannelAccess) -> bool {
+        !(node == self.0 && channel == self.1 && access == ChannelAccess::Subscribe)
+    }
+}
+
+#[test]
+fn feature_pubsub_authorizer_should_deny_node() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let denied = ChannelId(1000);
+    let allowed = ChannelId(1001);
+    let pubsub = PubSubCfg {
+        authorizer: Arc::new(DenyNode(node1, denied)),
+        ..Default::default()
+    };
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::with_cfg(node2, 1235, vec![], TestNodeCfg::default().pubsub(pubsub)));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    sim.control(node1, control(Control(denied, ChannelControl::SubSource(node2))));
+    sim.control(node1, control(Control(allowed, ChannelControl::SubSource(node2))));
+    sim.process(1);
+    sim.process(1);
+    assert_eq!(sim.pop_res(), Some((node1, event(Event(denied, ChannelEvent::Denied(ChannelAccess::Subscribe, node2))))));
+    assert_eq!(sim.pop_res(), None);
+
+    sim.control(node2, control(Control(denied, ChannelControl::PubData(vec![1]))));
+    sim.control(node2, control(Control(allowed, ChannelControl::PubData(vec![2]))));
+    sim.process(1);
+    assert_eq!(sim.pop_res(), Some((node1, event(Event(allowed, ChannelEvent::SourceData(node2, vec![2]))))));
+    assert_eq!(sim.pop_res(), None);
+}
+
+fn retain_cfg(channel: ChannelId, depth: usize) -> PubSubCfg {
+    let mut pubsub = PubSubCfg::default();
+    pubsub.retain.insert(channel, RetainCfg { depth, max_bytes: 1024 });
+    pubsub
+}
+
+#[test]
+fn feature_pubsub_retain_single_node() {
+    let node_id = 1;
+    let channel = ChannelId(1000);
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    sim.add_node(TestNode::with_cfg(node_id, 1234, vec![], TestNodeCfg::default().pubsub(retain_cfg(channel, 1))));
+
+    sim.process(100);
+
+    sim.control(node_id, control(Control(channel, ChannelControl::PubStart)));
+    sim.control(node_id, control(Control(channel, ChannelControl::PubData(vec![1]))));
+    sim.control(node_id, control(Control(channel, ChannelControl::PubData(vec![2]))));
+    sim.process(1);
+    assert_eq!(sim.pop_res(), None);
+
+    //only the last value is retained
+    sim.control(node_id, control(Control(channel, ChannelControl::SubSource(node_id))));
+    sim.process(1);
+    assert_eq!(sim.pop_res(), Some((node_id, event(Event(channel, ChannelEvent::SourceData(node_id, vec![2]))))));
+    assert_eq!(sim.pop_res(), None);
+}
+
+#[test]
+fn feature_pubsub_retain_two_nodes() {
+    let node1 = 1;
+    let node2 = 2;
+    let channel = ChannelId(1000);
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::with_cfg(node1, 1234, vec![], TestNodeCfg::default().pubsub(retain_cfg(channel, 2))));
+    let addr2 = sim.add_node(TestNode::with_cfg(node2, 1235, vec![], TestNodeCfg::default().pubsub(retain_cfg(channel, 2))));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    sim.control(node2, control(Control(channel, ChannelControl::PubStart)));
+    for i in 1..=3 {
+        sim.control(node2, control(Control(channel, ChannelControl::PubData(vec![i]))));
+    }
+    sim.process(1);
+    assert_eq!(sim.pop_res(), None);
+
+    //the late subscriber gets the retained frames in order, then live frames
+    sim.control(node1, control(Control(channel, ChannelControl::SubSource(node2))));
+    sim.process(1);
+    assert_eq!(sim.pop_res(), Some((node1, event(Event(channel, ChannelEvent::SourceData(node2, vec![2]))))));
+    assert_eq!(sim.pop_res(), Some((node1, event(Event(channel, ChannelEvent::SourceData(node2, vec![3]))))));
+    assert_eq!(sim.pop_res(), None);
+
+    sim.control(node2, control(Control(channel, ChannelControl::PubData(vec![4]))));
+    sim.process(1);
+    assert_eq!(sim.pop_res(), Some((node1, event(Event(channel, ChannelEvent::SourceData(node2, vec![4]))))));
+    assert_eq!(sim.pop_res(), None);
+}
@@ -0,0 +1,74 @@
+use atm0s_sdn_network::{
+    features::{
+        pubsub::{ChannelControl, ChannelEvent, ChannelId, Control, Event},
+        FeaturesControl, FeaturesEvent,
+    },
+    ExtIn, ExtOut,
+};
+
+use crate::simulator::{NetworkSimulator, TestNode};
+
+mod simulator;
+
+fn control(control: Control) -> ExtIn<(), ()> {
+    ExtIn::FeaturesControl((), FeaturesControl::PubSub(control))
+}
+
+fn event(event: Event) -> ExtOut<(), ()> {
+    ExtOut::FeaturesEvent((), FeaturesEvent::PubSub(event))
+}
+
+#[test]
+fn feature_pubsub_consumer_churn_no_warnings() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::with_seed(0, 1272);
+    sim.enable_log(log::LevelFilter::Warn);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    let channel = ChannelId(1000);
+    let value = vec![1, 2, 3, 4];
+    sim.control(node1, control(Control(channel, ChannelControl::PubStart)));
+    sim.process(1);
+
+    for i in 0..100 {
+        sim.control(node2, control(Control(channel, ChannelControl::SubSource(node1))));
+        sim.control(node2, control(Control(channel, ChannelControl::UnsubSource(node1))));
+        sim.control(node1, control(Control(channel, ChannelControl::SubAuto)));
+        sim.control(node1, control(Control(channel, ChannelControl::UnsubAuto)));
+        //only process every few cycles, so some destroys are queued right after their create
+        if i % 3 == 0 {
+            sim.process(1);
+        }
+    }
+    for _i in 0..10 {
+        sim.process(500);
+    }
+    assert_eq!(sim.log_warnings(), 0);
+
+    sim.control(node1, control(Control(channel, ChannelControl::PubData(value.clone()))));
+    sim.process(10);
+    assert_eq!(sim.pop_res(), None);
+
+    //a consumer created after the churn still works and gets data only once
+    sim.control(node2, control(Control(channel, ChannelControl::SubSource(node1))));
+    sim.process(10);
+    sim.control(node1, control(Control(channel, ChannelControl::PubData(value.clone()))));
+    sim.process(10);
+    assert_eq!(sim.pop_res(), Some((node2, event(Event(channel, ChannelEvent::SourceData(node1, value.clone()))))));
+    assert_eq!(sim.pop_res(), None);
+
+    sim.control(node2, control(Control(channel, ChannelControl::UnsubSource(node1))));
+    sim.process(10);
+    sim.control(node1, control(Control(channel, ChannelControl::PubData(value))));
+    sim.process(10);
+    assert_eq!(sim.pop_res(), None);
+    assert_eq!(sim.log_warnings(), 0);
+}
@@ -1,16 +1,17 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
+use atm0s_sdn_identity::NodeId;
 use atm0s_sdn_network::{
     base::{
         NetIncomingMeta, NetOutgoingMeta, Service, ServiceBuilder, ServiceCtx, ServiceInput, ServiceOutput, ServiceSharedInput, ServiceWorker, ServiceWorkerCtx, ServiceWorkerInput,
         ServiceWorkerOutput,
     },
-    features::{data, FeaturesControl, FeaturesEvent},
+    features::{data, router_sync, Features, FeaturesControl, FeaturesEvent},
     ExtIn, ExtOut,
 };
 use atm0s_sdn_router::RouteRule;
 
-use crate::simulator::{NetworkSimulator, TestNode};
+use crate::simulator::{LinkModel, NetworkSimulator, TestNode, TestNodeCfg};
 
 mod simulator;
 
@@ -130,6 +131,21 @@ fn feature_router_sync_single_node() {
     );
 }
 
+#[test]
+fn feature_router_sync_unreachable_ping_should_fail_fast() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    sim.process(500);
+
+    //no route to node2, the ping is reported without waiting for its timeout
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::Ping(node2))));
+    sim.process(10);
+    assert_eq!(sim.pop_res(), Some((node1, ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::Pong(node2, None))))));
+}
+
 #[test]
 fn feature_router_sync_two_nodes() {
     let node1 = 1;
@@ -165,12 +181,254 @@ fn feature_router_sync_three_nodes() {
     sim.control(node1, ExtIn::ConnectTo(addr2));
     sim.control(node2, ExtIn::ConnectTo(addr3));
 
-    // For sync
-    for _i in 0..4 {
-        sim.process(500);
-    }
+    // Wait for the tables instead of a fixed time
+    sim.process_until_converged(100, 10_000);
 
     sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::Ping(node3))));
     sim.process(10);
     assert_eq!(sim.pop_res(), Some((node1, ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::Pong(node3, Some(0)))))));
 }
+
+fn sync_interval(sim: &mut NetworkSimulator<(), (), (), ()>, node: NodeId) -> u64 {
+    sim.control(node, ExtIn::FeaturesControl((), FeaturesControl::RouterSync(router_sync::Control::GetSyncInterval)));
+    sim.process(1);
+    match sim.pop_res() {
+        Some((res_node, ExtOut::FeaturesEvent((), FeaturesEvent::RouterSync(router_sync::Event::SyncInterval(interval))))) if res_node == node => interval,
+        res => panic!("unexpected result {res:?}"),
+    }
+}
+
+#[test]
+fn feature_router_sync_adaptive_interval() {
+    // node1 <-> node2, then node2 connects to node3 and node4
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let node4 = 4;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    let cfg = router_sync::RouterSyncCfg {
+        sync_interval: router_sync::SyncIntervalCfg { min_ms: 1_000, max_ms: 10_000 },
+        ..Default::default()
+    };
+
+    let _addr1 = sim.add_node(TestNode::with_cfg(node1, 1234, vec![], TestNodeCfg::default().router_sync(cfg)));
+    let addr2 = sim.add_node(TestNode::with_cfg(node2, 1235, vec![], TestNodeCfg::default().router_sync(cfg)));
+    let addr3 = sim.add_node(TestNode::with_cfg(node3, 1236, vec![], TestNodeCfg::default().router_sync(cfg)));
+    let addr4 = sim.add_node(TestNode::with_cfg(node4, 1237, vec![], TestNodeCfg::default().router_sync(cfg)));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+
+    // stable mesh, interval backs off to max
+    for _i in 0..60 {
+        sim.process(1000);
+    }
+    let stable = sync_interval(&mut sim, node2);
+    assert_eq!(stable, cfg.sync_interval.max_ms);
+
+    // topology change burst
+    sim.control(node2, ExtIn::ConnectTo(addr3));
+    sim.process(1000);
+    sim.control(node2, ExtIn::ConnectTo(addr4));
+    for _i in 0..3 {
+        sim.process(1000);
+    }
+    let churning = sync_interval(&mut sim, node2);
+    assert!(churning < stable, "interval should shrink while churning: {churning} vs {stable}");
+    assert!(churning >= cfg.sync_interval.min_ms);
+
+    // interval grows back step by step once the mesh stabilizes
+    let mut last = churning;
+    for _i in 0..60 {
+        sim.process(1000);
+        let interval = sync_interval(&mut sim, node2);
+        assert!(interval >= cfg.sync_interval.min_ms && interval <= cfg.sync_interval.max_ms);
+        assert!(interval <= last + last / 2, "interval should grow smoothly: {last} => {interval}");
+        last = interval;
+    }
+    assert_eq!(last, cfg.sync_interval.max_ms);
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::Ping(node4))));
+    sim.process(10);
+    assert_eq!(sim.pop_res(), Some((node1, ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::Pong(node4, Some(0)))))));
+}
+
+fn service_nodes(sim: &mut NetworkSimulator<(), (), (), ()>, node: NodeId) -> Vec<(NodeId, u32)> {
+    sim.control(node, ExtIn::FeaturesControl((), FeaturesControl::RouterSync(router_sync::Control::FindService(0))));
+    sim.process(1);
+    match sim.pop_res() {
+        Some((res_node, ExtOut::FeaturesEvent((), FeaturesEvent::RouterSync(router_sync::Event::ServiceNodes(0, nodes))))) if res_node == node => nodes,
+        res => panic!("unexpected result {res:?}"),
+    }
+}
+
+#[test]
+fn feature_router_sync_weighted_service_discovery() {
+    // node1 <-> node2 <-> node3 <-> node4, service runs on node1 and node4
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let node4 = 4;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::with_seed(0, 1280);
+    let cfg = router_sync::RouterSyncCfg {
+        service_load_interval_ms: Some(1000),
+        ..Default::default()
+    };
+
+    let _addr1 = sim.add_node(TestNode::with_cfg(node1, 1234, vec![Arc::new(MockServiceBuilder)], TestNodeCfg::default().router_sync(cfg)));
+    let addr2 = sim.add_node(TestNode::with_cfg(node2, 1235, vec![], TestNodeCfg::default().router_sync(cfg)));
+    let addr3 = sim.add_node(TestNode::with_cfg(node3, 1236, vec![], TestNodeCfg::default().router_sync(cfg)));
+    let addr4 = sim.add_node(TestNode::with_cfg(node4, 1237, vec![Arc::new(MockServiceBuilder)], TestNodeCfg::default().router_sync(cfg)));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    sim.control(node2, ExtIn::ConnectTo(addr3));
+    sim.control(node3, ExtIn::ConnectTo(addr4));
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::RouterSync(router_sync::Control::SetServiceLoad(0, 10))));
+    sim.control(node4, ExtIn::FeaturesControl((), FeaturesControl::RouterSync(router_sync::Control::SetServiceLoad(0, 90))));
+    for _i in 0..10 {
+        sim.process(500);
+    }
+
+    assert_eq!(service_nodes(&mut sim, node2), vec![(node1, 10), (node4, 90)]);
+    assert_eq!(service_nodes(&mut sim, node4), vec![(node1, 10), (node4, 90)]);
+
+    //weight changes are propagated with the next advertise
+    sim.control(node4, ExtIn::FeaturesControl((), FeaturesControl::RouterSync(router_sync::Control::SetServiceLoad(0, 5))));
+    for _i in 0..6 {
+        sim.process(500);
+    }
+    let nodes = service_nodes(&mut sim, node2);
+    assert_eq!(nodes, vec![(node1, 10), (node4, 5)]);
+    assert_eq!(router_sync::pick_by_inverse_load(&nodes, 0), Some(node1));
+    assert_eq!(router_sync::pick_by_inverse_load(&nodes, u64::MAX), Some(node4));
+
+    //node4 is gone, its weight expires without refresh
+    sim.partition(vec![vec![node1, node2, node3], vec![node4]]);
+    for _i in 0..30 {
+        sim.process(500);
+    }
+    assert_eq!(service_nodes(&mut sim, node2), vec![(node1, 10)]);
+}
+
+fn reachable(sim: &mut NetworkSimulator<(), (), (), ()>, node: NodeId) -> Vec<u8> {
+    sim.control(node, ExtIn::FeaturesControl((), FeaturesControl::RouterSync(router_sync::Control::DumpRouter)));
+    sim.process(1);
+    match sim.pop_res() {
+        Some((res_node, ExtOut::FeaturesEvent((), FeaturesEvent::RouterSync(router_sync::Event::DumpRouter(dump))))) if res_node == node => dump.layer(0).dest_indexes(),
+        res => panic!("unexpected result {res:?}"),
+    }
+}
+
+fn convergence(sim: &mut NetworkSimulator<(), (), (), ()>, node: NodeId) -> router_sync::Convergence {
+    sim.control(node, ExtIn::FeaturesControl((), FeaturesControl::RouterSync(router_sync::Control::GetConvergence)));
+    sim.process(1);
+    match sim.pop_res() {
+        Some((res_node, ExtOut::FeaturesEvent((), FeaturesEvent::RouterSync(router_sync::Event::Convergence(convergence))))) if res_node == node => convergence,
+        res => panic!("unexpected result {res:?}"),
+    }
+}
+
+#[test]
+fn feature_router_sync_convergence_generation() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+    let initial = convergence(&mut sim, node1);
+    assert!(!initial.converged);
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    sim.process_until_converged(100, 10_000);
+    let connected = convergence(&mut sim, node1);
+    assert!(connected.converged);
+    assert!(connected.generation > initial.generation);
+    assert_eq!(reachable(&mut sim, node1), vec![2]);
+
+    //periodic syncs without table changes keep the generation
+    for _i in 0..10 {
+        sim.process(500);
+    }
+    assert_eq!(convergence(&mut sim, node1), connected);
+
+    sim.control(node1, ExtIn::DisconnectFrom(node2));
+    sim.process(100);
+    let disconnected = convergence(&mut sim, node1);
+    assert!(!disconnected.converged);
+    assert!(disconnected.generation > connected.generation);
+    assert!(!sim.is_converged(node1));
+    sim.process_until_converged(100, 10_000);
+    assert_eq!(reachable(&mut sim, node1), Vec::<u8>::new());
+}
+
+#[test]
+fn feature_router_sync_delta_lossy_converge() {
+    // node1 <-> node2 <-> node3 <-> node4, lost deltas are recovered by full sync requests
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let node4 = 4;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::with_seed(0, 1285);
+    let cfg = router_sync::RouterSyncCfg {
+        delta_sync_full_every: Some(100),
+        ..Default::default()
+    };
+
+    let _addr1 = sim.add_node(TestNode::with_cfg(node1, 1234, vec![], TestNodeCfg::default().router_sync(cfg)));
+    let addr2 = sim.add_node(TestNode::with_cfg(node2, 1235, vec![], TestNodeCfg::default().router_sync(cfg)));
+    let addr3 = sim.add_node(TestNode::with_cfg(node3, 1236, vec![], TestNodeCfg::default().router_sync(cfg)));
+    let addr4 = sim.add_node(TestNode::with_cfg(node4, 1237, vec![], TestNodeCfg::default().router_sync(cfg)));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    sim.control(node2, ExtIn::ConnectTo(addr3));
+    sim.control(node3, ExtIn::ConnectTo(addr4));
+    for _i in 0..6 {
+        sim.process(500);
+    }
+    assert_eq!(reachable(&mut sim, node1), vec![2, 3, 4]);
+    assert_eq!(reachable(&mut sim, node4), vec![1, 2, 3]);
+
+    let model = LinkModel { loss_pct: 30, ..Default::default() };
+    for (from, to) in [(node2, node3), (node3, node2)] {
+        sim.set_link(from, to, model);
+    }
+    sim.partition(vec![vec![node1, node2, node3], vec![node4]]);
+    for _i in 0..40 {
+        sim.process(500);
+    }
+    sim.set_link(node2, node3, LinkModel::default());
+    sim.set_link(node3, node2, LinkModel::default());
+    for _i in 0..40 {
+        sim.process(500);
+    }
+    assert_eq!(reachable(&mut sim, node1), vec![2, 3]);
+    assert_eq!(reachable(&mut sim, node3), vec![1, 2]);
+}
+
+#[test]
+fn feature_router_sync_delta_with_old_neighbour() {
+    // node1 <-> node2 <-> node3, node2 is pinned to the router_sync version without delta syncs
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    let cfg = router_sync::RouterSyncCfg {
+        delta_sync_full_every: Some(100),
+        ..Default::default()
+    };
+    let pinned = HashMap::from([(Features::RouterSync, 1)]);
+
+    let addr1 = sim.add_node(TestNode::with_cfg(node1, 1234, vec![], TestNodeCfg::default().router_sync(cfg)));
+    sim.add_node(TestNode::with_cfg(node2, 1235, vec![], TestNodeCfg::default().router_sync(cfg).feature_versions(pinned)));
+    let addr3 = sim.add_node(TestNode::with_cfg(node3, 1236, vec![], TestNodeCfg::default().router_sync(cfg)));
+
+    // node2 dials both sides, without SIM_SEED two nodes dialing node2 would use the same session
+    sim.control(node2, ExtIn::ConnectTo(addr1));
+    sim.control(node2, ExtIn::ConnectTo(addr3));
+    for _i in 0..6 {
+        sim.process(500);
+    }
+    assert_eq!(reachable(&mut sim, node1), vec![2, 3]);
+    assert_eq!(reachable(&mut sim, node2), vec![1, 3]);
+    assert_eq!(reachable(&mut sim, node3), vec![1, 2]);
+}
@@ -0,0 +1,101 @@
+use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_network::{
+    features::{
+        rpc::{self, RpcError},
+        FeaturesControl, FeaturesEvent,
+    },
+    ExtIn, ExtOut,
+};
+use atm0s_sdn_router::RouteRule;
+
+use crate::simulator::{NetworkSimulator, TestNode};
+
+mod simulator;
+
+fn call(id: u64, dest: NodeId, method: u16) -> ExtIn<(), ()> {
+    ExtIn::FeaturesControl(
+        (),
+        FeaturesControl::Rpc(rpc::Control::Call {
+            id,
+            dest: RouteRule::ToNode(dest),
+            method,
+            payload: vec![1, 2, 3],
+            timeout_ms: 3000,
+            retries: 2,
+        }),
+    )
+}
+
+#[test]
+fn feature_rpc_call_over_relay() {
+    // node1 <-> node2 <-> node3
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::with_seed(0, 1289);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+    let addr3 = sim.add_node(TestNode::new(node3, 1236, vec![]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    sim.control(node2, ExtIn::ConnectTo(addr3));
+    sim.control(node3, ExtIn::FeaturesControl((), FeaturesControl::Rpc(rpc::Control::Listen(1))));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    sim.control(node1, call(100, node3, 1));
+    sim.process(10);
+    let req = match sim.pop_res() {
+        Some((node, ExtOut::FeaturesEvent((), FeaturesEvent::Rpc(rpc::Event::Request(req, 1, payload))))) if node == node3 && payload == vec![1, 2, 3] => req,
+        res => panic!("unexpected result {res:?}"),
+    };
+    assert_eq!(req.from, node1);
+
+    sim.control(node3, ExtIn::FeaturesControl((), FeaturesControl::Rpc(rpc::Control::Answer(req, Ok(vec![4, 5])))));
+    sim.process(10);
+    assert_eq!(sim.pop_res(), Some((node1, ExtOut::FeaturesEvent((), FeaturesEvent::Rpc(rpc::Event::Response(100, Ok(vec![4, 5])))))));
+
+    sim.control(node1, call(101, node3, 2));
+    sim.process(10);
+    assert_eq!(
+        sim.pop_res(),
+        Some((node1, ExtOut::FeaturesEvent((), FeaturesEvent::Rpc(rpc::Event::Response(101, Err(RpcError::NoHandler))))))
+    );
+}
+
+#[test]
+fn feature_rpc_timeout_after_retries() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::with_seed(0, 1289);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    sim.control(node2, ExtIn::FeaturesControl((), FeaturesControl::Rpc(rpc::Control::Listen(1))));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    //the handler never answers, each resent call is dropped by the callee
+    sim.control(node1, call(100, node2, 1));
+    let mut requests = 0;
+    for _i in 0..40 {
+        sim.process(100);
+        while let Some(res) = sim.pop_res() {
+            match res {
+                (node, ExtOut::FeaturesEvent((), FeaturesEvent::Rpc(rpc::Event::Request(..)))) if node == node2 => requests += 1,
+                (node, ExtOut::FeaturesEvent((), FeaturesEvent::Rpc(rpc::Event::Response(100, result)))) if node == node1 => {
+                    assert_eq!(result, Err(RpcError::Timeout));
+                    assert_eq!(requests, 1);
+                    return;
+                }
+                res => panic!("unexpected result {res:?}"),
+            }
+        }
+    }
+    panic!("Should timeout");
+}
@@ -0,0 +1,62 @@
+use std::path::PathBuf;
+
+use atm0s_sdn_network::{
+    base::NeighboursControl,
+    features::{data, FeaturesControl},
+    ExtIn,
+};
+
+use crate::simulator::{NetworkSimulator, TestNode};
+
+//only a part of the simulator helpers is used here
+#[allow(dead_code)]
+mod simulator;
+
+/// Packets of a short run: handshake, router sync and data pings over a relay
+fn capture() -> Vec<Vec<u8>> {
+    let mut sim = NetworkSimulator::<(), (), (), ()>::with_seed(0, 1312);
+    let _addr1 = sim.add_node(TestNode::new(1, 1234, vec![]));
+    let _addr2 = sim.add_node(TestNode::new(2, 1235, vec![]));
+    let addr3 = sim.add_node(TestNode::new(3, 1236, vec![]));
+    sim.capture_packets();
+
+    sim.control(1, ExtIn::ConnectTo(addr3.clone()));
+    sim.control(2, ExtIn::ConnectTo(addr3));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    sim.control(1, ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::Ping(2))));
+    sim.control(1, ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::Ping(3))));
+    sim.process(100);
+    sim.take_captured()
+}
+
+#[test]
+fn captured_controls_should_parse() {
+    let packets = capture();
+    let controls: Vec<_> = packets.iter().filter(|pkt| NeighboursControl::is_control(pkt[0])).collect();
+    assert!(!controls.is_empty());
+    assert!(controls.len() < packets.len());
+    for pkt in controls {
+        assert!(NeighboursControl::try_from(pkt.as_slice()).is_ok());
+    }
+}
+
+/// Write the captured packets as seeds of the fuzz targets, run it with `cargo test --test fuzz_corpus -- --ignored`
+#[test]
+#[ignore]
+fn write_fuzz_corpus() {
+    let corpus = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../fuzz/corpus");
+    for (index, pkt) in capture().iter().enumerate() {
+        let targets: &[&str] = if NeighboursControl::is_control(pkt[0]) {
+            &["network_control_pkt"]
+        } else {
+            &["transport_msg", "transport_header"]
+        };
+        for target in targets {
+            let dir = corpus.join(target);
+            std::fs::create_dir_all(&dir).expect("Should create corpus dir");
+            std::fs::write(dir.join(format!("sim-{index:04}")), pkt).expect("Should write seed");
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use std::time::{Duration, Instant};
+
+use atm0s_sdn_network::{
+    features::{socket, FeaturesControl, FeaturesEvent},
+    memory::{MemoryNetwork, MemoryNode},
+    ExtIn, ExtOut,
+};
+
+use crate::simulator::live_node_cfg;
+
+//only a part of the simulator helpers is used here
+#[allow(dead_code)]
+mod simulator;
+
+type TestMemoryNode = MemoryNode<(), (), ()>;
+
+fn socket_control(node: &TestMemoryNode, control: socket::Control) {
+    node.send(ExtIn::FeaturesControl((), FeaturesControl::Socket(control)));
+}
+
+#[test]
+fn memory_mesh_three_nodes() {
+    // node1 <-> node2 <-> node3, each on its own thread
+    let network = MemoryNetwork::default();
+    let node1: TestMemoryNode = MemoryNode::spawn::<(), ()>(&network, 1, live_node_cfg(1, 1234, vec![])).expect("Should spawn node1");
+    let node2: TestMemoryNode = MemoryNode::spawn::<(), ()>(&network, 2, live_node_cfg(2, 1235, vec![])).expect("Should spawn node2");
+    let node3: TestMemoryNode = MemoryNode::spawn::<(), ()>(&network, 3, live_node_cfg(3, 1236, vec![])).expect("Should spawn node3");
+    assert!(MemoryNode::<(), (), ()>::spawn::<(), ()>(&network, 3, live_node_cfg(4, 1237, vec![])).is_err());
+
+    //node2 dials both sides, nodes without seeded random would dial node2 with the same session
+    node2.send(ExtIn::ConnectTo(node1.addr()));
+    node2.send(ExtIn::ConnectTo(node3.addr()));
+    socket_control(&node1, socket::Control::Bind(10000));
+    socket_control(&node3, socket::Control::Bind(10001));
+
+    //the message is routed over node2 once router sync has converged, so it is sent again until received
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let received = loop {
+        assert!(Instant::now() < deadline, "message was not routed in time");
+        socket_control(&node3, socket::Control::SendTo(10001, node1.node_id(), 10000, vec![1, 2, 3, 4].into(), 0));
+        if let Some(ExtOut::FeaturesEvent((), FeaturesEvent::Socket(event))) = node1.recv_timeout(Duration::from_millis(200)) {
+            break event;
+        }
+    };
+    assert_eq!(received, socket::Event::RecvFrom(10000, node3.node_id(), 10001, vec![1, 2, 3, 4].into(), 0));
+}
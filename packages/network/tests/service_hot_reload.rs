@@ -0,0 +1,149 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use atm0s_sdn_network::{
+    base::{Service, ServiceBuilder, ServiceCtx, ServiceInput, ServiceOutput, ServiceSharedInput, ServiceWorker, ServiceWorkerCtx, ServiceWorkerInput, ServiceWorkerOutput},
+    features::{FeaturesControl, FeaturesEvent},
+    ExtIn, ExtOut,
+};
+
+use crate::simulator::{NetworkSimulator, TestNode};
+
+mod simulator;
+
+const ECHO_SERVICE: u8 = 7;
+
+/// Answers each control with the value plus `step`
+#[derive(Default)]
+struct EchoService {
+    step: u32,
+    outputs: VecDeque<ServiceOutput<(), FeaturesControl, u32, ()>>,
+}
+
+impl Service<(), FeaturesControl, FeaturesEvent, u32, u32, (), ()> for EchoService {
+    fn is_service_empty(&self) -> bool {
+        false
+    }
+
+    fn service_id(&self) -> u8 {
+        ECHO_SERVICE
+    }
+
+    fn service_name(&self) -> &str {
+        "echo"
+    }
+
+    fn on_input(&mut self, _ctx: &ServiceCtx, _now: u64, input: ServiceInput<(), FeaturesEvent, u32, ()>) {
+        if let ServiceInput::Control(actor, value) = input {
+            self.outputs.push_back(ServiceOutput::Event(actor, value + self.step));
+        }
+    }
+
+    fn on_shared_input<'a>(&mut self, _ctx: &ServiceCtx, _now: u64, _input: ServiceSharedInput) {}
+
+    fn on_shutdown(&mut self, _ctx: &ServiceCtx, _now: u64) {}
+
+    fn pop_output2(&mut self, _now: u64) -> Option<ServiceOutput<(), FeaturesControl, u32, ()>> {
+        self.outputs.pop_front()
+    }
+}
+
+struct EchoServiceWorker;
+
+impl ServiceWorker<(), FeaturesControl, FeaturesEvent, u32, u32, (), ()> for EchoServiceWorker {
+    fn is_service_empty(&self) -> bool {
+        false
+    }
+
+    fn service_id(&self) -> u8 {
+        ECHO_SERVICE
+    }
+
+    fn service_name(&self) -> &str {
+        "echo"
+    }
+
+    fn on_tick(&mut self, _ctx: &ServiceWorkerCtx, _now: u64, _tick_count: u64) {}
+
+    fn on_input(&mut self, _ctx: &ServiceWorkerCtx, _now: u64, _input: ServiceWorkerInput<(), FeaturesEvent, u32, ()>) {}
+
+    fn on_shutdown(&mut self, _ctx: &ServiceWorkerCtx, _now: u64) {}
+
+    fn pop_output2(&mut self, _now: u64) -> Option<ServiceWorkerOutput<(), FeaturesControl, FeaturesEvent, u32, u32, ()>> {
+        None
+    }
+}
+
+struct EchoServiceBuilder {
+    step: u32,
+}
+
+impl ServiceBuilder<(), FeaturesControl, FeaturesEvent, u32, u32, (), ()> for EchoServiceBuilder {
+    fn service_id(&self) -> u8 {
+        ECHO_SERVICE
+    }
+
+    fn service_name(&self) -> &str {
+        "echo"
+    }
+
+    fn create(&self) -> Box<dyn Service<(), FeaturesControl, FeaturesEvent, u32, u32, (), ()>> {
+        Box::new(EchoService {
+            step: self.step,
+            ..Default::default()
+        })
+    }
+
+    fn create_worker(&self) -> Box<dyn ServiceWorker<(), FeaturesControl, FeaturesEvent, u32, u32, (), ()>> {
+        Box::new(EchoServiceWorker)
+    }
+}
+
+fn echo(sim: &mut NetworkSimulator<u32, u32, (), ()>, node: u32, value: u32) -> Option<u32> {
+    sim.control(node, ExtIn::ServicesControl(ECHO_SERVICE.into(), (), value));
+    sim.process(10);
+    let mut answer = None;
+    while let Some((from, out)) = sim.pop_res() {
+        if let (true, ExtOut::ServicesEvent(service, (), value)) = (from == node, out) {
+            assert_eq!(*service, ECHO_SERVICE);
+            answer = Some(value);
+        }
+    }
+    answer
+}
+
+#[test]
+fn service_register_at_runtime() {
+    let node1 = 1;
+    let mut sim = NetworkSimulator::<u32, u32, (), ()>::new(0);
+    sim.add_node(TestNode::new(node1, 1234, vec![]));
+    sim.process(10);
+
+    //not registered yet, the control is dropped as an unknown service
+    assert_eq!(echo(&mut sim, node1, 1), None);
+
+    sim.register_service(node1, Arc::new(EchoServiceBuilder { step: 1 }));
+    assert_eq!(echo(&mut sim, node1, 1), Some(2));
+
+    //registering again replaces the running service
+    sim.register_service(node1, Arc::new(EchoServiceBuilder { step: 10 }));
+    assert_eq!(echo(&mut sim, node1, 1), Some(11));
+
+    assert!(sim.unregister_service(node1, ECHO_SERVICE.into()));
+    assert!(!sim.unregister_service(node1, ECHO_SERVICE.into()));
+    assert_eq!(echo(&mut sim, node1, 1), None);
+}
+
+#[test]
+fn service_reinstall_startup_service() {
+    let node1 = 1;
+    let mut sim = NetworkSimulator::<u32, u32, (), ()>::new(0);
+    sim.add_node(TestNode::new(node1, 1234, vec![Arc::new(EchoServiceBuilder { step: 1 })]));
+    sim.process(10);
+    assert_eq!(echo(&mut sim, node1, 5), Some(6));
+
+    //services created at startup can be removed and installed back
+    assert!(sim.unregister_service(node1, ECHO_SERVICE.into()));
+    assert_eq!(echo(&mut sim, node1, 5), None);
+    sim.register_service(node1, Arc::new(EchoServiceBuilder { step: 2 }));
+    assert_eq!(echo(&mut sim, node1, 5), Some(7));
+}
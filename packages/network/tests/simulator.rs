@@ -3,25 +3,52 @@
 //! We will create a node with a controller and single worker, which is enough for testing
 //!
 
-use std::collections::HashMap;
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
 use std::{collections::VecDeque, net::IpAddr};
 
-use atm0s_sdn_identity::{NodeAddr, NodeAddrBuilder, NodeId, Protocol};
-use atm0s_sdn_network::base::ServiceBuilder;
+use atm0s_sdn_identity::{ConnId, NodeAddr, NodeAddrBuilder, NodeId, Protocol};
+use atm0s_sdn_network::base::{
+    AcceptAll, AcceptPolicy, CipherSuite, Clock, FeatureEventTarget, ManualClock, NameResolver, RekeyPolicy, ServiceBuilder, ServiceId, SystemClock, WireCodec, DEFAULT_MSG_TTL,
+};
 use atm0s_sdn_network::controller_plane::ControllerPlaneCfg;
 use atm0s_sdn_network::data_plane::{DataPlaneCfg, NetPair};
-use atm0s_sdn_network::features::{FeaturesControl, FeaturesEvent};
+use atm0s_sdn_network::features::{
+    data::DataCfg,
+    dht_kv::DhtKvCfg,
+    neighbours::{ConnectionCounts, NeighboursCfg},
+    pubsub::PubSubCfg,
+    router_sync::RouterSyncCfg,
+    Features, FeaturesConfig, FeaturesControl, FeaturesEvent,
+};
+use atm0s_sdn_network::node::{Node, NodeCfg, NodeOutput};
 use atm0s_sdn_network::secure::{HandshakeBuilderXDA, StaticKeyAuthorization};
-use atm0s_sdn_network::worker::{SdnWorker, SdnWorkerCfg, SdnWorkerInput, SdnWorkerOutput};
 use atm0s_sdn_network::{base::Buffer, data_plane, ExtIn, ExtOut};
 use atm0s_sdn_router::shadow::ShadowRouterHistory;
-use log::{LevelFilter, Metadata, Record};
+use log::{Level, LevelFilter, Metadata, Record};
 use parking_lot::Mutex;
-use rand::rngs::mock::StepRng;
-use sans_io_runtime::{TaskSwitcher, TaskSwitcherChild};
+use rand::rngs::{mock::StepRng, StdRng};
+use rand::{Rng, RngCore, SeedableRng};
+use sans_io_runtime::TaskSwitcher;
+
+thread_local! {
+    /// Seed of the simulator running in current test thread, each test runs in its own thread
+    static SIM_SEED: Cell<Option<u64>> = const { Cell::new(None) };
+    /// Number of warn or error records logged from current test thread
+    static LOG_WARNINGS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Random source for a node, derived from the simulator seed if it is set.
+/// Without seed, all nodes share the same fixed sequence
+fn node_random(node_id: NodeId, stream: u64, default_start: u64) -> Box<dyn RngCore + Send + Sync> {
+    match SIM_SEED.get() {
+        Some(seed) => Box::new(StdRng::seed_from_u64(seed ^ ((node_id as u64) << 8) ^ stream)),
+        None => Box::new(StepRng::new(default_start, 5)),
+    }
+}
 
 static CONTEXT_LOGGER: ContextLogger = ContextLogger { node: Mutex::new(None) };
 
@@ -45,6 +72,9 @@ impl log::Log for ContextLogger {
     }
 
     fn log(&self, record: &Record) {
+        if record.level() <= Level::Warn {
+            LOG_WARNINGS.set(LOG_WARNINGS.get() + 1);
+        }
         if self.enabled(record.metadata()) {
             if let Some(node) = self.node.lock().as_ref() {
                 println!("[Node {}] {} - {}", node, record.level(), record.args());
@@ -80,17 +110,6 @@ pub enum TestNodeIn<SC> {
     Tun(Buffer),
 }
 
-#[derive(Debug)]
-pub enum TestNodeOut<SE> {
-    Ext(ExtOut<(), SE>),
-    ExtWorker(ExtOut<(), SE>),
-    Udp(Vec<NetPair>, Buffer),
-    #[cfg(feature = "vpn")]
-    #[allow(dead_code)]
-    Tun(Buffer),
-    Continue,
-}
-
 pub fn build_addr(node_id: NodeId) -> NodeAddr {
     let mut builder = NodeAddrBuilder::new(node_id);
     builder.add_protocol(Protocol::Ip4(Ipv4Addr::LOCALHOST));
@@ -124,35 +143,111 @@ impl ShadowRouterHistory for SingleThreadDataWorkerHistory {
     fn set_ts(&self, _now: u64) {}
 }
 
+/// Non default configs of a test node, see [`TestNode::with_cfg`]
+#[derive(Default)]
+pub struct TestNodeCfg {
+    feature_targets: HashMap<Features, FeatureEventTarget>,
+    router_sync: RouterSyncCfg,
+    rekey: RekeyPolicy,
+    dht_kv: DhtKvCfg,
+    data: DataCfg,
+    neighbours: NeighboursCfg,
+    resolver: Option<Arc<dyn NameResolver>>,
+    accept_policy: Option<Arc<dyn AcceptPolicy>>,
+    features: FeaturesConfig,
+    pubsub: PubSubCfg,
+    feature_versions: HashMap<Features, u8>,
+    /// Also bind and advertise the IPv6 loopback
+    dual_stack: bool,
+}
+
+#[allow(dead_code)]
+impl TestNodeCfg {
+    pub fn feature_targets(mut self, feature_targets: HashMap<Features, FeatureEventTarget>) -> Self {
+        self.feature_targets = feature_targets;
+        self
+    }
+
+    pub fn router_sync(mut self, router_sync: RouterSyncCfg) -> Self {
+        self.router_sync = router_sync;
+        self
+    }
+
+    pub fn rekey(mut self, rekey: RekeyPolicy) -> Self {
+        self.rekey = rekey;
+        self
+    }
+
+    pub fn dht_kv(mut self, dht_kv: DhtKvCfg) -> Self {
+        self.dht_kv = dht_kv;
+        self
+    }
+
+    pub fn neighbours(mut self, neighbours: NeighboursCfg) -> Self {
+        self.neighbours = neighbours;
+        self
+    }
+
+    pub fn data(mut self, data: DataCfg) -> Self {
+        self.data = data;
+        self
+    }
+
+    pub fn resolver(mut self, resolver: Arc<dyn NameResolver>) -> Self {
+        self.resolver = Some(resolver);
+        self
+    }
+
+    pub fn accept_policy(mut self, accept_policy: Arc<dyn AcceptPolicy>) -> Self {
+        self.accept_policy = Some(accept_policy);
+        self
+    }
+
+    pub fn features(mut self, features: FeaturesConfig) -> Self {
+        self.features = features;
+        self
+    }
+
+    pub fn pubsub(mut self, pubsub: PubSubCfg) -> Self {
+        self.pubsub = pubsub;
+        self
+    }
+
+    pub fn dual_stack(mut self) -> Self {
+        self.dual_stack = true;
+        self
+    }
+
+    /// Advertises `feature_versions` instead of the versions of this build, like a node which is not upgraded yet
+    pub fn feature_versions(mut self, feature_versions: HashMap<Features, u8>) -> Self {
+        self.feature_versions = feature_versions;
+        self
+    }
+}
+
 pub struct TestNode<SC, SE, TC, TW> {
     node_id: NodeId,
-    worker: SdnWorker<(), SC, SE, TC, TW>,
+    dual_stack: bool,
+    node: Node<(), SC, SE, TC, TW>,
+    /// Follows the simulator time, set on each tick
+    clock: Arc<ManualClock>,
 }
 
 #[allow(clippy::type_complexity)]
 impl<SC: Debug, SE: Debug, TC: Debug, TW: Debug> TestNode<SC, SE, TC, TW> {
     pub fn new(node_id: NodeId, session: u64, services: Vec<Arc<dyn ServiceBuilder<(), FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>) -> Self {
+        Self::with_cfg(node_id, session, services, Default::default())
+    }
+
+    pub fn with_cfg(node_id: NodeId, session: u64, services: Vec<Arc<dyn ServiceBuilder<(), FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>, cfg: TestNodeCfg) -> Self {
         let _log = AutoContext::new(node_id);
-        let authorization: Arc<StaticKeyAuthorization> = Arc::new(StaticKeyAuthorization::new("demo-key"));
-        let handshake_builder = Arc::new(HandshakeBuilderXDA);
-        let random = Box::new(StepRng::new(1000, 5));
-        let history = Arc::new(SingleThreadDataWorkerHistory::default());
+        let clock = Arc::new(ManualClock::default());
+        let dual_stack = cfg.dual_stack;
         Self {
             node_id,
-            worker: SdnWorker::new(SdnWorkerCfg {
-                node_id,
-                tick_ms: 1,
-                controller: Some(ControllerPlaneCfg {
-                    session,
-                    bind_addrs: vec![node_to_addr(node_id)],
-                    services: services.clone(),
-                    authorization,
-                    handshake_builder,
-                    random,
-                    history: history.clone(),
-                }),
-                data: DataPlaneCfg { worker_id: 0, services, history },
-            }),
+            dual_stack,
+            clock: clock.clone(),
+            node: Node::new(node_cfg(node_id, session, services, cfg, clock)),
         }
     }
 
@@ -161,48 +256,135 @@ impl<SC: Debug, SE: Debug, TC: Debug, TW: Debug> TestNode<SC, SE, TC, TW> {
     }
 
     pub fn addr(&self) -> NodeAddr {
-        build_addr(self.node_id)
+        if !self.dual_stack {
+            return build_addr(self.node_id);
+        }
+        let mut builder = NodeAddrBuilder::new(self.node_id);
+        builder.add_protocol(Protocol::Ip4(Ipv4Addr::LOCALHOST));
+        builder.add_protocol(Protocol::Udp(self.node_id as u16));
+        builder.add_protocol(Protocol::Ip6(Ipv6Addr::LOCALHOST));
+        builder.add_protocol(Protocol::Udp(self.node_id as u16));
+        builder.addr()
+    }
+
+    pub fn connection_counts(&self) -> ConnectionCounts {
+        self.node.connection_counts()
+    }
+
+    pub fn connection_rtt_ms(&self, conn: ConnId) -> Option<u32> {
+        self.node.connection_rtt_ms(conn)
+    }
+
+    pub fn is_converged(&self) -> bool {
+        self.node.is_converged()
+    }
+
+    pub fn feature_unavailable_count(&self) -> u64 {
+        self.node.feature_unavailable_count()
+    }
+
+    pub fn register_service(&mut self, service: Arc<dyn ServiceBuilder<(), FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>) {
+        let _log = AutoContext::new(self.node_id);
+        self.node.register_service(service);
+    }
+
+    pub fn unregister_service(&mut self, service: ServiceId) -> bool {
+        let _log = AutoContext::new(self.node_id);
+        self.node.unregister_service(service)
     }
 
     pub fn tick(&mut self, now: u64) {
         let _log = AutoContext::new(self.node_id);
-        self.worker.on_tick(now);
+        self.clock.set_ms(now);
+        self.node.on_tick(self.node.now_ms());
     }
 
     pub fn on_input(&mut self, now: u64, input: TestNodeIn<SC>) {
         let _log = AutoContext::new(self.node_id);
-        let input = match input {
-            TestNodeIn::Ext(ext_in) => SdnWorkerInput::Ext(ext_in),
-            TestNodeIn::ExtWorker(ext_in) => SdnWorkerInput::ExtWorker(ext_in),
-            TestNodeIn::Udp(addr, buf) => SdnWorkerInput::Net(data_plane::NetInput::UdpPacket(addr, buf)),
+        match input {
+            TestNodeIn::Ext(ext_in) => self.node.on_ext(now, ext_in),
+            TestNodeIn::ExtWorker(ext_in) => self.node.on_ext_worker(now, ext_in),
+            TestNodeIn::Udp(addr, buf) => self.node.on_udp(now, addr, buf),
             #[cfg(feature = "vpn")]
-            TestNodeIn::Tun(buf) => SdnWorkerInput::Net(data_plane::NetInput::TunPacket(buf)),
-        };
-        self.worker.on_event(now, input);
+            TestNodeIn::Tun(buf) => self.node.on_tun(now, buf),
+        }
     }
 
-    pub fn pop_output(&mut self, now: u64) -> Option<TestNodeOut<SE>> {
+    pub fn pop_output(&mut self, now: u64) -> Option<NodeOutput<(), SE>> {
         let _log = AutoContext::new(self.node_id);
-        let output = self.worker.pop_output(now)?;
-        Some(self.process_worker_output(now, output))
+        self.node.pop_output(now)
     }
+}
 
-    fn process_worker_output(&mut self, now: u64, output: SdnWorkerOutput<(), SC, SE, TC, TW>) -> TestNodeOut<SE> {
-        match output {
-            SdnWorkerOutput::Ext(ext) => TestNodeOut::Ext(ext),
-            SdnWorkerOutput::ExtWorker(ext) => TestNodeOut::ExtWorker(ext),
-            SdnWorkerOutput::Net(data_plane::NetOutput::UdpPacket(dest, data)) => TestNodeOut::Udp(vec![dest], data),
-            SdnWorkerOutput::Net(data_plane::NetOutput::UdpPackets(dests, data)) => TestNodeOut::Udp(dests, data),
-            #[cfg(feature = "vpn")]
-            SdnWorkerOutput::Net(data_plane::NetOutput::TunPacket(data)) => TestNodeOut::Tun(data),
-            SdnWorkerOutput::Bus(bus) => {
-                self.worker.on_event(now, SdnWorkerInput::Bus(bus));
-                TestNodeOut::Continue
-            }
-            SdnWorkerOutput::OnResourceEmpty => TestNodeOut::Continue,
-            SdnWorkerOutput::Continue => TestNodeOut::Continue,
-        }
+/// Config of a test node with the simulator addrs, see [`TestNode`]
+#[allow(clippy::type_complexity)]
+fn node_cfg<SC, SE, TC, TW>(
+    node_id: NodeId,
+    session: u64,
+    services: Vec<Arc<dyn ServiceBuilder<(), FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>,
+    cfg: TestNodeCfg,
+    clock: Arc<dyn Clock>,
+) -> NodeCfg<(), SC, SE, TC, TW> {
+    let authorization: Arc<StaticKeyAuthorization> = Arc::new(StaticKeyAuthorization::new("demo-key"));
+    let handshake_builder = Arc::new(HandshakeBuilderXDA);
+    //first value is used for features seeds, so neighbours sessions start from 1000
+    let random = node_random(node_id, 0, 995);
+    let history = Arc::new(SingleThreadDataWorkerHistory::default());
+    let mut bind_addrs = vec![node_to_addr(node_id)];
+    if cfg.dual_stack {
+        bind_addrs.push(node_to_addr6(node_id));
     }
+    NodeCfg {
+        node_id,
+        tick_ms: 1,
+        controller: ControllerPlaneCfg {
+            session,
+            bind_addrs,
+            services: services.clone(),
+            authorization,
+            handshake_builder,
+            identity: None,
+            accept_policy: cfg.accept_policy.unwrap_or_else(|| Arc::new(AcceptAll)),
+            random,
+            history: history.clone(),
+            unknown_service: Default::default(),
+            router_sync: cfg.router_sync,
+            dht_kv: cfg.dht_kv,
+            data: cfg.data,
+            neighbours: cfg.neighbours,
+            vpn: Default::default(),
+            pubsub: cfg.pubsub,
+            cipher_suites: CipherSuite::DEFAULT_PREFERENCE.to_vec(),
+            codecs: WireCodec::DEFAULT_PREFERENCE.to_vec(),
+            feature_weights: Default::default(),
+            feature_versions: cfg.feature_versions,
+            features: cfg.features,
+            resolver: cfg.resolver,
+        },
+        data: DataPlaneCfg {
+            worker_id: 0,
+            services,
+            history,
+            unknown_service: Default::default(),
+            random: node_random(node_id, 1, 0),
+            rekey: cfg.rekey,
+            max_ttl: DEFAULT_MSG_TTL,
+            output_queue: Default::default(),
+            feature_weights: Default::default(),
+            dscp: Default::default(),
+            shapers: Default::default(),
+            features: cfg.features,
+            max_payloads: Default::default(),
+        },
+        feature_targets: cfg.feature_targets,
+        clock,
+    }
+}
+
+/// Config of a node which runs on its own thread with the system clock, the bind addrs are set by the transport
+#[allow(dead_code, clippy::type_complexity)]
+pub fn live_node_cfg<SC, SE, TC, TW>(node_id: NodeId, session: u64, services: Vec<Arc<dyn ServiceBuilder<(), FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>) -> NodeCfg<(), SC, SE, TC, TW> {
+    node_cfg(node_id, session, services, Default::default(), Arc::new(SystemClock::default()))
 }
 
 pub fn addr_to_node(addr: SocketAddr) -> NodeId {
@@ -213,6 +395,47 @@ pub fn node_to_addr(node: NodeId) -> SocketAddr {
     SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), node as u16)
 }
 
+pub fn node_to_addr6(node: NodeId) -> SocketAddr {
+    SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), node as u16)
+}
+
+/// Behaviour of a directed link between two nodes, the default is a lossless link without delay
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkModel {
+    /// Percent of packets which are dropped
+    pub loss_pct: u8,
+    /// Percent of packets which are held back one more step, so packets sent after them arrive first
+    pub reorder_pct: u8,
+    pub extra_latency_ms: u64,
+    /// Bandwidth of the link, packets wait until previous ones are sent. 0 is unlimited
+    pub bytes_per_ms: u64,
+}
+
+/// Source translation applied to the packets of a node. Mapped addresses are in `100.64.0.0/10` and keep the port,
+/// because the simulator finds nodes by port. Packets to a mapped address are delivered to the private address again
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatModel {
+    /// All destinations see the same public address
+    Cone,
+    /// Each destination sees another public address
+    Symmetric,
+}
+
+impl NatModel {
+    fn map(&self, local: SocketAddr, dest: NodeId) -> SocketAddr {
+        let ip = match self {
+            NatModel::Cone => Ipv4Addr::new(100, 64, 0, 1),
+            NatModel::Symmetric => Ipv4Addr::new(100, 65, (dest >> 8) as u8, dest as u8),
+        };
+        SocketAddr::new(IpAddr::V4(ip), local.port())
+    }
+
+    fn is_mapped(addr: SocketAddr) -> bool {
+        matches!(addr.ip(), IpAddr::V4(ip) if ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64)
+    }
+}
+
 pub struct NetworkSimulator<SC, SE, TC: Clone, TW: Clone> {
     clock_ms: u64,
     input: VecDeque<(NodeId, ExtIn<(), SC>)>,
@@ -222,10 +445,38 @@ pub struct NetworkSimulator<SC, SE, TC: Clone, TW: Clone> {
     nodes: Vec<TestNode<SC, SE, TC, TW>>,
     nodes_index: HashMap<NodeId, usize>,
     switcher: TaskSwitcher,
+    links: HashMap<(NodeId, NodeId), LinkModel>,
+    /// Group of each node while the network is partitioned, nodes which are not listed share one group
+    partitions: Option<HashMap<NodeId, usize>>,
+    nats: HashMap<NodeId, NatModel>,
+    /// Packets waiting for delivery, ordered by (deliver_at, seq)
+    in_flight: BTreeMap<(u64, u64), (NodeId, NetPair, Buffer)>,
+    in_flight_seq: u64,
+    /// Time until which a rate limited link is busy sending previous packets
+    link_busy_until: HashMap<(NodeId, NodeId), u64>,
+    link_random: StdRng,
+    /// Sent udp packets, only recorded after `capture_packets`
+    captured: Option<Vec<Vec<u8>>>,
 }
 
 impl<SC: Debug, SE: Debug, TC: Debug + Clone, TW: Debug + Clone> NetworkSimulator<SC, SE, TC, TW> {
+    #[allow(dead_code)]
     pub fn new(started_ms: u64) -> Self {
+        SIM_SEED.set(None);
+        Self::build(started_ms)
+    }
+
+    /// Simulator with deterministic random sources. Nodes created after this call in the same thread
+    /// get random sources derived from `seed` and their node id, so a failing run can be replayed from the logged seed
+    #[allow(dead_code)]
+    pub fn with_seed(started_ms: u64, seed: u64) -> Self {
+        log::info!("NetworkSimulator seed {}", seed);
+        SIM_SEED.set(Some(seed));
+        Self::build(started_ms)
+    }
+
+    fn build(started_ms: u64) -> Self {
+        LOG_WARNINGS.set(0);
         Self {
             clock_ms: started_ms,
             input: VecDeque::new(),
@@ -235,6 +486,14 @@ impl<SC: Debug, SE: Debug, TC: Debug + Clone, TW: Debug + Clone> NetworkSimulato
             nodes: Vec::new(),
             nodes_index: HashMap::new(),
             switcher: TaskSwitcher::new(0),
+            links: HashMap::new(),
+            partitions: None,
+            nats: HashMap::new(),
+            in_flight: BTreeMap::new(),
+            in_flight_seq: 0,
+            link_busy_until: HashMap::new(),
+            link_random: StdRng::seed_from_u64(SIM_SEED.get().unwrap_or(0)),
+            captured: None,
         }
     }
 
@@ -244,6 +503,12 @@ impl<SC: Debug, SE: Debug, TC: Debug + Clone, TW: Debug + Clone> NetworkSimulato
         log::set_max_level(level);
     }
 
+    /// Warn and error records logged by this test since the simulator was created, needs `enable_log`
+    #[allow(unused)]
+    pub fn log_warnings(&self) -> usize {
+        LOG_WARNINGS.get()
+    }
+
     pub fn control(&mut self, node: NodeId, control: ExtIn<(), SC>) {
         self.input.push_back((node, control));
     }
@@ -262,6 +527,110 @@ impl<SC: Debug, SE: Debug, TC: Debug + Clone, TW: Debug + Clone> NetworkSimulato
         self.output_worker.pop_front()
     }
 
+    /// Set the model of the link from `from` to `to`, the other direction is not affected
+    #[allow(dead_code)]
+    pub fn set_link(&mut self, from: NodeId, to: NodeId, model: LinkModel) {
+        if model == LinkModel::default() {
+            self.links.remove(&(from, to));
+        } else {
+            self.links.insert((from, to), model);
+        }
+    }
+
+    /// Put the node behind a NAT, it must be set before connecting because packets of open connections would come from another address
+    #[allow(dead_code)]
+    pub fn set_nat(&mut self, node: NodeId, model: NatModel) {
+        self.nats.insert(node, model);
+    }
+
+    /// Split the network, packets between nodes in different groups are dropped until `heal` is called
+    #[allow(dead_code)]
+    pub fn partition(&mut self, groups: Vec<Vec<NodeId>>) {
+        let mut partitions = HashMap::new();
+        for (index, group) in groups.into_iter().enumerate() {
+            for node in group {
+                partitions.insert(node, index);
+            }
+        }
+        log::info!("Partition network {:?}", partitions);
+        self.partitions = Some(partitions);
+    }
+
+    #[allow(dead_code)]
+    pub fn heal(&mut self) {
+        log::info!("Heal network partitions");
+        self.partitions = None;
+    }
+
+    fn is_partitioned(&self, from: NodeId, to: NodeId) -> bool {
+        match &self.partitions {
+            Some(partitions) => partitions.get(&from) != partitions.get(&to),
+            None => false,
+        }
+    }
+
+    /// Record every udp packet sent from now on, including dropped ones
+    #[allow(dead_code)]
+    pub fn capture_packets(&mut self) {
+        self.captured.get_or_insert_with(Vec::new);
+    }
+
+    /// Take the packets recorded since `capture_packets` or the previous call
+    #[allow(dead_code)]
+    pub fn take_captured(&mut self) -> Vec<Vec<u8>> {
+        self.captured.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    #[allow(unused)]
+    pub fn connection_counts(&self, node: NodeId) -> ConnectionCounts {
+        self.nodes[self.nodes_index[&node]].connection_counts()
+    }
+
+    #[allow(unused)]
+    pub fn connection_rtt_ms(&self, node: NodeId, conn: ConnId) -> Option<u32> {
+        self.nodes[self.nodes_index[&node]].connection_rtt_ms(conn)
+    }
+
+    #[allow(unused)]
+    pub fn is_converged(&self, node: NodeId) -> bool {
+        self.nodes[self.nodes_index[&node]].is_converged()
+    }
+
+    /// Process in `step_ms` steps until the routing tables of all nodes are converged, returns the elapsed time.
+    /// Panics if they are not converged within `max_ms`
+    #[allow(unused)]
+    pub fn process_until_converged(&mut self, step_ms: u64, max_ms: u64) -> u64 {
+        let mut elapsed = 0;
+        loop {
+            self.process(step_ms);
+            elapsed += step_ms;
+            if self.nodes.iter().all(|node| node.is_converged()) {
+                return elapsed;
+            }
+            assert!(elapsed < max_ms, "routing is not converged after {max_ms} ms");
+        }
+    }
+
+    #[allow(unused)]
+    pub fn feature_unavailable_count(&self, node: NodeId) -> u64 {
+        self.nodes[self.nodes_index[&node]].feature_unavailable_count()
+    }
+
+    /// Install a service in a running node, its outputs are processed with the next `process`
+    #[allow(unused)]
+    pub fn register_service(&mut self, node: NodeId, service: Arc<dyn ServiceBuilder<(), FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>) {
+        let index = self.nodes_index[&node];
+        self.nodes[index].register_service(service);
+        self.switcher.flag_task(index);
+    }
+
+    #[allow(unused)]
+    pub fn unregister_service(&mut self, node: NodeId, service: ServiceId) -> bool {
+        let index = self.nodes_index[&node];
+        self.switcher.flag_task(index);
+        self.nodes[index].unregister_service(service)
+    }
+
     pub fn add_node(&mut self, node: TestNode<SC, SE, TC, TW>) -> NodeAddr {
         let index = self.nodes.len();
         self.nodes_index.insert(node.node_id(), index);
@@ -293,39 +662,111 @@ impl<SC: Debug, SE: Debug, TC: Debug + Clone, TW: Debug + Clone> NetworkSimulato
     }
 
     fn pop_outputs(&mut self, now: u64) {
-        while let Some(index) = self.switcher.current() {
-            let node = self.nodes[index].node_id();
-            if let Some(out) = self.nodes[index].pop_output(now) {
-                self.process_out(now, node, out);
-            } else {
-                self.switcher.finished(index);
+        loop {
+            while let Some(index) = self.switcher.current() {
+                let node = self.nodes[index].node_id();
+                if let Some(out) = self.nodes[index].pop_output(now) {
+                    self.process_out(now, node, out);
+                } else {
+                    self.switcher.finished(index);
+                }
+            }
+
+            if !self.deliver_in_flight(now) {
+                break;
             }
         }
     }
 
-    fn process_out(&mut self, now: u64, node: NodeId, out: TestNodeOut<SE>) {
+    /// Deliver all packets which are due, return false if there is nothing to deliver
+    fn deliver_in_flight(&mut self, now: u64) -> bool {
+        let mut delivered = false;
+        while let Some(entry) = self.in_flight.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            let (dest_node, pair, data) = entry.remove();
+            self.deliver_udp(now, dest_node, pair, data);
+            delivered = true;
+        }
+        delivered
+    }
+
+    fn deliver_udp(&mut self, now: u64, dest_node: NodeId, pair: NetPair, data: Buffer) {
+        let dest_index = *self.nodes_index.get(&dest_node).expect("Node not found");
+        self.switcher.flag_task(dest_index);
+        self.nodes[dest_index].on_input(now, TestNodeIn::Udp(pair, data));
+    }
+
+    fn send_udp(&mut self, now: u64, node: NodeId, dest: NetPair, data: Buffer) {
+        log::debug!("Send UDP packet from {} to {}, buf len {}", dest.local, dest.remote, data.len());
+        if let Some(captured) = self.captured.as_mut() {
+            captured.push(data.to_vec());
+        }
+        let dest_node = addr_to_node(dest.remote);
+        let source = match self.nats.get(&node) {
+            Some(nat) => nat.map(dest.local, dest_node),
+            None => dest.local,
+        };
+        let local = if NatModel::is_mapped(dest.remote) {
+            node_to_addr(dest_node)
+        } else {
+            dest.remote
+        };
+        let in_pair = NetPair::new(local, source);
+        if self.is_partitioned(node, dest_node) {
+            log::debug!("Drop UDP packet from {} to {} by partition", node, dest_node);
+            return;
+        }
+        let model = match self.links.get(&(node, dest_node)) {
+            Some(model) => *model,
+            None => {
+                self.deliver_udp(now, dest_node, in_pair, data);
+                return;
+            }
+        };
+        if self.link_random.gen_range(0..100) < model.loss_pct {
+            log::debug!("Drop UDP packet from {} to {}", node, dest_node);
+            return;
+        }
+        let mut deliver_at = now + model.extra_latency_ms;
+        if model.bytes_per_ms > 0 {
+            let busy_until = self.link_busy_until.entry((node, dest_node)).or_insert(now);
+            *busy_until = (*busy_until).max(now) + (data.len() as u64).div_ceil(model.bytes_per_ms);
+            deliver_at += *busy_until - now;
+        }
+        if self.link_random.gen_range(0..100) < model.reorder_pct {
+            deliver_at += 1;
+        }
+        self.in_flight.insert((deliver_at, self.in_flight_seq), (dest_node, in_pair, data));
+        self.in_flight_seq += 1;
+    }
+
+    fn process_out(&mut self, now: u64, node: NodeId, out: NodeOutput<(), SE>) {
         let node_index = *self.nodes_index.get(&node).expect("Node not found");
         self.switcher.flag_task(node_index);
         match out {
-            TestNodeOut::Ext(out) => {
+            NodeOutput::Ext(out) => {
                 self.output.push_back((node, out));
             }
-            TestNodeOut::ExtWorker(out) => {
+            NodeOutput::ExtWorker(out) => {
                 self.output_worker.push_back((node, out));
             }
-            TestNodeOut::Udp(dests, data) => {
+            NodeOutput::Net(data_plane::NetOutput::UdpPacket(dest, data)) => self.send_udp(now, node, dest, data),
+            NodeOutput::Net(data_plane::NetOutput::UdpPackets(dests, data)) => {
                 for dest in dests {
-                    log::debug!("Send UDP packet from {} to {}, buf len {}", dest.local, dest.remote, data.len());
-                    let dest_node = addr_to_node(dest.remote);
-                    let dest_index = *self.nodes_index.get(&dest_node).expect("Node not found");
-                    self.switcher.flag_task(dest_index);
-                    let in_pair = NetPair::new(dest.remote, dest.local);
-                    self.nodes[dest_index].on_input(now, TestNodeIn::Udp(in_pair, data.clone()));
+                    self.send_udp(now, node, dest, data.clone());
+                }
+            }
+            NodeOutput::Net(data_plane::NetOutput::UdpBatch(batch)) => {
+                for (dest, data) in batch {
+                    self.send_udp(now, node, dest, data);
                 }
             }
+            NodeOutput::Net(data_plane::NetOutput::UdpMarked(dest, _dscp, data)) => self.send_udp(now, node, dest, data),
+            NodeOutput::Backlog(_) => {}
             #[cfg(feature = "vpn")]
-            TestNodeOut::Tun(_) => todo!(),
-            TestNodeOut::Continue => {}
+            NodeOutput::Net(data_plane::NetOutput::TunPacket(_)) => todo!(),
         }
     }
 }
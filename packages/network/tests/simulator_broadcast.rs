@@ -0,0 +1,101 @@
+use std::{collections::HashMap, sync::Arc};
+
+use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_network::{
+    base::NetOutgoingMeta,
+    features::{data, FeaturesControl, FeaturesEvent},
+    services::manual_discovery::ManualDiscoveryServiceBuilder,
+    ExtIn, ExtOut,
+};
+use atm0s_sdn_router::{RouteRule, ServiceBroadcastLevel};
+
+use crate::simulator::{build_addr, NetworkSimulator, TestNode};
+
+mod simulator;
+
+fn node(id: NodeId, session: u64) -> TestNode<(), (), (), ()> {
+    TestNode::new(id, session, vec![Arc::new(ManualDiscoveryServiceBuilder::new(build_addr(id), vec![], vec![]))])
+}
+
+#[test]
+fn broadcast_in_triangle_should_deliver_once() {
+    // every node connects to both others, so each broadcast reaches a node over two paths
+    let (node1, node2, node3) = (1, 2, 3);
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    let addr1 = sim.add_node(node(node1, 1234));
+    let addr2 = sim.add_node(node(node2, 1235));
+    sim.add_node(node(node3, 1236));
+
+    sim.control(node2, ExtIn::ConnectTo(addr1.clone()));
+    sim.control(node3, ExtIn::ConnectTo(addr1));
+    sim.control(node3, ExtIn::ConnectTo(addr2));
+    for _i in 0..8 {
+        sim.process(500);
+    }
+
+    for node in [node1, node2, node3] {
+        sim.control(node, ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::DataListen(1))));
+    }
+    for seq in 0..4 {
+        let rule = RouteRule::ToServices(0, ServiceBroadcastLevel::Global, seq);
+        sim.control(
+            node1,
+            ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::DataSendRule(1, rule, NetOutgoingMeta::default(), vec![seq as u8]))),
+        );
+    }
+    sim.process(100);
+
+    let mut received: HashMap<NodeId, Vec<u8>> = HashMap::new();
+    while let Some((node, res)) = sim.pop_res() {
+        match res {
+            ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::Recv(1, _meta, data))) => received.entry(node).or_default().extend(data),
+            res => panic!("unexpected result {res:?} at node {node}"),
+        }
+    }
+    for node in [node1, node2, node3] {
+        let mut data = received.remove(&node).unwrap_or_default();
+        data.sort();
+        assert_eq!(data, vec![0, 1, 2, 3], "node {node} should receive each broadcast once");
+    }
+}
+
+/// Nodes which received the broadcast of `from`
+fn scoped_broadcast(sim: &mut NetworkSimulator<(), (), (), ()>, from: NodeId, seq: u16, radius: u8) -> Vec<NodeId> {
+    let rule = RouteRule::BroadcastScoped(0, seq, radius);
+    sim.control(
+        from,
+        ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::DataSendRule(1, rule, NetOutgoingMeta::default(), vec![seq as u8]))),
+    );
+    sim.process(100);
+    let mut received = vec![];
+    while let Some((node, res)) = sim.pop_res() {
+        match res {
+            ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::Recv(1, _meta, data))) if data == vec![seq as u8] => received.push(node),
+            res => panic!("unexpected result {res:?} at node {node}"),
+        }
+    }
+    received.sort();
+    received
+}
+
+#[test]
+fn scoped_broadcast_in_line_should_stop_at_radius() {
+    // node1 <-> node2 <-> node3 <-> node4
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    let addrs: Vec<_> = (1..=4).map(|id| sim.add_node(node(id, 1233 + id as u64))).collect();
+    for id in 2..=4 {
+        sim.control(id, ExtIn::ConnectTo(addrs[id as usize - 2].clone()));
+    }
+    for _i in 0..8 {
+        sim.process(500);
+    }
+    for id in 1..=4 {
+        sim.control(id, ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::DataListen(1))));
+    }
+
+    //radius 1 reaches only the neighbours
+    assert_eq!(scoped_broadcast(&mut sim, 2, 0, 1), vec![1, 2, 3]);
+    assert_eq!(scoped_broadcast(&mut sim, 2, 1, 0), vec![2]);
+    assert_eq!(scoped_broadcast(&mut sim, 1, 2, 2), vec![1, 2, 3]);
+    assert_eq!(scoped_broadcast(&mut sim, 1, 3, 3), vec![1, 2, 3, 4]);
+}
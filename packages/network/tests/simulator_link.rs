@@ -0,0 +1,113 @@
+use atm0s_sdn_network::{
+    features::{data, neighbours, FeaturesControl, FeaturesEvent},
+    ExtIn, ExtOut,
+};
+
+use crate::simulator::{LinkModel, NetworkSimulator, TestNode};
+
+mod simulator;
+
+fn ping_result(sim: &mut NetworkSimulator<(), (), (), ()>, steps: usize) -> Option<Option<u16>> {
+    for _ in 0..steps {
+        sim.process(10);
+        while let Some(res) = sim.pop_res() {
+            if let (_, ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::Pong(_, rtt)))) = res {
+                return Some(rtt);
+            }
+        }
+    }
+    None
+}
+
+#[test]
+fn simulator_link_extra_latency() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    let model = LinkModel {
+        extra_latency_ms: 100,
+        ..Default::default()
+    };
+    sim.set_link(node1, node2, model);
+    sim.set_link(node2, node1, model);
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::Ping(node2))));
+    assert_eq!(ping_result(&mut sim, 100), Some(Some(200)));
+}
+
+#[test]
+fn simulator_link_full_loss_blocks_connection() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.set_link(node1, node2, LinkModel { loss_pct: 100, ..Default::default() });
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    sim.control(node1, ExtIn::ConnectTo(addr2.clone()));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    assert_eq!(sim.pop_res(), None);
+
+    //back to the default lossless link
+    sim.set_link(node1, node2, LinkModel::default());
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    assert!(matches!(
+        sim.pop_res(),
+        Some((_, ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::Connected(node, _))))) if node == node2
+    ));
+}
+
+#[test]
+fn simulator_link_lossy_reordered_still_converge() {
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::with_seed(0, 1257);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+    let addr3 = sim.add_node(TestNode::new(node3, 1236, vec![]));
+
+    let model = LinkModel {
+        loss_pct: 20,
+        reorder_pct: 30,
+        extra_latency_ms: 5,
+        ..Default::default()
+    };
+    for (from, to) in [(node1, node2), (node2, node1), (node2, node3), (node3, node2)] {
+        sim.set_link(from, to, model);
+    }
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    sim.control(node2, ExtIn::ConnectTo(addr3));
+    for _i in 0..10 {
+        sim.process(500);
+    }
+
+    //pings can be lost, but some must arrive after router_sync converged
+    let mut success = 0;
+    for _ in 0..10 {
+        sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::Ping(node3))));
+        if let Some(Some(_)) = ping_result(&mut sim, 250) {
+            success += 1;
+        }
+    }
+    assert!(success > 0);
+}
@@ -0,0 +1,60 @@
+use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_network::{
+    features::{router_sync, FeaturesControl, FeaturesEvent},
+    ExtIn, ExtOut,
+};
+
+use crate::simulator::{NetworkSimulator, TestNode};
+
+mod simulator;
+
+fn reachable(sim: &mut NetworkSimulator<(), (), (), ()>, node: NodeId) -> Vec<u8> {
+    sim.control(node, ExtIn::FeaturesControl((), FeaturesControl::RouterSync(router_sync::Control::DumpRouter)));
+    sim.process(1);
+    match sim.pop_res() {
+        Some((res_node, ExtOut::FeaturesEvent((), FeaturesEvent::RouterSync(router_sync::Event::DumpRouter(dump))))) if res_node == node => dump.layer(0).dest_indexes(),
+        res => panic!("unexpected result {res:?}"),
+    }
+}
+
+#[test]
+fn simulator_partition_then_heal() {
+    // node1 <-> node2 <-> node3 <-> node4
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let node4 = 4;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+    let addr3 = sim.add_node(TestNode::new(node3, 1236, vec![]));
+    let addr4 = sim.add_node(TestNode::new(node4, 1237, vec![]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    sim.control(node2, ExtIn::ConnectTo(addr3.clone()));
+    sim.control(node3, ExtIn::ConnectTo(addr4));
+    for _i in 0..6 {
+        sim.process(500);
+    }
+    assert_eq!(reachable(&mut sim, node1), vec![2, 3, 4]);
+    assert_eq!(reachable(&mut sim, node4), vec![1, 2, 3]);
+
+    sim.partition(vec![vec![node1, node2], vec![node3, node4]]);
+    //connection between node2 and node3 times out without pong
+    for _i in 0..30 {
+        sim.process(500);
+    }
+    assert_eq!(reachable(&mut sim, node1), vec![2]);
+    assert_eq!(reachable(&mut sim, node2), vec![1]);
+    assert_eq!(reachable(&mut sim, node3), vec![4]);
+    assert_eq!(reachable(&mut sim, node4), vec![3]);
+
+    sim.heal();
+    sim.control(node2, ExtIn::ConnectTo(addr3));
+    for _i in 0..10 {
+        sim.process(500);
+    }
+    assert_eq!(reachable(&mut sim, node1), vec![2, 3, 4]);
+    assert_eq!(reachable(&mut sim, node4), vec![1, 2, 3]);
+}
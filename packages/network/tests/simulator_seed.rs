@@ -0,0 +1,67 @@
+use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_network::{
+    features::{neighbours, FeaturesControl, FeaturesEvent},
+    ExtIn, ExtOut,
+};
+
+use crate::simulator::{NetworkSimulator, TestNode};
+
+mod simulator;
+
+fn run_connect(seed: Option<u64>) -> Vec<(NodeId, ExtOut<(), ()>)> {
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let mut sim = match seed {
+        Some(seed) => NetworkSimulator::<(), (), (), ()>::with_seed(0, seed),
+        None => NetworkSimulator::<(), (), (), ()>::new(0),
+    };
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+    let addr3 = sim.add_node(TestNode::new(node3, 1236, vec![]));
+
+    for node in [node1, node2, node3] {
+        sim.control(node, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    }
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    sim.control(node1, ExtIn::ConnectTo(addr3));
+
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    let mut out = vec![];
+    while let Some(res) = sim.pop_res() {
+        out.push(res);
+    }
+    out
+}
+
+#[test]
+fn simulator_same_seed_should_replay_same_run() {
+    let run1 = run_connect(Some(42));
+    let run2 = run_connect(Some(42));
+    assert_eq!(run1.len(), 4);
+    assert_eq!(run1, run2);
+}
+
+#[test]
+fn simulator_seed_should_drive_session_ids() {
+    let run1 = run_connect(Some(42));
+    let run2 = run_connect(Some(43));
+    assert_eq!(run1.len(), run2.len());
+    assert_ne!(run1, run2);
+    assert_ne!(run1, run_connect(None));
+
+    //each node has its own random source, so sessions to node1 from node2 and node3 don't collide
+    let conns_to_node1: Vec<_> = run1
+        .iter()
+        .filter_map(|(node, out)| match out {
+            ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::Connected(1, conn))) if *node != 1 => Some(*conn),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(conns_to_node1.len(), 2);
+    assert_ne!(conns_to_node1[0].session(), conns_to_node1[1].session());
+}
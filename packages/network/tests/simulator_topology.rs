@@ -0,0 +1,113 @@
+use atm0s_sdn_identity::{ConnDirection, NodeId};
+use atm0s_sdn_network::{ExtIn, ExtOut, Topology};
+use atm0s_sdn_router::core::{RouteExplanation, RouteReason};
+
+use crate::simulator::{node_to_addr, NetworkSimulator, TestNode};
+
+mod simulator;
+
+fn query_topology(sim: &mut NetworkSimulator<(), (), (), ()>, node: NodeId) -> Box<Topology> {
+    sim.control(node, ExtIn::QueryTopology(()));
+    sim.process(1);
+    match sim.pop_res() {
+        Some((res_node, ExtOut::Topology((), topology))) if res_node == node => topology,
+        res => panic!("unexpected result {res:?}"),
+    }
+}
+
+fn explain_route(sim: &mut NetworkSimulator<(), (), (), ()>, node: NodeId, dest: NodeId) -> Option<Box<RouteExplanation>> {
+    sim.control(node, ExtIn::ExplainRoute((), dest));
+    sim.process(1);
+    match sim.pop_res() {
+        Some((res_node, ExtOut::RouteExplanation((), res_dest, explanation))) if res_node == node && res_dest == dest => explanation,
+        res => panic!("unexpected result {res:?}"),
+    }
+}
+
+#[test]
+fn simulator_topology_should_match_mesh() {
+    // node1 <-> node2 <-> node3, node4 only connects to node3
+    let (node1, node2, node3, node4) = (1, 2, 3, 4);
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    let addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+    let addr3 = sim.add_node(TestNode::new(node3, 1236, vec![]));
+    sim.add_node(TestNode::new(node4, 1237, vec![]));
+
+    sim.control(node2, ExtIn::ConnectTo(addr1));
+    sim.control(node3, ExtIn::ConnectTo(addr2));
+    sim.control(node4, ExtIn::ConnectTo(addr3));
+    for _i in 0..8 {
+        sim.process(500);
+    }
+
+    let topology = query_topology(&mut sim, node2);
+    let neighbours: Vec<_> = topology.neighbours.iter().map(|info| (info.node, info.remote)).collect();
+    assert_eq!(neighbours, vec![(node1, node_to_addr(node1)), (node3, node_to_addr(node3))]);
+    assert!(topology.neighbours.iter().all(|info| info.rtt_ms.is_some()));
+    assert_eq!(topology.routes.layer(0).dest_indexes(), vec![1, 3, 4]);
+
+    let topology = query_topology(&mut sim, node4);
+    let neighbours: Vec<_> = topology.neighbours.iter().map(|info| info.node).collect();
+    assert_eq!(neighbours, vec![node3]);
+    assert_eq!(topology.routes.layer(0).dest_indexes(), vec![1, 2, 3]);
+}
+
+#[test]
+fn simulator_topology_should_show_direction_and_uptime() {
+    let (node1, node2, node3) = (1, 2, 3);
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    let addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    sim.add_node(TestNode::new(node2, 1235, vec![]));
+    let addr3 = sim.add_node(TestNode::new(node3, 1236, vec![]));
+
+    //node2 dials both, so node1 and node3 only have inbound connections
+    sim.control(node2, ExtIn::ConnectTo(addr1));
+    sim.control(node2, ExtIn::ConnectTo(addr3));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    let topology = query_topology(&mut sim, node2);
+    assert_eq!(topology.neighbours.len(), 2);
+    assert!(topology.neighbours.iter().all(|info| info.direction == ConnDirection::Outgoing));
+    let topology1 = query_topology(&mut sim, node1);
+    assert_eq!(topology1.neighbours.len(), 1);
+    assert_eq!(topology1.neighbours[0].node, node2);
+    assert_eq!(topology1.neighbours[0].direction, ConnDirection::Incoming);
+
+    //uptime grows with the simulator time while the connection stays the same
+    let before = &topology.neighbours[0];
+    sim.process(1000);
+    let topology = query_topology(&mut sim, node2);
+    let after = &topology.neighbours[0];
+    assert_eq!((after.conn, after.established_ms), (before.conn, before.established_ms));
+    assert!(after.uptime_ms >= before.uptime_ms + 1000, "{} then {}", before.uptime_ms, after.uptime_ms);
+}
+
+#[test]
+fn simulator_explain_route_should_show_candidates() {
+    // node1, node2 and node3 in a triangle
+    let (node1, node2, node3) = (1, 2, 3);
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    let addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+    sim.add_node(TestNode::new(node3, 1236, vec![]));
+
+    sim.control(node2, ExtIn::ConnectTo(addr1.clone()));
+    // without SIM_SEED every node draws the same session sequence, connect node3 to node2 first
+    // so its session towards node1 differs from node2's one
+    sim.control(node3, ExtIn::ConnectTo(addr2));
+    sim.control(node3, ExtIn::ConnectTo(addr1));
+    for _i in 0..8 {
+        sim.process(500);
+    }
+
+    let explanation = explain_route(&mut sim, node1, node3).expect("Should explain remote node");
+    let overs: Vec<_> = explanation.candidates.iter().map(|c| c.over).collect();
+    assert_eq!(overs, vec![node3, node2]);
+    assert_eq!(explanation.chosen().map(|c| c.over), Some(node3));
+    assert_eq!(explanation.reason, Some(RouteReason::FewerHops));
+
+    assert_eq!(explain_route(&mut sim, node1, node1), None);
+}
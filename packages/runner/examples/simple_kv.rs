@@ -113,7 +113,7 @@ fn main() {
             log::info!("Set key: {:?}", data);
             controller.send_to(
                 0,
-                SdnExtIn::FeaturesControl((), FeaturesControl::DhtKv(Control::MapCmd(Map(args.kv_map), MapControl::Set(Key(200), data)))),
+                SdnExtIn::FeaturesControl((), FeaturesControl::DhtKv(Control::MapCmd(Map(args.kv_map), MapControl::Set(Key(200), data, None)))),
             );
         }
         while let Some(out) = controller.pop_event() {
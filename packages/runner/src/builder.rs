@@ -1,4 +1,5 @@
 use std::{
+    collections::HashMap,
     fmt::Debug,
     hash::Hash,
     marker::PhantomData,
@@ -8,24 +9,54 @@ use std::{
 };
 
 use atm0s_sdn_identity::{NodeAddr, NodeAddrBuilder, NodeId, Protocol};
+#[cfg(feature = "vpn")]
+use atm0s_sdn_network::features::vpn::{Ipv4Cidr, VpnResolveCfg, VpnRoute};
 use atm0s_sdn_network::{
-    base::{Authorization, HandshakeBuilder, ServiceBuilder},
-    features::{FeaturesControl, FeaturesEvent},
-    secure::{HandshakeBuilderXDA, StaticKeyAuthorization},
+    base::{
+        AcceptAll, AcceptPolicy, Authorization, CipherSuite, Clock, FeatureEventTarget, HandshakeBuilder, NameResolver, RekeyPolicy, ServiceBuilder, SystemClock, UnknownServicePolicy, WireCodec,
+        DEFAULT_MSG_TTL,
+    },
+    data_plane::{OutputQueueCfg, OverflowPolicy, ShaperCfg},
+    features::{
+        data::DataCfg,
+        dht_kv::DhtKvCfg,
+        neighbours::{HandshakeRateCfg, IpPreference, NeighboursCfg},
+        pubsub::{ChannelAuthorizer, ChannelId, PubSubCfg, RetainCfg},
+        router_sync::{RouterSyncCfg, SyncIntervalCfg},
+        vpn::VpnCfg,
+        Features, FeaturesConfig, FeaturesControl, FeaturesEvent,
+    },
+    secure::{HandshakeBuilderXDA, NodeIdentity, StaticKeyAuthorization},
     services::{manual_discovery, visualization},
 };
+use atm0s_sdn_router::core::{FlapDampingCfg, MetricCompareMode};
+use atm0s_sdn_router::shadow::{LruShadowHistory, ShadowRouterHistory};
+#[cfg(feature = "vpn")]
+use atm0s_sdn_router::ServiceBroadcastLevel;
 use rand::{thread_rng, RngCore};
 use sans_io_runtime::backend::Backend;
 use serde::{de::DeserializeOwned, Serialize};
 
 use crate::{
-    history::DataWorkerHistory,
+    resolver::ThreadResolver,
     worker_inner::{ControllerCfg, SdnController, SdnExtIn, SdnInnerCfg, SdnOwner, SdnWorkerInner},
 };
 
+/// Builder of a node which runs on the sans-io-runtime backend.
+///
+/// The backend can't set socket options, so there is no DSCP setting here and packets are sent without marks.
+/// Applications which need marking drive a `Node` with `DataPlaneCfg::dscp` and their own `MarkedUdpSocket`
 pub struct SdnBuilder<UserData, SC, SE, TC, TW, NodeInfo> {
     auth: Option<Arc<dyn Authorization>>,
     handshake: Option<Arc<dyn HandshakeBuilder>>,
+    identity: Option<Arc<NodeIdentity>>,
+    accept_policy: Option<Arc<dyn AcceptPolicy>>,
+    resolver: Option<Arc<dyn NameResolver>>,
+    clock: Option<Arc<dyn Clock>>,
+    history: Option<Arc<dyn ShadowRouterHistory>>,
+    cipher_suites: Vec<CipherSuite>,
+    codecs: Vec<WireCodec>,
+    feature_versions: HashMap<Features, u8>,
     node_addr: NodeAddr,
     node_id: NodeId,
     session: u64,
@@ -35,6 +66,21 @@ pub struct SdnBuilder<UserData, SC, SE, TC, TW, NodeInfo> {
     seeds: Vec<NodeAddr>,
     #[allow(clippy::type_complexity)]
     services: Vec<Arc<dyn ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>,
+    unknown_service: UnknownServicePolicy,
+    feature_targets: HashMap<Features, FeatureEventTarget>,
+    router_sync: RouterSyncCfg,
+    dht_kv: DhtKvCfg,
+    data: DataCfg,
+    neighbours: NeighboursCfg,
+    vpn: VpnCfg,
+    pubsub: PubSubCfg,
+    rekey: RekeyPolicy,
+    max_ttl: u8,
+    output_queue: OutputQueueCfg,
+    shapers: HashMap<Features, ShaperCfg>,
+    max_payloads: HashMap<Features, usize>,
+    feature_weights: HashMap<Features, u8>,
+    features: FeaturesConfig,
     #[cfg(feature = "vpn")]
     vpn_enable: bool,
     #[cfg(feature = "vpn")]
@@ -60,6 +106,14 @@ where
         Self {
             auth: None,
             handshake: None,
+            identity: None,
+            accept_policy: None,
+            resolver: None,
+            clock: None,
+            history: None,
+            cipher_suites: CipherSuite::DEFAULT_PREFERENCE.to_vec(),
+            codecs: WireCodec::DEFAULT_PREFERENCE.to_vec(),
+            feature_versions: HashMap::new(),
             node_addr,
             node_id,
             tick_ms: 1000,
@@ -68,6 +122,21 @@ where
             visualization_collector: false,
             seeds: vec![],
             services: vec![],
+            unknown_service: UnknownServicePolicy::default(),
+            feature_targets: HashMap::new(),
+            router_sync: RouterSyncCfg::default(),
+            dht_kv: DhtKvCfg::default(),
+            data: DataCfg::default(),
+            neighbours: NeighboursCfg::default(),
+            vpn: VpnCfg::default(),
+            pubsub: PubSubCfg::default(),
+            rekey: RekeyPolicy::default(),
+            max_ttl: DEFAULT_MSG_TTL,
+            output_queue: OutputQueueCfg::default(),
+            shapers: HashMap::new(),
+            max_payloads: HashMap::new(),
+            feature_weights: HashMap::new(),
+            features: FeaturesConfig::default(),
             #[cfg(feature = "vpn")]
             vpn_enable: false,
             #[cfg(feature = "vpn")]
@@ -96,6 +165,82 @@ where
         self.handshake = Some(Arc::new(handshake));
     }
 
+    /// Setting the time source of all workers, default is SystemClock.
+    /// All nodes of a network must agree on the time within the neighbour message timeout
+    pub fn set_clock<C: Clock + 'static>(&mut self, clock: C) {
+        self.clock = Some(Arc::new(clock));
+    }
+
+    /// Setting the history which drops broadcasts that were already received, shared by all workers.
+    /// Default is LruShadowHistory with HISTORY_CAPACITY entries for HISTORY_TTL_MS, keep a clone to read its stats
+    pub fn set_history(&mut self, history: Arc<dyn ShadowRouterHistory>) {
+        self.history = Some(history);
+    }
+
+    /// Setting how hostnames of NodeAddr are resolved, default is ThreadResolver with the system resolver
+    pub fn set_resolver<R: NameResolver + 'static>(&mut self, resolver: R) {
+        self.resolver = Some(Arc::new(resolver));
+    }
+
+    /// Setting cipher preference of connections, from most to least preferred.
+    /// ChaCha20-Poly1305 is always accepted when the remote doesn't support any of them
+    pub fn set_cipher_suites(&mut self, suites: Vec<CipherSuite>) {
+        self.cipher_suites = suites;
+    }
+
+    /// Setting codec preference of neighbour controls, from most to least preferred.
+    /// Bincode is always accepted when the remote doesn't support any of them
+    pub fn set_codecs(&mut self, codecs: Vec<WireCodec>) {
+        self.codecs = codecs;
+    }
+
+    /// Setting when connections rotate their session key by a new key exchange, disabled by default
+    pub fn set_rekey_policy(&mut self, policy: RekeyPolicy) {
+        self.rekey = policy;
+    }
+
+    /// Setting the max TTL of outgoing packets, incoming packets with a higher TTL are dropped.
+    /// All nodes in a network should use the same value
+    pub fn set_max_ttl(&mut self, ttl: u8) {
+        self.max_ttl = ttl;
+    }
+
+    /// Setting how many packets of bulk features each worker queues and what happens when the queue is full.
+    /// Packets of neighbours and router_sync are never dropped
+    pub fn set_output_queue(&mut self, capacity: usize, policy: OverflowPolicy) {
+        self.output_queue.capacity = capacity;
+        self.output_queue.policy = policy;
+    }
+
+    /// Setting a rate for the outgoing packets of a bulk feature, bursts above `burst_bytes` wait for the tick loop.
+    /// The rate is applied by each worker on its own. Control features are never shaped
+    pub fn set_shaper(&mut self, feature: Features, bytes_per_sec: u64, burst_bytes: u64) {
+        self.shapers.insert(feature, ShaperCfg { bytes_per_sec, burst_bytes });
+    }
+
+    /// Setting the largest payload which the node accepts from the network for a feature, bigger packets are dropped
+    /// before decoding. Default is DEFAULT_MAX_PAYLOAD
+    pub fn set_max_payload(&mut self, feature: Features, bytes: usize) {
+        self.max_payloads.insert(feature, bytes);
+    }
+
+    /// Setting how many outputs a feature pops per turn before other busy features, higher weights also go first.
+    /// Default is DEFAULT_CONTROL_WEIGHT for neighbours and router_sync, 1 for others
+    pub fn set_feature_weight(&mut self, feature: Features, weight: u8) {
+        self.feature_weights.insert(feature, weight);
+    }
+
+    /// Pinning the wire version which the node advertises for a feature, for rolling upgrades.
+    /// Neighbours only send newer messages of a feature when both sides advertise its version
+    pub fn set_feature_version(&mut self, feature: Features, version: u8) {
+        self.feature_versions.insert(feature, version);
+    }
+
+    /// Setting which features the node runs, default is all. Packets and controls of disabled features are dropped
+    pub fn set_features(&mut self, features: FeaturesConfig) {
+        self.features = features;
+    }
+
     /// Setting visualization collector mode
     pub fn set_visualization_collector(&mut self, value: bool) {
         self.visualization_collector = value;
@@ -114,6 +259,126 @@ where
         self.services.push(service);
     }
 
+    /// Setting how to react with messages for unregistered services
+    pub fn set_unknown_service_policy(&mut self, policy: UnknownServicePolicy) {
+        self.unknown_service = policy;
+    }
+
+    /// Setting where events of features go when their controls are sent over the controller ext api.
+    /// Features which are not in the map deliver to the controller
+    pub fn set_feature_targets(&mut self, targets: HashMap<Features, FeatureEventTarget>) {
+        self.feature_targets = targets;
+    }
+
+    /// Setting bounds of the adaptive router sync interval, the default is a fixed 1 second interval
+    pub fn set_router_sync_interval(&mut self, min_ms: u64, max_ms: u64) {
+        self.router_sync.sync_interval = SyncIntervalCfg { min_ms, max_ms };
+    }
+
+    /// Enable multi-path routing: paths whose score is within `tolerance` of the best path share the traffic, balanced per flow
+    pub fn set_ecmp_tolerance(&mut self, tolerance: u32) {
+        self.router_sync.ecmp_tolerance = Some(tolerance);
+    }
+
+    /// Setting how paths are ordered, all nodes in the network must use the same mode
+    pub fn set_metric_compare_mode(&mut self, mode: MetricCompareMode) {
+        self.router_sync.compare_mode = mode;
+    }
+
+    /// Enable damping of route flaps, flapping destinations are not advertised to neighbours until stable
+    pub fn set_flap_damping(&mut self, cfg: FlapDampingCfg) {
+        self.router_sync.flap_damping = Some(cfg);
+    }
+
+    /// Evict learned routes which are not refreshed by neighbour syncs within `timeout_ms`, should be greater than max sync interval
+    pub fn set_route_timeout(&mut self, timeout_ms: u64) {
+        self.router_sync.route_timeout_ms = Some(timeout_ms);
+    }
+
+    /// Reject routes which are longer than `max_hops`
+    pub fn set_max_hops(&mut self, max_hops: usize) {
+        self.router_sync.max_hops = Some(max_hops);
+    }
+
+    /// Send only changed routes in router syncs, with a full sync after each `full_every` deltas
+    pub fn set_delta_sync(&mut self, full_every: u32) {
+        self.router_sync.delta_sync_full_every = Some(full_every);
+    }
+
+    /// Only replace the latency of a neighbour route when its measured RTT changes by more than `threshold_ms`
+    pub fn set_rtt_threshold(&mut self, threshold_ms: u16) {
+        self.router_sync.rtt_threshold_ms = threshold_ms;
+    }
+
+    /// Advertise changed service load weights every `interval_ms`, for weighted service discovery
+    pub fn set_service_load_interval(&mut self, interval_ms: u64) {
+        self.router_sync.service_load_interval_ms = Some(interval_ms);
+    }
+
+    /// Store each dht_kv map on `factor` nodes closest to its key, so it survives when some of them go down
+    pub fn set_dht_kv_replication(&mut self, factor: u8) {
+        self.dht_kv.replication_factor = factor;
+    }
+
+    /// Check which nodes can subscribe or publish each pubsub channel, all channels are open by default
+    pub fn set_channel_authorizer<A: ChannelAuthorizer + 'static>(&mut self, authorizer: A) {
+        self.pubsub.authorizer = Arc::new(authorizer);
+    }
+
+    /// Replay the last frames of the channel to each new consumer, all nodes of the channel should use the same config
+    pub fn set_channel_retain(&mut self, channel: ChannelId, cfg: RetainCfg) {
+        self.pubsub.retain.insert(channel, cfg);
+    }
+
+    /// Split data messages bigger than `mtu` bytes into fragments, which are reassembled by the receiver.
+    /// Disabled by default, because older nodes drop fragments
+    pub fn set_data_fragment_mtu(&mut self, mtu: usize) {
+        self.data.fragment_mtu = Some(mtu);
+    }
+
+    /// Prove the node id with an Ed25519 key in each handshake, the node id of the builder must be derived from it.
+    /// Peers verify the proof when it is present, even if they don't require it
+    pub fn set_identity(&mut self, identity: NodeIdentity) {
+        assert_eq!(identity.node_id(), self.node_id, "node id should be derived from the identity key");
+        self.identity = Some(Arc::new(identity));
+    }
+
+    /// Rules for new connections which are checked before the blacklist, allowlist and limits, default accepts all
+    pub fn set_accept_policy<P: AcceptPolicy + 'static>(&mut self, policy: P) {
+        self.accept_policy = Some(Arc::new(policy));
+    }
+
+    /// Reject neighbours which don't prove their node id with an identity key
+    pub fn require_peer_identity(&mut self) {
+        self.neighbours.require_identity = true;
+    }
+
+    /// Reject new incoming connections when the node already has `max` connections
+    pub fn set_max_connections(&mut self, max: usize) {
+        self.neighbours.max_connections = Some(max);
+    }
+
+    /// Reject new incoming connections from an ip which already has `max` connections to the node
+    pub fn set_max_connections_per_ip(&mut self, max: usize) {
+        self.neighbours.max_connections_per_ip = Some(max);
+    }
+
+    /// Ping neighbours every `interval_ms`, a connection is closed after `miss_limit` pings in a row are not answered
+    pub fn set_keepalive(&mut self, interval_ms: u64, miss_limit: u32) {
+        self.neighbours.keepalive_interval_ms = interval_ms;
+        self.neighbours.keepalive_miss_limit = miss_limit;
+    }
+
+    /// Accept at most `burst` handshakes in a row from an ip, then `per_sec` handshakes per second. Others are dropped
+    pub fn set_handshake_rate(&mut self, per_sec: u32, burst: u32) {
+        self.neighbours.handshake_rate = Some(HandshakeRateCfg { per_sec, burst });
+    }
+
+    /// Dial only one family when both this node and the peer have IPv4 and IPv6 addresses
+    pub fn set_ip_preference(&mut self, preference: IpPreference) {
+        self.neighbours.ip_preference = preference;
+    }
+
     #[cfg(feature = "vpn")]
     pub fn enable_vpn(&mut self) {
         self.vpn_enable = true;
@@ -129,12 +394,27 @@ where
         self.vpn_netmask = Some(netmask);
     }
 
+    /// Allow tunneled packets to `cidr` and send them to `node`, the longest matched prefix wins.
+    /// Once a route is added, packets to destinations which match no route are dropped
+    #[cfg(feature = "vpn")]
+    pub fn add_vpn_route(&mut self, cidr: Ipv4Cidr, node: NodeId) {
+        self.vpn.routes.push(VpnRoute { cidr, node });
+    }
+
+    /// Find the owner of tunneled destinations by broadcasting to the nodes which run `service`, instead of
+    /// mapping the last ip byte to a node id. Each node claims its vpn ip, duplicated claims prefer the lower node id
+    #[cfg(feature = "vpn")]
+    pub fn enable_vpn_resolve(&mut self, service: u8, level: ServiceBroadcastLevel) {
+        self.vpn.resolve = Some(VpnResolveCfg { service, level });
+    }
+
     pub fn build<B: Backend<SdnOwner>>(mut self, workers: usize, info: NodeInfo) -> SdnController<UserData, SC, SE, TC, TW> {
         assert!(workers > 0);
         #[cfg(feature = "vpn")]
         let (tun_device, mut queue_fds) = {
             if self.vpn_enable {
                 let vpn_ip = self.vpn_ip.unwrap_or((10, 33, 33, self.node_id as u8));
+                self.vpn.virtual_ip = Some(std::net::Ipv4Addr::new(vpn_ip.0, vpn_ip.1, vpn_ip.2, vpn_ip.3));
                 let vpn_netmask = self.vpn_netmask.unwrap_or((255, 255, 255, 0));
                 let mut tun_device = sans_io_runtime::backend::tun::create_tun(&format!("utun{}", self.node_id as u8), vpn_ip, vpn_netmask, 1400, workers);
                 let mut queue_fds = std::collections::VecDeque::with_capacity(workers);
@@ -152,7 +432,8 @@ where
             self.visualization_collector,
         )));
 
-        let history = Arc::new(DataWorkerHistory::default());
+        let history = self.history.unwrap_or_else(|| Arc::new(LruShadowHistory::default()));
+        let clock = self.clock.unwrap_or_else(|| Arc::new(SystemClock::default()));
 
         let mut controller = SdnController::default();
         controller.add_worker::<SdnOwner, _, SdnWorkerInner<UserData, SC, SE, TC, TW>, B>(
@@ -163,10 +444,32 @@ where
                 bind_addrs: self.bind_addrs.to_vec(),
                 services: self.services.clone(),
                 history: history.clone(),
+                clock: clock.clone(),
+                unknown_service: self.unknown_service,
+                feature_targets: self.feature_targets.clone(),
+                router_sync: self.router_sync,
+                rekey: self.rekey,
+                max_ttl: self.max_ttl,
+                output_queue: self.output_queue,
+                shapers: self.shapers.clone(),
+                max_payloads: self.max_payloads.clone(),
+                feature_weights: self.feature_weights.clone(),
+                features: self.features,
                 controller: Some(ControllerCfg {
                     session: self.session,
                     auth: self.auth.unwrap_or_else(|| Arc::new(StaticKeyAuthorization::new("unsecure"))),
                     handshake: self.handshake.unwrap_or_else(|| Arc::new(HandshakeBuilderXDA)),
+                    identity: self.identity,
+                    accept_policy: self.accept_policy.unwrap_or_else(|| Arc::new(AcceptAll)),
+                    resolver: self.resolver.unwrap_or_else(|| Arc::new(ThreadResolver::default())),
+                    cipher_suites: self.cipher_suites,
+                    codecs: self.codecs,
+                    feature_versions: self.feature_versions,
+                    dht_kv: self.dht_kv,
+                    data: self.data,
+                    neighbours: self.neighbours,
+                    vpn: self.vpn,
+                    pubsub: self.pubsub,
                     #[cfg(feature = "vpn")]
                     vpn_tun_device: tun_device,
                 }),
@@ -185,6 +488,17 @@ where
                     bind_addrs: self.bind_addrs.to_vec(),
                     services: self.services.clone(),
                     history: history.clone(),
+                    clock: clock.clone(),
+                    unknown_service: self.unknown_service,
+                    feature_targets: self.feature_targets.clone(),
+                    router_sync: self.router_sync,
+                    rekey: self.rekey,
+                    max_ttl: self.max_ttl,
+                    output_queue: self.output_queue,
+                    shapers: self.shapers.clone(),
+                    max_payloads: self.max_payloads.clone(),
+                    feature_weights: self.feature_weights.clone(),
+                    features: self.features,
                     controller: None,
                     #[cfg(feature = "vpn")]
                     vpn_tun_fd: queue_fds.pop_front(),
@@ -0,0 +1,359 @@
+//! Typed wrapper over the dht_kv feature.
+//!
+//! `DhtKvSdk` can be cloned into async tasks. Each call is queued as an `SdnExtIn::FeaturesControl` with the actor of
+//! the sdk, the loop which owns the `SdnController` sends them with `pop_control` and hands every event to `on_event`,
+//! which wakes the request waiting for it. Gets of the same map are answered in order, so no request id is needed.
+//! Timeouts are detected by the feature and returned as `GetError::Timeout`.
+//!
+//! Watches of a map share one subscription, which follows relay changes by itself. The sdk keeps the values of
+//! watched maps, so a new watch starts with the current values and only changes are delivered after that.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    future::poll_fn,
+    sync::Arc,
+    task::{Poll, Waker},
+};
+
+use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_network::features::{
+    dht_kv::{Control, Event, GetError, Key, Map, MapControl, MapEvent},
+    FeaturesControl, FeaturesEvent,
+};
+use parking_lot::Mutex;
+
+use crate::{SdnExtIn, SdnExtOut};
+
+type GetRes = Result<Vec<(Key, NodeId, Vec<u8>)>, GetError>;
+
+struct Waiting<T> {
+    value: Option<T>,
+    waker: Option<Waker>,
+}
+
+impl<T> Default for Waiting<T> {
+    fn default() -> Self {
+        Self { value: None, waker: None }
+    }
+}
+
+type Slot<T> = Arc<Mutex<Waiting<T>>>;
+
+struct Watcher {
+    /// Only events of this sub-key, all events of the map if None
+    key: Option<Key>,
+    slot: Slot<VecDeque<MapEvent>>,
+}
+
+impl Watcher {
+    fn matches(&self, event: &MapEvent) -> bool {
+        match (self.key, event) {
+            (None, _) => true,
+            (Some(key), MapEvent::OnSet(event_key, ..) | MapEvent::OnDel(event_key, ..)) => key == *event_key,
+            (Some(_), MapEvent::OnRelaySelected(_)) => false,
+        }
+    }
+
+    fn push(&self, event: MapEvent) {
+        let mut waiting = self.slot.lock();
+        waiting.value.get_or_insert_with(VecDeque::new).push_back(event);
+        if let Some(waker) = waiting.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+#[derive(Default)]
+struct Watched {
+    values: HashMap<(Key, NodeId), Vec<u8>>,
+    watchers: Vec<Watcher>,
+}
+
+struct State {
+    controls: VecDeque<Control>,
+    gets: HashMap<Map, VecDeque<Slot<GetRes>>>,
+    watches: HashMap<Map, Watched>,
+}
+
+#[derive(Clone)]
+pub struct DhtKvSdk<UserData> {
+    actor: UserData,
+    state: Arc<Mutex<State>>,
+}
+
+impl<UserData: Copy + Eq> DhtKvSdk<UserData> {
+    /// Events of the feature are matched by `actor`, it should not be used for other dht_kv controls
+    pub fn new(actor: UserData) -> Self {
+        Self {
+            actor,
+            state: Arc::new(Mutex::new(State {
+                controls: VecDeque::new(),
+                gets: HashMap::new(),
+                watches: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Set a sub-key, `ttl_ms` deletes it everywhere after it lapses
+    pub async fn set(&self, map: Map, key: Key, value: Vec<u8>, ttl_ms: Option<u64>) {
+        self.state.lock().controls.push_back(Control::MapCmd(map, MapControl::Set(key, value, ttl_ms)));
+    }
+
+    pub async fn del(&self, map: Map, key: Key) {
+        self.state.lock().controls.push_back(Control::MapCmd(map, MapControl::Del(key)));
+    }
+
+    /// All sub-keys of the map with the node which set them
+    pub async fn get(&self, map: Map) -> GetRes {
+        let slot: Slot<GetRes> = Default::default();
+        {
+            let mut state = self.state.lock();
+            state.controls.push_back(Control::MapGet(map));
+            state.gets.entry(map).or_default().push_back(slot.clone());
+        }
+        poll_fn(|cx| {
+            let mut waiting = slot.lock();
+            match waiting.value.take() {
+                Some(res) => Poll::Ready(res),
+                None => {
+                    waiting.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+
+    /// Events of the map until the watch is dropped, it starts with the current values
+    pub fn watch(&self, map: Map) -> DhtKvWatch {
+        self.watch_inner(map, None)
+    }
+
+    /// Like `watch` but only for changes of one sub-key
+    pub fn watch_key(&self, map: Map, key: Key) -> DhtKvWatch {
+        self.watch_inner(map, Some(key))
+    }
+
+    fn watch_inner(&self, map: Map, key: Option<Key>) -> DhtKvWatch {
+        let slot: Slot<VecDeque<MapEvent>> = Arc::new(Mutex::new(Waiting {
+            value: Some(VecDeque::new()),
+            waker: None,
+        }));
+        let mut guard = self.state.lock();
+        let state = &mut *guard;
+        let watched = state.watches.entry(map).or_default();
+        let watcher = Watcher { key, slot: slot.clone() };
+        for ((sub_key, source), value) in watched.values.iter() {
+            let event = MapEvent::OnSet(*sub_key, *source, value.clone());
+            if watcher.matches(&event) {
+                watcher.push(event);
+            }
+        }
+        watched.watchers.push(watcher);
+        if watched.watchers.len() == 1 {
+            state.controls.push_back(Control::MapCmd(map, MapControl::Sub));
+        }
+        DhtKvWatch { map, slot, state: self.state.clone() }
+    }
+
+    /// Next control which must be sent to the controller
+    pub fn pop_control<SC>(&self) -> Option<SdnExtIn<UserData, SC>> {
+        let control = self.state.lock().controls.pop_front()?;
+        Some(SdnExtIn::FeaturesControl(self.actor, FeaturesControl::DhtKv(control)))
+    }
+
+    /// Consume the event if it is for this sdk, other events are returned back
+    pub fn on_event<SE>(&self, event: SdnExtOut<UserData, SE>) -> Option<SdnExtOut<UserData, SE>> {
+        let event = match event {
+            SdnExtOut::FeaturesEvent(actor, FeaturesEvent::DhtKv(event)) if actor == self.actor => event,
+            event => return Some(event),
+        };
+        let mut state = self.state.lock();
+        match event {
+            Event::MapGetRes(map, res) => {
+                let res = res.map(|entries| entries.into_iter().map(|(key, source, _version, value)| (key, source.0, value)).collect());
+                if let Some(slot) = state.gets.get_mut(&map).and_then(|gets| gets.pop_front()) {
+                    wake(&slot, res);
+                }
+                if state.gets.get(&map).is_some_and(|gets| gets.is_empty()) {
+                    state.gets.remove(&map);
+                }
+            }
+            Event::MapEvent(map, event) => {
+                let watched = match state.watches.get_mut(&map) {
+                    Some(watched) => watched,
+                    None => return None,
+                };
+                //a new relay fires all values again, they are not changes
+                let changed = match &event {
+                    MapEvent::OnSet(key, source, value) => watched.values.insert((*key, *source), value.clone()).as_ref() != Some(value),
+                    MapEvent::OnDel(key, source) => watched.values.remove(&(*key, *source)).is_some(),
+                    MapEvent::OnRelaySelected(_) => true,
+                };
+                if changed {
+                    for watcher in watched.watchers.iter().filter(|watcher| watcher.matches(&event)) {
+                        watcher.push(event.clone());
+                    }
+                }
+            }
+            event => log::debug!("[DhtKvSdk] ignore event {:?}", event),
+        }
+        None
+    }
+}
+
+fn wake<T>(slot: &Slot<T>, value: T) {
+    let mut waiting = slot.lock();
+    waiting.value = Some(value);
+    if let Some(waker) = waiting.waker.take() {
+        waker.wake();
+    }
+}
+
+/// Stream of events of a map, created by `DhtKvSdk::watch`
+pub struct DhtKvWatch {
+    map: Map,
+    slot: Slot<VecDeque<MapEvent>>,
+    state: Arc<Mutex<State>>,
+}
+
+impl DhtKvWatch {
+    pub async fn recv(&mut self) -> MapEvent {
+        poll_fn(|cx| {
+            let mut waiting = self.slot.lock();
+            match waiting.value.as_mut().and_then(|queue| queue.pop_front()) {
+                Some(event) => Poll::Ready(event),
+                None => {
+                    waiting.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+}
+
+impl Drop for DhtKvWatch {
+    fn drop(&mut self) {
+        let mut guard = self.state.lock();
+        let state = &mut *guard;
+        if let Some(watched) = state.watches.get_mut(&self.map) {
+            watched.watchers.retain(|watcher| !Arc::ptr_eq(&watcher.slot, &self.slot));
+            if watched.watchers.is_empty() {
+                state.watches.remove(&self.map);
+                state.controls.push_back(Control::MapCmd(self.map, MapControl::Unsub));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::Future,
+        pin::pin,
+        sync::Arc,
+        task::{Context, Poll, Wake, Waker},
+    };
+
+    use atm0s_sdn_network::features::{
+        dht_kv::{Control, Event, GetError, MapControl, MapEvent},
+        FeaturesControl, FeaturesEvent,
+    };
+
+    use crate::{SdnExtIn, SdnExtOut};
+
+    use super::DhtKvSdk;
+
+    struct NoopWake;
+
+    impl Wake for NoopWake {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    fn poll<F: Future>(fut: std::pin::Pin<&mut F>) -> Poll<F::Output> {
+        let waker = Waker::from(Arc::new(NoopWake));
+        fut.poll(&mut Context::from_waker(&waker))
+    }
+
+    fn pop(sdk: &DhtKvSdk<u32>) -> Option<Control> {
+        match sdk.pop_control::<()>() {
+            Some(SdnExtIn::FeaturesControl(1, FeaturesControl::DhtKv(control))) => Some(control),
+            None => None,
+            _ => panic!("unexpected control"),
+        }
+    }
+
+    fn event(actor: u32, event: Event) -> SdnExtOut<u32, ()> {
+        SdnExtOut::FeaturesEvent(actor, FeaturesEvent::DhtKv(event))
+    }
+
+    #[test]
+    fn get_should_wait_matching_result() {
+        let sdk = DhtKvSdk::new(1);
+        let mut get = pin!(sdk.get(1000.into()));
+        assert!(poll(get.as_mut()).is_pending());
+        assert_eq!(pop(&sdk), Some(Control::MapGet(1000.into())));
+
+        //events of other actors and maps are not consumed
+        assert!(sdk.on_event(event(2, Event::MapGetRes(1000.into(), Err(GetError::NotFound)))).is_some());
+        assert!(sdk.on_event(event(1, Event::MapGetRes(1001.into(), Err(GetError::NotFound)))).is_none());
+        assert!(poll(get.as_mut()).is_pending());
+
+        assert!(sdk.on_event(event(1, Event::MapGetRes(1000.into(), Err(GetError::Timeout)))).is_none());
+        assert_eq!(poll(get.as_mut()), Poll::Ready(Err(GetError::Timeout)));
+    }
+
+    #[test]
+    fn watch_should_sub_once_and_unsub_after_last_drop() {
+        let sdk = DhtKvSdk::new(1);
+        let mut watch1 = sdk.watch(1000.into());
+        let watch2 = sdk.watch(1000.into());
+        assert_eq!(pop(&sdk), Some(Control::MapCmd(1000.into(), MapControl::Sub)));
+        assert_eq!(pop(&sdk), None);
+
+        assert!(sdk.on_event(event(1, Event::MapEvent(1000.into(), MapEvent::OnSet(2000.into(), 2, vec![1])))).is_none());
+        {
+            let mut recv = pin!(watch1.recv());
+            assert_eq!(poll(recv.as_mut()), Poll::Ready(MapEvent::OnSet(2000.into(), 2, vec![1])));
+        }
+
+        drop(watch2);
+        assert_eq!(pop(&sdk), None);
+        drop(watch1);
+        assert_eq!(pop(&sdk), Some(Control::MapCmd(1000.into(), MapControl::Unsub)));
+    }
+
+    #[test]
+    fn watch_key_should_replay_current_value_and_skip_refires() {
+        let sdk = DhtKvSdk::new(1);
+        let _watch = sdk.watch(1000.into());
+        assert_eq!(pop(&sdk), Some(Control::MapCmd(1000.into(), MapControl::Sub)));
+        for (key, value) in [(2000, 1), (2001, 2)] {
+            assert!(sdk.on_event(event(1, Event::MapEvent(1000.into(), MapEvent::OnSet(key.into(), 2, vec![value])))).is_none());
+        }
+
+        //a later watch of the same map starts with the current value of its key
+        let mut watch_key = sdk.watch_key(1000.into(), 2000.into());
+        assert_eq!(pop(&sdk), None);
+        {
+            let mut recv = pin!(watch_key.recv());
+            assert_eq!(poll(recv.as_mut()), Poll::Ready(MapEvent::OnSet(2000.into(), 2, vec![1])));
+        }
+        {
+            let mut recv = pin!(watch_key.recv());
+            assert!(poll(recv.as_mut()).is_pending());
+        }
+
+        //same value from a new relay is not a change, other keys are filtered
+        sdk.on_event(event(1, Event::MapEvent(1000.into(), MapEvent::OnSet(2000.into(), 2, vec![1]))));
+        sdk.on_event(event(1, Event::MapEvent(1000.into(), MapEvent::OnSet(2001.into(), 2, vec![3]))));
+        sdk.on_event(event(1, Event::MapEvent(1000.into(), MapEvent::OnDel(2000.into(), 2))));
+        {
+            let mut recv = pin!(watch_key.recv());
+            assert_eq!(poll(recv.as_mut()), Poll::Ready(MapEvent::OnDel(2000.into(), 2)));
+        }
+        let mut recv = pin!(watch_key.recv());
+        assert!(poll(recv.as_mut()).is_pending());
+    }
+}
@@ -0,0 +1,95 @@
+//! DSCP marking on UDP sockets.
+//!
+//! The sans-io-runtime backend keeps its sockets private, so workers created by `SdnBuilder` send
+//! `NetOutput::UdpMarked` packets with the socket default. Applications which drive a `Node` with own sockets
+//! can send through `MarkedUdpSocket` to apply the mark of each packet.
+
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+};
+
+/// Set the DSCP of all next packets sent by the socket. This is a no-op on platforms which don't support it
+pub fn set_dscp(socket: &UdpSocket, dscp: u8) -> io::Result<()> {
+    //DSCP is the upper 6 bits of the TOS / traffic class byte, the lower 2 bits are ECN
+    let tos = (dscp as u32) << 2;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let sock = socket2::SockRef::from(socket);
+        match socket.local_addr()? {
+            SocketAddr::V4(_) => sock.set_tos(tos),
+            SocketAddr::V6(_) => sock.set_tclass_v6(tos),
+        }
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        let _ = (socket, tos);
+        Ok(())
+    }
+}
+
+/// UDP socket which only changes the socket option when the DSCP differs from the last send
+pub struct MarkedUdpSocket {
+    socket: UdpSocket,
+    dscp: u8,
+}
+
+impl MarkedUdpSocket {
+    pub fn new(socket: UdpSocket) -> Self {
+        Self { socket, dscp: 0 }
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    pub fn dscp(&self) -> u8 {
+        self.dscp
+    }
+
+    pub fn send_to(&mut self, buf: &[u8], to: SocketAddr, dscp: u8) -> io::Result<usize> {
+        if self.dscp != dscp {
+            if let Err(e) = set_dscp(&self.socket, dscp) {
+                log::warn!("[MarkedUdpSocket] set dscp {dscp} error {e:?}, send with previous mark");
+            } else {
+                self.dscp = dscp;
+            }
+        }
+        self.socket.send_to(buf, to)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+
+    use super::MarkedUdpSocket;
+
+    #[test]
+    fn apply_dscp_per_send() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").expect("Should bind");
+        let mut socket = MarkedUdpSocket::new(UdpSocket::bind("127.0.0.1:0").expect("Should bind"));
+        let to = receiver.local_addr().expect("Should have addr");
+
+        let mut buf = [0; 16];
+        for dscp in [48, 0] {
+            assert_eq!(socket.send_to(&[1, 2, 3], to, dscp).expect("Should send"), 3);
+            assert_eq!(receiver.recv_from(&mut buf).expect("Should receive").0, 3);
+            assert_eq!(socket.dscp(), dscp);
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            assert_eq!(socket2::SockRef::from(socket.socket()).tos().expect("Should get tos"), (dscp as u32) << 2);
+        }
+    }
+
+    #[test]
+    fn apply_dscp_ipv6() {
+        //hosts without ipv6 loopback can't run this
+        let socket = match UdpSocket::bind("[::1]:0") {
+            Ok(socket) => socket,
+            Err(_) => return,
+        };
+        super::set_dscp(&socket, 46).expect("Should set dscp");
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        assert_eq!(socket2::SockRef::from(&socket).tclass_v6().expect("Should get tclass"), 46 << 2);
+    }
+}
@@ -4,33 +4,52 @@ use std::{fmt::Debug, hash::Hash};
 
 pub use atm0s_sdn_identity::{ConnDirection, ConnId, NodeAddr, NodeAddrBuilder, NodeId, NodeIdType, Protocol};
 pub use atm0s_sdn_network::controller_plane::ControllerPlaneCfg;
-pub use atm0s_sdn_network::data_plane::DataPlaneCfg;
+pub use atm0s_sdn_network::data_plane::{DataPlaneCfg, DscpMap, OverflowPolicy};
 use atm0s_sdn_network::features::FeaturesControl;
 pub use atm0s_sdn_network::{
-    base, features, secure, services,
+    base, features, metrics,
+    node::{Node, NodeCfg, NodeOutput},
+    secure, services,
     worker::{SdnWorker, SdnWorkerBusEvent, SdnWorkerCfg, SdnWorkerInput, SdnWorkerOutput},
 };
 pub use atm0s_sdn_network::{
-    base::ServiceId,
+    base::{CipherSuite, RekeyPolicy, ServiceId},
     data_plane::{NetInput, NetOutput},
 };
-pub use atm0s_sdn_router::{shadow::ShadowRouterHistory, RouteRule, ServiceBroadcastLevel};
+pub use atm0s_sdn_router::{
+    core::{FlapDampingCfg, MetricCompareMode},
+    shadow::{LruShadowHistory, ShadowHistoryStats, ShadowRouterHistory, HISTORY_CAPACITY, HISTORY_TTL_MS},
+    RouteRule, ServiceBroadcastLevel,
+};
 pub use sans_io_runtime;
 
 mod builder;
-mod history;
+mod dht_kv_sdk;
+mod dscp;
+mod pubsub_sdk;
+mod resolver;
 mod time;
 mod worker_inner;
 
 pub use builder::{generate_node_addr, SdnBuilder};
-pub use history::DataWorkerHistory;
+pub use dht_kv_sdk::{DhtKvSdk, DhtKvWatch};
+pub use dscp::{set_dscp, MarkedUdpSocket};
+pub use pubsub_sdk::{PubsubSdk, PubsubStream, StreamCfg, StreamOverflow};
+pub use resolver::{ThreadResolver, DEFAULT_DNS_TTL_MS};
 pub use time::{TimePivot, TimeTicker};
 pub use worker_inner::{SdnChannel, SdnController, SdnEvent, SdnExtIn, SdnExtOut, SdnOwner};
 
+/// Former name of the default broadcast history
+pub type DataWorkerHistory = LruShadowHistory;
+
 pub trait SdnControllerUtils<UserData, SC> {
     fn connect_to(&mut self, addr: NodeAddr);
     fn feature_control(&mut self, userdata: UserData, cmd: FeaturesControl);
     fn service_control(&mut self, service: ServiceId, userdata: UserData, cmd: SC);
+    /// Answered with `SdnExtOut::Topology`
+    fn query_topology(&mut self, userdata: UserData);
+    /// Answered with `SdnExtOut::RouteExplanation`
+    fn explain_route(&mut self, userdata: UserData, dest: NodeId);
 }
 
 impl<
@@ -51,4 +70,12 @@ impl<
     fn service_control(&mut self, service: ServiceId, userdata: UserData, cmd: SC) {
         self.send_to(0, SdnExtIn::ServicesControl(service, userdata, cmd));
     }
+
+    fn query_topology(&mut self, userdata: UserData) {
+        self.send_to(0, SdnExtIn::QueryTopology(userdata));
+    }
+
+    fn explain_route(&mut self, userdata: UserData, dest: NodeId) {
+        self.send_to(0, SdnExtIn::ExplainRoute(userdata, dest));
+    }
 }
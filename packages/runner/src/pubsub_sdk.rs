@@ -0,0 +1,284 @@
+//! Stream api for pubsub consumers.
+//!
+//! Like `DhtKvSdk`, the loop which owns the `SdnController` sends the queued controls with `pop_control` and hands
+//! events to `on_event`, and calls `on_tick` periodically. Each `PubsubStream` yields the data of its channel, and
+//! dropping it unsubscribes.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+};
+
+use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_network::features::{
+    pubsub::{ChannelAccess, ChannelControl, ChannelEvent, ChannelId, ConsumerQueueCfg, Control, Event, OverflowPolicy},
+    FeaturesControl, FeaturesEvent,
+};
+use futures::Stream;
+use parking_lot::Mutex;
+
+use crate::{SdnExtIn, SdnExtOut};
+
+/// What a stream does when its buffer is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamOverflow {
+    /// Drop the oldest buffered data
+    DropOldest,
+    /// Frames are kept in the feature consumer queue and pulled when the stream has room. Publishers get a feedback of
+    /// `kind` when the queue is above the buffer size, and the stream ends if the consumer is evicted for being too slow
+    Lossless { kind: u8 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamCfg {
+    pub buffer: usize,
+    pub overflow: StreamOverflow,
+}
+
+impl Default for StreamCfg {
+    fn default() -> Self {
+        Self {
+            buffer: 64,
+            overflow: StreamOverflow::DropOldest,
+        }
+    }
+}
+
+struct Buffered {
+    id: u64,
+    cfg: StreamCfg,
+    data: VecDeque<(NodeId, Vec<u8>)>,
+    /// Data consumed since the last pull, only used by lossless streams
+    consumed: usize,
+    dropped: u64,
+    denied_by: Option<NodeId>,
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+struct State {
+    next_id: u64,
+    controls: VecDeque<Control>,
+    streams: HashMap<ChannelId, Arc<Mutex<Buffered>>>,
+}
+
+#[derive(Clone)]
+pub struct PubsubSdk<UserData> {
+    actor: UserData,
+    state: Arc<Mutex<State>>,
+}
+
+impl<UserData: Copy + Eq> PubsubSdk<UserData> {
+    /// Events of the feature are matched by `actor`, it should not be used for other pubsub controls
+    pub fn new(actor: UserData) -> Self {
+        Self {
+            actor,
+            state: Arc::new(Mutex::new(State {
+                next_id: 0,
+                controls: VecDeque::new(),
+                streams: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Subscribe the channel, a previous stream of the same channel ends
+    pub fn subscribe(&self, channel: ChannelId, cfg: StreamCfg) -> PubsubStream {
+        let mut guard = self.state.lock();
+        let state = &mut *guard;
+        let id = state.next_id;
+        state.next_id += 1;
+        let buffered = Arc::new(Mutex::new(Buffered {
+            id,
+            cfg,
+            data: VecDeque::with_capacity(cfg.buffer),
+            consumed: 0,
+            dropped: 0,
+            denied_by: None,
+            closed: false,
+            waker: None,
+        }));
+        match state.streams.insert(channel, buffered.clone()) {
+            Some(old) => {
+                if is_lossless(&old) && !matches!(cfg.overflow, StreamOverflow::Lossless { .. }) {
+                    state.controls.push_back(Control(channel, ChannelControl::SetConsumerQueue(None)));
+                }
+                close(&old);
+            }
+            None => state.controls.push_back(Control(channel, ChannelControl::SubAuto)),
+        }
+        if let StreamOverflow::Lossless { kind } = cfg.overflow {
+            let high_water = cfg.buffer.max(1);
+            state.controls.push_back(Control(
+                channel,
+                ChannelControl::SetConsumerQueue(Some(ConsumerQueueCfg {
+                    high_water,
+                    policy: OverflowPolicy::SlowDown { kind },
+                })),
+            ));
+        }
+        PubsubStream {
+            channel,
+            buffered,
+            state: self.state.clone(),
+        }
+    }
+
+    pub fn publish_start(&self, channel: ChannelId) {
+        self.state.lock().controls.push_back(Control(channel, ChannelControl::PubStart));
+    }
+
+    pub fn publish(&self, channel: ChannelId, data: Vec<u8>) {
+        self.state.lock().controls.push_back(Control(channel, ChannelControl::PubData(data)));
+    }
+
+    pub fn publish_stop(&self, channel: ChannelId) {
+        self.state.lock().controls.push_back(Control(channel, ChannelControl::PubStop));
+    }
+
+    /// Lossless streams pull the frames they have room for, it should be called periodically because the consumer
+    /// queue only delivers frames which are already queued when a pull arrives
+    pub fn on_tick(&self) {
+        let mut state = self.state.lock();
+        let mut pulls = vec![];
+        for (channel, buffered) in state.streams.iter() {
+            let buffered = buffered.lock();
+            let room = buffered.cfg.buffer.max(1).saturating_sub(buffered.data.len());
+            if matches!(buffered.cfg.overflow, StreamOverflow::Lossless { .. }) && room > 0 {
+                pulls.push(Control(*channel, ChannelControl::Pull(room as u32)));
+            }
+        }
+        state.controls.extend(pulls);
+    }
+
+    /// Next control which must be sent to the controller
+    pub fn pop_control<SC>(&self) -> Option<SdnExtIn<UserData, SC>> {
+        let control = self.state.lock().controls.pop_front()?;
+        Some(SdnExtIn::FeaturesControl(self.actor, FeaturesControl::PubSub(control)))
+    }
+
+    /// Consume the event if it is for this sdk, other events are returned back
+    pub fn on_event<SE>(&self, event: SdnExtOut<UserData, SE>) -> Option<SdnExtOut<UserData, SE>> {
+        let Event(channel, event) = match event {
+            SdnExtOut::FeaturesEvent(actor, FeaturesEvent::PubSub(event)) if actor == self.actor => event,
+            event => return Some(event),
+        };
+        let mut state = self.state.lock();
+        let buffered = match state.streams.get(&channel) {
+            Some(buffered) => buffered.clone(),
+            None => {
+                log::debug!("[PubsubSdk] no stream for channel {channel}, ignore event {:?}", event);
+                return None;
+            }
+        };
+        match event {
+            ChannelEvent::SourceData(source, data) => {
+                let mut buffered = buffered.lock();
+                if buffered.cfg.overflow == StreamOverflow::DropOldest && buffered.data.len() >= buffered.cfg.buffer.max(1) {
+                    buffered.data.pop_front();
+                    buffered.dropped += 1;
+                }
+                buffered.data.push_back((source, data));
+                if let Some(waker) = buffered.waker.take() {
+                    waker.wake();
+                }
+            }
+            ChannelEvent::SlowConsumer | ChannelEvent::Denied(ChannelAccess::Subscribe, _) => {
+                if let ChannelEvent::Denied(_, node) = event {
+                    log::warn!("[PubsubSdk] stream of channel {channel} is denied by node {node}");
+                    buffered.lock().denied_by = Some(node);
+                } else {
+                    log::warn!("[PubsubSdk] stream of channel {channel} is evicted because it is too slow");
+                }
+                state.streams.remove(&channel);
+                if is_lossless(&buffered) {
+                    state.controls.push_back(Control(channel, ChannelControl::SetConsumerQueue(None)));
+                }
+                close(&buffered);
+            }
+            event => log::debug!("[PubsubSdk] ignore event {:?} of channel {channel}", event),
+        }
+        None
+    }
+}
+
+fn is_lossless(buffered: &Mutex<Buffered>) -> bool {
+    matches!(buffered.lock().cfg.overflow, StreamOverflow::Lossless { .. })
+}
+
+fn close(buffered: &Mutex<Buffered>) {
+    let mut buffered = buffered.lock();
+    buffered.closed = true;
+    if let Some(waker) = buffered.waker.take() {
+        waker.wake();
+    }
+}
+
+/// Data of a channel as (source, data), created by `PubsubSdk::subscribe`
+pub struct PubsubStream {
+    channel: ChannelId,
+    buffered: Arc<Mutex<Buffered>>,
+    state: Arc<Mutex<State>>,
+}
+
+impl PubsubStream {
+    pub fn channel(&self) -> ChannelId {
+        self.channel
+    }
+
+    /// Data dropped because the buffer was full
+    pub fn dropped(&self) -> u64 {
+        self.buffered.lock().dropped
+    }
+
+    /// Node whose `ChannelAuthorizer` rejected the subscription, the stream ends after it
+    pub fn denied_by(&self) -> Option<NodeId> {
+        self.buffered.lock().denied_by
+    }
+}
+
+impl Stream for PubsubStream {
+    type Item = (NodeId, Vec<u8>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut buffered = self.buffered.lock();
+        let item = match buffered.data.pop_front() {
+            Some(item) => item,
+            None if buffered.closed => return Poll::Ready(None),
+            None => {
+                buffered.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+        };
+        let mut pull = 0;
+        if matches!(buffered.cfg.overflow, StreamOverflow::Lossless { .. }) {
+            //pull again after half of the buffer is consumed
+            buffered.consumed += 1;
+            if buffered.consumed >= (buffered.cfg.buffer / 2).max(1) && !buffered.closed {
+                pull = std::mem::take(&mut buffered.consumed);
+            }
+        }
+        //the state is always locked before a stream buffer
+        drop(buffered);
+        if pull > 0 {
+            self.state.lock().controls.push_back(Control(self.channel, ChannelControl::Pull(pull as u32)));
+        }
+        Poll::Ready(Some(item))
+    }
+}
+
+impl Drop for PubsubStream {
+    fn drop(&mut self) {
+        let mut state = self.state.lock();
+        let id = self.buffered.lock().id;
+        //the stream may already be replaced or evicted
+        if state.streams.get(&self.channel).is_some_and(|current| current.lock().id == id) {
+            state.streams.remove(&self.channel);
+            state.controls.push_back(Control(self.channel, ChannelControl::UnsubAuto));
+            if is_lossless(&self.buffered) {
+                state.controls.push_back(Control(self.channel, ChannelControl::SetConsumerQueue(None)));
+            }
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! Hostname resolution with the system resolver for NodeAddr with `/dns` protocols.
+//!
+//! The system resolver blocks and doesn't report record TTLs, so each lookup runs in its own thread and
+//! all results are cached for the same time.
+
+use std::{
+    collections::VecDeque,
+    net::{IpAddr, ToSocketAddrs},
+    sync::Arc,
+    thread,
+};
+
+use atm0s_sdn_network::base::{NameResolved, NameResolver};
+use parking_lot::Mutex;
+
+pub const DEFAULT_DNS_TTL_MS: u64 = 60_000;
+
+pub struct ThreadResolver {
+    ttl_ms: u64,
+    resolved: Arc<Mutex<VecDeque<NameResolved>>>,
+}
+
+impl ThreadResolver {
+    pub fn new(ttl_ms: u64) -> Self {
+        Self { ttl_ms, resolved: Default::default() }
+    }
+}
+
+impl Default for ThreadResolver {
+    fn default() -> Self {
+        Self::new(DEFAULT_DNS_TTL_MS)
+    }
+}
+
+impl NameResolver for ThreadResolver {
+    fn resolve(&self, host: &str) {
+        let host = host.to_string();
+        let ttl_ms = self.ttl_ms;
+        let resolved = self.resolved.clone();
+        thread::spawn(move || {
+            let mut addrs: Vec<IpAddr> = match (host.as_str(), 0).to_socket_addrs() {
+                Ok(addrs) => addrs.map(|addr| addr.ip()).collect(),
+                Err(e) => {
+                    log::warn!("[ThreadResolver] Resolve {host} error {e:?}");
+                    vec![]
+                }
+            };
+            addrs.dedup();
+            resolved.lock().push_back(NameResolved { host, addrs, ttl_ms });
+        });
+    }
+
+    fn pop_resolved(&self) -> Option<NameResolved> {
+        self.resolved.lock().pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use atm0s_sdn_network::base::NameResolver;
+
+    use super::ThreadResolver;
+
+    #[test]
+    fn resolve_in_background() {
+        let resolver = ThreadResolver::new(1000);
+        assert_eq!(resolver.pop_resolved(), None);
+        resolver.resolve("localhost");
+        let resolved = loop {
+            if let Some(resolved) = resolver.pop_resolved() {
+                break resolved;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(resolved.host, "localhost");
+        assert_eq!(resolved.ttl_ms, 1000);
+        assert!(resolved.addrs.iter().any(|ip| ip.is_loopback()), "{:?}", resolved.addrs);
+    }
+}
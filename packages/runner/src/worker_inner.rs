@@ -9,10 +9,11 @@ use std::{
 
 use atm0s_sdn_identity::NodeId;
 use atm0s_sdn_network::{
-    base::{Authorization, HandshakeBuilder, ServiceBuilder},
+    base::{AcceptPolicy, Authorization, CipherSuite, Clock, FeatureEventTarget, HandshakeBuilder, NameResolver, RekeyPolicy, ServiceBuilder, UnknownServicePolicy, WireCodec},
     controller_plane::ControllerPlaneCfg,
-    data_plane::{DataPlaneCfg, NetInput, NetOutput, NetPair},
-    features::{FeaturesControl, FeaturesEvent},
+    data_plane::{DataPlaneCfg, DscpMap, NetInput, NetOutput, NetPair, OutputQueueCfg, ShaperCfg},
+    features::{data::DataCfg, dht_kv::DhtKvCfg, neighbours::NeighboursCfg, pubsub::PubSubCfg, router_sync::RouterSyncCfg, vpn::VpnCfg, Features, FeaturesConfig, FeaturesControl, FeaturesEvent},
+    secure::NodeIdentity,
     worker::{SdnWorker, SdnWorkerBusEvent, SdnWorkerCfg, SdnWorkerInput, SdnWorkerOutput},
     ExtIn, ExtOut,
 };
@@ -20,11 +21,9 @@ use atm0s_sdn_router::shadow::ShadowRouterHistory;
 use rand::rngs::OsRng;
 use sans_io_runtime::{
     backend::{BackendIncoming, BackendOutgoing},
-    BusChannelControl, BusControl, BusEvent, Controller, WorkerInner, WorkerInnerInput, WorkerInnerOutput,
+    Buffer, BusChannelControl, BusControl, BusEvent, Controller, WorkerInner, WorkerInnerInput, WorkerInnerOutput,
 };
 
-use crate::time::TimePivot;
-
 pub type SdnController<UserData, SC, SE, TC, TW> = Controller<SdnExtIn<UserData, SC>, SdnExtOut<UserData, SE>, SdnSpawnCfg, SdnChannel, SdnEvent<UserData, SC, SE, TC, TW>, 1024>;
 
 pub type SdnExtIn<UserData, SC> = ExtIn<UserData, SC>;
@@ -45,6 +44,17 @@ pub struct ControllerCfg {
     pub session: u64,
     pub auth: Arc<dyn Authorization>,
     pub handshake: Arc<dyn HandshakeBuilder>,
+    pub identity: Option<Arc<NodeIdentity>>,
+    pub accept_policy: Arc<dyn AcceptPolicy>,
+    pub resolver: Arc<dyn NameResolver>,
+    pub cipher_suites: Vec<CipherSuite>,
+    pub codecs: Vec<WireCodec>,
+    pub feature_versions: HashMap<Features, u8>,
+    pub dht_kv: DhtKvCfg,
+    pub data: DataCfg,
+    pub neighbours: NeighboursCfg,
+    pub vpn: VpnCfg,
+    pub pubsub: PubSubCfg,
     #[cfg(feature = "vpn")]
     pub vpn_tun_device: Option<sans_io_runtime::backend::tun::TunDevice>,
 }
@@ -57,6 +67,18 @@ pub struct SdnInnerCfg<UserData, SC, SE, TC, TW> {
     #[allow(clippy::type_complexity)]
     pub services: Vec<Arc<dyn ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>,
     pub history: Arc<dyn ShadowRouterHistory>,
+    /// Shared by all workers of the node, so their timestamps agree
+    pub clock: Arc<dyn Clock>,
+    pub unknown_service: UnknownServicePolicy,
+    pub feature_targets: HashMap<Features, FeatureEventTarget>,
+    pub router_sync: RouterSyncCfg,
+    pub rekey: RekeyPolicy,
+    pub max_ttl: u8,
+    pub output_queue: OutputQueueCfg,
+    pub shapers: HashMap<Features, ShaperCfg>,
+    pub max_payloads: HashMap<Features, usize>,
+    pub feature_weights: HashMap<Features, u8>,
+    pub features: FeaturesConfig,
     #[cfg(feature = "vpn")]
     pub vpn_tun_fd: Option<sans_io_runtime::backend::tun::TunFd>,
 }
@@ -66,7 +88,7 @@ pub type SdnSpawnCfg = ();
 pub struct SdnWorkerInner<UserData, SC, SE, TC, TW> {
     worker: u16,
     worker_inner: SdnWorker<UserData, SC, SE, TC, TW>,
-    timer: TimePivot,
+    clock: Arc<dyn Clock>,
     #[cfg(feature = "vpn")]
     _vpn_tun_device: Option<sans_io_runtime::backend::tun::TunDevice>,
     bind_addrs: HashMap<SocketAddr, usize>,
@@ -80,6 +102,19 @@ pub struct SdnWorkerInner<UserData, SC, SE, TC, TW> {
 
 #[allow(clippy::type_complexity)]
 impl<UserData: 'static + Eq + Copy + Hash + Debug, SC: Debug, SE: Debug, TC: Debug, TW: Debug> SdnWorkerInner<UserData, SC, SE, TC, TW> {
+    /// Backend slot of the local addr of a packet. The backend drops the buffers which it sent, so only the buffers
+    /// of packets which can't be sent go back to the buffer pool of the worker
+    fn bound_slot(&mut self, pair: NetPair, data: Buffer) -> Option<(usize, Buffer)> {
+        match self.bind_addrs.get(&pair.local) {
+            Some(slot) => Some((*slot, data)),
+            None => {
+                log::debug!("[SdnWorkerInner] Drop packet from unbound addr {}", pair.local);
+                self.worker_inner.recycle_buffer(data);
+                None
+            }
+        }
+    }
+
     fn convert_output(
         &mut self,
         now_ms: u64,
@@ -92,15 +127,26 @@ impl<UserData: 'static + Eq + Copy + Hash + Debug, SC: Debug, SE: Debug, TC: Deb
             }
             SdnWorkerOutput::Net(net) => {
                 let out = match net {
-                    NetOutput::UdpPacket(pair, data) => BackendOutgoing::UdpPacket {
-                        slot: *self.bind_addrs.get(&pair.local)?,
-                        to: pair.remote,
-                        data,
-                    },
+                    NetOutput::UdpPacket(pair, data) | NetOutput::UdpMarked(pair, _, data) => {
+                        let (slot, data) = self.bound_slot(pair, data)?;
+                        BackendOutgoing::UdpPacket { slot, to: pair.remote, data }
+                    }
                     NetOutput::UdpPackets(pairs, data) => {
                         let to = pairs.into_iter().filter_map(|p| self.bind_addrs.get(&p.local).map(|s| (*s, p.remote))).collect::<Vec<_>>();
                         BackendOutgoing::UdpPackets2 { to, data }
                     }
+                    NetOutput::UdpBatch(batch) => {
+                        //backend has no vectored send yet, so the batch is expanded into single packets
+                        let mut packets = VecDeque::with_capacity(batch.len());
+                        for (pair, data) in batch {
+                            if let Some((slot, data)) = self.bound_slot(pair, data) {
+                                packets.push_back(BackendOutgoing::UdpPacket { slot, to: pair.remote, data });
+                            }
+                        }
+                        let first = packets.pop_front()?;
+                        self.queue.extend(packets.into_iter().map(|out| WorkerInnerOutput::Net(SdnOwner, out)));
+                        first
+                    }
                     #[cfg(feature = "vpn")]
                     NetOutput::TunPacket(data) => BackendOutgoing::TunPacket {
                         slot: self.tun_backend_slot.expect("should have tun"),
@@ -117,6 +163,12 @@ impl<UserData: 'static + Eq + Copy + Hash + Debug, SC: Debug, SE: Debug, TC: Deb
                     BusChannelControl::Publish(SdnChannel::Worker(*worker), true, event),
                 ))),
             },
+            SdnWorkerOutput::Backlog(backlogged) => {
+                //the backend sends each packet when it is popped, so there is nothing to hold back
+                log::debug!("[SdnWorkerInner] worker {} output backlog {backlogged}", self.worker);
+                let out = self.worker_inner.pop_output2(now_ms)?;
+                self.convert_output(now_ms, out)
+            }
             SdnWorkerOutput::Continue => {
                 //we need to continue pop for continue gather output
                 let out = self.worker_inner.pop_output2(now_ms)?;
@@ -154,18 +206,45 @@ impl<UserData: 'static + Eq + Copy + Hash + Debug, SC: Debug, SE: Debug, TC: Deb
                         bind_addrs: cfg.bind_addrs,
                         authorization: controller.auth,
                         handshake_builder: controller.handshake,
+                        identity: controller.identity,
+                        accept_policy: controller.accept_policy,
                         session: controller.session,
                         random: Box::new(OsRng),
                         services: cfg.services.clone(),
                         history: cfg.history.clone(),
+                        unknown_service: cfg.unknown_service,
+                        router_sync: cfg.router_sync,
+                        dht_kv: controller.dht_kv,
+                        data: controller.data,
+                        neighbours: controller.neighbours,
+                        vpn: controller.vpn,
+                        pubsub: controller.pubsub,
+                        cipher_suites: controller.cipher_suites,
+                        codecs: controller.codecs,
+                        feature_weights: cfg.feature_weights.clone(),
+                        feature_versions: controller.feature_versions,
+                        features: cfg.features,
+                        resolver: Some(controller.resolver),
                     }),
                     data: DataPlaneCfg {
                         worker_id: worker,
                         services: cfg.services,
                         history: cfg.history,
+                        unknown_service: cfg.unknown_service,
+                        random: Box::new(OsRng),
+                        rekey: cfg.rekey,
+                        max_ttl: cfg.max_ttl,
+                        output_queue: cfg.output_queue,
+                        shapers: cfg.shapers,
+                        feature_weights: cfg.feature_weights,
+                        features: cfg.features,
+                        max_payloads: cfg.max_payloads,
+                        //the backend can't set socket options, so packets are not split for marking
+                        dscp: DscpMap::disabled(),
                     },
+                    feature_targets: cfg.feature_targets,
                 }),
-                timer: TimePivot::build(),
+                clock: cfg.clock,
                 #[cfg(feature = "vpn")]
                 _vpn_tun_device: controller.vpn_tun_device,
                 queue,
@@ -187,9 +266,21 @@ impl<UserData: 'static + Eq + Copy + Hash + Debug, SC: Debug, SE: Debug, TC: Deb
                         worker_id: worker,
                         services: cfg.services,
                         history: cfg.history,
+                        unknown_service: cfg.unknown_service,
+                        random: Box::new(OsRng),
+                        rekey: cfg.rekey,
+                        max_ttl: cfg.max_ttl,
+                        output_queue: cfg.output_queue,
+                        shapers: cfg.shapers,
+                        feature_weights: cfg.feature_weights,
+                        features: cfg.features,
+                        max_payloads: cfg.max_payloads,
+                        //the backend can't set socket options, so packets are not split for marking
+                        dscp: DscpMap::disabled(),
                     },
+                    feature_targets: cfg.feature_targets,
                 }),
-                timer: TimePivot::build(),
+                clock: cfg.clock,
                 #[cfg(feature = "vpn")]
                 _vpn_tun_device: None,
                 queue,
@@ -218,13 +309,13 @@ impl<UserData: 'static + Eq + Copy + Hash + Debug, SC: Debug, SE: Debug, TC: Deb
         panic!("Spawn not supported")
     }
 
-    fn on_tick(&mut self, now: Instant) {
-        let now_ms = self.timer.timestamp_ms(now);
+    fn on_tick(&mut self, _now: Instant) {
+        let now_ms = self.clock.now_ms();
         self.worker_inner.on_tick(now_ms);
     }
 
-    fn on_event(&mut self, now: Instant, event: WorkerInnerInput<SdnOwner, SdnExtIn<UserData, SC>, SdnChannel, SdnEvent<UserData, SC, SE, TC, TW>>) {
-        let now_ms = self.timer.timestamp_ms(now);
+    fn on_event(&mut self, _now: Instant, event: WorkerInnerInput<SdnOwner, SdnExtIn<UserData, SC>, SdnChannel, SdnEvent<UserData, SC, SE, TC, TW>>) {
+        let now_ms = self.clock.now_ms();
         match event {
             WorkerInnerInput::Net(_, event) => match event {
                 BackendIncoming::UdpListenResult { bind: _, result } => {
@@ -254,20 +345,20 @@ impl<UserData: 'static + Eq + Copy + Hash + Debug, SC: Debug, SE: Debug, TC: Deb
         };
     }
 
-    fn pop_output(&mut self, now: Instant) -> Option<WorkerInnerOutput<SdnOwner, SdnExtOut<UserData, SE>, SdnChannel, SdnEvent<UserData, SC, SE, TC, TW>, SdnSpawnCfg>> {
+    fn pop_output(&mut self, _now: Instant) -> Option<WorkerInnerOutput<SdnOwner, SdnExtOut<UserData, SE>, SdnChannel, SdnEvent<UserData, SC, SE, TC, TW>, SdnSpawnCfg>> {
         if let Some(e) = self.queue.pop_front() {
             return Some(e);
         }
-        let now_ms = self.timer.timestamp_ms(now);
+        let now_ms = self.clock.now_ms();
         let out = self.worker_inner.pop_output2(now_ms)?;
         self.convert_output(now_ms, out)
     }
 
-    fn on_shutdown(&mut self, now: Instant) {
+    fn on_shutdown(&mut self, _now: Instant) {
         if self.shutdown {
             return;
         }
-        let now_ms = self.timer.timestamp_ms(now);
+        let now_ms = self.clock.now_ms();
         self.worker_inner.on_shutdown(now_ms);
         for slot in self.bind_addrs.values() {
             self.queue.push_back(WorkerInnerOutput::Net(SdnOwner, BackendOutgoing::UdpUnlisten { slot: *slot }));
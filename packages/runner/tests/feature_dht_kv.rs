@@ -10,8 +10,9 @@ use atm0s_sdn::{
     },
     secure::StaticKeyAuthorization,
     services::visualization,
-    NodeAddr, NodeId, SdnBuilder, SdnController, SdnControllerUtils, SdnExtOut, SdnOwner,
+    DhtKvSdk, NodeAddr, NodeId, SdnBuilder, SdnController, SdnControllerUtils, SdnExtOut, SdnOwner,
 };
+use futures::FutureExt;
 use sans_io_runtime::backend::PollingBackend;
 
 type UserInfo = u32;
@@ -19,6 +20,7 @@ type SC = visualization::Control<UserInfo>;
 type SE = visualization::Event<UserInfo>;
 type TC = ();
 type TW = ();
+type TestController = SdnController<(), SC, SE, TC, TW>;
 
 fn process(nodes: &mut [&mut SdnController<(), SC, SE, TC, TW>], timeout_ms: u64) {
     let mut count = 0;
@@ -33,6 +35,28 @@ fn process(nodes: &mut [&mut SdnController<(), SC, SE, TC, TW>], timeout_ms: u64
     }
 }
 
+/// Like `process` but controls and events of each node go through its sdk
+fn process_sdk(nodes: &mut [(&mut TestController, &DhtKvSdk<()>)], timeout_ms: u64) {
+    let mut count = 0;
+    while count < timeout_ms / 10 {
+        std::thread::sleep(Duration::from_millis(10));
+        count += 1;
+        for (node, sdk) in nodes.iter_mut() {
+            while let Some(control) = sdk.pop_control() {
+                node.send_to(0, control);
+            }
+            if node.process().is_none() {
+                panic!("Node is shutdown");
+            }
+            while let Some(event) = node.pop_event() {
+                if let Some(event) = sdk.on_event(event) {
+                    panic!("Unexpected event: {:?}", event)
+                }
+            }
+        }
+    }
+}
+
 fn expect_event(node: &mut SdnController<(), SC, SE, TC, TW>, expected: dht_kv::Event) {
     match node.pop_event() {
         Some(SdnExtOut::FeaturesEvent((), FeaturesEvent::DhtKv(event))) => {
@@ -68,7 +92,7 @@ fn test_single_node() {
     process(&mut [&mut node], 100);
     expect_event(&mut node, dht_kv::Event::MapEvent(1000.into(), MapEvent::OnRelaySelected(1)));
 
-    node.feature_control((), FeaturesControl::DhtKv(dht_kv::Control::MapCmd(1000.into(), MapControl::Set(2000.into(), vec![1, 2, 3]))));
+    node.feature_control((), FeaturesControl::DhtKv(dht_kv::Control::MapCmd(1000.into(), MapControl::Set(2000.into(), vec![1, 2, 3], None))));
     process(&mut [&mut node], 100);
     expect_event(&mut node, dht_kv::Event::MapEvent(1000.into(), MapEvent::OnSet(2000.into(), 1, vec![1, 2, 3])));
 }
@@ -88,7 +112,7 @@ fn test_two_nodes() {
     process(&mut [&mut node1, &mut node2], 100);
     expect_event(&mut node1, dht_kv::Event::MapEvent(1000.into(), MapEvent::OnRelaySelected(node1_id)));
 
-    node2.feature_control((), FeaturesControl::DhtKv(dht_kv::Control::MapCmd(1000.into(), MapControl::Set(2000.into(), vec![1, 2, 3]))));
+    node2.feature_control((), FeaturesControl::DhtKv(dht_kv::Control::MapCmd(1000.into(), MapControl::Set(2000.into(), vec![1, 2, 3], None))));
     process(&mut [&mut node1, &mut node2], 100);
 
     expect_event(&mut node1, dht_kv::Event::MapEvent(1000.into(), MapEvent::OnSet(2000.into(), node2_id, vec![1, 2, 3])));
@@ -113,8 +137,38 @@ fn test_three_nodes() {
 
     expect_event(&mut node2, dht_kv::Event::MapEvent(1000.into(), MapEvent::OnRelaySelected(node1_id)));
 
-    node3.feature_control((), FeaturesControl::DhtKv(dht_kv::Control::MapCmd(1000.into(), MapControl::Set(2000.into(), vec![1, 2, 3]))));
+    node3.feature_control((), FeaturesControl::DhtKv(dht_kv::Control::MapCmd(1000.into(), MapControl::Set(2000.into(), vec![1, 2, 3], None))));
     process(&mut [&mut node1, &mut node2, &mut node3], 100);
 
     expect_event(&mut node2, dht_kv::Event::MapEvent(1000.into(), MapEvent::OnSet(2000.into(), node3_id, vec![1, 2, 3])));
 }
+
+#[test]
+fn test_two_nodes_watch_key() {
+    let node1_id = 1;
+    let node2_id = 2;
+    let (mut node1, node_addr1) = build_node(node1_id, 13000);
+    let (mut node2, _node_addr2) = build_node(node2_id, 13001);
+    let (sdk1, sdk2) = (DhtKvSdk::new(()), DhtKvSdk::new(()));
+
+    node2.connect_to(node_addr1);
+    process_sdk(&mut [(&mut node1, &sdk1), (&mut node2, &sdk2)], 100);
+
+    let mut watch = sdk1.watch_key(1000.into(), 2000.into());
+    process_sdk(&mut [(&mut node1, &sdk1), (&mut node2, &sdk2)], 100);
+
+    async_std::task::block_on(sdk2.set(1000.into(), 2000.into(), vec![1, 2, 3], None));
+    async_std::task::block_on(sdk2.set(1000.into(), 2001.into(), vec![4, 5, 6], None));
+    process_sdk(&mut [(&mut node1, &sdk1), (&mut node2, &sdk2)], 100);
+    assert_eq!(watch.recv().now_or_never(), Some(MapEvent::OnSet(2000.into(), node2_id, vec![1, 2, 3])));
+    assert_eq!(watch.recv().now_or_never(), None);
+
+    //a later watch starts with the current value
+    let mut watch2 = sdk1.watch_key(1000.into(), 2000.into());
+    assert_eq!(watch2.recv().now_or_never(), Some(MapEvent::OnSet(2000.into(), node2_id, vec![1, 2, 3])));
+
+    async_std::task::block_on(sdk2.del(1000.into(), 2000.into()));
+    process_sdk(&mut [(&mut node1, &sdk1), (&mut node2, &sdk2)], 100);
+    assert_eq!(watch.recv().now_or_never(), Some(MapEvent::OnDel(2000.into(), node2_id)));
+    assert_eq!(watch2.recv().now_or_never(), Some(MapEvent::OnDel(2000.into(), node2_id)));
+}
@@ -0,0 +1,119 @@
+use std::time::Duration;
+
+use atm0s_sdn::{
+    features::{
+        pubsub::{ChannelControl, ChannelEvent, Control, Event},
+        FeaturesControl, FeaturesEvent,
+    },
+    PubsubSdk, SdnExtIn, SdnExtOut, StreamCfg, StreamOverflow,
+};
+use futures::{select, StreamExt};
+
+fn pop_controls(sdk: &PubsubSdk<()>) -> Vec<Control> {
+    let mut controls = vec![];
+    while let Some(control) = sdk.pop_control::<()>() {
+        match control {
+            SdnExtIn::FeaturesControl((), FeaturesControl::PubSub(control)) => controls.push(control),
+            _ => panic!("unexpected control"),
+        }
+    }
+    controls
+}
+
+fn data(channel: u64, source: u32, data: u8) -> SdnExtOut<(), ()> {
+    SdnExtOut::FeaturesEvent((), FeaturesEvent::PubSub(Event(channel.into(), ChannelEvent::SourceData(source, vec![data]))))
+}
+
+#[test]
+fn consume_two_channels_with_select() {
+    let sdk = PubsubSdk::new(());
+    let mut stream1 = sdk.subscribe(1.into(), StreamCfg::default()).fuse();
+    let mut stream2 = sdk.subscribe(2.into(), StreamCfg::default()).fuse();
+    assert_eq!(pop_controls(&sdk), vec![Control(1.into(), ChannelControl::SubAuto), Control(2.into(), ChannelControl::SubAuto)]);
+
+    //events are fed by the loop which owns the controller, here it is a thread
+    let feeder = sdk.clone();
+    let handle = std::thread::spawn(move || {
+        for i in 0..3 {
+            std::thread::sleep(Duration::from_millis(10));
+            assert!(feeder.on_event(data(1, 10, i)).is_none());
+            assert!(feeder.on_event(data(2, 20, i)).is_none());
+        }
+    });
+
+    let (received1, received2) = async_std::task::block_on(async {
+        let (mut received1, mut received2) = (vec![], vec![]);
+        while received1.len() < 3 || received2.len() < 3 {
+            select! {
+                msg = stream1.next() => received1.push(msg.expect("Should have data")),
+                msg = stream2.next() => received2.push(msg.expect("Should have data")),
+            }
+        }
+        (received1, received2)
+    });
+    handle.join().expect("Should join feeder");
+    assert_eq!(received1, vec![(10, vec![0]), (10, vec![1]), (10, vec![2])]);
+    assert_eq!(received2, vec![(20, vec![0]), (20, vec![1]), (20, vec![2])]);
+
+    //closing a stream unsubscribes it, later data of the channel is ignored
+    drop(stream1);
+    assert_eq!(pop_controls(&sdk), vec![Control(1.into(), ChannelControl::UnsubAuto)]);
+    assert!(sdk.on_event(data(1, 10, 3)).is_none());
+    assert!(sdk
+        .on_event::<()>(SdnExtOut::FeaturesEvent((), FeaturesEvent::PubSub(Event(2.into(), ChannelEvent::SlowConsumer))))
+        .is_none());
+    assert_eq!(async_std::task::block_on(stream2.next()), None);
+    drop(stream2);
+    assert_eq!(pop_controls(&sdk), vec![]);
+}
+
+#[test]
+fn lossy_stream_should_drop_oldest() {
+    let sdk = PubsubSdk::new(());
+    let mut stream = sdk.subscribe(
+        1.into(),
+        StreamCfg {
+            buffer: 2,
+            overflow: StreamOverflow::DropOldest,
+        },
+    );
+    for i in 0..3 {
+        sdk.on_event(data(1, 10, i));
+    }
+    assert_eq!(stream.dropped(), 1);
+    assert_eq!(async_std::task::block_on(stream.next()), Some((10, vec![1])));
+    assert_eq!(async_std::task::block_on(stream.next()), Some((10, vec![2])));
+}
+
+#[test]
+fn lossless_stream_should_pull_from_consumer_queue() {
+    let sdk = PubsubSdk::new(());
+    let mut stream = sdk.subscribe(
+        1.into(),
+        StreamCfg {
+            buffer: 2,
+            overflow: StreamOverflow::Lossless { kind: 1 },
+        },
+    );
+    let controls = pop_controls(&sdk);
+    assert_eq!(controls.len(), 2);
+    assert_eq!(controls[0], Control(1.into(), ChannelControl::SubAuto));
+    assert!(matches!(controls[1], Control(_, ChannelControl::SetConsumerQueue(Some(cfg))) if cfg.high_water == 2));
+
+    sdk.on_tick();
+    assert_eq!(pop_controls(&sdk), vec![Control(1.into(), ChannelControl::Pull(2))]);
+    sdk.on_event(data(1, 10, 0));
+    sdk.on_event(data(1, 10, 1));
+    sdk.on_tick();
+    assert_eq!(pop_controls(&sdk), vec![]);
+
+    //consuming half of the buffer pulls again
+    assert_eq!(async_std::task::block_on(stream.next()), Some((10, vec![0])));
+    assert_eq!(pop_controls(&sdk), vec![Control(1.into(), ChannelControl::Pull(1))]);
+
+    drop(stream);
+    assert_eq!(
+        pop_controls(&sdk),
+        vec![Control(1.into(), ChannelControl::UnsubAuto), Control(1.into(), ChannelControl::SetConsumerQueue(None))]
+    );
+}